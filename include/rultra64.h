@@ -0,0 +1,60 @@
+/*
+    C API of the rultra64 core, generated from src/ffi.rs with:
+    cbindgen --config cbindgen.toml --output include/rultra64.h
+*/
+
+#ifndef RULTRA64_H
+#define RULTRA64_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define RULTRA64_API_VERSION 2
+
+#define RULTRA64_OK 0
+
+#define RULTRA64_ERROR -1
+
+#define RULTRA64_RESAMPLER_LINEAR 0
+
+#define RULTRA64_RESAMPLER_SINC 1
+
+typedef struct Rultra64 Rultra64;
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+uint32_t rultra64_api_version(void);
+
+Rultra64 *rultra64_create(void);
+
+void rultra64_destroy(Rultra64 *core);
+
+int rultra64_load_rom(Rultra64 *core, const char *path);
+
+void rultra64_run_frame(Rultra64 *core);
+
+void rultra64_read_memory(const Rultra64 *core, uint32_t address, uint8_t *data, size_t length);
+
+void rultra64_write_memory(Rultra64 *core, uint32_t address, const uint8_t *data, size_t length);
+
+const uint8_t *rultra64_framebuffer(Rultra64 *core, uint32_t *width, uint32_t *height);
+
+const int16_t *rultra64_audio_samples(Rultra64 *core, size_t *count);
+
+uint32_t rultra64_audio_sample_rate(const Rultra64 *core);
+
+int rultra64_set_audio_output(Rultra64 *core, uint32_t rate, int resampler);
+
+const int16_t *rultra64_audio_output(Rultra64 *core, size_t *count);
+
+int rultra64_set_input(Rultra64 *core, uint32_t port, uint16_t buttons, int8_t x, int8_t y);
+
+const char *rultra64_last_error(const Rultra64 *core);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RULTRA64_H */
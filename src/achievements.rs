@@ -0,0 +1,155 @@
+use crate::rdram::{RDRAM, RDRAM_SIZE};
+
+/*
+    Hooks for RetroAchievements through rcheevos. The cores RetroAchievements supports expose RDRAM
+    as host order 32 bit words, so sets address it with the bytes of every word reversed.
+    The view below uses the same layout and the same little endian peeks, so existing sets work.
+    https://docs.retroachievements.org/developer-docs/memory-inspector.html
+*/
+const ADDRESS_SWIZZLE: u32 = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameAction {
+    Continue,
+    // Stops the emulation after the frame, for a trigger the player should see before it goes on
+    Pause,
+}
+
+// Called after every frame, where a frontend would call rc_runtime_do_frame
+pub type FrameHook = Box<dyn FnMut(&MemoryView) -> FrameAction + Send + Sync>;
+
+// What a frame hook sees, reading doesn't go through the MMU so it leaves no trace in the heatmap
+pub struct MemoryView<'a> {
+    rdram: &'a RDRAM,
+    frames: u64,
+}
+
+impl<'a> MemoryView<'a> {
+    pub fn new(rdram: &'a RDRAM, frames: u64) -> Self {
+        Self {
+            rdram,
+            frames,
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn size(&self) -> usize {
+        RDRAM_SIZE
+    }
+
+    // Bytes outside of RDRAM read as 0, like rcheevos expects
+    pub fn read8(&self, address: u32) -> u8 {
+        match (address as usize) < RDRAM_SIZE {
+            true => self.rdram.read8((address ^ ADDRESS_SWIZZLE) as i64),
+            false => 0,
+        }
+    }
+
+    // rc_runtime_peek_t: 1, 2 or 4 bytes combined as little endian
+    pub fn peek(&self, address: u32, bytes: u32) -> u32 {
+        (0..bytes.min(4)).fold(0, |value, byte| value | (self.read8(address.wrapping_add(byte)) as u32) << (byte * 8))
+    }
+
+    // The whole view at once, for frontends giving rcheevos a flat buffer. Copies up to the buffer's size
+    pub fn copy_to(&self, buffer: &mut [u8]) {
+        for (address, byte) in buffer.iter_mut().take(RDRAM_SIZE).enumerate() {
+            *byte = self.read8(address as u32);
+        }
+    }
+}
+
+/*
+    The hash RetroAchievements identifies N64 games by: the MD5 of the ROM in big endian (.z64) order,
+    which is how the ROM is kept after loading, in lowercase hex.
+*/
+pub fn rom_hash(data: &[u8]) -> String {
+    md5(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+const MD5_CONSTANTS: [u32; 64] = [
+    0xD76AA478, 0xE8C7B756, 0x242070DB, 0xC1BDCEEE,
+    0xF57C0FAF, 0x4787C62A, 0xA8304613, 0xFD469501,
+    0x698098D8, 0x8B44F7AF, 0xFFFF5BB1, 0x895CD7BE,
+    0x6B901122, 0xFD987193, 0xA679438E, 0x49B40821,
+    0xF61E2562, 0xC040B340, 0x265E5A51, 0xE9B6C7AA,
+    0xD62F105D, 0x02441453, 0xD8A1E681, 0xE7D3FBC8,
+    0x21E1CDE6, 0xC33707D6, 0xF4D50D87, 0x455A14ED,
+    0xA9E3E905, 0xFCEFA3F8, 0x676F02D9, 0x8D2A4C8A,
+    0xFFFA3942, 0x8771F681, 0x6D9D6122, 0xFDE5380C,
+    0xA4BEEA44, 0x4BDECFA9, 0xF6BB4B60, 0xBEBFBC70,
+    0x289B7EC6, 0xEAA127FA, 0xD4EF3085, 0x04881D05,
+    0xD9D4D039, 0xE6DB99E5, 0x1FA27CF8, 0xC4AC5665,
+    0xF4292244, 0x432AFF97, 0xAB9423A7, 0xFC93A039,
+    0x655B59C3, 0x8F0CCC92, 0xFFEFF47D, 0x85845DD1,
+    0x6FA87E4F, 0xFE2CE6E0, 0xA3014314, 0x4E0811A1,
+    0xF7537E82, 0xBD3AF235, 0x2AD7D2BB, 0xEB86D391,
+];
+
+// RFC 1321
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    for chunk in message.chunks_exact(64) {
+        let words: Vec<u32> = chunk.chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(MD5_CONSTANTS[i]).wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[(i / 16) * 4 + i % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+    let mut digest = [0; 16];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod achievements_tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_view() {
+        assert_eq!(rom_hash(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(rom_hash(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(rom_hash(&[b'a'; 100]), "36a92cc94a9e0fa21f625f8bfb007adf");
+
+        let mut rdram = RDRAM::new();
+        for (address, byte) in [0x12, 0x34, 0x56, 0x78].into_iter().enumerate() {
+            rdram.write8(0x100 + address as i64, byte);
+        }
+        let view = MemoryView::new(&rdram, 5);
+        assert_eq!(view.frames(), 5);
+        assert_eq!(view.peek(0x100, 4), 0x12345678);
+        assert_eq!(view.peek(0x100, 2), 0x5678);
+        assert_eq!(view.peek(0x102, 2), 0x1234);
+        assert_eq!(view.read8(0x103), 0x12);
+        assert_eq!(view.peek(RDRAM_SIZE as u32, 4), 0);
+        let mut buffer = vec![0; 0x104];
+        view.copy_to(&mut buffer);
+        assert_eq!(&buffer[0x100..], &[0x78, 0x56, 0x34, 0x12]);
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::{Error, ErrorKind, Read, Result};
+
+use flate2::read::{DeflateDecoder, GzDecoder};
+
+pub fn decompress_gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+pub struct ZipEntry<'a> {
+    pub name: String,
+    method: u16,
+    data: &'a [u8],
+}
+
+impl ZipEntry<'_> {
+    pub fn decompress(&self) -> Result<Vec<u8>> {
+        match self.method {
+            0 => Ok(self.data.to_vec()),
+            8 => {
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(self.data).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Unsupported zip compression method {}", self.method))),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<usize> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().unwrap()) as usize)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()) as usize)
+}
+
+/*
+    Entries listed in the central directory, which also has the sizes of the files written with a data descriptor.
+    https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+*/
+pub fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry<'_>>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid zip file");
+    let end = (0..data.len().saturating_sub(21)).rev()
+        .find(|offset| data[*offset..].starts_with(&[b'P', b'K', 0x05, 0x06]))
+        .ok_or_else(invalid)?;
+    let count = read_u16(data, end + 10).ok_or_else(invalid)?;
+    let mut offset = read_u32(data, end + 16).ok_or_else(invalid)?;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if !data.get(offset..).ok_or_else(invalid)?.starts_with(&[b'P', b'K', 0x01, 0x02]) {
+            return Err(invalid());
+        }
+        let method = read_u16(data, offset + 10).ok_or_else(invalid)? as u16;
+        let compressed_size = read_u32(data, offset + 20).ok_or_else(invalid)?;
+        let name_len = read_u16(data, offset + 28).ok_or_else(invalid)?;
+        let extra_len = read_u16(data, offset + 30).ok_or_else(invalid)?;
+        let comment_len = read_u16(data, offset + 32).ok_or_else(invalid)?;
+        let local_header = read_u32(data, offset + 42).ok_or_else(invalid)?;
+        let name = data.get(offset + 46..offset + 46 + name_len).ok_or_else(invalid)?;
+        let start = local_header + 30
+            + read_u16(data, local_header + 26).ok_or_else(invalid)?
+            + read_u16(data, local_header + 28).ok_or_else(invalid)?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            method,
+            data: data.get(start..start + compressed_size).ok_or_else(invalid)?,
+        });
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod archive_tests {
+    use super::*;
+
+    // Stored (uncompressed) zip with a single entry
+    fn stored_zip(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[b'P', b'K', 0x03, 0x04, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(contents);
+        let directory = data.len();
+        data.extend_from_slice(&[b'P', b'K', 0x01, 0x02, 20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(&0_u32.to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        let directory_len = data.len() - directory;
+        data.extend_from_slice(&[b'P', b'K', 0x05, 0x06, 0, 0, 0, 0, 1, 0, 1, 0]);
+        data.extend_from_slice(&(directory_len as u32).to_le_bytes());
+        data.extend_from_slice(&(directory as u32).to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_zip_entries() {
+        let data = stored_zip("game.z64", b"ROM DATA");
+        let entries = zip_entries(&data).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "game.z64");
+        assert_eq!(entries[0].decompress().unwrap(), b"ROM DATA");
+        assert!(zip_entries(&data[..data.len() - 22]).is_err());
+    }
+}
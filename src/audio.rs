@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+pub const AI_DACRATE_ADDRESS: i64 = 0x04500010;
+pub const AI_STATUS_ADDRESS: i64 = 0x0450000C;
+pub const AI_STATUS_BUSY: u32 = 1 << 30;
+
+// Buffers and stereo samples kept for the audio debug window
+pub const AUDIO_BUFFER_HISTORY: usize = 16;
+pub const AUDIO_SAMPLE_HISTORY: usize = 2048;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AudioBuffer {
+    // Physical address in RDRAM
+    pub address: i64,
+    pub length: u32,
+}
+
+/*
+    Audio output isn't emulated yet, the monitor keeps the buffers the game hands to the AI
+    so the debugger can tell a game producing silence apart from one producing no samples.
+    Samples are big endian 16 bit stereo: https://n64brew.dev/wiki/Audio_Interface
+*/
+pub struct AudioMonitor {
+    buffers: VecDeque<AudioBuffer>,
+    // Left and right
+    samples: VecDeque<[i16; 2]>,
+    // Every sample pushed so far, so outputs can take only the ones they haven't played
+    total_samples: u64,
+}
+
+impl AudioMonitor {
+    pub fn new() -> Self {
+        Self {
+            buffers: VecDeque::with_capacity(AUDIO_BUFFER_HISTORY),
+            samples: VecDeque::with_capacity(AUDIO_SAMPLE_HISTORY),
+            total_samples: 0,
+        }
+    }
+
+    pub fn push(&mut self, buffer: AudioBuffer, data: &[u8]) {
+        if self.buffers.len() == AUDIO_BUFFER_HISTORY {
+            self.buffers.pop_front();
+        }
+        self.buffers.push_back(buffer);
+        for frame in data.chunks_exact(4) {
+            if self.samples.len() == AUDIO_SAMPLE_HISTORY {
+                self.samples.pop_front();
+            }
+            self.samples.push_back([i16::from_be_bytes([frame[0], frame[1]]), i16::from_be_bytes([frame[2], frame[3]])]);
+            self.total_samples += 1;
+        }
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.total_samples
+    }
+
+    // Samples pushed after the first `seen` ones, as far as the history goes back
+    pub fn samples_after(&self, seen: u64) -> Vec<[i16; 2]> {
+        let new = (self.total_samples.saturating_sub(seen) as usize).min(self.samples.len());
+        self.samples.iter().skip(self.samples.len() - new).copied().collect()
+    }
+
+    // Oldest first
+    pub fn buffers(&self) -> &VecDeque<AudioBuffer> {
+        &self.buffers
+    }
+
+    // Oldest first
+    pub fn samples(&self) -> &VecDeque<[i16; 2]> {
+        &self.samples
+    }
+}
+
+// The DAC divides the VI clock: https://n64brew.dev/wiki/Audio_Interface#0x0450_0010_-_AI_DACRATE
+pub fn sample_rate(vi_clock_rate: u64, dacrate: u32) -> u64 {
+    vi_clock_rate / ((dacrate & 0x3FFF) as u64 + 1)
+}
+
+// Peak and RMS of each channel, from 0 to 1
+pub fn levels(samples: &[[i16; 2]]) -> [(f32, f32); 2] {
+    let mut levels = [(0.0, 0.0); 2];
+    if samples.is_empty() {
+        return levels;
+    }
+    for (channel, level) in levels.iter_mut().enumerate() {
+        let mut peak: f32 = 0.0;
+        let mut sum = 0.0;
+        for sample in samples {
+            let value = sample[channel] as f32 / 32768.0;
+            peak = peak.max(value.abs());
+            sum += value * value;
+        }
+        *level = (peak, (sum / samples.len() as f32).sqrt());
+    }
+    levels
+}
+
+#[cfg(test)]
+mod audio_tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_monitor() {
+        let mut monitor = AudioMonitor::new();
+        let buffer = AudioBuffer { address: 0x1000, length: 8 };
+        monitor.push(buffer, &[0x40, 0x00, 0xC0, 0x00, 0xC0, 0x00, 0x00, 0x00]);
+        assert_eq!(monitor.buffers().len(), 1);
+        let samples: Vec<[i16; 2]> = monitor.samples().iter().copied().collect();
+        assert_eq!(samples, vec![[0x4000, -0x4000], [-0x4000, 0]]);
+        assert_eq!(monitor.samples_after(1), vec![[-0x4000, 0]]);
+        let [left, right] = levels(&samples);
+        assert_eq!(left, (0.5, 0.5));
+        assert_eq!(right.0, 0.5);
+
+        for _ in 0..AUDIO_BUFFER_HISTORY {
+            monitor.push(buffer, &vec![0; AUDIO_SAMPLE_HISTORY * 4]);
+        }
+        assert_eq!(monitor.buffers().len(), AUDIO_BUFFER_HISTORY);
+        assert!(monitor.samples().iter().all(|sample| *sample == [0, 0]));
+        assert_eq!(sample_rate(48681812, 1103), 44095);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::rom::ROM;
+use crate::storage::{self, DataKind};
+
+// Key of the settings in the GUI's persisted storage
+pub const AUTOSAVE_KEY: &str = "autosave";
+
+/*
+    A savestate written when the emulator closes or another ROM is loaded, in the same format as
+    the slots and next to them as <game>.auto. Loading the game again offers to resume from it.
+*/
+pub fn autosave_path(rom: &ROM) -> Option<PathBuf> {
+    storage::game_file(rom, DataKind::States, "auto")
+}
+
+// Games are keyed by ROM::game_id, the ones without an entry follow the default
+pub struct AutosaveConfig {
+    pub default: bool,
+    games: HashMap<String, bool>,
+}
+
+impl AutosaveConfig {
+    pub fn new() -> Self {
+        Self {
+            default: true,
+            games: HashMap::new(),
+        }
+    }
+
+    // "default=on" and one "<game id>=on|off" line per game, the format the GUI stores
+    pub fn from_lines(data: &str) -> Self {
+        let mut config = AutosaveConfig::new();
+        for (key, value) in data.lines().filter_map(|line| line.split_once('=')) {
+            let enabled = match value.trim() {
+                "on" => true,
+                "off" => false,
+                _ => continue,
+            };
+            match key.trim() {
+                "default" => config.default = enabled,
+                game => {
+                    config.games.insert(game.to_string(), enabled);
+                },
+            };
+        }
+        config
+    }
+
+    pub fn to_lines(&self) -> String {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        let mut games: Vec<_> = self.games.iter().collect();
+        games.sort();
+        let mut lines = format!("default={}\n", on_off(self.default));
+        for (game, enabled) in games {
+            lines.push_str(&format!("{}={}\n", game, on_off(*enabled)));
+        }
+        lines
+    }
+
+    // Never for a ROM without a header, there's nowhere to keep its state
+    pub fn enabled(&self, game: &Option<String>) -> bool {
+        match game {
+            Some(game) => self.games.get(game).copied().unwrap_or(self.default),
+            None => false,
+        }
+    }
+
+    pub fn set_enabled(&mut self, game: &str, enabled: bool) {
+        self.games.insert(game.to_string(), enabled);
+    }
+}
+
+#[cfg(test)]
+mod autosave_tests {
+    use super::*;
+
+    #[test]
+    fn test_config_lines() {
+        let mut config = AutosaveConfig::from_lines("default=off\nAAAAAAAA-BBBBBBBB=on\nbroken\nCCCCCCCC-DDDDDDDD=maybe\n");
+        assert!(!config.default);
+        assert!(config.enabled(&Some(String::from("AAAAAAAA-BBBBBBBB"))));
+        assert!(!config.enabled(&Some(String::from("CCCCCCCC-DDDDDDDD"))));
+        assert!(!config.enabled(&None));
+
+        config.default = true;
+        config.set_enabled("CCCCCCCC-DDDDDDDD", false);
+        assert!(config.enabled(&Some(String::from("EEEEEEEE-FFFFFFFF"))));
+        let lines = config.to_lines();
+        assert_eq!(lines, "default=on\nAAAAAAAA-BBBBBBBB=on\nCCCCCCCC-DDDDDDDD=off\n");
+        assert_eq!(AutosaveConfig::from_lines(&lines).to_lines(), lines);
+    }
+}
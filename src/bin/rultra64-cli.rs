@@ -0,0 +1,347 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use rultra64::crash_report::{CrashReport, panic_message};
+use rultra64::emulator::Emulator;
+use rultra64::log::{self, Level, Subsystem};
+use rultra64::mmu::UnmappedPolicy;
+use rultra64::rdp_capture::RdpCapture;
+use rultra64::rom::ROM;
+use rultra64::storage;
+use rultra64::symbols::SymbolTable;
+use rultra64::test_rom::framebuffer_hash;
+use rultra64::trace::{TraceEntry, find_divergence};
+
+const USAGE: &str = "Usage: rultra64-cli <rom> [--frames N] [--trace] [--trace-registers] [--symbols PATH] [--compare-trace PATH] [--loadstate PATH] [--savestate PATH] [--log-level [SUBSYSTEM=]LEVEL] [--log-json] [--framebuffer-hash] [--no-vi-filters] [--capture-rdp PATH] [--fastmem] [--unmapped POLICY] [--dump-textures] [--raw-savestate] [--data-dir PATH] [--paranoid]
+       rultra64-cli --replay-rdp PATH
+
+Runs a ROM headless and exits with status 0 on success, 1 when the emulation fails and 2 on invalid arguments.
+
+Options:
+    --frames N          Number of frames to run (default: 60)
+    --trace             Print the PC and opcode of every executed instruction
+    --trace-registers   Print the registers too, before running each instruction
+    --symbols PATH      Name the function of every traced instruction, from a .sym, .map or address=name file.
+                        A file with the ROM's name and one of those extensions is used by default
+    --compare-trace PATH
+                        Run along a trace in the same format, from this or another emulator, and
+                        report the first instruction that differs instead of running N frames
+    --loadstate PATH    Load a savestate before running
+    --savestate PATH    Write a savestate after running
+    --raw-savestate     Write it without compression, to look at it in a hex editor
+    --log-level LEVEL   Most verbose messages logged, error, warn, info or debug, for every subsystem
+                        or for one like pif=debug. Can be repeated
+    --log-json          Write the log to stderr as JSON lines
+    --framebuffer-hash  Print the CRC32 of the frame after running, the golden used by tests/test_roms.txt
+    --no-vi-filters     Leave out the gamma, dither filter and divot VI_CTRL enables from the frame
+    --capture-rdp PATH  Run one more frame recording its RDP commands and the memory they read
+    --replay-rdp PATH   Run the commands of a capture on their own and print the CRC32 of TMEM
+    --fastmem           Access RDRAM and the ROM through a page table instead of the device dispatch
+    --unmapped POLICY   What CPU accesses to unmapped addresses do: ignore, log (default) or bus-error
+    --dump-textures     Write the textures the game loads as PNGs, in the dump directory of its texture pack
+    --data-dir PATH     Keep saves, savestates and screenshots in PATH, one directory per kind and game,
+                        instead of next to the ROM. Files already next to the ROM are moved there
+    --paranoid          Check every integer ALU instruction against a reference model, print the
+                        registers that didn't match and exit with status 1 if any";
+
+struct Options {
+    // Only missing when replaying an RDP capture
+    rom: Option<String>,
+    frames: u64,
+    trace: bool,
+    trace_registers: bool,
+    symbols: Option<String>,
+    compare_trace: Option<String>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    raw_save_state: bool,
+    log_json: bool,
+    framebuffer_hash: bool,
+    vi_filters: bool,
+    capture_rdp: Option<String>,
+    replay_rdp: Option<String>,
+    fastmem: bool,
+    unmapped_policy: UnmappedPolicy,
+    dump_textures: bool,
+    data_directory: Option<String>,
+    paranoid: bool,
+}
+
+// The crash report goes next to the ROM, named after the run's Unix time
+fn report_crash(emulator: &Emulator, err: &(dyn Any + Send)) {
+    let message = panic_message(err);
+    rultra64::log!(Level::Error, Subsystem::CPU, "Emulation failed at PC {:016X} after {} frames: {}", emulator.cpu().registers().get_program_counter(), emulator.frames(), message);
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    match CrashReport::capture(emulator, &message).write(emulator, timestamp) {
+        Ok(path) => rultra64::log!(Level::Error, Subsystem::Frontend, "Crash report written to {}", path.display()),
+        Err(err) => rultra64::log!(Level::Error, Subsystem::Frontend, "Could not write the crash report: {}", err),
+    };
+}
+
+// "debug" for every subsystem or "pif=debug" for one
+fn set_log_level(value: &str) -> Result<(), String> {
+    let (subsystems, level) = match value.split_once('=') {
+        Some((subsystem, level)) => (vec![Subsystem::parse(subsystem).ok_or(format!("Unknown subsystem {}", subsystem))?], level),
+        None => (Subsystem::ALL.to_vec(), value),
+    };
+    let level = Level::parse(level).ok_or(format!("Unknown log level {}", level))?;
+    for subsystem in subsystems {
+        log::set_max_level(subsystem, level);
+    }
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut rom = None;
+    let mut frames = 60;
+    let mut trace = false;
+    let mut trace_registers = false;
+    let mut symbols = None;
+    let mut compare_trace = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut raw_save_state = false;
+    let mut log_json = false;
+    let mut framebuffer_hash = false;
+    let mut vi_filters = true;
+    let mut capture_rdp = None;
+    let mut replay_rdp = None;
+    let mut fastmem = false;
+    let mut unmapped_policy = UnmappedPolicy::Log;
+    let mut dump_textures = false;
+    let mut data_directory = None;
+    let mut paranoid = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = args.next().ok_or("--frames expects a value")?;
+                frames = value.parse().map_err(|_| format!("Invalid number of frames: {}", value))?;
+            },
+            "--trace" => trace = true,
+            "--trace-registers" => {
+                trace = true;
+                trace_registers = true;
+            },
+            "--symbols" => symbols = Some(args.next().ok_or("--symbols expects a path")?),
+            "--compare-trace" => compare_trace = Some(args.next().ok_or("--compare-trace expects a path")?),
+            "--loadstate" => load_state = Some(args.next().ok_or("--loadstate expects a path")?),
+            "--savestate" => save_state = Some(args.next().ok_or("--savestate expects a path")?),
+            "--raw-savestate" => raw_save_state = true,
+            "--log-level" => set_log_level(&args.next().ok_or("--log-level expects a level")?)?,
+            "--log-json" => log_json = true,
+            "--framebuffer-hash" => framebuffer_hash = true,
+            "--no-vi-filters" => vi_filters = false,
+            "--capture-rdp" => capture_rdp = Some(args.next().ok_or("--capture-rdp expects a path")?),
+            "--replay-rdp" => replay_rdp = Some(args.next().ok_or("--replay-rdp expects a path")?),
+            "--fastmem" => fastmem = true,
+            "--unmapped" => {
+                let value = args.next().ok_or("--unmapped expects a policy")?;
+                unmapped_policy = UnmappedPolicy::parse(&value).ok_or(format!("Unknown unmapped policy {}", value))?;
+            },
+            "--dump-textures" => dump_textures = true,
+            "--data-dir" => data_directory = Some(args.next().ok_or("--data-dir expects a path")?),
+            "--paranoid" => paranoid = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => {
+                if rom.is_some() {
+                    return Err(format!("Unexpected argument {}", arg));
+                }
+                rom = Some(arg);
+            },
+        };
+    }
+    if rom.is_none() && replay_rdp.is_none() {
+        return Err(String::from("Missing ROM path"));
+    }
+    Ok(Options {
+        rom,
+        frames,
+        trace,
+        trace_registers,
+        symbols,
+        compare_trace,
+        load_state,
+        save_state,
+        raw_save_state,
+        log_json,
+        framebuffer_hash,
+        vi_filters,
+        capture_rdp,
+        replay_rdp,
+        fastmem,
+        unmapped_policy,
+        dump_textures,
+        data_directory,
+        paranoid,
+    })
+}
+
+fn run(emulator: &mut Emulator, options: &Options, symbols: &SymbolTable) {
+    for _ in 0..options.frames {
+        loop {
+            if options.trace {
+                let entry = TraceEntry::capture(emulator, options.trace_registers);
+                // TraceEntry::parse skips the name, so symbolized traces can still be compared
+                match symbols.describe(entry.pc) {
+                    Some(name) => println!("{} <{}>", entry, name),
+                    None => println!("{}", entry),
+                };
+            }
+            if emulator.tick() {
+                break;
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{}\n", err);
+            }
+            eprintln!("{}", USAGE);
+            exit(2);
+        },
+    };
+    #[cfg(feature = "trace")]
+    log::init_tracing(options.log_json);
+    #[cfg(not(feature = "trace"))]
+    if options.log_json {
+        eprintln!("--log-json needs the trace feature");
+        exit(2);
+    }
+
+    if let Some(path) = &options.replay_rdp {
+        let result = std::fs::read(path).and_then(|data| RdpCapture::from_bytes(&data)?.replay_hash());
+        match result {
+            Ok(hash) => println!("{:08X}", hash),
+            Err(err) => {
+                rultra64::log!(Level::Error, Subsystem::RDP, "Could not replay {}: {}", path, err);
+                exit(1);
+            },
+        };
+        return;
+    }
+
+    let rom_path = options.rom.as_deref().unwrap();
+    let rom = match ROM::new_from_filename(rom_path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            rultra64::log!(Level::Error, Subsystem::Frontend, "Could not load ROM {}: {}", rom_path, err);
+            exit(1);
+        },
+    };
+    let symbols = match &options.symbols {
+        Some(path) => match SymbolTable::load(Path::new(path)) {
+            Ok(symbols) => symbols,
+            Err(err) => {
+                rultra64::log!(Level::Error, Subsystem::Frontend, "Could not load symbols {}: {}", path, err);
+                exit(1);
+            },
+        },
+        None => SymbolTable::load_for_rom(Path::new(rom_path)).unwrap_or_else(SymbolTable::new),
+    };
+    storage::set_data_directory(options.data_directory.as_ref().map(PathBuf::from));
+    let mut emulator = Emulator::new_hle();
+    emulator.set_fastmem(options.fastmem);
+    emulator.set_unmapped_policy(options.unmapped_policy);
+    emulator.set_vi_filters(options.vi_filters);
+    emulator.set_texture_dump(options.dump_textures);
+    emulator.set_compress_states(!options.raw_save_state);
+    emulator.set_paranoid(options.paranoid);
+    emulator.load_rom(rom);
+
+    if let Some(path) = &options.load_state {
+        let result = std::fs::read(path).and_then(|data| emulator.load_state(&data));
+        if let Err(err) = result {
+            rultra64::log!(Level::Error, Subsystem::Frontend, "Could not load savestate {}: {}", path, err);
+            exit(1);
+        }
+    }
+
+    if let Some(path) = &options.compare_trace {
+        let reference = match std::fs::read_to_string(path) {
+            Ok(reference) => reference,
+            Err(err) => {
+                rultra64::log!(Level::Error, Subsystem::Frontend, "Could not read trace {}: {}", path, err);
+                exit(1);
+            },
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| find_divergence(&mut emulator, reference.lines().filter_map(TraceEntry::parse))));
+        match result {
+            Ok(Ok(matched)) => println!("No divergence in {} instructions", matched),
+            Ok(Err(divergence)) => {
+                println!("{}", divergence);
+                exit(1);
+            },
+            Err(err) => {
+                report_crash(&emulator, err.as_ref());
+                exit(1);
+            },
+        };
+        emulator.flush_saves();
+        return;
+    }
+
+    let result = catch_unwind(AssertUnwindSafe(|| run(&mut emulator, &options, &symbols)));
+    if let Err(err) = result {
+        report_crash(&emulator, err.as_ref());
+        emulator.flush_saves();
+        exit(1);
+    }
+
+    if let Some(path) = &options.capture_rdp {
+        let result = catch_unwind(AssertUnwindSafe(|| emulator.capture_rdp_frame()));
+        let written = match result {
+            Ok(capture) => std::fs::write(path, capture.to_bytes()),
+            Err(err) => {
+                report_crash(&emulator, err.as_ref());
+                emulator.flush_saves();
+                exit(1);
+            },
+        };
+        if let Err(err) = written {
+            rultra64::log!(Level::Error, Subsystem::Frontend, "Could not write RDP capture {}: {}", path, err);
+            exit(1);
+        }
+    }
+
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    for (index, file) in emulator.mut_mmu().take_usb_files().iter().enumerate() {
+        match file.write(emulator.mmu().rom(), timestamp, index) {
+            Ok(path) => rultra64::log!(Level::Info, Subsystem::USB, "Data saved to {}", path.display()),
+            Err(err) => rultra64::log!(Level::Error, Subsystem::USB, "Could not save the data: {}", err),
+        };
+    }
+
+    if let Some(path) = &options.save_state {
+        if let Err(err) = std::fs::write(path, emulator.save_state()) {
+            rultra64::log!(Level::Error, Subsystem::Frontend, "Could not write savestate {}: {}", path, err);
+            exit(1);
+        }
+    }
+    emulator.flush_saves();
+    if options.framebuffer_hash {
+        match emulator.mmu().framebuffer_rgba() {
+            Some((_, _, pixels)) => println!("{:08X}", framebuffer_hash(&pixels)),
+            None => {
+                rultra64::log!(Level::Error, Subsystem::VI, "The video output is blank");
+                exit(1);
+            },
+        };
+    }
+    if let Some(checker) = emulator.paranoid() {
+        for mismatch in checker.mismatches() {
+            println!("{}", mismatch);
+        }
+        println!("{} mismatches in {} checked instructions", checker.mismatches().len(), checker.checked());
+        if !checker.mismatches().is_empty() {
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::mmu::MMU;
+
+// Key of the cheats of every game in the GUI's persisted storage
+pub const CHEATS_KEY: &str = "cheats";
+
+// Code types the engine runs: https://n64brew.dev/wiki/GameShark#Code_types
+const SUPPORTED_CODE_TYPES: [u8; 9] = [0x80, 0x81, 0xA0, 0xA1, 0xD0, 0xD1, 0xD2, 0xD3, 0x50];
+
+// A GameShark code, the type is the top byte of the address
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CheatCode {
+    pub address: u32,
+    pub value: u16,
+}
+
+impl CheatCode {
+    // "8033B21D 0064", spaces and dashes between the halves are optional
+    pub fn parse(text: &str) -> Option<Self> {
+        let digits: String = text.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self {
+            address: u32::from_str_radix(&digits[..8], 16).ok()?,
+            value: u16::from_str_radix(&digits[8..], 16).ok()?,
+        })
+    }
+
+    pub fn code_type(&self) -> u8 {
+        (self.address >> 24) as u8
+    }
+
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_CODE_TYPES.contains(&self.code_type())
+    }
+
+    fn physical_address(&self) -> i64 {
+        (self.address & 0xFFFFFF) as i64
+    }
+}
+
+impl fmt::Display for CheatCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X} {:04X}", self.address, self.value)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cheat {
+    pub name: String,
+    pub enabled: bool,
+    pub codes: Vec<CheatCode>,
+}
+
+/*
+    Cheat lists are a name line followed by its codes, the format most cheat text files share.
+    Enabled cheats get a '*' before the name, lists from elsewhere load disabled.
+*/
+pub fn parse_cheats(text: &str) -> Vec<Cheat> {
+    let mut cheats: Vec<Cheat> = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with("//")) {
+        match (CheatCode::parse(line), cheats.last_mut()) {
+            (Some(code), Some(cheat)) => cheat.codes.push(code),
+            (Some(_), None) => {},
+            (None, _) => cheats.push(Cheat {
+                name: line.trim_start_matches('*').trim().to_string(),
+                enabled: line.starts_with('*'),
+                codes: Vec::new(),
+            }),
+        };
+    }
+    cheats
+}
+
+pub fn cheats_to_text(cheats: &[Cheat]) -> String {
+    let mut text = String::new();
+    for cheat in cheats {
+        text.push_str(&format!("{}{}\n", if cheat.enabled { "*" } else { "" }, cheat.name));
+        for code in cheat.codes.iter() {
+            text.push_str(&format!("{}\n", code));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// Cheats of every game, by ROM::game_id
+pub struct CheatDatabase {
+    games: BTreeMap<String, Vec<Cheat>>,
+}
+
+impl CheatDatabase {
+    pub fn new() -> Self {
+        Self {
+            games: BTreeMap::new(),
+        }
+    }
+
+    // Each game's list starts with its id between brackets
+    pub fn from_text(text: &str) -> Self {
+        let mut games = BTreeMap::new();
+        let mut game: Option<&str> = None;
+        let mut start = 0;
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                if let Some(game) = game {
+                    games.insert(game.to_string(), parse_cheats(&text[start..offset]));
+                }
+                game = Some(&trimmed[1..trimmed.len() - 1]);
+                start = offset + line.len();
+            }
+            offset += line.len();
+        }
+        if let Some(game) = game {
+            games.insert(game.to_string(), parse_cheats(&text[start..]));
+        }
+        Self {
+            games,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        self.games.iter()
+            .filter(|(_, cheats)| !cheats.is_empty())
+            .map(|(game, cheats)| format!("[{}]\n{}", game, cheats_to_text(cheats)))
+            .collect()
+    }
+
+    pub fn cheats(&self, game: &str) -> &[Cheat] {
+        self.games.get(game).map_or(&[], |cheats| &cheats[..])
+    }
+
+    pub fn mut_cheats(&mut self, game: &str) -> &mut Vec<Cheat> {
+        self.games.entry(game.to_string()).or_default()
+    }
+}
+
+/*
+    Runs the enabled cheats, once per frame. Conditional codes decide whether the code after them
+    runs and patch codes repeat the write after them with increasing addresses and values.
+*/
+pub fn apply_cheats(cheats: &[Cheat], mmu: &mut MMU) {
+    for cheat in cheats.iter().filter(|cheat| cheat.enabled) {
+        let mut codes = cheat.codes.iter();
+        while let Some(code) = codes.next() {
+            let address = code.physical_address();
+            match code.code_type() {
+                0x80 | 0xA0 => mmu.write_physical(address, &[code.value as u8]),
+                0x81 | 0xA1 => mmu.write_physical(address, &code.value.to_be_bytes()),
+                0xD0..=0xD3 => {
+                    let current = match code.code_type() & 1 {
+                        0 => mmu.read_physical(address, 1)[0] as u16,
+                        _ => u16::from_be_bytes(mmu.read_physical(address, 2).try_into().unwrap()),
+                    };
+                    let expected = match code.code_type() & 1 {
+                        0 => code.value & 0xFF,
+                        _ => code.value,
+                    };
+                    let condition = match code.code_type() {
+                        0xD0 | 0xD1 => current == expected,
+                        _ => current != expected,
+                    };
+                    if !condition {
+                        codes.next();
+                    }
+                },
+                0x50 => {
+                    let count = (code.address >> 8) & 0xFF;
+                    let address_step = (code.address & 0xFF) as i64;
+                    if let Some(next) = codes.next() {
+                        for i in 0..count as i64 {
+                            let address = next.physical_address() + address_step * i;
+                            let value = next.value.wrapping_add(code.value.wrapping_mul(i as u16));
+                            match next.code_type() {
+                                0x80 | 0xA0 => mmu.write_physical(address, &[value as u8]),
+                                0x81 | 0xA1 => mmu.write_physical(address, &value.to_be_bytes()),
+                                _ => {},
+                            };
+                        }
+                    }
+                },
+                _ => {},
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod cheat_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cheats() {
+        let text = "Infinite Lives\n8033B21D 0064\n\n*Have Wings Cap\n8133B176 0002\n// comment\nD033AFA1-0020\n8133B172-0001\n";
+        let cheats = parse_cheats(text);
+        assert_eq!(cheats.len(), 2);
+        assert!(!cheats[0].enabled);
+        assert_eq!(cheats[0].codes, vec![CheatCode { address: 0x8033B21D, value: 0x0064 }]);
+        assert!(cheats[1].enabled);
+        assert_eq!(cheats[1].codes.len(), 3);
+        assert_eq!(parse_cheats(&cheats_to_text(&cheats)), cheats);
+
+        let mut database = CheatDatabase::new();
+        *database.mut_cheats("635A2BFF-8B022326") = cheats.clone();
+        let restored = CheatDatabase::from_text(&database.to_text());
+        assert_eq!(restored.cheats("635A2BFF-8B022326"), &cheats[..]);
+        assert!(restored.cheats("00000000-00000000").is_empty());
+    }
+
+    #[test]
+    fn test_apply_cheats() {
+        let mut mmu = MMU::new();
+        mmu.write_physical(0x1000, &[0x20]);
+        let cheats = parse_cheats("*Cheat\n80002000 0012\nD0001000 0020\n81002002 BEEF\nD2001000 0020\n80002004 00FF\n50000302 0001\n81002010 0100\n");
+        apply_cheats(&cheats, &mut mmu);
+        assert_eq!(mmu.read_physical(0x2000, 6), vec![0x12, 0x00, 0xBE, 0xEF, 0x00, 0x00]);
+        assert_eq!(mmu.read_physical(0x2010, 6), vec![0x01, 0x00, 0x01, 0x01, 0x01, 0x02]);
+    }
+}
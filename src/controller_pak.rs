@@ -0,0 +1,231 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use crate::pif::CONTROLLER_PORTS;
+use crate::rom::ROM;
+use crate::storage::{self, DataKind};
+
+pub const CONTROLLER_PAK_SIZE: usize = 0x8000;
+pub const NOTE_EXTENSION: &str = "note";
+
+/*
+    Layout of the Controller Pak filesystem: https://n64brew.dev/wiki/Controller_Pak/Filesystem_Format
+    128 pages of 256 bytes, the first five hold the ID area, the index table, its backup and the note table.
+*/
+const PAGE_SIZE: usize = 0x100;
+const PAGES: usize = CONTROLLER_PAK_SIZE / PAGE_SIZE;
+const FIRST_DATA_PAGE: usize = 5;
+const INODE_TABLE: usize = 0x100;
+const INODE_TABLE_BACKUP: usize = 0x200;
+const NOTE_TABLE: usize = 0x300;
+const NOTE_ENTRY_SIZE: usize = 32;
+const NOTES: usize = 16;
+
+const INODE_LAST_PAGE: u16 = 0x0001;
+const INODE_FREE: u16 = 0x0003;
+
+// Paks are stored with the battery saves, one file per port: <game>.mpk1 to <game>.mpk4
+pub fn controller_pak_paths(rom: &ROM) -> [Option<PathBuf>; CONTROLLER_PORTS] {
+    [1, 2, 3, 4].map(|port| storage::game_file(rom, DataKind::Saves, &format!("mpk{}", port)))
+}
+
+// Note names use the controller's own character set
+fn decode_char(c: u8) -> Option<char> {
+    match c {
+        0x0F => Some(' '),
+        0x10..=0x19 => Some((b'0' + c - 0x10) as char),
+        0x1A..=0x33 => Some((b'A' + c - 0x1A) as char),
+        0x34..=0x41 => Some(b"!\"#'*+,-./:=?@"[(c - 0x34) as usize] as char),
+        _ => None,
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Note {
+    // Position in the note table
+    pub index: usize,
+    pub game_code: String,
+    pub publisher_code: String,
+    pub name: String,
+    pub pages: usize,
+}
+
+pub struct ControllerPak {
+    data: Vec<u8>,
+    // Written by the game since it was last saved
+    dirty: bool,
+}
+
+impl ControllerPak {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        if data.len() != CONTROLLER_PAK_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("A Controller Pak has {} bytes, not {}", CONTROLLER_PAK_SIZE, data.len())));
+        }
+        Ok(Self {
+            data,
+            dirty: false,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Writes coming from the game through the controller, the filesystem is left as they leave it
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        self.dirty |= self.data[offset..offset + data.len()] != *data;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+
+    fn inode(&self, page: usize) -> u16 {
+        u16::from_be_bytes([self.data[INODE_TABLE + page * 2], self.data[INODE_TABLE + page * 2 + 1]])
+    }
+
+    fn set_inode(&mut self, page: usize, value: u16) {
+        self.data[INODE_TABLE + page * 2..INODE_TABLE + page * 2 + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    // The checksum covers the data pages' entries, the backup table gets a copy
+    fn update_inode_checksum(&mut self) {
+        let checksum = self.data[INODE_TABLE + FIRST_DATA_PAGE * 2..INODE_TABLE + PAGE_SIZE].iter()
+            .fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        self.data[INODE_TABLE + 1] = checksum;
+        self.data.copy_within(INODE_TABLE..INODE_TABLE + PAGE_SIZE, INODE_TABLE_BACKUP);
+    }
+
+    fn entry(&self, index: usize) -> &[u8] {
+        &self.data[NOTE_TABLE + index * NOTE_ENTRY_SIZE..NOTE_TABLE + (index + 1) * NOTE_ENTRY_SIZE]
+    }
+
+    fn start_page(&self, index: usize) -> usize {
+        let entry = self.entry(index);
+        u16::from_be_bytes([entry[6], entry[7]]) as usize
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        let entry = self.entry(index);
+        entry[..4] != [0; 4] && (FIRST_DATA_PAGE..PAGES).contains(&self.start_page(index))
+    }
+
+    // Pages of the note in order, stops on a broken chain instead of looping forever
+    fn chain(&self, index: usize) -> Vec<usize> {
+        let mut pages = Vec::new();
+        let mut page = self.start_page(index);
+        while (FIRST_DATA_PAGE..PAGES).contains(&page) && !pages.contains(&page) {
+            pages.push(page);
+            match self.inode(page) {
+                INODE_LAST_PAGE => break,
+                next => page = (next & 0xFF) as usize,
+            };
+        }
+        pages
+    }
+
+    pub fn notes(&self) -> Vec<Note> {
+        (0..NOTES).filter(|index| self.is_used(*index)).map(|index| {
+            let entry = self.entry(index);
+            let name: String = entry[0x10..0x20].iter().take_while(|c| **c != 0).filter_map(|c| decode_char(*c)).collect();
+            let extension: String = entry[0x0C..0x10].iter().take_while(|c| **c != 0).filter_map(|c| decode_char(*c)).collect();
+            Note {
+                index,
+                game_code: String::from_utf8_lossy(&entry[..4]).to_string(),
+                publisher_code: String::from_utf8_lossy(&entry[4..6]).to_string(),
+                name: match extension.is_empty() {
+                    true => name,
+                    false => format!("{}.{}", name, extension),
+                },
+                pages: self.chain(index).len(),
+            }
+        }).collect()
+    }
+
+    pub fn free_pages(&self) -> usize {
+        (FIRST_DATA_PAGE..PAGES).filter(|page| self.inode(*page) == INODE_FREE).count()
+    }
+
+    pub fn delete(&mut self, index: usize) {
+        for page in self.chain(index) {
+            self.set_inode(page, INODE_FREE);
+        }
+        self.data[NOTE_TABLE + index * NOTE_ENTRY_SIZE..NOTE_TABLE + (index + 1) * NOTE_ENTRY_SIZE].fill(0);
+        self.update_inode_checksum();
+    }
+
+    // The note table entry followed by the note's pages
+    pub fn export(&self, index: usize) -> Vec<u8> {
+        let mut note = self.entry(index).to_vec();
+        for page in self.chain(index) {
+            note.extend_from_slice(&self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE]);
+        }
+        note
+    }
+
+    pub fn import(&mut self, note: &[u8]) -> Result<()> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        if note.len() <= NOTE_ENTRY_SIZE || (note.len() - NOTE_ENTRY_SIZE) % PAGE_SIZE != 0 {
+            return Err(invalid("Not a note file"));
+        }
+        let index = (0..NOTES).find(|index| !self.is_used(*index)).ok_or_else(|| invalid("The note table is full"))?;
+        let pages: Vec<usize> = (FIRST_DATA_PAGE..PAGES).filter(|page| self.inode(*page) == INODE_FREE)
+            .take((note.len() - NOTE_ENTRY_SIZE) / PAGE_SIZE)
+            .collect();
+        if pages.len() < (note.len() - NOTE_ENTRY_SIZE) / PAGE_SIZE {
+            return Err(invalid("Not enough free pages"));
+        }
+        for (i, page) in pages.iter().enumerate() {
+            let source = NOTE_ENTRY_SIZE + i * PAGE_SIZE;
+            self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].copy_from_slice(&note[source..source + PAGE_SIZE]);
+            let next = pages.get(i + 1).map_or(INODE_LAST_PAGE, |next| *next as u16);
+            self.set_inode(*page, next);
+        }
+        let entry = NOTE_TABLE + index * NOTE_ENTRY_SIZE;
+        self.data[entry..entry + NOTE_ENTRY_SIZE].copy_from_slice(&note[..NOTE_ENTRY_SIZE]);
+        self.data[entry + 6..entry + 8].copy_from_slice(&(pages[0] as u16).to_be_bytes());
+        self.update_inode_checksum();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod controller_pak_tests {
+    use super::*;
+
+    fn empty_pak() -> ControllerPak {
+        let mut pak = ControllerPak::new(vec![0; CONTROLLER_PAK_SIZE]).unwrap();
+        for page in FIRST_DATA_PAGE..PAGES {
+            pak.set_inode(page, INODE_FREE);
+        }
+        pak.update_inode_checksum();
+        pak
+    }
+
+    #[test]
+    fn test_import_export() {
+        let mut pak = empty_pak();
+        let mut note = vec![0; NOTE_ENTRY_SIZE + PAGE_SIZE * 2];
+        note[..6].copy_from_slice(b"NSME01");
+        note[0x10..0x15].copy_from_slice(&[0x1E, 0x1A, 0x2B, 0x2C, 0x10]);
+        note[NOTE_ENTRY_SIZE..].iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+        pak.import(&note).unwrap();
+        let notes = pak.notes();
+        assert_eq!(notes, vec![Note {
+            index: 0,
+            game_code: String::from("NSME"),
+            publisher_code: String::from("01"),
+            name: String::from("EARS0"),
+            pages: 2,
+        }]);
+        assert_eq!(pak.free_pages(), PAGES - FIRST_DATA_PAGE - 2);
+        assert_eq!(&pak.export(0)[NOTE_ENTRY_SIZE..], &note[NOTE_ENTRY_SIZE..]);
+        assert_eq!(&pak.data()[INODE_TABLE..INODE_TABLE + PAGE_SIZE], &pak.data()[INODE_TABLE_BACKUP..INODE_TABLE_BACKUP + PAGE_SIZE]);
+
+        pak.delete(0);
+        assert!(pak.notes().is_empty());
+        assert_eq!(pak.free_pages(), PAGES - FIRST_DATA_PAGE);
+        assert!(pak.import(&note[..40]).is_err());
+    }
+}
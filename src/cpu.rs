@@ -1,5 +1,11 @@
-use crate::registers::{CPURegisters, CP0Registers};
+use std::io;
+
+use crate::registers::{CPURegisters, CP0Registers, CP1Registers};
 use crate::mmu::{MMU};
+use crate::tlb::TLBEntry;
+use crate::log::{log, Level, Subsystem};
+use crate::exception::{Exception, CAUSE_BD, EXCEPTION_INTERRUPT, EXCEPTION_SYSCALL, EXCEPTION_BREAKPOINT, EXCEPTION_OVERFLOW, EXCEPTION_INSTRUCTION_BUS_ERROR, EXCEPTION_DATA_BUS_ERROR};
+use crate::savestate::{StateReader, StateWriter};
 
 pub fn params_rd_rs_rt(opcode: u32) -> (usize, usize, usize) {
     let rd = (opcode >> 11) & 0b11111;
@@ -82,6 +88,12 @@ pub fn params_target(opcode: u32) -> i32 {
 pub struct CPU {
     registers: CPURegisters,
     cp0: CP0Registers,
+    cp1: CP1Registers,
+    // Address of the instruction being executed, exceptions return to it
+    instruction_address: i64,
+    // Set when that instruction is in the delay slot of a taken branch, exceptions return to the branch instead
+    delay_slot: bool,
+    last_exception: Option<Exception>,
 }
 
 impl CPU {
@@ -89,6 +101,10 @@ impl CPU {
         Self {
             registers: CPURegisters::new(),
             cp0: CP0Registers::new(),
+            cp1: CP1Registers::new(),
+            instruction_address: 0,
+            delay_slot: false,
+            last_exception: None,
         }
     }
 
@@ -96,6 +112,10 @@ impl CPU {
         Self {
             registers: CPURegisters::new_hle(),
             cp0: CP0Registers::new_hle(),
+            cp1: CP1Registers::new(),
+            instruction_address: 0,
+            delay_slot: false,
+            last_exception: None,
         }
     }
 
@@ -103,18 +123,139 @@ impl CPU {
         &self.registers
     }
 
+    pub fn mut_registers(&mut self) -> &mut CPURegisters {
+        &mut self.registers
+    }
+
+    pub fn cp0(&self) -> &CP0Registers {
+        &self.cp0
+    }
+
+    pub fn mut_cp0(&mut self) -> &mut CP0Registers {
+        &mut self.cp0
+    }
+
+    pub fn cp1(&self) -> &CP1Registers {
+        &self.cp1
+    }
+
+    pub fn mut_cp1(&mut self) -> &mut CP1Registers {
+        &mut self.cp1
+    }
+
+    // Address of the last instruction that was fetched
+    pub fn instruction_address(&self) -> i64 {
+        self.instruction_address
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        self.cp0.save_state(writer);
+        self.cp1.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.registers.load_state(reader)?;
+        self.cp0.load_state(reader)?;
+        self.cp1.load_state(reader)
+    }
+
     pub fn fetch_opcode(address: i64, mmu: &MMU) -> u32 {
-        let data = mmu.read_virtual(address, 4);
+        let data = mmu.fetch_virtual(address, 4);
         let opcode = ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | ((data[3] as u32) << 8);
         opcode
     }
 
     pub fn fetch_and_exec_opcode(&mut self, mmu: &mut MMU) {
+        self.instruction_address = self.registers.get_program_counter();
+        // Branches and jumps point the next PC somewhere else while their delay slot runs
+        self.delay_slot = self.registers.get_next_program_counter() != self.instruction_address.wrapping_add(4);
+        if self.interrupt_pending() {
+            self.raise_exception(EXCEPTION_INTERRUPT);
+            return;
+        }
+        // Accesses the debuggers made since the last instruction don't count
+        mmu.take_bus_error();
         let opcode = CPU::fetch_opcode(self.registers.get_program_counter(), mmu); // use pc to fetch the opcode
+        if mmu.take_bus_error() {
+            self.raise_exception(EXCEPTION_INSTRUCTION_BUS_ERROR);
+            return;
+        }
         let next_pc = self.registers.get_next_program_counter();
         self.registers.set_program_counter(next_pc);
         self.registers.set_next_program_counter(next_pc.wrapping_add(4));
         self.exec_opcode(opcode, mmu);
+        // Loads have already written the 0 they read to their register by now
+        if mmu.take_bus_error() {
+            self.raise_exception(EXCEPTION_DATA_BUS_ERROR);
+        }
+    }
+
+    // Cause IP bits enabled in the Status IM mask, while IE is set and no exception is being handled
+    fn interrupt_pending(&self) -> bool {
+        let status = self.cp0.get_by_number_32(12);
+        let cause = self.cp0.get_by_number_32(13);
+        status & 0b111 == 0b001 && (status & cause & 0xFF00) != 0
+    }
+
+    /*
+        Jumps to the general exception vector, TLB refill is not implemented yet. In a delay slot EPC
+        points to the branch and Cause.BD is set, so ERET runs the branch again instead of losing it.
+        https://n64brew.dev/wiki/COP0#Exceptions
+    */
+    pub fn raise_exception(&mut self, code: u8) {
+        let mut status = self.cp0.get_by_number_32(12);
+        let mut cause = (self.cp0.get_by_number_32(13) & !0x7C) | ((code as i32) << 2);
+        if status & 0b10 == 0 {
+            let epc = match self.delay_slot {
+                true => {
+                    cause |= CAUSE_BD;
+                    self.instruction_address.wrapping_sub(4)
+                },
+                false => {
+                    cause &= !CAUSE_BD;
+                    self.instruction_address
+                },
+            };
+            self.cp0.set_by_number_64(14, epc);
+            status |= 0b10;
+            self.cp0.set_by_number_32(12, status);
+        }
+        self.cp0.set_by_number_32(13, cause);
+        let vector = match status & (1 << 22) != 0 {
+            true => 0xFFFFFFFFBFC00380_u64 as i64,
+            false => 0xFFFFFFFF80000180_u64 as i64,
+        };
+        self.registers.set_program_counter(vector);
+        self.registers.set_next_program_counter(vector.wrapping_add(4));
+        self.last_exception = Some(Exception {
+            code,
+            program_counter: self.instruction_address,
+            cause: cause as u32,
+            vector: vector & 0xFFFFFFFF,
+        });
+    }
+
+    // The emulator collects the exceptions raised by every instruction for the debugger
+    pub fn take_exception(&mut self) -> Option<Exception> {
+        self.last_exception.take()
+    }
+
+    pub fn eret(&mut self) {
+        let status = self.cp0.get_by_number_32(12);
+        let program_counter = match status & 0b100 != 0 {
+            true => {
+                self.cp0.set_by_number_32(12, status & !0b100);
+                self.cp0.get_by_number_64(30)
+            },
+            false => {
+                self.cp0.set_by_number_32(12, status & !0b10);
+                self.cp0.get_by_number_64(14)
+            },
+        };
+        self.registers.set_program_counter(program_counter);
+        self.registers.set_next_program_counter(program_counter.wrapping_add(4));
+        self.registers.set_load_link(false);
     }
 
     pub fn exec_opcode(&mut self, opcode: u32, mmu: &mut MMU) {
@@ -129,7 +270,7 @@ impl CPU {
                         let (rd, rs, rt) = params_rd_rs_rt(opcode);
                         let res = self.add(rd, rs, rt);
                         if let Err(_) = res {
-                            todo!("Throw exception for add overflow ADD");
+                            self.raise_exception(EXCEPTION_OVERFLOW);
                         }
                     },
                     // ADDU
@@ -144,14 +285,14 @@ impl CPU {
                     },
                     // BREAK
                     0b001101 => {
-                        todo!("BREAK instruction");
+                        self.raise_exception(EXCEPTION_BREAKPOINT);
                     },
                     // DADD
                     0b101100 => {
                         let (rd, rs, rt) = params_rd_rs_rt(opcode);
                         let res = self.dadd(rd, rs, rt);
                         if let Err(_) = res {
-                            todo!("Throw exception for add overflow DADD");
+                            self.raise_exception(EXCEPTION_OVERFLOW);
                         }
                     },
                     // DADDU
@@ -239,7 +380,7 @@ impl CPU {
                         let (rd, rs, rt) = params_rd_rs_rt(opcode);
                         let res = self.dsub(rd, rs, rt);
                         if let Err(_) = res {
-                            todo!("Throw exception for sub overflow DSUB");
+                            self.raise_exception(EXCEPTION_OVERFLOW);
                         }
                     },
                     // DSUBU
@@ -331,7 +472,7 @@ impl CPU {
                         let (rd, rs, rt) = params_rd_rs_rt(opcode);
                         let res = self.sub(rd, rs, rt);
                         if let Err(_) = res {
-                            todo!("Throw exception for sub overflow SUB");
+                            self.raise_exception(EXCEPTION_OVERFLOW);
                         }
                     },
                     // SUBU
@@ -344,6 +485,7 @@ impl CPU {
                     },
                     // SYSCALL
                     0b001100 => {
+                        self.raise_exception(EXCEPTION_SYSCALL);
                     },
                     // TEQ
                     0b110100 => {
@@ -441,7 +583,7 @@ impl CPU {
                 let res = self.daddi(rt, rs, immediate);
                 if inst == 0b0110_00 {
                     if let Err(_) = res {
-                        todo!("Throw exception for add overflow DADDI");
+                        self.raise_exception(EXCEPTION_OVERFLOW);
                     }
                 }
             },
@@ -456,7 +598,7 @@ impl CPU {
                 let res = self.addi(rt, rs, immediate);
                 if inst == 0b0010_00 {
                     if let Err(_) = res {
-                        todo!("Throw exception for add overflow ADDI");
+                        self.raise_exception(EXCEPTION_OVERFLOW);
                     }
                 }
             },
@@ -502,6 +644,7 @@ impl CPU {
                     0b00101 => {
                         let (rt, rd) = params_rt_rd(opcode);
                         self.dmtc0(rt, rd);
+                        self.sync_asid(mmu);
                     },
                     // MFC0
                     0b00000 => {
@@ -512,29 +655,76 @@ impl CPU {
                     0b00100 => {
                         let (rt, rd) = params_rt_rd(opcode);
                         self.mtc0(rt, rd);
+                        self.sync_asid(mmu);
                     },
                     _ => {
                         match opcode & 0b111111 {
                             // ERET
                             0b011000 => {
+                                self.eret();
                             },
                             // TLBP
                             0b001000 => {
+                                self.tlbp(mmu);
                             },
                             // TLBR
                             0b000001 => {
+                                self.tlbr(mmu);
                             },
                             // TLBWI
                             0b000010 => {
+                                let index = self.cp0.get_by_name_32("index") as usize;
+                                self.tlbw(index, mmu);
                             },
                             // TLBWR
                             0b000110 => {
+                                let index = self.cp0.get_by_name_32("random") as usize;
+                                self.tlbw(index, mmu);
                             },
                             _ => unimplemented!(),
                         };
                     },
                 };
             },
+            // COP1, only the moves between registers, the arithmetic isn't implemented yet
+            0b010001 => {
+                let (rt, fs) = params_rt_rd(opcode);
+                match (opcode >> 21) & 0b11111 {
+                    // MFC1
+                    0b00000 => self.mfc1(rt, fs),
+                    // DMFC1
+                    0b00001 => self.dmfc1(rt, fs),
+                    // CFC1
+                    0b00010 => self.cfc1(rt, fs),
+                    // MTC1
+                    0b00100 => self.mtc1(rt, fs),
+                    // DMTC1
+                    0b00101 => self.dmtc1(rt, fs),
+                    // CTC1
+                    0b00110 => self.ctc1(rt, fs),
+                    _ => unimplemented!(),
+                };
+            },
+            // LWC1
+            0b110001 => {
+                let (ft, offset, base) = params_rt_offset_base(opcode);
+                self.lwc1(ft, offset, base, mmu);
+            },
+            // LDC1
+            0b110101 => {
+                let (ft, offset, base) = params_rt_offset_base(opcode);
+                self.ldc1(ft, offset, base, mmu);
+            },
+            // SWC1
+            0b111001 => {
+                let (ft, offset, base) = params_rt_offset_base(opcode);
+                self.swc1(ft, offset, base, mmu);
+            },
+            // SDC1
+            0b111101 => {
+                let (ft, offset, base) = params_rt_offset_base(opcode);
+                self.sdc1(ft, offset, base, mmu);
+            },
             // LB
             0b100000 => {
                 let (rt, offset, base) = params_rt_offset_base(opcode);
@@ -668,18 +858,22 @@ impl CPU {
         let t = self.registers.get_by_number(rt) as i32;
         let result = s.wrapping_add(t) as i64;
         let will_overflow = s.checked_add(t);
-        self.registers.set_by_number(rd, result);
+        // Overflow traps leave the destination as it was
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rd, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
 
     pub fn addu(&mut self, rd: usize, rs: usize, rt: usize) {
-        let s = (self.registers.get_by_number(rs) as i32) as u32;
-        let t = (self.registers.get_by_number(rt) as i32) as u32;
-        let result = s.wrapping_add(t) as u64;
-        self.registers.set_by_number(rd, result as i64);
+        let s = self.registers.get_by_number(rs) as i32;
+        let t = self.registers.get_by_number(rt) as i32;
+        // The 32 bit result is sign extended like every other word operation
+        let result = s.wrapping_add(t) as i64;
+        self.registers.set_by_number(rd, result);
     }
 
     pub fn addi(&mut self, rt: usize, rs: usize, immediate: i16) -> Result<i64, i64> {
@@ -687,9 +881,11 @@ impl CPU {
         let immediate = immediate as i32;
         let result = s.wrapping_add(immediate) as i64;
         let will_overflow = s.checked_add(immediate);
-        self.registers.set_by_number(rt, result);
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rt, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
@@ -706,9 +902,11 @@ impl CPU {
         let t = self.registers.get_by_number(rt);
         let result = s.wrapping_add(t);
         let will_overflow = s.checked_add(t);
-        self.registers.set_by_number(rd, result);
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rd, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
@@ -725,9 +923,11 @@ impl CPU {
         let immediate = immediate as i64;
         let result = s.wrapping_add(immediate);
         let will_overflow = s.checked_add(immediate);
-        self.registers.set_by_number(rt, result);
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rt, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
@@ -744,9 +944,11 @@ impl CPU {
         let t = self.registers.get_by_number(rt) as i32;
         let result = s.wrapping_sub(t) as i64;
         let will_overflow = s.checked_sub(t);
-        self.registers.set_by_number(rd, result);
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rd, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
@@ -763,9 +965,11 @@ impl CPU {
         let t = self.registers.get_by_number(rt);
         let result = s.wrapping_sub(t);
         let will_overflow = s.checked_sub(t);
-        self.registers.set_by_number(rd, result);
         match will_overflow {
-            Some(_) => Ok(result),
+            Some(_) => {
+                self.registers.set_by_number(rd, result);
+                Ok(result)
+            },
             None => Err(result),
         }
     }
@@ -777,11 +981,19 @@ impl CPU {
         self.registers.set_by_number(rd, result as i64);
     }
 
+    /*
+        The remainder takes the sign of the dividend. Dividing by zero doesn't trap: the quotient is
+        -1, or 1 for a negative signed dividend, and the remainder is the dividend.
+        32 bit results are sign extended into LO and HI.
+        https://n64brew.dev/wiki/MIPS_III_instructions#Division
+    */
     pub fn div(&mut self, rs: usize, rt: usize) {
         let s = self.registers.get_by_number(rs) as i32;
         let t = self.registers.get_by_number(rt) as i32;
-        let quotient = s.wrapping_div(t);
-        let remainder = s.wrapping_rem_euclid(t);
+        let (quotient, remainder) = match t {
+            0 => (if s < 0 { 1 } else { -1 }, s),
+            _ => (s.wrapping_div(t), s.wrapping_rem(t)),
+        };
         self.registers.set_lo(quotient as i64);
         self.registers.set_hi(remainder as i64);
     }
@@ -789,8 +1001,10 @@ impl CPU {
     pub fn ddiv(&mut self, rs: usize, rt: usize) {
         let s = self.registers.get_by_number(rs);
         let t = self.registers.get_by_number(rt);
-        let quotient = s.wrapping_div(t);
-        let remainder = s.wrapping_rem_euclid(t);
+        let (quotient, remainder) = match t {
+            0 => (if s < 0 { 1 } else { -1 }, s),
+            _ => (s.wrapping_div(t), s.wrapping_rem(t)),
+        };
         self.registers.set_lo(quotient);
         self.registers.set_hi(remainder);
     }
@@ -798,8 +1012,10 @@ impl CPU {
     pub fn divu(&mut self, rs: usize, rt: usize) {
         let s = self.registers.get_by_number(rs) as u32;
         let t = self.registers.get_by_number(rt) as u32;
-        let quotient = s.wrapping_div(t);
-        let remainder = s.wrapping_rem_euclid(t);
+        let (quotient, remainder) = match t {
+            0 => (u32::MAX, s),
+            _ => (s / t, s % t),
+        };
         self.registers.set_lo((quotient as i32) as i64);
         self.registers.set_hi((remainder as i32) as i64);
     }
@@ -807,41 +1023,44 @@ impl CPU {
     pub fn ddivu(&mut self, rs: usize, rt: usize) {
         let s = self.registers.get_by_number(rs) as u64;
         let t = self.registers.get_by_number(rt) as u64;
-        let quotient = s.wrapping_div(t);
-        let remainder = s.wrapping_rem_euclid(t);
+        let (quotient, remainder) = match t {
+            0 => (u64::MAX, s),
+            _ => (s / t, s % t),
+        };
         self.registers.set_lo(quotient as i64);
         self.registers.set_hi(remainder as i64);
     }
 
+    // The full product is kept, LO gets the low half and HI the high one, each sign extended
     pub fn mult(&mut self, rs: usize, rt: usize) {
         let s = (self.registers.get_by_number(rs) as i32) as i64;
         let t = (self.registers.get_by_number(rt) as i32) as i64;
         let result = s * t;
-        self.registers.set_lo(result & 0x000000FFFFFF);
-        self.registers.set_hi(result >> 32);
+        self.registers.set_lo((result as i32) as i64);
+        self.registers.set_hi(((result >> 32) as i32) as i64);
     }
 
     pub fn dmult(&mut self, rs: usize, rt: usize) {
         let s = self.registers.get_by_number(rs) as i128;
         let t = self.registers.get_by_number(rt) as i128;
         let result = s * t;
-        self.registers.set_lo((result & 0xFFFFFFFFFFFF) as i64);
+        self.registers.set_lo(result as i64);
         self.registers.set_hi((result >> 64) as i64);
     }
 
     pub fn multu(&mut self, rs: usize, rt: usize) {
-        let s = self.registers.get_by_number(rs) as u64;
-        let t = self.registers.get_by_number(rt) as u64;
+        let s = (self.registers.get_by_number(rs) as u32) as u64;
+        let t = (self.registers.get_by_number(rt) as u32) as u64;
         let result = s * t;
-        self.registers.set_lo((result & 0x000000FFFFFF) as i64);
-        self.registers.set_hi((result >> 32) as i64);
+        self.registers.set_lo((result as i32) as i64);
+        self.registers.set_hi(((result >> 32) as i32) as i64);
     }
 
     pub fn dmultu(&mut self, rs: usize, rt: usize) {
-        let s = self.registers.get_by_number(rs) as u128;
-        let t = self.registers.get_by_number(rt) as u128;
+        let s = (self.registers.get_by_number(rs) as u64) as u128;
+        let t = (self.registers.get_by_number(rt) as u64) as u128;
         let result = s * t;
-        self.registers.set_lo((result & 0xFFFFFFFFFFFF) as i64);
+        self.registers.set_lo(result as i64);
         self.registers.set_hi((result >> 64) as i64);
     }
 
@@ -1029,8 +1248,11 @@ impl CPU {
         self.registers.set_lo(self.registers.get_by_number(rs));
     }
 
+    // Writing Compare acknowledges the timer interrupt, Config keeps its hardwired bits
     pub fn mtc0(&mut self, rt: usize, rd: usize) {
         match CP0Registers::is_32bits(rd) {
+            true if rd == 11 => self.cp0.set_compare(self.registers.get_by_number(rt) as i32),
+            true if rd == 16 => self.cp0.set_config(self.registers.get_by_number(rt) as i32),
             true => self.cp0.set_by_number_32(rd, self.registers.get_by_number(rt) as i32),
             false => self.cp0.set_by_number_64(rd, self.registers.get_by_number(rt)),
         };
@@ -1043,8 +1265,43 @@ impl CPU {
         };
     }
 
+    // The TLB needs the ASID of EntryHi to match non global entries
+    fn sync_asid(&self, mmu: &mut MMU) {
+        mmu.mut_tlb().set_asid(self.cp0.get_by_name_64("EntryHi") as u8);
+    }
+
+    pub fn tlbp(&mut self, mmu: &MMU) {
+        let index = match mmu.tlb().probe(self.cp0.get_by_name_64("EntryHi") as u64) {
+            Some(index) => index as i32,
+            None => 0x80000000_u32 as i32,
+        };
+        self.cp0.set_by_name_32("index", index);
+    }
+
+    pub fn tlbr(&mut self, mmu: &mut MMU) {
+        let entry = mmu.tlb().read(self.cp0.get_by_name_32("index") as usize & 0x1F);
+        let global = entry.global() as u64;
+        self.cp0.set_by_name_32("PageMask", entry.page_mask as i32);
+        self.cp0.set_by_name_64("EntryHi", entry.entry_hi as i64);
+        self.cp0.set_by_name_64("EntryLo0", ((entry.entry_lo_0 & !1) | global) as i64);
+        self.cp0.set_by_name_64("EntryLo1", ((entry.entry_lo_1 & !1) | global) as i64);
+        self.sync_asid(mmu);
+    }
+
+    pub fn tlbw(&mut self, index: usize, mmu: &mut MMU) {
+        let entry = TLBEntry {
+            page_mask: self.cp0.get_by_name_32("PageMask") as u32,
+            entry_hi: self.cp0.get_by_name_64("EntryHi") as u64,
+            entry_lo_0: self.cp0.get_by_name_64("EntryLo0") as u64,
+            entry_lo_1: self.cp0.get_by_name_64("EntryLo1") as u64,
+        };
+        mmu.mut_tlb().write(index & 0x1F, entry);
+    }
+
     pub fn dmtc0(&mut self, rt: usize, rd: usize) {
         match CP0Registers::is_32bits(rd) {
+            true if rd == 11 => self.cp0.set_compare(self.registers.get_by_number(rt) as i32),
+            true if rd == 16 => self.cp0.set_config(self.registers.get_by_number(rt) as i32),
             true => self.cp0.set_by_number_32(rd, self.registers.get_by_number(rt) as i32),
             false => self.cp0.set_by_number_64(rd, self.registers.get_by_number(rt)),
         };
@@ -1057,6 +1314,67 @@ impl CPU {
         };
     }
 
+    // The FPRs seen by COP1 instructions depend on FR in Status, see CP1Registers
+    pub fn mfc1(&mut self, rt: usize, fs: usize) {
+        let value = self.cp1.get_fpr_32(fs, self.cp0.fr());
+        self.registers.set_by_number(rt, value as i64);
+    }
+
+    pub fn dmfc1(&mut self, rt: usize, fs: usize) {
+        let value = self.cp1.get_fpr_64(fs, self.cp0.fr());
+        self.registers.set_by_number(rt, value);
+    }
+
+    pub fn mtc1(&mut self, rt: usize, fs: usize) {
+        let fr = self.cp0.fr();
+        self.cp1.set_fpr_32(fs, self.registers.get_by_number(rt) as i32, fr);
+    }
+
+    pub fn dmtc1(&mut self, rt: usize, fs: usize) {
+        let fr = self.cp0.fr();
+        self.cp1.set_fpr_64(fs, self.registers.get_by_number(rt), fr);
+    }
+
+    // FCR0 is the implementation and revision, FCR31 the control and status register
+    pub fn cfc1(&mut self, rt: usize, fs: usize) {
+        let value = match fs {
+            0 => self.cp1.get_fcr0(),
+            31 => self.cp1.get_fcr31(),
+            _ => 0,
+        };
+        self.registers.set_by_number(rt, value as i64);
+    }
+
+    pub fn ctc1(&mut self, rt: usize, fs: usize) {
+        if fs == 31 {
+            self.cp1.set_fcr31(self.registers.get_by_number(rt) as i32);
+        }
+    }
+
+    pub fn lwc1(&mut self, ft: usize, offset: i16, base: usize, mmu: &MMU) {
+        let address = self.registers.get_by_number(base).wrapping_add(offset as i64);
+        let data = i32::from_be_bytes(mmu.read_virtual(address, 4).try_into().unwrap());
+        let fr = self.cp0.fr();
+        self.cp1.set_fpr_32(ft, data, fr);
+    }
+
+    pub fn ldc1(&mut self, ft: usize, offset: i16, base: usize, mmu: &MMU) {
+        let address = self.registers.get_by_number(base).wrapping_add(offset as i64);
+        let data = i64::from_be_bytes(mmu.read_virtual(address, 8).try_into().unwrap());
+        let fr = self.cp0.fr();
+        self.cp1.set_fpr_64(ft, data, fr);
+    }
+
+    pub fn swc1(&mut self, ft: usize, offset: i16, base: usize, mmu: &mut MMU) {
+        let address = self.registers.get_by_number(base).wrapping_add(offset as i64);
+        mmu.write_virtual(address, &self.cp1.get_fpr_32(ft, self.cp0.fr()).to_be_bytes());
+    }
+
+    pub fn sdc1(&mut self, ft: usize, offset: i16, base: usize, mmu: &mut MMU) {
+        let address = self.registers.get_by_number(base).wrapping_add(offset as i64);
+        mmu.write_virtual(address, &self.cp1.get_fpr_64(ft, self.cp0.fr()).to_be_bytes());
+    }
+
     pub fn lb(&mut self, rt: usize, offset: i16, base: usize, mmu: &MMU) {
         let address = self.registers.get_by_number(base) + (offset as i64);
         let data = mmu.read_virtual(address, 1);
@@ -1090,34 +1408,28 @@ impl CPU {
         self.registers.set_by_number(rt, (data as i32) as i64)
     }
 
+    /*
+        LWL loads the bytes from the address up to the end of its word into the left of the register,
+        LWR the bytes from the start of the word up to the address into the right.
+        https://n64brew.dev/wiki/MIPS_III_instructions#LWL
+    */
     pub fn lwl(&mut self, rt: usize, offset: i16, base: usize, mmu: &MMU) {
         let address = self.registers.get_by_number(base) + (offset as i64);
-        let bytes_to_read = (4 - (address % 4)) as usize;
+        let shift = (address & 0b11) * 8;
         let t = self.registers.get_by_number(rt) as u32;
-        let data = mmu.read_virtual(address, bytes_to_read);
-        let mut result: u32 = 0;
-        let mut bitmask: u32 = 0xFFFFFFFF;
-        for byte in data {
-            result = (result << 8) | (byte as u32);
-            bitmask = bitmask >> 8;
-        }
-        let left = 4 - bytes_to_read;
-        let result = ((t & bitmask) | (result << left)) as i32;
+        let data = u32::from_be_bytes(mmu.read_virtual(address & !0b11, 4).try_into().unwrap());
+        let kept = !(0xFFFFFFFFu32 << shift);
+        let result = ((t & kept) | (data << shift)) as i32;
         self.registers.set_by_number(rt, result as i64)
     }
 
     pub fn lwr(&mut self, rt: usize, offset: i16, base: usize, mmu: &MMU) {
         let address = self.registers.get_by_number(base) + (offset as i64);
-        let bytes_to_read = (4 - (address % 4)) as usize;
+        let shift = (3 - (address & 0b11)) * 8;
         let t = self.registers.get_by_number(rt) as u32;
-        let data = mmu.read_virtual(address, bytes_to_read);
-        let mut result: u32 = 0;
-        let mut bitmask: u32 = 0xFFFFFFFF;
-        for byte in data {
-            result = (result << 8) | (byte as u32);
-            bitmask = bitmask << 8;
-        }
-        let result = ((t & bitmask) | result) as i32;
+        let data = u32::from_be_bytes(mmu.read_virtual(address & !0b11, 4).try_into().unwrap());
+        let kept = !(0xFFFFFFFFu32 >> shift);
+        let result = ((t & kept) | (data >> shift)) as i32;
         self.registers.set_by_number(rt, result as i64)
     }
 
@@ -1249,7 +1561,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BEQL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BEQL nullify current instruction");
         }
     }
 
@@ -1279,7 +1591,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BGEZALL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BGEZALL nullify current instruction");
         }
     }
 
@@ -1289,7 +1601,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BGEZL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BGEZL nullify current instruction");
         }
     }
 
@@ -1307,7 +1619,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BGTZL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BGTZL nullify current instruction");
         }
     }
 
@@ -1325,7 +1637,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BGEZL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BGEZL nullify current instruction");
         }
     }
 
@@ -1355,7 +1667,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BLTZALL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BLTZALL nullify current instruction");
         }
     }
 
@@ -1365,7 +1677,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BLTZL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BLTZL nullify current instruction");
         }
     }
 
@@ -1385,7 +1697,7 @@ impl CPU {
             let offset = (((offset << 2) as u64) as i64) | ((((offset as u16) & 0x8000) as i16) as i64);
             self.registers.increment_next_program_counter(offset);
         } else {
-            println!("BNEL nullify current instruction");
+            log!(Level::Debug, Subsystem::CPU, "BNEL nullify current instruction");
         }
     }
 }
@@ -1393,6 +1705,8 @@ impl CPU {
 #[cfg(test)]
 mod cpu_instructions_tests {
     use super::*;
+    use crate::mmu::UnmappedPolicy;
+    use crate::registers::STATUS_FR;
 
     #[test]
     fn test_add() {
@@ -1414,7 +1728,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_t, 1);
         let res = cpu.add(reg_dest, reg_s, reg_t);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest) as i32, i32::MIN);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), -40);
     }
 
     #[test]
@@ -1433,7 +1747,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_s, i32::MAX as i64);
         let res = cpu.addi(reg_dest, reg_s, 1);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest) as i32, i32::MIN);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), 40);
     }
 
     #[test]
@@ -1456,7 +1770,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_t, 1);
         let res = cpu.dadd(reg_dest, reg_s, reg_t);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest), i64::MIN);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), -40);
     }
 
     #[test]
@@ -1475,7 +1789,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_s, i64::MAX);
         let res = cpu.daddi(reg_dest, reg_s, 1);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest), i64::MIN);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), 40);
     }
 
     #[test]
@@ -1498,7 +1812,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_t, 1);
         let res = cpu.sub(reg_dest, reg_s, reg_t);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest) as i32, i32::MAX);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), 120);
     }
 
     #[test]
@@ -1521,7 +1835,7 @@ mod cpu_instructions_tests {
         cpu.registers.set_by_number(reg_t, 1);
         let res = cpu.dsub(reg_dest, reg_s, reg_t);
         assert!(res.is_err());
-        assert_eq!(cpu.registers.get_by_number(reg_dest), i64::MAX);
+        assert_eq!(cpu.registers.get_by_number(reg_dest), 120);
     }
 
     #[test]
@@ -1584,6 +1898,92 @@ mod cpu_instructions_tests {
         assert_eq!(cpu.registers.get_hi(), 0);
     }
 
+    #[test]
+    fn test_mult_edge_cases() {
+        let mut cpu = CPU::new();
+        let reg_s = 15;
+        let reg_t = 20;
+        // (s, t, signed lo, signed hi, unsigned lo, unsigned hi), only the low words are used
+        let cases: [(i64, i64, i64, i64, i64, i64); 7] = [
+            (-1, 1, -1, -1, -1, 0),
+            (-1, -1, 1, 0, 1, -2),
+            (0x80000000, 0x80000000, 0, 0x40000000, 0, 0x40000000),
+            (0x80000000, -1, 0xFFFFFFFF80000000u64 as i64, 0, 0xFFFFFFFF80000000u64 as i64, 0x7FFFFFFF),
+            (0x7FFFFFFF, 0x7FFFFFFF, 1, 0x3FFFFFFF, 1, 0x3FFFFFFF),
+            (0x12345678, 0x10000, 0x56780000, 0x1234, 0x56780000, 0x1234),
+            (0x1_0000_0003, 0xFFFF_0000_0002, 6, 0, 6, 0),
+        ];
+        for (s, t, lo, hi, lo_unsigned, hi_unsigned) in cases {
+            cpu.registers.set_by_number(reg_s, s);
+            cpu.registers.set_by_number(reg_t, t);
+            cpu.mult(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (lo, hi), "mult {:X} {:X}", s, t);
+            cpu.multu(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (lo_unsigned, hi_unsigned), "multu {:X} {:X}", s, t);
+        }
+    }
+
+    #[test]
+    fn test_dmult_edge_cases() {
+        let mut cpu = CPU::new();
+        let reg_s = 15;
+        let reg_t = 20;
+        let cases: [(i64, i64, i64, i64, i64, i64); 5] = [
+            (-1, 1, -1, -1, -1, 0),
+            (-1, -1, 1, 0, 1, -2),
+            (i64::MIN, i64::MIN, 0, 0x4000000000000000, 0, 0x4000000000000000),
+            (i64::MIN, -1, i64::MIN, 0, i64::MIN, i64::MAX),
+            (i64::MAX, i64::MAX, 1, 0x3FFFFFFFFFFFFFFF, 1, 0x3FFFFFFFFFFFFFFF),
+        ];
+        for (s, t, lo, hi, lo_unsigned, hi_unsigned) in cases {
+            cpu.registers.set_by_number(reg_s, s);
+            cpu.registers.set_by_number(reg_t, t);
+            cpu.dmult(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (lo, hi), "dmult {:X} {:X}", s, t);
+            cpu.dmultu(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (lo_unsigned, hi_unsigned), "dmultu {:X} {:X}", s, t);
+        }
+    }
+
+    #[test]
+    fn test_div_edge_cases() {
+        let mut cpu = CPU::new();
+        let reg_s = 15;
+        let reg_t = 20;
+        // The remainder keeps the sign of the dividend
+        cpu.registers.set_by_number(reg_s, -7);
+        cpu.registers.set_by_number(reg_t, 2);
+        cpu.div(reg_s, reg_t);
+        assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (-3, -1));
+        cpu.ddiv(reg_s, reg_t);
+        assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (-3, -1));
+
+        // Overflows wrap instead of trapping
+        cpu.registers.set_by_number(reg_s, 0x80000000);
+        cpu.registers.set_by_number(reg_t, -1);
+        cpu.div(reg_s, reg_t);
+        assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (i32::MIN as i64, 0));
+        cpu.divu(reg_s, reg_t);
+        assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (0, i32::MIN as i64));
+        cpu.registers.set_by_number(reg_s, i64::MIN);
+        cpu.ddiv(reg_s, reg_t);
+        assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (i64::MIN, 0));
+
+        // Dividing by zero
+        cpu.registers.set_by_number(reg_t, 0);
+        for (s, quotient) in [(5, -1), (-5, 1), (0, -1)] {
+            cpu.registers.set_by_number(reg_s, s);
+            cpu.div(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (quotient, s));
+            cpu.ddiv(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (quotient, s));
+            cpu.divu(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (-1, s));
+            cpu.ddivu(reg_s, reg_t);
+            assert_eq!((cpu.registers.get_lo(), cpu.registers.get_hi()), (-1, s));
+        }
+    }
+
     #[test]
     fn test_and() {
         let mut cpu = CPU::new();
@@ -1969,6 +2369,108 @@ mod cpu_instructions_tests {
         assert_eq!(cpu.cp0.get_by_number_32(rd), 65535);
     }
 
+    #[test]
+    fn test_exception_and_eret() {
+        let mut cpu = CPU::new_hle();
+        let mut mmu = MMU::new();
+        cpu.cp0.set_by_name_32("status", 0);
+        // SYSCALL
+        cpu.instruction_address = 0x80001000;
+        cpu.exec_opcode(0x0000000C, &mut mmu);
+        assert_eq!(cpu.registers.get_program_counter() & 0xFFFFFFFF, 0x80000180);
+        assert_eq!(cpu.cp0.get_by_name_64("epc"), 0x80001000);
+        assert_eq!((cpu.cp0.get_by_name_32("cause") >> 2) & 0x1F, EXCEPTION_SYSCALL as i32);
+        assert_eq!(cpu.cp0.get_by_name_32("status") & 0b10, 0b10);
+        assert_eq!(cpu.take_exception().map(|exception| exception.vector), Some(0x80000180));
+        assert_eq!(cpu.take_exception(), None);
+
+        cpu.eret();
+        assert_eq!(cpu.registers.get_program_counter(), 0x80001000);
+        assert_eq!(cpu.cp0.get_by_name_32("status") & 0b10, 0);
+
+        // Software interrupt 0 enabled in the mask
+        cpu.cp0.set_by_name_32("status", 0x101);
+        cpu.cp0.set_by_name_32("cause", 0x100);
+        cpu.fetch_and_exec_opcode(&mut mmu);
+        assert_eq!(cpu.take_exception().map(|exception| exception.code), Some(EXCEPTION_INTERRUPT));
+        assert_eq!(cpu.cp0.get_by_name_64("epc"), 0x80001000);
+        assert_eq!(cpu.cp0.get_by_name_32("cause") & CAUSE_BD, 0);
+
+        // Taken in the delay slot of a branch to 0x80002000, the branch runs again after ERET
+        cpu.cp0.set_by_name_32("status", 0x101);
+        cpu.registers.set_program_counter(0xFFFFFFFF80001004_u64 as i64);
+        cpu.registers.set_next_program_counter(0xFFFFFFFF80002000_u64 as i64);
+        cpu.fetch_and_exec_opcode(&mut mmu);
+        assert_eq!(cpu.cp0.get_by_name_64("epc") & 0xFFFFFFFF, 0x80001000);
+        assert_eq!(cpu.cp0.get_by_name_32("cause") & CAUSE_BD, CAUSE_BD);
+        cpu.eret();
+        assert_eq!(cpu.registers.get_program_counter() & 0xFFFFFFFF, 0x80001000);
+    }
+
+    #[test]
+    fn test_overflow_trap() {
+        let mut cpu = CPU::new_hle();
+        let mut mmu = MMU::new();
+        cpu.cp0.set_by_name_32("status", 0);
+        cpu.registers.set_by_number(8, i32::MAX as i64);
+        cpu.registers.set_by_number(9, 1);
+        cpu.registers.set_by_number(10, 0x1234);
+        // ADD t2, t0, t1
+        cpu.instruction_address = 0x80001000;
+        cpu.exec_opcode(0x01095020, &mut mmu);
+        assert_eq!(cpu.take_exception().map(|exception| exception.code), Some(EXCEPTION_OVERFLOW));
+        assert_eq!(cpu.registers.get_by_number(10), 0x1234);
+    }
+
+    #[test]
+    fn test_bus_error() {
+        let mut cpu = CPU::new_hle();
+        let mut mmu = MMU::new();
+        mmu.set_unmapped_policy(UnmappedPolicy::BusError);
+        cpu.cp0.set_by_name_32("status", 0);
+        // LW t0, 0(t1) with t1 in the unused range after the SI
+        mmu.write_virtual(0x80001000, &0x8D280000_u32.to_be_bytes());
+        cpu.registers.set_by_number(9, 0xFFFFFFFFA4900000_u64 as i64);
+        cpu.registers.set_program_counter(0x80001000);
+        cpu.registers.set_next_program_counter(0x80001004);
+        cpu.fetch_and_exec_opcode(&mut mmu);
+        assert_eq!(cpu.take_exception().map(|exception| exception.code), Some(EXCEPTION_DATA_BUS_ERROR));
+        assert_eq!(cpu.cp0.get_by_name_64("epc"), 0x80001000);
+
+        // Jumping there
+        cpu.cp0.set_by_name_32("status", 0);
+        cpu.registers.set_program_counter(0xFFFFFFFFA4900000_u64 as i64);
+        cpu.registers.set_next_program_counter(0xFFFFFFFFA4900004_u64 as i64);
+        cpu.fetch_and_exec_opcode(&mut mmu);
+        assert_eq!(cpu.take_exception().map(|exception| exception.code), Some(EXCEPTION_INSTRUCTION_BUS_ERROR));
+
+        // The other policies let the CPU carry on
+        mmu.set_unmapped_policy(UnmappedPolicy::Ignore);
+        cpu.registers.set_program_counter(0x80001000);
+        cpu.registers.set_next_program_counter(0x80001004);
+        cpu.fetch_and_exec_opcode(&mut mmu);
+        assert_eq!(cpu.take_exception(), None);
+        assert_eq!(cpu.registers.get_by_number(8), 0);
+    }
+
+    #[test]
+    fn test_tlbw_tlbp() {
+        let mut cpu = CPU::new();
+        let mut mmu = MMU::new();
+        cpu.cp0.set_by_name_64("EntryHi", 0x00400000);
+        cpu.cp0.set_by_name_64("EntryLo0", (0x100 << 6) | 0b011);
+        cpu.cp0.set_by_name_64("EntryLo1", (0x200 << 6) | 0b011);
+        cpu.tlbw(3, &mut mmu);
+        assert_eq!(mmu.translate(0x00401004), 0x00200004);
+
+        cpu.cp0.set_by_name_64("EntryHi", 0x00401000);
+        cpu.tlbp(&mmu);
+        assert_eq!(cpu.cp0.get_by_name_32("index"), 3);
+        cpu.cp0.set_by_name_64("EntryHi", 0x00800000);
+        cpu.tlbp(&mmu);
+        assert_eq!(cpu.cp0.get_by_name_32("index"), 0x80000000_u32 as i32);
+    }
+
     #[test]
     fn test_mfc0() {
         let mut cpu = CPU::new();
@@ -1989,6 +2491,34 @@ mod cpu_instructions_tests {
         assert_eq!(cpu.cp0.get_by_number_32(rd), 65535);
     }
 
+    #[test]
+    fn test_cop1_fr_modes() {
+        let mut cpu = CPU::new();
+        let mut mmu = MMU::new();
+        cpu.registers.set_by_number(8, 0x3FF80000);
+        cpu.registers.set_by_number(9, 0x80001000_u32 as i32 as i64);
+        // FR clear, f1 is the upper half of f0
+        cpu.mtc1(8, 1);
+        cpu.mtc1(0, 0);
+        cpu.dmfc1(10, 0);
+        assert_eq!(f64::from_bits(cpu.registers.get_by_number(10) as u64), 1.5);
+        cpu.sdc1(1, 0, 9, &mut mmu);
+        assert_eq!(mmu.read_physical(0x1000, 8), 1.5_f64.to_be_bytes().to_vec());
+        cpu.lwc1(3, 0, 9, &mmu);
+        cpu.mfc1(11, 3);
+        assert_eq!(cpu.registers.get_by_number(11), 0x3FF80000);
+        assert_eq!(cpu.cp1.get_fpr(2) >> 32, 0x3FF80000);
+
+        // FR set, every register on its own
+        cpu.registers.set_by_number(8, STATUS_FR as i64);
+        cpu.mtc0(8, 12);
+        cpu.ldc1(1, 0, 9, &mmu);
+        cpu.dmfc1(10, 1);
+        assert_eq!(f64::from_bits(cpu.registers.get_by_number(10) as u64), 1.5);
+        cpu.mfc1(11, 0);
+        assert_eq!(cpu.registers.get_by_number(11), 0);
+    }
+
     #[test]
     fn test_dmfc0() {
         let mut cpu = CPU::new();
@@ -2452,3 +2982,103 @@ mod cpu_instructions_tests {
         assert_eq!(cpu.registers.get_next_program_counter(), 0xFF);
     }
 }
+
+/*
+    Properties over random operands, the kind of sign extension and masking
+    mistakes the examples above don't reach.
+*/
+#[cfg(test)]
+mod cpu_property_tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const RD: usize = 10;
+    const RS: usize = 15;
+    const RT: usize = 20;
+
+    fn cpu_with(s: i64, t: i64) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.registers.set_by_number(RS, s);
+        cpu.registers.set_by_number(RT, t);
+        cpu
+    }
+
+    proptest! {
+        #[test]
+        fn addu_wraps_and_sign_extends(s: i64, t: i64) {
+            let mut cpu = cpu_with(s, t);
+            cpu.addu(RD, RS, RT);
+            // Only the low words take part and the sum is sign extended, ignoring overflows
+            prop_assert_eq!(cpu.registers.get_by_number(RD), (s as i32).wrapping_add(t as i32) as i64);
+            cpu.addu(RD, RT, RS);
+            prop_assert_eq!(cpu.registers.get_by_number(RD), (s as i32).wrapping_add(t as i32) as i64);
+        }
+
+        #[test]
+        fn dsllv_masks_the_shift_amount(t: i64, s: i64) {
+            let mut cpu = cpu_with(s, t);
+            cpu.dsllv(RD, RT, RS);
+            prop_assert_eq!(cpu.registers.get_by_number(RD), t << (s & 0b111111));
+            let shifted = cpu.registers.get_by_number(RD);
+            cpu.registers.set_by_number(RS, s & 0b111111);
+            cpu.dsllv(RD, RT, RS);
+            prop_assert_eq!(cpu.registers.get_by_number(RD), shifted);
+        }
+
+        #[test]
+        fn slt_and_sltu_order_operands(s: i64, t: i64) {
+            let mut cpu = cpu_with(s, t);
+            cpu.slt(RD, RS, RT);
+            let less = cpu.registers.get_by_number(RD);
+            cpu.slt(RD, RT, RS);
+            let greater = cpu.registers.get_by_number(RD);
+            prop_assert_eq!(less, (s < t) as i64);
+            // At most one of the orders holds, none when the operands are equal
+            prop_assert_eq!(less + greater, (s != t) as i64);
+
+            cpu.sltu(RD, RS, RT);
+            prop_assert_eq!(cpu.registers.get_by_number(RD), ((s as u64) < (t as u64)) as i64);
+        }
+
+        #[test]
+        fn mult_and_multu_keep_the_full_product(s: i64, t: i64) {
+            let mut cpu = cpu_with(s, t);
+            cpu.mult(RS, RT);
+            let product = (s as i32 as i64) * (t as i32 as i64);
+            prop_assert_eq!(cpu.registers.get_lo(), product as i32 as i64);
+            prop_assert_eq!(cpu.registers.get_hi(), (product >> 32) as i32 as i64);
+
+            cpu.multu(RS, RT);
+            let product = (s as u32 as u64) * (t as u32 as u64);
+            prop_assert_eq!(cpu.registers.get_lo(), product as i32 as i64);
+            prop_assert_eq!(cpu.registers.get_hi(), (product >> 32) as i32 as i64);
+        }
+
+        #[test]
+        fn dmult_and_dmultu_keep_the_full_product(s: i64, t: i64) {
+            let mut cpu = cpu_with(s, t);
+            cpu.dmult(RS, RT);
+            let product = ((cpu.registers.get_hi() as i128) << 64) | (cpu.registers.get_lo() as u64 as i128);
+            prop_assert_eq!(product, s as i128 * t as i128);
+
+            cpu.dmultu(RS, RT);
+            let product = ((cpu.registers.get_hi() as u64 as u128) << 64) | (cpu.registers.get_lo() as u64 as u128);
+            prop_assert_eq!(product, s as u64 as u128 * t as u64 as u128);
+        }
+
+        #[test]
+        fn lwl_and_lwr_load_an_unaligned_word(bytes: [u8; 8], offset in 0..4usize, previous: i64) {
+            let mut mmu = MMU::new();
+            mmu.write_physical(0x1000, &bytes);
+            let mut cpu = CPU::new();
+            // KSEG0, mapped straight to the physical address
+            cpu.registers.set_by_number(RS, 0x80001000u32 as i32 as i64 + offset as i64);
+            cpu.registers.set_by_number(RT, previous);
+            cpu.lwl(RT, 0, RS, &mmu);
+            cpu.lwr(RT, 3, RS, &mmu);
+            let word = i32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            prop_assert_eq!(cpu.registers.get_by_number(RT), word as i64);
+        }
+    }
+}
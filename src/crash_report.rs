@@ -0,0 +1,208 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::emulator::Emulator;
+use crate::registers::{CP0Registers, CPU_REGISTER_NAMES, CP0_REGISTER_NAMES};
+
+// Instructions kept for the report
+pub const CRASH_HISTORY_SIZE: usize = 32;
+
+// Bytes dumped before and after each address of interest
+const DUMP_RADIUS: i64 = 0x40;
+
+// Addresses of the last executed instructions, oldest first
+pub struct InstructionHistory {
+    addresses: VecDeque<i64>,
+}
+
+impl InstructionHistory {
+    pub fn new() -> Self {
+        Self {
+            addresses: VecDeque::with_capacity(CRASH_HISTORY_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, address: i64) {
+        if self.addresses.len() == CRASH_HISTORY_SIZE {
+            self.addresses.pop_front();
+        }
+        self.addresses.push_back(address);
+    }
+
+    pub fn addresses(&self) -> &VecDeque<i64> {
+        &self.addresses
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+    }
+}
+
+// What a caught panic says, unimplemented!() and the like carry a &str
+pub fn panic_message(err: &(dyn Any + Send)) -> String {
+    match err.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => match err.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => String::from("Emulation stopped"),
+        },
+    }
+}
+
+pub struct MemoryDump {
+    pub label: &'static str,
+    // Virtual address of the first byte
+    pub address: i64,
+    pub data: Vec<u8>,
+}
+
+/*
+    What the core looked like when it panicked: the message, the registers, the instructions that
+    led there and the memory around the PC, the stack and the address the instruction accessed.
+    Written as text next to the ROM so it can be attached to a bug report.
+*/
+pub struct CrashReport {
+    pub message: String,
+    pub frames: u64,
+    pub cycles: u64,
+    pub program_counter: i64,
+    pub hi: i64,
+    pub lo: i64,
+    pub gpr: Vec<i64>,
+    // 32 bit registers are sign extended
+    pub cp0: Vec<i64>,
+    // Address and opcode, the last one is the instruction that failed
+    pub instructions: Vec<(i64, u32)>,
+    pub memory: Vec<MemoryDump>,
+}
+
+// Loads and stores, base + offset: https://n64brew.dev/wiki/MIPS_III_instructions
+fn accessed_address(opcode: u32, gpr: &[i64]) -> Option<i64> {
+    match opcode >> 26 {
+        0x1A..=0x1B | 0x20..=0x2E | 0x30..=0x3F => {
+            let base = ((opcode >> 21) & 0x1F) as usize;
+            Some(gpr[base].wrapping_add(opcode as i16 as i64))
+        },
+        _ => None,
+    }
+}
+
+impl CrashReport {
+    pub fn capture(emulator: &Emulator, message: &str) -> Self {
+        let mmu = emulator.mmu();
+        let registers = emulator.cpu().registers();
+        let cp0 = emulator.cpu().cp0();
+        // Read through the physical address, so the report doesn't show up in the memory heatmap
+        let read = |address: i64, bytes: usize| mmu.read_physical(mmu.translate(address), bytes);
+        let opcode = |address: i64| u32::from_be_bytes(read(address, 4).try_into().unwrap());
+        let program_counter = registers.get_program_counter();
+        let gpr: Vec<i64> = (0..32).map(|index| registers.get_by_number(index)).collect();
+        let mut instructions: Vec<(i64, u32)> = emulator.instruction_history().addresses().iter()
+            .map(|address| (*address, opcode(*address)))
+            .collect();
+        // The failing instruction panicked before the PC moved on, it's the last one run
+        if instructions.last().map(|(address, _)| *address) != Some(program_counter) {
+            instructions.push((program_counter, opcode(program_counter)));
+        }
+
+        let mut interesting = vec![("PC", program_counter), ("Stack", gpr[29])];
+        if let Some(address) = accessed_address(opcode(program_counter), &gpr) {
+            interesting.push(("Accessed", address));
+        }
+        let memory = interesting.into_iter()
+            .map(|(label, address)| {
+                let start = (address & !0xF).wrapping_sub(DUMP_RADIUS);
+                MemoryDump {
+                    label,
+                    address: start,
+                    data: read(start, DUMP_RADIUS as usize * 2),
+                }
+            })
+            .collect();
+
+        Self {
+            message: message.to_string(),
+            frames: emulator.frames(),
+            cycles: emulator.scheduler().get_cycles(),
+            program_counter,
+            hi: registers.get_hi(),
+            lo: registers.get_lo(),
+            gpr,
+            cp0: (0..32).map(|index| match CP0Registers::is_32bits(index) {
+                true => cp0.get_by_number_32(index) as i64,
+                false => cp0.get_by_number_64(index),
+            }).collect(),
+            instructions,
+            memory,
+        }
+    }
+
+    // Next to the ROM, named after the game and the Unix time like screenshots
+    pub fn write(&self, emulator: &Emulator, timestamp: u64) -> std::io::Result<PathBuf> {
+        let path = emulator.mmu().rom().save_path_with_extension(&format!("crash-{}.txt", timestamp))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No ROM loaded from a file"))?;
+        std::fs::write(&path, self.to_string())?;
+        Ok(path)
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rultra64 crash report")?;
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "Frame {}, cycle {}", self.frames, self.cycles)?;
+        writeln!(f)?;
+        writeln!(f, "PC {:016X}  hi {:016X}  lo {:016X}", self.program_counter, self.hi, self.lo)?;
+        for (names, values) in CPU_REGISTER_NAMES.chunks(4).zip(self.gpr.chunks(4)) {
+            let line: Vec<String> = names.iter().zip(values).map(|(name, value)| format!("{:>4} {:016X}", name, value)).collect();
+            writeln!(f, "{}", line.join("  "))?;
+        }
+        writeln!(f)?;
+        for (names, values) in CP0_REGISTER_NAMES.chunks(4).zip(self.cp0.chunks(4)) {
+            let line: Vec<String> = names.iter().zip(values).map(|(name, value)| format!("{:>10} {:016X}", name, value)).collect();
+            writeln!(f, "{}", line.join("  "))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Last instructions:")?;
+        for (address, opcode) in &self.instructions {
+            writeln!(f, "{:016X}: {:08X}", address, opcode)?;
+        }
+        for dump in &self.memory {
+            writeln!(f)?;
+            writeln!(f, "{} memory:", dump.label)?;
+            for (row, bytes) in dump.data.chunks(16).enumerate() {
+                let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                writeln!(f, "{:016X}: {}", dump.address.wrapping_add(row as i64 * 16), hex.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod crash_report_tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let mut emulator = Emulator::new_hle();
+        emulator.tick();
+        // LW t0, 0x10(sp)
+        emulator.mut_cpu().mut_registers().set_by_number(29, 0x80200000);
+        emulator.mut_mmu().write_virtual(0x80100000, &[0x8F, 0xA8, 0x00, 0x10]);
+        emulator.mut_mmu().write_virtual(0x80200010, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        emulator.mut_cpu().mut_registers().set_program_counter(0x80100000);
+
+        let report = CrashReport::capture(&emulator, "not implemented");
+        assert_eq!(report.instructions.len(), 2);
+        assert_eq!(report.instructions.last(), Some(&(0x80100000, 0x8FA80010)));
+        let accessed = report.memory.iter().find(|dump| dump.label == "Accessed").unwrap();
+        assert_eq!(accessed.address, 0x80200010 - DUMP_RADIUS);
+        assert_eq!(&accessed.data[DUMP_RADIUS as usize..DUMP_RADIUS as usize + 4], &[0xDE, 0xAD, 0xBE, 0xEF]);
+        let text = report.to_string();
+        assert!(text.contains("not implemented"));
+        assert!(text.contains("0000000080100000: 8FA80010"));
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use gimli::{AttributeValue, EndianSlice, Operation, RunTimeEndian};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+use crate::symbols::{Symbol, SymbolTable};
+
+// Looked for next to the ROM, like "Game.z64" and "Game.elf"
+pub const DEBUG_INFO_EXTENSION: &str = "elf";
+
+// Where a local variable lives, from the first operation of its DW_AT_location
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VariableLocation {
+    // Offset from DW_AT_frame_base, usually sp or fp for MIPS GCC
+    FrameOffset(i64),
+    Register(u16),
+    RegisterOffset(u16, i64),
+    // Location lists and longer expressions
+    Unknown,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub parameter: bool,
+    pub location: VariableLocation,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Function {
+    pub name: String,
+    pub low_pc: u64,
+    // Exclusive
+    pub high_pc: u64,
+    // Parameters and locals, including the ones in nested blocks
+    pub variables: Vec<Variable>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct LineRow {
+    address: u64,
+    // Index in the file names and the line, None at the end of a sequence
+    location: Option<(usize, u32)>,
+}
+
+/*
+    Debug information of a homebrew ELF built with -g: functions with their variables and the
+    line table, copied out of the DWARF sections so nothing borrows the file.
+    Addresses are compared in 32 bits like the symbol table does, the CPU sign extends KSEG0.
+*/
+pub struct DebugInfo {
+    functions: Vec<Function>,
+    lines: Vec<LineRow>,
+    files: Vec<String>,
+    symbols: SymbolTable,
+}
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+fn attr_string(dwarf: &gimli::Dwarf<Reader>, unit: &gimli::Unit<Reader>, value: AttributeValue<Reader>) -> Option<String> {
+    dwarf.attr_string(unit, value).ok().map(|name| name.to_string_lossy().to_string())
+}
+
+fn variable_location(unit: &gimli::Unit<Reader>, value: Option<AttributeValue<Reader>>) -> VariableLocation {
+    let mut expression = match value.and_then(|value| value.exprloc_value()) {
+        Some(expression) => expression.0,
+        None => return VariableLocation::Unknown,
+    };
+    match Operation::parse(&mut expression, unit.encoding()) {
+        Ok(Operation::FrameOffset { offset }) => VariableLocation::FrameOffset(offset),
+        Ok(Operation::Register { register }) => VariableLocation::Register(register.0),
+        Ok(Operation::RegisterOffset { register, offset, .. }) => VariableLocation::RegisterOffset(register.0, offset),
+        _ => VariableLocation::Unknown,
+    }
+}
+
+impl DebugInfo {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let file = object::File::parse(data).map_err(|err| format!("Not an ELF file: {}", err))?;
+        let endian = match file.is_little_endian() {
+            true => RunTimeEndian::Little,
+            false => RunTimeEndian::Big,
+        };
+        let dwarf = gimli::Dwarf::load(|section| -> Result<Reader, gimli::Error> {
+            let data = file.section_by_name(section.name()).and_then(|section| section.data().ok()).unwrap_or(&[]);
+            Ok(EndianSlice::new(data, endian))
+        }).map_err(|err| err.to_string())?;
+
+        let mut info = Self {
+            functions: Vec::new(),
+            lines: Vec::new(),
+            files: Vec::new(),
+            symbols: SymbolTable::from_symbols(file.symbols()
+                .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+                .filter_map(|symbol| Some(Symbol { address: symbol.address() as u32, name: symbol.name().ok()?.to_string() }))
+                .collect()),
+        };
+        let mut units = dwarf.units();
+        while let Some(header) = units.next().map_err(|err| err.to_string())? {
+            let unit = dwarf.unit(header).map_err(|err| err.to_string())?;
+            info.read_lines(&dwarf, &unit).map_err(|err| err.to_string())?;
+            info.read_functions(&dwarf, &unit).map_err(|err| err.to_string())?;
+        }
+        info.lines.sort_by_key(|row| (row.address, row.location.is_some()));
+        info.functions.sort_by_key(|function| function.low_pc);
+        Ok(info)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        Self::parse(&data)
+    }
+
+    pub fn load_for_rom(rom_path: &Path) -> Option<Self> {
+        let path = rom_path.with_extension(DEBUG_INFO_EXTENSION);
+        match path.is_file() {
+            true => Self::load(&path).ok(),
+            false => None,
+        }
+    }
+
+    fn read_lines(&mut self, dwarf: &gimli::Dwarf<Reader>, unit: &gimli::Unit<Reader>) -> gimli::Result<()> {
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => return Ok(()),
+        };
+        let mut file_indices: HashMap<u64, usize> = HashMap::new();
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            if row.end_sequence() {
+                self.lines.push(LineRow { address: row.address(), location: None });
+                continue;
+            }
+            let file = match file_indices.get(&row.file_index()) {
+                Some(index) => *index,
+                None => {
+                    let mut path = PathBuf::new();
+                    if let Some(entry) = row.file(header) {
+                        if let Some(directory) = entry.directory(header).and_then(|directory| attr_string(dwarf, unit, directory)) {
+                            path.push(directory);
+                        }
+                        path.push(attr_string(dwarf, unit, entry.path_name()).unwrap_or_default());
+                    }
+                    self.files.push(path.display().to_string());
+                    file_indices.insert(row.file_index(), self.files.len() - 1);
+                    self.files.len() - 1
+                },
+            };
+            let line = row.line().map_or(0, |line| line.get() as u32);
+            self.lines.push(LineRow { address: row.address(), location: Some((file, line)) });
+        }
+        Ok(())
+    }
+
+    // Walks the tree keeping the functions that enclose each entry, so variables in blocks find theirs
+    fn read_functions(&mut self, dwarf: &gimli::Dwarf<Reader>, unit: &gimli::Unit<Reader>) -> gimli::Result<()> {
+        let mut depth = 0;
+        let mut enclosing: Vec<(isize, Option<usize>)> = Vec::new();
+        let mut entries = unit.entries();
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            while enclosing.last().is_some_and(|(function_depth, _)| *function_depth >= depth) {
+                enclosing.pop();
+            }
+            match entry.tag() {
+                gimli::DW_TAG_subprogram => {
+                    let name = entry.attr_value(gimli::DW_AT_name)?.and_then(|name| attr_string(dwarf, unit, name));
+                    let low_pc = match entry.attr_value(gimli::DW_AT_low_pc)? {
+                        Some(value) => dwarf.attr_address(unit, value)?,
+                        None => None,
+                    };
+                    let high_pc = match entry.attr_value(gimli::DW_AT_high_pc)? {
+                        Some(AttributeValue::Addr(address)) => Some(address),
+                        Some(value) => value.udata_value().and_then(|size| Some(low_pc? + size)),
+                        None => None,
+                    };
+                    // Declarations and abstract inline copies have no code, their variables aren't kept
+                    let index = match (name, low_pc, high_pc) {
+                        (Some(name), Some(low_pc), Some(high_pc)) => {
+                            self.functions.push(Function { name, low_pc, high_pc, variables: Vec::new() });
+                            Some(self.functions.len() - 1)
+                        },
+                        _ => None,
+                    };
+                    enclosing.push((depth, index));
+                },
+                gimli::DW_TAG_variable | gimli::DW_TAG_formal_parameter => {
+                    let function = match enclosing.last() {
+                        Some((_, Some(function))) => *function,
+                        _ => continue,
+                    };
+                    let name = match entry.attr_value(gimli::DW_AT_name)?.and_then(|name| attr_string(dwarf, unit, name)) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    self.functions[function].variables.push(Variable {
+                        name,
+                        parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
+                        location: variable_location(unit, entry.attr_value(gimli::DW_AT_location)?),
+                    });
+                },
+                _ => {},
+            };
+        }
+        Ok(())
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    // From the ELF symbol table, for the places that take symbols
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // The innermost function, the one with the smallest range
+    pub fn function(&self, pc: i64) -> Option<&Function> {
+        let address = pc as u32 as u64;
+        self.functions.iter()
+            .filter(|function| (function.low_pc..function.high_pc).contains(&address))
+            .min_by_key(|function| function.high_pc - function.low_pc)
+    }
+
+    // File and line of the instruction
+    pub fn source_line(&self, pc: i64) -> Option<(&str, u32)> {
+        let address = pc as u32 as u64;
+        let index = self.lines.partition_point(|row| row.address <= address).checked_sub(1)?;
+        let (file, line) = self.lines[index].location?;
+        Some((&self.files[file], line))
+    }
+}
+
+#[cfg(test)]
+mod debug_info_tests {
+    use super::*;
+
+    // The test binary itself has DWARF, homebrew ELFs are read the same way
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_debug_info() {
+        let info = DebugInfo::load(&std::env::current_exe().unwrap()).unwrap();
+        let function = info.functions().iter().find(|function| function.name == "variable_location").unwrap();
+        assert!(function.variables.iter().any(|variable| variable.name == "unit" && variable.parameter));
+        let (file, line) = info.source_line(function.low_pc as i64).unwrap();
+        assert!(file.ends_with("debug_info.rs"));
+        assert!(line > 0);
+        assert_eq!(info.function(function.low_pc as i64).map(|function| function.name.as_str()), Some("variable_location"));
+        assert!(!info.symbols().is_empty());
+        assert!(DebugInfo::parse(b"not an elf").is_err());
+    }
+}
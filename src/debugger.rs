@@ -0,0 +1,206 @@
+use crate::cpu::CPU;
+use crate::expression::{Expression, EmulatorContext};
+use crate::mmu::MMU;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BreakpointKind {
+    Execute,
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub address: i64,
+    // Watchpoints cover the bytes from address to address + len
+    pub len: i64,
+    pub kind: BreakpointKind,
+    pub enabled: bool,
+    pub hit_count: u64,
+    // Evaluated when the breakpoint matches, the emulation only stops when it isn't 0
+    pub condition: Option<Expression>,
+}
+
+impl Breakpoint {
+    fn matches_execute(&self, pc: i64) -> bool {
+        self.kind == BreakpointKind::Execute && MMU::convert(self.address) == MMU::convert(pc)
+    }
+
+    fn matches_access(&self, address: i64, len: i64, write: bool) -> bool {
+        let kind_matches = match self.kind {
+            BreakpointKind::Execute => false,
+            BreakpointKind::Read => !write,
+            BreakpointKind::Write => write,
+            BreakpointKind::Access => true,
+        };
+        let start = MMU::convert(self.address);
+        let access_start = MMU::convert(address);
+        kind_matches && access_start < start + self.len && start < access_start + len
+    }
+}
+
+/*
+    Returns the virtual address, size and direction of the load or store the opcode is about to do.
+    https://n64brew.dev/wiki/MIPS_III_instructions
+*/
+pub fn memory_access(opcode: u32, cpu: &CPU) -> Option<(i64, i64, bool)> {
+    let (len, write) = match opcode >> 26 {
+        0x20 | 0x24 => (1, false), // LB, LBU
+        0x21 | 0x25 => (2, false), // LH, LHU
+        0x22 | 0x23 | 0x26 | 0x27 | 0x30 | 0x31 => (4, false), // LWL, LW, LWR, LWU, LL, LWC1
+        0x1A | 0x1B | 0x34 | 0x35 | 0x37 => (8, false), // LDL, LDR, LLD, LDC1, LD
+        0x28 => (1, true), // SB
+        0x29 => (2, true), // SH
+        0x2A | 0x2B | 0x2E | 0x38 | 0x39 => (4, true), // SWL, SW, SWR, SC, SWC1
+        0x2C | 0x2D | 0x3C | 0x3D | 0x3F => (8, true), // SDL, SDR, SCD, SDC1, SD
+        _ => return None,
+    };
+    let base = ((opcode >> 21) & 0x1F) as usize;
+    let offset = (opcode & 0xFFFF) as i16 as i64;
+    let address = cpu.registers().get_by_number(base).wrapping_add(offset) & 0xFFFFFFFF;
+    Some((address, len, write))
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: u32,
+    hit: Option<u32>,
+    // Lets the instruction that triggered the last hit run when resuming
+    resuming: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            next_id: 1,
+            hit: None,
+            resuming: false,
+        }
+    }
+
+    pub fn add(&mut self, address: i64, len: i64, kind: BreakpointKind, condition: Option<Expression>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            address: address & 0xFFFFFFFF,
+            len: len.max(1),
+            kind,
+            enabled: true,
+            hit_count: 0,
+            condition,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+    }
+
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) {
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|breakpoint| breakpoint.id == id) {
+            breakpoint.enabled = enabled;
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    // Id of the breakpoint that stopped the emulation
+    pub fn hit(&self) -> Option<u32> {
+        self.hit
+    }
+
+    pub fn resume(&mut self) {
+        self.resuming = self.hit.is_some();
+        self.hit = None;
+    }
+
+    // Called before every instruction, returns true when it must not be executed
+    pub fn check(&mut self, cpu: &CPU, mmu: &MMU) -> bool {
+        if std::mem::replace(&mut self.resuming, false) || self.hit.is_some() {
+            return self.hit.is_some();
+        }
+        if !self.breakpoints.iter().any(|breakpoint| breakpoint.enabled) {
+            return false;
+        }
+        let pc = cpu.registers().get_program_counter() & 0xFFFFFFFF;
+        let access = memory_access(CPU::fetch_opcode(pc, mmu), cpu);
+        let context = EmulatorContext {
+            cpu,
+            mmu,
+        };
+        let hit = self.breakpoints.iter_mut()
+            .filter(|breakpoint| breakpoint.enabled)
+            .filter(|breakpoint| {
+                breakpoint.matches_execute(pc) || access.is_some_and(|(address, len, write)| breakpoint.matches_access(address, len, write))
+            })
+            .find(|breakpoint| breakpoint.condition.as_ref().is_none_or(|condition| condition.evaluate(&context) != 0));
+        match hit {
+            Some(breakpoint) => {
+                breakpoint.hit_count += 1;
+                self.hit = Some(breakpoint.id);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+
+    #[test]
+    fn test_execute_breakpoint() {
+        let cpu = CPU::new_hle();
+        let mmu = MMU::new();
+        let mut debugger = Debugger::new();
+        let id = debugger.add(0xA0001000, 1, BreakpointKind::Execute, None);
+        assert!(debugger.check(&cpu, &mmu));
+        assert_eq!(debugger.hit(), Some(id));
+        assert_eq!(debugger.breakpoints()[0].hit_count, 1);
+
+        debugger.resume();
+        assert!(!debugger.check(&cpu, &mmu));
+        assert!(debugger.check(&cpu, &mmu));
+
+        debugger.resume();
+        debugger.set_enabled(id, false);
+        assert!(!debugger.check(&cpu, &mmu));
+        assert!(!debugger.check(&cpu, &mmu));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut cpu = CPU::new_hle();
+        let mut mmu = MMU::new();
+        // SW t0, 0(t1)
+        mmu.write_virtual(0x80001000, &0xAD280000_u32.to_be_bytes());
+        cpu.mut_registers().set_by_number(9, 0x80002010);
+        let mut debugger = Debugger::new();
+        debugger.add(0x80002010, 4, BreakpointKind::Read, None);
+        assert!(!debugger.check(&cpu, &mmu));
+        let id = debugger.add(0x80002012, 1, BreakpointKind::Write, None);
+        assert!(debugger.check(&cpu, &mmu));
+        assert_eq!(debugger.hit(), Some(id));
+        debugger.remove(id);
+        assert!(debugger.breakpoints().iter().all(|breakpoint| breakpoint.id != id));
+    }
+
+    #[test]
+    fn test_condition() {
+        let mut cpu = CPU::new_hle();
+        let mmu = MMU::new();
+        let mut debugger = Debugger::new();
+        debugger.add(0x80001000, 1, BreakpointKind::Execute, Some(Expression::parse("t0 == 0xDEADBEEF").unwrap()));
+        assert!(!debugger.check(&cpu, &mmu));
+        assert_eq!(debugger.breakpoints()[0].hit_count, 0);
+        cpu.mut_registers().set_by_number(8, 0xFFFFFFFFDEADBEEF_u64 as i64);
+        assert!(debugger.check(&cpu, &mmu));
+    }
+}
@@ -0,0 +1,125 @@
+// The VI always outputs a 4:3 picture no matter the frame buffer resolution
+pub const ASPECT_RATIO: f32 = 4.0 / 3.0;
+
+// Games with a widescreen option draw an anamorphic 4:3 picture meant to be stretched to this
+pub const WIDESCREEN_ASPECT_RATIO: f32 = 16.0 / 9.0;
+
+// Frames are enlarged by this factor with nearest neighbour before the GPU filters them
+pub const NEAREST_PRESCALE: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ScalingMode {
+    Stretch,
+    AspectRatio,
+    Integer,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DisplaySettings {
+    pub scaling: ScalingMode,
+    pub filter: Filter,
+    pub widescreen: bool,
+    // Gamma, dither filter and divot of the VI
+    pub vi_filters: bool,
+}
+
+impl DisplaySettings {
+    pub fn new() -> Self {
+        Self {
+            scaling: ScalingMode::AspectRatio,
+            filter: Filter::Nearest,
+            widescreen: false,
+            vi_filters: true,
+        }
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        match self.widescreen {
+            true => WIDESCREEN_ASPECT_RATIO,
+            false => ASPECT_RATIO,
+        }
+    }
+
+    // egui always samples textures linearly, so nearest filtering is done by enlarging the frame beforehand
+    pub fn prescale(&self) -> usize {
+        match self.filter {
+            Filter::Nearest => NEAREST_PRESCALE,
+            Filter::Bilinear => 1,
+        }
+    }
+}
+
+// Size of the picture inside the available area, the frame size comes from the VI
+pub fn display_size(frame: (usize, usize), available: (f32, f32), scaling: ScalingMode, aspect_ratio: f32) -> (f32, f32) {
+    let (frame_width, frame_height) = (frame.0.max(1) as f32, frame.1.max(1) as f32);
+    let (available_width, available_height) = (available.0.max(0.0), available.1.max(0.0));
+    match scaling {
+        ScalingMode::Stretch => (available_width, available_height),
+        ScalingMode::AspectRatio => match available_width / available_height > aspect_ratio {
+            true => (available_height * aspect_ratio, available_height),
+            false => (available_width, available_width / aspect_ratio),
+        },
+        ScalingMode::Integer => {
+            // Only the height is an exact multiple when the picture is widened
+            let frame_width = frame_width * aspect_ratio / ASPECT_RATIO;
+            let factor = (available_width / frame_width).min(available_height / frame_height).floor().max(1.0);
+            (frame_width * factor, frame_height * factor)
+        },
+    }
+}
+
+// Nearest neighbour enlargement of an RGBA8888 picture
+pub fn scale_nearest(pixels: &[u8], width: usize, height: usize, factor: usize) -> Vec<u8> {
+    if factor <= 1 {
+        return pixels.to_vec();
+    }
+    let mut scaled = Vec::with_capacity(pixels.len() * factor * factor);
+    for row in pixels.chunks_exact(width * 4).take(height) {
+        let mut scaled_row = Vec::with_capacity(row.len() * factor);
+        for pixel in row.chunks_exact(4) {
+            for _ in 0..factor {
+                scaled_row.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..factor {
+            scaled.extend_from_slice(&scaled_row);
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod display_tests {
+    use super::*;
+
+    #[test]
+    fn test_display_size() {
+        assert_eq!(display_size((320, 240), (1000.0, 600.0), ScalingMode::Stretch, ASPECT_RATIO), (1000.0, 600.0));
+        assert_eq!(display_size((320, 240), (1000.0, 600.0), ScalingMode::AspectRatio, ASPECT_RATIO), (800.0, 600.0));
+        assert_eq!(display_size((640, 240), (400.0, 600.0), ScalingMode::AspectRatio, ASPECT_RATIO), (400.0, 300.0));
+        assert_eq!(display_size((320, 240), (1000.0, 600.0), ScalingMode::Integer, ASPECT_RATIO), (640.0, 480.0));
+        assert_eq!(display_size((320, 240), (100.0, 100.0), ScalingMode::Integer, ASPECT_RATIO), (320.0, 240.0));
+
+        // Widescreen stretches the same picture to 16:9
+        assert_eq!(display_size((320, 240), (1000.0, 600.0), ScalingMode::AspectRatio, WIDESCREEN_ASPECT_RATIO), (1000.0, 562.5));
+        let (width, height) = display_size((320, 240), (1000.0, 600.0), ScalingMode::Integer, WIDESCREEN_ASPECT_RATIO);
+        assert_eq!((width.round(), height), (853.0, 480.0));
+    }
+
+    #[test]
+    fn test_scale_nearest() {
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        let scaled = scale_nearest(&pixels, 2, 1, 2);
+        assert_eq!(scaled, vec![
+            1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8, 5, 6, 7, 8,
+            1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8, 5, 6, 7, 8,
+        ]);
+        assert_eq!(scale_nearest(&pixels, 2, 1, 1), pixels.to_vec());
+    }
+}
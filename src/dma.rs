@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+
+// Writing the last byte of these registers starts a transfer
+pub const PI_RD_LEN_ADDRESS: i64 = 0x04600008;
+pub const PI_WR_LEN_ADDRESS: i64 = 0x0460000C;
+pub const SI_PIF_AD_RD64B_ADDRESS: i64 = 0x04800004;
+pub const SI_PIF_AD_WR64B_ADDRESS: i64 = 0x04800010;
+pub const AI_LENGTH_ADDRESS: i64 = 0x04500004;
+
+pub const PI_DRAM_ADDR_ADDRESS: i64 = 0x04600000;
+pub const PI_CART_ADDR_ADDRESS: i64 = 0x04600004;
+pub const SI_DRAM_ADDR_ADDRESS: i64 = 0x04800000;
+pub const AI_DRAM_ADDR_ADDRESS: i64 = 0x04500000;
+
+pub const PI_STATUS_ADDRESS: i64 = 0x04600010;
+pub const PI_BSD_DOM1_LAT_ADDRESS: i64 = 0x04600014;
+pub const PI_BSD_DOM2_LAT_ADDRESS: i64 = 0x04600024;
+pub const SI_STATUS_ADDRESS: i64 = 0x04800018;
+
+/*
+    Status bits computed from the transfers in flight, writes don't stick. Writing PI_STATUS_RESET
+    stops the DMA and clears the error, writing PI_STATUS_CLEAR_INTERRUPT or anything to SI_STATUS
+    acknowledges the interrupt of the last DMA.
+    https://n64brew.dev/wiki/Peripheral_Interface#0x0460_0010_-_PI_STATUS
+    https://n64brew.dev/wiki/Serial_Interface#0x0480_0018_-_SI_STATUS
+*/
+pub const PI_STATUS_DMA_BUSY: u32 = 1 << 0;
+pub const PI_STATUS_IO_BUSY: u32 = 1 << 1;
+pub const PI_STATUS_DMA_ERROR: u32 = 1 << 2;
+pub const PI_STATUS_INTERRUPT: u32 = 1 << 3;
+pub const PI_STATUS_RESET: u8 = 1 << 0;
+pub const PI_STATUS_CLEAR_INTERRUPT: u8 = 1 << 1;
+pub const SI_STATUS_DMA_BUSY: u32 = 1 << 0;
+pub const SI_STATUS_IO_BUSY: u32 = 1 << 1;
+pub const SI_STATUS_DMA_ERROR: u32 = 1 << 3;
+pub const SI_STATUS_INTERRUPT: u32 = 1 << 12;
+
+/*
+    CPU cycles of a 64 byte SI DMA, about 25µs counting the PIF going through the commands. CPU
+    accesses to PIF RAM keep the SI busy for one word of it.
+*/
+pub const SI_DMA_CYCLES: u64 = 2304;
+pub const SI_IO_CYCLES: u64 = SI_DMA_CYCLES / 16;
+
+/*
+    Speed of the cartridge bus for one PI domain, from PI_BSD_DOMx_LAT, PWD, PGS and RLS.
+    https://n64brew.dev/wiki/Peripheral_Interface#Domains
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PiDomainTiming {
+    pub latency: u32,
+    pub pulse_width: u32,
+    pub page_size: u32,
+    pub release: u32,
+}
+
+impl PiDomainTiming {
+    // The four registers of the domain, one after the other
+    pub fn from_registers(registers: [u32; 4]) -> Self {
+        Self {
+            latency: registers[0] & 0xFF,
+            pulse_width: registers[1] & 0xFF,
+            page_size: registers[2] & 0xF,
+            release: registers[3] & 0b11,
+        }
+    }
+
+    /*
+        RCP cycles to move length bytes: the latency at the start of every page of 2^(PGS+2) bytes,
+        then a pulse and a release for every 16 bits.
+    */
+    pub fn transfer_cycles(&self, length: u32) -> u64 {
+        let page_bytes = 1_u64 << (self.page_size + 2);
+        let length = length as u64;
+        let pages = length.div_ceil(page_bytes);
+        let halfwords = length.div_ceil(2);
+        pages * (self.latency as u64 + 1) + halfwords * (self.pulse_width as u64 + 1 + self.release as u64 + 1)
+    }
+}
+
+// How many transfers the history keeps before dropping the oldest ones
+pub const DMA_LOG_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DmaKind {
+    PI,
+    SI,
+    SP,
+    AI,
+}
+
+impl DmaKind {
+    pub const ALL: [DmaKind; 4] = [DmaKind::PI, DmaKind::SI, DmaKind::SP, DmaKind::AI];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DmaKind::PI => "PI",
+            DmaKind::SI => "SI",
+            DmaKind::SP => "SP",
+            DmaKind::AI => "AI",
+        }
+    }
+}
+
+// Addresses are physical
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DmaTransfer {
+    pub kind: DmaKind,
+    pub source: i64,
+    pub destination: i64,
+    pub length: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DmaRecord {
+    pub transfer: DmaTransfer,
+    // Address of the instruction whose register write started the transfer
+    pub program_counter: i64,
+    pub cycle: u64,
+}
+
+pub struct DmaLog {
+    records: VecDeque<DmaRecord>,
+}
+
+impl DmaLog {
+    pub fn new() -> Self {
+        Self {
+            records: VecDeque::with_capacity(DMA_LOG_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, transfer: DmaTransfer, program_counter: i64, cycle: u64) {
+        if self.records.len() == DMA_LOG_SIZE {
+            self.records.pop_front();
+        }
+        self.records.push_back(DmaRecord {
+            transfer,
+            program_counter,
+            cycle,
+        });
+    }
+
+    pub fn records(&self) -> &VecDeque<DmaRecord> {
+        &self.records
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+mod dma_tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut log = DmaLog::new();
+        for cycle in 0..DMA_LOG_SIZE as u64 + 5 {
+            log.push(DmaTransfer {
+                kind: DmaKind::PI,
+                source: 0x10001000,
+                destination: 0x00001000,
+                length: 0x100,
+            }, 0x80000400, cycle);
+        }
+        assert_eq!(log.records().len(), DMA_LOG_SIZE);
+        assert_eq!(log.records()[0].cycle, 5);
+        log.clear();
+        assert!(log.records().is_empty());
+    }
+}
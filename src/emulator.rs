@@ -1,9 +1,61 @@
-use crate::mmu::MMU;
+use std::io::Result;
+
+use crate::mmu::{MMU, UnmappedPolicy};
+use crate::achievements::{FrameAction, FrameHook, MemoryView};
+use crate::cheat::{Cheat, apply_cheats};
+use crate::controller_pak::{ControllerPak, CONTROLLER_PAK_SIZE, controller_pak_paths};
 use crate::cpu::CPU;
+use crate::crash_report::InstructionHistory;
+use crate::debugger::Debugger;
+use crate::dma::DmaLog;
+use crate::exception::ExceptionLog;
+use crate::expression::RegisterName;
+use crate::log::{log, Level, Subsystem};
+use crate::paranoid::{ParanoidChecker, RegisterSnapshot};
+use crate::pif::{Pak, PakKind, CONTROLLER_PORTS};
+use crate::rdp_capture::RdpCapture;
+use crate::rom::{ROM, Region};
+use crate::save::SaveFlusher;
+use crate::savestate::{StateReader, StateWriter, compress_state, decompress_state};
+use crate::scheduler::{Scheduler, TimingProfile};
+use crate::storage;
+use crate::texture_pack::TexturePack;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RunTarget {
+    // Number of instructions left to run
+    Instructions(u64),
+    Address(i64),
+    // Frames end on the vertical interrupt, so this also advances exactly one frame
+    VerticalInterrupt,
+}
 
 pub struct Emulator {
     cpu: CPU,
     mmu: MMU,
+    scheduler: Scheduler,
+    frames: u64,
+    region_override: Option<Region>,
+    save_flusher: SaveFlusher,
+    debugger: Debugger,
+    exception_log: ExceptionLog,
+    dma_log: DmaLog,
+    instruction_history: InstructionHistory,
+    cheats: Vec<Cheat>,
+    frame_hook: Option<FrameHook>,
+    // Set when the frame hook asked to pause, until the caller takes it
+    pause_requested: bool,
+    // Kept here since reloading replaces the MMU
+    fastmem: bool,
+    texture_dump: bool,
+    vi_filters: bool,
+    unmapped_policy: UnmappedPolicy,
+    connected: [bool; CONTROLLER_PORTS],
+    paks: [PakKind; CONTROLLER_PORTS],
+    // Raw states are bigger but can be read in a hex editor
+    compress_states: bool,
+    // Cross-checks every ALU instruction against a reference model when set, see paranoid.rs
+    paranoid: Option<ParanoidChecker>,
 }
 
 impl Emulator {
@@ -11,6 +63,25 @@ impl Emulator {
         Self {
             cpu: CPU::new(),
             mmu: MMU::new(),
+            scheduler: Scheduler::new(),
+            frames: 0,
+            region_override: None,
+            save_flusher: SaveFlusher::new(),
+            debugger: Debugger::new(),
+            exception_log: ExceptionLog::new(),
+            dma_log: DmaLog::new(),
+            instruction_history: InstructionHistory::new(),
+            cheats: Vec::new(),
+            frame_hook: None,
+            pause_requested: false,
+            fastmem: false,
+            texture_dump: false,
+            vi_filters: true,
+            unmapped_policy: UnmappedPolicy::Log,
+            connected: [true, false, false, false],
+            paks: [PakKind::None; CONTROLLER_PORTS],
+            compress_states: true,
+            paranoid: None,
         }
     }
 
@@ -18,28 +89,531 @@ impl Emulator {
         Self {
             cpu: CPU::new_hle(),
             mmu: MMU::new(),
+            scheduler: Scheduler::new(),
+            frames: 0,
+            region_override: None,
+            save_flusher: SaveFlusher::new(),
+            debugger: Debugger::new(),
+            exception_log: ExceptionLog::new(),
+            dma_log: DmaLog::new(),
+            instruction_history: InstructionHistory::new(),
+            cheats: Vec::new(),
+            frame_hook: None,
+            pause_requested: false,
+            fastmem: false,
+            texture_dump: false,
+            vi_filters: true,
+            unmapped_policy: UnmappedPolicy::Log,
+            connected: [true, false, false, false],
+            paks: [PakKind::None; CONTROLLER_PORTS],
+            compress_states: true,
+            paranoid: None,
         }
     }
 
     pub fn reload(&mut self) {
         self.cpu = CPU::new();
         self.mmu = MMU::new();
+        self.mmu.set_fastmem(self.fastmem);
+        self.mmu.set_vi_filters(self.vi_filters);
+        self.mmu.set_unmapped_policy(self.unmapped_policy);
+        for (port, connected) in self.connected.iter().enumerate() {
+            self.mmu.mut_pif().set_connected(port, *connected);
+        }
+        self.mmu.set_vi_clock_rate(self.scheduler.get_timing().vi_clock_rate);
+        self.scheduler.reset();
+        self.exception_log.clear();
+        self.dma_log.clear();
+        if let Some(checker) = self.paranoid.as_mut() {
+            checker.clear();
+        }
+        self.instruction_history.clear();
+        self.frames = 0;
     }
 
     pub fn reload_hle(&mut self) {
         self.cpu = CPU::new_hle();
         self.mmu = MMU::new();
+        self.mmu.set_fastmem(self.fastmem);
+        self.mmu.set_vi_filters(self.vi_filters);
+        self.mmu.set_unmapped_policy(self.unmapped_policy);
+        for (port, connected) in self.connected.iter().enumerate() {
+            self.mmu.mut_pif().set_connected(port, *connected);
+        }
+        self.mmu.set_vi_clock_rate(self.scheduler.get_timing().vi_clock_rate);
+        self.scheduler.reset();
+        self.exception_log.clear();
+        self.dma_log.clear();
+        if let Some(checker) = self.paranoid.as_mut() {
+            checker.clear();
+        }
+        self.instruction_history.clear();
+        self.frames = 0;
+    }
+
+    pub fn load_rom(&mut self, mut rom: ROM) {
+        self.flush_saves();
+        if let Err(err) = storage::prepare(&rom) {
+            log!(Level::Warn, Subsystem::Frontend, "Could not prepare the data directory: {}", err);
+        }
+        if let Some(save_path) = rom.save_path() {
+            if let Ok(data) = std::fs::read(save_path) {
+                rom.load_save(&data);
+            }
+        }
+        self.reload_hle();
+        self.cheats.clear();
+        self.mmu.set_rom(rom);
+        for port in 0..CONTROLLER_PORTS {
+            let pak = self.make_pak(port, self.paks[port]);
+            self.mmu.mut_pif().set_pak(port, pak);
+        }
+        let mut texture_pack = TexturePack::for_rom(self.mmu.rom());
+        if let Some(pack) = texture_pack.as_mut() {
+            pack.set_dumping(self.texture_dump);
+        }
+        self.mmu.set_texture_pack(texture_pack);
+        self.apply_timing();
+        self.mmu.hle_ipl(self.region());
+    }
+
+    // The region comes from the ROM header unless it has been manually overridden
+    pub fn region(&self) -> Region {
+        match self.region_override {
+            Some(region) => region,
+            None => self.mmu.rom().region(),
+        }
+    }
+
+    pub fn get_region_override(&self) -> Option<Region> {
+        self.region_override
     }
 
-    pub fn tick(&mut self) {
+    pub fn set_region_override(&mut self, region: Option<Region>) {
+        self.region_override = region;
+        self.apply_timing();
+    }
+
+    fn apply_timing(&mut self) {
+        let timing = TimingProfile::from_region(self.region());
+        self.scheduler.set_timing(timing);
+        self.mmu.set_vi_clock_rate(timing.vi_clock_rate);
+    }
+
+    fn raw_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.cpu.save_state(&mut writer);
+        self.mmu.save_state(&mut writer);
+        self.scheduler.save_state(&mut writer);
+        writer.write_u64(self.frames);
+        writer.finish()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        match self.compress_states {
+            true => compress_state(&self.raw_state()),
+            false => self.raw_state(),
+        }
+    }
+
+    fn read_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(state)?;
+        self.cpu.load_state(&mut reader)?;
+        self.mmu.load_state(&mut reader)?;
+        self.scheduler.load_state(&mut reader)?;
+        self.frames = reader.read_u64()?;
+        Ok(())
+    }
+
+    /*
+        Both compressed and raw states load. The sections are read straight into the machine, so a
+        state that fails halfway puts back the one it had before instead of leaving it half loaded.
+    */
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let state = decompress_state(data)?;
+        let previous = self.raw_state();
+        if let Err(err) = self.read_state(&state) {
+            self.read_state(&previous).expect("Could not restore the state from before the failed load");
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Returns true when the instruction completed a frame, nothing is executed while stopped at a breakpoint
+    pub fn tick(&mut self) -> bool {
+        if self.debugger.check(&self.cpu, &self.mmu) {
+            return false;
+        }
+        let program_counter = self.cpu.registers().get_program_counter();
+        self.instruction_history.push(program_counter);
+        // Read through the physical address like traces, so it doesn't show up in the heatmap
+        let paranoid_before = self.paranoid.as_ref().map(|_| {
+            let opcode = self.mmu.read_physical(self.mmu.translate(program_counter), 4);
+            (u32::from_be_bytes(opcode.try_into().unwrap()), RegisterSnapshot::capture(self.cpu.registers()))
+        });
         self.cpu.fetch_and_exec_opcode(&mut self.mmu);
+        let exception = self.cpu.take_exception();
+        if let (Some(checker), Some((opcode, before)), None) = (self.paranoid.as_mut(), paranoid_before, exception) {
+            checker.check(program_counter, opcode, &before, self.cpu.registers());
+        }
+        if let Some(exception) = exception {
+            self.exception_log.push(exception, self.scheduler.get_cycles());
+        }
+        for transfer in self.mmu.take_dma_transfers() {
+            self.dma_log.push(transfer, self.cpu.instruction_address(), self.scheduler.get_cycles());
+        }
+        self.mmu.tick_rsp();
+        self.mmu.tick(1);
+        let vertical_interrupt = self.scheduler.tick(1);
+        let count_ticks = self.scheduler.take_count_ticks();
+        if count_ticks > 0 {
+            self.cpu.mut_cp0().advance_count(count_ticks);
+        }
+        self.mmu.mut_video_interface().set_current_half_line(self.scheduler.vi_half_line());
+        if vertical_interrupt {
+            self.frames += 1;
+            self.mmu.mut_pif().next_frame();
+            apply_cheats(&self.cheats, &mut self.mmu);
+            self.mmu.mut_heatmap().decay();
+            self.schedule_saves();
+            if let Some(hook) = self.frame_hook.as_mut() {
+                if hook(&MemoryView::new(self.mmu.rdram(), self.frames)) == FrameAction::Pause {
+                    self.pause_requested = true;
+                }
+            }
+            return true;
+        }
+        false
+    }
+
+    fn schedule_saves(&mut self) {
+        let rom = self.mmu.mut_rom();
+        if rom.take_ram_dirty() {
+            if let Some(save_path) = rom.save_path() {
+                self.save_flusher.schedule(save_path, rom.save_data().to_vec());
+            }
+        }
+        let paths = controller_pak_paths(self.mmu.rom());
+        for (port, data) in self.mmu.mut_pif().take_controller_pak_writes() {
+            if let Some(path) = paths[port].clone() {
+                self.save_flusher.schedule(path, data);
+            }
+        }
+    }
+
+    // Controller Paks come from the file of the port, a missing file starts unformatted like a new pak
+    fn make_pak(&self, port: usize, kind: PakKind) -> Option<Pak> {
+        match kind {
+            PakKind::None => None,
+            PakKind::RumblePak => Some(Pak::RumblePak { rumbling: false }),
+            PakKind::ControllerPak => {
+                let path = controller_pak_paths(self.mmu.rom())[port].clone();
+                let data = path.as_ref().and_then(|path| std::fs::read(path).ok()).unwrap_or_else(|| vec![0; CONTROLLER_PAK_SIZE]);
+                match ControllerPak::new(data) {
+                    Ok(pak) => Some(Pak::ControllerPak(pak)),
+                    Err(err) => {
+                        log!(Level::Warn, Subsystem::Frontend, "Could not load the Controller Pak of port {}: {}", port + 1, err);
+                        None
+                    },
+                }
+            },
+        }
+    }
+
+    pub fn connected(&self, port: usize) -> bool {
+        self.connected[port]
+    }
+
+    pub fn set_connected(&mut self, port: usize, connected: bool) {
+        self.connected[port] = connected;
+        self.mmu.mut_pif().set_connected(port, connected);
+    }
+
+    pub fn pak(&self, port: usize) -> PakKind {
+        self.paks[port]
+    }
+
+    /*
+        Swaps the pak of a port while the game runs, the game sees it was pulled out on its next
+        status check. The old Controller Pak is saved first, so the new one reads what it wrote.
+    */
+    pub fn set_pak(&mut self, port: usize, kind: PakKind) {
+        self.flush_saves();
+        self.paks[port] = kind;
+        let pak = self.make_pak(port, kind);
+        self.mmu.mut_pif().swap_pak(port, pak);
+    }
+
+    // Writes pending battery saves to disk, to be called before closing the ROM or exiting
+    pub fn flush_saves(&mut self) {
+        self.schedule_saves();
+        self.save_flusher.flush();
+    }
+
+    pub fn run_frame(&mut self) {
+        // Events logged while the frame runs are grouped under its number
+        #[cfg(feature = "trace")]
+        let _span = tracing::debug_span!("frame", number = self.frames).entered();
+        while !self.tick() && self.debugger.hit().is_none() {}
+    }
+
+    // For RetroAchievements, see achievements.rs
+    pub fn set_frame_hook(&mut self, hook: Option<FrameHook>) {
+        self.frame_hook = hook;
+    }
+
+    pub fn take_pause_request(&mut self) -> bool {
+        std::mem::take(&mut self.pause_requested)
+    }
+
+    // Runs a frame recording the RDP commands it sends
+    pub fn capture_rdp_frame(&mut self) -> RdpCapture {
+        self.mmu.start_rdp_capture();
+        self.run_frame();
+        self.mmu.take_rdp_capture().unwrap()
+    }
+
+    /*
+        Runs until the target is reached, returning true, or until the frame ends or a breakpoint hits.
+        Long runs are split in frames so the caller can keep the pacing and stop in between.
+    */
+    pub fn run_frame_until(&mut self, target: &mut RunTarget) -> bool {
+        if *target == RunTarget::Instructions(0) {
+            return true;
+        }
+        #[cfg(feature = "trace")]
+        let _span = tracing::debug_span!("frame", number = self.frames).entered();
+        loop {
+            let vertical_interrupt = self.tick();
+            if self.debugger.hit().is_some() {
+                return false;
+            }
+            let reached = match target {
+                RunTarget::Instructions(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                RunTarget::Address(address) => (self.cpu.registers().get_program_counter() & 0xFFFFFFFF) == (*address & 0xFFFFFFFF),
+                RunTarget::VerticalInterrupt => vertical_interrupt,
+            };
+            if reached {
+                return true;
+            } else if vertical_interrupt {
+                return false;
+            }
+        }
+    }
+
+    // Cheats stay across resets, loading another ROM clears them
+    pub fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        self.cheats = cheats;
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn timing(&self) -> TimingProfile {
+        self.scheduler.get_timing()
+    }
+
+    pub fn get_cpu_clock_multiplier(&self) -> u8 {
+        self.scheduler.get_clock_multiplier()
+    }
+
+    pub fn texture_dump(&self) -> bool {
+        self.texture_dump
+    }
+
+    // Writes the textures the game loads to the dump directory of its texture pack
+    pub fn set_texture_dump(&mut self, enabled: bool) {
+        self.texture_dump = enabled;
+        if let Some(pack) = self.mmu.mut_texture_pack() {
+            pack.set_dumping(enabled);
+        }
+    }
+
+    pub fn vi_filters(&self) -> bool {
+        self.vi_filters
+    }
+
+    // Gamma, dither filter and divot, when VI_CTRL enables them
+    pub fn set_vi_filters(&mut self, enabled: bool) {
+        self.vi_filters = enabled;
+        self.mmu.set_vi_filters(enabled);
+    }
+
+    pub fn unmapped_policy(&self) -> UnmappedPolicy {
+        self.unmapped_policy
+    }
+
+    pub fn set_unmapped_policy(&mut self, policy: UnmappedPolicy) {
+        self.unmapped_policy = policy;
+        self.mmu.set_unmapped_policy(policy);
+    }
+
+    pub fn fastmem(&self) -> bool {
+        self.fastmem
+    }
+
+    pub fn set_fastmem(&mut self, enabled: bool) {
+        self.fastmem = enabled;
+        self.mmu.set_fastmem(enabled);
+    }
+
+    pub fn compress_states(&self) -> bool {
+        self.compress_states
+    }
+
+    pub fn set_compress_states(&mut self, enabled: bool) {
+        self.compress_states = enabled;
+    }
+
+    pub fn paranoid(&self) -> Option<&ParanoidChecker> {
+        self.paranoid.as_ref()
+    }
+
+    // Slows emulation down a lot, meant for developing the CPU
+    pub fn set_paranoid(&mut self, enabled: bool) {
+        self.paranoid = match enabled {
+            true => self.paranoid.take().or_else(|| Some(ParanoidChecker::new())),
+            false => None,
+        };
+    }
+
+    pub fn set_cpu_clock_multiplier(&mut self, multiplier: u8) {
+        self.scheduler.set_clock_multiplier(multiplier);
+    }
+
+    // Setting the PC also moves the next PC, so the new address runs without a delay slot
+    pub fn set_register(&mut self, register: RegisterName, value: i64) {
+        let registers = self.cpu.mut_registers();
+        match register {
+            RegisterName::GPR(index) => registers.set_by_number(index, value),
+            RegisterName::PC => {
+                registers.set_program_counter(value);
+                registers.set_next_program_counter(value.wrapping_add(4));
+            },
+            RegisterName::Hi => registers.set_hi(value),
+            RegisterName::Lo => registers.set_lo(value),
+        };
     }
 
     pub fn cpu(&self) -> &CPU {
         &self.cpu
     }
 
+    pub fn mut_cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn mut_debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn exception_log(&self) -> &ExceptionLog {
+        &self.exception_log
+    }
+
+    // For crash reports, see crash_report.rs
+    pub fn instruction_history(&self) -> &InstructionHistory {
+        &self.instruction_history
+    }
+
+    pub fn mut_exception_log(&mut self) -> &mut ExceptionLog {
+        &mut self.exception_log
+    }
+
+    pub fn dma_log(&self) -> &DmaLog {
+        &self.dma_log
+    }
+
+    pub fn mut_dma_log(&mut self) -> &mut DmaLog {
+        &mut self.dma_log
+    }
+
+    pub fn mmu(&self) -> &MMU {
+        &self.mmu
+    }
+
     pub fn mut_mmu(&mut self) -> &mut MMU {
         &mut self.mmu
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod emulator_tests {
+    use super::*;
+    use crate::savestate::SAVESTATE_MAGIC;
+
+    #[test]
+    fn test_savestate() {
+        let mut emulator = Emulator::new_hle();
+        emulator.mut_mmu().write_virtual(0x80000400, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        let state = emulator.save_state();
+
+        let mut other = Emulator::new();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.mmu().read_virtual(0x80000400, 4), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(other.cpu().registers().get_program_counter(), emulator.cpu().registers().get_program_counter());
+        assert_eq!(other.cpu().registers().get_by_name("sp"), emulator.cpu().registers().get_by_name("sp"));
+        assert!(other.load_state(&state[..state.len() - 1]).is_err());
+
+        // Raw states load too and are much bigger
+        emulator.set_compress_states(false);
+        let raw = emulator.save_state();
+        assert_eq!(&raw[..4], SAVESTATE_MAGIC);
+        assert!(raw.len() > state.len() * 10);
+        other.load_state(&raw).unwrap();
+        assert_eq!(other.mmu().read_virtual(0x80000400, 4), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert!(other.load_state(&raw[..raw.len() - 1]).is_err());
+
+        // A state cut in the middle of the MMU leaves the machine as it was, CPU included
+        other.mut_mmu().write_virtual(0x80000400, &[1, 2, 3, 4]);
+        other.mut_cpu().mut_registers().set_by_name("sp", 0x1234);
+        let frames = other.frames();
+        assert!(other.load_state(&raw[..raw.len() / 2]).is_err());
+        assert_eq!(other.mmu().read_virtual(0x80000400, 4), vec![1, 2, 3, 4]);
+        assert_eq!(other.cpu().registers().get_by_name("sp"), 0x1234);
+        assert_eq!(other.frames(), frames);
+    }
+
+    #[test]
+    fn test_run_frame_until() {
+        let mut emulator = Emulator::new_hle();
+        let mut target = RunTarget::Instructions(3);
+        assert!(emulator.run_frame_until(&mut target));
+        assert_eq!(emulator.cpu().registers().get_program_counter(), 0x8000100C);
+
+        let mut target = RunTarget::Address(0x80001100);
+        assert!(emulator.run_frame_until(&mut target));
+        assert_eq!(emulator.cpu().registers().get_program_counter(), 0x80001100);
+
+        let frames = emulator.frames();
+        assert!(emulator.run_frame_until(&mut RunTarget::VerticalInterrupt));
+        assert_eq!(emulator.frames(), frames + 1);
+    }
+
+    #[test]
+    fn test_dma_log() {
+        let mut emulator = Emulator::new_hle();
+        // sw t1, 0(t0) with t0 pointing to PI_WR_LEN
+        emulator.mut_mmu().write_virtual(0x80001000, &[0xAD, 0x09, 0x00, 0x00]);
+        emulator.set_register(RegisterName::GPR(8), 0xFFFFFFFFA460000C_u64 as i64);
+        emulator.set_register(RegisterName::GPR(9), 0x100);
+        emulator.set_register(RegisterName::PC, 0x80001000);
+        emulator.tick();
+        let records = emulator.dma_log().records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].transfer.length, 0x101);
+        assert_eq!(records[0].program_counter as u32, 0x80001000);
+    }
+}
@@ -0,0 +1,1036 @@
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::audio::{self, AudioBuffer, AI_DACRATE_ADDRESS};
+use crate::autosave::autosave_path;
+use crate::cheat::Cheat;
+use crate::controller_pak::controller_pak_paths;
+use crate::crash_report::{CrashReport, panic_message};
+use crate::debugger::{Breakpoint, BreakpointKind};
+use crate::emulator::{Emulator, RunTarget};
+use crate::dma::DmaRecord;
+use crate::exception::ExceptionRecord;
+use crate::expression::{Expression, EmulatorContext, RegisterName};
+use crate::hardware_registers;
+use crate::ipc::{self, IpcRequest, IpcResponse};
+use crate::mmu::{MMU, MEMORY_PAGE_SIZE};
+use crate::microcode::Microcode;
+use crate::os_threads::{self, MessageQueue, ThreadList};
+use crate::osd::osd;
+use crate::pif::{ControllerState, JoybusDevice, PakKind, CONTROLLER_PORTS, PIF_RAM_SIZE};
+use crate::rcp::FramebufferView;
+use crate::rdp::{RdpCommand, TileDescriptor, TILE_DESCRIPTORS, decode_commands};
+use crate::rdp_combiner::CombineMode;
+use crate::search::{MemorySearch, ValueType, Comparison, MAX_SEARCH_RESULTS};
+use crate::registers::CP0Registers;
+use crate::rom::{ROM, Region, RomInfo};
+use crate::rsp::RSP;
+use crate::scheduler::Event;
+use crate::tlb::{TLBEntry, TLB_ENTRIES};
+use crate::savestate_import;
+use crate::screenshot::{encode_png, screenshot_path};
+use crate::state_slots::{self, SlotInfo, Thumbnail, STATE_SLOTS};
+
+// A state is kept for rewinding every REWIND_INTERVAL frames, up to REWIND_STATES of them
+pub const REWIND_INTERVAL: u64 = 60;
+pub const REWIND_STATES: usize = 10;
+
+pub enum Command {
+    LoadRom(ROM),
+    Run,
+    // Runs until the target is reached, then pauses
+    RunTo(RunTarget),
+    Pause,
+    Step,
+    SetCpuClockMultiplier(u8),
+    SetRegionOverride(Option<Region>),
+    RequestSnapshot,
+    ReadMemoryPage { address: i64, virtual_address: bool },
+    WriteMemory { address: i64, virtual_address: bool, data: Vec<u8> },
+    AddBreakpoint { address: i64, len: i64, kind: BreakpointKind, condition: Option<Expression> },
+    RemoveBreakpoint(u32),
+    SetBreakpointEnabled(u32, bool),
+    SetRegister(RegisterName, i64),
+    SetCP0Register(usize, i64),
+    SetRspHalted(bool),
+    SetControllerState(usize, ControllerState),
+    // Buttons of the port to press and release on their own while held, and the frames per press and release
+    SetTurbo(usize, u16, u32),
+    // A real controller answering for the port, like a raw adapter. None goes back to the emulated one
+    SetPassthrough(usize, Option<Box<dyn JoybusDevice>>),
+    // Plugs or unplugs the emulated controller of the port
+    SetConnected(usize, bool),
+    // Swaps what's in the slot of the controller, the game sees the pak was pulled out
+    SetPak(usize, PakKind),
+    SetCheats(Vec<Cheat>),
+    // Shows the region instead of what VI_ORIGIN points to, None goes back to the VI
+    SetFramebufferOverride(Option<FramebufferView>),
+    SetTextureDump(bool),
+    SetViFilters(bool),
+    StepRsp,
+    ClearExceptionLog,
+    ClearDmaLog,
+    AddWatch(Expression),
+    RemoveWatch(usize),
+    StartSearch(ValueType),
+    Scan(Comparison),
+    ImportState(Vec<u8>),
+    SaveStateSlot(usize),
+    LoadStateSlot(usize),
+    RequestStateSlots,
+    // Whether to write the autosave of the game when it's unloaded or the emulator closes
+    SetAutosave(bool),
+    ResumeAutosave,
+    RequestRomInfo,
+    // libultra threads, walked from __osActiveQueue at the address given or searched for, see os_threads.rs
+    RequestThreads(Option<u32>),
+    // The same, then every message queue linked to those threads
+    RequestMessageQueues(Option<u32>),
+    // Runs without waiting for the console's refresh rate
+    SetFastForward(bool),
+    // Goes back to the last state kept for rewinding
+    Rewind,
+    // Saves the frame as a PNG with the screenshots of the game
+    Screenshot,
+    FlushSaves,
+    // From an external tool through ipc::start_server, answered on the sender
+    Ipc(IpcRequest, Sender<IpcResponse>),
+    Quit,
+}
+
+pub enum Response {
+    Snapshot(Snapshot),
+    Frame(Frame),
+    MemoryPage(MemoryPage),
+    SearchResults(SearchResults),
+    // One entry per slot, None for the empty ones
+    StateSlots(Vec<Option<SlotInfo>>),
+    // Sent after loading a game with an autosave, so it can be resumed
+    AutosaveFound { game: Option<String>, info: SlotInfo },
+    // None when no ROM is loaded
+    RomInfo(Option<RomInfo>),
+    // None when no libultra thread list was found
+    Threads(Option<ThreadList>),
+    MessageQueues { threads: Option<ThreadList>, queues: Vec<MessageQueue> },
+    Error(String),
+}
+
+// Copy of the emulator state that the GUI can render without touching the core
+pub struct Snapshot {
+    pub running: bool,
+    pub frames: u64,
+    pub program_counter: i64,
+    pub hi: i64,
+    pub lo: i64,
+    pub registers: [i64; 32],
+    // 32 bit registers are sign extended
+    pub cp0: [i64; 32],
+    pub fpr: [i64; 32],
+    // The FPRs as 32 bit instructions see them, depending on FR
+    pub fpr_words: [i32; 32],
+    pub fr: bool,
+    pub fcr31: u32,
+    pub tlb: [TLBEntry; TLB_ENTRIES],
+    pub tlb_last_used: Option<usize>,
+    pub rsp: RspSnapshot,
+    pub rdp: RdpSnapshot,
+    // Values of the registers listed in hardware_registers::INTERFACES
+    pub hardware_registers: Vec<Vec<u32>>,
+    // Oldest first
+    pub exceptions: Vec<ExceptionRecord>,
+    // Oldest first
+    pub dma_transfers: Vec<DmaRecord>,
+    pub cycles: u64,
+    pub speed: Speed,
+    pub scheduler_events: Vec<Event>,
+    pub cpu_clock_multiplier: u8,
+    pub region: Region,
+    pub region_override: Option<Region>,
+    // Frame buffer described by the VI registers, None when the output is blank
+    pub framebuffer: Option<FramebufferView>,
+    pub framebuffer_override: Option<FramebufferView>,
+    // Read, write and execute counts of every RDRAM page, see heatmap::AccessHeatmap
+    pub heatmap: Vec<[u32; 3]>,
+    pub pif_ram: [u8; PIF_RAM_SIZE],
+    // State the PIF reports for each port, None when nothing is plugged in
+    pub controllers: [Option<ControllerState>; CONTROLLER_PORTS],
+    pub audio: AudioSnapshot,
+    // None until a ROM is loaded
+    pub game_id: Option<String>,
+    pub controller_pak_paths: [Option<PathBuf>; CONTROLLER_PORTS],
+    // Whether the emulated controller is plugged in, the port answers anyway while a real controller is passed through
+    pub connected: [bool; CONTROLLER_PORTS],
+    pub paks: [PakKind; CONTROLLER_PORTS],
+    pub breakpoints: Vec<Breakpoint>,
+    pub breakpoint_hit: Option<u32>,
+    // Watch expressions and their current values
+    pub watches: Vec<(Expression, i64)>,
+}
+
+impl Snapshot {
+    pub fn new(emulator: &Emulator, running: bool, speed: Speed, watches: &[Expression]) -> Self {
+        let registers = emulator.cpu().registers();
+        let mut values = [0; 32];
+        for (index, value) in values.iter_mut().enumerate() {
+            *value = registers.get_by_number(index);
+        }
+        let mut fpr = [0; 32];
+        for (index, value) in fpr.iter_mut().enumerate() {
+            *value = emulator.cpu().cp1().get_fpr(index);
+        }
+        let fr = emulator.cpu().cp0().fr();
+        let fpr_words = std::array::from_fn(|index| emulator.cpu().cp1().get_fpr_32(index, fr));
+        let mut cp0 = [0; 32];
+        for (index, value) in cp0.iter_mut().enumerate() {
+            *value = match CP0Registers::is_32bits(index) {
+                true => emulator.cpu().cp0().get_by_number_32(index) as i64,
+                false => emulator.cpu().cp0().get_by_number_64(index),
+            };
+        }
+        Self {
+            running,
+            frames: emulator.frames(),
+            program_counter: registers.get_program_counter(),
+            hi: registers.get_hi(),
+            lo: registers.get_lo(),
+            registers: values,
+            cp0,
+            fpr,
+            fpr_words,
+            fr,
+            fcr31: emulator.cpu().cp1().get_fcr31() as u32,
+            tlb: *emulator.mmu().tlb().entries(),
+            tlb_last_used: emulator.mmu().tlb().last_used(),
+            rsp: RspSnapshot::new(emulator.mmu().rsp()),
+            rdp: RdpSnapshot::new(emulator.mmu()),
+            hardware_registers: hardware_registers::read_all(emulator.mmu()),
+            exceptions: emulator.exception_log().records().iter().copied().collect(),
+            dma_transfers: emulator.dma_log().records().iter().copied().collect(),
+            cycles: emulator.scheduler().get_cycles(),
+            speed,
+            scheduler_events: emulator.scheduler().pending_events(),
+            cpu_clock_multiplier: emulator.get_cpu_clock_multiplier(),
+            region: emulator.region(),
+            region_override: emulator.get_region_override(),
+            framebuffer: emulator.mmu().video_interface().framebuffer_view(),
+            framebuffer_override: emulator.mmu().video_interface().view_override(),
+            heatmap: emulator.mmu().heatmap().counts(),
+            pif_ram: *emulator.mmu().pif().ram(),
+            controllers: std::array::from_fn(|port| {
+                let pif = emulator.mmu().pif();
+                pif.is_connected(port).then(|| pif.controller(port))
+            }),
+            audio: AudioSnapshot::new(emulator),
+            game_id: emulator.mmu().rom().game_id(),
+            controller_pak_paths: controller_pak_paths(emulator.mmu().rom()),
+            connected: std::array::from_fn(|port| emulator.connected(port)),
+            paks: std::array::from_fn(|port| emulator.pak(port)),
+            breakpoints: emulator.debugger().breakpoints().to_vec(),
+            breakpoint_hit: emulator.debugger().hit(),
+            watches: evaluate_watches(emulator, watches),
+        }
+    }
+}
+
+pub struct RspSnapshot {
+    pub program_counter: u32,
+    pub status: u32,
+    pub semaphore: bool,
+    pub registers: [u32; 32],
+    pub vector_registers: [[u16; 8]; 32],
+    pub dmem: Vec<u8>,
+    pub imem: Vec<u8>,
+    pub microcode: Option<Microcode>,
+    pub microcodes: Vec<Microcode>,
+}
+
+impl RspSnapshot {
+    pub fn new(rsp: &RSP) -> Self {
+        Self {
+            program_counter: rsp.get_program_counter(),
+            status: rsp.get_status(),
+            semaphore: rsp.semaphore(),
+            registers: *rsp.registers(),
+            vector_registers: *rsp.vector_registers(),
+            dmem: rsp.dmem().to_vec(),
+            imem: rsp.imem().to_vec(),
+            microcode: rsp.microcode().cloned(),
+            microcodes: rsp.microcodes().to_vec(),
+        }
+    }
+}
+
+pub struct RdpSnapshot {
+    pub start: u32,
+    pub end: u32,
+    pub current: u32,
+    pub status: u32,
+    pub commands: Vec<RdpCommand>,
+    pub tmem: Vec<u8>,
+    pub tiles: [TileDescriptor; TILE_DESCRIPTORS],
+    pub cycle_type: u8,
+    pub combine_mode: CombineMode,
+    pub other_modes: u64,
+    pub blend_color: [u8; 4],
+    pub fog_color: [u8; 4],
+}
+
+impl RdpSnapshot {
+    pub fn new(mmu: &MMU) -> Self {
+        let rdp = mmu.rdp();
+        Self {
+            start: rdp.get_start(),
+            end: rdp.get_end(),
+            current: rdp.get_current(),
+            status: rdp.get_status(),
+            commands: decode_commands(&mmu.rdp_command_buffer(), rdp.get_start()),
+            tmem: rdp.tmem().to_vec(),
+            tiles: *rdp.tiles(),
+            cycle_type: rdp.cycle_type(),
+            combine_mode: *rdp.combiner().mode(),
+            other_modes: rdp.other_modes(),
+            blend_color: rdp.blender().blend_color(),
+            fog_color: rdp.blender().fog_color(),
+        }
+    }
+}
+
+pub struct AudioSnapshot {
+    // Oldest first
+    pub buffers: Vec<AudioBuffer>,
+    pub samples: Vec<[i16; 2]>,
+    pub sample_rate: u64,
+}
+
+impl AudioSnapshot {
+    pub fn new(emulator: &Emulator) -> Self {
+        let monitor = emulator.mmu().audio();
+        let dacrate = u32::from_be_bytes(emulator.mmu().read_physical(AI_DACRATE_ADDRESS, 4).try_into().unwrap());
+        Self {
+            buffers: monitor.buffers().iter().copied().collect(),
+            samples: monitor.samples().iter().copied().collect(),
+            sample_rate: audio::sample_rate(emulator.timing().vi_clock_rate, dacrate),
+        }
+    }
+}
+
+// Emulation speed over the last measured interval, zero until a full interval ran
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Speed {
+    pub vi_per_second: f64,
+    pub instructions_per_second: f64,
+    // 100 means the console's refresh rate is reached
+    pub percentage: f64,
+}
+
+impl Speed {
+    pub fn measure(frames: u64, instructions: u64, elapsed: Duration, refresh_rate: u64) -> Self {
+        let seconds = elapsed.as_secs_f64();
+        let vi_per_second = frames as f64 / seconds;
+        Self {
+            vi_per_second,
+            instructions_per_second: instructions as f64 / seconds,
+            percentage: vi_per_second * 100.0 / refresh_rate as f64,
+        }
+    }
+}
+
+const SPEED_INTERVAL: Duration = Duration::from_millis(500);
+
+struct SpeedMeter {
+    start: Instant,
+    frames: u64,
+    cycles: u64,
+    speed: Speed,
+}
+
+impl SpeedMeter {
+    fn new(emulator: &Emulator) -> Self {
+        Self {
+            start: Instant::now(),
+            frames: emulator.frames(),
+            cycles: emulator.scheduler().get_cycles(),
+            speed: Speed::default(),
+        }
+    }
+
+    // Every tick runs one instruction, so the cycle counter also counts instructions
+    fn update(&mut self, emulator: &Emulator) {
+        let elapsed = self.start.elapsed();
+        if elapsed < SPEED_INTERVAL {
+            return;
+        }
+        let frames = emulator.frames().saturating_sub(self.frames);
+        let cycles = emulator.scheduler().get_cycles().saturating_sub(self.cycles);
+        let speed = Speed::measure(frames, cycles, elapsed, emulator.timing().refresh_rate);
+        *self = SpeedMeter::new(emulator);
+        self.speed = speed;
+    }
+}
+
+fn evaluate_watches(emulator: &Emulator, watches: &[Expression]) -> Vec<(Expression, i64)> {
+    let context = EmulatorContext {
+        cpu: emulator.cpu(),
+        mmu: emulator.mmu(),
+    };
+    watches.iter()
+        .map(|watch| (watch.clone(), watch.evaluate(&context)))
+        .collect()
+}
+
+// RGBA8888 picture of the frame buffer
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+// MEMORY_PAGE_SIZE bytes starting at the page aligned address
+pub struct MemoryPage {
+    pub address: i64,
+    pub virtual_address: bool,
+    pub data: Vec<u8>,
+}
+
+// RDRAM offsets and values of the first MAX_SEARCH_RESULTS matches
+pub struct SearchResults {
+    pub value_type: ValueType,
+    pub count: usize,
+    pub results: Vec<(u32, f64)>,
+}
+
+impl SearchResults {
+    fn new(search: &MemorySearch) -> Self {
+        Self {
+            value_type: search.value_type(),
+            count: search.count(),
+            results: search.results(MAX_SEARCH_RESULTS),
+        }
+    }
+}
+
+/*
+    Runs the emulator on its own thread so the GUI stays responsive while the game runs.
+    The GUI drives it with Commands and receives Snapshots and Frames back.
+*/
+pub struct EmulatorThread {
+    sender: Sender<Command>,
+    receiver: Receiver<Response>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EmulatorThread {
+    pub fn spawn(emulator: Emulator) -> Self {
+        let (command_sender, command_receiver) = channel();
+        let (response_sender, response_receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            emulation_loop(emulator, command_receiver, response_sender);
+        });
+        Self {
+            sender: command_sender,
+            receiver: response_receiver,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        let _ = self.sender.send(command);
+    }
+
+    pub fn try_recv(&self) -> Option<Response> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn start_ipc_server(&self, port: u16) -> std::io::Result<SocketAddr> {
+        ipc::start_server(port, self.sender.clone())
+    }
+}
+
+impl Drop for EmulatorThread {
+    fn drop(&mut self) {
+        self.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Nothing is sent while the frame buffer stays the same
+fn send_frame(emulator: &mut Emulator, responses: &Sender<Response>) {
+    if let Some((width, height, pixels)) = emulator.mut_mmu().take_frame() {
+        let _ = responses.send(Response::Frame(Frame {
+            width,
+            height,
+            pixels,
+        }));
+    }
+}
+
+fn state_slot_path(emulator: &Emulator, slot: usize) -> std::io::Result<PathBuf> {
+    state_slots::slot_path(emulator.mmu().rom(), slot)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No ROM loaded from a file"))
+}
+
+fn save_state_slot(emulator: &Emulator, slot: usize) -> std::io::Result<()> {
+    let thumbnail = emulator.mmu().framebuffer_rgba().map(|(width, height, pixels)| Thumbnail::new(width, height, &pixels));
+    std::fs::write(state_slot_path(emulator, slot)?, state_slots::write_slot(thumbnail.as_ref(), &emulator.save_state()))
+}
+
+// Nothing is written for a game that didn't run, it would replace the previous autosave with a fresh boot
+fn write_autosave(emulator: &Emulator, responses: &Sender<Response>) {
+    let path = match autosave_path(emulator.mmu().rom()) {
+        Some(path) if emulator.frames() > 0 => path,
+        _ => return,
+    };
+    let thumbnail = emulator.mmu().framebuffer_rgba().map(|(width, height, pixels)| Thumbnail::new(width, height, &pixels));
+    if let Err(err) = std::fs::write(&path, state_slots::write_slot(thumbnail.as_ref(), &emulator.save_state())) {
+        let _ = responses.send(Response::Error(format!("Could not write the autosave {}: {}", path.display(), err)));
+    }
+}
+
+fn send_autosave(emulator: &Emulator, responses: &Sender<Response>) {
+    let info = autosave_path(emulator.mmu().rom()).and_then(|path| state_slots::read_slot_info(&path));
+    if let Some(info) = info {
+        let _ = responses.send(Response::AutosaveFound { game: emulator.mmu().rom().game_id(), info });
+    }
+}
+
+fn load_autosave(emulator: &mut Emulator) -> std::io::Result<()> {
+    let path = autosave_path(emulator.mmu().rom())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No ROM loaded from a file"))?;
+    emulator.load_state(state_slots::read_slot(&std::fs::read(path)?)?)
+}
+
+fn load_state_slot(emulator: &mut Emulator, slot: usize) -> std::io::Result<()> {
+    let data = std::fs::read(state_slot_path(emulator, slot)?)?;
+    emulator.load_state(state_slots::read_slot(&data)?)
+}
+
+fn save_screenshot(emulator: &Emulator) -> std::io::Result<PathBuf> {
+    let (width, height, pixels) = emulator.mmu().framebuffer_rgba()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "The video output is blank"))?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let path = screenshot_path(emulator.mmu().rom(), timestamp)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No ROM loaded from a file"))?;
+    std::fs::write(&path, encode_png(width, height, &pixels))?;
+    Ok(path)
+}
+
+// Files homebrew sent through the flashcart USB go next to the ROM
+fn write_usb_files(emulator: &mut Emulator, responses: &Sender<Response>) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    for (index, file) in emulator.mut_mmu().take_usb_files().iter().enumerate() {
+        match file.write(emulator.mmu().rom(), timestamp, index) {
+            Ok(path) => osd!("USB data saved to {}", path.display()),
+            Err(err) => {
+                let _ = responses.send(Response::Error(format!("Could not save the USB data: {}", err)));
+            },
+        };
+    }
+}
+
+fn send_state_slots(emulator: &Emulator, responses: &Sender<Response>) {
+    let slots = (0..STATE_SLOTS)
+        .map(|slot| state_slots::slot_path(emulator.mmu().rom(), slot).and_then(|path| state_slots::read_slot_info(&path)))
+        .collect();
+    let _ = responses.send(Response::StateSlots(slots));
+}
+
+// Runs the closure, turning a panic of the core into an error message
+fn guarded<F: FnOnce()>(responses: &Sender<Response>, f: F) -> bool {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(_) => true,
+        Err(err) => {
+            let _ = responses.send(Response::Error(panic_message(err.as_ref())));
+            false
+        },
+    }
+}
+
+// Like guarded for running the CPU, where a panic also leaves a crash report next to the ROM
+fn guarded_run<F: FnOnce(&mut Emulator)>(emulator: &mut Emulator, responses: &Sender<Response>, f: F) -> bool {
+    let err = match catch_unwind(AssertUnwindSafe(|| f(emulator))) {
+        Ok(_) => return true,
+        Err(err) => err,
+    };
+    let message = panic_message(err.as_ref());
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let report = CrashReport::capture(emulator, &message);
+    let message = match report.write(emulator, timestamp) {
+        Ok(path) => format!("{}, crash report written to {}", message, path.display()),
+        Err(err) => format!("{}, could not write the crash report: {}", message, err),
+    };
+    let _ = responses.send(Response::Error(message));
+    false
+}
+
+fn emulation_loop(mut emulator: Emulator, commands: Receiver<Command>, responses: Sender<Response>) {
+    let mut running = false;
+    let mut next_frame = Instant::now();
+    let mut search: Option<MemorySearch> = None;
+    let mut watches: Vec<Expression> = Vec::new();
+    let mut speed = SpeedMeter::new(&emulator);
+    let mut target: Option<RunTarget> = None;
+    let mut fast_forward = false;
+    let mut autosave = false;
+    // Frame number and state, oldest first
+    let mut rewind: VecDeque<(u64, Vec<u8>)> = VecDeque::with_capacity(REWIND_STATES);
+    loop {
+        let command = match running {
+            true => match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            },
+            false => match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => break,
+            },
+        };
+
+        if let Some(command) = command {
+            match command {
+                Command::LoadRom(rom) => {
+                    if autosave {
+                        write_autosave(&emulator, &responses);
+                    }
+                    emulator.load_rom(rom);
+                    running = false;
+                    rewind.clear();
+                    send_autosave(&emulator, &responses);
+                },
+                Command::Run => {
+                    emulator.mut_debugger().resume();
+                    running = true;
+                    next_frame = Instant::now();
+                    speed = SpeedMeter::new(&emulator);
+                    target = None;
+                },
+                Command::RunTo(run_target) => {
+                    emulator.mut_debugger().resume();
+                    running = true;
+                    next_frame = Instant::now();
+                    speed = SpeedMeter::new(&emulator);
+                    target = Some(run_target);
+                },
+                Command::Pause => {
+                    running = false;
+                    target = None;
+                },
+                Command::Step => {
+                    emulator.mut_debugger().resume();
+                    running = false;
+                    guarded_run(&mut emulator, &responses, |emulator| {
+                        emulator.tick();
+                    });
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::SetCpuClockMultiplier(multiplier) => emulator.set_cpu_clock_multiplier(multiplier),
+                Command::SetRegionOverride(region) => emulator.set_region_override(region),
+                Command::RequestSnapshot => {
+                    guarded(&responses, || {
+                        let _ = responses.send(Response::Snapshot(Snapshot::new(&emulator, running, speed.speed, &watches)));
+                    });
+                },
+                Command::ReadMemoryPage { address, virtual_address } => {
+                    guarded(&responses, || {
+                        let data = emulator.mmu().read_page(address, virtual_address);
+                        let _ = responses.send(Response::MemoryPage(MemoryPage {
+                            address: address & !(MEMORY_PAGE_SIZE as i64 - 1),
+                            virtual_address,
+                            data,
+                        }));
+                    });
+                },
+                // Goes through the MMU like a CPU store so the devices see the write
+                Command::WriteMemory { address, virtual_address, data } => {
+                    guarded(&responses, || match virtual_address {
+                        true => emulator.mut_mmu().write_virtual(address, &data),
+                        false => emulator.mut_mmu().write_physical(address, &data),
+                    });
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::AddBreakpoint { address, len, kind, condition } => {
+                    emulator.mut_debugger().add(address, len, kind, condition);
+                },
+                Command::RemoveBreakpoint(id) => emulator.mut_debugger().remove(id),
+                Command::SetBreakpointEnabled(id, enabled) => emulator.mut_debugger().set_enabled(id, enabled),
+                Command::SetRegister(register, value) => emulator.set_register(register, value),
+                Command::SetControllerState(port, state) => emulator.mut_mmu().mut_pif().set_controller(port, state),
+                Command::SetTurbo(port, buttons, period) => emulator.mut_mmu().mut_pif().set_turbo(port, buttons, period),
+                Command::SetPassthrough(port, device) => emulator.mut_mmu().mut_pif().set_passthrough(port, device),
+                Command::SetCheats(cheats) => emulator.set_cheats(cheats),
+                Command::SetConnected(port, connected) => {
+                    emulator.set_connected(port, connected);
+                    osd!("Controller {} {}", port + 1, if connected { "connected" } else { "disconnected" });
+                },
+                Command::SetPak(port, kind) => {
+                    emulator.set_pak(port, kind);
+                    osd!("Port {}: {}", port + 1, kind.name());
+                },
+                Command::SetFramebufferOverride(view) => {
+                    emulator.mut_mmu().mut_video_interface().set_view_override(view);
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::SetTextureDump(enabled) => emulator.set_texture_dump(enabled),
+                Command::SetViFilters(enabled) => {
+                    emulator.set_vi_filters(enabled);
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::SetCP0Register(index, value) => {
+                    let cp0 = emulator.mut_cpu().mut_cp0();
+                    match CP0Registers::is_32bits(index) {
+                        true => cp0.set_by_number_32(index, value as i32),
+                        false => cp0.set_by_number_64(index, value),
+                    };
+                },
+                Command::SetRspHalted(halted) => emulator.mut_mmu().mut_rsp().set_halted(halted),
+                // Steps the RSP alone, even when it is halted
+                Command::StepRsp => emulator.mut_mmu().step_rsp(),
+                Command::ClearExceptionLog => emulator.mut_exception_log().clear(),
+                Command::ClearDmaLog => emulator.mut_dma_log().clear(),
+                Command::AddWatch(expression) => watches.push(expression),
+                Command::RemoveWatch(index) => {
+                    if index < watches.len() {
+                        watches.remove(index);
+                    }
+                },
+                Command::StartSearch(value_type) => {
+                    let new_search = MemorySearch::new(value_type, emulator.mmu().rdram().as_slice());
+                    let _ = responses.send(Response::SearchResults(SearchResults::new(&new_search)));
+                    search = Some(new_search);
+                },
+                Command::Scan(comparison) => {
+                    if let Some(search) = search.as_mut() {
+                        search.scan(emulator.mmu().rdram().as_slice(), comparison);
+                        let _ = responses.send(Response::SearchResults(SearchResults::new(search)));
+                    }
+                },
+                Command::ImportState(data) => {
+                    if let Err(err) = savestate_import::import(&mut emulator, &data) {
+                        let _ = responses.send(Response::Error(format!("Could not import savestate: {}", err)));
+                    }
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::SaveStateSlot(slot) => {
+                    match save_state_slot(&emulator, slot) {
+                        Ok(_) => osd!("State saved to slot {}", slot),
+                        Err(err) => {
+                            let _ = responses.send(Response::Error(format!("Could not save to slot {}: {}", slot, err)));
+                        },
+                    };
+                    send_state_slots(&emulator, &responses);
+                },
+                Command::LoadStateSlot(slot) => {
+                    match load_state_slot(&mut emulator, slot) {
+                        Ok(_) => osd!("State loaded from slot {}", slot),
+                        Err(err) => {
+                            let _ = responses.send(Response::Error(format!("Could not load slot {}: {}", slot, err)));
+                        },
+                    };
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::RequestStateSlots => send_state_slots(&emulator, &responses),
+                Command::SetAutosave(enabled) => autosave = enabled,
+                Command::ResumeAutosave => {
+                    match load_autosave(&mut emulator) {
+                        Ok(_) => osd!("Resumed from the autosave"),
+                        Err(err) => {
+                            let _ = responses.send(Response::Error(format!("Could not resume: {}", err)));
+                        },
+                    };
+                    rewind.clear();
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::RequestRomInfo => {
+                    let _ = responses.send(Response::RomInfo(emulator.mmu().rom().info()));
+                },
+                Command::RequestThreads(active_queue) => {
+                    let threads = os_threads::threads(emulator.mmu().rdram().as_slice(), active_queue);
+                    let _ = responses.send(Response::Threads(threads));
+                },
+                Command::RequestMessageQueues(active_queue) => {
+                    let rdram = emulator.mmu().rdram().as_slice();
+                    let threads = os_threads::threads(rdram, active_queue);
+                    let queues = threads.as_ref().map(|list| os_threads::message_queues(rdram, list)).unwrap_or_default();
+                    let _ = responses.send(Response::MessageQueues { threads, queues });
+                },
+                Command::SetFastForward(enabled) => {
+                    fast_forward = enabled;
+                    next_frame = Instant::now();
+                    osd!("Fast forward {}", if enabled { "ON" } else { "OFF" });
+                },
+                Command::Rewind => {
+                    match rewind.pop_back() {
+                        Some((frame, state)) => match emulator.load_state(&state) {
+                            Ok(_) => osd!("Rewound to frame {}", frame),
+                            Err(err) => {
+                                let _ = responses.send(Response::Error(format!("Could not rewind: {}", err)));
+                            },
+                        },
+                        None => osd!("Nothing to rewind"),
+                    };
+                    send_frame(&mut emulator, &responses);
+                },
+                Command::Screenshot => {
+                    match save_screenshot(&emulator) {
+                        Ok(path) => osd!("Screenshot saved to {}", path.display()),
+                        Err(err) => {
+                            let _ = responses.send(Response::Error(format!("Could not save the screenshot: {}", err)));
+                        },
+                    };
+                },
+                Command::FlushSaves => emulator.flush_saves(),
+                Command::Ipc(request, reply) => {
+                    let response = match request {
+                        IpcRequest::Status => IpcResponse::Status {
+                            running,
+                            frames: emulator.frames(),
+                            program_counter: emulator.cpu().registers().get_program_counter(),
+                        },
+                        IpcRequest::Pause => {
+                            running = false;
+                            target = None;
+                            IpcResponse::Ok
+                        },
+                        IpcRequest::Resume => {
+                            emulator.mut_debugger().resume();
+                            running = true;
+                            next_frame = Instant::now();
+                            speed = SpeedMeter::new(&emulator);
+                            target = None;
+                            IpcResponse::Ok
+                        },
+                        request => {
+                            // Like the GUI's Step, stepping pauses
+                            if let IpcRequest::Step { .. } = request {
+                                running = false;
+                            }
+                            let mut response = IpcResponse::error("Emulation stopped");
+                            guarded_run(&mut emulator, &responses, |emulator| response = ipc::handle_request(emulator, request));
+                            send_frame(&mut emulator, &responses);
+                            response
+                        },
+                    };
+                    let _ = reply.send(response);
+                },
+                Command::Quit => {
+                    if autosave {
+                        write_autosave(&emulator, &responses);
+                    }
+                    break;
+                },
+            };
+            continue;
+        }
+
+        let mut reached = false;
+        running = guarded_run(&mut emulator, &responses, |emulator| match target.as_mut() {
+            Some(target) => reached = emulator.run_frame_until(target),
+            None => emulator.run_frame(),
+        }) && emulator.debugger().hit().is_none() && !reached;
+        if emulator.take_pause_request() {
+            running = false;
+        }
+        if !running {
+            target = None;
+        }
+        write_usb_files(&mut emulator, &responses);
+        send_frame(&mut emulator, &responses);
+        speed.update(&emulator);
+        if emulator.frames() % REWIND_INTERVAL == 0 && rewind.back().map(|(frame, _)| *frame) != Some(emulator.frames()) {
+            if rewind.len() == REWIND_STATES {
+                rewind.pop_front();
+            }
+            rewind.push_back((emulator.frames(), emulator.save_state()));
+        }
+
+        // Keep the emulation at the console's refresh rate
+        if fast_forward {
+            continue;
+        }
+        next_frame += Duration::from_secs(1) / (emulator.timing().refresh_rate as u32);
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    emulator.flush_saves();
+}
+
+#[cfg(test)]
+mod emulator_thread_tests {
+    use super::*;
+    use crate::rsp::SP_STATUS_HALT;
+
+    fn wait_snapshot(thread: &EmulatorThread) -> Snapshot {
+        thread.send(Command::RequestSnapshot);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            match thread.try_recv() {
+                Some(Response::Snapshot(snapshot)) => return snapshot,
+                Some(_) => {},
+                None => std::thread::sleep(Duration::from_millis(1)),
+            };
+        }
+        panic!("No snapshot received");
+    }
+
+    #[test]
+    fn test_speed() {
+        let speed = Speed::measure(30, 46_875_000, Duration::from_millis(500), 60);
+        assert_eq!(speed.vi_per_second, 60.0);
+        assert_eq!(speed.instructions_per_second, 93_750_000.0);
+        assert_eq!(speed.percentage, 100.0);
+    }
+
+    #[test]
+    fn test_step() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        let snapshot = wait_snapshot(&thread);
+        assert!(!snapshot.running);
+        assert_eq!(snapshot.program_counter, 0x80001000);
+
+        thread.send(Command::Step);
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.program_counter, 0x80001004);
+    }
+
+    #[test]
+    fn test_run_to() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::RunTo(RunTarget::Instructions(4)));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let snapshot = wait_snapshot(&thread);
+            if !snapshot.running {
+                assert_eq!(snapshot.program_counter, 0x80001010);
+                break;
+            }
+            assert!(Instant::now() < deadline);
+        }
+    }
+
+    #[test]
+    fn test_read_memory_page() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::ReadMemoryPage { address: 0x80000310, virtual_address: true });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(Response::MemoryPage(page)) = thread.try_recv() {
+                assert_eq!(page.address, 0x80000300);
+                assert_eq!(page.data.len(), MEMORY_PAGE_SIZE);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("No memory page received");
+    }
+
+    #[test]
+    fn test_write_memory() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::WriteMemory { address: 0x80000404, virtual_address: true, data: vec![0xDE, 0xAD] });
+        thread.send(Command::ReadMemoryPage { address: 0x400, virtual_address: false });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(Response::MemoryPage(page)) = thread.try_recv() {
+                assert_eq!(&page.data[4..6], &[0xDE, 0xAD]);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("No memory page received");
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::AddBreakpoint { address: 0x80001008, len: 1, kind: BreakpointKind::Execute, condition: None });
+        thread.send(Command::Run);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let snapshot = wait_snapshot(&thread);
+            if !snapshot.running && snapshot.breakpoint_hit.is_some() {
+                assert_eq!(snapshot.program_counter, 0x80001008);
+                assert_eq!(snapshot.breakpoints[0].hit_count, 1);
+                break;
+            }
+            assert!(Instant::now() < deadline, "The breakpoint was not hit");
+        }
+    }
+
+    #[test]
+    fn test_watches() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::AddWatch(Expression::parse("pc + 4").unwrap()));
+        thread.send(Command::AddWatch(Expression::parse("w[0x80000300] + 5").unwrap()));
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.watches[0].1, 0x80001004);
+        assert_eq!(snapshot.watches[1].1, 5);
+
+        thread.send(Command::RemoveWatch(0));
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.watches.len(), 1);
+    }
+
+    #[test]
+    fn test_set_cp0_register() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::SetCP0Register(12, 0xFFFFFFFF));
+        thread.send(Command::SetCP0Register(4, 0x12345678_9ABCDEF0));
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.cp0[12], -1);
+        assert_eq!(snapshot.cp0[4], 0x12345678_9ABCDEF0);
+    }
+
+    #[test]
+    fn test_rsp_controls() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::StepRsp);
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.rsp.program_counter, 4);
+        assert_eq!(snapshot.rsp.status, SP_STATUS_HALT);
+
+        thread.send(Command::SetRspHalted(false));
+        thread.send(Command::Step);
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.rsp.program_counter, 8);
+        assert_eq!(snapshot.rsp.status, 0);
+    }
+
+    #[test]
+    fn test_set_register() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::SetRegister(RegisterName::GPR(8), -5));
+        thread.send(Command::SetRegister(RegisterName::GPR(0), 5));
+        thread.send(Command::SetRegister(RegisterName::Hi, 1));
+        thread.send(Command::SetRegister(RegisterName::PC, 0x80002000));
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.registers[8], -5);
+        assert_eq!(snapshot.registers[0], 0);
+        assert_eq!(snapshot.hi, 1);
+        assert_eq!(snapshot.program_counter, 0x80002000);
+
+        thread.send(Command::Step);
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.program_counter, 0x80002004);
+    }
+
+    #[test]
+    fn test_settings() {
+        let thread = EmulatorThread::spawn(Emulator::new_hle());
+        thread.send(Command::SetCpuClockMultiplier(2));
+        thread.send(Command::SetRegionOverride(Some(Region::PAL)));
+        let snapshot = wait_snapshot(&thread);
+        assert_eq!(snapshot.cpu_clock_multiplier, 2);
+        assert_eq!(snapshot.region, Region::PAL);
+    }
+}
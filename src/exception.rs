@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+// ExcCode values: https://n64brew.dev/wiki/COP0#Cause
+pub const EXCEPTION_INTERRUPT: u8 = 0;
+pub const EXCEPTION_INSTRUCTION_BUS_ERROR: u8 = 6;
+pub const EXCEPTION_DATA_BUS_ERROR: u8 = 7;
+pub const EXCEPTION_SYSCALL: u8 = 8;
+pub const EXCEPTION_BREAKPOINT: u8 = 9;
+pub const EXCEPTION_OVERFLOW: u8 = 12;
+// Cause bit set when the exception was raised in a branch delay slot
+pub const CAUSE_BD: i32 = 1 << 31;
+
+// How many exceptions the history keeps before dropping the oldest ones
+pub const EXCEPTION_LOG_SIZE: usize = 256;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Exception {
+    pub code: u8,
+    // Address of the instruction that caused it, or the one that was interrupted
+    pub program_counter: i64,
+    pub cause: u32,
+    pub vector: i64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ExceptionRecord {
+    pub exception: Exception,
+    pub cycle: u64,
+}
+
+pub struct ExceptionLog {
+    records: VecDeque<ExceptionRecord>,
+}
+
+impl ExceptionLog {
+    pub fn new() -> Self {
+        Self {
+            records: VecDeque::with_capacity(EXCEPTION_LOG_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, exception: Exception, cycle: u64) {
+        if self.records.len() == EXCEPTION_LOG_SIZE {
+            self.records.pop_front();
+        }
+        self.records.push_back(ExceptionRecord {
+            exception,
+            cycle,
+        });
+    }
+
+    pub fn records(&self) -> &VecDeque<ExceptionRecord> {
+        &self.records
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+mod exception_tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut log = ExceptionLog::new();
+        for cycle in 0..EXCEPTION_LOG_SIZE as u64 + 10 {
+            log.push(Exception {
+                code: EXCEPTION_SYSCALL,
+                program_counter: 0x80001000,
+                cause: 0x20,
+                vector: 0x80000180,
+            }, cycle);
+        }
+        assert_eq!(log.records().len(), EXCEPTION_LOG_SIZE);
+        assert_eq!(log.records()[0].cycle, 10);
+        log.clear();
+        assert!(log.records().is_empty());
+    }
+}
@@ -0,0 +1,447 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::mmu::MMU;
+use crate::registers::CPU_REGISTER_NAMES;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RegisterName {
+    GPR(usize),
+    PC,
+    Hi,
+    Lo,
+}
+
+impl RegisterName {
+    // Accepts the ABI names ("t0", "sp") as well as "r8" style names
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "pc" => return Some(Self::PC),
+            "hi" => return Some(Self::Hi),
+            "lo" => return Some(Self::Lo),
+            "fp" => return Some(Self::GPR(30)),
+            _ => {},
+        };
+        if let Some(index) = CPU_REGISTER_NAMES.iter().position(|register| *register == name) {
+            return Some(Self::GPR(index));
+        }
+        match name.strip_prefix('r').map(|number| number.parse::<usize>()) {
+            Some(Ok(index)) if index < 32 => Some(Self::GPR(index)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RegisterName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::GPR(index) => write!(f, "{}", CPU_REGISTER_NAMES[*index]),
+            Self::PC => write!(f, "pc"),
+            Self::Hi => write!(f, "hi"),
+            Self::Lo => write!(f, "lo"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+    BitwiseNot,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BinaryOperator {
+    Or,
+    And,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+// (symbol, operator, precedence), the longer symbols go first so "<=" isn't read as "<"
+const BINARY_OPERATORS: [(&str, BinaryOperator, u8); 18] = [
+    ("||", BinaryOperator::Or, 1),
+    ("&&", BinaryOperator::And, 2),
+    ("==", BinaryOperator::Equal, 6),
+    ("!=", BinaryOperator::NotEqual, 6),
+    ("<=", BinaryOperator::LessEqual, 7),
+    (">=", BinaryOperator::GreaterEqual, 7),
+    ("<<", BinaryOperator::ShiftLeft, 8),
+    (">>", BinaryOperator::ShiftRight, 8),
+    ("|", BinaryOperator::BitwiseOr, 3),
+    ("^", BinaryOperator::BitwiseXor, 4),
+    ("&", BinaryOperator::BitwiseAnd, 5),
+    ("<", BinaryOperator::Less, 7),
+    (">", BinaryOperator::Greater, 7),
+    ("+", BinaryOperator::Add, 9),
+    ("-", BinaryOperator::Subtract, 9),
+    ("*", BinaryOperator::Multiply, 10),
+    ("/", BinaryOperator::Divide, 10),
+    ("%", BinaryOperator::Remainder, 10),
+];
+
+impl BinaryOperator {
+    fn symbol(&self) -> &'static str {
+        BINARY_OPERATORS.iter().find(|(_, operator, _)| operator == self).unwrap().0
+    }
+
+    fn apply(&self, left: i64, right: i64) -> i64 {
+        match self {
+            Self::Or => (left != 0 || right != 0) as i64,
+            Self::And => (left != 0 && right != 0) as i64,
+            Self::BitwiseOr => left | right,
+            Self::BitwiseXor => left ^ right,
+            Self::BitwiseAnd => left & right,
+            Self::Equal => (left == right) as i64,
+            Self::NotEqual => (left != right) as i64,
+            Self::Less => (left < right) as i64,
+            Self::LessEqual => (left <= right) as i64,
+            Self::Greater => (left > right) as i64,
+            Self::GreaterEqual => (left >= right) as i64,
+            Self::ShiftLeft => left.wrapping_shl(right as u32),
+            Self::ShiftRight => ((left as u64).wrapping_shr(right as u32)) as i64,
+            Self::Add => left.wrapping_add(right),
+            Self::Subtract => left.wrapping_sub(right),
+            Self::Multiply => left.wrapping_mul(right),
+            Self::Divide => left.checked_div(right).unwrap_or(0),
+            Self::Remainder => left.checked_rem(right).unwrap_or(0),
+        }
+    }
+}
+
+/*
+    Debugger expressions like `t0 == 0xDEADBEEF && [0x8012A0] > 5`.
+    [address] reads a word, b[], h[] and d[] read a byte, a halfword and a doubleword.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expression {
+    Number(i64),
+    Register(RegisterName),
+    Memory(Box<Expression>, usize),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+}
+
+// What an expression can look at while it gets evaluated
+pub trait Context {
+    fn register(&self, register: RegisterName) -> i64;
+    fn read(&self, address: i64, size: usize) -> i64;
+}
+
+/*
+    Registers are seen as their low 32 bits like the games use them, so a sign extended
+    0xFFFFFFFFDEADBEEF still compares equal to 0xDEADBEEF.
+*/
+pub struct EmulatorContext<'a> {
+    pub cpu: &'a CPU,
+    pub mmu: &'a MMU,
+}
+
+impl<'a> Context for EmulatorContext<'a> {
+    fn register(&self, register: RegisterName) -> i64 {
+        let registers = self.cpu.registers();
+        let value = match register {
+            RegisterName::GPR(index) => registers.get_by_number(index),
+            RegisterName::PC => registers.get_program_counter(),
+            RegisterName::Hi => registers.get_hi(),
+            RegisterName::Lo => registers.get_lo(),
+        };
+        value & 0xFFFFFFFF
+    }
+
+    fn read(&self, address: i64, size: usize) -> i64 {
+        // Not counted in the access heatmap, the debugger shouldn't show up in it
+        self.mmu.read_physical(self.mmu.translate(address), size).iter().fold(0, |value, byte| (value << 8) | *byte as i64)
+    }
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expression = parser.expression(0)?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(format!("Unexpected {}", token)),
+        }
+    }
+
+    pub fn evaluate(&self, context: &dyn Context) -> i64 {
+        match self {
+            Self::Number(value) => *value,
+            Self::Register(register) => context.register(*register),
+            Self::Memory(address, size) => context.read(address.evaluate(context), *size),
+            Self::Unary(operator, operand) => {
+                let value = operand.evaluate(context);
+                match operator {
+                    UnaryOperator::Negate => value.wrapping_neg(),
+                    UnaryOperator::Not => (value == 0) as i64,
+                    UnaryOperator::BitwiseNot => !value,
+                }
+            },
+            Self::Binary(operator, left, right) => {
+                let left = left.evaluate(context);
+                // Short circuit so a condition can guard a memory read
+                match (operator, left != 0) {
+                    (BinaryOperator::And, false) => 0,
+                    (BinaryOperator::Or, true) => 1,
+                    _ => operator.apply(left, right.evaluate(context)),
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(value) if *value > 9 => write!(f, "0x{:X}", value),
+            Self::Number(value) => write!(f, "{}", value),
+            Self::Register(register) => write!(f, "{}", register),
+            Self::Memory(address, size) => {
+                let prefix = match size {
+                    1 => "b",
+                    2 => "h",
+                    8 => "d",
+                    _ => "",
+                };
+                write!(f, "{}[{}]", prefix, address)
+            },
+            Self::Unary(operator, operand) => {
+                let symbol = match operator {
+                    UnaryOperator::Negate => "-",
+                    UnaryOperator::Not => "!",
+                    UnaryOperator::BitwiseNot => "~",
+                };
+                write!(f, "{}{}", symbol, operand)
+            },
+            Self::Binary(operator, left, right) => write!(f, "({} {} {})", left, operator.symbol(), right),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Operator(&'static str),
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Number(value) => write!(f, "{}", value),
+            Self::Identifier(name) => write!(f, "\"{}\"", name),
+            Self::Operator(symbol) => write!(f, "\"{}\"", symbol),
+            Self::OpenParen => write!(f, "\"(\""),
+            Self::CloseParen => write!(f, "\")\""),
+            Self::OpenBracket => write!(f, "\"[\""),
+            Self::CloseBracket => write!(f, "\"]\""),
+        }
+    }
+}
+
+const UNARY_OPERATORS: [&str; 3] = ["-", "!", "~"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while let Some(next) = rest.chars().next() {
+        let len = if next.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let literal = &rest[..len];
+            let value = match literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => literal.parse::<i64>(),
+            }.map_err(|_| format!("Invalid number {}", literal))?;
+            tokens.push(Token::Number(value));
+            len
+        } else if next.is_ascii_alphabetic() || next == '_' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push(Token::Identifier(rest[..len].to_string()));
+            len
+        } else {
+            let token = match next {
+                '(' => Some(Token::OpenParen),
+                ')' => Some(Token::CloseParen),
+                '[' => Some(Token::OpenBracket),
+                ']' => Some(Token::CloseBracket),
+                _ => None,
+            };
+            match token {
+                Some(token) => tokens.push(token),
+                None => {
+                    let symbol = BINARY_OPERATORS.iter().map(|(symbol, _, _)| *symbol)
+                        .chain(UNARY_OPERATORS)
+                        .find(|symbol| rest.starts_with(symbol))
+                        .ok_or_else(|| format!("Unexpected character '{}'", next))?;
+                    tokens.push(Token::Operator(symbol));
+                    rest = rest[symbol.len()..].trim_start();
+                    continue;
+                },
+            };
+            1
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or_else(|| String::from("Unexpected end of expression"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected {} but found {}", expected, token)),
+        }
+    }
+
+    // Precedence climbing, only operators binding tighter than min_precedence are consumed
+    fn expression(&mut self, min_precedence: u8) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Operator(symbol)) = self.peek() {
+            let (_, operator, precedence) = match BINARY_OPERATORS.iter().find(|(candidate, _, _)| candidate == symbol) {
+                Some(entry) => *entry,
+                None => return Err(format!("Unexpected \"{}\"", symbol)),
+            };
+            if precedence <= min_precedence {
+                break;
+            }
+            self.position += 1;
+            let right = self.expression(precedence)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        let operator = match self.peek() {
+            Some(Token::Operator("-")) => UnaryOperator::Negate,
+            Some(Token::Operator("!")) => UnaryOperator::Not,
+            Some(Token::Operator("~")) => UnaryOperator::BitwiseNot,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+        Ok(Expression::Unary(operator, Box::new(self.unary()?)))
+    }
+
+    fn memory(&mut self, size: usize) -> Result<Expression, String> {
+        let address = self.expression(0)?;
+        self.expect(Token::CloseBracket)?;
+        Ok(Expression::Memory(Box::new(address), size))
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next()? {
+            Token::Number(value) => Ok(Expression::Number(value)),
+            Token::OpenParen => {
+                let expression = self.expression(0)?;
+                self.expect(Token::CloseParen)?;
+                Ok(expression)
+            },
+            Token::OpenBracket => self.memory(4),
+            Token::Identifier(name) => {
+                if self.peek() == Some(&Token::OpenBracket) {
+                    let size = match name.as_str() {
+                        "b" => 1,
+                        "h" => 2,
+                        "w" => 4,
+                        "d" => 8,
+                        _ => return Err(format!("Unknown memory size {}", name)),
+                    };
+                    self.position += 1;
+                    return self.memory(size);
+                }
+                RegisterName::parse(&name)
+                    .map(Expression::Register)
+                    .ok_or_else(|| format!("Unknown register {}", name))
+            },
+            token => Err(format!("Unexpected {}", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use super::*;
+
+    struct TestContext;
+
+    impl Context for TestContext {
+        fn register(&self, register: RegisterName) -> i64 {
+            match register {
+                RegisterName::GPR(8) => 0xDEADBEEF,
+                RegisterName::PC => 0x80001000,
+                _ => 0,
+            }
+        }
+
+        fn read(&self, address: i64, size: usize) -> i64 {
+            match (address, size) {
+                (0x8012A0, 4) => 6,
+                (0x8012A0, 1) => 0xFF,
+                _ => 0,
+            }
+        }
+    }
+
+    fn evaluate(input: &str) -> i64 {
+        Expression::parse(input).unwrap().evaluate(&TestContext)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("t0 == 0xDEADBEEF && [0x8012A0] > 5"), 1);
+        assert_eq!(evaluate("r8 != 0xDEADBEEF || b[0x8012A0] < 5"), 0);
+        assert_eq!(evaluate("1 + 2 * 3 - 4 / 2"), 5);
+        assert_eq!(evaluate("(1 + 2) * 3 % 4"), 1);
+        assert_eq!(evaluate("-1 & 0xFF | 1 << 8"), 0x1FF);
+        assert_eq!(evaluate("!pc + ~0 + 10 / 0"), -1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expression::parse("t0 ==").is_err());
+        assert!(Expression::parse("foo == 1").is_err());
+        assert!(Expression::parse("[0x10").is_err());
+        assert!(Expression::parse("1 $ 2").is_err());
+        assert!(Expression::parse("1 2").is_err());
+        assert_eq!(Expression::parse("t0==1&&b[sp+4]").unwrap().to_string(), "((t0 == 1) && b[(sp + 4)])");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::is_viewer::IS_VIEWER;
+use crate::mmu::{CARTRIDGE_DOMAIN_1_ADDRESS_2, RDRAM1};
+use crate::usb_debug::UsbDebug;
+
+/*
+    Page table over the physical space KSEG0 and KSEG1 reach, telling which pages are plain memory.
+    Loads and stores there become a slice access into RDRAM or the ROM, every other page falls back
+    to the device dispatch of the MMU. The ROM is read-only through it, writes always fall back.
+*/
+pub const FASTMEM_PAGE_SIZE: usize = 0x1000;
+const FASTMEM_PAGES: usize = 0x20000000 / FASTMEM_PAGE_SIZE;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FastPage {
+    // Registers, debug ports and anything with side effects
+    Slow,
+    RDRAM,
+    ROM,
+}
+
+pub struct FastMem {
+    pages: Box<[FastPage]>,
+}
+
+impl FastMem {
+    pub fn new() -> Self {
+        let pages = (0..FASTMEM_PAGES)
+            .map(|page| {
+                let start = (page * FASTMEM_PAGE_SIZE) as i64;
+                let end = start + FASTMEM_PAGE_SIZE as i64 - 1;
+                // The debug ports mapped over the cartridge are page aligned, checking both ends is enough
+                let overlay = IS_VIEWER.contains(&start) || IS_VIEWER.contains(&end) || UsbDebug::contains(start) || UsbDebug::contains(end);
+                if RDRAM1.contains(&start) && RDRAM1.contains(&end) {
+                    FastPage::RDRAM
+                } else if CARTRIDGE_DOMAIN_1_ADDRESS_2.contains(&start) && CARTRIDGE_DOMAIN_1_ADDRESS_2.contains(&end) && !overlay {
+                    FastPage::ROM
+                } else {
+                    FastPage::Slow
+                }
+            })
+            .collect();
+        Self {
+            pages,
+        }
+    }
+
+    pub fn page(&self, address: i64) -> FastPage {
+        self.pages.get(address as usize / FASTMEM_PAGE_SIZE).copied().unwrap_or(FastPage::Slow)
+    }
+
+    // The kind of memory and the offset into it, when the whole access stays inside one fast page
+    pub fn lookup(&self, address: i64, len: usize) -> Option<(FastPage, usize)> {
+        if address < 0 || len == 0 || (address as usize % FASTMEM_PAGE_SIZE) + len > FASTMEM_PAGE_SIZE {
+            return None;
+        }
+        match self.page(address) {
+            FastPage::Slow => None,
+            FastPage::RDRAM => Some((FastPage::RDRAM, address as usize)),
+            FastPage::ROM => Some((FastPage::ROM, (address - CARTRIDGE_DOMAIN_1_ADDRESS_2.start()) as usize)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod fastmem_tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let fastmem = FastMem::new();
+        assert_eq!(fastmem.lookup(0x00001000, 4), Some((FastPage::RDRAM, 0x1000)));
+        assert_eq!(fastmem.lookup(0x10000040, 8), Some((FastPage::ROM, 0x40)));
+        // Crossing a page, registers and the debug ports over the cartridge
+        assert_eq!(fastmem.lookup(0x00000FFE, 4), None);
+        assert_eq!(fastmem.lookup(0x04400000, 4), None);
+        assert_eq!(fastmem.page(0x13FF0000), FastPage::Slow);
+        assert_eq!(fastmem.page(0x18000000), FastPage::Slow);
+        assert_eq!(fastmem.page(0x00800000), FastPage::Slow);
+    }
+}
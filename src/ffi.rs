@@ -0,0 +1,239 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use crate::audio::{self, AI_DACRATE_ADDRESS};
+use crate::emulator::Emulator;
+use crate::pif::{ControllerState, CONTROLLER_PORTS};
+use crate::resampler::{Resampler, ResamplerKind};
+use crate::rom::ROM;
+
+// Bumped whenever a function of the C API changes, include/rultra64.h has the same value
+pub const RULTRA64_API_VERSION: u32 = 2;
+
+pub const RULTRA64_OK: c_int = 0;
+pub const RULTRA64_ERROR: c_int = -1;
+
+pub const RULTRA64_RESAMPLER_LINEAR: c_int = 0;
+pub const RULTRA64_RESAMPLER_SINC: c_int = 1;
+
+/*
+    Handle given to C frontends. The frame buffer and the samples are kept here so the pointers
+    returned to C stay valid until the next call that asks for them.
+*/
+pub struct Rultra64 {
+    emulator: Emulator,
+    framebuffer: Vec<u8>,
+    samples: Vec<i16>,
+    // Converts the AI samples to the frontend's rate, see rultra64_audio_output
+    resampler: Resampler,
+    output: Vec<i16>,
+    played_samples: u64,
+    last_error: CString,
+}
+
+impl Rultra64 {
+    fn set_error(&mut self, message: String) -> c_int {
+        self.last_error = CString::new(message.replace('\0', " ")).unwrap();
+        RULTRA64_ERROR
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rultra64_api_version() -> u32 {
+    RULTRA64_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn rultra64_create() -> *mut Rultra64 {
+    Box::into_raw(Box::new(Rultra64 {
+        emulator: Emulator::new_hle(),
+        framebuffer: Vec::new(),
+        samples: Vec::new(),
+        resampler: Resampler::new(ResamplerKind::Linear, 44100),
+        output: Vec::new(),
+        played_samples: 0,
+        last_error: CString::default(),
+    }))
+}
+
+/// # Safety
+/// `core` has to come from `rultra64_create` and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_destroy(core: *mut Rultra64) {
+    if !core.is_null() {
+        let mut core = Box::from_raw(core);
+        core.emulator.flush_saves();
+    }
+}
+
+/// # Safety
+/// `core` has to be a live handle and `path` a NUL terminated UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_load_rom(core: *mut Rultra64, path: *const c_char) -> c_int {
+    let core = &mut *core;
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return core.set_error("The path isn't valid UTF-8".to_string()),
+    };
+    match ROM::new_from_filename(path) {
+        Ok(rom) => {
+            core.emulator.load_rom(rom);
+            RULTRA64_OK
+        },
+        Err(err) => core.set_error(format!("Could not load {}: {}", path, err)),
+    }
+}
+
+/// # Safety
+/// `core` has to be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_run_frame(core: *mut Rultra64) {
+    (*core).emulator.run_frame();
+}
+
+/// # Safety
+/// `core` has to be a live handle and `data` has room for `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_read_memory(core: *const Rultra64, address: u32, data: *mut u8, length: usize) {
+    let bytes = (*core).emulator.mmu().read_physical(address as i64, length);
+    ptr::copy_nonoverlapping(bytes.as_ptr(), data, length);
+}
+
+/// # Safety
+/// `core` has to be a live handle and `data` points to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_write_memory(core: *mut Rultra64, address: u32, data: *const u8, length: usize) {
+    let data = std::slice::from_raw_parts(data, length);
+    (*core).emulator.mut_mmu().write_physical(address as i64, data);
+}
+
+/// # Safety
+/// `core` has to be a live handle, `width` and `height` valid pointers.
+/// Returns RGBA8888 pixels valid until the next call, or NULL while the video output is blank.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_framebuffer(core: *mut Rultra64, width: *mut u32, height: *mut u32) -> *const u8 {
+    let core = &mut *core;
+    match core.emulator.mmu().framebuffer_rgba() {
+        Some((frame_width, frame_height, pixels)) => {
+            *width = frame_width as u32;
+            *height = frame_height as u32;
+            core.framebuffer = pixels;
+            core.framebuffer.as_ptr()
+        },
+        None => {
+            *width = 0;
+            *height = 0;
+            ptr::null()
+        },
+    }
+}
+
+/// # Safety
+/// `core` has to be a live handle and `count` a valid pointer.
+/// Returns the last interleaved stereo samples the game handed to the AI, oldest first, valid until the next call.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_audio_samples(core: *mut Rultra64, count: *mut usize) -> *const i16 {
+    let core = &mut *core;
+    core.samples = core.emulator.mmu().audio().samples().iter().flatten().copied().collect();
+    *count = core.samples.len() / 2;
+    core.samples.as_ptr()
+}
+
+/// # Safety
+/// `core` has to be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_audio_sample_rate(core: *const Rultra64) -> u32 {
+    let emulator = &(*core).emulator;
+    let dacrate = u32::from_be_bytes(emulator.mmu().read_physical(AI_DACRATE_ADDRESS, 4).try_into().unwrap());
+    audio::sample_rate(emulator.timing().vi_clock_rate, dacrate) as u32
+}
+
+/// # Safety
+/// `core` has to be a live handle.
+/// Sets the rate and the resampler of rultra64_audio_output, 44100Hz with linear interpolation by default.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_set_audio_output(core: *mut Rultra64, rate: u32, resampler: c_int) -> c_int {
+    let core = &mut *core;
+    let kind = match resampler {
+        RULTRA64_RESAMPLER_LINEAR => ResamplerKind::Linear,
+        RULTRA64_RESAMPLER_SINC => ResamplerKind::Sinc,
+        _ => return core.set_error(format!("Unknown resampler {}", resampler)),
+    };
+    if rate == 0 {
+        return core.set_error("The output rate can't be 0".to_string());
+    }
+    core.resampler = Resampler::new(kind, rate as u64);
+    RULTRA64_OK
+}
+
+/// # Safety
+/// `core` has to be a live handle and `count` a valid pointer.
+/// Returns the interleaved stereo samples the game produced since the last call, at the output rate, valid until the next call.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_audio_output(core: *mut Rultra64, count: *mut usize) -> *const i16 {
+    let core = &mut *core;
+    let monitor = core.emulator.mmu().audio();
+    let samples = monitor.samples_after(core.played_samples);
+    core.played_samples = monitor.total_samples();
+    core.resampler.set_input_rate(rultra64_audio_sample_rate(core) as u64);
+    core.output = core.resampler.process(&samples).iter().flatten().copied().collect();
+    *count = core.output.len() / 2;
+    core.output.as_ptr()
+}
+
+/// # Safety
+/// `core` has to be a live handle. The buttons use the bits of the Joybus controller state.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_set_input(core: *mut Rultra64, port: u32, buttons: u16, x: i8, y: i8) -> c_int {
+    let core = &mut *core;
+    if port as usize >= CONTROLLER_PORTS {
+        return core.set_error(format!("There is no controller port {}", port));
+    }
+    core.emulator.mut_mmu().mut_pif().set_controller(port as usize, ControllerState { buttons, x, y });
+    RULTRA64_OK
+}
+
+/// # Safety
+/// `core` has to be a live handle. The message stays valid until the next call that fails.
+#[no_mangle]
+pub unsafe extern "C" fn rultra64_last_error(core: *const Rultra64) -> *const c_char {
+    (*core).last_error.as_ptr()
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use super::*;
+
+    #[test]
+    fn test_c_api() {
+        unsafe {
+            let core = rultra64_create();
+            let path = CString::new("/does/not/exist.z64").unwrap();
+            assert_eq!(rultra64_load_rom(core, path.as_ptr()), RULTRA64_ERROR);
+            assert!(CStr::from_ptr(rultra64_last_error(core)).to_str().unwrap().starts_with("Could not load"));
+
+            let data = [0x12, 0x34, 0x56, 0x78];
+            rultra64_write_memory(core, 0x1000, data.as_ptr(), data.len());
+            let mut read = [0; 4];
+            rultra64_read_memory(core, 0x1000, read.as_mut_ptr(), read.len());
+            assert_eq!(read, data);
+
+            assert_eq!(rultra64_set_input(core, 0, 0x8000, 10, -10), RULTRA64_OK);
+            assert_eq!((*core).emulator.mmu().pif().controller(0), ControllerState { buttons: 0x8000, x: 10, y: -10 });
+            assert_eq!(rultra64_set_input(core, CONTROLLER_PORTS as u32, 0, 0, 0), RULTRA64_ERROR);
+
+            let (mut width, mut height) = (1, 1);
+            assert!(rultra64_framebuffer(core, &mut width, &mut height).is_null());
+            assert_eq!((width, height), (0, 0));
+            let mut count = 1;
+            rultra64_audio_samples(core, &mut count);
+            assert_eq!(count, 0);
+            assert_eq!(rultra64_set_audio_output(core, 48000, RULTRA64_RESAMPLER_SINC), RULTRA64_OK);
+            assert_eq!(rultra64_set_audio_output(core, 48000, 2), RULTRA64_ERROR);
+            rultra64_audio_output(core, &mut count);
+            assert_eq!(count, 0);
+            rultra64_destroy(core);
+        }
+    }
+}
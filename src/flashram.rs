@@ -0,0 +1,213 @@
+use std::io::Result;
+
+use crate::mmu::CARTRIDGE_DOMAIN_2_ADDRESS_2;
+use crate::rom::{ROM, CART_RAM_SIZE};
+use crate::savestate::{StateReader, StateWriter};
+use crate::scheduler::CPU_CLOCK_RATE;
+
+/*
+    MX29L1100 FlashRAM in the cartridge domain 2. Commands go to the register at 0x08010000 and pick
+    what reads and writes of 0x08000000 do. Erasing and programming take time, meanwhile the status
+    register has the busy bit of the operation set and further erases and programs are dropped. Once
+    done it keeps the success bit until it's cleared by writing to it.
+    https://n64brew.dev/wiki/Flash_RAM
+*/
+pub const FLASHRAM_COMMAND_OFFSET: i64 = 0x10000;
+
+pub const FLASHRAM_CHIP_ERASE: u8 = 0x3C;
+pub const FLASHRAM_SECTOR_ERASE: u8 = 0x4B;
+pub const FLASHRAM_EXECUTE_ERASE: u8 = 0x78;
+pub const FLASHRAM_PROGRAM: u8 = 0xA5;
+pub const FLASHRAM_PAGE_BUFFER: u8 = 0xB4;
+pub const FLASHRAM_STATUS: u8 = 0xD2;
+pub const FLASHRAM_ID: u8 = 0xE1;
+pub const FLASHRAM_READ_ARRAY: u8 = 0xF0;
+
+pub const FLASHRAM_PROGRAM_BUSY: u8 = 1 << 0;
+pub const FLASHRAM_ERASE_BUSY: u8 = 1 << 1;
+pub const FLASHRAM_PROGRAM_OK: u8 = 1 << 2;
+pub const FLASHRAM_ERASE_OK: u8 = 1 << 3;
+
+pub const FLASHRAM_PAGE_SIZE: usize = 128;
+pub const FLASHRAM_SECTOR_SIZE: usize = 128 * FLASHRAM_PAGE_SIZE;
+
+// Rough timings, libultra only polls the busy bits so they don't have to be exact
+pub const FLASHRAM_PROGRAM_CYCLES: u64 = CPU_CLOCK_RATE * 2 / 1000;
+pub const FLASHRAM_SECTOR_ERASE_CYCLES: u64 = CPU_CLOCK_RATE * 15 / 1000;
+pub const FLASHRAM_CHIP_ERASE_CYCLES: u64 = CPU_CLOCK_RATE * 200 / 1000;
+
+// Read in ID mode, the second word is what libultra checks to tell the chips apart
+const SILICON_ID: [u32; 2] = [0x11118001, 0x00C2001E];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FlashRamMode {
+    ReadArray,
+    Status,
+    Id,
+    PageBuffer,
+}
+
+impl FlashRamMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Status,
+            2 => Self::Id,
+            3 => Self::PageBuffer,
+            _ => Self::ReadArray,
+        }
+    }
+}
+
+pub struct FlashRam {
+    mode: FlashRamMode,
+    status: u8,
+    // Offset and length selected by the erase commands, done on Execute Erase
+    erase: Option<(usize, usize)>,
+    page_buffer: [u8; FLASHRAM_PAGE_SIZE],
+    busy_cycles: u64,
+    // Bytes of the command being written, it only runs on the last one
+    pending_command: [u8; 4],
+}
+
+impl FlashRam {
+    pub fn new() -> Self {
+        Self {
+            mode: FlashRamMode::ReadArray,
+            status: 0,
+            erase: None,
+            page_buffer: [0xFF; FLASHRAM_PAGE_SIZE],
+            busy_cycles: 0,
+            pending_command: [0; 4],
+        }
+    }
+
+    pub fn mode(&self) -> FlashRamMode {
+        self.mode
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    pub fn busy(&self) -> bool {
+        self.busy_cycles > 0
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        if self.busy_cycles == 0 {
+            return;
+        }
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+        if self.busy_cycles == 0 {
+            self.status = match self.status & FLASHRAM_ERASE_BUSY != 0 {
+                true => FLASHRAM_ERASE_OK,
+                false => FLASHRAM_PROGRAM_OK,
+            };
+        }
+    }
+
+    /*
+        The PI addresses the array in 16 bit words, libultra halves the offset it reads from. Only
+        DMAs are translated, they're the only way games read it.
+    */
+    pub fn dma_address(&self, address: i64) -> i64 {
+        let base = *CARTRIDGE_DOMAIN_2_ADDRESS_2.start();
+        match self.mode {
+            FlashRamMode::ReadArray => base + (address - base) * 2,
+            _ => address,
+        }
+    }
+
+    // The offset is from the start of the domain 2 range
+    pub fn read(&self, offset: i64, rom: &ROM) -> u8 {
+        let word = match self.mode {
+            FlashRamMode::ReadArray => return rom.read(CARTRIDGE_DOMAIN_2_ADDRESS_2.start() + (offset & (CART_RAM_SIZE as i64 - 1))),
+            FlashRamMode::Id => SILICON_ID[((offset >> 2) & 1) as usize],
+            _ => match offset & 0b100 {
+                0 => self.status as u32,
+                _ => 0,
+            },
+        };
+        word.to_be_bytes()[(offset & 0b11) as usize]
+    }
+
+    pub fn write(&mut self, offset: i64, data: u8, rom: &mut ROM) {
+        if offset & !0b11 == FLASHRAM_COMMAND_OFFSET {
+            let byte = (offset & 0b11) as usize;
+            self.pending_command[byte] = data;
+            if byte == 3 {
+                self.command(u32::from_be_bytes(self.pending_command), rom);
+            }
+            return;
+        }
+        match self.mode {
+            FlashRamMode::PageBuffer => self.page_buffer[offset as usize % FLASHRAM_PAGE_SIZE] = data,
+            // How libultra clears it after checking an erase or program
+            FlashRamMode::Status if !self.busy() => self.status = 0,
+            _ => {},
+        }
+    }
+
+    fn command(&mut self, command: u32, rom: &mut ROM) {
+        let page = (command & 0xFFFF) as usize;
+        let operation = (command >> 24) as u8;
+        let starts_operation = matches!(operation, FLASHRAM_CHIP_ERASE | FLASHRAM_SECTOR_ERASE | FLASHRAM_EXECUTE_ERASE | FLASHRAM_PROGRAM | FLASHRAM_PAGE_BUFFER);
+        if self.busy() && starts_operation {
+            return;
+        }
+        match operation {
+            FLASHRAM_CHIP_ERASE => self.erase = Some((0, CART_RAM_SIZE)),
+            FLASHRAM_SECTOR_ERASE => {
+                let offset = (page * FLASHRAM_PAGE_SIZE) & !(FLASHRAM_SECTOR_SIZE - 1) & (CART_RAM_SIZE - 1);
+                self.erase = Some((offset, FLASHRAM_SECTOR_SIZE));
+            },
+            FLASHRAM_EXECUTE_ERASE => if let Some((offset, length)) = self.erase.take() {
+                rom.write_ram(offset, &vec![0xFF; length]);
+                self.status = FLASHRAM_ERASE_BUSY;
+                self.busy_cycles = match length {
+                    CART_RAM_SIZE => FLASHRAM_CHIP_ERASE_CYCLES,
+                    _ => FLASHRAM_SECTOR_ERASE_CYCLES,
+                };
+                self.mode = FlashRamMode::Status;
+            },
+            FLASHRAM_PROGRAM => {
+                let offset = (page * FLASHRAM_PAGE_SIZE) & (CART_RAM_SIZE - 1);
+                rom.write_ram(offset, &self.page_buffer);
+                self.status = FLASHRAM_PROGRAM_BUSY;
+                self.busy_cycles = FLASHRAM_PROGRAM_CYCLES;
+                self.mode = FlashRamMode::Status;
+            },
+            FLASHRAM_PAGE_BUFFER => self.mode = FlashRamMode::PageBuffer,
+            FLASHRAM_STATUS => self.mode = FlashRamMode::Status,
+            FLASHRAM_ID => self.mode = FlashRamMode::Id,
+            FLASHRAM_READ_ARRAY => self.mode = FlashRamMode::ReadArray,
+            _ => {},
+        };
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.mode as u8);
+        writer.write_u8(self.status);
+        let (offset, length) = self.erase.unwrap_or((0, 0));
+        writer.write_u32(offset as u32);
+        writer.write_u32(length as u32);
+        writer.write_bytes(&self.page_buffer);
+        writer.write_u64(self.busy_cycles);
+        writer.write_bytes(&self.pending_command);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.mode = FlashRamMode::from_u8(reader.read_u8()?);
+        self.status = reader.read_u8()?;
+        let offset = reader.read_u32()? as usize;
+        let length = reader.read_u32()? as usize;
+        self.erase = match length {
+            0 => None,
+            _ => Some((offset, length)),
+        };
+        self.page_buffer.copy_from_slice(reader.read_bytes(FLASHRAM_PAGE_SIZE)?);
+        self.busy_cycles = reader.read_u64()?;
+        self.pending_command.copy_from_slice(reader.read_bytes(4)?);
+        Ok(())
+    }
+}
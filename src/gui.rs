@@ -1,147 +1,3481 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
 use eframe::{egui, epi};
-use std::cell::RefCell;
-use std::rc::Rc;
 
-use crate::emulator::Emulator;
+use crate::audio::levels;
+use crate::autosave::{AutosaveConfig, AUTOSAVE_KEY};
+use crate::storage;
+use crate::cheat::{Cheat, CheatCode, CheatDatabase, CHEATS_KEY, parse_cheats, cheats_to_text};
+use crate::controller_pak::{ControllerPak, NOTE_EXTENSION};
+use crate::debugger::BreakpointKind;
+use crate::dma::{DmaKind, DmaTransfer};
+use crate::expression::{Expression, RegisterName};
+use crate::display::{DisplaySettings, ScalingMode, Filter, display_size, scale_nearest};
+use crate::emulator::{Emulator, RunTarget};
+use crate::emulator_thread::{EmulatorThread, Command, Response, Snapshot, Frame, SearchResults, RdpSnapshot};
+use crate::hardware_registers::INTERFACES;
+use crate::heatmap::{Access, HEATMAP_PAGE_SIZE, HEATMAP_PAGES, heatmap_rgba};
+use crate::hotkeys::{Hotkey, HotkeyConfig, KeyCombination, HOTKEYS_KEY};
+use crate::layout::{GuiLayout, Theme, LAYOUT_KEY};
+use crate::ipc::IPC_DEFAULT_PORT;
+use crate::input::{Input, InputConfig, Binding, INPUT_CONFIG_KEY, STICK_RANGE, TURBO_PERIODS};
+use crate::log::{self, log, Level, Subsystem, LogEntry, LOG_SIZE};
+use crate::mmu::MEMORY_PAGE_SIZE;
+use crate::os_threads::{MessageQueue, ThreadList, OS_STATE_RUNNING};
+use crate::osd::{osd, OsdMessages};
+use crate::pif::{ControllerState, CONTROLLER_PORTS, JoybusFrame, PakKind, joybus_frames, joybus_command_name};
+use crate::rcp::{FramebufferView, PixelFormat};
+use crate::registers::{CP0Registers, CPU_REGISTER_NAMES, CP0_REGISTER_NAMES, CACHE_ALGORITHM_UNCACHED, exception_code_name};
+use crate::rdp_blender::{OTHER_MODES_ALPHA_COMPARE, OTHER_MODES_ANTIALIAS, OTHER_MODES_FORCE_BLEND, OTHER_MODES_Z_COMPARE, OTHER_MODES_Z_UPDATE};
+use crate::rdp::{CYCLE_TYPE_2CYCLE, DPC_STATUS_XBUS, TileDescriptor, TILE_DESCRIPTORS, TEXTURE_FORMATS, TEXEL_SIZES, decode_tile};
+use crate::rsp::{SP_STATUS_HALT, SP_STATUS_BROKE, SP_STATUS_SSTEP, SP_STATUS_INTR_BREAK};
+use crate::search::{ValueType, Comparison};
+use crate::state_slots::{SlotInfo, STATE_SLOTS, format_timestamp};
+use crate::symbols::SymbolTable;
+use crate::debug_info::{DebugInfo, VariableLocation};
+use crate::tlb::TLBEntry;
+use crate::recent_roms::{RecentRoms, RECENT_ROMS_KEY};
+use crate::raw_adapter::{RawAdapter, find_adapters};
+use crate::rom::{ROM, Region, RomInfo};
+use crate::scheduler::{MIN_CLOCK_MULTIPLIER, MAX_CLOCK_MULTIPLIER, CPU_CLOCK_RATE};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Register {
+    CPU,
+    CP0,
+    CP1,
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Self::CPU
+    }
+}
+
+// (name, physical address, size) of the regions the memory viewer can jump to
+const MEMORY_PRESETS: [(&str, i64, i64); 5] = [
+    ("RDRAM", 0x00000000, 0x400000),
+    ("DMEM", 0x04000000, 0x1000),
+    ("IMEM", 0x04001000, 0x1000),
+    ("PIF RAM", 0x1FC007C0, 0x40),
+    ("Cart", 0x10000000, 0x4000000),
+];
+const MEMORY_CUSTOM_SIZE: i64 = 0x100000;
+const MEMORY_ROW_SIZE: usize = 16;
+
+struct MemoryViewer {
+    open: bool,
+    start: i64,
+    size: i64,
+    virtual_address: bool,
+    address_input: String,
+    pages: HashMap<i64, Vec<u8>>,
+    edit_address: Option<i64>,
+    edit_size: usize,
+    edit_value: String,
+}
+
+impl MemoryViewer {
+    fn new() -> Self {
+        Self {
+            open: false,
+            start: 0,
+            size: 0x400000,
+            virtual_address: false,
+            address_input: String::new(),
+            pages: HashMap::new(),
+            edit_address: None,
+            edit_size: 1,
+            edit_value: String::new(),
+        }
+    }
+
+    fn go_to(&mut self, start: i64, size: i64, virtual_address: bool) {
+        self.start = start;
+        self.size = size;
+        self.virtual_address = virtual_address;
+        self.pages.clear();
+        self.edit_address = None;
+    }
+
+    // Big endian value of edit_size bytes starting at the address
+    fn value(&self, address: i64, size: usize) -> Option<u64> {
+        let mut value = 0;
+        for offset in 0..size as i64 {
+            value = (value << 8) | self.byte(address + offset)? as u64;
+        }
+        Some(value)
+    }
+
+    fn byte(&self, address: i64) -> Option<u8> {
+        let page = address & !(MEMORY_PAGE_SIZE as i64 - 1);
+        self.pages.get(&page).map(|data| data[(address - page) as usize])
+    }
+}
+
+const SEARCH_VALUE_TYPES: [(ValueType, &str); 7] = [
+    (ValueType::U8, "u8"),
+    (ValueType::I8, "s8"),
+    (ValueType::U16, "u16"),
+    (ValueType::I16, "s16"),
+    (ValueType::U32, "u32"),
+    (ValueType::I32, "s32"),
+    (ValueType::F32, "f32"),
+];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SearchComparison {
+    Equal,
+    Greater,
+    Less,
+    Changed,
+    Unchanged,
+}
+
+struct MemorySearchPanel {
+    open: bool,
+    value_type: ValueType,
+    comparison: SearchComparison,
+    value_input: String,
+    results: Option<SearchResults>,
+}
+
+impl MemorySearchPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            value_type: ValueType::U32,
+            comparison: SearchComparison::Equal,
+            value_input: String::new(),
+            results: None,
+        }
+    }
+
+    // Values starting with 0x are read as hexadecimal
+    fn comparison(&self) -> Option<Comparison> {
+        let input = self.value_input.trim();
+        let value = match input.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok().map(|value| value as f64),
+            None => input.parse::<f64>().ok(),
+        };
+        match self.comparison {
+            SearchComparison::Equal => value.map(Comparison::Equal),
+            SearchComparison::Greater => value.map(Comparison::Greater),
+            SearchComparison::Less => value.map(Comparison::Less),
+            SearchComparison::Changed => Some(Comparison::Changed),
+            SearchComparison::Unchanged => Some(Comparison::Unchanged),
+        }
+    }
+}
+
+struct BreakpointPanel {
+    open: bool,
+    address_input: String,
+    condition_input: String,
+    len: i64,
+    kind: BreakpointKind,
+    error: Option<String>,
+}
+
+impl BreakpointPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            address_input: String::new(),
+            condition_input: String::new(),
+            len: 4,
+            kind: BreakpointKind::Execute,
+            error: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum RspMemory {
+    Dmem,
+    Imem,
+}
+
+struct RspPanel {
+    open: bool,
+    memory: RspMemory,
+}
+
+impl RspPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            memory: RspMemory::Dmem,
+        }
+    }
+}
+
+struct HardwareRegistersPanel {
+    open: bool,
+    // Index in hardware_registers::INTERFACES
+    interface: usize,
+    editing: Option<i64>,
+    input: String,
+}
+
+impl HardwareRegistersPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            interface: 0,
+            editing: None,
+            input: String::new(),
+        }
+    }
+}
+
+// Register being edited from the Registers window, only possible while paused
+struct RegisterEditor {
+    editing: Option<(Register, usize)>,
+    // Registers can show the value in more than one column
+    column: usize,
+    input: String,
+}
+
+impl RegisterEditor {
+    fn new() -> Self {
+        Self {
+            editing: None,
+            column: 0,
+            input: String::new(),
+        }
+    }
+}
+
+struct ThreadPanel {
+    open: bool,
+    list: Option<ThreadList>,
+}
+
+impl ThreadPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            list: None,
+        }
+    }
+
+    // The symbol when the game has one, otherwise where the list was found last time
+    fn active_queue(&self, symbols: &SymbolTable) -> Option<u32> {
+        symbols.address_of("__osActiveQueue").or_else(|| self.list.as_ref().map(|list| list.active_queue))
+    }
+}
+
+struct MessageQueuePanel {
+    open: bool,
+    queues: Vec<MessageQueue>,
+    // Searching RDRAM takes a while, so it's done once per frame of the game instead of every repaint
+    frame: Option<u64>,
+}
+
+impl MessageQueuePanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            queues: Vec::new(),
+            frame: None,
+        }
+    }
+}
+
+struct DmaPanel {
+    open: bool,
+    // Indexed like DmaKind::ALL
+    kinds: [bool; 4],
+    // Only show transfers touching this address
+    address: String,
+}
+
+impl DmaPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            kinds: [true; 4],
+            address: String::new(),
+        }
+    }
+
+    fn show(&self, transfer: &DmaTransfer) -> bool {
+        let kind = DmaKind::ALL.iter().position(|kind| *kind == transfer.kind).unwrap();
+        if !self.kinds[kind] {
+            return false;
+        }
+        match parse_address(&self.address) {
+            Some(address) => [transfer.source, transfer.destination].iter()
+                .any(|start| (*start..*start + transfer.length as i64).contains(&address)),
+            None => true,
+        }
+    }
+}
+
+struct LogConsole {
+    open: bool,
+    entries: VecDeque<LogEntry>,
+    // Id of the first message that hasn't been fetched yet
+    next_id: u64,
+    // Messages more verbose than this are hidden
+    level: Level,
+    // Messages more verbose than this aren't even recorded, see log::set_max_level
+    record_level: Level,
+    // Indexed like Subsystem::ALL
+    subsystems: [bool; 13],
+}
+
+impl LogConsole {
+    fn new() -> Self {
+        Self {
+            open: false,
+            entries: VecDeque::with_capacity(LOG_SIZE),
+            next_id: 0,
+            level: Level::Info,
+            record_level: Level::Debug,
+            subsystems: [true; 13],
+        }
+    }
+
+    fn fetch(&mut self) {
+        for entry in log::entries_since(self.next_id) {
+            if self.entries.len() == LOG_SIZE {
+                self.entries.pop_front();
+            }
+            self.next_id = entry.id + 1;
+            self.entries.push_back(entry);
+        }
+    }
+
+    fn show(&self, entry: &LogEntry) -> bool {
+        let subsystem = Subsystem::ALL.iter().position(|subsystem| *subsystem == entry.subsystem).unwrap();
+        entry.level <= self.level && self.subsystems[subsystem]
+    }
+
+    fn filtered_text(&self) -> String {
+        self.entries.iter()
+            .filter(|entry| self.show(entry))
+            .map(|entry| format!("{}\n", entry))
+            .collect()
+    }
+}
+
+struct WatchPanel {
+    open: bool,
+    input: String,
+    error: Option<String>,
+}
+
+impl WatchPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            input: String::new(),
+            error: None,
+        }
+    }
+}
+
+// Addresses are written in hexadecimal, with or without the 0x prefix
+fn parse_address(input: &str) -> Option<i64> {
+    let input = input.trim();
+    i64::from_str_radix(input.strip_prefix("0x").unwrap_or(input), 16).ok()
+}
+
+struct RunControls {
+    steps: u64,
+    address_input: String,
+}
+
+impl RunControls {
+    fn new() -> Self {
+        Self {
+            steps: 100,
+            address_input: String::new(),
+        }
+    }
+}
+
+// GUI repaints per second, measured over half a second
+// Every key egui reports, bindings store them by their Debug name
+const KEYS: [egui::Key; 51] = [
+    egui::Key::ArrowDown, egui::Key::ArrowLeft, egui::Key::ArrowRight, egui::Key::ArrowUp,
+    egui::Key::Escape, egui::Key::Tab, egui::Key::Backspace, egui::Key::Enter, egui::Key::Space,
+    egui::Key::Insert, egui::Key::Delete, egui::Key::Home, egui::Key::End, egui::Key::PageUp, egui::Key::PageDown,
+    egui::Key::Num0, egui::Key::Num1, egui::Key::Num2, egui::Key::Num3, egui::Key::Num4,
+    egui::Key::Num5, egui::Key::Num6, egui::Key::Num7, egui::Key::Num8, egui::Key::Num9,
+    egui::Key::A, egui::Key::B, egui::Key::C, egui::Key::D, egui::Key::E, egui::Key::F, egui::Key::G,
+    egui::Key::H, egui::Key::I, egui::Key::J, egui::Key::K, egui::Key::L, egui::Key::M, egui::Key::N,
+    egui::Key::O, egui::Key::P, egui::Key::Q, egui::Key::R, egui::Key::S, egui::Key::T, egui::Key::U,
+    egui::Key::V, egui::Key::W, egui::Key::X, egui::Key::Y, egui::Key::Z,
+];
+
+const GAMEPAD_BUTTONS: [gilrs::Button; 19] = [
+    gilrs::Button::South, gilrs::Button::East, gilrs::Button::North, gilrs::Button::West,
+    gilrs::Button::C, gilrs::Button::Z,
+    gilrs::Button::LeftTrigger, gilrs::Button::LeftTrigger2, gilrs::Button::RightTrigger, gilrs::Button::RightTrigger2,
+    gilrs::Button::Select, gilrs::Button::Start, gilrs::Button::Mode, gilrs::Button::LeftThumb, gilrs::Button::RightThumb,
+    gilrs::Button::DPadUp, gilrs::Button::DPadDown, gilrs::Button::DPadLeft, gilrs::Button::DPadRight,
+];
+
+const GAMEPAD_AXES: [gilrs::Axis; 8] = [
+    gilrs::Axis::LeftStickX, gilrs::Axis::LeftStickY, gilrs::Axis::LeftZ,
+    gilrs::Axis::RightStickX, gilrs::Axis::RightStickY, gilrs::Axis::RightZ,
+    gilrs::Axis::DPadX, gilrs::Axis::DPadY,
+];
+
+// Axes have to be pushed past this to be captured or to press a button
+const AXIS_THRESHOLD: f32 = 0.5;
+
+fn find_by_name<T: std::fmt::Debug + Copy>(values: &[T], name: &str) -> Option<T> {
+    values.iter().copied().find(|value| format!("{:?}", value) == name)
+}
+
+// How far a binding is pressed, keys only count when no text field has the focus
+fn binding_value(input: &egui::InputState, gamepad: Option<&gilrs::Gamepad>, keyboard: bool, binding: &Binding) -> f32 {
+    let pressed = match binding {
+        Binding::Key(name) => keyboard && find_by_name(&KEYS, name).map_or(false, |key| input.key_down(key)),
+        Binding::GamepadButton(name) => gamepad.zip(find_by_name(&GAMEPAD_BUTTONS, name)).map_or(false, |(gamepad, button)| gamepad.is_pressed(button)),
+        Binding::GamepadAxis(name, positive) => {
+            let value = gamepad.zip(find_by_name(&GAMEPAD_AXES, name)).map_or(0.0, |(gamepad, axis)| gamepad.value(axis));
+            return if *positive { value } else { -value };
+        },
+    };
+    if pressed { 1.0 } else { 0.0 }
+}
+
+struct InputPanel {
+    open: bool,
+    port: usize,
+    // Input and slot (keyboard or gamepad) waiting for a key or a button
+    capturing: Option<(Input, bool)>,
+    // Raw adapters found with Refresh, and the one and its port used by each console port
+    adapters: Vec<PathBuf>,
+    adapter_channel: u8,
+    passthrough: [Option<String>; CONTROLLER_PORTS],
+}
+
+impl InputPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            port: 0,
+            capturing: None,
+            adapters: Vec::new(),
+            adapter_channel: 0,
+            passthrough: Default::default(),
+        }
+    }
+}
+
+struct HotkeyPanel {
+    open: bool,
+    // Hotkey waiting for a key combination
+    capturing: Option<Hotkey>,
+}
+
+impl HotkeyPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            capturing: None,
+        }
+    }
+}
+
+struct CheatPanel {
+    open: bool,
+    database: CheatDatabase,
+    // Game of the loaded ROM, its cheats are the ones shown and sent to the core
+    game: Option<String>,
+    selected: Option<usize>,
+    name_input: String,
+    codes_input: String,
+}
+
+impl CheatPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            database: CheatDatabase::new(),
+            game: None,
+            selected: None,
+            name_input: String::new(),
+            codes_input: String::new(),
+        }
+    }
+
+    fn cheats(&self) -> &[Cheat] {
+        match &self.game {
+            Some(game) => self.database.cheats(game),
+            None => &[],
+        }
+    }
+
+    fn send(&self, emulator: &EmulatorThread) {
+        emulator.send(Command::SetCheats(self.cheats().to_vec()));
+    }
+
+    // Follows the loaded ROM, which can change from the file menu, a drop or a savestate import
+    fn update_game(&mut self, emulator: &EmulatorThread, game: &Option<String>) {
+        if self.game != *game {
+            self.game = game.clone();
+            self.selected = None;
+            self.send(emulator);
+        }
+    }
+
+    fn select(&mut self, index: Option<usize>) {
+        self.selected = index;
+        let cheat = index.and_then(|index| self.cheats().get(index)).cloned();
+        self.name_input = cheat.as_ref().map(|cheat| cheat.name.clone()).unwrap_or_default();
+        self.codes_input = cheat.map(|cheat| cheat.codes.iter().map(|code| code.to_string()).collect::<Vec<_>>().join("\n")).unwrap_or_default();
+    }
+
+    // The cheat being edited, or an error naming the first line that isn't a code
+    fn parse_input(&self) -> Result<Cheat, String> {
+        let mut codes = Vec::new();
+        for line in self.codes_input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match CheatCode::parse(line) {
+                Some(code) => codes.push(code),
+                None => return Err(format!("Invalid code: {}", line)),
+            };
+        }
+        if self.name_input.trim().is_empty() {
+            return Err(String::from("The cheat needs a name"));
+        }
+        Ok(Cheat {
+            name: self.name_input.trim().to_string(),
+            enabled: false,
+            codes,
+        })
+    }
+}
+
+// Keys that pick the slot used by the quick save and quick load hotkeys
+const SLOT_KEYS: [egui::Key; STATE_SLOTS] = [
+    egui::Key::Num0, egui::Key::Num1, egui::Key::Num2, egui::Key::Num3, egui::Key::Num4,
+    egui::Key::Num5, egui::Key::Num6, egui::Key::Num7, egui::Key::Num8, egui::Key::Num9,
+];
+
+struct RomInfoPanel {
+    open: bool,
+    info: Option<RomInfo>,
+    // The information is requested again when another ROM is loaded
+    game: Option<String>,
+}
+
+impl RomInfoPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            info: None,
+            game: None,
+        }
+    }
+}
+
+struct StateSlotMenu {
+    slots: Vec<Option<SlotInfo>>,
+    thumbnails: Vec<Option<egui::TextureId>>,
+    // Slot used by the quick save and quick load hotkeys
+    current: usize,
+    // The slots are listed again when another ROM is loaded
+    game: Option<String>,
+}
+
+impl StateSlotMenu {
+    fn new() -> Self {
+        Self {
+            slots: vec![None; STATE_SLOTS],
+            thumbnails: vec![None; STATE_SLOTS],
+            current: 0,
+            game: None,
+        }
+    }
+
+    fn set_slots(&mut self, frame: &epi::Frame, slots: Vec<Option<SlotInfo>>) {
+        for texture_id in self.thumbnails.drain(..).flatten() {
+            frame.free_texture(texture_id);
+        }
+        self.thumbnails = slots.iter().map(|slot| {
+            let thumbnail = slot.as_ref()?.thumbnail.as_ref()?;
+            Some(frame.alloc_texture(epi::Image::from_rgba_unmultiplied([thumbnail.width, thumbnail.height], &thumbnail.pixels)))
+        }).collect();
+        self.slots = slots;
+    }
+
+    // Returns the clicked slot
+    fn show(&self, ui: &mut egui::Ui) -> Option<usize> {
+        let mut clicked = None;
+        for slot in 0..STATE_SLOTS {
+            ui.horizontal(|ui| {
+                match self.thumbnails.get(slot).copied().flatten() {
+                    Some(texture_id) => ui.image(texture_id, [40.0, 30.0]),
+                    None => ui.allocate_exact_size(egui::vec2(40.0, 30.0), egui::Sense::hover()).1,
+                };
+                let text = match self.slots.get(slot).and_then(|info| info.as_ref()) {
+                    Some(info) => format!("Slot {}  {}", slot, format_timestamp(info.timestamp)),
+                    None => format!("Slot {}  Empty", slot),
+                };
+                if ui.selectable_label(slot == self.current, text).clicked() {
+                    clicked = Some(slot);
+                }
+            });
+        }
+        clicked
+    }
+}
+
+struct ControllerPakPanel {
+    open: bool,
+    // Port whose pak file is shown, None for a file opened by hand
+    port: Option<usize>,
+    path: Option<PathBuf>,
+    pak: Option<ControllerPak>,
+    selected: Option<usize>,
+    message: Option<String>,
+}
+
+impl ControllerPakPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            port: Some(0),
+            path: None,
+            pak: None,
+            selected: None,
+            message: None,
+        }
+    }
+
+    fn open_file(&mut self, path: Option<PathBuf>) {
+        self.selected = None;
+        self.pak = None;
+        self.message = None;
+        if let Some(path) = &path {
+            match std::fs::read(path).and_then(ControllerPak::new) {
+                Ok(pak) => self.pak = Some(pak),
+                Err(err) => self.message = Some(format!("Could not open {}: {}", path.display(), err)),
+            };
+        }
+        self.path = path;
+    }
+
+    fn write_file(&mut self) {
+        if let (Some(path), Some(pak)) = (&self.path, &self.pak) {
+            if let Err(err) = std::fs::write(path, pak.data()) {
+                self.message = Some(format!("Could not write {}: {}", path.display(), err));
+            }
+        }
+    }
+}
+
+struct TmemViewer {
+    open: bool,
+    tile: usize,
+    // Format, size, palette and dimensions to use instead of the tile descriptor's
+    custom: bool,
+    format: u8,
+    size: u8,
+    palette: u8,
+    width: usize,
+    height: usize,
+    zoom: f32,
+    pixels: Vec<u8>,
+    texture: Option<(egui::TextureId, [usize; 2])>,
+    // Writes the loaded textures to the texture pack of the game
+    dump_textures: bool,
+}
+
+impl TmemViewer {
+    fn new() -> Self {
+        Self {
+            open: false,
+            tile: 0,
+            custom: false,
+            format: 0,
+            size: 2,
+            palette: 0,
+            width: 32,
+            height: 32,
+            zoom: 4.0,
+            pixels: Vec::new(),
+            texture: None,
+            dump_textures: false,
+        }
+    }
+
+    fn descriptor(&self, rdp: &RdpSnapshot) -> (TileDescriptor, usize, usize) {
+        let mut tile = rdp.tiles[self.tile];
+        let (mut width, mut height) = tile.dimensions();
+        if self.custom {
+            tile.format = self.format;
+            tile.size = self.size;
+            tile.palette = self.palette;
+            width = self.width;
+            height = self.height;
+        }
+        // Tiles without a size show a square as wide as their line, 32 bit lines are split like 16 bit ones
+        if width == 0 || height == 0 {
+            width = ((tile.line.max(4) as usize * 16) >> tile.size.min(2)).min(256);
+            height = width;
+        }
+        (tile, width.min(1024), height.min(1024))
+    }
+}
+
+struct FramebufferPanel {
+    open: bool,
+    // Shows the region in the display instead of what VI_ORIGIN points to
+    enabled: bool,
+    address: String,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    // Last override sent to the emulator
+    sent: Option<FramebufferView>,
+}
+
+impl FramebufferPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            enabled: false,
+            address: String::from("100000"),
+            width: 320,
+            height: 240,
+            format: PixelFormat::RGBA5551,
+            sent: None,
+        }
+    }
+
+    fn view(&self) -> Option<FramebufferView> {
+        Some(FramebufferView {
+            origin: (parse_address(&self.address)? & 0xFFFFFF) as u32,
+            width: self.width,
+            height: self.height,
+            format: self.format,
+        })
+    }
+
+    fn set_view(&mut self, view: &FramebufferView) {
+        self.address = format!("{:06X}", view.origin);
+        self.width = view.width;
+        self.height = view.height;
+        self.format = view.format;
+    }
+
+    // Moves the origin by a number of bytes, to walk through RDRAM a line or a whole frame at a time
+    fn move_origin(&mut self, offset: i64) {
+        if let Some(view) = self.view() {
+            self.address = format!("{:06X}", (view.origin as i64 + offset).clamp(0, 0xFFFFFF));
+        }
+    }
+}
+
+// RDRAM pages are laid out in rows of HEATMAP_COLUMNS, 128KB per row
+const HEATMAP_COLUMNS: usize = 32;
+
+struct HeatmapPanel {
+    open: bool,
+    // Read, write and execute, in the order of Access::ALL
+    shown: [bool; 3],
+    zoom: f32,
+    pixels: Vec<u8>,
+    texture: Option<egui::TextureId>,
+}
+
+impl HeatmapPanel {
+    fn new() -> Self {
+        Self {
+            open: false,
+            shown: [true; 3],
+            zoom: 12.0,
+            pixels: Vec::new(),
+            texture: None,
+        }
+    }
+}
+
+struct FpsCounter {
+    start: Instant,
+    frames: u32,
+    fps: f64,
+}
+
+impl FpsCounter {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frames: 0,
+            fps: 0.0,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.frames += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed >= Duration::from_millis(500) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.start = Instant::now();
+            self.frames = 0;
+        }
+    }
+}
+
+struct Display {
+    texture_id: egui::TextureId,
+    frame_size: (usize, usize),
+}
+
+pub struct EmulatorApp {
+    emulator: EmulatorThread,
+    snapshot: Option<Snapshot>,
+    display: Option<Display>,
+    last_frame: Option<Frame>,
+    display_settings: DisplaySettings,
+    fullscreen: bool,
+    theme: Theme,
+    show_speed: bool,
+    show_inputs: bool,
+    fps: FpsCounter,
+    run_controls: RunControls,
+    recent_roms: RecentRoms,
+    input_config: InputConfig,
+    input_panel: InputPanel,
+    hotkeys: HotkeyConfig,
+    hotkey_panel: HotkeyPanel,
+    fast_forward: bool,
+    cheats: CheatPanel,
+    state_slots: StateSlotMenu,
+    autosave: AutosaveConfig,
+    // Autosave of the game just loaded, until resuming is accepted or declined
+    autosave_offer: Option<SlotInfo>,
+    rom_info: RomInfoPanel,
+    controller_paks: ControllerPakPanel,
+    tmem_viewer: TmemViewer,
+    framebuffer: FramebufferPanel,
+    heatmap: HeatmapPanel,
+    pif_viewer_open: bool,
+    audio_viewer_open: bool,
+    osd: OsdMessages,
+    gilrs: Option<gilrs::Gilrs>,
+    memory_viewer: MemoryViewer,
+    memory_search: MemorySearchPanel,
+    breakpoints: BreakpointPanel,
+    watches: WatchPanel,
+    tlb_viewer_open: bool,
+    rsp: RspPanel,
+    rdp_viewer_open: bool,
+    hardware_registers: HardwareRegistersPanel,
+    exceptions_open: bool,
+    dma: DmaPanel,
+    threads: ThreadPanel,
+    message_queues: MessageQueuePanel,
+    scheduler_open: bool,
+    log_console: LogConsole,
+    // Address external tools connect to, see ipc.rs
+    ipc_address: Option<SocketAddr>,
+    symbols: SymbolTable,
+    // From a homebrew ELF built with -g
+    debug_info: Option<DebugInfo>,
+    source_open: bool,
+    error: Option<String>,
+    selected_register: Register,
+    register_editor: RegisterEditor,
+}
+
+impl Default for EmulatorApp {
+    fn default() -> Self {
+        Self {
+            emulator: EmulatorThread::spawn(Emulator::new_hle()),
+            snapshot: None,
+            display: None,
+            last_frame: None,
+            display_settings: DisplaySettings::new(),
+            fullscreen: false,
+            theme: Theme::Dark,
+            show_speed: true,
+            show_inputs: false,
+            fps: FpsCounter::new(),
+            run_controls: RunControls::new(),
+            recent_roms: RecentRoms::new(),
+            input_config: InputConfig::with_defaults(),
+            input_panel: InputPanel::new(),
+            hotkeys: HotkeyConfig::with_defaults(),
+            hotkey_panel: HotkeyPanel::new(),
+            fast_forward: false,
+            cheats: CheatPanel::new(),
+            state_slots: StateSlotMenu::new(),
+            autosave: AutosaveConfig::new(),
+            autosave_offer: None,
+            rom_info: RomInfoPanel::new(),
+            controller_paks: ControllerPakPanel::new(),
+            tmem_viewer: TmemViewer::new(),
+            framebuffer: FramebufferPanel::new(),
+            heatmap: HeatmapPanel::new(),
+            pif_viewer_open: false,
+            audio_viewer_open: false,
+            osd: OsdMessages::new(),
+            gilrs: match gilrs::Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(err) => {
+                    log!(Level::Warn, Subsystem::Frontend, "Gamepads are unavailable: {}", err);
+                    None
+                },
+            },
+            memory_viewer: MemoryViewer::new(),
+            memory_search: MemorySearchPanel::new(),
+            breakpoints: BreakpointPanel::new(),
+            watches: WatchPanel::new(),
+            tlb_viewer_open: false,
+            rsp: RspPanel::new(),
+            rdp_viewer_open: false,
+            hardware_registers: HardwareRegistersPanel::new(),
+            exceptions_open: false,
+            dma: DmaPanel::new(),
+            threads: ThreadPanel::new(),
+            message_queues: MessageQueuePanel::new(),
+            scheduler_open: false,
+            log_console: LogConsole::new(),
+            ipc_address: None,
+            symbols: SymbolTable::new(),
+            debug_info: None,
+            source_open: false,
+            error: None,
+            selected_register: Register::CPU,
+            register_editor: RegisterEditor::new(),
+        }
+    }
+}
+
+fn theme_visuals(theme: Theme) -> egui::Visuals {
+    match theme {
+        Theme::Dark => egui::Visuals::dark(),
+        Theme::Light => egui::Visuals::light(),
+    }
+}
+
+impl EmulatorApp {
+    // Every window that can be toggled from the menus, by the name of its entry
+    fn toggled_windows(&mut self) -> Vec<(&'static str, &mut bool)> {
+        vec![
+            ("ROM information", &mut self.rom_info.open),
+            ("Input", &mut self.input_panel.open),
+            ("Hotkeys", &mut self.hotkey_panel.open),
+            ("Cheats", &mut self.cheats.open),
+            ("Controller Paks", &mut self.controller_paks.open),
+            ("Memory viewer", &mut self.memory_viewer.open),
+            ("Memory search", &mut self.memory_search.open),
+            ("Memory heatmap", &mut self.heatmap.open),
+            ("Breakpoints", &mut self.breakpoints.open),
+            ("Watch", &mut self.watches.open),
+            ("TLB", &mut self.tlb_viewer_open),
+            ("RSP", &mut self.rsp.open),
+            ("RDP commands", &mut self.rdp_viewer_open),
+            ("TMEM", &mut self.tmem_viewer.open),
+            ("Frame buffer", &mut self.framebuffer.open),
+            ("Hardware registers", &mut self.hardware_registers.open),
+            ("PIF RAM", &mut self.pif_viewer_open),
+            ("Audio", &mut self.audio_viewer_open),
+            ("Exceptions", &mut self.exceptions_open),
+            ("DMA log", &mut self.dma.open),
+            ("Threads", &mut self.threads.open),
+            ("Message queues", &mut self.message_queues.open),
+            ("Scheduler", &mut self.scheduler_open),
+            ("Log console", &mut self.log_console.open),
+            ("Source", &mut self.source_open),
+        ]
+    }
+
+    fn layout(&mut self) -> GuiLayout {
+        let mut layout = GuiLayout::new();
+        layout.theme = self.theme;
+        layout.open_windows = self.toggled_windows().into_iter()
+            .filter(|(_, open)| **open)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        layout
+    }
+
+    fn apply_layout(&mut self, ctx: &egui::CtxRef, layout: &GuiLayout) {
+        self.theme = layout.theme;
+        ctx.set_visuals(theme_visuals(layout.theme));
+        for (name, open) in self.toggled_windows() {
+            *open = layout.is_open(name);
+        }
+    }
+
+    fn process_responses(&mut self, frame: &epi::Frame) {
+        while let Some(response) = self.emulator.try_recv() {
+            match response {
+                Response::Snapshot(snapshot) => self.snapshot = Some(snapshot),
+                Response::Frame(emulator_frame) => {
+                    self.last_frame = Some(emulator_frame);
+                    self.upload_frame(frame);
+                },
+                Response::MemoryPage(page) => {
+                    if page.virtual_address == self.memory_viewer.virtual_address {
+                        self.memory_viewer.pages.insert(page.address, page.data);
+                    }
+                },
+                Response::SearchResults(results) => self.memory_search.results = Some(results),
+                Response::StateSlots(slots) => self.state_slots.set_slots(frame, slots),
+                Response::AutosaveFound { game, info } => {
+                    if self.autosave.enabled(&game) {
+                        self.autosave_offer = Some(info);
+                    }
+                },
+                Response::RomInfo(info) => self.rom_info.info = info,
+                Response::Threads(list) => self.threads.list = list,
+                Response::MessageQueues { threads, queues } => {
+                    self.threads.list = threads;
+                    self.message_queues.queues = queues;
+                },
+                Response::Error(message) => self.error = Some(message),
+            };
+        }
+    }
+
+    /*
+        epi has no way to query the monitor or to make the window fullscreen, so fullscreen removes the
+        decorations and draws only the game over the whole window. The debug windows keep their layout
+        in the egui memory while they are hidden.
+    */
+    fn set_fullscreen(&mut self, frame: &epi::Frame, fullscreen: bool) {
+        self.fullscreen = fullscreen;
+        frame.set_decorations(!fullscreen);
+    }
+
+    fn upload_frame(&mut self, frame: &epi::Frame) {
+        if let Some(display) = self.display.take() {
+            frame.free_texture(display.texture_id);
+        }
+        if let Some(emulator_frame) = &self.last_frame {
+            let prescale = self.display_settings.prescale();
+            let pixels = scale_nearest(&emulator_frame.pixels, emulator_frame.width, emulator_frame.height, prescale);
+            let image = epi::Image::from_rgba_unmultiplied([emulator_frame.width * prescale, emulator_frame.height * prescale], &pixels);
+            self.display = Some(Display {
+                texture_id: frame.alloc_texture(image),
+                frame_size: (emulator_frame.width, emulator_frame.height),
+            });
+        }
+    }
+
+    // The texture is uploaded again only when the decoded pixels change
+    fn update_tmem_texture(&mut self, frame: &epi::Frame) {
+        let viewer = &mut self.tmem_viewer;
+        let rdp = match (&self.snapshot, viewer.open) {
+            (Some(snapshot), true) => &snapshot.rdp,
+            _ => return,
+        };
+        let (tile, width, height) = viewer.descriptor(rdp);
+        let pixels = decode_tile(&rdp.tmem, &tile, width, height);
+        if viewer.texture.map(|(_, size)| size) == Some([width, height]) && pixels == viewer.pixels {
+            return;
+        }
+        if let Some((texture_id, _)) = viewer.texture.take() {
+            frame.free_texture(texture_id);
+        }
+        let texture_id = frame.alloc_texture(epi::Image::from_rgba_unmultiplied([width, height], &pixels));
+        viewer.texture = Some((texture_id, [width, height]));
+        viewer.pixels = pixels;
+    }
+
+    fn update_heatmap_texture(&mut self, frame: &epi::Frame) {
+        let panel = &mut self.heatmap;
+        let counts = match (&self.snapshot, panel.open) {
+            (Some(snapshot), true) => &snapshot.heatmap,
+            _ => return,
+        };
+        let accesses: Vec<Access> = Access::ALL.iter().zip(panel.shown.iter()).filter(|(_, shown)| **shown).map(|(access, _)| *access).collect();
+        let pixels = heatmap_rgba(counts, &accesses);
+        if panel.texture.is_some() && pixels == panel.pixels {
+            return;
+        }
+        if let Some(texture_id) = panel.texture.take() {
+            frame.free_texture(texture_id);
+        }
+        let size = [HEATMAP_COLUMNS, HEATMAP_PAGES / HEATMAP_COLUMNS];
+        panel.texture = Some(frame.alloc_texture(epi::Image::from_rgba_unmultiplied(size, &pixels)));
+        panel.pixels = pixels;
+    }
+
+    // A ROM dropped on the window loads like one picked in the file dialog
+    fn handle_dropped_files(&mut self, ctx: &egui::CtxRef) {
+        if !ctx.input().raw.hovered_files.is_empty() {
+            let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Foreground, egui::Id::new("rom_drop_target")));
+            let screen_rect = ctx.input().screen_rect();
+            painter.rect_filled(screen_rect, 0.0, egui::Color32::from_black_alpha(192));
+            painter.text(screen_rect.center(), egui::Align2::CENTER_CENTER, "Drop a ROM to load it", egui::TextStyle::Heading, egui::Color32::WHITE);
+        }
+        let dropped = ctx.input().raw.dropped_files.iter().find_map(|file| file.path.clone());
+        if let Some(path) = dropped {
+            load_rom(&self.emulator, &mut self.recent_roms, &mut self.symbols, &mut self.debug_info, &path, &mut self.error);
+        }
+    }
+
+    /*
+        Hotkeys work whatever window has focus. Only the combinations without Ctrl or Alt are left
+        to a text field being edited, and Ctrl and a number always picks the slot.
+    */
+    fn handle_hotkeys(&mut self, ctx: &egui::CtxRef, frame: &epi::Frame) {
+        let typing = ctx.wants_keyboard_input();
+        let pressed: Vec<KeyCombination> = ctx.input().events.iter().filter_map(|event| match event {
+            egui::Event::Key { key, pressed: true, modifiers } => Some(KeyCombination {
+                ctrl: modifiers.command,
+                shift: modifiers.shift,
+                alt: modifiers.alt,
+                key: format!("{:?}", key),
+            }),
+            _ => None,
+        }).collect();
+
+        if let Some(hotkey) = self.hotkey_panel.capturing {
+            if let Some(combination) = pressed.first() {
+                if combination.key != "Escape" {
+                    self.hotkeys.bind(hotkey, combination.clone());
+                }
+                self.hotkey_panel.capturing = None;
+            }
+            return;
+        }
+
+        for combination in pressed {
+            if combination.ctrl {
+                if let Some(slot) = SLOT_KEYS.iter().position(|key| format!("{:?}", key) == combination.key) {
+                    self.select_state_slot(slot);
+                    continue;
+                }
+            }
+            if typing && !combination.has_command_modifier() {
+                continue;
+            }
+            if let Some(hotkey) = self.hotkeys.find(&combination) {
+                self.run_hotkey(hotkey, frame);
+            }
+        }
+    }
+
+    fn select_state_slot(&mut self, slot: usize) {
+        self.state_slots.current = slot;
+        osd!("State slot {} selected", slot);
+    }
+
+    fn run_hotkey(&mut self, hotkey: Hotkey, frame: &epi::Frame) {
+        let running = self.snapshot.as_ref().map_or(false, |snapshot| snapshot.running);
+        match hotkey {
+            Hotkey::SaveState => self.emulator.send(Command::SaveStateSlot(self.state_slots.current)),
+            Hotkey::LoadState => {
+                self.emulator.send(Command::LoadStateSlot(self.state_slots.current));
+                self.emulator.send(Command::RequestSnapshot);
+            },
+            Hotkey::NextSlot => self.select_state_slot((self.state_slots.current + 1) % STATE_SLOTS),
+            Hotkey::PreviousSlot => self.select_state_slot((self.state_slots.current + STATE_SLOTS - 1) % STATE_SLOTS),
+            Hotkey::Pause => {
+                self.emulator.send(if running { Command::Pause } else { Command::Run });
+                osd!("{}", if running { "Paused" } else { "Resumed" });
+            },
+            Hotkey::FrameAdvance => self.emulator.send(Command::RunTo(RunTarget::VerticalInterrupt)),
+            Hotkey::FastForward => {
+                self.fast_forward = !self.fast_forward;
+                self.emulator.send(Command::SetFastForward(self.fast_forward));
+            },
+            Hotkey::Rewind => self.emulator.send(Command::Rewind),
+            Hotkey::Screenshot => self.emulator.send(Command::Screenshot),
+            Hotkey::CopyFrame => copy_frame(&self.last_frame, &mut self.error),
+            Hotkey::Fullscreen => self.set_fullscreen(frame, !self.fullscreen),
+            Hotkey::SwapPak => if let Some(snapshot) = &self.snapshot {
+                let kind = match snapshot.paks[0] {
+                    PakKind::ControllerPak => PakKind::RumblePak,
+                    _ => PakKind::ControllerPak,
+                };
+                self.emulator.send(Command::SetPak(0, kind));
+            },
+        };
+    }
+
+    /*
+        Port N reads its keyboard bindings and the Nth connected gamepad. The state is sent every frame
+        because resetting the console rebuilds the PIF.
+    */
+    fn poll_controllers(&mut self, ctx: &egui::CtxRef) {
+        let mut gamepad_event = None;
+        if let Some(gilrs) = &mut self.gilrs {
+            while let Some(event) = gilrs.next_event() {
+                // Ports take the connected gamepads in order
+                match event.event {
+                    gilrs::EventType::Connected => osd!("{} connected", gilrs.gamepad(event.id).name()),
+                    gilrs::EventType::Disconnected => osd!("{} disconnected", gilrs.gamepad(event.id).name()),
+                    _ => {},
+                };
+                let binding = match event.event {
+                    gilrs::EventType::ButtonPressed(button, _) if GAMEPAD_BUTTONS.contains(&button) => {
+                        Some(Binding::GamepadButton(format!("{:?}", button)))
+                    },
+                    gilrs::EventType::AxisChanged(axis, value, _) if GAMEPAD_AXES.contains(&axis) && value.abs() > AXIS_THRESHOLD => {
+                        Some(Binding::GamepadAxis(format!("{:?}", axis), value > 0.0))
+                    },
+                    _ => None,
+                };
+                gamepad_event = gamepad_event.or(binding);
+            }
+        }
+
+        let input = ctx.input();
+        if let Some((capture_input, key)) = self.input_panel.capturing {
+            let captured = match key {
+                true => input.events.iter().find_map(|event| match event {
+                    egui::Event::Key { key, pressed: true, .. } if *key != egui::Key::Escape => Some(Binding::Key(format!("{:?}", key))),
+                    _ => None,
+                }),
+                false => gamepad_event,
+            };
+            if let Some(binding) = captured {
+                self.input_config.mut_port(self.input_panel.port).bind(capture_input, binding);
+                self.input_panel.capturing = None;
+            } else if input.key_pressed(egui::Key::Escape) {
+                self.input_panel.capturing = None;
+            }
+        }
+
+        let keyboard = !ctx.wants_keyboard_input();
+        let gamepads: Vec<gilrs::GamepadId> = self.gilrs.iter().flat_map(|gilrs| gilrs.gamepads().map(|(id, _)| id)).collect();
+        for port in 0..CONTROLLER_PORTS {
+            let gamepad = self.gilrs.as_ref().zip(gamepads.get(port)).map(|(gilrs, id)| gilrs.gamepad(*id));
+            let config = self.input_config.port(port);
+            let state = config.controller_state(|binding| binding_value(&input, gamepad.as_ref(), keyboard, binding));
+            self.emulator.send(Command::SetControllerState(port, state));
+            // Pulsed by the emulator thread on every emulated frame, so it keeps its rate in fast forward
+            self.emulator.send(Command::SetTurbo(port, config.turbo_buttons(), config.turbo_period()));
+        }
+    }
+}
+
+impl epi::App for EmulatorApp {
+    fn name(&self) -> &str {
+        "Rultra64"
+    }
+
+    /// Called once before the first frame.
+    fn setup(
+        &mut self,
+        ctx: &egui::CtxRef,
+        _frame: &epi::Frame,
+        storage: Option<&dyn epi::Storage>,
+    ) {
+        storage::set_data_directory(storage::default_data_directory());
+        // Load previous app state (if any).
+        if let Some(data) = storage.and_then(|storage| storage.get_string(RECENT_ROMS_KEY)) {
+            self.recent_roms = RecentRoms::from_lines(&data);
+        }
+        if let Some(data) = storage.and_then(|storage| storage.get_string(INPUT_CONFIG_KEY)) {
+            self.input_config = InputConfig::from_lines(&data);
+        }
+        if let Some(data) = storage.and_then(|storage| storage.get_string(CHEATS_KEY)) {
+            self.cheats.database = CheatDatabase::from_text(&data);
+        }
+        if let Some(data) = storage.and_then(|storage| storage.get_string(AUTOSAVE_KEY)) {
+            self.autosave = AutosaveConfig::from_lines(&data);
+        }
+        if let Some(data) = storage.and_then(|storage| storage.get_string(HOTKEYS_KEY)) {
+            self.hotkeys = HotkeyConfig::from_lines(&data);
+        }
+        if let Some(data) = storage.and_then(|storage| storage.get_string(LAYOUT_KEY)) {
+            self.apply_layout(ctx, &GuiLayout::from_lines(&data));
+        }
+    }
+
+    /// Called by the frame work to save state before shutdown.
+    fn save(&mut self, storage: &mut dyn epi::Storage) {
+        storage.set_string(RECENT_ROMS_KEY, self.recent_roms.to_lines());
+        storage.set_string(INPUT_CONFIG_KEY, self.input_config.to_lines());
+        storage.set_string(CHEATS_KEY, self.cheats.database.to_text());
+        storage.set_string(AUTOSAVE_KEY, self.autosave.to_lines());
+        storage.set_string(HOTKEYS_KEY, self.hotkeys.to_lines());
+        storage.set_string(LAYOUT_KEY, self.layout().to_lines());
+    }
+
+    /// Called once on shutdown, after `save`.
+    fn on_exit(&mut self) {
+        self.emulator.send(Command::FlushSaves);
+    }
+
+    /// Called each time the UI needs repainting, which may be many times per second.
+    /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
+    fn update(&mut self, ctx: &egui::CtxRef, frame: &epi::Frame) {
+        self.process_responses(frame);
+        self.emulator.send(Command::RequestSnapshot);
+        self.fps.tick();
+        self.handle_dropped_files(ctx);
+        self.poll_controllers(ctx);
+        if let Some(snapshot) = &self.snapshot {
+            self.cheats.update_game(&self.emulator, &snapshot.game_id);
+            if self.state_slots.game != snapshot.game_id {
+                self.state_slots.game = snapshot.game_id.clone();
+                self.emulator.send(Command::RequestStateSlots);
+                self.emulator.send(Command::SetAutosave(self.autosave.enabled(&snapshot.game_id)));
+            }
+            if self.rom_info.open && self.rom_info.game != snapshot.game_id {
+                self.rom_info.game = snapshot.game_id.clone();
+                self.emulator.send(Command::RequestRomInfo);
+            }
+        }
+        if self.threads.open {
+            self.emulator.send(Command::RequestThreads(self.threads.active_queue(&self.symbols)));
+        }
+        let game_frame = self.snapshot.as_ref().map(|snapshot| snapshot.frames);
+        if self.message_queues.open && self.message_queues.frame != game_frame {
+            self.message_queues.frame = game_frame;
+            self.emulator.send(Command::RequestMessageQueues(self.threads.active_queue(&self.symbols)));
+        }
+        self.handle_hotkeys(ctx, frame);
+        let now = Instant::now();
+        self.osd.update(now);
+        let osd_messages = self.osd.visible(now);
+        if !osd_messages.is_empty() {
+            ctx.request_repaint();
+        }
+        // Ports with a controller plugged in, as the PIF last reported them to the game
+        let inputs: Vec<(usize, ControllerState)> = match &self.snapshot {
+            Some(snapshot) if self.show_inputs => snapshot.controllers.iter().enumerate()
+                .filter_map(|(port, state)| state.map(|state| (port, state)))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let leave_fullscreen = self.fullscreen && ctx.input().key_pressed(egui::Key::Escape);
+        if leave_fullscreen {
+            self.set_fullscreen(frame, false);
+        }
+
+        if self.fullscreen {
+            let Self { display, display_settings, snapshot, show_speed, fps, .. } = self;
+            egui::CentralPanel::default().frame(egui::Frame::none().fill(egui::Color32::BLACK)).show(ctx, |ui| {
+                build_display(ui, display, display_settings, &osd_messages, &inputs);
+            });
+            if let Some(snapshot) = snapshot.as_ref().filter(|snapshot| snapshot.running) {
+                if *show_speed {
+                    build_speed_overlay(ctx, snapshot, fps);
+                }
+                ctx.request_repaint();
+            }
+            return;
+        }
+
+        let gamepad_names: Vec<String> = self.gilrs.iter()
+            .flat_map(|gilrs| gilrs.gamepads().map(|(_, gamepad)| gamepad.name().to_string()))
+            .collect();
+        self.update_tmem_texture(frame);
+        self.update_heatmap_texture(frame);
+        let previous_filter = self.display_settings.filter;
+        let mut enter_fullscreen = false;
+        let Self { emulator, snapshot, display, last_frame, display_settings, theme, memory_viewer, memory_search, breakpoints, watches, tlb_viewer_open, rsp, rdp_viewer_open, hardware_registers, exceptions_open, dma, threads, message_queues, scheduler_open, log_console, ipc_address, symbols, debug_info, source_open, error, selected_register, register_editor, show_speed, show_inputs, fps, run_controls, recent_roms, input_config, input_panel, hotkeys, hotkey_panel, cheats, state_slots, autosave, autosave_offer, rom_info, controller_paks, tmem_viewer, framebuffer, heatmap, pif_viewer_open, audio_viewer_open, .. } = self;
+
+        egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
+            // The top panel is often a good place for a menu bar:
+            egui::menu::bar(ui, |ui| {
+                ui.menu_button("File", |ui| {
+                    if ui.button("Load ROM").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            load_rom(emulator, recent_roms, symbols, debug_info, &path, error);
+                        }
+                    }
+                    ui.menu_button("Recent ROMs", |ui| {
+                        let mut picked = None;
+                        for path in recent_roms.paths() {
+                            let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().to_string();
+                            if ui.button(name).on_hover_text(path.display().to_string()).clicked() {
+                                picked = Some(path.clone());
+                            }
+                        }
+                        if recent_roms.paths().is_empty() {
+                            ui.label("No recent ROMs");
+                        } else {
+                            ui.separator();
+                            if ui.button("Clear").clicked() {
+                                recent_roms.clear();
+                            }
+                        }
+                        if let Some(path) = picked {
+                            load_rom(emulator, recent_roms, symbols, debug_info, &path, error);
+                        }
+                    });
+                    if ui.button("Load ROM with patch").clicked() {
+                        let rom_path = rfd::FileDialog::new().pick_file();
+                        let patch_path = rom_path.as_ref().and_then(|_| {
+                            rfd::FileDialog::new().add_filter("IPS / BPS / xdelta patch", &crate::patch::PATCH_EXTENSIONS).pick_file()
+                        });
+                        if let (Some(rom_path), Some(patch_path)) = (rom_path, patch_path) {
+                            let result = crate::rom::ROM::new_from_filename(&rom_path.display().to_string()).and_then(|mut rom| {
+                                rom.apply_patch(&std::fs::read(&patch_path)?)?;
+                                Ok(rom)
+                            });
+                            match result {
+                                Ok(rom) => {
+                                    emulator.send(Command::LoadRom(rom));
+                                    *error = None;
+                                },
+                                Err(err) => *error = Some(format!("Could not patch {}: {}", rom_path.display(), err)),
+                            };
+                        }
+                    }
+                    if ui.button("Load symbols").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().add_filter("Symbols", &["sym", "map", "txt"]).pick_file() {
+                            match SymbolTable::load(&path) {
+                                Ok(table) => *symbols = table,
+                                Err(err) => *error = Some(format!("Could not read {}: {}", path.display(), err)),
+                            };
+                        }
+                    }
+                    if ui.button("Load debug info").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().add_filter("ELF", &["elf", "out"]).pick_file() {
+                            match DebugInfo::load(&path) {
+                                Ok(info) => {
+                                    if symbols.is_empty() {
+                                        *symbols = info.symbols().clone();
+                                    }
+                                    *debug_info = Some(info);
+                                },
+                                Err(err) => *error = Some(err),
+                            };
+                        }
+                    }
+                    ui.checkbox(&mut rom_info.open, "ROM information");
+                    ui.separator();
+                    if ui.button(hotkey_label("Screenshot", hotkeys, Hotkey::Screenshot)).clicked() {
+                        emulator.send(Command::Screenshot);
+                    }
+                    if ui.button(hotkey_label("Copy frame", hotkeys, Hotkey::CopyFrame)).clicked() {
+                        copy_frame(last_frame, error);
+                    }
+                    ui.menu_button(hotkey_label("Save State", hotkeys, Hotkey::SaveState), |ui| {
+                        if let Some(slot) = state_slots.show(ui) {
+                            state_slots.current = slot;
+                            emulator.send(Command::SaveStateSlot(slot));
+                            ui.close_menu();
+                        }
+                    });
+                    ui.menu_button(hotkey_label("Load State", hotkeys, Hotkey::LoadState), |ui| {
+                        if let Some(slot) = state_slots.show(ui) {
+                            state_slots.current = slot;
+                            emulator.send(Command::LoadStateSlot(slot));
+                            emulator.send(Command::RequestSnapshot);
+                            ui.close_menu();
+                        }
+                    });
+                    if ui.button("Import savestate").clicked() {
+                        let dialog = rfd::FileDialog::new().add_filter("mupen64plus / Project64 savestate", &["st", "pj", "zip"]);
+                        if let Some(path) = dialog.pick_file() {
+                            match std::fs::read(&path) {
+                                Ok(data) => emulator.send(Command::ImportState(data)),
+                                Err(err) => *error = Some(format!("Could not read {}: {}", path.display(), err)),
+                            };
+                        }
+                    }
+                    let game = snapshot.as_ref().and_then(|snapshot| snapshot.game_id.clone());
+                    if let Some(id) = &game {
+                        let mut enabled = autosave.enabled(&game);
+                        if ui.checkbox(&mut enabled, "Auto-save this game").changed() {
+                            autosave.set_enabled(id, enabled);
+                            emulator.send(Command::SetAutosave(enabled));
+                        }
+                    }
+                    if ui.checkbox(&mut autosave.default, "Auto-save other games").changed() {
+                        emulator.send(Command::SetAutosave(autosave.enabled(&game)));
+                    }
+                    ui.menu_button("Region", |ui| {
+                        if let Some(snapshot) = snapshot {
+                            let mut region_override = snapshot.region_override;
+                            let mut changed = false;
+                            changed |= ui.radio_value(&mut region_override, None, "Auto").changed();
+                            changed |= ui.radio_value(&mut region_override, Some(Region::NTSC), "NTSC").changed();
+                            changed |= ui.radio_value(&mut region_override, Some(Region::PAL), "PAL").changed();
+                            changed |= ui.radio_value(&mut region_override, Some(Region::MPAL), "MPAL").changed();
+                            if changed {
+                                emulator.send(Command::SetRegionOverride(region_override));
+                            }
+                        }
+                    });
+                    ui.menu_button("Controllers", |ui| {
+                        if let Some(snapshot) = snapshot {
+                            for port in 0..CONTROLLER_PORTS {
+                                let mut connected = snapshot.connected[port];
+                                if ui.checkbox(&mut connected, format!("Port {}", port + 1)).changed() {
+                                    emulator.send(Command::SetConnected(port, connected));
+                                }
+                                for kind in PakKind::ALL {
+                                    if ui.radio(snapshot.paks[port] == kind, kind.name()).clicked() && snapshot.paks[port] != kind {
+                                        emulator.send(Command::SetPak(port, kind));
+                                    }
+                                }
+                            }
+                        }
+                    });
+                    if ui.button("Quit").clicked() {
+                        frame.quit();
+                    }
+                });
+                ui.menu_button("View", |ui| {
+                    ui.label("Scaling");
+                    ui.radio_value(&mut display_settings.scaling, ScalingMode::AspectRatio, "Aspect ratio");
+                    ui.radio_value(&mut display_settings.scaling, ScalingMode::Integer, "Integer");
+                    ui.radio_value(&mut display_settings.scaling, ScalingMode::Stretch, "Stretch");
+                    ui.checkbox(&mut display_settings.widescreen, "Widescreen (16:9)");
+                    ui.separator();
+                    ui.label("Filter");
+                    ui.radio_value(&mut display_settings.filter, Filter::Nearest, "Nearest");
+                    ui.radio_value(&mut display_settings.filter, Filter::Bilinear, "Bilinear");
+                    if ui.checkbox(&mut display_settings.vi_filters, "VI filters (gamma, dither, divot)").changed() {
+                        emulator.send(Command::SetViFilters(display_settings.vi_filters));
+                    }
+                    ui.separator();
+                    ui.label("Theme");
+                    for option in Theme::ALL {
+                        if ui.radio_value(theme, option, option.name()).changed() {
+                            ctx.set_visuals(theme_visuals(option));
+                        }
+                    }
+                    ui.separator();
+                    ui.checkbox(show_speed, "Show speed");
+                    ui.checkbox(show_inputs, "Show inputs");
+                    if ui.button(hotkey_label("Fullscreen", hotkeys, Hotkey::Fullscreen)).clicked() {
+                        enter_fullscreen = true;
+                    }
+                });
+                ui.menu_button("Settings", |ui| {
+                    ui.checkbox(&mut input_panel.open, "Input");
+                    ui.checkbox(&mut hotkey_panel.open, "Hotkeys");
+                    ui.checkbox(&mut cheats.open, "Cheats");
+                    ui.checkbox(&mut controller_paks.open, "Controller Paks");
+                });
+                ui.menu_button("Debug", |ui| {
+                    ui.checkbox(&mut memory_viewer.open, "Memory viewer");
+                    ui.checkbox(&mut memory_search.open, "Memory search");
+                    ui.checkbox(&mut heatmap.open, "Memory heatmap");
+                    ui.checkbox(&mut breakpoints.open, "Breakpoints");
+                    ui.checkbox(&mut watches.open, "Watch");
+                    ui.checkbox(tlb_viewer_open, "TLB");
+                    ui.checkbox(&mut rsp.open, "RSP");
+                    ui.checkbox(rdp_viewer_open, "RDP commands");
+                    ui.checkbox(&mut tmem_viewer.open, "TMEM");
+                    ui.checkbox(&mut framebuffer.open, "Frame buffer");
+                    ui.checkbox(&mut hardware_registers.open, "Hardware registers");
+                    ui.checkbox(pif_viewer_open, "PIF RAM");
+                    ui.checkbox(audio_viewer_open, "Audio");
+                    ui.checkbox(exceptions_open, "Exceptions");
+                    ui.checkbox(&mut dma.open, "DMA log");
+                    ui.checkbox(&mut threads.open, "Threads");
+                    ui.checkbox(&mut message_queues.open, "Message queues");
+                    ui.checkbox(scheduler_open, "Scheduler");
+                    ui.checkbox(&mut log_console.open, "Log console");
+                    ui.checkbox(source_open, "Source");
+                    ui.separator();
+                    match ipc_address {
+                        Some(address) => {
+                            ui.label(format!("IPC server on {}", address));
+                        },
+                        None => if ui.button("Start IPC server").clicked() {
+                            match emulator.start_ipc_server(IPC_DEFAULT_PORT) {
+                                Ok(address) => *ipc_address = Some(address),
+                                Err(err) => *error = Some(format!("Could not start the IPC server: {}", err)),
+                            };
+                        },
+                    };
+                });
+            });
+        });
+
+        if let Some(snapshot) = snapshot {
+            build_registers_window(ctx, emulator, selected_register, register_editor, snapshot);
+            build_emulator_controls_window(ctx, emulator, snapshot, symbols, run_controls, error);
+            if breakpoints.open {
+                build_breakpoints_window(ctx, emulator, snapshot, symbols, breakpoints);
+            }
+            if watches.open {
+                build_watch_window(ctx, emulator, snapshot, watches);
+            }
+            if *tlb_viewer_open {
+                build_tlb_window(ctx, snapshot, tlb_viewer_open);
+            }
+            if rsp.open {
+                build_rsp_window(ctx, emulator, snapshot, rsp);
+            }
+            if *rdp_viewer_open {
+                build_rdp_window(ctx, snapshot, rdp_viewer_open);
+            }
+            if tmem_viewer.open {
+                build_tmem_window(ctx, emulator, snapshot, tmem_viewer);
+            }
+            if framebuffer.open {
+                build_framebuffer_window(ctx, emulator, snapshot, framebuffer);
+            }
+            if heatmap.open {
+                build_heatmap_window(ctx, snapshot, heatmap);
+            }
+            if *pif_viewer_open {
+                build_pif_window(ctx, snapshot, pif_viewer_open);
+            }
+            if *audio_viewer_open {
+                build_audio_window(ctx, snapshot, audio_viewer_open);
+            }
+            if hardware_registers.open {
+                build_hardware_registers_window(ctx, emulator, snapshot, hardware_registers);
+            }
+            if *exceptions_open {
+                build_exceptions_window(ctx, emulator, snapshot, exceptions_open);
+            }
+            if dma.open {
+                build_dma_window(ctx, emulator, snapshot, dma);
+            }
+            if threads.open {
+                build_threads_window(ctx, snapshot, symbols, threads);
+            }
+            if message_queues.open {
+                build_message_queues_window(ctx, symbols, message_queues);
+            }
+            if *scheduler_open {
+                build_scheduler_window(ctx, snapshot, scheduler_open);
+            }
+            if *source_open {
+                build_source_window(ctx, snapshot, debug_info, source_open);
+            }
+            if snapshot.running {
+                if *show_speed {
+                    build_speed_overlay(ctx, snapshot, fps);
+                }
+                ctx.request_repaint();
+            }
+        }
+        build_display_window(ctx, display, display_settings, &osd_messages, &inputs);
+        build_autosave_window(ctx, emulator, autosave_offer);
+        if hotkey_panel.open {
+            build_hotkeys_window(ctx, hotkeys, hotkey_panel);
+        }
+        if rom_info.open {
+            build_rom_info_window(ctx, rom_info);
+        }
+        if input_panel.open {
+            build_input_window(ctx, emulator, input_config, input_panel, &gamepad_names);
+        }
+        if cheats.open {
+            build_cheats_window(ctx, emulator, cheats, error);
+        }
+        if controller_paks.open {
+            let paths = snapshot.as_ref().map(|snapshot| snapshot.controller_pak_paths.clone()).unwrap_or_default();
+            build_controller_pak_window(ctx, controller_paks, &paths);
+        }
+        if log_console.open {
+            build_log_window(ctx, log_console, error);
+        }
+        if memory_viewer.open {
+            build_memory_window(ctx, emulator, memory_viewer);
+        }
+        if memory_search.open {
+            build_memory_search_window(ctx, emulator, memory_search, memory_viewer);
+        }
+
+        if self.display_settings.filter != previous_filter {
+            self.upload_frame(frame);
+        }
+        if enter_fullscreen {
+            self.set_fullscreen(frame, true);
+        }
+    }
+}
+
+fn build_registers_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, selected_register: &mut Register, editor: &mut RegisterEditor, snapshot: &Snapshot) {
+    egui::Window::new("Registers").vscroll(true).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.selectable_value(selected_register, Register::CPU, "CPU");
+            ui.selectable_value(selected_register, Register::CP0, "CP0");
+            ui.selectable_value(selected_register, Register::CP1, "CP1");
+        });
+        ui.separator();
+        match selected_register {
+            Register::CPU => build_cpu_registers(ui, emulator, snapshot, editor),
+            Register::CP0 => build_cp0_registers(ui, emulator, snapshot, editor),
+            Register::CP1 => build_cp1_registers(ui, snapshot),
+        };
+    });
+}
+
+// https://n64brew.dev/wiki/COP0#Status
+const STATUS_FLAGS: [(&str, u32); 16] = [
+    ("IE", 0), ("EXL", 1), ("ERL", 2), ("UX", 5), ("SX", 6), ("KX", 7),
+    ("DE", 16), ("CE", 17), ("CH", 18), ("SR", 20), ("TS", 21), ("BEV", 22),
+    ("ITS", 24), ("RE", 25), ("FR", 26), ("RP", 27),
+];
+
+fn build_cp0_registers(ui: &mut egui::Ui, emulator: &EmulatorThread, snapshot: &Snapshot, editor: &mut RegisterEditor) {
+    if snapshot.running {
+        ui.label("Pause the emulation to edit the registers");
+    }
+    egui::Grid::new("cp0_registers").striped(true).show(ui, |ui| {
+        ui.label("#");
+        ui.label("Name");
+        ui.label("Value");
+        ui.end_row();
+        for (index, name) in CP0_REGISTER_NAMES.into_iter().enumerate() {
+            ui.label(format!("{}", index));
+            ui.label(name);
+            let value = match CP0Registers::is_32bits(index) {
+                true => format!("0x{:08X}", snapshot.cp0[index] as u32),
+                false => format!("0x{:016X}", snapshot.cp0[index]),
+            };
+            if let Some(value) = editable_register(ui, editor, (Register::CP0, index), &[value], !snapshot.running) {
+                emulator.send(Command::SetCP0Register(index, value));
+                emulator.send(Command::RequestSnapshot);
+            }
+            ui.end_row();
+        }
+    });
+
+    ui.separator();
+    let status = snapshot.cp0[12] as u32;
+    ui.label("Status");
+    ui.horizontal_wrapped(|ui| {
+        for (name, bit) in STATUS_FLAGS {
+            ui.monospace(format!("{}={}", name, (status >> bit) & 1));
+        }
+        ui.monospace(format!("KSU={}", (status >> 3) & 0b11));
+        ui.monospace(format!("IM={:08b}", (status >> 8) & 0xFF));
+        ui.monospace(format!("CU={:04b}", status >> 28));
+    });
+    ui.separator();
+    let cause = snapshot.cp0[13] as u32;
+    let exception_code = ((cause >> 2) & 0x1F) as u8;
+    ui.label("Cause");
+    ui.horizontal_wrapped(|ui| {
+        ui.monospace(format!("ExcCode={} ({})", exception_code, exception_code_name(exception_code)));
+        ui.monospace(format!("IP={:08b}", (cause >> 8) & 0xFF));
+        ui.monospace(format!("CE={}", (cause >> 28) & 0b11));
+        ui.monospace(format!("BD={}", cause >> 31));
+    });
+    ui.separator();
+    let config = snapshot.cp0[16] as u32;
+    let k0 = (config & 0b111) as u8;
+    ui.label("Config");
+    ui.horizontal_wrapped(|ui| {
+        let cached = match k0 == CACHE_ALGORITHM_UNCACHED {
+            true => "uncached",
+            false => "cached",
+        };
+        ui.monospace(format!("K0={} ({})", k0, cached));
+        ui.monospace(format!("CU={}", (config >> 3) & 1));
+        ui.monospace(format!("BE={}", (config >> 15) & 1));
+        ui.monospace(format!("EP={}", (config >> 24) & 0xF));
+        ui.monospace(format!("EC={}", (config >> 28) & 0b111));
+    });
+}
+
+// Index of the rows after the 32 GPRs in the CPU tab
+const PC_ROW: usize = 32;
+const HI_ROW: usize = 33;
+const LO_ROW: usize = 34;
+
+fn build_cpu_registers(ui: &mut egui::Ui, emulator: &EmulatorThread, snapshot: &Snapshot, editor: &mut RegisterEditor) {
+    if snapshot.running {
+        ui.label("Pause the emulation to edit the registers");
+    }
+    let mut rows = vec![
+        (PC_ROW, String::from("-"), "PC", snapshot.program_counter),
+        (HI_ROW, String::from("-"), "hi", snapshot.hi),
+        (LO_ROW, String::from("-"), "lo", snapshot.lo),
+    ];
+    for (index, name) in CPU_REGISTER_NAMES.into_iter().enumerate() {
+        rows.push((index, format!("r{}", index), name, snapshot.registers[index]));
+    }
+    egui::Grid::new("cpu_registers").striped(true).show(ui, |ui| {
+        ui.label("#");
+        ui.label("Name");
+        ui.label("Hex");
+        ui.label("Decimal");
+        ui.end_row();
+        for (row, number, name, value) in rows {
+            ui.label(number);
+            ui.label(name);
+            let columns = [format!("0x{:016X}", value), format!("{}", value)];
+            if let Some(value) = editable_register(ui, editor, (Register::CPU, row), &columns, !snapshot.running) {
+                let register = match row {
+                    PC_ROW => RegisterName::PC,
+                    HI_ROW => RegisterName::Hi,
+                    LO_ROW => RegisterName::Lo,
+                    index => RegisterName::GPR(index),
+                };
+                emulator.send(Command::SetRegister(register, value));
+                emulator.send(Command::RequestSnapshot);
+            }
+            ui.end_row();
+        }
+    });
+}
+
+// Values are decimal unless they start with 0x
+fn parse_register_value(input: &str) -> Option<i64> {
+    let input = input.trim();
+    match input.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|value| value as i64),
+        None => input.parse().ok(),
+    }
+}
+
+/*
+    Draws one cell per column for a register value, clicking a cell while enabled turns it into a text field.
+    Returns the new value once it is submitted with Enter.
+*/
+fn editable_register(ui: &mut egui::Ui, editor: &mut RegisterEditor, target: (Register, usize), columns: &[String], enabled: bool) -> Option<i64> {
+    let mut submitted = None;
+    for (column, text) in columns.iter().enumerate() {
+        if enabled && editor.editing == Some(target) && editor.column == column {
+            let response = ui.text_edit_singleline(&mut editor.input);
+            if response.lost_focus() {
+                if ui.input().key_pressed(egui::Key::Enter) {
+                    submitted = parse_register_value(&editor.input);
+                }
+                editor.editing = None;
+            } else {
+                response.request_focus();
+            }
+        } else if ui.add_enabled(enabled, egui::Button::new(egui::RichText::new(text).monospace()).frame(false)).clicked() {
+            editor.editing = Some(target);
+            editor.column = column;
+            editor.input = text.clone();
+        }
+    }
+    submitted
+}
+
+// https://n64brew.dev/wiki/COP1#FCR31
+const FCR31_EXCEPTIONS: [&str; 6] = ["I", "U", "O", "Z", "V", "E"];
+const ROUNDING_MODES: [&str; 4] = ["Nearest", "Zero", "+Inf", "-Inf"];
+
+fn build_cp1_registers(ui: &mut egui::Ui, snapshot: &Snapshot) {
+    let fcr31 = snapshot.fcr31;
+    ui.label("FCR31");
+    ui.monospace(format!("{:08X}", fcr31));
+    ui.horizontal_wrapped(|ui| {
+        ui.monospace(format!("RM={}", ROUNDING_MODES[(fcr31 & 0b11) as usize]));
+        ui.monospace(format!("C={}", (fcr31 >> 23) & 1));
+        ui.monospace(format!("FS={}", (fcr31 >> 24) & 1));
+    });
+    egui::Grid::new("fcr31_flags").show(ui, |ui| {
+        ui.label("");
+        for name in FCR31_EXCEPTIONS {
+            ui.monospace(name);
+        }
+        ui.end_row();
+        // Flags and Enables don't have the unimplemented operation (E) bit
+        for (label, shift, count) in [("Flags", 2, 5), ("Enables", 7, 5), ("Cause", 12, 6)] {
+            ui.label(label);
+            for bit in 0..count {
+                ui.monospace(format!("{}", (fcr31 >> (shift + bit)) & 1));
+            }
+            ui.end_row();
+        }
+    });
+    ui.separator();
+    // With FR clear the odd registers are the upper halves of the even ones
+    ui.label(match snapshot.fr {
+        true => "FR=1, 32 registers of 64 bits",
+        false => "FR=0, 16 registers of 64 bits",
+    });
+    egui::Grid::new("cp1_registers").striped(true).show(ui, |ui| {
+        ui.label("Name");
+        ui.label("Raw");
+        ui.label("Single");
+        ui.label("Double");
+        ui.end_row();
+        for (index, value) in snapshot.fpr.into_iter().enumerate() {
+            ui.label(format!("f{}", index));
+            ui.monospace(format!("{:016X}", value));
+            ui.monospace(format!("{}", f32::from_bits(snapshot.fpr_words[index] as u32)));
+            match snapshot.fr || index % 2 == 0 {
+                true => ui.monospace(format!("{}", f64::from_bits(value as u64))),
+                false => ui.monospace("-"),
+            };
+            ui.end_row();
+        }
+    });
+}
+
+fn build_emulator_controls_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, symbols: &SymbolTable, controls: &mut RunControls, error: &Option<String>) {
+    egui::Window::new("Controls").vscroll(true).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            if snapshot.running {
+                if ui.button("Pause").clicked() {
+                    emulator.send(Command::Pause);
+                }
+            } else if ui.button("Run").clicked() {
+                emulator.send(Command::Run);
+            }
+            if ui.add_enabled(!snapshot.running, egui::Button::new("Tick")).clicked() {
+                emulator.send(Command::Step);
+            }
+            if ui.add_enabled(!snapshot.running, egui::Button::new("Frame advance")).on_hover_text("Run until the next VI").clicked() {
+                emulator.send(Command::RunTo(RunTarget::VerticalInterrupt));
+            }
+        });
+        ui.add_enabled_ui(!snapshot.running, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Step").clicked() {
+                    emulator.send(Command::RunTo(RunTarget::Instructions(controls.steps)));
+                }
+                ui.add(egui::DragValue::new(&mut controls.steps).clamp_range(1..=100_000_000).suffix(" instructions"));
+            });
+            ui.horizontal(|ui| {
+                let address = symbols.resolve(&controls.address_input);
+                if ui.add_enabled(address.is_some(), egui::Button::new("Run to")).clicked() {
+                    emulator.send(Command::RunTo(RunTarget::Address(address.unwrap())));
+                }
+                ui.text_edit_singleline(&mut controls.address_input);
+            });
+        });
+        ui.label(format!("Frames: {}", snapshot.frames));
+        if let Some(name) = symbols.describe(snapshot.program_counter) {
+            ui.label(format!("In {}", name));
+        }
+        if let Some(id) = snapshot.breakpoint_hit {
+            ui.colored_label(egui::Color32::YELLOW, format!("Stopped at breakpoint {}", id));
+        }
+        if let Some(error) = error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        ui.separator();
+        let mut multiplier = snapshot.cpu_clock_multiplier;
+        let slider = egui::Slider::new(&mut multiplier, MIN_CLOCK_MULTIPLIER..=MAX_CLOCK_MULTIPLIER).text("CPU overclock").suffix("x");
+        if ui.add(slider).changed() {
+            emulator.send(Command::SetCpuClockMultiplier(multiplier));
+        }
+    });
+}
+
+/*
+    Missing files are dropped from the recent list. Symbols and the ELF with the ROM's name are loaded
+    along with it, the ELF's symbols are used when there's no symbol file.
+*/
+fn load_rom(emulator: &EmulatorThread, recent_roms: &mut RecentRoms, symbols: &mut SymbolTable, debug_info: &mut Option<DebugInfo>, path: &Path, error: &mut Option<String>) {
+    match ROM::new_from_filename(&path.display().to_string()) {
+        Ok(rom) => {
+            emulator.send(Command::LoadRom(rom));
+            *debug_info = DebugInfo::load_for_rom(path);
+            *symbols = SymbolTable::load_for_rom(path)
+                .or_else(|| debug_info.as_ref().map(|info| info.symbols().clone()))
+                .unwrap_or_else(SymbolTable::new);
+            recent_roms.push(path);
+            *error = None;
+            log!(Level::Info, Subsystem::Frontend, "ROM loaded: {}", path.display());
+        },
+        Err(err) => {
+            if !path.is_file() {
+                recent_roms.remove(path);
+            }
+            *error = Some(format!("Could not load {}: {}", path.display(), err));
+        },
+    };
+}
+
+fn build_speed_overlay(ctx: &egui::CtxRef, snapshot: &Snapshot, fps: &FpsCounter) {
+    let speed = &snapshot.speed;
+    egui::Area::new("speed_overlay").anchor(egui::Align2::RIGHT_BOTTOM, [-8.0, -8.0]).interactable(false).show(ctx, |ui| {
+        egui::Frame::popup(ui.style()).show(ui, |ui| {
+            ui.monospace(format!(
+                "VI/s {:.1} ({:.0}%)  FPS {:.1}  {:.2} MIPS",
+                speed.vi_per_second, speed.percentage, fps.fps, speed.instructions_per_second / 1_000_000.0,
+            ));
+        });
+    });
+}
+
+// Asked once after loading a game that was left with an autosave
+fn build_autosave_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, offer: &mut Option<SlotInfo>) {
+    let mut answered = false;
+    if let Some(info) = offer.as_ref() {
+        egui::Window::new("Resume").collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label(format!("Resume from where you left off on {}?", format_timestamp(info.timestamp)));
+            ui.horizontal(|ui| {
+                if ui.button("Resume").clicked() {
+                    emulator.send(Command::ResumeAutosave);
+                    emulator.send(Command::RequestSnapshot);
+                    answered = true;
+                }
+                if ui.button("Start over").clicked() {
+                    answered = true;
+                }
+            });
+        });
+    }
+    if answered {
+        *offer = None;
+    }
+}
+
+fn build_display_window(ctx: &egui::CtxRef, display: &Option<Display>, settings: &DisplaySettings, osd_messages: &[(String, f32)], inputs: &[(usize, ControllerState)]) {
+    egui::Window::new("Display").resizable(true).default_size([640.0, 480.0]).show(ctx, |ui| {
+        build_display(ui, display, settings, osd_messages, inputs);
+    });
+}
+
+fn build_display(ui: &mut egui::Ui, display: &Option<Display>, settings: &DisplaySettings, osd_messages: &[(String, f32)], inputs: &[(usize, ControllerState)]) {
+    let rect = match display {
+        Some(display) => {
+            let available = ui.available_size();
+            let (width, height) = display_size(display.frame_size, (available.x, available.y), settings.scaling, settings.aspect_ratio());
+            ui.vertical_centered(|ui| {
+                ui.add_space((available.y - height).max(0.0) / 2.0);
+                ui.image(display.texture_id, egui::vec2(width, height)).rect
+            }).inner
+        },
+        None => ui.label("No video output").rect,
+    };
+    build_osd(ui, rect, osd_messages);
+    build_input_overlay(ui, rect, inputs);
+}
+
+// OSD messages go over the top left corner of the game, the newest at the bottom
+fn build_osd(ui: &egui::Ui, rect: egui::Rect, osd_messages: &[(String, f32)]) {
+    let painter = ui.painter_at(rect);
+    let mut position = rect.left_top() + egui::vec2(8.0, 8.0);
+    for (message, opacity) in osd_messages {
+        let background = painter.add(egui::Shape::Noop);
+        let text_rect = painter.text(position, egui::Align2::LEFT_TOP, message, egui::TextStyle::Body, egui::Color32::WHITE.linear_multiply(*opacity));
+        painter.set(background, egui::epaint::RectShape {
+            rect: text_rect.expand(4.0),
+            corner_radius: 4.0,
+            fill: egui::Color32::from_black_alpha((192.0 * opacity) as u8),
+            stroke: Default::default(),
+        });
+        position.y = text_rect.bottom() + 10.0;
+    }
+}
+
+// One line per connected port over the bottom left corner of the game: the stick and every button, lit while held
+fn build_input_overlay(ui: &egui::Ui, rect: egui::Rect, inputs: &[(usize, ControllerState)]) {
+    const STICK_RADIUS: f32 = 14.0;
+    let painter = ui.painter_at(rect);
+    let mut bottom = rect.bottom() - 8.0;
+    for (port, state) in inputs.iter().rev() {
+        let background = painter.add(egui::Shape::Noop);
+        let center = egui::pos2(rect.left() + 8.0 + STICK_RADIUS, bottom - STICK_RADIUS);
+        let label = painter.text(center, egui::Align2::CENTER_CENTER, format!("P{}", port + 1), egui::TextStyle::Small, egui::Color32::from_white_alpha(96));
+        painter.circle_stroke(center, STICK_RADIUS, egui::Stroke::new(1.0, egui::Color32::GRAY));
+        // The stick Y axis points up while the screen one points down
+        let stick = egui::vec2(state.x as f32, -(state.y as f32)) / STICK_RANGE * STICK_RADIUS;
+        let stick_position = center + if stick.length() > STICK_RADIUS { stick.normalized() * STICK_RADIUS } else { stick };
+        painter.circle_filled(stick_position, 3.0, egui::Color32::WHITE);
+
+        let mut left = center.x + STICK_RADIUS + 8.0;
+        for (input, button) in Input::ALL.iter().filter_map(|input| input.button().map(|button| (input, button))) {
+            let color = match state.buttons & button {
+                0 => egui::Color32::from_white_alpha(64),
+                _ => egui::Color32::WHITE,
+            };
+            left = painter.text(egui::pos2(left, center.y), egui::Align2::LEFT_CENTER, input.name(), egui::TextStyle::Small, color).right() + 6.0;
+        }
+        let line_rect = egui::Rect::from_min_max(egui::pos2(rect.left() + 8.0, bottom - STICK_RADIUS * 2.0), egui::pos2(left, bottom)).union(label);
+        painter.set(background, egui::epaint::RectShape {
+            rect: line_rect.expand(4.0),
+            corner_radius: 4.0,
+            fill: egui::Color32::from_black_alpha(160),
+            stroke: Default::default(),
+        });
+        bottom -= STICK_RADIUS * 2.0 + 12.0;
+    }
+}
+
+fn build_memory_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, viewer: &mut MemoryViewer) {
+    let mut open = viewer.open;
+    egui::Window::new("Memory").open(&mut open).default_size([560.0, 400.0]).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for (name, start, size) in MEMORY_PRESETS {
+                if ui.button(name).clicked() {
+                    viewer.go_to(start, size, false);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            ui.text_edit_singleline(&mut viewer.address_input);
+            let mut virtual_address = viewer.virtual_address;
+            ui.checkbox(&mut virtual_address, "Virtual");
+            let input = viewer.address_input.trim().trim_start_matches("0x").to_string();
+            if ui.button("Go").clicked() || virtual_address != viewer.virtual_address {
+                let start = i64::from_str_radix(&input, 16).unwrap_or(viewer.start) & !(MEMORY_ROW_SIZE as i64 - 1);
+                viewer.go_to(start, MEMORY_CUSTOM_SIZE, virtual_address);
+            }
+        });
+        if let Some(edit_address) = viewer.edit_address {
+            ui.horizontal(|ui| {
+                ui.monospace(format!("{:08X}", edit_address & 0xFFFFFFFF));
+                for (size, name) in [(1, "Byte"), (2, "Half"), (4, "Word")] {
+                    if ui.radio_value(&mut viewer.edit_size, size, name).changed() {
+                        viewer.edit_value = viewer.value(edit_address, size).map(|value| format!("{:X}", value)).unwrap_or_default();
+                    }
+                }
+                let response = ui.text_edit_singleline(&mut viewer.edit_value);
+                let submitted = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+                if ui.button("Write").clicked() || submitted {
+                    match u64::from_str_radix(viewer.edit_value.trim().trim_start_matches("0x"), 16) {
+                        Ok(value) => {
+                            let data = value.to_be_bytes()[8 - viewer.edit_size..].to_vec();
+                            emulator.send(Command::WriteMemory { address: edit_address, virtual_address: viewer.virtual_address, data });
+                        },
+                        Err(_) => viewer.edit_value = String::from("Invalid value"),
+                    };
+                }
+            });
+        }
+        ui.separator();
+
+        let row_height = ui.fonts().row_height(egui::TextStyle::Monospace).max(ui.spacing().interact_size.y);
+        let rows = (viewer.size as usize + MEMORY_ROW_SIZE - 1) / MEMORY_ROW_SIZE;
+        egui::ScrollArea::vertical().auto_shrink([false; 2]).show_rows(ui, row_height, rows, |ui, visible_rows| {
+            // Only the pages on screen are requested, they arrive on the next frames
+            let first = viewer.start + (visible_rows.start * MEMORY_ROW_SIZE) as i64;
+            let last = viewer.start + (visible_rows.end * MEMORY_ROW_SIZE) as i64;
+            let mut page = first & !(MEMORY_PAGE_SIZE as i64 - 1);
+            while page < last {
+                emulator.send(Command::ReadMemoryPage { address: page, virtual_address: viewer.virtual_address });
+                page += MEMORY_PAGE_SIZE as i64;
+            }
+
+            for row in visible_rows {
+                let address = viewer.start + (row * MEMORY_ROW_SIZE) as i64;
+                ui.horizontal(|ui| {
+                    ui.spacing_mut().item_spacing.x = 2.0;
+                    ui.monospace(format!("{:08X} ", address & 0xFFFFFFFF));
+                    let mut ascii = String::new();
+                    for byte_address in address..address + MEMORY_ROW_SIZE as i64 {
+                        let byte = viewer.byte(byte_address);
+                        let text = byte.map(|byte| format!("{:02X}", byte)).unwrap_or_else(|| String::from("??"));
+                        let selected = viewer.edit_address.is_some_and(|edit_address| {
+                            (edit_address..edit_address + viewer.edit_size as i64).contains(&byte_address)
+                        });
+                        // Clicking a byte selects it for editing
+                        if ui.selectable_label(selected, egui::RichText::new(text).monospace()).clicked() {
+                            viewer.edit_address = Some(byte_address);
+                            viewer.edit_value = viewer.value(byte_address, viewer.edit_size).map(|value| format!("{:X}", value)).unwrap_or_default();
+                        }
+                        ascii.push(match byte {
+                            Some(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                            Some(_) => '.',
+                            None => ' ',
+                        });
+                    }
+                    ui.monospace(format!(" {}", ascii));
+                });
+            }
+        });
+    });
+    viewer.open = open;
+}
+
+fn build_memory_search_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, search: &mut MemorySearchPanel, viewer: &mut MemoryViewer) {
+    let mut open = search.open;
+    egui::Window::new("Memory search").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for (value_type, name) in SEARCH_VALUE_TYPES {
+                ui.radio_value(&mut search.value_type, value_type, name);
+            }
+        });
+        if ui.button("New search").clicked() {
+            emulator.send(Command::StartSearch(search.value_type));
+        }
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut search.comparison, SearchComparison::Equal, "=");
+            ui.radio_value(&mut search.comparison, SearchComparison::Greater, ">");
+            ui.radio_value(&mut search.comparison, SearchComparison::Less, "<");
+            ui.radio_value(&mut search.comparison, SearchComparison::Changed, "Changed");
+            ui.radio_value(&mut search.comparison, SearchComparison::Unchanged, "Unchanged");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Value");
+            ui.text_edit_singleline(&mut search.value_input);
+            let comparison = search.comparison();
+            let can_scan = comparison.is_some() && search.results.is_some();
+            if ui.add_enabled(can_scan, egui::Button::new("Scan")).clicked() {
+                if let Some(comparison) = comparison {
+                    emulator.send(Command::Scan(comparison));
+                }
+            }
+        });
+        ui.separator();
+        match &search.results {
+            Some(results) => {
+                ui.label(format!("{} matches", results.count));
+                egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    for (offset, value) in results.results.iter() {
+                        let address = 0x80000000 | *offset as i64;
+                        let text = match results.value_type {
+                            ValueType::F32 => format!("{:08X}  {}", address, value),
+                            _ => format!("{:08X}  {} (0x{:X})", address, value, *value as i64),
+                        };
+                        // Clicking a match shows it in the memory viewer
+                        if ui.selectable_label(false, egui::RichText::new(text).monospace()).clicked() {
+                            viewer.go_to(address & !(MEMORY_ROW_SIZE as i64 - 1), MEMORY_CUSTOM_SIZE, true);
+                            viewer.edit_address = Some(address);
+                            viewer.edit_size = results.value_type.size();
+                            viewer.open = true;
+                        }
+                    }
+                });
+            },
+            None => {ui.label("Start a new search to take a snapshot of RDRAM");},
+        };
+    });
+    search.open = open;
+}
+
+fn build_breakpoints_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, symbols: &SymbolTable, panel: &mut BreakpointPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Breakpoints").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut panel.kind, BreakpointKind::Execute, "Execute");
+            ui.radio_value(&mut panel.kind, BreakpointKind::Read, "Read");
+            ui.radio_value(&mut panel.kind, BreakpointKind::Write, "Write");
+            ui.radio_value(&mut panel.kind, BreakpointKind::Access, "Read/Write");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            ui.text_edit_singleline(&mut panel.address_input);
+            if panel.kind != BreakpointKind::Execute {
+                ui.add(egui::DragValue::new(&mut panel.len).clamp_range(1..=0x1000).prefix("Size: "));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Condition");
+            ui.text_edit_singleline(&mut panel.condition_input);
+            if ui.button("Add").clicked() {
+                let condition = match panel.condition_input.trim() {
+                    "" => Ok(None),
+                    input => Expression::parse(input).map(Some),
+                };
+                match (symbols.resolve(&panel.address_input), condition) {
+                    (Some(address), Ok(condition)) => {
+                        let len = match panel.kind {
+                            BreakpointKind::Execute => 1,
+                            _ => panel.len,
+                        };
+                        emulator.send(Command::AddBreakpoint { address, len, kind: panel.kind, condition });
+                        panel.error = None;
+                    },
+                    (None, _) => panel.error = Some(format!("Unknown address or symbol: {}", panel.address_input)),
+                    (_, Err(err)) => panel.error = Some(format!("Invalid condition: {}", err)),
+                };
+            }
+        });
+        if let Some(error) = &panel.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        ui.separator();
+        for breakpoint in snapshot.breakpoints.iter() {
+            ui.horizontal(|ui| {
+                let mut enabled = breakpoint.enabled;
+                if ui.checkbox(&mut enabled, "").changed() {
+                    emulator.send(Command::SetBreakpointEnabled(breakpoint.id, enabled));
+                }
+                let range = match breakpoint.kind {
+                    BreakpointKind::Execute => match symbols.describe(breakpoint.address) {
+                        Some(name) => format!("{:08X} ({})", breakpoint.address, name),
+                        None => format!("{:08X}", breakpoint.address),
+                    },
+                    _ => format!("{:08X}-{:08X}", breakpoint.address, breakpoint.address + breakpoint.len - 1),
+                };
+                let mut text = format!("{:?} {} hits: {}", breakpoint.kind, range, breakpoint.hit_count);
+                if let Some(condition) = &breakpoint.condition {
+                    text.push_str(&format!(" if {}", condition));
+                }
+                match snapshot.breakpoint_hit == Some(breakpoint.id) {
+                    true => ui.colored_label(egui::Color32::YELLOW, text),
+                    false => ui.label(text),
+                };
+                if ui.button("Remove").clicked() {
+                    emulator.send(Command::RemoveBreakpoint(breakpoint.id));
+                }
+            });
+        }
+    });
+    panel.open = open;
+}
+
+fn build_watch_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, panel: &mut WatchPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Watch").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            let response = ui.text_edit_singleline(&mut panel.input);
+            let submitted = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            if ui.button("Add").clicked() || submitted {
+                match Expression::parse(&panel.input) {
+                    Ok(expression) => {
+                        emulator.send(Command::AddWatch(expression));
+                        panel.input.clear();
+                        panel.error = None;
+                    },
+                    Err(err) => panel.error = Some(err),
+                };
+            }
+        });
+        if let Some(error) = &panel.error {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        ui.separator();
+        egui::Grid::new("watches").striped(true).show(ui, |ui| {
+            for (index, (expression, value)) in snapshot.watches.iter().enumerate() {
+                ui.monospace(expression.to_string());
+                ui.monospace(format!("0x{:X}", value));
+                ui.monospace(format!("{}", value));
+                if ui.button("Remove").clicked() {
+                    emulator.send(Command::RemoveWatch(index));
+                }
+                ui.end_row();
+            }
+        });
+    });
+    panel.open = open;
+}
 
-#[derive(PartialEq, Eq)]
-enum Register {
-    CPU,
-    CP0,
+// The entry used by the most recent translation is highlighted
+fn build_tlb_window(ctx: &egui::CtxRef, snapshot: &Snapshot, open: &mut bool) {
+    egui::Window::new("TLB").open(open).vscroll(true).show(ctx, |ui| {
+        egui::Grid::new("tlb_entries").striped(true).show(ui, |ui| {
+            for header in ["#", "VPN2", "Mask", "ASID", "G", "PFN0", "C0", "D0", "V0", "PFN1", "C1", "D1", "V1"] {
+                ui.label(header);
+            }
+            ui.end_row();
+            for (index, entry) in snapshot.tlb.iter().enumerate() {
+                let color = match snapshot.tlb_last_used == Some(index) {
+                    true => egui::Color32::YELLOW,
+                    false => ui.visuals().text_color(),
+                };
+                let columns = [
+                    format!("{}", index),
+                    format!("{:07X}", entry.vpn2()),
+                    format!("{:04X}", entry.page_mask >> 13),
+                    format!("{:02X}", entry.asid()),
+                    format!("{}", entry.global() as u8),
+                    format!("{:05X}", TLBEntry::pfn(entry.entry_lo_0)),
+                    format!("{}", TLBEntry::cache_algorithm(entry.entry_lo_0)),
+                    format!("{}", TLBEntry::dirty(entry.entry_lo_0) as u8),
+                    format!("{}", TLBEntry::valid(entry.entry_lo_0) as u8),
+                    format!("{:05X}", TLBEntry::pfn(entry.entry_lo_1)),
+                    format!("{}", TLBEntry::cache_algorithm(entry.entry_lo_1)),
+                    format!("{}", TLBEntry::dirty(entry.entry_lo_1) as u8),
+                    format!("{}", TLBEntry::valid(entry.entry_lo_1) as u8),
+                ];
+                for column in columns {
+                    ui.label(egui::RichText::new(column).monospace().color(color));
+                }
+                ui.end_row();
+            }
+        });
+    });
 }
 
-impl Default for Register {
-    fn default() -> Self {
-        Self::CPU
-    }
+fn build_rsp_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, panel: &mut RspPanel) {
+    let rsp = &snapshot.rsp;
+    let mut open = panel.open;
+    egui::Window::new("RSP").open(&mut open).vscroll(true).show(ctx, |ui| {
+        let halted = rsp.status & SP_STATUS_HALT != 0;
+        ui.horizontal(|ui| {
+            if halted {
+                if ui.button("Run").clicked() {
+                    emulator.send(Command::SetRspHalted(false));
+                }
+            } else if ui.button("Halt").clicked() {
+                emulator.send(Command::SetRspHalted(true));
+            }
+            if ui.add_enabled(halted, egui::Button::new("Step")).clicked() {
+                emulator.send(Command::StepRsp);
+                emulator.send(Command::RequestSnapshot);
+            }
+        });
+        ui.monospace(format!("SP_PC     {:03X}", rsp.program_counter));
+        ui.monospace(format!("SP_STATUS {:08X}", rsp.status));
+        ui.horizontal_wrapped(|ui| {
+            for (name, bit) in [("HALT", SP_STATUS_HALT), ("BROKE", SP_STATUS_BROKE), ("SSTEP", SP_STATUS_SSTEP), ("INTR_BREAK", SP_STATUS_INTR_BREAK)] {
+                ui.monospace(format!("{}={}", name, (rsp.status & bit != 0) as u8));
+            }
+            ui.monospace(format!("SIG={:08b}", (rsp.status >> 7) & 0xFF));
+            ui.monospace(format!("SEMAPHORE={}", rsp.semaphore as u8));
+        });
+        match &rsp.microcode {
+            Some(microcode) => ui.label(format!("Microcode: {}", microcode)),
+            None => ui.label("Microcode: no task started yet"),
+        };
+        if rsp.microcodes.len() > 1 {
+            ui.collapsing("Microcodes used", |ui| {
+                for microcode in &rsp.microcodes {
+                    ui.monospace(microcode.to_string());
+                }
+            });
+        }
+
+        ui.collapsing("Scalar registers", |ui| {
+            egui::Grid::new("rsp_registers").striped(true).show(ui, |ui| {
+                for (index, value) in rsp.registers.iter().enumerate() {
+                    ui.label(format!("r{} {}", index, CPU_REGISTER_NAMES[index]));
+                    ui.monospace(format!("{:08X}", value));
+                    if index % 4 == 3 {
+                        ui.end_row();
+                    }
+                }
+            });
+        });
+
+        ui.collapsing("Vector registers", |ui| {
+            egui::Grid::new("rsp_vector_registers").striped(true).show(ui, |ui| {
+                ui.label("");
+                for element in 0..8 {
+                    ui.label(format!("e{}", element));
+                }
+                ui.end_row();
+                for (index, register) in rsp.vector_registers.iter().enumerate() {
+                    ui.label(format!("v{}", index));
+                    for element in register {
+                        ui.monospace(format!("{:04X}", element));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut panel.memory, RspMemory::Dmem, "DMEM");
+            ui.selectable_value(&mut panel.memory, RspMemory::Imem, "IMEM");
+        });
+        let (memory, base) = match panel.memory {
+            RspMemory::Dmem => (&rsp.dmem, 0x04000000),
+            RspMemory::Imem => (&rsp.imem, 0x04001000),
+        };
+        let row_height = ui.fonts().row_height(egui::TextStyle::Monospace);
+        egui::ScrollArea::vertical().max_height(300.0).auto_shrink([false; 2]).show_rows(ui, row_height, memory.len() / MEMORY_ROW_SIZE, |ui, visible_rows| {
+            for row in visible_rows {
+                let bytes = &memory[row * MEMORY_ROW_SIZE..(row + 1) * MEMORY_ROW_SIZE];
+                let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                ui.monospace(format!("{:08X}  {}", base + row * MEMORY_ROW_SIZE, hex.join(" ")));
+            }
+        });
+    });
+    panel.open = open;
 }
 
-pub struct EmulatorApp {
-    core: Emulator,
-    selected_register: Register,
+// The command DPC_CURRENT points to is highlighted, hovering a command shows its raw words
+fn build_rdp_window(ctx: &egui::CtxRef, snapshot: &Snapshot, open: &mut bool) {
+    let rdp = &snapshot.rdp;
+    egui::Window::new("RDP commands").open(open).default_size([420.0, 400.0]).show(ctx, |ui| {
+        ui.monospace(format!("DPC_START   {:06X}", rdp.start));
+        ui.monospace(format!("DPC_END     {:06X}", rdp.end));
+        ui.monospace(format!("DPC_CURRENT {:06X}", rdp.current));
+        ui.monospace(format!("DPC_STATUS  {:08X} (XBUS={})", rdp.status, (rdp.status & DPC_STATUS_XBUS != 0) as u8));
+        ui.collapsing("Combiner", |ui| {
+            let cycles = match rdp.cycle_type {
+                CYCLE_TYPE_2CYCLE => 0..2,
+                _ => 1..2,
+            };
+            for cycle in cycles {
+                ui.monospace(format!("RGB   {}", rdp.combine_mode.rgb_equation(cycle)));
+                ui.monospace(format!("Alpha {}", rdp.combine_mode.alpha_equation(cycle)));
+            }
+        });
+        ui.collapsing("Blender", |ui| {
+            ui.monospace(format!("Other modes {:014X}", rdp.other_modes));
+            ui.monospace(format!("Blend color {:02X?}", rdp.blend_color));
+            ui.monospace(format!("Fog color   {:02X?}", rdp.fog_color));
+            ui.horizontal_wrapped(|ui| {
+                for (name, bit) in [("FORCE_BLEND", OTHER_MODES_FORCE_BLEND), ("AA", OTHER_MODES_ANTIALIAS), ("Z_CMP", OTHER_MODES_Z_COMPARE), ("Z_UPD", OTHER_MODES_Z_UPDATE), ("ALPHA_CMP", OTHER_MODES_ALPHA_COMPARE)] {
+                    ui.monospace(format!("{}={}", name, (rdp.other_modes & bit != 0) as u8));
+                }
+            });
+        });
+        ui.separator();
+        if rdp.commands.is_empty() {
+            ui.label("The command buffer is empty");
+            return;
+        }
+        let row_height = ui.fonts().row_height(egui::TextStyle::Monospace);
+        egui::ScrollArea::vertical().auto_shrink([false; 2]).show_rows(ui, row_height, rdp.commands.len(), |ui, visible_rows| {
+            for command in &rdp.commands[visible_rows] {
+                let end = command.address + command.words.len() as u32 * 8;
+                let color = match (command.address..end).contains(&rdp.current) {
+                    true => egui::Color32::YELLOW,
+                    false => ui.visuals().text_color(),
+                };
+                let words: Vec<String> = command.words.iter().map(|word| format!("{:016X}", word)).collect();
+                ui.label(egui::RichText::new(format!("{:06X}  {}", command.address, command.text)).monospace().color(color))
+                    .on_hover_text(words.join("\n"));
+            }
+        });
+    });
 }
 
-impl Default for EmulatorApp {
-    fn default() -> Self {
-        Self {
-            core: Emulator::new_hle(),
-            selected_register: Register::CPU,
+fn build_tmem_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, viewer: &mut TmemViewer) {
+    let mut open = viewer.open;
+    egui::Window::new("TMEM").open(&mut open).default_size([360.0, 420.0]).show(ctx, |ui| {
+        if ui.checkbox(&mut viewer.dump_textures, "Dump textures").on_hover_text("Writes every texture the game loads next to the ROM, in the dump directory of its texture pack").changed() {
+            emulator.send(Command::SetTextureDump(viewer.dump_textures));
         }
-    }
+        ui.horizontal(|ui| {
+            ui.label("Tile");
+            for tile in 0..TILE_DESCRIPTORS {
+                ui.selectable_value(&mut viewer.tile, tile, tile.to_string());
+            }
+        });
+        let tile = snapshot.rdp.tiles[viewer.tile];
+        let format = TEXTURE_FORMATS.get(tile.format as usize).copied().unwrap_or("?");
+        ui.monospace(format!("{} {}  line {}  TMEM {:03X}  palette {}", format, TEXEL_SIZES[tile.size as usize], tile.line, tile.tmem_address as usize * 8, tile.palette));
+        ui.monospace(format!("({}, {}) - ({}, {})", tile.sl as f32 / 4.0, tile.tl as f32 / 4.0, tile.sh as f32 / 4.0, tile.th as f32 / 4.0));
+        for (axis, clamp, mirror, mask, shift) in [("S", tile.clamp_s, tile.mirror_s, tile.mask_s, tile.shift_s), ("T", tile.clamp_t, tile.mirror_t, tile.mask_t, tile.shift_t)] {
+            ui.monospace(format!("{}  clamp {}  mirror {}  mask {}  shift {}", axis, clamp as u8, mirror as u8, mask, shift));
+        }
+        ui.separator();
+        ui.checkbox(&mut viewer.custom, "Override the descriptor");
+        ui.add_enabled_ui(viewer.custom, |ui| {
+            ui.horizontal(|ui| {
+                for (index, name) in TEXTURE_FORMATS.iter().enumerate() {
+                    ui.selectable_value(&mut viewer.format, index as u8, *name);
+                }
+                ui.separator();
+                for (index, name) in TEXEL_SIZES.iter().enumerate() {
+                    ui.selectable_value(&mut viewer.size, index as u8, *name);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Palette");
+                ui.add(egui::DragValue::new(&mut viewer.palette).clamp_range(0..=15));
+                ui.label("Size");
+                ui.add(egui::DragValue::new(&mut viewer.width).clamp_range(1..=1024));
+                ui.add(egui::DragValue::new(&mut viewer.height).clamp_range(1..=1024));
+            });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Zoom");
+            ui.add(egui::Slider::new(&mut viewer.zoom, 1.0..=8.0).integer());
+        });
+        ui.separator();
+        if let Some((texture_id, [width, height])) = viewer.texture {
+            egui::ScrollArea::both().show(ui, |ui| {
+                ui.image(texture_id, [width as f32 * viewer.zoom, height as f32 * viewer.zoom]);
+            });
+        }
+    });
+    viewer.open = open;
 }
 
-impl epi::App for EmulatorApp {
-    fn name(&self) -> &str {
-        "Rultra64"
+fn build_framebuffer_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, panel: &mut FramebufferPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Frame buffer").open(&mut open).show(ctx, |ui| {
+        match &snapshot.framebuffer {
+            Some(view) => {
+                ui.monospace(format!("VI: {:06X}  {}x{}  {}", view.origin, view.width, view.height, view.format.name()));
+                if ui.button("Use the VI registers").clicked() {
+                    panel.set_view(view);
+                }
+            },
+            None => {
+                ui.monospace("VI: blank");
+            },
+        };
+        ui.separator();
+        ui.checkbox(&mut panel.enabled, "Display this region instead of VI_ORIGIN");
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            ui.add(egui::TextEdit::singleline(&mut panel.address).desired_width(80.0));
+        });
+        let line = (panel.width * panel.format.bytes_per_pixel()) as i64;
+        let frame = line * panel.height as i64;
+        ui.horizontal(|ui| {
+            if ui.button("- frame").clicked() {
+                panel.move_origin(-frame);
+            }
+            if ui.button("- line").clicked() {
+                panel.move_origin(-line);
+            }
+            if ui.button("+ line").clicked() {
+                panel.move_origin(line);
+            }
+            if ui.button("+ frame").clicked() {
+                panel.move_origin(frame);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Size");
+            ui.add(egui::DragValue::new(&mut panel.width).clamp_range(1..=1024));
+            ui.add(egui::DragValue::new(&mut panel.height).clamp_range(1..=1024));
+        });
+        ui.horizontal(|ui| {
+            for format in PixelFormat::ALL {
+                ui.selectable_value(&mut panel.format, format, format.name());
+            }
+        });
+        if panel.enabled && panel.view().is_none() {
+            ui.colored_label(egui::Color32::RED, "Invalid address");
+        }
+    });
+    panel.open = open;
+
+    let view = match panel.enabled {
+        true => panel.view(),
+        false => None,
+    };
+    if view != panel.sent {
+        emulator.send(Command::SetFramebufferOverride(view));
+        panel.sent = view;
     }
+}
 
-    /// Called once before the first frame.
-    fn setup(
-        &mut self,
-        _ctx: &egui::CtxRef,
-        _frame: &epi::Frame,
-        _storage: Option<&dyn epi::Storage>,
-    ) {
-        // Load previous app state (if any).
-        // Note that you must enable the `persistence` feature for this to work.
-        #[cfg(feature = "persistence")]
-        if let Some(storage) = _storage {
-            *self = epi::get_value(storage, epi::APP_KEY).unwrap_or_default()
+fn build_heatmap_window(ctx: &egui::CtxRef, snapshot: &Snapshot, panel: &mut HeatmapPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Memory heatmap").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for (access, shown) in Access::ALL.iter().zip(panel.shown.iter_mut()) {
+                ui.checkbox(shown, access.name());
+            }
+            ui.separator();
+            ui.label("Zoom");
+            ui.add(egui::Slider::new(&mut panel.zoom, 4.0..=24.0).integer());
+        });
+        ui.label("Red: written, green: read, blue: executed. Each cell is a 4KB page.");
+        ui.separator();
+        if let Some(texture_id) = panel.texture {
+            let rows = HEATMAP_PAGES / HEATMAP_COLUMNS;
+            let response = ui.image(texture_id, [HEATMAP_COLUMNS as f32 * panel.zoom, rows as f32 * panel.zoom]);
+            if let Some(position) = response.hover_pos() {
+                let offset = (position - response.rect.min) / panel.zoom;
+                let page = (offset.y as usize).min(rows - 1) * HEATMAP_COLUMNS + (offset.x as usize).min(HEATMAP_COLUMNS - 1);
+                if let Some(counts) = snapshot.heatmap.get(page) {
+                    response.on_hover_text(format!(
+                        "{:06X} - {:06X}\nRead {}  Write {}  Execute {}",
+                        page * HEATMAP_PAGE_SIZE, (page + 1) * HEATMAP_PAGE_SIZE - 1, counts[0], counts[1], counts[2],
+                    ));
+                }
+            }
         }
-    }
+    });
+    panel.open = open;
+}
 
-    /// Called by the frame work to save state before shutdown.
-    /// Note that you must enable the `persistence` feature for this to work.
-    #[cfg(feature = "persistence")]
-    fn save(&mut self, storage: &mut dyn epi::Storage) {
-        epi::set_value(storage, epi::APP_KEY, self);
+// Meaning of the response bytes of the commands a game sends every frame
+fn describe_joybus_response(frame: &JoybusFrame) -> Option<String> {
+    if frame.no_device {
+        return Some(String::from("No device"));
+    }
+    match (frame.command.first()?, frame.response.as_slice()) {
+        (0x00 | 0xFF, [high, low, status]) => Some(format!("Device {:04X}, status {:02X}", u16::from_be_bytes([*high, *low]), status)),
+        (0x01, [high, low, x, y]) => {
+            let buttons = u16::from_be_bytes([*high, *low]);
+            let pressed: Vec<&str> = Input::ALL.iter()
+                .filter(|input| input.button().map_or(false, |mask| buttons & mask != 0))
+                .map(|input| input.name())
+                .collect();
+            Some(format!("X {} Y {} {}", *x as i8, *y as i8, pressed.join(" ")))
+        },
+        _ => None,
     }
+}
 
-    /// Called each time the UI needs repainting, which may be many times per second.
-    /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
-    fn update(&mut self, ctx: &egui::CtxRef, frame: &epi::Frame) {
-        let Self { core: emulator_core, selected_register } = self;
+fn build_rom_info_window(ctx: &egui::CtxRef, panel: &mut RomInfoPanel) {
+    let mut open = panel.open;
+    egui::Window::new("ROM information").open(&mut open).show(ctx, |ui| {
+        let info = match &panel.info {
+            Some(info) => info,
+            None => {
+                ui.label("No ROM loaded");
+                return;
+            },
+        };
+        egui::Grid::new("rom_info").striped(true).show(ui, |ui| {
+            ui.label("Name");
+            ui.label(&info.name);
+            ui.end_row();
+            ui.label("Game code");
+            ui.monospace(&info.game_code);
+            ui.end_row();
+            ui.label("Version");
+            ui.label(format!("1.{}", info.version));
+            ui.end_row();
+            ui.label("Region");
+            ui.label(info.region.name());
+            ui.end_row();
+            ui.label("Size");
+            ui.label(format!("{} MB", info.size as f32 / (1024.0 * 1024.0)));
+            ui.end_row();
+            ui.label("CRC");
+            ui.monospace(format!("{:08X} {:08X}", info.crc.0, info.crc.1));
+            ui.end_row();
+            ui.label("Calculated CRC");
+            match info.calculated_crc {
+                Some(crc) if crc == info.crc => ui.monospace(format!("{:08X} {:08X}", crc.0, crc.1)),
+                Some(crc) => ui.colored_label(egui::Color32::RED, format!("{:08X} {:08X} (mismatch)", crc.0, crc.1)),
+                None => ui.label("Unknown CIC"),
+            };
+            ui.end_row();
+            ui.label("CIC");
+            ui.label(info.cic.map_or("Unknown", |cic| cic.name()));
+            ui.end_row();
+            ui.label("Save type");
+            ui.label(info.save_type.name());
+            ui.end_row();
+            ui.label("RetroAchievements hash");
+            ui.monospace(&info.ra_hash);
+            ui.end_row();
+            ui.label("Expansion Pak");
+            ui.label(info.expansion_pak.name());
+            ui.end_row();
+        });
+    });
+    panel.open = open;
+}
 
-        let emulator_core = Rc::new(RefCell::new(emulator_core));
-        egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
-            // The top panel is often a good place for a menu bar:
-            egui::menu::bar(ui, |ui| {
-                ui.menu_button("File", |ui| {
-                    if ui.button("Load ROM").clicked() {
-                        if let Some(path) = rfd::FileDialog::new().pick_file() {
-                            let picked_path = path.display().to_string();
-                            if let Ok(rom) = crate::rom::ROM::new_from_filename(&picked_path) {
-                                let mut emulator_core = emulator_core.borrow_mut();
-                                emulator_core.reload_hle();
-                                emulator_core.mut_mmu().set_rom(rom);
-                                emulator_core.mut_mmu().hle_ipl();
-                                println!("ROM loaded!");
+fn build_pif_window(ctx: &egui::CtxRef, snapshot: &Snapshot, open: &mut bool) {
+    egui::Window::new("PIF RAM").open(open).show(ctx, |ui| {
+        for (row, bytes) in snapshot.pif_ram.chunks(8).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            ui.monospace(format!("{:02X}  {}", row * 8, hex.join(" ")));
+        }
+        ui.separator();
+        let frames = joybus_frames(&snapshot.pif_ram);
+        if frames.is_empty() {
+            ui.label("No Joybus commands");
+        }
+        egui::Grid::new("joybus_frames").striped(true).show(ui, |ui| {
+            for frame in frames.iter() {
+                // Channels 0 to 3 are the controller ports, 4 is the cartridge
+                match frame.channel {
+                    channel if channel < CONTROLLER_PORTS => ui.label(format!("Controller {}", channel + 1)),
+                    _ => ui.label("Cartridge"),
+                };
+                let name = frame.command.first().map_or("Empty", |command| joybus_command_name(*command));
+                let command: Vec<String> = frame.command.iter().map(|byte| format!("{:02X}", byte)).collect();
+                let response: Vec<String> = frame.response.iter().map(|byte| format!("{:02X}", byte)).collect();
+                ui.label(name);
+                ui.monospace(command.join(" "));
+                ui.monospace(response.join(" "));
+                ui.label(describe_joybus_response(frame).unwrap_or_default());
+                ui.end_row();
+            }
+        });
+    });
+}
+
+// There is no host audio output yet, so this shows what the game sends to the AI
+fn build_audio_window(ctx: &egui::CtxRef, snapshot: &Snapshot, open: &mut bool) {
+    let audio = &snapshot.audio;
+    egui::Window::new("Audio").open(open).default_width(420.0).show(ctx, |ui| {
+        ui.label(format!("Sample rate: {} Hz", audio.sample_rate));
+        if audio.buffers.is_empty() {
+            ui.label("The game hasn't sent any audio buffer");
+        }
+        let [left, right] = levels(&audio.samples);
+        for (name, (peak, rms)) in [("L", left), ("R", right)] {
+            ui.horizontal(|ui| {
+                ui.monospace(name);
+                ui.add(egui::ProgressBar::new(peak).desired_width(300.0).text(format!("peak {:.2}  rms {:.2}", peak, rms)));
+            });
+        }
+        ui.separator();
+        let (response, painter) = ui.allocate_painter(egui::vec2(ui.available_width(), 120.0), egui::Sense::hover());
+        let rect = response.rect;
+        painter.rect_filled(rect, 0.0, egui::Color32::from_gray(16));
+        let count = audio.samples.len().max(2);
+        for (channel, color) in [(0, egui::Color32::LIGHT_GREEN), (1, egui::Color32::LIGHT_BLUE)] {
+            let points: Vec<egui::Pos2> = audio.samples.iter().enumerate().map(|(index, sample)| {
+                let x = rect.left() + rect.width() * index as f32 / (count - 1) as f32;
+                let y = rect.center().y - rect.height() / 2.0 * sample[channel] as f32 / 32768.0;
+                egui::pos2(x, y)
+            }).collect();
+            painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+        }
+        ui.separator();
+        ui.label("Last buffers");
+        egui::ScrollArea::vertical().max_height(160.0).show(ui, |ui| {
+            egui::Grid::new("audio_buffers").striped(true).show(ui, |ui| {
+                for buffer in audio.buffers.iter().rev() {
+                    ui.monospace(format!("{:08X}", buffer.address));
+                    ui.monospace(format!("{} bytes", buffer.length));
+                    if audio.sample_rate > 0 {
+                        ui.monospace(format!("{:.1} ms", (buffer.length / 4) as f64 * 1000.0 / audio.sample_rate as f64));
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    });
+}
+
+// Values can be poked while paused, the writes go through the MMU like CPU stores
+fn build_hardware_registers_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, panel: &mut HardwareRegistersPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Hardware registers").open(&mut open).vscroll(true).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for (index, interface) in INTERFACES.iter().enumerate() {
+                ui.selectable_value(&mut panel.interface, index, interface.name);
+            }
+        });
+        if snapshot.running {
+            ui.label("Pause the emulation to edit the registers");
+        }
+        ui.separator();
+        let interface = &INTERFACES[panel.interface];
+        egui::Grid::new("hardware_registers").striped(true).show(ui, |ui| {
+            for (register, value) in interface.registers.iter().zip(snapshot.hardware_registers[panel.interface].iter()) {
+                ui.label(register.name);
+                if panel.editing == Some(register.address) && !snapshot.running {
+                    let response = ui.text_edit_singleline(&mut panel.input);
+                    if response.lost_focus() {
+                        if ui.input().key_pressed(egui::Key::Enter) {
+                            if let Some(value) = parse_address(&panel.input) {
+                                let data = (value as u32).to_be_bytes().to_vec();
+                                emulator.send(Command::WriteMemory { address: register.address, virtual_address: false, data });
+                                emulator.send(Command::RequestSnapshot);
                             }
                         }
+                        panel.editing = None;
+                    } else {
+                        response.request_focus();
                     }
-                    if ui.button("Quit").clicked() {
-                        frame.quit();
+                } else {
+                    let text = format!("{:08X}", value);
+                    if ui.add_enabled(!snapshot.running, egui::Button::new(egui::RichText::new(&text).monospace()).frame(false)).clicked() {
+                        panel.editing = Some(register.address);
+                        panel.input = text;
                     }
-                });
-            });
+                }
+                let fields: Vec<String> = register.fields.iter()
+                    .map(|field| format!("{}={:X}", field.name, field.extract(*value)))
+                    .collect();
+                ui.monospace(fields.join(" "));
+                ui.end_row();
+            }
         });
+    });
+    panel.open = open;
+}
 
-        build_registers_window(ctx, selected_register, emulator_core.clone());
-        build_emulator_controls_window(ctx, emulator_core.clone());
-    }
+// Most recent exceptions first
+fn build_exceptions_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, open: &mut bool) {
+    egui::Window::new("Exceptions").open(open).default_size([480.0, 300.0]).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.label(format!("{} exceptions", snapshot.exceptions.len()));
+            if ui.button("Clear").clicked() {
+                emulator.send(Command::ClearExceptionLog);
+                emulator.send(Command::RequestSnapshot);
+            }
+        });
+        ui.separator();
+        egui::ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+            egui::Grid::new("exceptions").striped(true).show(ui, |ui| {
+                for header in ["Cycle", "Type", "PC", "Cause", "Vector"] {
+                    ui.label(header);
+                }
+                ui.end_row();
+                for record in snapshot.exceptions.iter().rev() {
+                    let exception = &record.exception;
+                    ui.monospace(format!("{}", record.cycle));
+                    ui.monospace(format!("{} ({})", exception_code_name(exception.code), exception.code));
+                    ui.monospace(format!("{:08X}", exception.program_counter & 0xFFFFFFFF));
+                    ui.monospace(format!("{:08X}", exception.cause));
+                    ui.monospace(format!("{:08X}", exception.vector));
+                    ui.end_row();
+                }
+            });
+        });
+    });
 }
 
-fn build_registers_window(ctx: &egui::CtxRef, selected_register: &mut Register, emulator_core: Rc<RefCell<&mut Emulator>>) {
-    egui::Window::new("Registers").vscroll(true).show(ctx, |ui| {
+// Most recent transfers first, addresses are physical
+fn build_dma_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, snapshot: &Snapshot, panel: &mut DmaPanel) {
+    let mut open = panel.open;
+    egui::Window::new("DMA log").open(&mut open).default_size([560.0, 320.0]).show(ctx, |ui| {
         ui.horizontal(|ui| {
-            ui.selectable_value(selected_register, Register::CPU, "CPU");
-            ui.selectable_value(selected_register, Register::CP0, "CP0");
+            for (kind, shown) in DmaKind::ALL.iter().zip(panel.kinds.iter_mut()) {
+                ui.checkbox(shown, kind.name());
+            }
+            ui.label("Address");
+            ui.text_edit_singleline(&mut panel.address);
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{} transfers", snapshot.dma_transfers.len()));
+            if ui.button("Clear").clicked() {
+                emulator.send(Command::ClearDmaLog);
+                emulator.send(Command::RequestSnapshot);
+            }
         });
         ui.separator();
-        match selected_register {
-            Register::CPU => build_cpu_registers(ui, emulator_core),
-            Register::CP0 => {ui.label("CP0 registers");},
+        egui::ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
+            egui::Grid::new("dma_transfers").striped(true).show(ui, |ui| {
+                for header in ["Cycle", "Type", "Source", "Destination", "Length", "PC"] {
+                    ui.label(header);
+                }
+                ui.end_row();
+                for record in snapshot.dma_transfers.iter().rev().filter(|record| panel.show(&record.transfer)) {
+                    let transfer = &record.transfer;
+                    ui.monospace(format!("{}", record.cycle));
+                    ui.monospace(transfer.kind.name());
+                    ui.monospace(format!("{:08X}", transfer.source));
+                    ui.monospace(format!("{:08X}", transfer.destination));
+                    ui.monospace(format!("{:X}", transfer.length));
+                    ui.monospace(format!("{:08X}", record.program_counter & 0xFFFFFFFF));
+                    ui.end_row();
+                }
+            });
+        });
+    });
+    panel.open = open;
+}
+
+// The running thread shows the CPU program counter, the others where they'll continue
+fn build_threads_window(ctx: &egui::CtxRef, snapshot: &Snapshot, symbols: &SymbolTable, panel: &mut ThreadPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Threads").open(&mut open).vscroll(true).show(ctx, |ui| {
+        let list = match &panel.list {
+            Some(list) => list,
+            None => {
+                ui.label("No libultra thread list found");
+                return;
+            },
         };
+        ui.monospace(format!("__osActiveQueue {:08X}", list.active_queue));
+        ui.separator();
+        egui::Grid::new("threads").striped(true).show(ui, |ui| {
+            for header in ["ID", "Priority", "State", "PC", "SP", "Address"] {
+                ui.label(header);
+            }
+            ui.end_row();
+            for thread in list.threads.iter() {
+                let pc = match thread.state == OS_STATE_RUNNING {
+                    true => snapshot.program_counter as u32,
+                    false => thread.pc,
+                };
+                ui.monospace(format!("{}", thread.id));
+                ui.monospace(format!("{}", thread.priority));
+                ui.monospace(thread.state_name());
+                match symbols.describe(pc as i64) {
+                    Some(name) => ui.monospace(format!("{:08X} ({})", pc, name)),
+                    None => ui.monospace(format!("{:08X}", pc)),
+                };
+                ui.monospace(format!("{:08X}", thread.sp));
+                ui.monospace(format!("{:08X}", thread.address));
+                ui.end_row();
+            }
+        });
     });
+    panel.open = open;
 }
 
-fn build_cpu_registers(ui: &mut egui::Ui, emulator_core: Rc<RefCell<&mut Emulator>>) {
-    let emulator_core = emulator_core.borrow();
-    ui.columns(3, |cols| {
-        cols[0].label("#");
-        cols[1].label("Name");
-        cols[2].label("Value");
+// Queues with threads waiting for a message are highlighted, they're where a stuck game usually is
+fn build_message_queues_window(ctx: &egui::CtxRef, symbols: &SymbolTable, panel: &mut MessageQueuePanel) {
+    let mut open = panel.open;
+    egui::Window::new("Message queues").open(&mut open).vscroll(true).show(ctx, |ui| {
+        if ui.button("Refresh").clicked() {
+            panel.frame = None;
+        }
+        ui.separator();
+        if panel.queues.is_empty() {
+            ui.label("No libultra message queue found");
+            return;
+        }
+        let ids = |ids: &[i32]| ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ");
+        egui::Grid::new("message_queues").striped(true).show(ui, |ui| {
+            for header in ["Address", "Messages", "First", "Receiving", "Sending"] {
+                ui.label(header);
+            }
+            ui.end_row();
+            for queue in panel.queues.iter() {
+                let address = match symbols.describe(queue.address as i64) {
+                    Some(name) => format!("{:08X} ({})", queue.address, name),
+                    None => format!("{:08X}", queue.address),
+                };
+                let color = match queue.receivers.is_empty() {
+                    true => ui.visuals().text_color(),
+                    false => egui::Color32::YELLOW,
+                };
+                ui.label(egui::RichText::new(address).monospace().color(color));
+                ui.monospace(format!("{}/{}", queue.valid_count, queue.msg_count));
+                ui.monospace(queue.first_message.map(|message| format!("{:08X}", message)).unwrap_or_default());
+                ui.monospace(ids(&queue.receivers));
+                ui.monospace(ids(&queue.senders));
+                ui.end_row();
+            }
+        });
     });
-    ui.separator();
-    ui.columns(3, |cols| {
-        cols[0].label("-");
-        cols[1].label("PC");
-        cols[2].label(format!("{:64X}", emulator_core.cpu().registers().get_program_counter()));
+    panel.open = open;
+}
+
+// Events that are already due show a negative delta
+fn build_scheduler_window(ctx: &egui::CtxRef, snapshot: &Snapshot, open: &mut bool) {
+    egui::Window::new("Scheduler").open(open).show(ctx, |ui| {
+        ui.monospace(format!("Cycle {}", snapshot.cycles));
+        ui.separator();
+        egui::Grid::new("scheduler_events").striped(true).show(ui, |ui| {
+            for header in ["Event", "Target cycle", "Delta", "Time"] {
+                ui.label(header);
+            }
+            ui.end_row();
+            for event in snapshot.scheduler_events.iter() {
+                let delta = event.cycle as i64 - snapshot.cycles as i64;
+                let color = match delta < 0 {
+                    true => egui::Color32::RED,
+                    false => ui.visuals().text_color(),
+                };
+                ui.monospace(event.kind.name());
+                ui.monospace(format!("{}", event.cycle));
+                ui.label(egui::RichText::new(format!("{:+}", delta)).monospace().color(color));
+                ui.monospace(format!("{:.3} ms", delta as f64 * 1000.0 / CPU_CLOCK_RATE as f64));
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn build_source_window(ctx: &egui::CtxRef, snapshot: &Snapshot, debug_info: &Option<DebugInfo>, open: &mut bool) {
+    egui::Window::new("Source").open(open).vscroll(true).show(ctx, |ui| {
+        let debug_info = match debug_info {
+            Some(debug_info) => debug_info,
+            None => {
+                ui.label("No debug info, load the ELF the ROM was built from");
+                return;
+            },
+        };
+        let pc = snapshot.program_counter;
+        match debug_info.source_line(pc) {
+            Some((file, line)) => ui.monospace(format!("{}:{}", file, line)),
+            None => ui.label("No line information for the PC"),
+        };
+        let function = match debug_info.function(pc) {
+            Some(function) => function,
+            None => return,
+        };
+        ui.monospace(format!("{} ({:08X}-{:08X})", function.name, function.low_pc, function.high_pc));
+        ui.separator();
+        // Register numbers are the DWARF ones, which for MIPS are the GPRs: 29 is sp and 30 is fp
+        egui::Grid::new("source_variables").striped(true).show(ui, |ui| {
+            for header in ["Variable", "Location"] {
+                ui.label(header);
+            }
+            ui.end_row();
+            for variable in &function.variables {
+                let name = match variable.parameter {
+                    true => format!("{} (parameter)", variable.name),
+                    false => variable.name.clone(),
+                };
+                ui.monospace(name);
+                ui.monospace(match variable.location {
+                    VariableLocation::FrameOffset(offset) => format!("frame{:+}", offset),
+                    VariableLocation::Register(register) => format!("${}", register),
+                    VariableLocation::RegisterOffset(register, offset) => format!("${}{:+}", register, offset),
+                    VariableLocation::Unknown => "?".to_string(),
+                });
+                ui.end_row();
+            }
+        });
     });
-    ui.columns(3, |cols| {
-        cols[0].label("-");
-        cols[1].label("hi");
-        cols[2].label(format!("{}", emulator_core.cpu().registers().get_hi()));
+}
+
+fn build_log_window(ctx: &egui::CtxRef, console: &mut LogConsole, error: &mut Option<String>) {
+    console.fetch();
+    let mut open = console.open;
+    egui::Window::new("Log").open(&mut open).default_size([600.0, 300.0]).show(ctx, |ui| {
+        ui.horizontal_wrapped(|ui| {
+            for (subsystem, shown) in Subsystem::ALL.iter().zip(console.subsystems.iter_mut()) {
+                ui.checkbox(shown, subsystem.name());
+            }
+        });
+        ui.horizontal(|ui| {
+            for level in Level::ALL {
+                ui.selectable_value(&mut console.level, level, level.name());
+            }
+            ui.separator();
+            if ui.button("Copy").clicked() {
+                ctx.output().copied_text = console.filtered_text();
+            }
+            if ui.button("Export").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Log", &["log", "txt"]).save_file() {
+                    if let Err(err) = std::fs::write(&path, console.filtered_text()) {
+                        *error = Some(format!("Could not write {}: {}", path.display(), err));
+                    }
+                }
+            }
+            if ui.button("Clear").clicked() {
+                console.entries.clear();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Record");
+            for level in Level::ALL {
+                if ui.selectable_value(&mut console.record_level, level, level.name()).changed() {
+                    for subsystem in Subsystem::ALL {
+                        log::set_max_level(subsystem, level);
+                    }
+                }
+            }
+        });
+        ui.separator();
+        egui::ScrollArea::vertical().auto_shrink([false; 2]).stick_to_bottom().show(ui, |ui| {
+            for entry in console.entries.iter().filter(|entry| console.show(entry)) {
+                let color = match entry.level {
+                    Level::Error => egui::Color32::RED,
+                    Level::Warn => egui::Color32::YELLOW,
+                    _ => ui.visuals().text_color(),
+                };
+                ui.label(egui::RichText::new(entry.to_string()).monospace().color(color));
+            }
+        });
     });
-    ui.columns(3, |cols| {
-        cols[0].label("-");
-        cols[1].label("lo");
-        cols[2].label(format!("{}", emulator_core.cpu().registers().get_lo()));
+    console.open = open;
+}
+
+fn build_input_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, config: &mut InputConfig, panel: &mut InputPanel, gamepads: &[String]) {
+    let mut open = panel.open;
+    egui::Window::new("Input").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for port in 0..CONTROLLER_PORTS {
+                if ui.selectable_value(&mut panel.port, port, format!("Port {}", port + 1)).changed() {
+                    panel.capturing = None;
+                }
+            }
+        });
+        match gamepads.get(panel.port) {
+            Some(name) => ui.label(format!("Gamepad: {}", name)),
+            None => ui.label("Gamepad: none connected"),
+        };
+        // The real controller answers the game directly, the bindings below are ignored meanwhile
+        ui.horizontal(|ui| {
+            match &panel.passthrough[panel.port] {
+                Some(name) => {
+                    ui.label(format!("Raw adapter: {}", name));
+                    if ui.button("Disconnect").clicked() {
+                        emulator.send(Command::SetPassthrough(panel.port, None));
+                        panel.passthrough[panel.port] = None;
+                    }
+                },
+                None => {
+                    ui.menu_button("Raw adapter", |ui| {
+                        if ui.button("Refresh").clicked() {
+                            panel.adapters = find_adapters();
+                        }
+                        ui.add(egui::DragValue::new(&mut panel.adapter_channel).clamp_range(0..=3).prefix("Adapter port "));
+                        for path in panel.adapters.clone() {
+                            if ui.button(path.display().to_string()).clicked() {
+                                match RawAdapter::open(&path, panel.adapter_channel) {
+                                    Ok(adapter) => {
+                                        emulator.send(Command::SetPassthrough(panel.port, Some(Box::new(adapter))));
+                                        panel.passthrough[panel.port] = Some(format!("{} port {}", path.display(), panel.adapter_channel + 1));
+                                    },
+                                    Err(err) => log!(Level::Warn, Subsystem::Frontend, "Could not open {}: {}", path.display(), err),
+                                };
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                },
+            };
+        });
+        ui.label("Click a binding to change it, right click to clear it. Escape cancels.");
+        ui.separator();
+        egui::Grid::new("input_bindings").striped(true).show(ui, |ui| {
+            ui.label("Input");
+            ui.label("Keyboard");
+            ui.label("Gamepad");
+            ui.label("Turbo");
+            ui.end_row();
+            for input in Input::ALL {
+                ui.label(input.name());
+                for key in [true, false] {
+                    let port = config.mut_port(panel.port);
+                    let binding = match key {
+                        true => port.key(input),
+                        false => port.gamepad(input),
+                    };
+                    let text = match (panel.capturing == Some((input, key)), binding) {
+                        (true, _) if key => "Press a key...".to_string(),
+                        (true, _) => "Press a button...".to_string(),
+                        (false, Some(binding)) => binding.to_string(),
+                        (false, None) => "-".to_string(),
+                    };
+                    let response = ui.button(text);
+                    if response.clicked() {
+                        panel.capturing = Some((input, key));
+                    }
+                    if response.secondary_clicked() {
+                        port.unbind(input, key);
+                    }
+                }
+                let port = config.mut_port(panel.port);
+                let mut turbo = port.turbo(input);
+                if input.button().is_some() && ui.checkbox(&mut turbo, "").changed() {
+                    port.set_turbo(input, turbo);
+                }
+                ui.end_row();
+            }
+        });
+        ui.horizontal(|ui| {
+            let port = config.mut_port(panel.port);
+            let mut period = port.turbo_period();
+            ui.label("Turbo period");
+            if ui.add(egui::DragValue::new(&mut period).clamp_range(TURBO_PERIODS).suffix(" frames")).changed() {
+                port.set_turbo_period(period);
+            }
+        });
+        ui.separator();
+        if ui.button("Reset port to defaults").clicked() {
+            *config.mut_port(panel.port) = InputConfig::with_defaults().port(panel.port).clone();
+            panel.capturing = None;
+        }
     });
-    for (index, name) in crate::registers::CPU_REGISTER_NAMES.into_iter().enumerate() {
-        let val = emulator_core.cpu().registers().get_by_name(name);
-        ui.columns(3, |cols| {
-            cols[0].label(format!("r{}", index));
-            cols[1].label(format!("{}", name));
-            cols[2].label(format!("{}", val));
+    if !open {
+        panel.capturing = None;
+    }
+    panel.open = open;
+}
+
+// Menu entries show the key combination bound to them
+// Puts the last frame on the system clipboard as an image, for pasting it straight into a bug report
+fn copy_frame(frame: &Option<Frame>, error: &mut Option<String>) {
+    let frame = match frame {
+        Some(frame) => frame,
+        None => {
+            *error = Some("Could not copy the frame: the video output is blank".to_string());
+            return;
+        },
+    };
+    let result = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_image(arboard::ImageData {
+        width: frame.width,
+        height: frame.height,
+        bytes: std::borrow::Cow::Borrowed(&frame.pixels),
+    }));
+    match result {
+        Ok(_) => osd!("Frame copied to the clipboard"),
+        Err(err) => *error = Some(format!("Could not copy the frame: {}", err)),
+    };
+}
+
+fn hotkey_label(name: &str, hotkeys: &HotkeyConfig, hotkey: Hotkey) -> String {
+    match hotkeys.get(hotkey) {
+        Some(combination) => format!("{} ({})", name, combination),
+        None => name.to_string(),
+    }
+}
+
+fn build_hotkeys_window(ctx: &egui::CtxRef, hotkeys: &mut HotkeyConfig, panel: &mut HotkeyPanel) {
+    let mut open = panel.open;
+    egui::Window::new("Hotkeys").open(&mut open).show(ctx, |ui| {
+        ui.label("Click a hotkey to change it, right click to clear it. Escape cancels.");
+        ui.label("Ctrl and a number always selects the state slot.");
+        ui.separator();
+        egui::Grid::new("hotkey_bindings").striped(true).show(ui, |ui| {
+            for hotkey in Hotkey::ALL {
+                ui.label(hotkey.name());
+                let text = match (panel.capturing == Some(hotkey), hotkeys.get(hotkey)) {
+                    (true, _) => "Press a key...".to_string(),
+                    (false, Some(combination)) => combination.to_string(),
+                    (false, None) => "-".to_string(),
+                };
+                let response = ui.button(text);
+                if response.clicked() {
+                    panel.capturing = Some(hotkey);
+                }
+                if response.secondary_clicked() {
+                    hotkeys.unbind(hotkey);
+                }
+                ui.end_row();
+            }
         });
+        ui.separator();
+        if ui.button("Reset to defaults").clicked() {
+            *hotkeys = HotkeyConfig::with_defaults();
+            panel.capturing = None;
+        }
+    });
+    if !open {
+        panel.capturing = None;
     }
+    panel.open = open;
 }
 
-fn build_emulator_controls_window(ctx: &egui::CtxRef, emulator_core: Rc<RefCell<&mut Emulator>>) {
-    egui::Window::new("Controls").vscroll(true).show(ctx, |ui| {
-        if ui.button("Tick").clicked() {
-            emulator_core.borrow_mut().tick();
+fn build_cheats_window(ctx: &egui::CtxRef, emulator: &EmulatorThread, panel: &mut CheatPanel, error: &mut Option<String>) {
+    let mut open = panel.open;
+    egui::Window::new("Cheats").open(&mut open).default_size([400.0, 400.0]).show(ctx, |ui| {
+        let game = match panel.game.clone() {
+            Some(game) => game,
+            None => {
+                ui.label("Load a ROM to manage its cheats");
+                return;
+            },
+        };
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label(format!("Game: {}", game));
+            if ui.button("Import").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Cheat list", &["txt", "cht"]).pick_file() {
+                    match std::fs::read_to_string(&path) {
+                        Ok(text) => {
+                            panel.database.mut_cheats(&game).extend(parse_cheats(&text));
+                            changed = true;
+                        },
+                        Err(err) => *error = Some(format!("Could not read {}: {}", path.display(), err)),
+                    };
+                }
+            }
+            if ui.button("Export").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Cheat list", &["txt"]).save_file() {
+                    if let Err(err) = std::fs::write(&path, cheats_to_text(panel.cheats())) {
+                        *error = Some(format!("Could not write {}: {}", path.display(), err));
+                    }
+                }
+            }
+        });
+        ui.separator();
+        let mut selected = None;
+        egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+            for (index, cheat) in panel.database.mut_cheats(&game).iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    changed |= ui.checkbox(&mut cheat.enabled, "").changed();
+                    if ui.selectable_label(panel.selected == Some(index), &cheat.name).clicked() {
+                        selected = Some(index);
+                    }
+                    if let Some(code) = cheat.codes.iter().find(|code| !code.is_supported()) {
+                        ui.colored_label(egui::Color32::YELLOW, "Unsupported")
+                            .on_hover_text(format!("Code type {:02X} is ignored: {}", code.code_type(), code));
+                    }
+                });
+            }
+        });
+        if selected.is_some() {
+            panel.select(selected);
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut panel.name_input);
+        });
+        ui.label("Codes");
+        ui.add(egui::TextEdit::multiline(&mut panel.codes_input).code_editor().desired_rows(6));
+        let parsed = panel.parse_input();
+        ui.horizontal(|ui| {
+            if ui.add_enabled(parsed.is_ok(), egui::Button::new("Add")).clicked() {
+                let cheats = panel.database.mut_cheats(&game);
+                cheats.push(parsed.clone().unwrap());
+                panel.selected = Some(cheats.len() - 1);
+                changed = true;
+            }
+            if ui.add_enabled(parsed.is_ok() && panel.selected.is_some(), egui::Button::new("Update")).clicked() {
+                if let Some(cheat) = panel.selected.and_then(|index| panel.database.mut_cheats(&game).get_mut(index)) {
+                    *cheat = Cheat { enabled: cheat.enabled, ..parsed.clone().unwrap() };
+                    changed = true;
+                }
+            }
+            if ui.add_enabled(panel.selected.is_some(), egui::Button::new("Delete")).clicked() {
+                if let Some(index) = panel.selected {
+                    panel.database.mut_cheats(&game).remove(index);
+                    panel.select(None);
+                    changed = true;
+                }
+            }
+        });
+        if let Err(message) = &parsed {
+            if !panel.name_input.is_empty() || !panel.codes_input.is_empty() {
+                ui.colored_label(egui::Color32::RED, message);
+            }
+        }
+        if changed {
+            panel.send(emulator);
+        }
+    });
+    panel.open = open;
+}
+
+/*
+    Each port has a file with the saves of the game, other files can be opened by hand. A pak
+    plugged in through the Controllers menu only reads its file again when it's plugged back in.
+*/
+fn build_controller_pak_window(ctx: &egui::CtxRef, panel: &mut ControllerPakPanel, paths: &[Option<PathBuf>; CONTROLLER_PORTS]) {
+    let mut open = panel.open;
+    egui::Window::new("Controller Paks").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for (port, path) in paths.iter().enumerate() {
+                if ui.selectable_label(panel.port == Some(port), format!("Port {}", port + 1)).clicked() {
+                    panel.port = Some(port);
+                    panel.open_file(path.clone());
+                }
+            }
+            if ui.button("Open file").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Controller Pak", &["mpk", "mpk1", "mpk2", "mpk3", "mpk4"]).pick_file() {
+                    panel.port = None;
+                    panel.open_file(Some(path));
+                }
+            }
+        });
+        // Follow the ROM when another one is loaded
+        if let Some(port) = panel.port {
+            if panel.path != paths[port] {
+                panel.open_file(paths[port].clone());
+            }
+        }
+        match &panel.path {
+            Some(path) => ui.label(path.display().to_string()),
+            None => ui.label("Load a ROM to manage its Controller Paks"),
+        };
+        if let Some(message) = &panel.message {
+            ui.colored_label(egui::Color32::RED, message);
+        }
+        let pak = match &mut panel.pak {
+            Some(pak) => pak,
+            None => return,
+        };
+        ui.separator();
+        egui::Grid::new("controller_pak_notes").striped(true).show(ui, |ui| {
+            ui.label("Game");
+            ui.label("Publisher");
+            ui.label("Note");
+            ui.label("Pages");
+            ui.end_row();
+            for note in pak.notes() {
+                if ui.selectable_label(panel.selected == Some(note.index), &note.game_code).clicked() {
+                    panel.selected = Some(note.index);
+                }
+                ui.label(&note.publisher_code);
+                ui.label(&note.name);
+                ui.label(note.pages.to_string());
+                ui.end_row();
+            }
+        });
+        ui.label(format!("{} free pages", pak.free_pages()));
+        ui.separator();
+        let mut written = false;
+        ui.horizontal(|ui| {
+            if ui.add_enabled(panel.selected.is_some(), egui::Button::new("Delete")).clicked() {
+                if let Some(index) = panel.selected.take() {
+                    pak.delete(index);
+                    written = true;
+                }
+            }
+            if ui.add_enabled(panel.selected.is_some(), egui::Button::new("Export")).clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Note", &[NOTE_EXTENSION]).save_file() {
+                    if let Err(err) = std::fs::write(&path, pak.export(panel.selected.unwrap())) {
+                        panel.message = Some(format!("Could not write {}: {}", path.display(), err));
+                    }
+                }
+            }
+            if ui.button("Import").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("Note", &[NOTE_EXTENSION]).pick_file() {
+                    match std::fs::read(&path).and_then(|note| pak.import(&note)) {
+                        Ok(_) => written = true,
+                        Err(err) => panel.message = Some(format!("Could not import {}: {}", path.display(), err)),
+                    };
+                }
+            }
+        });
+        if written {
+            panel.write_file();
         }
     });
-}
\ No newline at end of file
+    panel.open = open;
+}
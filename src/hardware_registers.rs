@@ -0,0 +1,165 @@
+use crate::mmu::MMU;
+
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl Field {
+    pub fn extract(&self, value: u32) -> u32 {
+        (value >> self.shift) & (((1_u64 << self.width) - 1) as u32)
+    }
+}
+
+const fn field(name: &'static str, shift: u32, width: u32) -> Field {
+    Field {
+        name,
+        shift,
+        width,
+    }
+}
+
+pub struct HardwareRegister {
+    pub name: &'static str,
+    pub address: i64,
+    pub fields: &'static [Field],
+}
+
+const fn register(name: &'static str, address: i64, fields: &'static [Field]) -> HardwareRegister {
+    HardwareRegister {
+        name,
+        address,
+        fields,
+    }
+}
+
+pub struct Interface {
+    pub name: &'static str,
+    pub registers: &'static [HardwareRegister],
+}
+
+const START_END: [Field; 2] = [field("End", 0, 10), field("Start", 16, 10)];
+const SCALE: [Field; 2] = [field("Scale", 0, 12), field("Offset", 16, 12)];
+const INTERRUPTS: [Field; 6] = [field("SP", 0, 1), field("SI", 1, 1), field("AI", 2, 1), field("VI", 3, 1), field("PI", 4, 1), field("DP", 5, 1)];
+
+// https://n64brew.dev/wiki/MIPS_Interface
+const MI_REGISTERS: [HardwareRegister; 4] = [
+    register("MI_MODE", 0x04300000, &[field("Init length", 0, 7), field("Init mode", 7, 1), field("Ebus test", 8, 1), field("Upper", 9, 1)]),
+    register("MI_VERSION", 0x04300004, &[field("IO", 0, 8), field("RAC", 8, 8), field("RDP", 16, 8), field("RSP", 24, 8)]),
+    register("MI_INTERRUPT", 0x04300008, &INTERRUPTS),
+    register("MI_MASK", 0x0430000C, &INTERRUPTS),
+];
+
+// https://n64brew.dev/wiki/Video_Interface
+const VI_REGISTERS: [HardwareRegister; 14] = [
+    register("VI_CTRL", 0x04400000, &[
+        field("Pixel size", 0, 2), field("Gamma dither", 2, 1), field("Gamma", 3, 1), field("Divot", 4, 1),
+        field("Serrate", 6, 1), field("AA mode", 8, 2), field("Pixel advance", 12, 4), field("Dedither", 16, 1),
+    ]),
+    register("VI_ORIGIN", 0x04400004, &[field("Origin", 0, 24)]),
+    register("VI_WIDTH", 0x04400008, &[field("Width", 0, 12)]),
+    register("VI_V_INTR", 0x0440000C, &[field("Half line", 0, 10)]),
+    register("VI_V_CURRENT", 0x04400010, &[field("Half line", 0, 10)]),
+    register("VI_BURST", 0x04400014, &[field("HSync width", 0, 8), field("Burst width", 8, 8), field("VSync width", 16, 4), field("Burst start", 20, 10)]),
+    register("VI_V_SYNC", 0x04400018, &[field("Half lines", 0, 10)]),
+    register("VI_H_SYNC", 0x0440001C, &[field("Line duration", 0, 12), field("Leap", 16, 5)]),
+    register("VI_H_SYNC_LEAP", 0x04400020, &[field("Leap B", 0, 12), field("Leap A", 16, 12)]),
+    register("VI_H_VIDEO", 0x04400024, &START_END),
+    register("VI_V_VIDEO", 0x04400028, &START_END),
+    register("VI_V_BURST", 0x0440002C, &START_END),
+    register("VI_X_SCALE", 0x04400030, &SCALE),
+    register("VI_Y_SCALE", 0x04400034, &SCALE),
+];
+
+// https://n64brew.dev/wiki/Audio_Interface
+const AI_REGISTERS: [HardwareRegister; 6] = [
+    register("AI_DRAM_ADDR", 0x04500000, &[field("Address", 0, 24)]),
+    register("AI_LENGTH", 0x04500004, &[field("Length", 0, 18)]),
+    register("AI_CONTROL", 0x04500008, &[field("DMA enable", 0, 1)]),
+    register("AI_STATUS", 0x0450000C, &[field("Full", 0, 1), field("Enabled", 25, 1), field("Busy", 30, 1), field("Full", 31, 1)]),
+    register("AI_DACRATE", 0x04500010, &[field("Rate", 0, 14)]),
+    register("AI_BITRATE", 0x04500014, &[field("Rate", 0, 4)]),
+];
+
+// https://n64brew.dev/wiki/Peripheral_Interface
+const PI_REGISTERS: [HardwareRegister; 13] = [
+    register("PI_DRAM_ADDR", 0x04600000, &[field("Address", 0, 24)]),
+    register("PI_CART_ADDR", 0x04600004, &[field("Address", 0, 32)]),
+    register("PI_RD_LEN", 0x04600008, &[field("Length", 0, 24)]),
+    register("PI_WR_LEN", 0x0460000C, &[field("Length", 0, 24)]),
+    register("PI_STATUS", 0x04600010, &[field("DMA busy", 0, 1), field("IO busy", 1, 1), field("DMA error", 2, 1), field("Interrupt", 3, 1)]),
+    register("PI_BSD_DOM1_LAT", 0x04600014, &[field("Latency", 0, 8)]),
+    register("PI_BSD_DOM1_PWD", 0x04600018, &[field("Pulse width", 0, 8)]),
+    register("PI_BSD_DOM1_PGS", 0x0460001C, &[field("Page size", 0, 4)]),
+    register("PI_BSD_DOM1_RLS", 0x04600020, &[field("Release", 0, 2)]),
+    register("PI_BSD_DOM2_LAT", 0x04600024, &[field("Latency", 0, 8)]),
+    register("PI_BSD_DOM2_PWD", 0x04600028, &[field("Pulse width", 0, 8)]),
+    register("PI_BSD_DOM2_PGS", 0x0460002C, &[field("Page size", 0, 4)]),
+    register("PI_BSD_DOM2_RLS", 0x04600030, &[field("Release", 0, 2)]),
+];
+
+// https://n64brew.dev/wiki/Serial_Interface
+const SI_REGISTERS: [HardwareRegister; 6] = [
+    register("SI_DRAM_ADDR", 0x04800000, &[field("Address", 0, 24)]),
+    register("SI_PIF_AD_RD64B", 0x04800004, &[field("Address", 0, 32)]),
+    register("SI_PIF_AD_WR4B", 0x04800008, &[field("Address", 0, 32)]),
+    register("SI_PIF_AD_WR64B", 0x04800010, &[field("Address", 0, 32)]),
+    register("SI_PIF_AD_RD4B", 0x04800014, &[field("Address", 0, 32)]),
+    register("SI_STATUS", 0x04800018, &[
+        field("DMA busy", 0, 1), field("IO busy", 1, 1), field("Read pending", 2, 1), field("DMA error", 3, 1),
+        field("PCH state", 4, 4), field("DMA state", 8, 4), field("Interrupt", 12, 1),
+    ]),
+];
+
+// https://n64brew.dev/wiki/RDRAM_Interface
+const RI_REGISTERS: [HardwareRegister; 7] = [
+    register("RI_MODE", 0x04700000, &[field("Operating mode", 0, 2), field("Stop T active", 2, 1), field("Stop R active", 3, 1)]),
+    register("RI_CONFIG", 0x04700004, &[field("Current control input", 0, 6), field("Current control enable", 6, 1)]),
+    register("RI_SELECT", 0x0470000C, &[field("TX select", 0, 4), field("RX select", 4, 4)]),
+    register("RI_REFRESH", 0x04700010, &[
+        field("Clean delay", 0, 8), field("Dirty delay", 8, 8), field("Bank", 16, 1), field("Enable", 17, 1),
+        field("Optimize", 18, 1), field("Multibank", 19, 4),
+    ]),
+    register("RI_LATENCY", 0x04700014, &[field("DMA latency", 0, 4)]),
+    register("RI_ERROR", 0x04700018, &[field("Nack", 0, 1), field("Ack", 1, 1), field("Invalid ack", 2, 1)]),
+    register("RI_BANK_STATUS", 0x0470001C, &[field("Valid", 0, 8), field("Dirty", 8, 8)]),
+];
+
+pub const INTERFACES: [Interface; 6] = [
+    Interface { name: "MI", registers: &MI_REGISTERS },
+    Interface { name: "VI", registers: &VI_REGISTERS },
+    Interface { name: "AI", registers: &AI_REGISTERS },
+    Interface { name: "PI", registers: &PI_REGISTERS },
+    Interface { name: "SI", registers: &SI_REGISTERS },
+    Interface { name: "RI", registers: &RI_REGISTERS },
+];
+
+// Values of every register in INTERFACES, read like the CPU would
+pub fn read_all(mmu: &MMU) -> Vec<Vec<u32>> {
+    INTERFACES.iter()
+        .map(|interface| interface.registers.iter()
+            .map(|register| u32::from_be_bytes(mmu.read_physical(register.address, 4).try_into().unwrap()))
+            .collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod hardware_registers_tests {
+    use super::*;
+
+    #[test]
+    fn test_read_all() {
+        let mut mmu = MMU::new();
+        // A CPU write to the cartridge keeps the PI IO busy
+        mmu.write_virtual(0xB0000000, &0x00000003_u32.to_be_bytes());
+        let values = read_all(&mmu);
+        assert_eq!(values.len(), INTERFACES.len());
+        // MI_VERSION
+        assert_eq!(values[0][1], 0x02020102);
+        let pi_status = &INTERFACES[3].registers[4];
+        assert_eq!(pi_status.name, "PI_STATUS");
+        assert_eq!(pi_status.fields[1].extract(values[3][4]), 1);
+        assert_eq!(pi_status.fields[2].extract(values[3][4]), 0);
+    }
+}
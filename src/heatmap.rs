@@ -0,0 +1,125 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::rdram::RDRAM_SIZE;
+
+// Counters are kept per 4KB page of RDRAM, coarse enough to cost next to nothing on every access
+pub const HEATMAP_PAGE_SIZE: usize = 0x1000;
+pub const HEATMAP_PAGES: usize = RDRAM_SIZE / HEATMAP_PAGE_SIZE;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    pub const ALL: [Access; 3] = [Access::Read, Access::Write, Access::Execute];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Read => "Read",
+            Self::Write => "Write",
+            Self::Execute => "Execute",
+        }
+    }
+}
+
+/*
+    Reads go through &MMU, so the counters are atomics. They are halved on every frame,
+    so the pages touched recently stand out from the ones touched once at boot.
+*/
+pub struct AccessHeatmap {
+    pages: Vec<[AtomicU32; 3]>,
+}
+
+impl AccessHeatmap {
+    pub fn new() -> Self {
+        Self {
+            pages: (0..HEATMAP_PAGES).map(|_| Default::default()).collect(),
+        }
+    }
+
+    // Physical addresses outside of RDRAM aren't tracked
+    pub fn record(&self, address: i64, access: Access) {
+        if let Some(page) = self.pages.get(address as usize / HEATMAP_PAGE_SIZE) {
+            let counter = &page[access as usize];
+            // Only the core counts, readers on other threads can live with a count that's one behind
+            counter.store(counter.load(Ordering::Relaxed).saturating_add(1), Ordering::Relaxed);
+        }
+    }
+
+    pub fn decay(&mut self) {
+        for page in self.pages.iter_mut() {
+            for counter in page.iter_mut() {
+                *counter.get_mut() >>= 1;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for page in self.pages.iter_mut() {
+            for counter in page.iter_mut() {
+                *counter.get_mut() = 0;
+            }
+        }
+    }
+
+    // Read, write and execute counts of every page
+    pub fn counts(&self) -> Vec<[u32; 3]> {
+        self.pages.iter().map(|page| page.each_ref().map(|counter| counter.load(Ordering::Relaxed))).collect()
+    }
+}
+
+/*
+    One RGBA pixel per page, reads are green, writes red and execution blue.
+    The brightness is logarithmic, a page read a few times is visible next to one read thousands of times.
+*/
+pub fn heatmap_rgba(counts: &[[u32; 3]], accesses: &[Access]) -> Vec<u8> {
+    let intensity = |count: u32| match count {
+        0 => 0,
+        count => (64.0 + (count as f32).log2() * 12.0).min(255.0) as u8,
+    };
+    let mut pixels = Vec::with_capacity(counts.len() * 4);
+    for page in counts {
+        let mut pixel = [0x10, 0x10, 0x10, 0xFF];
+        for access in accesses {
+            let channel = match access {
+                Access::Read => 1,
+                Access::Write => 0,
+                Access::Execute => 2,
+            };
+            pixel[channel] = pixel[channel].max(intensity(page[*access as usize]));
+        }
+        pixels.extend_from_slice(&pixel);
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod heatmap_tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_decay() {
+        let mut heatmap = AccessHeatmap::new();
+        heatmap.record(0x1234, Access::Read);
+        heatmap.record(0x1FFF, Access::Read);
+        heatmap.record(0x2000, Access::Write);
+        heatmap.record(0x2000, Access::Execute);
+        heatmap.record(0x10000000, Access::Read);
+        let counts = heatmap.counts();
+        assert_eq!(counts[1], [2, 0, 0]);
+        assert_eq!(counts[2], [0, 1, 1]);
+        assert_eq!(counts.iter().map(|page| page.iter().sum::<u32>()).sum::<u32>(), 4);
+
+        let pixels = heatmap_rgba(&counts, &[Access::Read]);
+        assert_eq!(&pixels[4..8], &[0x10, 76, 0x10, 0xFF]);
+        assert_eq!(&pixels[8..12], &[0x10, 0x10, 0x10, 0xFF]);
+
+        heatmap.decay();
+        assert_eq!(heatmap.counts()[1], [1, 0, 0]);
+        heatmap.decay();
+        assert_eq!(heatmap.counts()[1], [0, 0, 0]);
+    }
+}
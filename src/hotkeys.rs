@@ -0,0 +1,199 @@
+use std::fmt;
+
+// Key of the hotkeys in the GUI's persisted storage
+pub const HOTKEYS_KEY: &str = "hotkeys";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    NextSlot,
+    PreviousSlot,
+    Pause,
+    FrameAdvance,
+    FastForward,
+    Rewind,
+    Screenshot,
+    CopyFrame,
+    Fullscreen,
+    // Between a Controller Pak and a Rumble Pak in port 1, for the games that ask to swap them
+    SwapPak,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 12] = [
+        Hotkey::SaveState, Hotkey::LoadState, Hotkey::NextSlot, Hotkey::PreviousSlot,
+        Hotkey::Pause, Hotkey::FrameAdvance, Hotkey::FastForward, Hotkey::Rewind,
+        Hotkey::Screenshot, Hotkey::CopyFrame, Hotkey::Fullscreen, Hotkey::SwapPak,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hotkey::SaveState => "Save state",
+            Hotkey::LoadState => "Load state",
+            Hotkey::NextSlot => "Next slot",
+            Hotkey::PreviousSlot => "Previous slot",
+            Hotkey::Pause => "Pause",
+            Hotkey::FrameAdvance => "Frame advance",
+            Hotkey::FastForward => "Fast forward",
+            Hotkey::Rewind => "Rewind",
+            Hotkey::Screenshot => "Screenshot",
+            Hotkey::CopyFrame => "Copy frame",
+            Hotkey::Fullscreen => "Fullscreen",
+            Hotkey::SwapPak => "Swap pak",
+        }
+    }
+
+    fn index(&self) -> usize {
+        Hotkey::ALL.iter().position(|hotkey| hotkey == self).unwrap()
+    }
+}
+
+// A key and the modifiers held with it, the key is stored by the name the GUI library gives it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyCombination {
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+    pub key: String,
+}
+
+impl KeyCombination {
+    // "Ctrl+Shift+S", the key goes last
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let key = parts.pop().filter(|key| !key.is_empty())?;
+        let mut combination = Self {
+            ctrl: false,
+            shift: false,
+            alt: false,
+            key: key.to_string(),
+        };
+        for modifier in parts {
+            match modifier {
+                "Ctrl" => combination.ctrl = true,
+                "Shift" => combination.shift = true,
+                "Alt" => combination.alt = true,
+                _ => return None,
+            };
+        }
+        Some(combination)
+    }
+
+    // Combinations with Ctrl or Alt can't be typed, so they are safe to handle while a text field has focus
+    pub fn has_command_modifier(&self) -> bool {
+        self.ctrl || self.alt
+    }
+}
+
+impl fmt::Display for KeyCombination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (held, name) in [(self.ctrl, "Ctrl+"), (self.shift, "Shift+"), (self.alt, "Alt+")] {
+            if held {
+                write!(f, "{}", name)?;
+            }
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+pub struct HotkeyConfig {
+    bindings: [Option<KeyCombination>; 12],
+}
+
+impl HotkeyConfig {
+    pub fn new() -> Self {
+        Self {
+            bindings: Default::default(),
+        }
+    }
+
+    // Modifiers keep the defaults away from the keys bound to the controller
+    pub fn with_defaults() -> Self {
+        let mut config = Self::new();
+        let defaults = [
+            (Hotkey::SaveState, "Ctrl+S"), (Hotkey::LoadState, "Ctrl+L"),
+            (Hotkey::NextSlot, "Ctrl+PageUp"), (Hotkey::PreviousSlot, "Ctrl+PageDown"),
+            (Hotkey::Pause, "Ctrl+P"), (Hotkey::FrameAdvance, "Ctrl+N"),
+            (Hotkey::FastForward, "Ctrl+Space"), (Hotkey::Rewind, "Ctrl+Backspace"),
+            (Hotkey::Screenshot, "Ctrl+M"), (Hotkey::CopyFrame, "Ctrl+Shift+M"),
+            (Hotkey::Fullscreen, "Alt+Enter"), (Hotkey::SwapPak, "Ctrl+K"),
+        ];
+        for (hotkey, combination) in defaults {
+            config.bind(hotkey, KeyCombination::parse(combination).unwrap());
+        }
+        config
+    }
+
+    pub fn get(&self, hotkey: Hotkey) -> Option<&KeyCombination> {
+        self.bindings[hotkey.index()].as_ref()
+    }
+
+    // A combination triggers a single hotkey, binding it again takes it from the previous one
+    pub fn bind(&mut self, hotkey: Hotkey, combination: KeyCombination) {
+        for binding in self.bindings.iter_mut() {
+            if binding.as_ref() == Some(&combination) {
+                *binding = None;
+            }
+        }
+        self.bindings[hotkey.index()] = Some(combination);
+    }
+
+    pub fn unbind(&mut self, hotkey: Hotkey) {
+        self.bindings[hotkey.index()] = None;
+    }
+
+    pub fn find(&self, combination: &KeyCombination) -> Option<Hotkey> {
+        Hotkey::ALL.into_iter().find(|hotkey| self.get(*hotkey) == Some(combination))
+    }
+
+    // One "hotkey,combination" line per bound hotkey, the format the GUI stores
+    pub fn to_lines(&self) -> String {
+        let mut lines = String::new();
+        for hotkey in Hotkey::ALL {
+            if let Some(combination) = self.get(hotkey) {
+                lines.push_str(&format!("{},{}\n", hotkey.name(), combination));
+            }
+        }
+        lines
+    }
+
+    // Unknown lines are skipped so a config from a newer version still loads
+    pub fn from_lines(data: &str) -> Self {
+        let mut config = Self::new();
+        for line in data.lines() {
+            let mut fields = line.splitn(2, ',');
+            let hotkey = fields.next().and_then(|name| Hotkey::ALL.into_iter().find(|hotkey| hotkey.name() == name));
+            let combination = fields.next().and_then(KeyCombination::parse);
+            if let (Some(hotkey), Some(combination)) = (hotkey, combination) {
+                config.bind(hotkey, combination);
+            }
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod hotkeys_tests {
+    use super::*;
+
+    #[test]
+    fn test_hotkey_config() {
+        let combination = KeyCombination::parse("Ctrl+Shift+S").unwrap();
+        assert!(combination.ctrl && combination.shift && !combination.alt);
+        assert_eq!(combination.to_string(), "Ctrl+Shift+S");
+        assert!(!KeyCombination::parse("Space").unwrap().has_command_modifier());
+        assert!(KeyCombination::parse("Hyper+S").is_none());
+
+        let mut config = HotkeyConfig::with_defaults();
+        assert_eq!(config.find(&KeyCombination::parse("Alt+Enter").unwrap()), Some(Hotkey::Fullscreen));
+        config.bind(Hotkey::Pause, KeyCombination::parse("Ctrl+S").unwrap());
+        assert_eq!(config.get(Hotkey::SaveState), None);
+        config.unbind(Hotkey::Rewind);
+
+        let restored = HotkeyConfig::from_lines(&config.to_lines());
+        for hotkey in Hotkey::ALL {
+            assert_eq!(restored.get(hotkey), config.get(hotkey));
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::fmt;
+
+use crate::pif::*;
+
+// Key of the bindings in the GUI's persisted storage
+pub const INPUT_CONFIG_KEY: &str = "input_config";
+
+// Full tilt of the analog stick, real controllers report a bit less than this
+pub const STICK_RANGE: f32 = 80.0;
+
+// Frames per press and release of a turbo button, 4 is 15 presses per second on NTSC
+pub const DEFAULT_TURBO_PERIOD: u32 = 4;
+pub const TURBO_PERIODS: std::ops::RangeInclusive<u32> = 2..=60;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Input {
+    A,
+    B,
+    Z,
+    Start,
+    DUp,
+    DDown,
+    DLeft,
+    DRight,
+    L,
+    R,
+    CUp,
+    CDown,
+    CLeft,
+    CRight,
+    StickUp,
+    StickDown,
+    StickLeft,
+    StickRight,
+}
+
+impl Input {
+    pub const ALL: [Input; 18] = [
+        Input::A, Input::B, Input::Z, Input::Start,
+        Input::DUp, Input::DDown, Input::DLeft, Input::DRight,
+        Input::L, Input::R,
+        Input::CUp, Input::CDown, Input::CLeft, Input::CRight,
+        Input::StickUp, Input::StickDown, Input::StickLeft, Input::StickRight,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Input::A => "A",
+            Input::B => "B",
+            Input::Z => "Z",
+            Input::Start => "Start",
+            Input::DUp => "D-Up",
+            Input::DDown => "D-Down",
+            Input::DLeft => "D-Left",
+            Input::DRight => "D-Right",
+            Input::L => "L",
+            Input::R => "R",
+            Input::CUp => "C-Up",
+            Input::CDown => "C-Down",
+            Input::CLeft => "C-Left",
+            Input::CRight => "C-Right",
+            Input::StickUp => "Stick Up",
+            Input::StickDown => "Stick Down",
+            Input::StickLeft => "Stick Left",
+            Input::StickRight => "Stick Right",
+        }
+    }
+
+    fn index(&self) -> usize {
+        Input::ALL.iter().position(|input| input == self).unwrap()
+    }
+
+    // None for the stick directions
+    pub fn button(&self) -> Option<u16> {
+        match self {
+            Input::A => Some(BUTTON_A),
+            Input::B => Some(BUTTON_B),
+            Input::Z => Some(BUTTON_Z),
+            Input::Start => Some(BUTTON_START),
+            Input::DUp => Some(BUTTON_D_UP),
+            Input::DDown => Some(BUTTON_D_DOWN),
+            Input::DLeft => Some(BUTTON_D_LEFT),
+            Input::DRight => Some(BUTTON_D_RIGHT),
+            Input::L => Some(BUTTON_L),
+            Input::R => Some(BUTTON_R),
+            Input::CUp => Some(BUTTON_C_UP),
+            Input::CDown => Some(BUTTON_C_DOWN),
+            Input::CLeft => Some(BUTTON_C_LEFT),
+            Input::CRight => Some(BUTTON_C_RIGHT),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Keys, buttons and axes are stored by the name their libraries give them,
+    so the config doesn't depend on the GUI or gamepad crates.
+*/
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Binding {
+    Key(String),
+    GamepadButton(String),
+    // Axis name and whether it's the positive direction
+    GamepadAxis(String, bool),
+}
+
+impl Binding {
+    pub fn is_key(&self) -> bool {
+        matches!(self, Binding::Key(_))
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        let (kind, name) = text.split_once(':')?;
+        match kind {
+            "Key" => Some(Binding::Key(name.to_string())),
+            "Button" => Some(Binding::GamepadButton(name.to_string())),
+            "Axis" => match name.strip_suffix('+') {
+                Some(axis) => Some(Binding::GamepadAxis(axis.to_string(), true)),
+                None => Some(Binding::GamepadAxis(name.strip_suffix('-')?.to_string(), false)),
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Binding::Key(name) => write!(f, "Key:{}", name),
+            Binding::GamepadButton(name) => write!(f, "Button:{}", name),
+            Binding::GamepadAxis(name, positive) => write!(f, "Axis:{}{}", name, if *positive { '+' } else { '-' }),
+        }
+    }
+}
+
+// Every input can be bound to a key and to something on the gamepad at the same time
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PortConfig {
+    keys: [Option<Binding>; 18],
+    gamepad: [Option<Binding>; 18],
+    // Buttons pressed and released on their own while held, the stick directions are ignored
+    turbo: [bool; 18],
+    turbo_period: u32,
+}
+
+impl PortConfig {
+    pub fn new() -> Self {
+        Self {
+            keys: Default::default(),
+            gamepad: Default::default(),
+            turbo: [false; 18],
+            turbo_period: DEFAULT_TURBO_PERIOD,
+        }
+    }
+
+    pub fn turbo(&self, input: Input) -> bool {
+        self.turbo[input.index()]
+    }
+
+    pub fn set_turbo(&mut self, input: Input, enabled: bool) {
+        self.turbo[input.index()] = enabled && input.button().is_some();
+    }
+
+    pub fn turbo_period(&self) -> u32 {
+        self.turbo_period
+    }
+
+    pub fn set_turbo_period(&mut self, period: u32) {
+        self.turbo_period = period.clamp(*TURBO_PERIODS.start(), *TURBO_PERIODS.end());
+    }
+
+    // The PIF pulses these on every emulated frame, see PIF::set_turbo
+    pub fn turbo_buttons(&self) -> u16 {
+        Input::ALL.iter()
+            .filter(|input| self.turbo(**input))
+            .filter_map(|input| input.button())
+            .fold(0, |buttons, mask| buttons | mask)
+    }
+
+    pub fn key(&self, input: Input) -> Option<&Binding> {
+        self.keys[input.index()].as_ref()
+    }
+
+    pub fn gamepad(&self, input: Input) -> Option<&Binding> {
+        self.gamepad[input.index()].as_ref()
+    }
+
+    // Goes to the keyboard or the gamepad slot depending on the binding
+    pub fn bind(&mut self, input: Input, binding: Binding) {
+        match binding.is_key() {
+            true => self.keys[input.index()] = Some(binding),
+            false => self.gamepad[input.index()] = Some(binding),
+        };
+    }
+
+    pub fn unbind(&mut self, input: Input, key: bool) {
+        match key {
+            true => self.keys[input.index()] = None,
+            false => self.gamepad[input.index()] = None,
+        };
+    }
+
+    /*
+        Builds the state the PIF reports from how far each binding is pressed, 0.0 to 1.0.
+        Buttons count as pressed past the halfway point.
+    */
+    pub fn controller_state(&self, value: impl Fn(&Binding) -> f32) -> ControllerState {
+        let strength = |input: Input| {
+            self.keys[input.index()].iter()
+                .chain(self.gamepad[input.index()].iter())
+                .map(&value)
+                .fold(0.0_f32, f32::max)
+                .clamp(0.0, 1.0)
+        };
+        let mut state = ControllerState::default();
+        for input in Input::ALL {
+            if let Some(mask) = input.button() {
+                if strength(input) > 0.5 {
+                    state.buttons |= mask;
+                }
+            }
+        }
+        state.x = ((strength(Input::StickRight) - strength(Input::StickLeft)) * STICK_RANGE) as i8;
+        state.y = ((strength(Input::StickUp) - strength(Input::StickDown)) * STICK_RANGE) as i8;
+        state
+    }
+}
+
+pub struct InputConfig {
+    ports: [PortConfig; CONTROLLER_PORTS],
+}
+
+impl InputConfig {
+    pub fn new() -> Self {
+        Self {
+            ports: [PortConfig::new(), PortConfig::new(), PortConfig::new(), PortConfig::new()],
+        }
+    }
+
+    // Keyboard on the first port and the Nth gamepad on each port
+    pub fn with_defaults() -> Self {
+        let mut config = Self::new();
+        let keys = [
+            (Input::A, "X"), (Input::B, "C"), (Input::Z, "Z"), (Input::Start, "Enter"),
+            (Input::DUp, "T"), (Input::DDown, "G"), (Input::DLeft, "F"), (Input::DRight, "H"),
+            (Input::L, "Q"), (Input::R, "E"),
+            (Input::CUp, "I"), (Input::CDown, "K"), (Input::CLeft, "J"), (Input::CRight, "L"),
+            (Input::StickUp, "ArrowUp"), (Input::StickDown, "ArrowDown"),
+            (Input::StickLeft, "ArrowLeft"), (Input::StickRight, "ArrowRight"),
+        ];
+        for (input, key) in keys {
+            config.ports[0].bind(input, Binding::Key(key.to_string()));
+        }
+        let buttons = [
+            (Input::A, "South"), (Input::B, "West"), (Input::Z, "LeftTrigger2"), (Input::Start, "Start"),
+            (Input::DUp, "DPadUp"), (Input::DDown, "DPadDown"), (Input::DLeft, "DPadLeft"), (Input::DRight, "DPadRight"),
+            (Input::L, "LeftTrigger"), (Input::R, "RightTrigger"),
+        ];
+        let axes = [
+            (Input::CUp, "RightStickY", true), (Input::CDown, "RightStickY", false),
+            (Input::CLeft, "RightStickX", false), (Input::CRight, "RightStickX", true),
+            (Input::StickUp, "LeftStickY", true), (Input::StickDown, "LeftStickY", false),
+            (Input::StickLeft, "LeftStickX", false), (Input::StickRight, "LeftStickX", true),
+        ];
+        for port in config.ports.iter_mut() {
+            for (input, button) in buttons {
+                port.bind(input, Binding::GamepadButton(button.to_string()));
+            }
+            for (input, axis, positive) in axes {
+                port.bind(input, Binding::GamepadAxis(axis.to_string(), positive));
+            }
+        }
+        config
+    }
+
+    pub fn port(&self, port: usize) -> &PortConfig {
+        &self.ports[port]
+    }
+
+    pub fn mut_port(&mut self, port: usize) -> &mut PortConfig {
+        &mut self.ports[port]
+    }
+
+    // One "port,input,binding" line per bound slot, the format the GUI stores. Turbo goes in "port,Turbo,input" lines
+    pub fn to_lines(&self) -> String {
+        let mut lines = String::new();
+        for (index, port) in self.ports.iter().enumerate() {
+            for input in Input::ALL {
+                for binding in port.key(input).iter().chain(port.gamepad(input).iter()) {
+                    lines.push_str(&format!("{},{},{}\n", index, input.name(), binding));
+                }
+                if port.turbo(input) {
+                    lines.push_str(&format!("{},Turbo,{}\n", index, input.name()));
+                }
+            }
+            lines.push_str(&format!("{},Turbo period,{}\n", index, port.turbo_period));
+        }
+        lines
+    }
+
+    // Unknown lines are skipped so a config from a newer version still loads
+    pub fn from_lines(data: &str) -> Self {
+        let mut config = Self::new();
+        for line in data.lines() {
+            let mut fields = line.splitn(3, ',');
+            let port = fields.next().and_then(|port| port.parse::<usize>().ok()).filter(|port| *port < CONTROLLER_PORTS);
+            let find_input = |name: &str| Input::ALL.into_iter().find(|input| input.name() == name);
+            let (port, name, value) = match (port, fields.next(), fields.next()) {
+                (Some(port), Some(name), Some(value)) => (&mut config.ports[port], name, value),
+                _ => continue,
+            };
+            match name {
+                "Turbo" => if let Some(input) = find_input(value) {
+                    port.set_turbo(input, true);
+                },
+                "Turbo period" => if let Ok(period) = value.parse() {
+                    port.set_turbo_period(period);
+                },
+                _ => if let (Some(input), Some(binding)) = (find_input(name), Binding::parse(value)) {
+                    port.bind(input, binding);
+                },
+            };
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod input_tests {
+    use super::*;
+
+    #[test]
+    fn test_controller_state() {
+        let config = InputConfig::with_defaults();
+        let value = |binding: &Binding| match binding {
+            Binding::Key(key) if key == "X" || key == "C" || key == "ArrowLeft" => 1.0,
+            Binding::GamepadAxis(axis, true) if axis == "LeftStickY" => 0.5,
+            _ => 0.0,
+        };
+        let state = config.port(0).controller_state(value);
+        assert_eq!(state, ControllerState { buttons: BUTTON_A | BUTTON_B, x: -80, y: 40 });
+
+        // The stick directions can't be turbo, the PIF pulses the buttons
+        let mut port = config.port(0).clone();
+        port.set_turbo(Input::B, true);
+        port.set_turbo(Input::StickLeft, true);
+        assert!(!port.turbo(Input::StickLeft));
+        assert_eq!(port.turbo_buttons(), BUTTON_B);
+        assert_eq!(port.controller_state(value), state);
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut config = InputConfig::with_defaults();
+        config.mut_port(2).bind(Input::Z, Binding::Key("Space".to_string()));
+        config.mut_port(0).unbind(Input::A, false);
+        config.mut_port(1).set_turbo(Input::Z, true);
+        config.mut_port(1).set_turbo_period(100);
+        let restored = InputConfig::from_lines(&config.to_lines());
+        for port in 0..CONTROLLER_PORTS {
+            assert_eq!(restored.port(port), config.port(port));
+        }
+        assert_eq!(Binding::parse("Axis:RightStickX-"), Some(Binding::GamepadAxis("RightStickX".to_string(), false)));
+        assert_eq!(Binding::parse("Joystick:1"), None);
+        assert!(restored.port(1).turbo(Input::Z));
+        assert_eq!(restored.port(1).turbo_period(), *TURBO_PERIODS.end());
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+
+use serde::{Deserialize, Serialize};
+
+use crate::emulator::Emulator;
+use crate::emulator_thread::Command;
+use crate::registers::CP0Registers;
+
+pub const IPC_DEFAULT_PORT: u16 = 6464;
+
+// Reads bigger than this are refused, a tool wanting the whole RDRAM asks in pieces
+pub const IPC_MAX_READ: usize = 0x10000;
+
+/*
+    One JSON object per line, selected by "command", for example
+    {"command": "read_memory", "address": 2147745792, "length": 16, "virtual": true}
+    Addresses are 32 bits, virtual ones are sign extended like the CPU does.
+*/
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum IpcRequest {
+    Status,
+    Pause,
+    Resume,
+    Step {
+        #[serde(default = "one")]
+        count: u32,
+    },
+    Registers,
+    SetRegister { index: usize, value: i64 },
+    ReadMemory {
+        address: u32,
+        length: usize,
+        #[serde(default, rename = "virtual")]
+        virtual_address: bool,
+    },
+    WriteMemory {
+        address: u32,
+        data: Vec<u8>,
+        #[serde(default, rename = "virtual")]
+        virtual_address: bool,
+    },
+}
+
+fn one() -> u32 {
+    1
+}
+
+// Answered with one line each, "result" tells which one
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum IpcResponse {
+    Ok,
+    Status { running: bool, frames: u64, program_counter: i64 },
+    Registers {
+        program_counter: i64,
+        hi: i64,
+        lo: i64,
+        gpr: Vec<i64>,
+        // 32 bit registers are sign extended
+        cp0: Vec<i64>,
+    },
+    Memory { data: Vec<u8> },
+    Error { message: String },
+}
+
+impl IpcResponse {
+    pub fn error(message: &str) -> Self {
+        Self::Error { message: message.to_string() }
+    }
+}
+
+fn address(address: u32, virtual_address: bool) -> i64 {
+    match virtual_address {
+        true => address as i32 as i64,
+        false => address as i64,
+    }
+}
+
+/*
+    Pausing, resuming and the status belong to the emulation loop, which knows whether it's running.
+    Here they're answered as if the emulator was paused.
+*/
+pub fn handle_request(emulator: &mut Emulator, request: IpcRequest) -> IpcResponse {
+    match request {
+        IpcRequest::Status | IpcRequest::Pause | IpcRequest::Resume => IpcResponse::Status {
+            running: false,
+            frames: emulator.frames(),
+            program_counter: emulator.cpu().registers().get_program_counter(),
+        },
+        IpcRequest::Step { count } => {
+            emulator.mut_debugger().resume();
+            for _ in 0..count {
+                emulator.tick();
+            }
+            IpcResponse::Ok
+        },
+        IpcRequest::Registers => {
+            let registers = emulator.cpu().registers();
+            let cp0 = emulator.cpu().cp0();
+            IpcResponse::Registers {
+                program_counter: registers.get_program_counter(),
+                hi: registers.get_hi(),
+                lo: registers.get_lo(),
+                gpr: (0..32).map(|index| registers.get_by_number(index)).collect(),
+                cp0: (0..32).map(|index| match CP0Registers::is_32bits(index) {
+                    true => cp0.get_by_number_32(index) as i64,
+                    false => cp0.get_by_number_64(index),
+                }).collect(),
+            }
+        },
+        IpcRequest::SetRegister { index, value } => {
+            if index > 31 {
+                return IpcResponse::error("Register index out of range");
+            }
+            emulator.mut_cpu().mut_registers().set_by_number(index, value);
+            IpcResponse::Ok
+        },
+        IpcRequest::ReadMemory { address: start, length, virtual_address } => {
+            if length > IPC_MAX_READ {
+                return IpcResponse::error("Read too long");
+            }
+            let data = match virtual_address {
+                true => emulator.mmu().read_virtual(address(start, true), length),
+                false => emulator.mmu().read_physical(address(start, false), length),
+            };
+            IpcResponse::Memory { data }
+        },
+        // Goes through the MMU like a CPU store so the devices see the write
+        IpcRequest::WriteMemory { address: start, data, virtual_address } => {
+            match virtual_address {
+                true => emulator.mut_mmu().write_virtual(address(start, true), &data),
+                false => emulator.mut_mmu().write_physical(address(start, false), &data),
+            };
+            IpcResponse::Ok
+        },
+    }
+}
+
+/*
+    Listens on localhost only, each connection gets a thread that hands the requests to the
+    emulation loop through the same channel as the GUI. Port 0 picks a free one.
+*/
+pub fn start_server(port: u16, commands: Sender<Command>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let commands = commands.clone();
+            std::thread::spawn(move || serve_client(stream, commands));
+        }
+    });
+    Ok(address)
+}
+
+fn serve_client(stream: TcpStream, commands: Sender<Command>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                let (reply, response) = channel();
+                if commands.send(Command::Ipc(request, reply)).is_err() {
+                    break;
+                }
+                response.recv().unwrap_or_else(|_| IpcResponse::error("The emulator stopped"))
+            },
+            Err(err) => IpcResponse::Error { message: format!("Invalid request: {}", err) },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod ipc_tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_request() {
+        let mut emulator = Emulator::new_hle();
+        let write = IpcRequest::WriteMemory { address: 0x100000, data: vec![0xDE, 0xAD, 0xBE, 0xEF], virtual_address: false };
+        assert_eq!(handle_request(&mut emulator, write), IpcResponse::Ok);
+        let read = IpcRequest::ReadMemory { address: 0x80100000, length: 4, virtual_address: true };
+        assert_eq!(handle_request(&mut emulator, read), IpcResponse::Memory { data: vec![0xDE, 0xAD, 0xBE, 0xEF] });
+        let read = IpcRequest::ReadMemory { address: 0, length: IPC_MAX_READ + 1, virtual_address: false };
+        assert_eq!(handle_request(&mut emulator, read), IpcResponse::error("Read too long"));
+
+        assert_eq!(handle_request(&mut emulator, IpcRequest::SetRegister { index: 8, value: -2 }), IpcResponse::Ok);
+        assert_eq!(handle_request(&mut emulator, IpcRequest::SetRegister { index: 32, value: 0 }), IpcResponse::error("Register index out of range"));
+        let pc = emulator.cpu().registers().get_program_counter();
+        match handle_request(&mut emulator, IpcRequest::Registers) {
+            IpcResponse::Registers { program_counter, gpr, cp0, .. } => {
+                assert_eq!(program_counter, pc);
+                assert_eq!(gpr[8], -2);
+                assert_eq!(cp0.len(), 32);
+            },
+            response => panic!("Unexpected response {:?}", response),
+        };
+
+        assert_eq!(handle_request(&mut emulator, IpcRequest::Step { count: 1 }), IpcResponse::Ok);
+        assert_ne!(emulator.cpu().registers().get_program_counter(), pc);
+    }
+}
@@ -0,0 +1,90 @@
+use std::ops::RangeInclusive;
+
+use crate::log::{log, Level, Subsystem};
+
+/*
+    The IS-Viewer 64 debug board, which libdragon and other homebrew print through. It's 64KB of
+    RAM mapped over the end of the ROM: programs check it's there by writing "IS64" at the start
+    and reading it back, copy the text from offset 0x20 and write its length at offset 0x14.
+    https://n64brew.dev/wiki/Intelligent_Systems_Viewer_64
+*/
+pub const IS_VIEWER: RangeInclusive<i64> = 0x13FF0000..=0x13FFFFFF;
+pub const IS_VIEWER_SIZE: usize = 0x10000;
+
+const LENGTH_OFFSET: usize = 0x14;
+const BUFFER_OFFSET: usize = 0x20;
+
+pub struct ISViewer {
+    memory: Vec<u8>,
+    // Text after the last new line, printed once the line is complete
+    line: Vec<u8>,
+}
+
+impl ISViewer {
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; IS_VIEWER_SIZE],
+            line: Vec::new(),
+        }
+    }
+
+    pub fn read(&self, address: i64) -> u8 {
+        self.memory[(address - IS_VIEWER.start()) as usize]
+    }
+
+    // The text is printed when the last byte of the length is written
+    pub fn write(&mut self, address: i64, data: u8) {
+        let offset = (address - IS_VIEWER.start()) as usize;
+        self.memory[offset] = data;
+        if offset == LENGTH_OFFSET + 3 {
+            let length = u32::from_be_bytes(self.memory[LENGTH_OFFSET..LENGTH_OFFSET + 4].try_into().unwrap()) as usize;
+            let end = (BUFFER_OFFSET + length).min(IS_VIEWER_SIZE);
+            self.print(BUFFER_OFFSET..end);
+        }
+    }
+
+    fn print(&mut self, range: std::ops::Range<usize>) {
+        for index in range {
+            match self.memory[index] {
+                b'\n' => {
+                    let line = String::from_utf8_lossy(&self.line).to_string();
+                    log!(Level::Info, Subsystem::ISViewer, "{}", line.trim_end_matches('\r'));
+                    self.line.clear();
+                },
+                byte => self.line.push(byte),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod is_viewer_tests {
+    use super::*;
+    use crate::log::entries_since;
+
+    #[test]
+    fn test_print() {
+        let mut viewer = ISViewer::new();
+        for (offset, byte) in b"IS64".iter().enumerate() {
+            viewer.write(IS_VIEWER.start() + offset as i64, *byte);
+        }
+        assert_eq!(viewer.read(IS_VIEWER.start() + 3), b'4');
+
+        let mut print = |text: &[u8]| {
+            for (offset, byte) in text.iter().enumerate() {
+                viewer.write(IS_VIEWER.start() + (BUFFER_OFFSET + offset) as i64, *byte);
+            }
+            for (offset, byte) in (text.len() as u32).to_be_bytes().iter().enumerate() {
+                viewer.write(IS_VIEWER.start() + (LENGTH_OFFSET + offset) as i64, *byte);
+            }
+        };
+        print(b"Hello from ");
+        print(b"libdragon\nsecond ");
+        let lines: Vec<String> = entries_since(0).into_iter()
+            .filter(|entry| entry.subsystem == Subsystem::ISViewer)
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(lines, vec!["Hello from libdragon"]);
+        assert_eq!(viewer.line, b"second ");
+    }
+}
@@ -0,0 +1,86 @@
+// Key of the layout in the GUI's persisted storage
+pub const LAYOUT_KEY: &str = "layout";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 2] = [Theme::Dark, Theme::Light];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Dark => "Dark",
+            Theme::Light => "Light",
+        }
+    }
+}
+
+/*
+    Window positions and sizes are part of the egui memory, which eframe already persists.
+    This keeps the rest: the theme and which windows were open, by the name of their menu entry.
+*/
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GuiLayout {
+    pub theme: Theme,
+    pub open_windows: Vec<String>,
+}
+
+impl GuiLayout {
+    pub fn new() -> Self {
+        Self {
+            theme: Theme::Dark,
+            open_windows: Vec::new(),
+        }
+    }
+
+    pub fn is_open(&self, window: &str) -> bool {
+        self.open_windows.iter().any(|open| open == window)
+    }
+
+    // "theme,Dark" followed by one "window,name" line per open window, the format the GUI stores
+    pub fn to_lines(&self) -> String {
+        let mut lines = format!("theme,{}\n", self.theme.name());
+        for window in &self.open_windows {
+            lines.push_str(&format!("window,{}\n", window));
+        }
+        lines
+    }
+
+    // Unknown lines are skipped so a layout from a newer version still loads
+    pub fn from_lines(data: &str) -> Self {
+        let mut layout = Self::new();
+        for line in data.lines() {
+            match line.split_once(',') {
+                Some(("theme", name)) => if let Some(theme) = Theme::ALL.into_iter().find(|theme| theme.name() == name) {
+                    layout.theme = theme;
+                },
+                Some(("window", name)) if !layout.is_open(name) => layout.open_windows.push(name.to_string()),
+                _ => {},
+            };
+        }
+        layout
+    }
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_lines() {
+        let mut layout = GuiLayout::new();
+        layout.theme = Theme::Light;
+        layout.open_windows = vec!["Memory viewer".to_string(), "RDP commands".to_string()];
+        let restored = GuiLayout::from_lines(&layout.to_lines());
+        assert_eq!(restored, layout);
+        assert!(restored.is_open("RDP commands"));
+        assert!(!restored.is_open("TLB"));
+
+        let restored = GuiLayout::from_lines("theme,Sepia\nwindow,TLB\nwindow,TLB\nsize,10\n");
+        assert_eq!(restored.theme, Theme::Dark);
+        assert_eq!(restored.open_windows, vec!["TLB".to_string()]);
+    }
+}
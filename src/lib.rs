@@ -1,9 +1,66 @@
 pub mod registers;
 pub mod cpu;
 pub mod mmu;
+pub mod tlb;
+pub mod pif;
+pub mod is_viewer;
+pub mod usb_debug;
+pub mod input;
+pub mod raw_adapter;
+pub mod cheat;
+pub mod controller_pak;
 pub mod rom;
+pub mod flashram;
 pub mod rdram;
+pub mod rdram_registers;
+pub mod fastmem;
 pub mod emulator;
+pub mod emulator_thread;
+pub mod shared;
 pub mod rcp;
+pub mod hardware_registers;
+pub mod rdp;
+pub mod rdp_blender;
+pub mod rdp_capture;
+pub mod rdp_combiner;
+pub mod rsp;
+pub mod microcode;
+pub mod scheduler;
+pub mod debugger;
+pub mod os_threads;
+pub mod exception;
+pub mod crash_report;
+pub mod dma;
+pub mod expression;
+pub mod save;
+pub mod patch;
+pub mod savestate;
+pub mod savestate_import;
+pub mod state_slots;
+pub mod autosave;
+pub mod storage;
+pub mod hotkeys;
+pub mod screenshot;
+pub mod texture_pack;
+pub mod search;
+pub mod heatmap;
+pub mod audio;
+pub mod resampler;
+pub mod recent_roms;
 pub mod utils;
+pub mod archive;
+pub mod log;
+pub mod osd;
+pub mod layout;
+pub mod display;
+pub mod ffi;
+pub mod test_rom;
+pub mod trace;
+pub mod paranoid;
+pub mod symbols;
+pub mod debug_info;
+pub mod ipc;
+pub mod achievements;
+#[cfg(feature = "python")]
+pub mod python;
 pub mod gui;
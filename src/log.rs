@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+// How many messages are kept before dropping the oldest ones
+pub const LOG_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "Error",
+            Level::Warn => "Warn",
+            Level::Info => "Info",
+            Level::Debug => "Debug",
+        }
+    }
+
+    // Accepts the names in any case, like "debug" on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        Level::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Subsystem {
+    CPU,
+    MMU,
+    RSP,
+    RDP,
+    VI,
+    AI,
+    PI,
+    SI,
+    PIF,
+    Save,
+    // Text homebrew prints through the IS-Viewer, see is_viewer.rs
+    ISViewer,
+    // Flashcart USB debug output, see usb_debug.rs
+    USB,
+    Frontend,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 13] = [
+        Subsystem::CPU, Subsystem::MMU, Subsystem::RSP, Subsystem::RDP, Subsystem::VI, Subsystem::AI,
+        Subsystem::PI, Subsystem::SI, Subsystem::PIF, Subsystem::Save, Subsystem::ISViewer, Subsystem::USB,
+        Subsystem::Frontend,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::CPU => "CPU",
+            Subsystem::MMU => "MMU",
+            Subsystem::RSP => "RSP",
+            Subsystem::RDP => "RDP",
+            Subsystem::VI => "VI",
+            Subsystem::AI => "AI",
+            Subsystem::PI => "PI",
+            Subsystem::SI => "SI",
+            Subsystem::PIF => "PIF",
+            Subsystem::Save => "Save",
+            Subsystem::ISViewer => "ISViewer",
+            Subsystem::USB => "USB",
+            Subsystem::Frontend => "Frontend",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Subsystem::ALL.into_iter().find(|subsystem| subsystem.name().eq_ignore_ascii_case(name))
+    }
+
+    fn index(&self) -> usize {
+        Subsystem::ALL.iter().position(|subsystem| subsystem == self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    // Increases with every message, so readers can ask for what they haven't seen yet
+    pub id: u64,
+    pub level: Level,
+    pub subsystem: Subsystem,
+    pub message: String,
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] [{}] {}", self.level.name(), self.subsystem.name(), self.message)
+    }
+}
+
+struct Logger {
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+/*
+    Messages come from the emulator thread, the save flusher and the frontend, so the sink is global.
+    With the trace feature every message is also a tracing event, otherwise errors and warnings
+    are written to stderr for the headless binaries.
+*/
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    entries: VecDeque::new(),
+    next_id: 0,
+});
+
+// Most verbose level recorded for each subsystem, indexed like Subsystem::ALL
+static MAX_LEVELS: Mutex<[Level; 13]> = Mutex::new([Level::Debug; 13]);
+
+pub fn max_level(subsystem: Subsystem) -> Level {
+    MAX_LEVELS.lock().unwrap_or_else(|err| err.into_inner())[subsystem.index()]
+}
+
+// Can be changed while running, lowering it on a busy subsystem saves formatting its messages
+pub fn set_max_level(subsystem: Subsystem, level: Level) {
+    MAX_LEVELS.lock().unwrap_or_else(|err| err.into_inner())[subsystem.index()] = level;
+}
+
+pub fn enabled(level: Level, subsystem: Subsystem) -> bool {
+    level <= max_level(subsystem)
+}
+
+#[cfg(feature = "trace")]
+fn trace_event(level: Level, subsystem: Subsystem, message: &str) {
+    let subsystem = subsystem.name();
+    match level {
+        Level::Error => tracing::error!(subsystem, "{}", message),
+        Level::Warn => tracing::warn!(subsystem, "{}", message),
+        Level::Info => tracing::info!(subsystem, "{}", message),
+        Level::Debug => tracing::debug!(subsystem, "{}", message),
+    };
+}
+
+/*
+    Writes the tracing events to stderr, as JSON lines for scripts comparing runs or as text.
+    Only the headless binaries call it, the GUI reads the messages from the console instead.
+*/
+#[cfg(feature = "trace")]
+pub fn init_tracing(json: bool) {
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::DEBUG);
+    match json {
+        true => builder.json().init(),
+        false => builder.init(),
+    };
+}
+
+pub fn push(level: Level, subsystem: Subsystem, message: String) {
+    if !enabled(level, subsystem) {
+        return;
+    }
+    #[cfg(feature = "trace")]
+    trace_event(level, subsystem, &message);
+    #[cfg(not(feature = "trace"))]
+    if level <= Level::Warn {
+        eprintln!("[{}] {}", subsystem.name(), message);
+    }
+    let mut logger = LOGGER.lock().unwrap_or_else(|err| err.into_inner());
+    if logger.entries.len() == LOG_SIZE {
+        logger.entries.pop_front();
+    }
+    let id = logger.next_id;
+    logger.next_id += 1;
+    logger.entries.push_back(LogEntry {
+        id,
+        level,
+        subsystem,
+        message,
+    });
+}
+
+// Entries with an id greater or equal than the given one, oldest first
+pub fn entries_since(id: u64) -> Vec<LogEntry> {
+    let logger = LOGGER.lock().unwrap_or_else(|err| err.into_inner());
+    logger.entries.iter().filter(|entry| entry.id >= id).cloned().collect()
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $subsystem:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level, $subsystem) {
+            $crate::log::push($level, $subsystem, format!($($arg)*))
+        }
+    };
+}
+
+pub(crate) use log;
+
+#[cfg(test)]
+mod log_tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_since() {
+        log!(Level::Info, Subsystem::PIF, "Command {:02X}", 0xFF);
+        let entries = entries_since(0);
+        let entry = entries.iter().rev().find(|entry| entry.subsystem == Subsystem::PIF).unwrap();
+        assert_eq!(entry.to_string(), "[Info] [PIF] Command FF");
+        assert!(entries_since(entry.id + 1).iter().all(|other| other.id > entry.id));
+    }
+
+    #[test]
+    fn test_max_level() {
+        assert_eq!(Level::parse("warn"), Some(Level::Warn));
+        assert_eq!(Subsystem::parse("pif"), Some(Subsystem::PIF));
+        set_max_level(Subsystem::Save, Level::Warn);
+        assert!(enabled(Level::Error, Subsystem::Save));
+        assert!(!enabled(Level::Info, Subsystem::Save));
+        let next_id = entries_since(0).last().map_or(0, |entry| entry.id + 1);
+        log!(Level::Debug, Subsystem::Save, "Hidden");
+        assert!(entries_since(next_id).iter().all(|entry| entry.subsystem != Subsystem::Save));
+        set_max_level(Subsystem::Save, Level::Debug);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+
+use flate2::Crc;
+
+/*
+    osSpTaskStart leaves the OSTask at the end of DMEM and starts the boot microcode, which loads
+    the task's microcode from RDRAM. The microcode is identified from the OSTask when it's there:
+    the CRC32 of its code tells versions apart and the signature Nintendo put in its data, like
+    "RSP Gfx ucode F3DEX       fifo 2.08  Yoshitaka Yasumoto 1999 Nintendo.", names it.
+    https://n64brew.dev/wiki/Reality_Signal_Processor#Microcode
+*/
+pub const OS_TASK_OFFSET: usize = 0xFC0;
+
+// type field of the OSTask, from libultra's sptask.h
+pub const M_GFXTASK: u32 = 1;
+pub const M_AUDTASK: u32 = 2;
+pub const M_VIDTASK: u32 = 3;
+pub const M_NJPEGTASK: u32 = 4;
+pub const M_HVQMTASK: u32 = 7;
+
+// Microcodes are at most the size of IMEM and their data the size of DMEM
+const MAX_UCODE_SIZE: u32 = 0x1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct OSTask {
+    pub task_type: u32,
+    pub flags: u32,
+    pub ucode: u32,
+    pub ucode_size: u32,
+    pub ucode_data: u32,
+    pub ucode_data_size: u32,
+}
+
+impl OSTask {
+    // None when DMEM doesn't hold something that looks like a task
+    pub fn from_dmem(dmem: &[u8]) -> Option<Self> {
+        let word = |index: usize| {
+            let offset = OS_TASK_OFFSET + index * 4;
+            u32::from_be_bytes(dmem[offset..offset + 4].try_into().unwrap())
+        };
+        let task = Self {
+            task_type: word(0),
+            flags: word(1),
+            ucode: word(4),
+            ucode_size: word(5),
+            ucode_data: word(6),
+            ucode_data_size: word(7),
+        };
+        let valid = (M_GFXTASK..=M_HVQMTASK).contains(&task.task_type)
+            && task.ucode != 0
+            && task.ucode_size <= MAX_UCODE_SIZE
+            && task.ucode_data_size <= MAX_UCODE_SIZE;
+        valid.then_some(task)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MicrocodeFamily {
+    // The first graphics microcode, signed "RSP SW Version"
+    Fast3D,
+    F3DEX,
+    F3DEX2,
+    F3DZEX,
+    L3DEX,
+    L3DEX2,
+    S2DEX,
+    S2DEX2,
+    Turbo3D,
+    Audio,
+    Jpeg,
+    Unknown,
+}
+
+impl MicrocodeFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MicrocodeFamily::Fast3D => "Fast3D",
+            MicrocodeFamily::F3DEX => "F3DEX",
+            MicrocodeFamily::F3DEX2 => "F3DEX2",
+            MicrocodeFamily::F3DZEX => "F3DZEX",
+            MicrocodeFamily::L3DEX => "L3DEX",
+            MicrocodeFamily::L3DEX2 => "L3DEX2",
+            MicrocodeFamily::S2DEX => "S2DEX",
+            MicrocodeFamily::S2DEX2 => "S2DEX2",
+            MicrocodeFamily::Turbo3D => "Turbo3D",
+            MicrocodeFamily::Audio => "Audio",
+            MicrocodeFamily::Jpeg => "JPEG",
+            MicrocodeFamily::Unknown => "Unknown",
+        }
+    }
+
+    /*
+        The second generation keeps the names of the first in the signatures, "F3DEX fifo 2.08",
+        only the version tells them apart. F3DLX and F3DLP are reduced F3DEX builds.
+    */
+    fn from_signature(signature: &str) -> Self {
+        if signature.contains("SW Version") {
+            return MicrocodeFamily::Fast3D;
+        }
+        if signature.to_ascii_lowercase().contains("turbo") {
+            return MicrocodeFamily::Turbo3D;
+        }
+        let families = [
+            ("F3DZEX", MicrocodeFamily::F3DZEX, MicrocodeFamily::F3DZEX),
+            ("F3DEX", MicrocodeFamily::F3DEX, MicrocodeFamily::F3DEX2),
+            ("F3DLX", MicrocodeFamily::F3DEX, MicrocodeFamily::F3DEX2),
+            ("F3DLP", MicrocodeFamily::F3DEX, MicrocodeFamily::F3DEX2),
+            ("L3DEX", MicrocodeFamily::L3DEX, MicrocodeFamily::L3DEX2),
+            ("S2DEX", MicrocodeFamily::S2DEX, MicrocodeFamily::S2DEX2),
+        ];
+        for (token, first, second) in families {
+            if let Some(position) = signature.find(token) {
+                let major = signature[position + token.len()..].chars().find(char::is_ascii_digit);
+                return match major {
+                    Some('2') => second,
+                    _ => first,
+                };
+            }
+        }
+        MicrocodeFamily::Unknown
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Microcode {
+    // CRC32 of the code
+    pub hash: u32,
+    pub family: MicrocodeFamily,
+    // With runs of spaces collapsed
+    pub signature: Option<String>,
+    // None when the task was started without an OSTask
+    pub task_type: Option<u32>,
+}
+
+impl fmt::Display for Microcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.signature {
+            Some(signature) => write!(f, "{} ({}, {:08X})", self.family.name(), signature, self.hash),
+            None => write!(f, "{} ({:08X})", self.family.name(), self.hash),
+        }
+    }
+}
+
+// Printable text starting at "RSP " up to the first byte that isn't
+fn find_signature(data: &[u8]) -> Option<String> {
+    let start = data.windows(4).position(|window| window == b"RSP ")?;
+    let text: String = data[start..]
+        .iter()
+        .take_while(|byte| (0x20..0x7F).contains(*byte))
+        .map(|byte| *byte as char)
+        .collect();
+    Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+// KSEG0 addresses as osSpTaskStart leaves them, or physical ones
+fn rdram_slice(rdram: &[u8], address: u32, size: u32) -> Option<&[u8]> {
+    let start = (address & 0x1FFFFFFF) as usize;
+    rdram.get(start..start + size as usize)
+}
+
+/*
+    Called when the RSP leaves the halted state. Without an OSTask, like in homebrew that loads its
+    microcode by hand, IMEM and DMEM are what's running.
+*/
+pub fn identify(dmem: &[u8], imem: &[u8], rdram: &[u8]) -> Microcode {
+    let task = OSTask::from_dmem(dmem);
+    let code = task.and_then(|task| {
+        let size = match task.ucode_size {
+            0 => MAX_UCODE_SIZE,
+            size => size,
+        };
+        rdram_slice(rdram, task.ucode, size)
+    });
+    let data = task.and_then(|task| rdram_slice(rdram, task.ucode_data, task.ucode_data_size));
+    let code = code.unwrap_or(imem);
+    let signature = find_signature(data.unwrap_or(dmem));
+
+    let mut crc = Crc::new();
+    crc.update(code);
+    let family = match (signature.as_deref().map(MicrocodeFamily::from_signature), task.map(|task| task.task_type)) {
+        (Some(family), _) if family != MicrocodeFamily::Unknown => family,
+        (_, Some(M_AUDTASK)) => MicrocodeFamily::Audio,
+        (_, Some(M_NJPEGTASK)) => MicrocodeFamily::Jpeg,
+        _ => MicrocodeFamily::Unknown,
+    };
+    Microcode {
+        hash: crc.sum(),
+        family,
+        signature,
+        task_type: task.map(|task| task.task_type),
+    }
+}
+
+#[cfg(test)]
+mod microcode_tests {
+    use super::*;
+
+    fn write_word(memory: &mut [u8], offset: usize, value: u32) {
+        memory[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    #[test]
+    fn test_identify() {
+        let mut rdram = vec![0; 0x10000];
+        let mut dmem = vec![0; 0x1000];
+        let imem = vec![0; 0x1000];
+        rdram[0x1000..0x1010].copy_from_slice(&[0x12; 16]);
+        let signature = b"\x00\x01RSP Gfx ucode F3DEX       fifo 2.08  Yoshitaka Yasumoto 1999 Nintendo.\x00\x00";
+        rdram[0x2000..0x2000 + signature.len()].copy_from_slice(signature);
+        for (index, value) in [M_GFXTASK, 0, 0x80000400, 0x1000, 0x80001000, 0x10, 0x80002000, 0x800].into_iter().enumerate() {
+            write_word(&mut dmem, OS_TASK_OFFSET + index * 4, value);
+        }
+        let microcode = identify(&dmem, &imem, &rdram);
+        assert_eq!(microcode.family, MicrocodeFamily::F3DEX2);
+        assert_eq!(microcode.signature.as_deref(), Some("RSP Gfx ucode F3DEX fifo 2.08 Yoshitaka Yasumoto 1999 Nintendo."));
+        assert_eq!(microcode.task_type, Some(M_GFXTASK));
+        let mut crc = Crc::new();
+        crc.update(&[0x12; 16]);
+        assert_eq!(microcode.hash, crc.sum());
+
+        assert_eq!(MicrocodeFamily::from_signature("RSP Gfx ucode F3DEX fifo 1.23 Yoshitaka Yasumoto 1997 Nintendo."), MicrocodeFamily::F3DEX);
+        assert_eq!(MicrocodeFamily::from_signature("RSP Gfx ucode S2DEX fifo 2.05 Yoshitaka Yasumoto 1998 Nintendo."), MicrocodeFamily::S2DEX2);
+        assert_eq!(MicrocodeFamily::from_signature("RSP SW Version: 2.0D, 04-01-96"), MicrocodeFamily::Fast3D);
+
+        // Audio microcodes aren't signed, the task type says what they are
+        write_word(&mut dmem, OS_TASK_OFFSET, M_AUDTASK);
+        write_word(&mut dmem, OS_TASK_OFFSET + 6 * 4, 0x80003000);
+        assert_eq!(identify(&dmem, &imem, &rdram).family, MicrocodeFamily::Audio);
+
+        // Without a task, what's in IMEM
+        let microcode = identify(&[0; 0x1000], &imem, &rdram);
+        assert_eq!(microcode.family, MicrocodeFamily::Unknown);
+        assert_eq!(microcode.task_type, None);
+    }
+}
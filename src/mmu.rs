@@ -1,8 +1,31 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::io::Result;
 use std::ops::RangeInclusive;
 
+use crate::audio::{AudioMonitor, AudioBuffer, AI_DACRATE_ADDRESS, AI_STATUS_ADDRESS, AI_STATUS_BUSY};
+use crate::dma::*;
+use crate::fastmem::{FastMem, FastPage};
+use crate::flashram::FlashRam;
+use crate::heatmap::{AccessHeatmap, Access, HEATMAP_PAGE_SIZE};
+use crate::is_viewer::{ISViewer, IS_VIEWER};
+use crate::log::{log, Level, Subsystem};
+use crate::microcode::identify;
+use crate::usb_debug::{UsbDebug, UsbFile};
+use crate::pif::PIF;
 use crate::rdram::RDRAM;
-use crate::rom::ROM;
-use crate::rcp::RCP;
+use crate::rdram_registers::RdramRegisters;
+use crate::rom::{ROM, Region};
+use crate::rcp::{RCP, FramebufferView, VideoInterface, MI_INTR_PI, MI_INTR_SI};
+use crate::rdp_capture::RdpCapture;
+use crate::rdp::{RDP, DPC_END_ADDRESS, DPC_STATUS_XBUS, MAX_RDP_COMMANDS, command_length};
+use crate::save::SaveType;
+use crate::rsp::{RSP, SP_DMA_SPADDR_ADDRESS, SP_DMA_RAMADDR_ADDRESS, SP_DMA_RDLEN_ADDRESS, SP_DMA_WRLEN_ADDRESS};
+use crate::savestate::{StateReader, StateWriter};
+use crate::scheduler::{TimingProfile, CPU_CLOCK_RATE};
+use crate::texture_pack::TexturePack;
+use crate::tlb::TLB;
 
 pub const KUSEG: RangeInclusive<i64> = 0x00000000..=0x7FFFFFFF;
 pub const KSEG0: RangeInclusive<i64> = 0x80000000..=0x9FFFFFFF;
@@ -37,22 +60,151 @@ pub const RESERVED2: RangeInclusive<i64>                    = 0x1FC00800..=0x1FC
 pub const CARTRIDGE_DOMAIN_1_ADDRESS_3: RangeInclusive<i64> = 0x1FD00000..=0x7FFFFFFF;
 pub const EXTERNAL_SYSAD_DEVICE_BUS: RangeInclusive<i64>    = 0x80000000..=0xFFFFFFFF;
 
+/*
+    Most devices only decode the low bits of the address, so their memories and registers repeat
+    across the whole range they answer to. https://n64brew.dev/wiki/Memory_map
+*/
+pub fn mirror(address: i64) -> i64 {
+    match address {
+        0x04000000..=0x0403FFFF => 0x04000000 | (address & 0x1FFF),
+        0x04040000..=0x040FFFFF if address & 0x80000 == 0 => 0x04040000 | (address & 0x1F),
+        // SP_PC and SP_IBIST
+        0x04040000..=0x040FFFFF => 0x04080000 | (address & 0x7),
+        0x04100000..=0x041FFFFF => 0x04100000 | (address & 0x1F),
+        0x04200000..=0x042FFFFF => 0x04200000 | (address & 0xF),
+        0x04300000..=0x043FFFFF => 0x04300000 | (address & 0xF),
+        0x04400000..=0x044FFFFF => 0x04400000 | (address & 0x3F),
+        0x04500000..=0x045FFFFF => 0x04500000 | (address & 0x1F),
+        0x04600000..=0x046FFFFF => 0x04600000 | (address & 0x3F),
+        0x04700000..=0x047FFFFF => 0x04700000 | (address & 0x1F),
+        0x04800000..=0x048FFFFF => 0x04800000 | (address & 0x1F),
+        0x1FC00000..=0x1FCFFFFF => 0x1FC00000 | (address & 0x7FF),
+        _ => address,
+    }
+}
+
+// Ranges no device answers to, reads return 0 and writes are dropped
+pub fn unmapped(address: i64) -> bool {
+    RESERVED1.contains(&address) || UNUSED.contains(&address) || EXTERNAL_SYSAD_DEVICE_BUS.contains(&address)
+}
+
+// What the CPU sees when it reaches unmapped space, see MMU::check_mapped
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnmappedPolicy {
+    Ignore,
+    // Warns in the MMU log
+    Log,
+    // Nothing acknowledges the access on the SysAD bus, so the CPU takes an IBE or DBE exception
+    BusError,
+}
+
+impl UnmappedPolicy {
+    pub const ALL: [UnmappedPolicy; 3] = [UnmappedPolicy::Ignore, UnmappedPolicy::Log, UnmappedPolicy::BusError];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnmappedPolicy::Ignore => "ignore",
+            UnmappedPolicy::Log => "log",
+            UnmappedPolicy::BusError => "bus-error",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        UnmappedPolicy::ALL.into_iter().find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// Debuggers read memory a page at a time so the GUI never has to go through the core byte by byte
+pub const MEMORY_PAGE_SIZE: usize = 0x100;
+
+// Smallest TLB page, so a whole fetch page always translates the same way
+const FETCH_PAGE_SIZE: usize = 0x1000;
+
 pub struct MMU {
     rdram: RDRAM,
+    rdram_registers: RdramRegisters,
     rom: ROM,
+    // Takes over the domain 2 range of games saving to FlashRAM, see flash_ram_enabled
+    flash_ram: FlashRam,
     rcp: RCP,
+    tlb: TLB,
+    pif: PIF,
+    heatmap: AccessHeatmap,
+    audio: AudioMonitor,
+    // Transfers started since the emulator last collected them
+    dma_transfers: Vec<DmaTransfer>,
+    // Records the RDP commands while set, see Emulator::capture_rdp_frame
+    rdp_capture: Option<RdpCapture>,
+    is_viewer: ISViewer,
+    usb_debug: UsbDebug,
+    // Optional shortcut for RDRAM and ROM accesses, see fastmem.rs
+    fastmem: Option<FastMem>,
+    /*
+        Virtual page of the last instruction fetch and where it starts in RDRAM, see fetch_virtual.
+        Both are page aligned, packed in the upper and lower halves with bit 0 set while valid.
+    */
+    fetch_page: AtomicU64,
+    // Region last handed to the frontend and whether RDRAM under it was written since, see take_frame
+    presented_view: Option<FramebufferView>,
+    framebuffer_dirty: bool,
+    // CPU cycles until the running PI DMA is done, PI_STATUS shows it busy until then
+    pi_dma_cycles: u64,
+    // The same for a CPU write to the cartridge bus
+    pi_io_cycles: u64,
+    // Set when a PI register is written during a DMA, until PI_STATUS_RESET
+    pi_dma_error: bool,
+    si_dma_cycles: u64,
+    si_io_cycles: u64,
+    // Set when an SI DMA is started during another one, the new one doesn't run
+    si_dma_error: bool,
+    // CPU cycles until the AI has played the last buffer, and the VI clock its sample rate divides
+    ai_dma_cycles: u64,
+    vi_clock_rate: u64,
+    // Textures of the game to dump or replace, see texture_pack.rs
+    texture_pack: Option<TexturePack>,
+    unmapped_policy: UnmappedPolicy,
+    // Set by a CPU access to unmapped space under UnmappedPolicy::BusError, until the CPU takes it
+    bus_error: AtomicBool,
+    // Pages of unmapped space already warned about, a game polling one would flood the log otherwise
+    unmapped_logged: Mutex<HashSet<i64>>,
 }
 
 impl MMU {
     pub fn new() -> Self {
         Self {
             rdram: RDRAM::new(),
+            rdram_registers: RdramRegisters::new(),
             rcp: RCP::new(),
             rom: ROM::new(),
+            flash_ram: FlashRam::new(),
+            tlb: TLB::new(),
+            pif: PIF::new(),
+            heatmap: AccessHeatmap::new(),
+            audio: AudioMonitor::new(),
+            dma_transfers: Vec::new(),
+            rdp_capture: None,
+            is_viewer: ISViewer::new(),
+            usb_debug: UsbDebug::new(),
+            fastmem: None,
+            fetch_page: AtomicU64::new(0),
+            presented_view: None,
+            framebuffer_dirty: false,
+            pi_dma_cycles: 0,
+            pi_io_cycles: 0,
+            pi_dma_error: false,
+            si_dma_cycles: 0,
+            si_io_cycles: 0,
+            si_dma_error: false,
+            ai_dma_cycles: 0,
+            vi_clock_rate: TimingProfile::from_region(Region::NTSC).vi_clock_rate,
+            texture_pack: None,
+            unmapped_policy: UnmappedPolicy::Log,
+            bus_error: AtomicBool::new(false),
+            unmapped_logged: Mutex::new(HashSet::new()),
         }
     }
 
-    pub fn hle_ipl(&mut self) {
+    pub fn hle_ipl(&mut self, region: Region) {
         // Skip IPL1 and IPL2
         for i in 0..0x1000 {
             let byte = self.read_virtual(0xB0000000 + i, 1);
@@ -63,10 +215,462 @@ impl MMU {
             let byte = self.read_physical_byte(0x10001000 + i);
             self.write_physical_byte(0x00001000 + i, byte);
         }
+        // IPL3 stores the TV type in osTvType
+        self.write_virtual(0x80000300, &region.tv_type().to_be_bytes());
     }
 
     pub fn set_rom(&mut self, rom: ROM) {
         self.rom = rom;
+        self.flash_ram = FlashRam::new();
+    }
+
+    // SRAM games see the cartridge RAM directly
+    fn flash_ram_enabled(&self) -> bool {
+        self.rom.save_type() == SaveType::FlashRam
+    }
+
+    pub fn rom(&self) -> &ROM {
+        &self.rom
+    }
+
+    pub fn mut_rom(&mut self) -> &mut ROM {
+        &mut self.rom
+    }
+
+    pub fn rdram(&self) -> &RDRAM {
+        &self.rdram
+    }
+
+    pub fn rdram_registers(&self) -> &RdramRegisters {
+        &self.rdram_registers
+    }
+
+    pub fn tlb(&self) -> &TLB {
+        &self.tlb
+    }
+
+    pub fn mut_tlb(&mut self) -> &mut TLB {
+        self.set_fetch_page(None);
+        &mut self.tlb
+    }
+
+    pub fn pif(&self) -> &PIF {
+        &self.pif
+    }
+
+    pub fn mut_pif(&mut self) -> &mut PIF {
+        &mut self.pif
+    }
+
+    pub fn heatmap(&self) -> &AccessHeatmap {
+        &self.heatmap
+    }
+
+    pub fn mut_heatmap(&mut self) -> &mut AccessHeatmap {
+        &mut self.heatmap
+    }
+
+    pub fn audio(&self) -> &AudioMonitor {
+        &self.audio
+    }
+
+    pub fn rsp(&self) -> &RSP {
+        &self.rcp.rsp
+    }
+
+    pub fn mut_rsp(&mut self) -> &mut RSP {
+        &mut self.rcp.rsp
+    }
+
+    pub fn rdp(&self) -> &RDP {
+        &self.rcp.rdp
+    }
+
+    // Command buffer between DPC_START and DPC_END
+    pub fn rdp_command_buffer(&self) -> Vec<u8> {
+        let rdp = &self.rcp.rdp;
+        self.read_rdp_commands(rdp.get_start(), rdp.get_end())
+    }
+
+    // Read from DMEM when the XBUS bit is set
+    fn read_rdp_commands(&self, start: u32, end: u32) -> Vec<u8> {
+        let len = (end.saturating_sub(start) as usize).min(MAX_RDP_COMMANDS * 8);
+        match self.rcp.rdp.get_status() & DPC_STATUS_XBUS != 0 {
+            true => (0..len).map(|i| self.rcp.rsp.dmem()[(start as usize + i) & 0xFFF]).collect(),
+            false => self.read_physical(start as i64, len),
+        }
+    }
+
+    // Runs an RSP instruction, the SP and DP registers it wrote are applied as if the CPU wrote them
+    pub fn tick_rsp(&mut self) {
+        self.rcp.rsp.tick(&self.rcp.rdp);
+        self.apply_rsp_cop0_writes();
+    }
+
+    pub fn step_rsp(&mut self) {
+        self.rcp.rsp.step(&self.rcp.rdp);
+        self.apply_rsp_cop0_writes();
+    }
+
+    fn apply_rsp_cop0_writes(&mut self) {
+        for (address, value) in self.rcp.rsp.take_cop0_writes() {
+            self.write_physical(address, &value.to_be_bytes());
+        }
+    }
+
+    // Runs the complete commands between DPC_CURRENT and DPC_END
+    fn run_rdp_commands(&mut self) {
+        let current = self.rcp.rdp.get_current();
+        let words: Vec<u64> = self.read_rdp_commands(current, self.rcp.rdp.get_end())
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let mut index = 0;
+        while index < words.len() && index + command_length(words[index]) <= words.len() {
+            let length = command_length(words[index]);
+            match &mut self.rdp_capture {
+                Some(capture) => capture.execute(&mut self.rcp.rdp, &words[index..index + length], &self.rdram),
+                None => self.rcp.rdp.execute(&words[index..index + length], &self.rdram),
+            };
+            // Set Tile Size and Load Tile leave a tile with both its texels and its size
+            if let (Some(pack), 0x32 | 0x34) = (&mut self.texture_pack, (words[index] >> 56) & 0x3F) {
+                let tile = ((words[index] >> 24) & 0b111) as usize;
+                pack.texture_loaded(self.rcp.rdp.tmem(), &self.rcp.rdp.tiles()[tile]);
+            }
+            index += length;
+        }
+        self.rcp.rdp.set_current(current + index as u32 * 8);
+    }
+
+    pub fn texture_pack(&self) -> Option<&TexturePack> {
+        self.texture_pack.as_ref()
+    }
+
+    pub fn mut_texture_pack(&mut self) -> Option<&mut TexturePack> {
+        self.texture_pack.as_mut()
+    }
+
+    pub fn set_texture_pack(&mut self, texture_pack: Option<TexturePack>) {
+        self.texture_pack = texture_pack;
+    }
+
+    pub fn start_rdp_capture(&mut self) {
+        self.rdp_capture = Some(RdpCapture::new(&self.rcp.rdp));
+    }
+
+    pub fn take_rdp_capture(&mut self) -> Option<RdpCapture> {
+        self.rdp_capture.take()
+    }
+
+    pub fn take_dma_transfers(&mut self) -> Vec<DmaTransfer> {
+        std::mem::take(&mut self.dma_transfers)
+    }
+
+    // Binaries and screenshots sent through the flashcart USB, see usb_debug.rs
+    pub fn take_usb_files(&mut self) -> Vec<UsbFile> {
+        self.usb_debug.take_files()
+    }
+
+    fn read_word(&self, address: i64) -> u32 {
+        u32::from_be_bytes(self.read_physical(address, 4).try_into().unwrap())
+    }
+
+    fn copy_physical(&mut self, source: i64, destination: i64, length: usize) {
+        // DMAs show in the heatmap once per page they touch
+        for offset in (0..length).step_by(HEATMAP_PAGE_SIZE) {
+            self.heatmap.record(source + offset as i64, Access::Read);
+            self.heatmap.record(destination + offset as i64, Access::Write);
+        }
+        let in_rdram = |address: i64| RDRAM1.contains(&address) && RDRAM1.contains(&(address + length as i64 - 1));
+        let data = match in_rdram(source) {
+            true => self.rdram.read_range(source, length),
+            false => self.read_physical(source, length),
+        };
+        match in_rdram(destination) {
+            true => {
+                self.rdram.as_mut_slice()[destination as usize..destination as usize + length].copy_from_slice(&data);
+                self.mark_written(destination, length);
+            },
+            false => self.write_physical(destination, &data),
+        };
+    }
+
+    /*
+        Called once the last byte of a register is written. The data is copied right away, the PI and
+        SI stay busy for the time the transfer takes and raise their interrupt once it's done.
+        https://n64brew.dev/wiki/Peripheral_Interface#DMA
+        https://n64brew.dev/wiki/Serial_Interface
+    */
+    fn start_dma(&mut self, register: i64) {
+        let transfer = match register {
+            PI_RD_LEN_ADDRESS | PI_WR_LEN_ADDRESS => {
+                let dram = (self.read_word(PI_DRAM_ADDR_ADDRESS) & 0xFFFFFE) as i64;
+                let mut cart = (self.read_word(PI_CART_ADDR_ADDRESS) & 0xFFFFFFFE) as i64;
+                if register == PI_WR_LEN_ADDRESS && CARTRIDGE_DOMAIN_2_ADDRESS_2.contains(&cart) && self.flash_ram_enabled() {
+                    cart = self.flash_ram.dma_address(cart);
+                }
+                let length = (self.read_word(register) & 0xFFFFFF) + 1;
+                let (source, destination) = match register {
+                    PI_RD_LEN_ADDRESS => (dram, cart),
+                    _ => (cart, dram),
+                };
+                self.copy_physical(source, destination, length as usize);
+                self.pi_dma_cycles = self.pi_dma_duration(cart, length);
+                DmaTransfer { kind: DmaKind::PI, source, destination, length }
+            },
+            SI_PIF_AD_RD64B_ADDRESS | SI_PIF_AD_WR64B_ADDRESS if self.si_dma_busy() => {
+                self.si_dma_error = true;
+                return;
+            },
+            SI_PIF_AD_RD64B_ADDRESS | SI_PIF_AD_WR64B_ADDRESS => {
+                let dram = (self.read_word(SI_DRAM_ADDR_ADDRESS) & 0xFFFFF8) as i64;
+                let pif = *PIF_RAM.start();
+                let (source, destination) = match register {
+                    SI_PIF_AD_RD64B_ADDRESS => (pif, dram),
+                    _ => (dram, pif),
+                };
+                self.copy_physical(source, destination, 64);
+                if register == SI_PIF_AD_WR64B_ADDRESS {
+                    self.pif.run_commands(&mut self.rom);
+                }
+                self.si_dma_cycles = SI_DMA_CYCLES;
+                DmaTransfer { kind: DmaKind::SI, source, destination, length: 64 }
+            },
+            // Audio output isn't emulated, the samples are only logged and kept for the audio monitor
+            AI_LENGTH_ADDRESS => {
+                let source = (self.read_word(AI_DRAM_ADDR_ADDRESS) & 0xFFFFF8) as i64;
+                let length = self.read_word(AI_LENGTH_ADDRESS) & 0x3FFF8;
+                let samples = self.read_physical(source, length as usize);
+                self.audio.push(AudioBuffer { address: source, length }, &samples);
+                self.ai_dma_cycles = self.ai_dma_duration(length);
+                DmaTransfer { kind: DmaKind::AI, source, destination: *AUDIO_INTERFACE.start(), length }
+            },
+            SP_DMA_RDLEN_ADDRESS | SP_DMA_WRLEN_ADDRESS => self.sp_dma(register),
+            _ => return,
+        };
+        self.dma_transfers.push(transfer);
+    }
+
+    /*
+        The data is copied when the transfer starts, only the busy time is emulated.
+        The PI runs at the RCP clock, 62.5MHz, 2 cycles for every 3 of the CPU.
+    */
+    fn pi_dma_duration(&self, cart: i64, length: u32) -> u64 {
+        let base = match (0x05000000..=0x0FFFFFFF).contains(&cart) {
+            true => PI_BSD_DOM2_LAT_ADDRESS,
+            false => PI_BSD_DOM1_LAT_ADDRESS,
+        };
+        let registers = [0, 4, 8, 12].map(|offset| self.read_word(base + offset));
+        PiDomainTiming::from_registers(registers).transfer_cycles(length) * 3 / 2
+    }
+
+    fn identify_microcode(&mut self) {
+        let rsp = &self.rcp.rsp;
+        let microcode = identify(rsp.dmem(), rsp.imem(), self.rdram.as_slice());
+        let description = microcode.to_string();
+        if self.rcp.rsp.set_microcode(microcode) {
+            log!(Level::Info, Subsystem::RSP, "Running microcode {}", description);
+        }
+    }
+
+    pub fn pi_dma_busy(&self) -> bool {
+        self.pi_dma_cycles > 0
+    }
+
+    pub fn pi_status(&self) -> u32 {
+        let mut status = 0;
+        if self.pi_dma_busy() {
+            status |= PI_STATUS_DMA_BUSY;
+        }
+        if self.pi_io_cycles > 0 {
+            status |= PI_STATUS_IO_BUSY;
+        }
+        if self.pi_dma_error {
+            status |= PI_STATUS_DMA_ERROR;
+        }
+        if self.rcp.interrupt_pending(MI_INTR_PI) {
+            status |= PI_STATUS_INTERRUPT;
+        }
+        status
+    }
+
+    pub fn si_dma_busy(&self) -> bool {
+        self.si_dma_cycles > 0
+    }
+
+    pub fn si_status(&self) -> u32 {
+        let mut status = 0;
+        if self.si_dma_busy() {
+            status |= SI_STATUS_DMA_BUSY;
+        }
+        if self.si_io_cycles > 0 {
+            status |= SI_STATUS_IO_BUSY;
+        }
+        if self.si_dma_error {
+            status |= SI_STATUS_DMA_ERROR;
+        }
+        if self.rcp.interrupt_pending(MI_INTR_SI) {
+            status |= SI_STATUS_INTERRUPT;
+        }
+        status
+    }
+
+    // CPU writes to the cartridge bus and PIF RAM keep the PI or the SI busy while they go through
+    fn start_io(&mut self, address: i64, length: usize) {
+        let address = mirror(address);
+        let cartridge = [
+            &CARTRIDGE_DOMAIN_2_ADDRESS_1, &CARTRIDGE_DOMAIN_1_ADDRESS_1, &CARTRIDGE_DOMAIN_2_ADDRESS_2,
+            &CARTRIDGE_DOMAIN_1_ADDRESS_2, &CARTRIDGE_DOMAIN_1_ADDRESS_3,
+        ];
+        if cartridge.iter().any(|range| range.contains(&address)) {
+            self.pi_io_cycles = self.pi_dma_duration(address, length as u32);
+        } else if PIF_RAM.contains(&address) {
+            self.si_io_cycles = SI_IO_CYCLES;
+        }
+    }
+
+    // Stereo 16 bit samples at the VI clock divided by AI_DACRATE + 1: https://n64brew.dev/wiki/Audio_Interface
+    fn ai_dma_duration(&self, length: u32) -> u64 {
+        let dacrate = (self.read_word(AI_DACRATE_ADDRESS) & 0x3FFF) as u64 + 1;
+        (length as u64 / 4) * dacrate * CPU_CLOCK_RATE / self.vi_clock_rate
+    }
+
+    pub fn ai_dma_busy(&self) -> bool {
+        self.ai_dma_cycles > 0
+    }
+
+    // Set along with the timing of the scheduler, the AI sample rate depends on it
+    pub fn set_vi_clock_rate(&mut self, rate: u64) {
+        self.vi_clock_rate = rate;
+    }
+
+    // Lets the devices that take time run for the cycles the CPU just did
+    pub fn tick(&mut self, cycles: u64) {
+        if self.pi_dma_busy() && self.pi_dma_cycles <= cycles {
+            self.rcp.raise_interrupt(MI_INTR_PI);
+        }
+        if self.si_dma_busy() && self.si_dma_cycles <= cycles {
+            self.rcp.raise_interrupt(MI_INTR_SI);
+        }
+        self.pi_dma_cycles = self.pi_dma_cycles.saturating_sub(cycles);
+        self.pi_io_cycles = self.pi_io_cycles.saturating_sub(cycles);
+        self.si_dma_cycles = self.si_dma_cycles.saturating_sub(cycles);
+        self.si_io_cycles = self.si_io_cycles.saturating_sub(cycles);
+        self.pif.tick(cycles);
+        self.flash_ram.tick(cycles);
+        self.ai_dma_cycles = self.ai_dma_cycles.saturating_sub(cycles);
+    }
+
+    // Copies count rows of length bytes, skipping bytes in RDRAM between rows: https://n64brew.dev/wiki/Reality_Signal_Processor/Interface#DMA
+    fn sp_dma(&mut self, register: i64) -> DmaTransfer {
+        let rsp = &self.rcp.rsp;
+        let sp_address = rsp.get_dma_register(SP_DMA_SPADDR_ADDRESS) as i64;
+        let mut ram_address = (rsp.get_dma_register(SP_DMA_RAMADDR_ADDRESS) & 0xFFFFF8) as i64;
+        let value = rsp.get_dma_register(register);
+        let length = ((value & 0xFFF) | 0b111) + 1;
+        let count = ((value >> 12) & 0xFF) + 1;
+        let skip = ((value >> 20) & 0xFFF) as i64;
+        // The IMEM/DMEM bit stays fixed, the offset wraps around the selected memory
+        let memory = *RSP_DMEM.start() + (sp_address & 0x1000);
+        let mut offset = sp_address & 0xFF8;
+        let (source, destination) = match register {
+            SP_DMA_RDLEN_ADDRESS => (ram_address, memory + offset),
+            _ => (memory + offset, ram_address),
+        };
+        for _ in 0..count {
+            for i in 0..length as i64 {
+                let sp_byte = memory + ((offset + i) & 0xFFF);
+                let (from, to) = match register {
+                    SP_DMA_RDLEN_ADDRESS => (ram_address + i, sp_byte),
+                    _ => (sp_byte, ram_address + i),
+                };
+                let byte = self.read_physical_byte(from);
+                self.write_physical_byte(to, byte);
+            }
+            offset = (offset + length as i64) & 0xFFF;
+            ram_address += length as i64 + skip;
+        }
+        DmaTransfer { kind: DmaKind::SP, source, destination, length: length * count }
+    }
+
+    pub fn framebuffer_rgba(&self) -> Option<(usize, usize, Vec<u8>)> {
+        self.rcp.framebuffer_rgba(&self.rdram)
+    }
+
+    /*
+        The frame to present, only when the frame buffer was written or the region shown changed
+        since the last call, so unchanged frames aren't converted and uploaded again.
+    */
+    pub fn take_frame(&mut self) -> Option<(usize, usize, Vec<u8>)> {
+        let video_interface = &self.rcp.video_interface;
+        let view = video_interface.view_override().or_else(|| video_interface.framebuffer_view());
+        if view == self.presented_view && !self.framebuffer_dirty {
+            return None;
+        }
+        self.presented_view = view;
+        self.framebuffer_dirty = false;
+        self.framebuffer_rgba()
+    }
+
+    fn mark_written(&mut self, address: i64, length: usize) {
+        if let Some(view) = self.presented_view {
+            let start = view.origin as i64;
+            let end = start + (view.width * view.height * view.format.bytes_per_pixel()) as i64;
+            if address < end && address + length as i64 > start {
+                self.framebuffer_dirty = true;
+            }
+        }
+    }
+
+    // The frame is presented again with or without the filters
+    pub fn set_vi_filters(&mut self, enabled: bool) {
+        self.rcp.video_interface.set_filters(enabled);
+        self.framebuffer_dirty = true;
+    }
+
+    pub fn video_interface(&self) -> &VideoInterface {
+        &self.rcp.video_interface
+    }
+
+    pub fn mut_video_interface(&mut self) -> &mut VideoInterface {
+        &mut self.rcp.video_interface
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.rdram.save_state(writer);
+        self.rdram_registers.save_state(writer);
+        self.rcp.save_state(writer);
+        self.rom.save_state(writer);
+        self.flash_ram.save_state(writer);
+        self.tlb.save_state(writer);
+        self.pif.save_state(writer);
+        writer.write_u64(self.pi_dma_cycles);
+        writer.write_u64(self.pi_io_cycles);
+        writer.write_bool(self.pi_dma_error);
+        writer.write_u64(self.si_dma_cycles);
+        writer.write_u64(self.si_io_cycles);
+        writer.write_bool(self.si_dma_error);
+        writer.write_u64(self.ai_dma_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.framebuffer_dirty = true;
+        self.rdram.load_state(reader)?;
+        self.rdram_registers.load_state(reader)?;
+        self.rcp.load_state(reader)?;
+        self.rom.load_state(reader)?;
+        self.flash_ram.load_state(reader)?;
+        self.set_fetch_page(None);
+        self.tlb.load_state(reader)?;
+        self.pif.load_state(reader)?;
+        self.pi_dma_cycles = reader.read_u64()?;
+        self.pi_io_cycles = reader.read_u64()?;
+        self.pi_dma_error = reader.read_bool()?;
+        self.si_dma_cycles = reader.read_u64()?;
+        self.si_io_cycles = reader.read_u64()?;
+        self.si_dma_error = reader.read_bool()?;
+        self.ai_dma_cycles = reader.read_u64()?;
+        Ok(())
     }
 
     pub fn convert(address: i64) -> i64 {
@@ -85,119 +689,577 @@ impl MMU {
         unreachable!("Invalid virtual memory address {:08X}", address);
     }
 
+    /*
+        KUSEG and KSSEG go through the TLB. Without a matching entry they keep the direct mapping,
+        since TLB miss exceptions aren't raised yet.
+    */
+    pub fn translate(&self, address: i64) -> i64 {
+        let address = address & 0x00000000FFFFFFFF;
+        if KUSEG.contains(&address) || KSSEG.contains(&address) {
+            if let Some(physical_address) = self.tlb.translate(address) {
+                return physical_address;
+            }
+        }
+        MMU::convert(address)
+    }
+
     pub fn read_virtual(&self, address: i64, bytes: usize) -> Vec<u8> {
-        let converted_address = MMU::convert(address);
+        let converted_address = self.translate(address);
+        self.heatmap.record(converted_address, Access::Read);
+        self.check_mapped(converted_address, Access::Read);
         self.read_physical(converted_address, bytes)
     }
 
     pub fn write_virtual(&mut self, address: i64, data: &[u8]) {
-        let converted_address = MMU::convert(address);
+        let converted_address = self.translate(address);
+        self.heatmap.record(converted_address, Access::Write);
+        self.check_mapped(converted_address, Access::Write);
+        self.start_io(converted_address, data.len());
         self.write_physical(converted_address, data)
     }
 
+    pub fn unmapped_policy(&self) -> UnmappedPolicy {
+        self.unmapped_policy
+    }
+
+    pub fn set_unmapped_policy(&mut self, policy: UnmappedPolicy) {
+        self.unmapped_policy = policy;
+        self.unmapped_logged.get_mut().unwrap_or_else(|err| err.into_inner()).clear();
+    }
+
+    // Whether an access since the last call should end in a bus error
+    pub fn take_bus_error(&self) -> bool {
+        self.bus_error.swap(false, Ordering::Relaxed)
+    }
+
+    // Only the accesses of the CPU go through the policy, DMAs and the debuggers read physical memory
+    fn check_mapped(&self, address: i64, access: Access) {
+        if !unmapped(address) {
+            return;
+        }
+        match self.unmapped_policy {
+            UnmappedPolicy::Ignore => {},
+            UnmappedPolicy::Log => {
+                let mut logged = self.unmapped_logged.lock().unwrap_or_else(|err| err.into_inner());
+                if logged.insert(address & !0xFFF) {
+                    log!(Level::Warn, Subsystem::MMU, "{} of unmapped address {:08X}, the rest of its page won't be logged", access.name(), address);
+                }
+            },
+            UnmappedPolicy::BusError => self.bus_error.store(true, Ordering::Relaxed),
+        }
+    }
+
+    /*
+        Instruction fetches, the same as a read but counted apart in the heatmap. While the PC stays in
+        the same page of RDRAM the translation and the device dispatch are skipped. Only the mapping
+        is cached, writes to the page are seen since the bytes are always read from RDRAM.
+    */
+    pub fn fetch_virtual(&self, address: i64, bytes: usize) -> Vec<u8> {
+        let page = address & 0xFFFFF000;
+        let offset = (address & 0xFFF) as usize;
+        let base = match self.cached_fetch_page() {
+            Some((cached_page, base)) if cached_page == page => Some(base),
+            _ => self.cache_fetch_page(page),
+        };
+        if let Some(start) = base.map(|base| base + offset).filter(|_| offset + bytes <= FETCH_PAGE_SIZE) {
+            self.heatmap.record(start as i64, Access::Execute);
+            return self.rdram.as_slice()[start..start + bytes].to_vec();
+        }
+        let converted_address = self.translate(address);
+        self.heatmap.record(converted_address, Access::Execute);
+        self.check_mapped(converted_address, Access::Execute);
+        self.read_physical(converted_address, bytes)
+    }
+
+    pub fn fastmem_enabled(&self) -> bool {
+        self.fastmem.is_some()
+    }
+
+    pub fn set_fastmem(&mut self, enabled: bool) {
+        if enabled != self.fastmem.is_some() {
+            self.fastmem = enabled.then(FastMem::new);
+        }
+    }
+
+    fn cached_fetch_page(&self) -> Option<(i64, usize)> {
+        let packed = self.fetch_page.load(Ordering::Relaxed);
+        (packed & 1 != 0).then_some(((packed >> 32) as i64, (packed as u32 & !1) as usize))
+    }
+
+    fn set_fetch_page(&self, page: Option<(i64, usize)>) {
+        let packed = page.map_or(0, |(page, base)| (page as u64) << 32 | base as u64 | 1);
+        self.fetch_page.store(packed, Ordering::Relaxed);
+    }
+
+    // Pages outside RDRAM aren't cached, their fetches keep going through the devices
+    fn cache_fetch_page(&self, page: i64) -> Option<usize> {
+        let physical_page = self.translate(page);
+        let last = physical_page + FETCH_PAGE_SIZE as i64 - 1;
+        let base = (RDRAM1.contains(&physical_page) && RDRAM1.contains(&last)).then_some(physical_page as usize);
+        if let Some(base) = base {
+            self.set_fetch_page(Some((page, base)));
+        }
+        base
+    }
+
     pub fn read_physical(&self, address: i64, bytes: usize) -> Vec<u8> {
+        if let Some((page, offset)) = self.fastmem.as_ref().and_then(|fastmem| fastmem.lookup(address, bytes)) {
+            let memory = match page {
+                FastPage::RDRAM => self.rdram.as_slice(),
+                _ => self.rom.as_slice(),
+            };
+            // Past the end of the ROM goes through the dispatch, which reads 0xFF
+            if let Some(data) = memory.get(offset..offset + bytes) {
+                return data.to_vec();
+            }
+        }
         let mut data = Vec::new();
-        for _ in 0..bytes {
-            data.push(self.read_physical_byte(address));
+        for i in 0..bytes {
+            data.push(self.read_physical_byte(address + i as i64));
         }
         data
     }
 
     pub fn write_physical(&mut self, address: i64, data: &[u8]) {
-        for byte in data {
-            self.write_physical_byte(address, *byte);
+        if let Some((FastPage::RDRAM, offset)) = self.fastmem.as_ref().and_then(|fastmem| fastmem.lookup(address, data.len())) {
+            self.rdram.as_mut_slice()[offset..offset + data.len()].copy_from_slice(data);
+            self.mark_written(address, data.len());
+            return;
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.write_physical_byte(address + i as i64, *byte);
         }
     }
 
+    /*
+        Reads the page containing the address without going through the per byte device dispatch when possible.
+        Virtual addresses are translated like the CPU does before reading.
+    */
+    pub fn read_page(&self, address: i64, virtual_address: bool) -> Vec<u8> {
+        let address = address & !(MEMORY_PAGE_SIZE as i64 - 1);
+        let physical_address = match virtual_address {
+            true => self.translate(address),
+            false => address & 0x00000000FFFFFFFF,
+        };
+        if RDRAM1.contains(&physical_address) {
+            return self.rdram.read_range(physical_address, MEMORY_PAGE_SIZE);
+        }
+        self.read_physical(physical_address, MEMORY_PAGE_SIZE)
+    }
+
     pub fn read_physical_byte(&self, address: i64) -> u8 {
+        let address = mirror(address);
         if RDRAM1.contains(&address) {
             return self.rdram.read8(address);
         } else if RDRAM2.contains(&address) {
-            return self.rdram.read8(address);
-        } else if RESERVED1.contains(&address) {
-            return 0xFF;
-        } else if RDRAM_REGISTERS.contains(&address) {
+            // Only available with the Expansion Pak
             return 0;
+        } else if RDRAM_REGISTERS.contains(&address) {
+            return self.rdram_registers.get_register(address);
         } else if RSP_DMEM.contains(&address) {
-            return 0;
+            return self.rcp.rsp.dmem()[(address - RSP_DMEM.min().unwrap()) as usize];
         } else if RSP_IMEM.contains(&address) {
-            return 0;
-        } else if UNKNOWN.contains(&address) {
-            return 0;
+            return self.rcp.rsp.imem()[(address - RSP_IMEM.min().unwrap()) as usize];
         } else if RSP_REGISTERS.contains(&address) {
-            return 0;
+            return self.rcp.rsp.get_register(address);
         } else if RDP_COMMAND_REGISTERS.contains(&address) {
-            return 0;
+            return self.rcp.rdp.get_register(address);
         } else if RDP_SPAN_REGISTERS.contains(&address) {
             return 0;
         } else if MIPS_INTERFACE.contains(&address) {
-            return 0;
+            return self.rcp.mips_interface.get_register(address);
         } else if VIDEO_INTERFACE.contains(&address) {
             return self.rcp.video_interface.get_register(address);
         } else if AUDIO_INTERFACE.contains(&address) {
-            return 0;
+            let register = self.rcp.audio_interface.get_register(address);
+            if (AI_STATUS_ADDRESS..AI_STATUS_ADDRESS + 4).contains(&address) && self.ai_dma_busy() {
+                return register | AI_STATUS_BUSY.to_be_bytes()[(address - AI_STATUS_ADDRESS) as usize];
+            }
+            return register;
         } else if PERIPHERAL_INTERFACE.contains(&address) {
-            return 0;
+            if (PI_STATUS_ADDRESS..PI_STATUS_ADDRESS + 4).contains(&address) {
+                return self.pi_status().to_be_bytes()[(address - PI_STATUS_ADDRESS) as usize];
+            }
+            return self.rcp.peripheral_interface.get_register(address);
         } else if RDRAM_INTERFACE.contains(&address) {
-            return 0;
+            return self.rcp.rdram_interface.get_register(address);
         } else if SERIAL_INTERFACE.contains(&address) {
-            return 0;
-        } else if UNUSED.contains(&address) {
-            return 0xFF;
+            if (SI_STATUS_ADDRESS..SI_STATUS_ADDRESS + 4).contains(&address) {
+                return self.si_status().to_be_bytes()[(address - SI_STATUS_ADDRESS) as usize];
+            }
+            return self.rcp.serial_interface.get_register(address);
         } else if CARTRIDGE_DOMAIN_2_ADDRESS_1.contains(&address) {
             return 0;
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_1.contains(&address) {
             return 0;
+        } else if IS_VIEWER.contains(&address) {
+            return self.is_viewer.read(address);
+        } else if UsbDebug::contains(address) {
+            return self.usb_debug.read(address);
         } else if CARTRIDGE_DOMAIN_2_ADDRESS_2.contains(&address) {
+            if self.flash_ram_enabled() {
+                return self.flash_ram.read(address - CARTRIDGE_DOMAIN_2_ADDRESS_2.start(), &self.rom);
+            }
             return self.rom.read(address);
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_2.contains(&address) {
             return self.rom.read(address);
         } else if PIF_ROM.contains(&address) {
             return 0;
         } else if PIF_RAM.contains(&address) {
-            return 0;
-        } else if RESERVED2.contains(&address) {
-            return 0;
+            return self.pif.read((address - PIF_RAM.start()) as usize);
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_3.contains(&address) {
             return 0;
-        } else if EXTERNAL_SYSAD_DEVICE_BUS.contains(&address) {
-            return 0;
         }
-        return 0xFF;
+        // Unmapped
+        return 0;
     }
 
     pub fn write_physical_byte(&mut self, address: i64, data: u8) {
+        let address = mirror(address);
         if RDRAM1.contains(&address) {
             self.rdram.write8(address, data);
+            self.mark_written(address, 1);
         } else if RDRAM2.contains(&address) {
-            self.rdram.write8(address, data);
-        } else if RESERVED1.contains(&address) {
         } else if RDRAM_REGISTERS.contains(&address) {
+            self.rdram_registers.set_register(address, data);
         } else if RSP_DMEM.contains(&address) {
+            self.rcp.rsp.mut_dmem()[(address - RSP_DMEM.min().unwrap()) as usize] = data;
         } else if RSP_IMEM.contains(&address) {
-        } else if UNKNOWN.contains(&address) {
+            self.rcp.rsp.mut_imem()[(address - RSP_IMEM.min().unwrap()) as usize] = data;
         } else if RSP_REGISTERS.contains(&address) {
+            let halted = self.rcp.rsp.is_halted();
+            self.rcp.rsp.set_register(address, data);
+            if address & 0b11 == 0b11 {
+                self.start_dma(address & !0b11);
+            }
+            if halted && !self.rcp.rsp.is_halted() {
+                self.identify_microcode();
+            }
         } else if RDP_COMMAND_REGISTERS.contains(&address) {
+            self.rcp.rdp.set_register(address, data);
+            if address == DPC_END_ADDRESS + 3 {
+                self.run_rdp_commands();
+            }
         } else if RDP_SPAN_REGISTERS.contains(&address) {
         } else if MIPS_INTERFACE.contains(&address) {
+            self.rcp.mips_interface.set_register(address, data);
         } else if VIDEO_INTERFACE.contains(&address) {
             self.rcp.video_interface.set_register(address, data);
         } else if AUDIO_INTERFACE.contains(&address) {
+            self.rcp.audio_interface.set_register(address, data);
+            if address & 0b11 == 0b11 {
+                self.start_dma(address & !0b11);
+            }
         } else if PERIPHERAL_INTERFACE.contains(&address) {
+            if address == PI_STATUS_ADDRESS + 3 && data & PI_STATUS_RESET != 0 {
+                self.pi_dma_cycles = 0;
+                self.pi_dma_error = false;
+            }
+            if address == PI_STATUS_ADDRESS + 3 && data & PI_STATUS_CLEAR_INTERRUPT != 0 {
+                self.rcp.clear_interrupt(MI_INTR_PI);
+            }
+            if (PI_STATUS_ADDRESS..PI_STATUS_ADDRESS + 4).contains(&address) {
+                return;
+            }
+            // The transfer in flight keeps its registers, the write is dropped
+            if self.pi_dma_busy() && (PI_DRAM_ADDR_ADDRESS..PI_STATUS_ADDRESS).contains(&address) {
+                self.pi_dma_error = true;
+                return;
+            }
+            self.rcp.peripheral_interface.set_register(address, data);
+            if address & 0b11 == 0b11 {
+                self.start_dma(address & !0b11);
+            }
         } else if RDRAM_INTERFACE.contains(&address) {
+            self.rcp.rdram_interface.set_register(address, data);
         } else if SERIAL_INTERFACE.contains(&address) {
-        } else if UNUSED.contains(&address) {
+            // Writing SI_STATUS acknowledges the interrupt
+            if (SI_STATUS_ADDRESS..SI_STATUS_ADDRESS + 4).contains(&address) {
+                self.rcp.clear_interrupt(MI_INTR_SI);
+                return;
+            }
+            self.rcp.serial_interface.set_register(address, data);
+            if address & 0b11 == 0b11 {
+                self.start_dma(address & !0b11);
+            }
         } else if CARTRIDGE_DOMAIN_2_ADDRESS_1.contains(&address) {
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_1.contains(&address) {
+        } else if IS_VIEWER.contains(&address) {
+            self.is_viewer.write(address, data);
+        } else if UsbDebug::contains(address) {
+            self.usb_debug.write(address, data);
         } else if CARTRIDGE_DOMAIN_2_ADDRESS_2.contains(&address) {
-            self.rom.write(address, data);
+            match self.flash_ram_enabled() {
+                true => self.flash_ram.write(address - CARTRIDGE_DOMAIN_2_ADDRESS_2.start(), data, &mut self.rom),
+                false => self.rom.write(address, data),
+            };
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_2.contains(&address) {
             self.rom.write(address, data);
         } else if PIF_ROM.contains(&address) {
         } else if PIF_RAM.contains(&address) {
-        } else if RESERVED2.contains(&address) {
+            self.pif.write((address - PIF_RAM.start()) as usize, data);
         } else if CARTRIDGE_DOMAIN_1_ADDRESS_3.contains(&address) {
-        } else if EXTERNAL_SYSAD_DEVICE_BUS.contains(&address) {
         }
     }
 }
+
+#[cfg(test)]
+mod mmu_tests {
+    use super::*;
+    use crate::rcp::{PixelFormat, MI_INTR_ADDRESS};
+    use crate::rsp::SP_PC_ADDRESS;
+
+    #[test]
+    fn test_pi_dma() {
+        let mut mmu = MMU::new();
+        mmu.write_physical(PI_DRAM_ADDR_ADDRESS, &0x00001000_u32.to_be_bytes());
+        mmu.write_physical(PI_CART_ADDR_ADDRESS, &0x10000000_u32.to_be_bytes());
+        mmu.write_physical(PI_WR_LEN_ADDRESS, &7_u32.to_be_bytes());
+        // Reads past the end of the ROM return 0xFF
+        assert_eq!(mmu.read_physical(0x1000, 9), vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        let transfers = mmu.take_dma_transfers();
+        assert_eq!(transfers, vec![DmaTransfer { kind: DmaKind::PI, source: 0x10000000, destination: 0x1000, length: 8 }]);
+        assert!(mmu.take_dma_transfers().is_empty());
+    }
+
+    #[test]
+    fn test_pi_dma_timing() {
+        let mut mmu = MMU::new();
+        // LAT 0x40, PWD 0x12, PGS 7, RLS 3, what IPL3 sets for most cartridges
+        for (offset, value) in [0x40_u32, 0x12, 0x07, 0x03].iter().enumerate() {
+            mmu.write_physical(PI_BSD_DOM1_LAT_ADDRESS + offset as i64 * 4, &value.to_be_bytes());
+        }
+        mmu.write_physical(PI_DRAM_ADDR_ADDRESS, &0x00002000_u32.to_be_bytes());
+        mmu.write_physical(PI_CART_ADDR_ADDRESS, &0x10000000_u32.to_be_bytes());
+        mmu.write_physical(PI_WR_LEN_ADDRESS, &0x3FF_u32.to_be_bytes());
+        // 2 pages of 512 bytes and 512 halfwords: 2 * 0x41 + 512 * (0x13 + 4) RCP cycles
+        assert_eq!(mmu.pi_dma_cycles, (2 * 0x41 + 512 * 0x17) * 3 / 2);
+        assert_eq!(mmu.read_physical(PI_STATUS_ADDRESS, 4), vec![0, 0, 0, 1]);
+        mmu.tick(mmu.pi_dma_cycles - 1);
+        assert!(!mmu.rcp.interrupt_pending(MI_INTR_PI));
+        mmu.tick(1);
+        assert!(!mmu.pi_dma_busy());
+        // Done, MI_INTR and PI_STATUS show the interrupt until it's acknowledged
+        assert_eq!(mmu.read_physical(MI_INTR_ADDRESS, 4), vec![0, 0, 0, MI_INTR_PI]);
+        assert_eq!(mmu.read_physical(PI_STATUS_ADDRESS, 4), vec![0, 0, 0, 0b1000]);
+        mmu.write_physical(PI_STATUS_ADDRESS, &[0, 0, 0, PI_STATUS_CLEAR_INTERRUPT]);
+        assert_eq!(mmu.read_physical(MI_INTR_ADDRESS, 4), vec![0; 4]);
+        assert_eq!(mmu.read_physical(PI_STATUS_ADDRESS, 4), vec![0; 4]);
+    }
+
+    #[test]
+    fn test_pi_status() {
+        let mut mmu = MMU::new();
+        mmu.write_physical(PI_DRAM_ADDR_ADDRESS, &0x00002000_u32.to_be_bytes());
+        mmu.write_physical(PI_CART_ADDR_ADDRESS, &0x10000000_u32.to_be_bytes());
+        mmu.write_physical(PI_WR_LEN_ADDRESS, &0xFF_u32.to_be_bytes());
+        // Writing the registers of the running transfer is an error, they keep their values
+        mmu.write_physical(PI_DRAM_ADDR_ADDRESS, &0x00004000_u32.to_be_bytes());
+        assert_eq!(mmu.read_word(PI_DRAM_ADDR_ADDRESS), 0x2000);
+        assert_eq!(mmu.pi_status(), PI_STATUS_DMA_BUSY | PI_STATUS_DMA_ERROR);
+        // Status writes don't read back, the reset stops the DMA and clears the error
+        mmu.write_physical(PI_STATUS_ADDRESS, &0b11_u32.to_be_bytes());
+        assert_eq!(mmu.read_physical(PI_STATUS_ADDRESS, 4), vec![0; 4]);
+
+        mmu.write_virtual(0xB3FF0020, &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(mmu.pi_status(), PI_STATUS_IO_BUSY);
+        mmu.tick(mmu.pi_io_cycles);
+        assert_eq!(mmu.pi_status(), 0);
+    }
+
+    #[test]
+    fn test_ai_dma_timing() {
+        let mut mmu = MMU::new();
+        // 48KHz on NTSC, 0x1000 bytes are 1024 stereo samples
+        let dacrate = 48_681_812 / 48_000 - 1;
+        mmu.write_physical(AI_DACRATE_ADDRESS, &(dacrate as u32).to_be_bytes());
+        mmu.write_physical(AI_DRAM_ADDR_ADDRESS, &0x00002000_u32.to_be_bytes());
+        mmu.write_physical(AI_LENGTH_ADDRESS, &0x1000_u32.to_be_bytes());
+        assert!(mmu.ai_dma_busy());
+        assert_eq!(mmu.ai_dma_cycles, 1024 * (dacrate + 1) * CPU_CLOCK_RATE / 48_681_812);
+        assert_eq!(mmu.read_physical(AI_STATUS_ADDRESS, 4)[0] & 0x40, 0x40);
+        mmu.tick(mmu.ai_dma_cycles);
+        assert!(!mmu.ai_dma_busy());
+        assert_eq!(mmu.read_physical(AI_STATUS_ADDRESS, 4)[0] & 0x40, 0);
+    }
+
+    #[test]
+    fn test_sp_dma() {
+        let mut mmu = MMU::new();
+        let data: Vec<u8> = (0..32).collect();
+        mmu.write_physical(0x2000, &data);
+        mmu.write_physical(SP_DMA_SPADDR_ADDRESS, &0x00000010_u32.to_be_bytes());
+        mmu.write_physical(SP_DMA_RAMADDR_ADDRESS, &0x00002000_u32.to_be_bytes());
+        // Two rows of 8 bytes, skipping 8 bytes of RDRAM after each one
+        mmu.write_physical(SP_DMA_RDLEN_ADDRESS, &((8 << 20) | (1 << 12) | 7_u32).to_be_bytes());
+        let expected: Vec<u8> = (0..8).chain(16..24).collect();
+        assert_eq!(&mmu.rsp().dmem()[0x10..0x20], &expected[..]);
+        let transfers = mmu.take_dma_transfers();
+        assert_eq!(transfers[0].destination, 0x04000010);
+        assert_eq!(transfers[0].length, 16);
+    }
+
+    #[test]
+    fn test_rsp_xbus_commands() {
+        let mut mmu = MMU::new();
+        // Set Tile leaving tile 2 at TMEM word 0x40 in DMEM, and one for tile 0 in RDRAM
+        mmu.write_physical(0x04000100, &((0x35_u64 << 56) | (0x40 << 32) | (2 << 24)).to_be_bytes());
+        mmu.write_physical(0x100, &((0x35_u64 << 56) | (0x80 << 32)).to_be_bytes());
+        let program: [u32; 7] = [
+            0x34010002, // ORI at, zero, 2
+            0x40815800, // MTC0 at, c11 (DPC_STATUS, set XBUS)
+            0x34010100, // ORI at, zero, 0x100
+            0x40814000, // MTC0 at, c8 (DPC_START)
+            0x34010108, // ORI at, zero, 0x108
+            0x40814800, // MTC0 at, c9 (DPC_END)
+            0x0000000D, // BREAK
+        ];
+        for (index, opcode) in program.iter().enumerate() {
+            mmu.write_physical(0x04001000 + index as i64 * 4, &opcode.to_be_bytes());
+        }
+        mmu.mut_rsp().set_halted(false);
+        while !mmu.rsp().is_halted() {
+            mmu.tick_rsp();
+        }
+        assert_eq!(mmu.rdp().tiles()[2].tmem_address, 0x40);
+        assert_eq!(mmu.rdp().get_current(), 0x108);
+        // The command in RDRAM at the same address wasn't run
+        assert_eq!(mmu.rdp().tiles()[0].tmem_address, 0);
+    }
+
+    #[test]
+    fn test_si_dma_controller_read() {
+        let mut mmu = MMU::new();
+        mmu.mut_pif().set_controller(0, crate::pif::ControllerState { buttons: 0x8000, x: 1, y: -1 });
+        let mut block = [0_u8; 64];
+        block[..8].copy_from_slice(&[0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        block[63] = 1;
+        mmu.write_physical(0x3000, &block);
+        mmu.write_physical(SI_DRAM_ADDR_ADDRESS, &0x00003000_u32.to_be_bytes());
+        mmu.write_physical(SI_PIF_AD_WR64B_ADDRESS, &PIF_RAM.start().to_be_bytes()[4..]);
+        mmu.tick(SI_DMA_CYCLES);
+        mmu.write_physical(SI_PIF_AD_RD64B_ADDRESS, &PIF_RAM.start().to_be_bytes()[4..]);
+        assert_eq!(mmu.read_physical(0x3003, 4), vec![0x80, 0x00, 0x01, 0xFF]);
+    }
+
+    #[test]
+    fn test_si_status() {
+        let mut mmu = MMU::new();
+        mmu.write_physical(SI_DRAM_ADDR_ADDRESS, &0x00003000_u32.to_be_bytes());
+        mmu.write_physical(SI_PIF_AD_RD64B_ADDRESS, &PIF_RAM.start().to_be_bytes()[4..]);
+        assert_eq!(mmu.si_status(), SI_STATUS_DMA_BUSY);
+        // Started during the first one, it never runs
+        mmu.write_physical(SI_PIF_AD_WR64B_ADDRESS, &PIF_RAM.start().to_be_bytes()[4..]);
+        assert_eq!(mmu.take_dma_transfers().len(), 1);
+        assert_eq!(mmu.read_physical(SI_STATUS_ADDRESS, 4), vec![0, 0, 0, 0b1001]);
+        mmu.tick(SI_DMA_CYCLES);
+        assert_eq!(mmu.si_status(), SI_STATUS_DMA_ERROR | SI_STATUS_INTERRUPT);
+        assert_eq!(mmu.read_physical(MI_INTR_ADDRESS, 4), vec![0, 0, 0, MI_INTR_SI]);
+        mmu.write_physical(SI_STATUS_ADDRESS, &[0; 4]);
+        assert!(!mmu.rcp.interrupt_pending(MI_INTR_SI));
+        mmu.write_virtual(0xBFC007FC, &[0, 0, 0, 0]);
+        assert_eq!(mmu.si_status() & SI_STATUS_IO_BUSY, SI_STATUS_IO_BUSY);
+    }
+
+    #[test]
+    fn test_rdram_interface_registers() {
+        let mut mmu = MMU::new();
+        // RI_SELECT as set by IPL3
+        mmu.write_physical(0x0470000C, &0x00000014_u32.to_be_bytes());
+        assert_eq!(mmu.read_physical(0x0470000C, 4), vec![0x00, 0x00, 0x00, 0x14]);
+        // The registers mirror every 32 bytes
+        assert_eq!(mmu.read_physical(0x0470002C, 4), vec![0x00, 0x00, 0x00, 0x14]);
+        assert_eq!(mmu.read_physical(0x04900020, 4), vec![0; 4]);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mmu = MMU::new();
+        // IMEM repeats after DMEM up to the SP registers
+        mmu.write_physical(0x04003004, &[0x12, 0x34]);
+        assert_eq!(mmu.read_physical(0x04001004, 2), vec![0x12, 0x34]);
+        assert_eq!(mmu.read_physical(0x0403F004, 2), vec![0x12, 0x34]);
+        mmu.write_physical(0x04080020, &0x00000100_u32.to_be_bytes());
+        assert_eq!(mmu.rsp().read_register(SP_PC_ADDRESS), 0x100);
+        mmu.write_physical(0x04400040, &0x00000002_u32.to_be_bytes());
+        assert_eq!(mmu.read_physical(0x04400000, 4), vec![0x00, 0x00, 0x00, 0x02]);
+        mmu.write_physical(0x1FC00FC0, &[0xAB]);
+        assert_eq!(mmu.read_physical(0x1FC007C0, 1), vec![0xAB]);
+
+        assert!(unmapped(0x04900000) && unmapped(0x00800000) && !unmapped(0x03F00000));
+        assert_eq!(mmu.read_virtual(0xA4900000, 4), vec![0; 4]);
+        assert!(!mmu.take_bus_error());
+        // Only the first access of a page is logged
+        mmu.read_virtual(0xA4900010, 4);
+        mmu.read_virtual(0xA4901000, 4);
+        assert_eq!(mmu.unmapped_logged.lock().unwrap().len(), 2);
+        mmu.set_unmapped_policy(UnmappedPolicy::BusError);
+        mmu.write_virtual(0xA4900000, &[0x12]);
+        assert!(mmu.take_bus_error());
+        assert!(!mmu.take_bus_error());
+        // Debuggers and DMAs don't raise them
+        mmu.read_physical(0x04900000, 4);
+        assert!(!mmu.take_bus_error());
+        assert_eq!(UnmappedPolicy::parse("Bus-Error"), Some(UnmappedPolicy::BusError));
+    }
+
+    #[test]
+    fn test_fetch_page_cache() {
+        let mut mmu = MMU::new();
+        mmu.write_virtual(0x80002000, &[0x24, 0x02, 0x00, 0x01]);
+        assert_eq!(mmu.fetch_virtual(0x80002000, 4), vec![0x24, 0x02, 0x00, 0x01]);
+        assert_eq!(mmu.cached_fetch_page(), Some((0x80002000, 0x2000)));
+        // Writes to the cached page are seen by the next fetch
+        mmu.write_virtual(0xA0002004, &[0x00, 0x00, 0x00, 0x0D]);
+        assert_eq!(mmu.fetch_virtual(0x80002004, 4), vec![0x00, 0x00, 0x00, 0x0D]);
+        mmu.mut_tlb();
+        assert_eq!(mmu.cached_fetch_page(), None);
+        // Nothing cached outside RDRAM
+        assert_eq!(mmu.fetch_virtual(0xA4000000, 4), vec![0; 4]);
+        assert_eq!(mmu.cached_fetch_page(), None);
+    }
+
+    #[test]
+    fn test_read_page() {
+        let mut mmu = MMU::new();
+        // 0x00400000 mapped to 0x00100000, the viewer sees what the CPU writes there
+        mmu.mut_tlb().write(0, crate::tlb::TLBEntry {
+            page_mask: 0,
+            entry_hi: 0x00400000,
+            entry_lo_0: (0x100 << 6) | 0b110,
+            entry_lo_1: 0,
+        });
+        mmu.write_virtual(0x00400010, &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(mmu.read_page(0x00400010, true)[0x10..0x14], [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(mmu.read_page(0x00100000, false)[0x10..0x14], [0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_take_frame() {
+        let mut mmu = MMU::new();
+        mmu.mut_video_interface().set_view_override(Some(FramebufferView { origin: 0x100000, width: 4, height: 2, format: PixelFormat::I8 }));
+        assert_eq!(mmu.take_frame().map(|(width, height, _)| (width, height)), Some((4, 2)));
+        assert!(mmu.take_frame().is_none());
+        // Outside the frame buffer, then inside it
+        mmu.write_physical(0x100008, &[0xFF]);
+        assert!(mmu.take_frame().is_none());
+        mmu.write_physical(0x100007, &[0xFF]);
+        assert_eq!(mmu.take_frame().unwrap().2[28..32], [0xFF, 0xFF, 0xFF, 0xFF]);
+        // Moving the origin shows the new region even without writes
+        mmu.mut_video_interface().set_view_override(Some(FramebufferView { origin: 0x100004, width: 4, height: 2, format: PixelFormat::I8 }));
+        assert!(mmu.take_frame().is_some());
+    }
+
+    #[test]
+    fn test_fastmem() {
+        let mut mmu = MMU::new();
+        mmu.set_fastmem(true);
+        mmu.write_physical(0x2000, &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(mmu.rdram().read8(0x2003), 0x78);
+        assert_eq!(mmu.read_physical(0x1FFE, 4), vec![0x00, 0x00, 0x12, 0x34]);
+        // Registers still go through their devices
+        mmu.write_physical(0x0470000C, &[0x00, 0x00, 0x00, 0x14]);
+        assert_eq!(mmu.read_physical(0x0470000C, 4), vec![0x00, 0x00, 0x00, 0x14]);
+        assert_eq!(mmu.read_physical(0x10000000, 2), vec![0xFF, 0xFF]);
+    }
+}
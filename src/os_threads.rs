@@ -0,0 +1,257 @@
+/*
+    Threads and message queues of libultra, read from its own structures in RDRAM. Every thread
+    created is linked from __osActiveQueue through tlnext, and the list ends at __osThreadTail, an
+    {NULL, -1} pair libultra keeps right before the run queue and the active queue pointers. Games
+    without symbols are searched for that layout.
+*/
+
+// Offsets in OSThread
+const THREAD_PRIORITY: usize = 0x04;
+const THREAD_QUEUE: usize = 0x08;
+const THREAD_TLNEXT: usize = 0x0C;
+const THREAD_STATE: usize = 0x10;
+const THREAD_FLAGS: usize = 0x12;
+const THREAD_ID: usize = 0x14;
+// The saved registers are 64 bit, the low word is enough for addresses
+const THREAD_SP: usize = 0x20 + 26 * 8 + 4;
+const THREAD_RA: usize = 0x20 + 28 * 8 + 4;
+const THREAD_PC: usize = 0x11C;
+
+pub const OS_STATE_STOPPED: u16 = 1 << 0;
+pub const OS_STATE_RUNNABLE: u16 = 1 << 1;
+pub const OS_STATE_RUNNING: u16 = 1 << 2;
+pub const OS_STATE_WAITING: u16 = 1 << 3;
+
+// Offsets in OSMesgQueue
+const QUEUE_MTQUEUE: usize = 0x00;
+const QUEUE_FULLQUEUE: usize = 0x04;
+const QUEUE_VALID_COUNT: usize = 0x08;
+const QUEUE_FIRST: usize = 0x0C;
+const QUEUE_MSG_COUNT: usize = 0x10;
+const QUEUE_MSG: usize = 0x14;
+const QUEUE_SIZE: usize = 0x18;
+
+// Bounds the walk in case the list is corrupted or isn't a thread list at all
+const MAX_THREADS: usize = 256;
+// Bigger queues are taken as something else that looks like one
+const MAX_MESSAGES: u32 = 0x1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct OsThread {
+    // Virtual address of the OSThread
+    pub address: u32,
+    pub id: i32,
+    pub priority: i32,
+    pub state: u16,
+    pub flags: u16,
+    // Message queue or run queue the thread is waiting in, 0 when there isn't one
+    pub queue: u32,
+    // Where the thread continues, the CPU program counter is more recent for the running one
+    pub pc: u32,
+    pub ra: u32,
+    pub sp: u32,
+}
+
+impl OsThread {
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            OS_STATE_STOPPED => "Stopped",
+            OS_STATE_RUNNABLE => "Runnable",
+            OS_STATE_RUNNING => "Running",
+            OS_STATE_WAITING => "Waiting",
+            _ => "Unknown",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ThreadList {
+    // Virtual address of __osActiveQueue, so the next read doesn't search again
+    pub active_queue: u32,
+    // Virtual address of __osThreadTail, where every list of threads ends
+    pub tail: u32,
+    pub threads: Vec<OsThread>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MessageQueue {
+    // Virtual address of the OSMesgQueue
+    pub address: u32,
+    pub valid_count: u32,
+    pub msg_count: u32,
+    // The message osRecvMesg would get next, None when the queue is empty
+    pub first_message: Option<u32>,
+    // IDs of the threads waiting for a message and of the ones waiting to send to a full queue
+    pub receivers: Vec<i32>,
+    pub senders: Vec<i32>,
+}
+
+fn read_word(rdram: &[u8], address: u32) -> Option<u32> {
+    let offset = (address & 0x1FFFFFFF) as usize;
+    rdram.get(offset..offset + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_half(rdram: &[u8], address: u32) -> Option<u16> {
+    let offset = (address & 0x1FFFFFFF) as usize;
+    rdram.get(offset..offset + 2).map(|bytes| u16::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+// KSEG0 or KSEG1 pointer into RDRAM, word aligned
+fn is_pointer(rdram: &[u8], address: u32) -> bool {
+    (0x80000000..0xC0000000).contains(&address) && address & 0b11 == 0 && ((address & 0x1FFFFFFF) as usize) < rdram.len()
+}
+
+fn read_thread(rdram: &[u8], address: u32) -> Option<OsThread> {
+    Some(OsThread {
+        address,
+        id: read_word(rdram, address + THREAD_ID as u32)? as i32,
+        priority: read_word(rdram, address + THREAD_PRIORITY as u32)? as i32,
+        state: read_half(rdram, address + THREAD_STATE as u32)?,
+        flags: read_half(rdram, address + THREAD_FLAGS as u32)?,
+        queue: read_word(rdram, address + THREAD_QUEUE as u32)?,
+        pc: read_word(rdram, address + THREAD_PC as u32)?,
+        ra: read_word(rdram, address + THREAD_RA as u32)?,
+        sp: read_word(rdram, address + THREAD_SP as u32)?,
+    })
+}
+
+/*
+    Walks the active queue at the address of __osActiveQueue. None unless it ends at the thread
+    tail, which is a NULL next pointer followed by a priority of -1.
+*/
+pub fn read_threads(rdram: &[u8], active_queue: u32) -> Option<ThreadList> {
+    let mut threads = Vec::new();
+    let mut address = read_word(rdram, active_queue)?;
+    while threads.len() <= MAX_THREADS {
+        if !is_pointer(rdram, address) {
+            return None;
+        }
+        if read_word(rdram, address)? == 0 && read_word(rdram, address + 4)? == u32::MAX {
+            return Some(ThreadList { active_queue, tail: address, threads });
+        }
+        threads.push(read_thread(rdram, address)?);
+        address = read_word(rdram, address + THREAD_TLNEXT as u32)?;
+    }
+    None
+}
+
+// Looks for __osThreadTail followed by the run queue and the active queue pointers
+pub fn find_active_queue(rdram: &[u8]) -> Option<u32> {
+    (0..rdram.len().saturating_sub(16)).step_by(4).find_map(|offset| {
+        let word = |index: usize| u32::from_be_bytes(rdram[offset + index * 4..offset + index * 4 + 4].try_into().unwrap());
+        if word(0) != 0 || word(1) != u32::MAX || !is_pointer(rdram, word(2)) || !is_pointer(rdram, word(3)) {
+            return None;
+        }
+        let tail = 0x80000000 | offset as u32;
+        let active_queue = tail + 12;
+        let list = read_threads(rdram, active_queue)?;
+        // Either no thread was created yet or the walk found some
+        (word(3) == tail || !list.threads.is_empty()).then_some(active_queue)
+    })
+}
+
+// The active queue symbol when there is one, otherwise the address found last time or a new search
+pub fn threads(rdram: &[u8], active_queue: Option<u32>) -> Option<ThreadList> {
+    active_queue
+        .and_then(|active_queue| read_threads(rdram, active_queue))
+        .or_else(|| read_threads(rdram, find_active_queue(rdram)?))
+}
+
+// IDs of the threads linked through next from the address, None unless it ends at the tail
+fn blocked_threads(rdram: &[u8], list: &ThreadList, mut address: u32) -> Option<Vec<i32>> {
+    let mut ids = Vec::new();
+    while address != list.tail {
+        let thread = list.threads.iter().find(|thread| thread.address == address)?;
+        if ids.len() > MAX_THREADS {
+            return None;
+        }
+        ids.push(thread.id);
+        address = read_word(rdram, address)?;
+    }
+    Some(ids)
+}
+
+fn read_queue(rdram: &[u8], list: &ThreadList, address: u32) -> Option<MessageQueue> {
+    let field = |offset: usize| read_word(rdram, address + offset as u32);
+    let (valid_count, first, msg_count, msg) = (field(QUEUE_VALID_COUNT)?, field(QUEUE_FIRST)?, field(QUEUE_MSG_COUNT)?, field(QUEUE_MSG)?);
+    if msg_count == 0 || msg_count > MAX_MESSAGES || valid_count > msg_count || first >= msg_count || !is_pointer(rdram, msg) {
+        return None;
+    }
+    Some(MessageQueue {
+        address,
+        valid_count,
+        msg_count,
+        first_message: match valid_count {
+            0 => None,
+            _ => Some(read_word(rdram, msg + first * 4)?),
+        },
+        receivers: blocked_threads(rdram, list, field(QUEUE_MTQUEUE)?)?,
+        senders: blocked_threads(rdram, list, field(QUEUE_FULLQUEUE)?)?,
+    })
+}
+
+/*
+    Every OSMesgQueue in RDRAM. Both of its lists of blocked threads start at a thread or at the
+    tail, which is rare enough in other data to search for it with the message counts in range.
+*/
+pub fn message_queues(rdram: &[u8], list: &ThreadList) -> Vec<MessageQueue> {
+    let is_node = |address: u32| address == list.tail || list.threads.iter().any(|thread| thread.address == address);
+    (0..rdram.len().saturating_sub(QUEUE_SIZE)).step_by(4)
+        .map(|offset| 0x80000000 | offset as u32)
+        .filter(|address| is_node(read_word(rdram, address + QUEUE_MTQUEUE as u32).unwrap()) && is_node(read_word(rdram, address + QUEUE_FULLQUEUE as u32).unwrap()))
+        .filter_map(|address| read_queue(rdram, list, address))
+        .collect()
+}
+
+#[cfg(test)]
+mod os_threads_tests {
+    use super::*;
+
+    fn write_word(rdram: &mut [u8], address: u32, value: u32) {
+        let offset = (address & 0x1FFFFFFF) as usize;
+        rdram[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    #[test]
+    fn test_find_threads() {
+        let mut rdram = vec![0; 0x10000];
+        let tail = 0x80008000;
+        // __osThreadTail, __osRunQueue and __osActiveQueue
+        write_word(&mut rdram, tail + 4, u32::MAX);
+        write_word(&mut rdram, tail + 8, tail);
+        write_word(&mut rdram, tail + 12, 0x80001000);
+        for (address, next, id, priority, state) in [(0x80001000, 0x80002000, 3, 10, OS_STATE_RUNNING), (0x80002000, tail, 1, 0, OS_STATE_WAITING)] {
+            write_word(&mut rdram, address + THREAD_PRIORITY as u32, priority);
+            write_word(&mut rdram, address + THREAD_TLNEXT as u32, next);
+            write_word(&mut rdram, address + THREAD_STATE as u32, (state as u32) << 16);
+            write_word(&mut rdram, address + THREAD_ID as u32, id);
+            write_word(&mut rdram, address + THREAD_PC as u32, 0x80000400 + id);
+        }
+
+        assert_eq!(find_active_queue(&rdram), Some(tail + 12));
+        let list = threads(&rdram, None).unwrap();
+        assert_eq!(list.threads.len(), 2);
+        assert_eq!((list.threads[0].id, list.threads[0].priority, list.threads[0].state_name()), (3, 10, "Running"));
+        assert_eq!((list.threads[1].id, list.threads[1].pc, list.threads[1].state_name()), (1, 0x80000401, "Waiting"));
+        // A wrong symbol falls back to the search
+        assert_eq!(threads(&rdram, Some(0x80000000)).as_ref(), Some(&list));
+
+        // Thread 1 waits for a message, the other queue holds two of its four
+        write_word(&mut rdram, 0x80002000, tail);
+        let queues = [(0x80003000, 0x80002000, 0, 0, 1, 0x80003100), (0x80004000, tail, 2, 3, 4, 0x80004100)];
+        for (address, mtqueue, valid_count, first, msg_count, msg) in queues {
+            for (offset, value) in [mtqueue, tail, valid_count, first, msg_count, msg].iter().enumerate() {
+                write_word(&mut rdram, address + offset as u32 * 4, *value);
+            }
+        }
+        write_word(&mut rdram, 0x80004100 + 3 * 4, 0x1234);
+        let queues = message_queues(&rdram, &list);
+        assert_eq!(queues.len(), 2);
+        assert_eq!((queues[0].address, queues[0].first_message, queues[0].receivers.clone()), (0x80003000, None, vec![1]));
+        assert_eq!((queues[1].valid_count, queues[1].first_message, queues[1].senders.clone()), (2, Some(0x1234), vec![]));
+
+        // A loop that never reaches the tail isn't a thread list
+        write_word(&mut rdram, 0x80002000 + THREAD_TLNEXT as u32, 0x80001000);
+        assert_eq!(find_active_queue(&rdram), None);
+    }
+}
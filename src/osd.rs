@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a message stays on screen, the last part of it fading out
+pub const OSD_DURATION: Duration = Duration::from_secs(3);
+pub const OSD_FADE: Duration = Duration::from_millis(500);
+// Older messages are dropped when more than this are shown at once
+pub const OSD_MESSAGES: usize = 5;
+
+/*
+    Like the log, messages come from the emulator thread and the frontend, so they are queued globally
+    until the GUI picks them up.
+*/
+static PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn push(message: String) {
+    PENDING.lock().unwrap_or_else(|err| err.into_inner()).push(message);
+}
+
+pub fn take_pending() -> Vec<String> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+#[macro_export]
+macro_rules! osd {
+    ($($arg:tt)*) => {
+        $crate::osd::push(format!($($arg)*))
+    };
+}
+
+pub(crate) use osd;
+
+// Messages on screen and when they were shown
+pub struct OsdMessages {
+    messages: VecDeque<(String, Instant)>,
+}
+
+impl OsdMessages {
+    pub fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        for message in take_pending() {
+            self.push(message, now);
+        }
+        self.messages.retain(|(_, shown)| now.saturating_duration_since(*shown) < OSD_DURATION);
+    }
+
+    pub fn push(&mut self, message: String, now: Instant) {
+        if self.messages.len() == OSD_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((message, now));
+    }
+
+    // Oldest first, with the opacity from 0 to 1. Owned, so the frontend can keep them while it changes its state
+    pub fn visible(&self, now: Instant) -> Vec<(String, f32)> {
+        self.messages.iter().map(|(message, shown)| {
+            let remaining = OSD_DURATION.saturating_sub(now.saturating_duration_since(*shown));
+            (message.clone(), (remaining.as_secs_f32() / OSD_FADE.as_secs_f32()).min(1.0))
+        }).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod osd_tests {
+    use super::*;
+
+    #[test]
+    fn test_osd_messages() {
+        let start = Instant::now();
+        let mut messages = OsdMessages::new();
+        osd!("State saved to slot {}", 3);
+        messages.update(start);
+        assert!(messages.visible(start).contains(&("State saved to slot 3".to_string(), 1.0)));
+
+        for index in 0..OSD_MESSAGES {
+            messages.push(index.to_string(), start + Duration::from_secs(1));
+        }
+        assert_eq!(messages.visible(start).len(), OSD_MESSAGES);
+        let fading = start + OSD_DURATION + Duration::from_millis(750);
+        assert_eq!(messages.visible(fading)[0], ("0".to_string(), 0.5));
+        messages.update(start + OSD_DURATION * 2);
+        assert!(messages.is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+use crate::expression::RegisterName;
+use crate::log::{log, Level, Subsystem};
+use crate::registers::CPURegisters;
+
+// How many mismatches are kept before dropping the oldest ones
+pub const PARANOID_LOG_SIZE: usize = 256;
+
+/*
+    Paranoid mode runs every integer ALU instruction a second time through the model below and
+    compares the registers. The model is written straight from the VR4300 manual and shares no code
+    with cpu.rs, so a decoding or sign extension mistake would have to be made twice to go unnoticed.
+    Loads, stores, branches, the coprocessors and instructions that raised an exception aren't checked.
+    https://n64brew.dev/wiki/MIPS_III_instructions
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RegisterSnapshot {
+    pub gpr: [i64; 32],
+    pub hi: i64,
+    pub lo: i64,
+}
+
+impl RegisterSnapshot {
+    pub fn capture(registers: &CPURegisters) -> Self {
+        Self {
+            gpr: std::array::from_fn(|index| registers.get_by_number(index)),
+            hi: registers.get_hi(),
+            lo: registers.get_lo(),
+        }
+    }
+
+    fn set(&mut self, index: usize, value: i64) {
+        // r0 is hardwired to zero
+        if index != 0 {
+            self.gpr[index] = value;
+        }
+    }
+
+    fn differences(&self, actual: &RegisterSnapshot) -> Vec<(RegisterName, i64, i64)> {
+        let mut differences: Vec<_> = (0..32)
+            .filter(|index| self.gpr[*index] != actual.gpr[*index])
+            .map(|index| (RegisterName::GPR(index), self.gpr[index], actual.gpr[index]))
+            .collect();
+        if self.hi != actual.hi {
+            differences.push((RegisterName::Hi, self.hi, actual.hi));
+        }
+        if self.lo != actual.lo {
+            differences.push((RegisterName::Lo, self.lo, actual.lo));
+        }
+        differences
+    }
+}
+
+fn sign_extend_word(value: u32) -> i64 {
+    value as i32 as i64
+}
+
+/*
+    The registers after running the opcode, None for the instructions the model doesn't cover.
+    Overflowing ADD, SUB and their immediate and doubleword forms trap and leave the destination as it was.
+*/
+pub fn reference_result(opcode: u32, before: &RegisterSnapshot) -> Option<RegisterSnapshot> {
+    let rs = ((opcode >> 21) & 0x1F) as usize;
+    let rt = ((opcode >> 16) & 0x1F) as usize;
+    let rd = ((opcode >> 11) & 0x1F) as usize;
+    let sa = (opcode >> 6) & 0x1F;
+    let s = before.gpr[rs];
+    let t = before.gpr[rt];
+    let immediate = (opcode & 0xFFFF) as u16;
+    let signed_immediate = immediate as i16 as i64;
+    let mut after = *before;
+    match opcode >> 26 {
+        // SPECIAL
+        0x00 => match opcode & 0x3F {
+            0x00 => after.set(rd, sign_extend_word((t as u32) << sa)),
+            0x02 => after.set(rd, sign_extend_word((t as u32) >> sa)),
+            0x03 => after.set(rd, sign_extend_word(((t as i32) >> sa) as u32)),
+            0x04 => after.set(rd, sign_extend_word((t as u32) << (s & 0x1F))),
+            0x06 => after.set(rd, sign_extend_word((t as u32) >> (s & 0x1F))),
+            0x07 => after.set(rd, sign_extend_word(((t as i32) >> (s & 0x1F)) as u32)),
+            0x10 => after.set(rd, before.hi),
+            0x11 => after.hi = s,
+            0x12 => after.set(rd, before.lo),
+            0x13 => after.lo = s,
+            0x14 => after.set(rd, t << (s & 0x3F)),
+            0x16 => after.set(rd, ((t as u64) >> (s & 0x3F)) as i64),
+            0x17 => after.set(rd, t >> (s & 0x3F)),
+            0x18 => {
+                let product = (s as i32 as i64) * (t as i32 as i64);
+                after.lo = sign_extend_word(product as u32);
+                after.hi = sign_extend_word((product >> 32) as u32);
+            },
+            0x19 => {
+                let product = (s as u32 as u64) * (t as u32 as u64);
+                after.lo = sign_extend_word(product as u32);
+                after.hi = sign_extend_word((product >> 32) as u32);
+            },
+            0x1A => {
+                let (dividend, divisor) = (s as i32, t as i32);
+                let (quotient, remainder) = match divisor {
+                    0 => (if dividend < 0 { 1 } else { -1 }, dividend),
+                    _ => (dividend.wrapping_div(divisor), dividend.wrapping_rem(divisor)),
+                };
+                after.lo = quotient as i64;
+                after.hi = remainder as i64;
+            },
+            0x1B => {
+                let (dividend, divisor) = (s as u32, t as u32);
+                let (quotient, remainder) = match divisor {
+                    0 => (u32::MAX, dividend),
+                    _ => (dividend / divisor, dividend % divisor),
+                };
+                after.lo = sign_extend_word(quotient);
+                after.hi = sign_extend_word(remainder);
+            },
+            0x1C => {
+                let product = (s as i128) * (t as i128);
+                after.lo = product as i64;
+                after.hi = (product >> 64) as i64;
+            },
+            0x1D => {
+                let product = (s as u64 as u128) * (t as u64 as u128);
+                after.lo = product as i64;
+                after.hi = (product >> 64) as i64;
+            },
+            0x1E => {
+                let (quotient, remainder) = match t {
+                    0 => (if s < 0 { 1 } else { -1 }, s),
+                    _ => (s.wrapping_div(t), s.wrapping_rem(t)),
+                };
+                after.lo = quotient;
+                after.hi = remainder;
+            },
+            0x1F => {
+                let (quotient, remainder) = match t {
+                    0 => (u64::MAX, s as u64),
+                    _ => ((s as u64) / (t as u64), (s as u64) % (t as u64)),
+                };
+                after.lo = quotient as i64;
+                after.hi = remainder as i64;
+            },
+            0x20 => if let Some(sum) = (s as i32).checked_add(t as i32) {
+                after.set(rd, sum as i64);
+            },
+            0x21 => after.set(rd, (s as i32).wrapping_add(t as i32) as i64),
+            0x22 => if let Some(difference) = (s as i32).checked_sub(t as i32) {
+                after.set(rd, difference as i64);
+            },
+            0x23 => after.set(rd, (s as i32).wrapping_sub(t as i32) as i64),
+            0x24 => after.set(rd, s & t),
+            0x25 => after.set(rd, s | t),
+            0x26 => after.set(rd, s ^ t),
+            0x27 => after.set(rd, !(s | t)),
+            0x2A => after.set(rd, (s < t) as i64),
+            0x2B => after.set(rd, ((s as u64) < (t as u64)) as i64),
+            0x2C => if let Some(sum) = s.checked_add(t) {
+                after.set(rd, sum);
+            },
+            0x2D => after.set(rd, s.wrapping_add(t)),
+            0x2E => if let Some(difference) = s.checked_sub(t) {
+                after.set(rd, difference);
+            },
+            0x2F => after.set(rd, s.wrapping_sub(t)),
+            0x38 => after.set(rd, t << sa),
+            0x3A => after.set(rd, ((t as u64) >> sa) as i64),
+            0x3B => after.set(rd, t >> sa),
+            0x3C => after.set(rd, t << (sa + 32)),
+            0x3E => after.set(rd, ((t as u64) >> (sa + 32)) as i64),
+            0x3F => after.set(rd, t >> (sa + 32)),
+            _ => return None,
+        },
+        0x08 => if let Some(sum) = (s as i32).checked_add(signed_immediate as i32) {
+            after.set(rt, sum as i64);
+        },
+        0x09 => after.set(rt, (s as i32).wrapping_add(signed_immediate as i32) as i64),
+        0x0A => after.set(rt, (s < signed_immediate) as i64),
+        // The immediate is sign extended and then compared as unsigned
+        0x0B => after.set(rt, ((s as u64) < (signed_immediate as u64)) as i64),
+        0x0C => after.set(rt, s & immediate as i64),
+        0x0D => after.set(rt, s | immediate as i64),
+        0x0E => after.set(rt, s ^ immediate as i64),
+        0x0F => after.set(rt, sign_extend_word((immediate as u32) << 16)),
+        0x18 => if let Some(sum) = s.checked_add(signed_immediate) {
+            after.set(rt, sum);
+        },
+        0x19 => after.set(rt, s.wrapping_add(signed_immediate)),
+        _ => return None,
+    };
+    Some(after)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParanoidMismatch {
+    pub address: i64,
+    pub opcode: u32,
+    pub register: RegisterName,
+    pub expected: i64,
+    pub actual: i64,
+}
+
+impl fmt::Display for ParanoidMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016X}: {:08X} {} expected {:016X}, got {:016X}", self.address, self.opcode, self.register, self.expected, self.actual)
+    }
+}
+
+pub struct ParanoidChecker {
+    mismatches: VecDeque<ParanoidMismatch>,
+    checked: u64,
+    // Only the first mismatch of every instruction is logged, loops would flood the log otherwise
+    reported: HashSet<i64>,
+}
+
+impl ParanoidChecker {
+    pub fn new() -> Self {
+        Self {
+            mismatches: VecDeque::with_capacity(PARANOID_LOG_SIZE),
+            checked: 0,
+            reported: HashSet::new(),
+        }
+    }
+
+    // Called after running the instruction at address, with the registers from before running it
+    pub fn check(&mut self, address: i64, opcode: u32, before: &RegisterSnapshot, registers: &CPURegisters) {
+        let expected = match reference_result(opcode, before) {
+            Some(expected) => expected,
+            None => return,
+        };
+        self.checked += 1;
+        for (register, expected, actual) in expected.differences(&RegisterSnapshot::capture(registers)) {
+            let mismatch = ParanoidMismatch {
+                address,
+                opcode,
+                register,
+                expected,
+                actual,
+            };
+            if self.reported.insert(address) {
+                log!(Level::Warn, Subsystem::CPU, "Paranoid mode mismatch at {}", mismatch);
+            }
+            if self.mismatches.len() == PARANOID_LOG_SIZE {
+                self.mismatches.pop_front();
+            }
+            self.mismatches.push_back(mismatch);
+        }
+    }
+
+    pub fn mismatches(&self) -> &VecDeque<ParanoidMismatch> {
+        &self.mismatches
+    }
+
+    // Instructions the model covered
+    pub fn checked(&self) -> u64 {
+        self.checked
+    }
+
+    pub fn clear(&mut self) {
+        self.mismatches.clear();
+        self.checked = 0;
+        self.reported.clear();
+    }
+}
+
+#[cfg(test)]
+mod paranoid_tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_and_check() {
+        let mut registers = CPURegisters::new();
+        registers.set_by_number(8, 0xFFFFFFFF80000000_u64 as i64);
+        registers.set_by_number(9, 4);
+        let before = RegisterSnapshot::capture(&registers);
+
+        // sra t2, t0, 4
+        let after = reference_result(0x00085103, &before).unwrap();
+        assert_eq!(after.gpr[10], 0xFFFFFFFFF8000000_u64 as i64);
+        // srlv t2, t0, t1
+        let after = reference_result(0x01285006, &before).unwrap();
+        assert_eq!(after.gpr[10], 0x08000000);
+        // sltiu t2, t1, -1
+        let after = reference_result(0x2D2AFFFF, &before).unwrap();
+        assert_eq!(after.gpr[10], 1);
+        // add t2, t0, t0 overflows and leaves t2 alone
+        assert_eq!(reference_result(0x01085020, &before), Some(before));
+        // lw t2, 0(t0) isn't covered
+        assert_eq!(reference_result(0x8D0A0000, &before), None);
+
+        let mut checker = ParanoidChecker::new();
+        // A right shift that forgot the sign
+        registers.set_by_number(10, 0x08000000);
+        checker.check(0x80001000, 0x00085103, &before, &registers);
+        assert_eq!(checker.checked(), 1);
+        assert_eq!(checker.mismatches().len(), 1);
+        let mismatch = checker.mismatches()[0];
+        assert_eq!(mismatch.register, RegisterName::GPR(10));
+        assert_eq!(mismatch.expected, 0xFFFFFFFFF8000000_u64 as i64);
+        assert_eq!(mismatch.to_string(), "0000000080001000: 00085103 t2 expected FFFFFFFFF8000000, got 0000000008000000");
+
+        registers.set_by_number(10, 0xFFFFFFFFF8000000_u64 as i64);
+        checker.check(0x80001000, 0x00085103, &before, &registers);
+        assert_eq!(checker.mismatches().len(), 1);
+        checker.clear();
+        assert!(checker.mismatches().is_empty());
+    }
+}
@@ -0,0 +1,580 @@
+use std::io::{Error, ErrorKind, Result};
+
+use flate2::Crc;
+
+use crate::rom::MAX_ROM_SIZE;
+
+// Patches with one of these extensions next to the ROM are applied when it gets loaded
+pub const PATCH_EXTENSIONS: [&str; 4] = ["ips", "bps", "xdelta", "vcdiff"];
+
+pub const IPS_MAGIC: &[u8; 5] = b"PATCH";
+pub const BPS_MAGIC: &[u8; 4] = b"BPS1";
+pub const VCDIFF_MAGIC: &[u8; 4] = &[0xD6, 0xC3, 0xC4, 0x00];
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.position.checked_add(len).and_then(|end| self.data.get(self.position..end)) {
+            Some(bytes) => {
+                self.position += len;
+                Ok(bytes)
+            },
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "Patch is truncated")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> Result<usize> {
+        let bytes = self.bytes(2)?;
+        Ok(((bytes[0] as usize) << 8) | bytes[1] as usize)
+    }
+
+    fn u24_be(&mut self) -> Result<usize> {
+        let bytes = self.bytes(3)?;
+        Ok(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+
+    // BPS numbers are little endian base 128 with the continuation implied by a clear top bit
+    fn bps_number(&mut self) -> Result<usize> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()? as usize;
+            data = data.checked_add((byte & 0x7F).checked_mul(shift).ok_or_else(|| invalid("Invalid BPS number"))?)
+                .ok_or_else(|| invalid("Invalid BPS number"))?;
+            if byte & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(|| invalid("Invalid BPS number"))?;
+            data = data.checked_add(shift).ok_or_else(|| invalid("Invalid BPS number"))?;
+        }
+    }
+
+    // VCDIFF numbers are big endian base 128 with the top bit set on every byte but the last
+    fn vcdiff_number(&mut self) -> Result<usize> {
+        let mut data: usize = 0;
+        for _ in 0..10 {
+            let byte = self.u8()?;
+            data = (data << 7) | (byte & 0x7F) as usize;
+            if byte & 0x80 == 0 {
+                return Ok(data);
+            }
+        }
+        Err(invalid("Invalid VCDIFF number"))
+    }
+}
+
+// https://zerosoft.zophar.net/ips.php
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = PatchReader::new(patch);
+    if reader.bytes(5)? != IPS_MAGIC {
+        return Err(invalid("Not an IPS patch"));
+    }
+    let mut target = source.to_vec();
+    loop {
+        let offset = reader.u24_be()?;
+        if offset == 0x454F46 { // "EOF"
+            break;
+        }
+        let size = reader.u16_be()?;
+        let (len, data) = match size {
+            0 => {
+                let len = reader.u16_be()?;
+                (len, vec![reader.u8()?; len])
+            },
+            _ => (size, reader.bytes(size)?.to_vec()),
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        target[offset..offset + len].copy_from_slice(&data);
+    }
+    // Some patches append the size the file has to be truncated to
+    if let Ok(size) = reader.u24_be() {
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+fn relative_offset(reader: &mut PatchReader, position: &mut usize) -> Result<()> {
+    let data = reader.bps_number()?;
+    let offset = data >> 1;
+    *position = match data & 1 {
+        0 => position.checked_add(offset),
+        _ => position.checked_sub(offset),
+    }.ok_or_else(|| invalid("Invalid BPS copy offset"))?;
+    Ok(())
+}
+
+// https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.len() < 16 || &patch[..4] != BPS_MAGIC {
+        return Err(invalid("Not a BPS patch"));
+    }
+    let footer = &patch[patch.len() - 12..];
+    let source_crc = u32::from_le_bytes(footer[0..4].try_into().unwrap());
+    let target_crc = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+    let patch_crc = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(invalid("The BPS patch is corrupted"));
+    }
+    if crc32(source) != source_crc {
+        return Err(invalid("The BPS patch was made for a different ROM"));
+    }
+
+    let mut reader = PatchReader::new(&patch[..patch.len() - 12]);
+    reader.bytes(4)?;
+    let source_size = reader.bps_number()?;
+    let target_size = reader.bps_number()?;
+    let metadata_size = reader.bps_number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err(invalid("The BPS patch was made for a different ROM"));
+    }
+
+    // The size comes from the patch, only what a cartridge can hold is reserved up front
+    let mut target = Vec::with_capacity(target_size.min(MAX_ROM_SIZE));
+    let mut source_relative = 0;
+    let mut target_relative = 0;
+    while !reader.is_empty() {
+        let data = reader.bps_number()?;
+        let len = (data >> 2) + 1;
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let position = target.len();
+                let bytes = position.checked_add(len).and_then(|end| source.get(position..end)).ok_or_else(|| invalid("Invalid BPS source read"))?;
+                target.extend_from_slice(bytes);
+            },
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy
+            2 => {
+                relative_offset(&mut reader, &mut source_relative)?;
+                let bytes = source_relative.checked_add(len).and_then(|end| source.get(source_relative..end))
+                    .ok_or_else(|| invalid("Invalid BPS source copy"))?;
+                target.extend_from_slice(bytes);
+                source_relative += len;
+            },
+            // TargetCopy, the ranges can overlap so it has to go byte by byte
+            _ => {
+                relative_offset(&mut reader, &mut target_relative)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_relative).ok_or_else(|| invalid("Invalid BPS target copy"))?;
+                    target.push(byte);
+                    target_relative += 1;
+                }
+            },
+        };
+    }
+
+    if target.len() != target_size || crc32(&target) != target_crc {
+        return Err(invalid("The BPS patch produced an invalid ROM"));
+    }
+    Ok(target)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum VcdiffInstruction {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+// (instruction, size, mode) pairs of the default code table, RFC 3284 section 5.6
+fn vcdiff_code_table() -> Vec<[(VcdiffInstruction, usize, usize); 2]> {
+    use VcdiffInstruction::*;
+    let noop = (Noop, 0, 0);
+    let mut table = vec![[(Run, 0, 0), noop]];
+    for size in 0..=17 {
+        table.push([(Add, size, 0), noop]);
+    }
+    for mode in 0..=8 {
+        table.push([(Copy, 0, mode), noop]);
+        for size in 4..=18 {
+            table.push([(Copy, size, mode), noop]);
+        }
+    }
+    for mode in 0..=5 {
+        for add_size in 1..=4 {
+            for copy_size in 4..=6 {
+                table.push([(Add, add_size, 0), (Copy, copy_size, mode)]);
+            }
+        }
+    }
+    for mode in 6..=8 {
+        for add_size in 1..=4 {
+            table.push([(Add, add_size, 0), (Copy, 4, mode)]);
+        }
+    }
+    for mode in 0..=8 {
+        table.push([(Copy, 4, mode), (Add, 1, 0)]);
+    }
+    table
+}
+
+const VCDIFF_NEAR_SIZE: usize = 4;
+const VCDIFF_SAME_SIZE: usize = 3;
+
+struct AddressCache {
+    near: [usize; VCDIFF_NEAR_SIZE],
+    next_slot: usize,
+    same: Vec<usize>,
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; VCDIFF_NEAR_SIZE],
+            next_slot: 0,
+            same: vec![0; VCDIFF_SAME_SIZE * 256],
+        }
+    }
+
+    fn decode(&mut self, reader: &mut PatchReader, here: usize, mode: usize) -> Result<usize> {
+        let address = match mode {
+            0 => reader.vcdiff_number()?,
+            1 => here.checked_sub(reader.vcdiff_number()?).ok_or_else(|| invalid("Invalid VCDIFF address"))?,
+            _ if mode < 2 + VCDIFF_NEAR_SIZE => self.near[mode - 2] + reader.vcdiff_number()?,
+            _ => self.same[(mode - 2 - VCDIFF_NEAR_SIZE) * 256 + reader.u8()? as usize],
+        };
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % VCDIFF_NEAR_SIZE;
+        self.same[address % (VCDIFF_SAME_SIZE * 256)] = address;
+        Ok(address)
+    }
+}
+
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+/*
+    VCDIFF as written by xdelta3, https://datatracker.ietf.org/doc/html/rfc3284
+    Secondary compression and custom code tables are not supported, patches have to be made with `xdelta3 -S none`.
+*/
+pub fn apply_vcdiff(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = PatchReader::new(patch);
+    if reader.bytes(4)? != VCDIFF_MAGIC {
+        return Err(invalid("Not a VCDIFF patch"));
+    }
+    let header_indicator = reader.u8()?;
+    if header_indicator & (VCD_DECOMPRESS | VCD_CODETABLE) != 0 {
+        return Err(invalid("VCDIFF patches with secondary compression or custom code tables are not supported"));
+    }
+    if header_indicator & VCD_APPHEADER != 0 {
+        let len = reader.vcdiff_number()?;
+        reader.bytes(len)?;
+    }
+
+    let code_table = vcdiff_code_table();
+    let mut target: Vec<u8> = Vec::new();
+    while !reader.is_empty() {
+        let window_indicator = reader.u8()?;
+        let segment = match window_indicator & (VCD_SOURCE | VCD_TARGET) {
+            0 => Vec::new(),
+            indicator => {
+                let len = reader.vcdiff_number()?;
+                let position = reader.vcdiff_number()?;
+                let data = match indicator {
+                    VCD_SOURCE => source,
+                    VCD_TARGET => &target,
+                    _ => return Err(invalid("Invalid VCDIFF window")),
+                };
+                position.checked_add(len).and_then(|end| data.get(position..end)).ok_or_else(|| invalid("Invalid VCDIFF source segment"))?.to_vec()
+            },
+        };
+        reader.vcdiff_number()?; // Length of the delta encoding
+        let window_size = reader.vcdiff_number()?;
+        if reader.u8()? != 0 {
+            return Err(invalid("Compressed VCDIFF sections are not supported"));
+        }
+        let data_len = reader.vcdiff_number()?;
+        let instructions_len = reader.vcdiff_number()?;
+        let addresses_len = reader.vcdiff_number()?;
+        if window_indicator & VCD_ADLER32 != 0 {
+            reader.bytes(4)?;
+        }
+        let mut data = PatchReader::new(reader.bytes(data_len)?);
+        let mut instructions = PatchReader::new(reader.bytes(instructions_len)?);
+        let mut addresses = PatchReader::new(reader.bytes(addresses_len)?);
+
+        let mut cache = AddressCache::new();
+        let mut window: Vec<u8> = Vec::with_capacity(window_size.min(MAX_ROM_SIZE));
+        while !instructions.is_empty() {
+            let entry = code_table[instructions.u8()? as usize];
+            for (instruction, size, mode) in entry {
+                if instruction == VcdiffInstruction::Noop {
+                    continue;
+                }
+                let size = match size {
+                    0 => instructions.vcdiff_number()?,
+                    _ => size,
+                };
+                match instruction {
+                    VcdiffInstruction::Add => window.extend_from_slice(data.bytes(size)?),
+                    VcdiffInstruction::Run => {
+                        let byte = data.u8()?;
+                        window.resize(window.len() + size, byte);
+                    },
+                    VcdiffInstruction::Copy => {
+                        let here = segment.len() + window.len();
+                        let start = cache.decode(&mut addresses, here, mode)?;
+                        for address in start..start + size {
+                            let byte = match address < segment.len() {
+                                true => segment[address],
+                                false => *window.get(address - segment.len()).ok_or_else(|| invalid("Invalid VCDIFF copy"))?,
+                            };
+                            window.push(byte);
+                        }
+                    },
+                    VcdiffInstruction::Noop => {},
+                };
+            }
+        }
+        if window.len() != window_size {
+            return Err(invalid("The VCDIFF patch produced an invalid window"));
+        }
+        target.extend_from_slice(&window);
+    }
+    Ok(target)
+}
+
+// Picks the format from the patch contents instead of trusting the file extension
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(source, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(source, patch)
+    } else if patch.starts_with(VCDIFF_MAGIC) {
+        apply_vcdiff(source, patch)
+    } else {
+        Err(invalid("Unknown patch format"))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CIC {
+    CIC6101,
+    CIC6102,
+    CIC6103,
+    CIC6105,
+    CIC6106,
+}
+
+impl CIC {
+    // Identified by the CRC32 of the IPL3 boot code stored after the header
+    pub fn detect(rom: &[u8]) -> Option<Self> {
+        match crc32(rom.get(0x40..0x1000)?) {
+            0x6170A4A1 => Some(Self::CIC6101),
+            0x90BB6CB5 => Some(Self::CIC6102),
+            0x0B050EE0 => Some(Self::CIC6103),
+            0x98BC2C86 => Some(Self::CIC6105),
+            0xACC8580A => Some(Self::CIC6106),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CIC6101 => "6101",
+            Self::CIC6102 => "6102",
+            Self::CIC6103 => "6103",
+            Self::CIC6105 => "6105",
+            Self::CIC6106 => "6106",
+        }
+    }
+
+    fn seed(&self) -> u32 {
+        match self {
+            Self::CIC6101 | Self::CIC6102 => 0xF8CA4DDC,
+            Self::CIC6103 => 0xA3886759,
+            Self::CIC6105 => 0xDF26F436,
+            Self::CIC6106 => 0x1FEA617A,
+        }
+    }
+}
+
+fn read_u32(rom: &[u8], address: usize) -> u32 {
+    u32::from_be_bytes(rom[address..address + 4].try_into().unwrap())
+}
+
+/*
+    The checksum IPL3 verifies over the first megabyte of the game, stored at 0x10 in the header.
+    https://n64brew.dev/wiki/ROM_Header#CRC
+*/
+pub fn calculate_crc(rom: &[u8], cic: CIC) -> Option<(u32, u32)> {
+    if rom.len() < 0x101000 {
+        return None;
+    }
+    let seed = cic.seed();
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+    for address in (0x1000..0x101000).step_by(4) {
+        let data = read_u32(rom, address);
+        if t6.wrapping_add(data) < t6 {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = t6.wrapping_add(data);
+        t3 ^= data;
+        let rotated = data.rotate_left(data & 0x1F);
+        t5 = t5.wrapping_add(rotated);
+        t2 = match t2 > data {
+            true => t2 ^ rotated,
+            false => t2 ^ t6 ^ data,
+        };
+        t1 = match cic {
+            CIC::CIC6105 => t1.wrapping_add(read_u32(rom, 0x0750 + (address & 0xFF)) ^ data),
+            _ => t1.wrapping_add(t5 ^ data),
+        };
+    }
+    Some(match cic {
+        CIC::CIC6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        CIC::CIC6106 => (t6.wrapping_mul(t4).wrapping_add(t3), t5.wrapping_mul(t2).wrapping_add(t1)),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    })
+}
+
+// Returns whether the CRC could be updated, ROMs with an unknown CIC are left untouched
+pub fn fix_crc(rom: &mut [u8]) -> bool {
+    let crc = CIC::detect(rom).and_then(|cic| calculate_crc(rom, cic));
+    match crc {
+        Some((crc1, crc2)) => {
+            rom[0x10..0x14].copy_from_slice(&crc1.to_be_bytes());
+            rom[0x14..0x18].copy_from_slice(&crc2.to_be_bytes());
+            true
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod patch_tests {
+    use super::*;
+
+    fn bps_number(mut data: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (data & 0x7F) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | byte);
+                break;
+            }
+            out.push(byte);
+            data -= 1;
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, b'X', b'Y']);
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, b'Z']);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(b"abcd", &patch).unwrap(), b"aXYd\0ZZZ");
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"hello world";
+        let mut patch = BPS_MAGIC.to_vec();
+        bps_number(source.len(), &mut patch);
+        bps_number(17, &mut patch);
+        bps_number(0, &mut patch);
+        bps_number((5 - 1) << 2, &mut patch); // SourceRead "hello"
+        bps_number(((3 - 1) << 2) | 1, &mut patch); // TargetRead ", w"
+        patch.extend_from_slice(b", w");
+        bps_number(((4 - 1) << 2) | 2, &mut patch); // SourceCopy "orld" from 7
+        bps_number(7 << 1, &mut patch);
+        bps_number(((5 - 1) << 2) | 3, &mut patch); // TargetCopy "hello" from 0
+        bps_number(0, &mut patch);
+        let target = b"hello, worldhello";
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        assert_eq!(apply(source, &patch).unwrap(), target);
+        assert!(apply(b"hello there", &patch).is_err());
+    }
+
+    #[test]
+    fn test_bps_invalid() {
+        let source = b"hello world";
+        // Sizes and lengths that overflow, in patches whose own CRC is right
+        let patches: [&[usize]; 4] = [
+            &[source.len(), 5, usize::MAX - 1],
+            &[source.len(), usize::MAX, 0],
+            &[source.len(), 5, 0, (5 - 1) << 2, usize::MAX & !3],
+            &[source.len(), 5, 0, 2, 2 << 1, usize::MAX & !3 | 2, usize::MAX & !1],
+        ];
+        for numbers in patches {
+            let mut patch = BPS_MAGIC.to_vec();
+            for number in numbers {
+                bps_number(*number, &mut patch);
+            }
+            patch.extend_from_slice(&crc32(source).to_le_bytes());
+            patch.extend_from_slice(&[0; 4]);
+            let patch_crc = crc32(&patch);
+            patch.extend_from_slice(&patch_crc.to_le_bytes());
+            assert!(apply(source, &patch).is_err());
+        }
+        // A number longer than usize
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x7F; 12]);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&[0; 4]);
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        assert_eq!(apply(source, &patch).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_vcdiff() {
+        let mut patch = VCDIFF_MAGIC.to_vec();
+        patch.push(0x00);
+        // COPY 6 bytes from the source and ADD "X"
+        patch.extend_from_slice(&[VCD_SOURCE, 6, 0, 9, 7, 0, 1, 2, 1, b'X', 22, 2, 0]);
+        // RUN of three "a"
+        patch.extend_from_slice(&[0, 8, 3, 0, 1, 2, 0, b'a', 0, 3]);
+        assert_eq!(apply(b"abcdef", &patch).unwrap(), b"abcdefXaaa");
+    }
+
+    #[test]
+    fn test_fix_crc() {
+        let mut rom = vec![0; 0x101000];
+        assert!(!fix_crc(&mut rom));
+        assert_eq!(calculate_crc(&rom, CIC::CIC6102), Some((0xF8CA4DDC, 0xF8CA4DDC_u32.wrapping_mul(0x40001))));
+        assert_eq!(calculate_crc(&rom[..0x1000], CIC::CIC6102), None);
+    }
+}
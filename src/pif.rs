@@ -0,0 +1,625 @@
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::controller_pak::{ControllerPak, CONTROLLER_PAK_SIZE};
+use crate::rom::ROM;
+use crate::save::SaveType;
+use crate::savestate::{StateReader, StateWriter};
+use crate::scheduler::CPU_CLOCK_RATE;
+
+pub const PIF_RAM_SIZE: usize = 64;
+pub const CONTROLLER_PORTS: usize = 4;
+
+// Bits of the first two bytes of the controller state: https://n64brew.dev/wiki/Joybus_Protocol#0x01_-_Read_Controller_State
+pub const BUTTON_A: u16 = 1 << 15;
+pub const BUTTON_B: u16 = 1 << 14;
+pub const BUTTON_Z: u16 = 1 << 13;
+pub const BUTTON_START: u16 = 1 << 12;
+pub const BUTTON_D_UP: u16 = 1 << 11;
+pub const BUTTON_D_DOWN: u16 = 1 << 10;
+pub const BUTTON_D_LEFT: u16 = 1 << 9;
+pub const BUTTON_D_RIGHT: u16 = 1 << 8;
+pub const BUTTON_L: u16 = 1 << 5;
+pub const BUTTON_R: u16 = 1 << 4;
+pub const BUTTON_C_UP: u16 = 1 << 3;
+pub const BUTTON_C_DOWN: u16 = 1 << 2;
+pub const BUTTON_C_LEFT: u16 = 1 << 1;
+pub const BUTTON_C_RIGHT: u16 = 1 << 0;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub buttons: u16,
+    pub x: i8,
+    pub y: i8,
+}
+
+// The cartridge comes after the controllers
+pub const CARTRIDGE_CHANNEL: usize = CONTROLLER_PORTS;
+
+/*
+    EEPROM reads and writes go 8 bytes at a time. A write keeps it busy for about 15ms, shown in
+    the last byte of the Info response and of the write's own, and other writes are dropped meanwhile.
+    https://n64brew.dev/wiki/Joybus_Protocol#0x05_-_Write_EEPROM
+*/
+pub const EEPROM_BLOCK_SIZE: usize = 8;
+pub const EEPROM_BUSY: u8 = 0x80;
+pub const EEPROM_WRITE_CYCLES: u64 = CPU_CLOCK_RATE * 15 / 1000;
+
+// Last byte of the Info response of a controller: https://n64brew.dev/wiki/Joybus_Protocol#0x00_-_Info
+pub const PAK_PRESENT: u8 = 1 << 0;
+// Set once the pak is swapped, until the next Info or reset. libultra reports it as CONT_CARD_PULL
+pub const PAK_CHANGED: u8 = 1 << 1;
+
+// Accessory reads and writes move 32 bytes, the low 5 bits of their address are a CRC of the rest
+pub const PAK_BLOCK_SIZE: usize = 32;
+
+// Rumble Pak: reads of its ID range give 0x80, bit 0 of a write to the motor range turns it on or off
+const RUMBLE_PAK_ID: u8 = 0x80;
+const RUMBLE_PAK_ID_RANGE: RangeInclusive<u16> = 0x8000..=0x8FFF;
+const RUMBLE_PAK_MOTOR_RANGE: RangeInclusive<u16> = 0xC000..=0xCFFF;
+
+// What's plugged into the slot of a controller
+pub enum Pak {
+    ControllerPak(ControllerPak),
+    RumblePak { rumbling: bool },
+}
+
+impl Pak {
+    pub fn kind(&self) -> PakKind {
+        match self {
+            Pak::ControllerPak(_) => PakKind::ControllerPak,
+            Pak::RumblePak { .. } => PakKind::RumblePak,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PakKind {
+    None,
+    ControllerPak,
+    RumblePak,
+}
+
+impl PakKind {
+    pub const ALL: [PakKind; 3] = [PakKind::None, PakKind::ControllerPak, PakKind::RumblePak];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PakKind::None => "None",
+            PakKind::ControllerPak => "Controller Pak",
+            PakKind::RumblePak => "Rumble Pak",
+        }
+    }
+}
+
+/*
+    CRC-8 with polynomial 0x85 the controller appends to accessory reads and writes, over the
+    32 bytes and then a zero byte. Without a pak the controller sends it inverted, which is how
+    libultra tells PFS_ERR_NOPACK from a transfer error.
+    https://n64brew.dev/wiki/Joybus_Protocol#Data_CRC
+*/
+pub fn pak_data_crc(data: &[u8]) -> u8 {
+    let mut crc = 0_u8;
+    for byte in data.iter().chain(std::iter::once(&0)) {
+        for bit in (0..8).rev() {
+            let tap = match crc & 0x80 != 0 {
+                true => 0x85,
+                false => 0,
+            };
+            crc = ((crc << 1) | ((byte >> bit) & 1)) ^ tap;
+        }
+    }
+    crc
+}
+
+// https://n64brew.dev/wiki/Joybus_Protocol#Command_Set
+pub fn joybus_command_name(command: u8) -> &'static str {
+    match command {
+        0x00 => "Info",
+        0x01 => "Read controller state",
+        0x02 => "Read controller accessory",
+        0x03 => "Write controller accessory",
+        0x04 => "Read EEPROM",
+        0x05 => "Write EEPROM",
+        0x06 => "RTC info",
+        0x07 => "Read RTC block",
+        0x08 => "Write RTC block",
+        0xFF => "Reset",
+        _ => "Unknown",
+    }
+}
+
+// A command found in PIF RAM and the space reserved for its response
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct JoybusFrame {
+    pub channel: usize,
+    // Offset of the length byte of the command
+    pub offset: usize,
+    pub command: Vec<u8>,
+    pub response: Vec<u8>,
+    // Set by the PIF in the response length byte when nothing answered
+    pub no_device: bool,
+}
+
+/*
+    Splits PIF RAM into the commands of each channel. 0x00 skips a channel, 0xFD and 0xFF are padding
+    and 0xFE ends the list. https://n64brew.dev/wiki/PIF-NUS#Joybus_commands
+*/
+pub fn joybus_frames(ram: &[u8; PIF_RAM_SIZE]) -> Vec<JoybusFrame> {
+    let mut frames = Vec::new();
+    let mut channel = 0;
+    let mut offset = 0;
+    while offset < PIF_RAM_SIZE - 1 {
+        let tx = ram[offset];
+        match tx {
+            0xFE => break,
+            0xFD | 0xFF => {
+                offset += 1;
+                continue;
+            },
+            0x00 => {
+                channel += 1;
+                offset += 1;
+                continue;
+            },
+            _ => {},
+        };
+        let command_start = offset + 2;
+        let response_start = command_start + (tx & 0x3F) as usize;
+        let rx = ram[(offset + 1) % PIF_RAM_SIZE];
+        let rx_len = (rx & 0x3F) as usize;
+        if response_start + rx_len > PIF_RAM_SIZE - 1 {
+            break;
+        }
+        frames.push(JoybusFrame {
+            channel,
+            offset,
+            command: ram[command_start..response_start].to_vec(),
+            response: ram[response_start..response_start + rx_len].to_vec(),
+            no_device: rx & 0x80 != 0,
+        });
+        offset = response_start + rx_len;
+        channel += 1;
+    }
+    frames
+}
+
+/*
+    PIF RAM and the Joybus devices behind it. Only standard controllers and their paks are answered,
+    the cartridge channel replies as if nothing was connected, unless a real controller is passed through.
+    https://n64brew.dev/wiki/PIF-NUS
+*/
+// serde only has arrays up to 32 elements, the RAM goes through a Vec
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "PIF")]
+struct PIFState {
+    #[serde(with = "crate::savestate::bytes")]
+    ram: Vec<u8>,
+    controllers: [ControllerState; CONTROLLER_PORTS],
+    connected: [bool; CONTROLLER_PORTS],
+    #[serde(default)]
+    eeprom_busy_cycles: u64,
+}
+
+// Something on a controller port that answers the Joybus commands itself, like a real controller
+pub trait JoybusDevice: Send + Sync {
+    // The command byte and its data. None when nothing answered
+    fn command(&mut self, command: &[u8]) -> Option<Vec<u8>>;
+}
+
+pub struct PIF {
+    ram: [u8; PIF_RAM_SIZE],
+    controllers: [ControllerState; CONTROLLER_PORTS],
+    connected: [bool; CONTROLLER_PORTS],
+    // Ports whose commands are forwarded, including the accessory reads and writes
+    passthrough: [Option<Box<dyn JoybusDevice>>; CONTROLLER_PORTS],
+    // Not part of the savestates, Controller Paks are saved in their own files
+    paks: [Option<Pak>; CONTROLLER_PORTS],
+    // Ports whose pak was swapped since the game last asked for their status
+    pak_changed: [bool; CONTROLLER_PORTS],
+    // CPU cycles until the EEPROM is done writing
+    eeprom_busy_cycles: u64,
+    // Buttons of each port pressed and released on their own while held, and the frames that takes
+    turbo_buttons: [u16; CONTROLLER_PORTS],
+    turbo_periods: [u32; CONTROLLER_PORTS],
+    // Vertical interrupts so far, the turbo buttons follow it
+    frame: u64,
+}
+
+impl PIF {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; PIF_RAM_SIZE],
+            controllers: [ControllerState::default(); CONTROLLER_PORTS],
+            connected: [true, false, false, false],
+            passthrough: Default::default(),
+            paks: Default::default(),
+            pak_changed: [false; CONTROLLER_PORTS],
+            eeprom_busy_cycles: 0,
+            turbo_buttons: [0; CONTROLLER_PORTS],
+            turbo_periods: [1; CONTROLLER_PORTS],
+            frame: 0,
+        }
+    }
+
+    pub fn ram(&self) -> &[u8; PIF_RAM_SIZE] {
+        &self.ram
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        self.ram[offset % PIF_RAM_SIZE]
+    }
+
+    pub fn write(&mut self, offset: usize, data: u8) {
+        self.ram[offset % PIF_RAM_SIZE] = data;
+    }
+
+    pub fn controller(&self, port: usize) -> ControllerState {
+        self.controllers[port]
+    }
+
+    pub fn set_controller(&mut self, port: usize, state: ControllerState) {
+        if let Some(controller) = self.controllers.get_mut(port) {
+            *controller = state;
+        }
+    }
+
+    pub fn set_turbo(&mut self, port: usize, buttons: u16, period: u32) {
+        if port < CONTROLLER_PORTS {
+            self.turbo_buttons[port] = buttons;
+            self.turbo_periods[port] = period.max(1);
+        }
+    }
+
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    // What a read of the port answers, turbo buttons only count as held in the first half of each period
+    fn polled_controller(&self, port: usize) -> ControllerState {
+        let mut state = self.controllers[port];
+        let period = self.turbo_periods[port] as u64;
+        if self.frame % period >= period / 2 {
+            state.buttons &= !self.turbo_buttons[port];
+        }
+        state
+    }
+
+    pub fn is_connected(&self, port: usize) -> bool {
+        self.connected[port] || self.passthrough[port].is_some()
+    }
+
+    // Plugs or unplugs the emulated controller, a disconnected port answers nothing to the game
+    pub fn set_connected(&mut self, port: usize, connected: bool) {
+        if let Some(slot) = self.connected.get_mut(port) {
+            *slot = connected;
+        }
+    }
+
+    pub fn set_passthrough(&mut self, port: usize, device: Option<Box<dyn JoybusDevice>>) {
+        if let Some(passthrough) = self.passthrough.get_mut(port) {
+            *passthrough = device;
+        }
+    }
+
+    pub fn has_passthrough(&self, port: usize) -> bool {
+        self.passthrough[port].is_some()
+    }
+
+    pub fn pak(&self, port: usize) -> Option<&Pak> {
+        self.paks[port].as_ref()
+    }
+
+    pub fn set_pak(&mut self, port: usize, pak: Option<Pak>) {
+        if let Some(slot) = self.paks.get_mut(port) {
+            *slot = pak;
+        }
+    }
+
+    pub fn pak_kind(&self, port: usize) -> PakKind {
+        self.paks[port].as_ref().map_or(PakKind::None, Pak::kind)
+    }
+
+    // Like pulling the pak out and plugging another one while the game runs, gives back the old one
+    pub fn swap_pak(&mut self, port: usize, pak: Option<Pak>) -> Option<Pak> {
+        self.pak_changed[port] = true;
+        std::mem::replace(&mut self.paks[port], pak)
+    }
+
+    // Contents of the Controller Paks the game wrote to since the last call, by port
+    pub fn take_controller_pak_writes(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.paks.iter_mut().enumerate()
+            .filter_map(|(port, pak)| match pak {
+                Some(Pak::ControllerPak(pak)) => pak.take_dirty().then(|| (port, pak.data().to_vec())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn eeprom_busy(&self) -> bool {
+        self.eeprom_busy_cycles > 0
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.eeprom_busy_cycles = self.eeprom_busy_cycles.saturating_sub(cycles);
+    }
+
+    pub fn rumbling(&self, port: usize) -> bool {
+        matches!(self.paks[port], Some(Pak::RumblePak { rumbling: true }))
+    }
+
+    fn read_pak(&self, port: usize, address: u16) -> Vec<u8> {
+        let mut data = vec![0; PAK_BLOCK_SIZE];
+        match &self.paks[port] {
+            Some(Pak::ControllerPak(pak)) if (address as usize) < CONTROLLER_PAK_SIZE => {
+                data.copy_from_slice(&pak.data()[address as usize..address as usize + PAK_BLOCK_SIZE]);
+            },
+            Some(Pak::RumblePak { .. }) if RUMBLE_PAK_ID_RANGE.contains(&address) => data.fill(RUMBLE_PAK_ID),
+            _ => {},
+        };
+        data
+    }
+
+    fn write_pak(&mut self, port: usize, address: u16, data: &[u8]) {
+        match &mut self.paks[port] {
+            Some(Pak::ControllerPak(pak)) if (address as usize) < CONTROLLER_PAK_SIZE => pak.write(address as usize, data),
+            Some(Pak::RumblePak { rumbling }) if RUMBLE_PAK_MOTOR_RANGE.contains(&address) => *rumbling = data[0] & 1 != 0,
+            _ => {},
+        };
+    }
+
+    fn pak_crc(&self, port: usize, data: &[u8]) -> u8 {
+        match self.paks[port].is_some() {
+            true => pak_data_crc(data),
+            false => !pak_data_crc(data),
+        }
+    }
+
+    // Runs the Joybus commands written in PIF RAM when the last byte asks for it, the responses are written right after each command
+    pub fn run_commands(&mut self, rom: &mut ROM) {
+        if self.ram[PIF_RAM_SIZE - 1] & 1 == 0 {
+            return;
+        }
+        for frame in joybus_frames(&self.ram) {
+            let response_start = frame.offset + 2 + frame.command.len();
+            match self.joybus(frame.channel, &frame.command, rom) {
+                Some(response) => {
+                    let len = response.len().min(frame.response.len());
+                    self.ram[response_start..response_start + len].copy_from_slice(&response[..len]);
+                },
+                // No device on this channel
+                None => self.ram[frame.offset + 1] |= 0x80,
+            };
+        }
+        self.ram[PIF_RAM_SIZE - 1] &= !1;
+    }
+
+    fn joybus(&mut self, channel: usize, command: &[u8], rom: &mut ROM) -> Option<Vec<u8>> {
+        if let Some(device) = self.passthrough.get_mut(channel).and_then(Option::as_mut) {
+            return device.command(command);
+        }
+        if channel == CARTRIDGE_CHANNEL {
+            return self.eeprom(command, rom);
+        }
+        if channel >= CONTROLLER_PORTS || !self.connected[channel] {
+            return None;
+        }
+        match command.first()? {
+            // Info and reset: standard controller
+            0x00 | 0xFF => {
+                let mut status = match self.paks[channel].is_some() {
+                    true => PAK_PRESENT,
+                    false => 0,
+                };
+                if std::mem::take(&mut self.pak_changed[channel]) {
+                    status |= PAK_CHANGED;
+                }
+                Some(vec![0x05, 0x00, status])
+            },
+            0x01 => {
+                let state = self.polled_controller(channel);
+                let [high, low] = state.buttons.to_be_bytes();
+                Some(vec![high, low, state.x as u8, state.y as u8])
+            },
+            0x02 => {
+                let address = u16::from_be_bytes([*command.get(1)?, *command.get(2)?]) & !0x1F;
+                let mut data = self.read_pak(channel, address);
+                data.push(self.pak_crc(channel, &data));
+                Some(data)
+            },
+            0x03 => {
+                let address = u16::from_be_bytes([*command.get(1)?, *command.get(2)?]) & !0x1F;
+                let data = command.get(3..3 + PAK_BLOCK_SIZE)?;
+                self.write_pak(channel, address, data);
+                Some(vec![self.pak_crc(channel, data)])
+            },
+            _ => None,
+        }
+    }
+
+    // Games without an EEPROM don't answer on the cartridge channel
+    fn eeprom(&mut self, command: &[u8], rom: &mut ROM) -> Option<Vec<u8>> {
+        let (id, blocks) = match rom.save_type() {
+            SaveType::Eeprom4K => (0x80, 64),
+            SaveType::Eeprom16K => (0xC0, 256),
+            _ => return None,
+        };
+        let status = match self.eeprom_busy() {
+            true => EEPROM_BUSY,
+            false => 0,
+        };
+        match command.first()? {
+            0x00 | 0xFF => Some(vec![0x00, id, status]),
+            0x04 => Some(rom.read_eeprom(*command.get(1)? as usize % blocks)),
+            0x05 => {
+                let block = *command.get(1)? as usize % blocks;
+                let data = command.get(2..2 + EEPROM_BLOCK_SIZE)?;
+                if !self.eeprom_busy() {
+                    rom.write_eeprom(block, data);
+                    self.eeprom_busy_cycles = EEPROM_WRITE_CYCLES;
+                }
+                Some(vec![status])
+            },
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_block(&self.ram);
+        writer.write_u64(self.eeprom_busy_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let ram = reader.read_block()?;
+        if ram.len() != PIF_RAM_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid PIF RAM size"));
+        }
+        self.ram.copy_from_slice(ram);
+        self.eeprom_busy_cycles = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+impl Serialize for PIF {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        PIFState {
+            ram: self.ram.to_vec(),
+            controllers: self.controllers,
+            connected: self.connected,
+            eeprom_busy_cycles: self.eeprom_busy_cycles,
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PIF {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let state = PIFState::deserialize(deserializer)?;
+        Ok(Self {
+            ram: state.ram.try_into().map_err(|_| D::Error::custom("Invalid PIF RAM size"))?,
+            controllers: state.controllers,
+            connected: state.connected,
+            // Real devices aren't part of the state
+            passthrough: Default::default(),
+            paks: Default::default(),
+            pak_changed: [false; CONTROLLER_PORTS],
+            eeprom_busy_cycles: state.eeprom_busy_cycles,
+            // Settings of the frontend, it sends them again
+            turbo_buttons: [0; CONTROLLER_PORTS],
+            turbo_periods: [1; CONTROLLER_PORTS],
+            frame: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod pif_tests {
+    use super::*;
+
+    #[test]
+    fn test_read_controllers() {
+        let mut pif = PIF::new();
+        pif.set_controller(0, ControllerState { buttons: BUTTON_A | BUTTON_START, x: -80, y: 40 });
+        // Read the state of channels 0 and 1, then stop
+        let commands = [0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE];
+        for (offset, byte) in commands.iter().enumerate() {
+            pif.write(offset, *byte);
+        }
+        pif.write(PIF_RAM_SIZE - 1, 1);
+        pif.run_commands(&mut ROM::new());
+        assert_eq!(&pif.ram()[3..7], &[0x90, 0x00, 0xB0, 0x28]);
+        // Nothing is connected to the second port
+        assert_eq!(pif.read(8), 0x84);
+        assert_eq!(pif.read(PIF_RAM_SIZE - 1), 0);
+
+        let frames = joybus_frames(pif.ram());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], JoybusFrame {
+            channel: 0,
+            offset: 0,
+            command: vec![0x01],
+            response: vec![0x90, 0x00, 0xB0, 0x28],
+            no_device: false,
+        });
+        assert_eq!((frames[1].channel, frames[1].offset, frames[1].no_device), (1, 7, true));
+
+        // Swap which port has a controller
+        pif.set_connected(0, false);
+        pif.set_connected(1, true);
+        pif.write(1, 0x04);
+        pif.write(8, 0x04);
+        pif.write(PIF_RAM_SIZE - 1, 1);
+        pif.run_commands(&mut ROM::new());
+        assert_eq!((pif.read(1), pif.read(8)), (0x84, 0x04));
+        assert_eq!(&pif.ram()[10..14], &[0x00, 0x00, 0x00, 0x00]);
+        assert!(!pif.is_connected(0) && pif.is_connected(1));
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut pif = PIF::new();
+        pif.set_controller(0, ControllerState { buttons: BUTTON_A | BUTTON_B, x: 0, y: 0 });
+        pif.set_turbo(0, BUTTON_B, 4);
+        // Turbo B is held for two frames out of four, however often the game polls, A stays held
+        let mut buttons = Vec::new();
+        for _ in 0..5 {
+            for _ in 0..2 {
+                pif.ram = [0; PIF_RAM_SIZE];
+                for (offset, byte) in [0x01, 0x04, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE].iter().enumerate() {
+                    pif.write(offset, *byte);
+                }
+                pif.write(PIF_RAM_SIZE - 1, 1);
+                pif.run_commands(&mut ROM::new());
+                buttons.push(u16::from_be_bytes([pif.read(3), pif.read(4)]));
+            }
+            pif.next_frame();
+        }
+        let both = BUTTON_A | BUTTON_B;
+        assert_eq!(buttons, vec![both, both, both, both, BUTTON_A, BUTTON_A, BUTTON_A, BUTTON_A, both, both]);
+    }
+
+    #[test]
+    fn test_paks() {
+        let mut pif = PIF::new();
+        let mut rom = ROM::new();
+        let mut write = [0_u8; 35];
+        write[..3].copy_from_slice(&[0x03, 0x01, 0x00]);
+        write[3..].iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+        let crc = pak_data_crc(&write[3..]);
+        assert_eq!(pak_data_crc(&[0; PAK_BLOCK_SIZE]), 0);
+
+        // Nothing plugged in, the CRC comes back inverted
+        assert_eq!(pif.joybus(0, &[0x00], &mut rom), Some(vec![0x05, 0x00, 0x00]));
+        assert_eq!(pif.joybus(0, &write, &mut rom), Some(vec![!crc]));
+        assert_eq!(pif.joybus(0, &[0x02, 0x01, 0x00], &mut rom).unwrap()[PAK_BLOCK_SIZE], 0xFF);
+
+        pif.set_pak(0, Some(Pak::ControllerPak(ControllerPak::new(vec![0; CONTROLLER_PAK_SIZE]).unwrap())));
+        assert_eq!(pif.joybus(0, &[0x00], &mut rom), Some(vec![0x05, 0x00, PAK_PRESENT]));
+        assert_eq!(pif.joybus(0, &write, &mut rom), Some(vec![crc]));
+        // The address CRC bits are left out
+        let read = pif.joybus(0, &[0x02, 0x01, 0x15], &mut rom).unwrap();
+        assert_eq!(&read[..PAK_BLOCK_SIZE], &write[3..]);
+        assert_eq!(read[PAK_BLOCK_SIZE], crc);
+
+        // What osMotorInit checks, then the motor
+        pif.set_pak(0, Some(Pak::RumblePak { rumbling: false }));
+        assert_eq!(pif.joybus(0, &[0x02, 0x80, 0x01], &mut rom).unwrap()[31], RUMBLE_PAK_ID);
+        write[..3].copy_from_slice(&[0x03, 0xC0, 0x1B]);
+        write[3..].fill(1);
+        pif.joybus(0, &write, &mut rom);
+        assert!(pif.rumbling(0));
+        assert!(pif.take_controller_pak_writes().is_empty());
+
+        // Swapping back reports the change once
+        let rumble_pak = pif.swap_pak(0, Some(Pak::ControllerPak(ControllerPak::new(vec![0; CONTROLLER_PAK_SIZE]).unwrap())));
+        assert_eq!(rumble_pak.map(|pak| pak.kind()), Some(PakKind::RumblePak));
+        assert_eq!(pif.joybus(0, &[0x00], &mut rom), Some(vec![0x05, 0x00, PAK_PRESENT | PAK_CHANGED]));
+        assert_eq!(pif.joybus(0, &[0x00], &mut rom), Some(vec![0x05, 0x00, PAK_PRESENT]));
+        write[..3].copy_from_slice(&[0x03, 0x00, 0x00]);
+        pif.joybus(0, &write, &mut rom);
+        let writes = pif.take_controller_pak_writes();
+        assert_eq!((writes.len(), writes[0].0, &writes[0].1[..PAK_BLOCK_SIZE]), (1, 0, &write[3..]));
+    }
+}
@@ -0,0 +1,96 @@
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::emulator::Emulator as Core;
+use crate::pif::{ControllerState, CONTROLLER_PORTS};
+use crate::rom::ROM;
+
+/*
+    Python wrapper of the core for scripts driving the emulator, like reinforcement learning agents.
+    The frame buffer comes out as bytes with its shape, ready for
+    numpy.frombuffer(pixels, dtype=numpy.uint8).reshape(height, width, 4).
+*/
+#[pyclass]
+pub struct Emulator {
+    core: Core,
+}
+
+#[pymethods]
+impl Emulator {
+    #[new]
+    fn new() -> Self {
+        Self {
+            core: Core::new_hle(),
+        }
+    }
+
+    fn load_rom(&mut self, path: &str) -> PyResult<()> {
+        let rom = ROM::new_from_filename(path).map_err(|err| PyIOError::new_err(format!("Could not load {}: {}", path, err)))?;
+        self.core.load_rom(rom);
+        Ok(())
+    }
+
+    fn run_frame(&mut self) {
+        self.core.run_frame();
+    }
+
+    // Runs the given amount of frames, the usual action repeat of an agent
+    fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.core.run_frame();
+        }
+    }
+
+    // Executes a single instruction, returns whether it completed a frame
+    fn step(&mut self) -> bool {
+        self.core.tick()
+    }
+
+    #[getter]
+    fn frames(&self) -> u64 {
+        self.core.frames()
+    }
+
+    #[getter]
+    fn program_counter(&self) -> i64 {
+        self.core.cpu().registers().get_program_counter()
+    }
+
+    fn read_memory<'py>(&self, py: Python<'py>, address: u32, length: usize) -> &'py PyBytes {
+        PyBytes::new(py, &self.core.mmu().read_physical(address as i64, length))
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) {
+        self.core.mut_mmu().write_physical(address as i64, data);
+    }
+
+    // The buttons use the bits of the Joybus controller state
+    fn set_input(&mut self, port: usize, buttons: u16, x: i8, y: i8) -> PyResult<()> {
+        if port >= CONTROLLER_PORTS {
+            return Err(PyValueError::new_err(format!("There is no controller port {}", port)));
+        }
+        self.core.mut_mmu().mut_pif().set_controller(port, ControllerState { buttons, x, y });
+        Ok(())
+    }
+
+    // (width, height, RGBA8888 pixels), None while the video output is blank
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Option<(usize, usize, &'py PyBytes)> {
+        let (width, height, pixels) = self.core.mmu().framebuffer_rgba()?;
+        Some((width, height, PyBytes::new(py, &pixels)))
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, &self.core.save_state())
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> PyResult<()> {
+        self.core.load_state(data).map_err(|err| PyValueError::new_err(format!("Could not load the state: {}", err)))
+    }
+}
+
+#[pymodule]
+fn rultra64(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<Emulator>()?;
+    Ok(())
+}
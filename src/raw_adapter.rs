@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+
+use crate::log::{log, Level, Subsystem};
+use crate::pif::JoybusDevice;
+
+/*
+    raphnet-tech N64 to USB adapters in raw mode: the Joybus commands of the game go to the real
+    controller through HID feature reports and its answers come back unchanged, so Controller,
+    Rumble and Transfer Paks work like on the console. Only Linux's hidraw is supported.
+    https://www.raphnet-tech.com/support/adapter_manager/
+*/
+pub const RAPHNET_VENDOR_ID: u16 = 0x289B;
+
+// Report ID 0 followed by the 63 bytes of a request or an answer
+const REPORT_SIZE: usize = 64;
+const RQ_SUSPEND_POLLING: u8 = 0x03;
+const RQ_RAW_SI_COMMAND: u8 = 0x80;
+
+// Request to send the command on one of the adapter's ports
+fn raw_command_report(channel: u8, command: &[u8]) -> Option<[u8; REPORT_SIZE]> {
+    if command.len() > REPORT_SIZE - 4 {
+        return None;
+    }
+    let mut report = [0; REPORT_SIZE];
+    report[1] = RQ_RAW_SI_COMMAND;
+    report[2] = channel;
+    report[3] = command.len() as u8;
+    report[4..4 + command.len()].copy_from_slice(command);
+    Some(report)
+}
+
+// The answer repeats the request and the port, then the length and the bytes received. Empty when nothing answered
+fn raw_command_answer(report: &[u8]) -> Option<Vec<u8>> {
+    if report.get(1) != Some(&RQ_RAW_SI_COMMAND) {
+        return None;
+    }
+    let length = *report.get(3)? as usize;
+    match length {
+        0 => None,
+        _ => report.get(4..4 + length).map(|data| data.to_vec()),
+    }
+}
+
+// hidraw devices of raphnet adapters, from their uevent in sysfs
+pub fn find_adapters() -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir("/sys/class/hidraw") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut adapters: Vec<PathBuf> = entries.flatten()
+        .filter(|entry| {
+            let uevent = std::fs::read_to_string(entry.path().join("device/uevent")).unwrap_or_default();
+            // HID_ID=0003:0000289B:00000060
+            uevent.lines()
+                .filter_map(|line| line.strip_prefix("HID_ID="))
+                .any(|id| id.split(':').nth(1).and_then(|vendor| u32::from_str_radix(vendor, 16).ok()) == Some(RAPHNET_VENDOR_ID as u32))
+        })
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+        .collect();
+    adapters.sort();
+    adapters
+}
+
+pub struct RawAdapter {
+    file: File,
+    channel: u8,
+}
+
+impl RawAdapter {
+    // The adapter stops polling the controller by itself while it's in use
+    pub fn open(path: &Path, channel: u8) -> Result<Self> {
+        let mut adapter = Self {
+            file: std::fs::OpenOptions::new().read(true).write(true).open(path)?,
+            channel,
+        };
+        adapter.exchange(&[0, RQ_SUSPEND_POLLING, 1])?;
+        Ok(adapter)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn exchange(&mut self, request: &[u8]) -> Result<[u8; REPORT_SIZE]> {
+        use std::os::unix::io::AsRawFd;
+        // HIDIOCSFEATURE and HIDIOCGFEATURE from linux/hidraw.h, read and write with the length in the size field
+        let ioctl = |number: u64, buffer: &mut [u8; REPORT_SIZE]| {
+            let request = (3 << 30) | ((REPORT_SIZE as u64) << 16) | ((b'H' as u64) << 8) | number;
+            match unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, buffer.as_mut_ptr()) } {
+                result if result < 0 => Err(Error::last_os_error()),
+                _ => Ok(()),
+            }
+        };
+        let mut report = [0; REPORT_SIZE];
+        report[..request.len()].copy_from_slice(request);
+        ioctl(0x06, &mut report)?;
+        let mut answer = [0; REPORT_SIZE];
+        ioctl(0x07, &mut answer)?;
+        Ok(answer)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn exchange(&mut self, _request: &[u8]) -> Result<[u8; REPORT_SIZE]> {
+        Err(Error::new(std::io::ErrorKind::Unsupported, "Raw adapters are only supported on Linux"))
+    }
+}
+
+impl JoybusDevice for RawAdapter {
+    fn command(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+        let report = raw_command_report(self.channel, command)?;
+        match self.exchange(&report) {
+            Ok(answer) => raw_command_answer(&answer),
+            Err(err) => {
+                log!(Level::Warn, Subsystem::SI, "Raw adapter command {:02X} failed: {}", command.first().copied().unwrap_or(0), err);
+                None
+            },
+        }
+    }
+}
+
+impl Drop for RawAdapter {
+    fn drop(&mut self) {
+        let _ = self.exchange(&[0, RQ_SUSPEND_POLLING, 0]);
+    }
+}
+
+#[cfg(test)]
+mod raw_adapter_tests {
+    use super::*;
+    use crate::pif::PIF;
+    use crate::rom::ROM;
+
+    // Answers like a controller with a Controller Pak full of 0xAA
+    struct FakeAdapter;
+
+    impl JoybusDevice for FakeAdapter {
+        fn command(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+            let report = raw_command_report(1, command)?;
+            assert_eq!(&report[1..4], &[RQ_RAW_SI_COMMAND, 1, command.len() as u8]);
+            let data = match command[0] {
+                0x00 => vec![0x05, 0x00, 0x01],
+                0x02 => vec![0xAA; 33],
+                _ => vec![],
+            };
+            let mut answer = [0; REPORT_SIZE];
+            answer[1] = RQ_RAW_SI_COMMAND;
+            answer[2] = 1;
+            answer[3] = data.len() as u8;
+            answer[4..4 + data.len()].copy_from_slice(&data);
+            raw_command_answer(&answer)
+        }
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut pif = PIF::new();
+        pif.set_passthrough(1, Some(Box::new(FakeAdapter)));
+        assert!(pif.is_connected(1));
+        // Skip channel 0, then info on channel 1
+        let commands = [0x00, 0x01, 0x03, 0x00, 0xFF, 0xFF, 0xFF, 0xFE];
+        for (offset, byte) in commands.iter().enumerate() {
+            pif.write(offset, *byte);
+        }
+        pif.write(63, 1);
+        pif.run_commands(&mut ROM::new());
+        assert_eq!(&pif.ram()[4..7], &[0x05, 0x00, 0x01]);
+
+        let mut pif = PIF::new();
+        pif.set_passthrough(0, Some(Box::new(FakeAdapter)));
+        // Controller Pak read of address 0x0000
+        let commands = [0x03, 0x21, 0x02, 0x00, 0x00];
+        for (offset, byte) in commands.iter().enumerate() {
+            pif.write(offset, *byte);
+        }
+        pif.write(5 + 33, 0xFE);
+        pif.write(63, 1);
+        pif.run_commands(&mut ROM::new());
+        assert_eq!(&pif.ram()[5..5 + 33], &[0xAA; 33]);
+        assert!(raw_command_report(0, &[0; 61]).is_none());
+    }
+}
@@ -1,8 +1,65 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::rdp::RDP;
 use crate::rdram::RDRAM;
+use crate::rsp::RSP;
+use crate::savestate::{StateReader, StateWriter};
 use crate::utils::box_array;
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    RGBA5551,
+    RGBA8888,
+    // Not a VI format, shows every byte as a gray level to look at raw RDRAM
+    I8,
+}
+
+impl PixelFormat {
+    pub const ALL: [PixelFormat; 3] = [PixelFormat::RGBA5551, PixelFormat::RGBA8888, PixelFormat::I8];
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::RGBA5551 => 2,
+            Self::RGBA8888 => 4,
+            Self::I8 => 1,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RGBA5551 => "RGBA5551",
+            Self::RGBA8888 => "RGBA8888",
+            Self::I8 => "I8",
+        }
+    }
+}
+
+// Post-processing of VI_CTRL: https://n64brew.dev/wiki/Video_Interface#0x0440_0000_-_VI_CTRL
+pub const VI_CTRL_GAMMA_DITHER: u32 = 1 << 2;
+pub const VI_CTRL_GAMMA: u32 = 1 << 3;
+pub const VI_CTRL_DIVOT: u32 = 1 << 4;
+pub const VI_CTRL_DITHER_FILTER: u32 = 1 << 16;
+
+// Bits of MI_INTR: https://n64brew.dev/wiki/MIPS_Interface#0x0430_0008_-_MI_INTERRUPT
+pub const MI_INTR_ADDRESS: i64 = 0x04300008;
+pub const MI_INTR_SI: u8 = 1 << 1;
+pub const MI_INTR_PI: u8 = 1 << 4;
+
+// Region of RDRAM shown as the picture
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FramebufferView {
+    pub origin: u32,
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+}
+
 pub struct VideoInterface {
     registers: Box<[u8; 0x100000]>,
+    // Debug setting to display any part of RDRAM instead of what the registers point to, not part of the savestates
+    view_override: Option<FramebufferView>,
+    // Applies the filters VI_CTRL enables, turned off for a cleaner picture. Not part of the savestates either
+    filters: bool,
 }
 
 impl VideoInterface {
@@ -18,6 +75,8 @@ impl VideoInterface {
         registers[0x0440001B - 0x04400000] = 0x07;
         Self {
             registers,
+            view_override: None,
+            filters: true,
         }
     }
 
@@ -25,10 +84,28 @@ impl VideoInterface {
         self.registers[(address - 0x04400000) as usize]
     }
 
+    // The emulator keeps VI_V_CURRENT in step with the scheduler: https://n64brew.dev/wiki/Video_Interface#0x0440_0010_-_VI_V_CURRENT
+    pub fn set_current_half_line(&mut self, half_line: u32) {
+        self.registers[0x10..0x14].copy_from_slice(&half_line.to_be_bytes());
+    }
+
     pub fn set_register(&mut self, address: i64, data: u8) {
         self.registers[(address - 0x04400000) as usize] = data;
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_block(&self.registers[..]);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let registers = reader.read_block()?;
+        if registers.len() != self.registers.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid VI registers size"));
+        }
+        self.registers.copy_from_slice(registers);
+        Ok(())
+    }
+
     /*
         RDRAM base address of the video output Frame Buffer. This can be changed as needed to implement double or triple buffering. 
         https://n64brew.dev/wiki/Video_Interface#0x0440_0004_-_VI_ORIGIN
@@ -48,26 +125,376 @@ impl VideoInterface {
         https://n64brew.dev/wiki/Video_Interface#0x0440_0008_-_VI_WIDTH
     */
     pub fn get_vi_width(&self) -> u16 {
-        (((self.get_register(0x04400010) as u16) << 8) & 0b1111) | (self.get_register(0x04400011) as u16)
+        (((self.get_register(0x0440000A) as u16) & 0b1111) << 8) | (self.get_register(0x0440000B) as u16)
+    }
+
+    /*
+        Pixel size of the frame buffer: 0 = blank, 2 = 16 bits RGBA5551, 3 = 32 bits RGBA8888.
+        https://n64brew.dev/wiki/Video_Interface#0x0440_0000_-_VI_CTRL
+    */
+    pub fn get_vi_pixel_type(&self) -> u8 {
+        self.get_register(0x04400003) & 0b11
+    }
+
+    pub fn get_vi_ctrl(&self) -> u32 {
+        u32::from_be_bytes(self.registers[0..4].try_into().unwrap())
+    }
+
+    // What the registers point to, None when the output is blank
+    pub fn framebuffer_view(&self) -> Option<FramebufferView> {
+        let format = match self.get_vi_pixel_type() {
+            2 => PixelFormat::RGBA5551,
+            3 => PixelFormat::RGBA8888,
+            _ => return None,
+        };
+        let width = self.get_vi_width() as usize;
+        if width == 0 || width > 640 {
+            return None;
+        }
+        Some(FramebufferView {
+            origin: self.get_vi_origin() & 0xFFFFFF,
+            width,
+            height: width * 3 / 4,
+            format,
+        })
+    }
+
+    pub fn view_override(&self) -> Option<FramebufferView> {
+        self.view_override
+    }
+
+    pub fn set_view_override(&mut self, view: Option<FramebufferView>) {
+        self.view_override = view;
+    }
+
+    pub fn filters(&self) -> bool {
+        self.filters
+    }
+
+    pub fn set_filters(&mut self, enabled: bool) {
+        self.filters = enabled;
+    }
+}
+
+/*
+    Plain storage for the registers of an interface whose side effects aren't emulated yet,
+    so the software reads back what it wrote.
+*/
+pub struct RegisterBlock {
+    base: i64,
+    registers: Vec<u8>,
+}
+
+impl RegisterBlock {
+    pub fn new(base: i64, count: usize) -> Self {
+        Self {
+            base,
+            registers: vec![0; count * 4],
+        }
+    }
+
+    pub fn get_register(&self, address: i64) -> u8 {
+        let offset = (address - self.base) as usize;
+        self.registers.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn set_register(&mut self, address: i64, data: u8) {
+        let offset = (address - self.base) as usize;
+        if let Some(byte) = self.registers.get_mut(offset) {
+            *byte = data;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_block(&self.registers);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let registers = reader.read_block()?;
+        if registers.len() != self.registers.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid interface registers size"));
+        }
+        self.registers.copy_from_slice(registers);
+        Ok(())
     }
 }
 
 pub struct RCP {
     pub video_interface: VideoInterface,
+    pub mips_interface: RegisterBlock,
+    pub audio_interface: RegisterBlock,
+    pub peripheral_interface: RegisterBlock,
+    pub serial_interface: RegisterBlock,
+    // RI_MODE to RI_BANK_STATUS: https://n64brew.dev/wiki/RDRAM_Interface
+    pub rdram_interface: RegisterBlock,
+    pub rsp: RSP,
+    pub rdp: RDP,
 }
 
 impl RCP {
     pub fn new() -> Self {
         Self {
             video_interface: VideoInterface::new(),
+            mips_interface: RCP::new_mips_interface(),
+            audio_interface: RegisterBlock::new(0x04500000, 6),
+            peripheral_interface: RegisterBlock::new(0x04600000, 13),
+            serial_interface: RegisterBlock::new(0x04800000, 7),
+            rdram_interface: RegisterBlock::new(0x04700000, 8),
+            rsp: RSP::new(),
+            rdp: RDP::new(),
+        }
+    }
+
+    // MI_VERSION of retail consoles: https://n64brew.dev/wiki/MIPS_Interface#0x0430_0004_-_MI_VERSION
+    fn new_mips_interface() -> RegisterBlock {
+        let mut mips_interface = RegisterBlock::new(0x04300000, 4);
+        for (i, byte) in 0x02020102_u32.to_be_bytes().iter().enumerate() {
+            mips_interface.set_register(0x04300004 + i as i64, *byte);
+        }
+        mips_interface
+    }
+
+    // Only MI_INTR is kept, the interrupts aren't routed to the CPU yet
+    pub fn raise_interrupt(&mut self, interrupt: u8) {
+        let pending = self.mips_interface.get_register(MI_INTR_ADDRESS + 3);
+        self.mips_interface.set_register(MI_INTR_ADDRESS + 3, pending | interrupt);
+    }
+
+    pub fn clear_interrupt(&mut self, interrupt: u8) {
+        let pending = self.mips_interface.get_register(MI_INTR_ADDRESS + 3);
+        self.mips_interface.set_register(MI_INTR_ADDRESS + 3, pending & !interrupt);
+    }
+
+    pub fn interrupt_pending(&self, interrupt: u8) -> bool {
+        self.mips_interface.get_register(MI_INTR_ADDRESS + 3) & interrupt != 0
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.video_interface.save_state(writer);
+        self.mips_interface.save_state(writer);
+        self.audio_interface.save_state(writer);
+        self.peripheral_interface.save_state(writer);
+        self.serial_interface.save_state(writer);
+        self.rdram_interface.save_state(writer);
+        self.rsp.save_state(writer);
+        self.rdp.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.video_interface.load_state(reader)?;
+        self.mips_interface.load_state(reader)?;
+        self.audio_interface.load_state(reader)?;
+        self.peripheral_interface.load_state(reader)?;
+        self.serial_interface.load_state(reader)?;
+        self.rdram_interface.load_state(reader)?;
+        self.rsp.load_state(reader)?;
+        self.rdp.load_state(reader)
+    }
+
+    /*
+        Converts the frame buffer pointed by VI_ORIGIN to RGBA8888 with the VI filters applied, or the
+        region set with the view override as it is in RDRAM. Returns None when the VI output is blank.
+    */
+    pub fn framebuffer_rgba(&self, rdram: &RDRAM) -> Option<(usize, usize, Vec<u8>)> {
+        if let Some(view) = self.video_interface.view_override() {
+            return Some((view.width, view.height, decode_framebuffer(rdram, &view)));
         }
+        let view = self.video_interface.framebuffer_view()?;
+        let mut pixels = decode_framebuffer(rdram, &view);
+        if self.video_interface.filters() {
+            apply_vi_filters(rdram, &view, self.video_interface.get_vi_ctrl(), &mut pixels);
+        }
+        Some((view.width, view.height, pixels))
     }
 
     pub fn copy_framebuffer(&self, rdram: &RDRAM, dest: &mut [u8]) {
-        let mut addr = self.video_interface.get_vi_origin() as i64;
-        for elem in dest {
-            *elem = rdram.read8(addr);
-            addr += 1;
+        let start = self.video_interface.get_vi_origin() as usize;
+        dest.copy_from_slice(&rdram.as_slice()[start..start + dest.len()]);
+    }
+}
+pub fn decode_framebuffer(rdram: &RDRAM, view: &FramebufferView) -> Vec<u8> {
+    let origin = view.origin as usize;
+    let bytes_per_pixel = view.format.bytes_per_pixel();
+    let memory = rdram.as_slice();
+    let byte = |address: usize| memory[address & 0x3FFFFF];
+    let mut pixels = Vec::with_capacity(view.width * view.height * 4);
+    for i in 0..view.width * view.height {
+        let address = origin + i * bytes_per_pixel;
+        match view.format {
+            PixelFormat::RGBA5551 => {
+                let pixel = ((byte(address) as u16) << 8) | (byte(address + 1) as u16);
+                pixels.push((((pixel >> 11) & 0x1F) << 3) as u8);
+                pixels.push((((pixel >> 6) & 0x1F) << 3) as u8);
+                pixels.push((((pixel >> 1) & 0x1F) << 3) as u8);
+                pixels.push(0xFF);
+            },
+            PixelFormat::RGBA8888 => {
+                for offset in 0..3 {
+                    pixels.push(byte(address + offset));
+                }
+                pixels.push(0xFF);
+            },
+            PixelFormat::I8 => {
+                let intensity = byte(address);
+                pixels.extend_from_slice(&[intensity, intensity, intensity, 0xFF]);
+            },
+        };
+    }
+    pixels
+}
+
+/*
+    Coverage of each pixel, 7 when it's fully covered. 16 bit pixels keep its top bit in place of
+    alpha and the rest in the hidden RDRAM bits, which aren't emulated and read as set.
+    32 bit pixels keep it in the top 3 bits of alpha.
+*/
+fn framebuffer_coverage(rdram: &RDRAM, view: &FramebufferView) -> Vec<u8> {
+    let memory = rdram.as_slice();
+    let byte = |address: usize| memory[address & 0x3FFFFF];
+    (0..view.width * view.height).map(|i| match view.format {
+        PixelFormat::RGBA5551 => ((byte(view.origin as usize + i * 2 + 1) & 1) << 2) | 0b11,
+        PixelFormat::RGBA8888 => byte(view.origin as usize + i * 4 + 3) >> 5,
+        PixelFormat::I8 => 7,
+    }).collect()
+}
+
+// Same noise for the same pixel, so frames and their hashes don't change from one run to another
+fn vi_noise(x: usize, y: usize) -> u32 {
+    let hash = (x as u32).wrapping_mul(0x9E3779B1) ^ (y as u32).wrapping_mul(0x85EBCA77);
+    let hash = (hash ^ (hash >> 15)).wrapping_mul(0x2C1B3C6D);
+    hash ^ (hash >> 12)
+}
+
+/*
+    The dither filter undoes the RDP's dithering of fully covered 16 bit pixels, each neighbor one
+    5 bit step above or below moves the color an eighth of a step. The divot filter takes the
+    median of each antialiased edge pixel and its horizontal neighbors, and gamma boost takes the
+    square root of the color, with 6 bits of noise under it when gamma dither is enabled.
+*/
+pub fn apply_vi_filters(rdram: &RDRAM, view: &FramebufferView, ctrl: u32, pixels: &mut [u8]) {
+    let (width, height) = (view.width, view.height);
+    let coverage = framebuffer_coverage(rdram, view);
+    if ctrl & VI_CTRL_DITHER_FILTER != 0 && view.format == PixelFormat::RGBA5551 {
+        let source = pixels.to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                let index = y * width + x;
+                if coverage[index] != 7 {
+                    continue;
+                }
+                for channel in 0..3 {
+                    let center = (source[index * 4 + channel] >> 3) as i32;
+                    let mut adjust = 0;
+                    for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                        let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                        if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                            continue;
+                        }
+                        match (source[(ny as usize * width + nx as usize) * 4 + channel] >> 3) as i32 - center {
+                            1 => adjust += 1,
+                            -1 => adjust -= 1,
+                            _ => {},
+                        };
+                    }
+                    pixels[index * 4 + channel] = ((center << 3) + adjust).clamp(0, 0xFF) as u8;
+                }
+            }
+        }
+    }
+    if ctrl & VI_CTRL_DIVOT != 0 {
+        let source = pixels.to_vec();
+        for y in 0..height {
+            for x in 1..width.saturating_sub(1) {
+                let index = y * width + x;
+                if coverage[index - 1..=index + 1].iter().all(|cvg| *cvg == 7) {
+                    continue;
+                }
+                for channel in 0..3 {
+                    let mut values = [source[(index - 1) * 4 + channel], source[index * 4 + channel], source[(index + 1) * 4 + channel]];
+                    values.sort_unstable();
+                    pixels[index * 4 + channel] = values[1];
+                }
+            }
+        }
+    }
+    if ctrl & (VI_CTRL_GAMMA | VI_CTRL_GAMMA_DITHER) != 0 {
+        for (index, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            let noise = match ctrl & VI_CTRL_GAMMA_DITHER != 0 {
+                true => vi_noise(index % width, index / width) & 0x3F,
+                false => 0,
+            };
+            for value in pixel.iter_mut().take(3) {
+                *value = match ctrl & VI_CTRL_GAMMA != 0 {
+                    true => ((((*value as u32) << 6) | noise) as f64).sqrt() as u8 * 2,
+                    false => (*value as u32 + (noise & 1)).min(0xFF) as u8,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod rcp_tests {
+    use super::*;
+
+    fn write_register(rcp: &mut RCP, address: i64, value: u32) {
+        for (i, byte) in value.to_be_bytes().iter().enumerate() {
+            rcp.video_interface.set_register(address + i as i64, *byte);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_vi_filters() {
+        let mut rcp = RCP::new();
+        let mut rdram = RDRAM::new();
+        // 4x3 RGBA5551 frame buffer at 0x1000, fully covered pixels of red 10 and a partially covered spike
+        for i in 0..12 {
+            rdram.write8(0x1000 + i * 2, 10 << 3);
+            rdram.write8(0x1001 + i * 2, 1);
+        }
+        rdram.write8(0x1000 + 5 * 2, 31 << 3);
+        rdram.write8(0x1001 + 5 * 2, 0);
+        // One neighbor a step above
+        rdram.write8(0x1000 + 8 * 2, 11 << 3);
+        write_register(&mut rcp, 0x04400004, 0x1000);
+        write_register(&mut rcp, 0x04400008, 4);
+
+        write_register(&mut rcp, 0x04400000, 2 | VI_CTRL_DITHER_FILTER | VI_CTRL_DIVOT);
+        let (width, height, pixels) = rcp.framebuffer_rgba(&rdram).unwrap();
+        assert_eq!((width, height), (4, 3));
+        // The pixel above the brighter one moves up an eighth of a step and the spike becomes the median of its neighbors
+        assert_eq!(pixels[4 * 4], 81);
+        assert_eq!(pixels[5 * 4], 81);
+        assert_eq!(pixels[0], 80);
+
+        write_register(&mut rcp, 0x04400000, 2 | VI_CTRL_GAMMA);
+        assert_eq!(rcp.framebuffer_rgba(&rdram).unwrap().2[0], 142);
+        rcp.video_interface.set_filters(false);
+        assert_eq!(rcp.framebuffer_rgba(&rdram).unwrap().2[0], 80);
+    }
+
+    #[test]
+    fn test_framebuffer_view_override() {
+        let mut rcp = RCP::new();
+        let mut rdram = RDRAM::new();
+        assert!(rcp.framebuffer_rgba(&rdram).is_none());
+
+        rdram.write8(0x1000, 0xF8);
+        rdram.write8(0x1001, 0x3F);
+        rcp.video_interface.set_view_override(Some(FramebufferView {
+            origin: 0x1000,
+            width: 2,
+            height: 1,
+            format: PixelFormat::RGBA5551,
+        }));
+        assert_eq!(rcp.framebuffer_rgba(&rdram), Some((2, 1, vec![0xF8, 0x00, 0xF8, 0xFF, 0x00, 0x00, 0x00, 0xFF])));
+
+        rcp.video_interface.set_view_override(Some(FramebufferView {
+            origin: 0x1000,
+            width: 1,
+            height: 2,
+            format: PixelFormat::I8,
+        }));
+        assert_eq!(rcp.framebuffer_rgba(&rdram).unwrap().2, vec![0xF8, 0xF8, 0xF8, 0xFF, 0x3F, 0x3F, 0x3F, 0xFF]);
+    }
+}
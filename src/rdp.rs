@@ -0,0 +1,714 @@
+use std::io::Result;
+
+use crate::rdp_blender::{Blender, BlenderInputs, FramebufferPixel};
+use crate::rdp_combiner::{Combiner, CombinerInputs, CombineMode};
+use crate::rdram::{RDRAM, RDRAM_SIZE};
+use crate::savestate::{StateReader, StateWriter};
+
+pub const DPC_START_ADDRESS: i64 = 0x04100000;
+pub const DPC_END_ADDRESS: i64 = 0x04100004;
+pub const DPC_CURRENT_ADDRESS: i64 = 0x04100008;
+pub const DPC_STATUS_ADDRESS: i64 = 0x0410000C;
+
+// https://n64brew.dev/wiki/Reality_Display_Processor/Interface#0x0410_000C_-_DPC_STATUS
+pub const DPC_STATUS_XBUS: u32 = 1 << 0;
+pub const DPC_STATUS_FREEZE: u32 = 1 << 1;
+pub const DPC_STATUS_FLUSH: u32 = 1 << 2;
+pub const DPC_STATUS_START_PENDING: u32 = 1 << 10;
+
+// The viewer stops decoding after this many commands
+pub const MAX_RDP_COMMANDS: usize = 4096;
+
+pub const TMEM_SIZE: usize = 0x1000;
+pub const TILE_DESCRIPTORS: usize = 8;
+
+// Cycle type of Set Other Modes: https://n64brew.dev/wiki/Reality_Display_Processor/Commands#0x2F_-_Set_Other_Modes
+pub const CYCLE_TYPE_1CYCLE: u8 = 0;
+pub const CYCLE_TYPE_2CYCLE: u8 = 1;
+pub const CYCLE_TYPE_COPY: u8 = 2;
+pub const CYCLE_TYPE_FILL: u8 = 3;
+
+// Texel formats and sizes: https://n64brew.dev/wiki/Reality_Display_Processor/Commands#0x35_-_Set_Tile
+pub const TEXTURE_FORMATS: [&str; 5] = ["RGBA", "YUV", "CI", "IA", "I"];
+pub const TEXEL_SIZES: [&str; 4] = ["4b", "8b", "16b", "32b"];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TileDescriptor {
+    pub format: u8,
+    pub size: u8,
+    // Both in 64 bit words
+    pub line: u16,
+    pub tmem_address: u16,
+    pub palette: u8,
+    // Clamp, mirror, mask and shift of the s and t coordinates
+    pub clamp_s: bool,
+    pub mirror_s: bool,
+    pub mask_s: u8,
+    pub shift_s: u8,
+    pub clamp_t: bool,
+    pub mirror_t: bool,
+    pub mask_t: u8,
+    pub shift_t: u8,
+    // 10.2 fixed point texel coordinates set by Set Tile Size and the load commands
+    pub sl: u16,
+    pub tl: u16,
+    pub sh: u16,
+    pub th: u16,
+}
+
+impl TileDescriptor {
+    // Size of the tile in texels, 0 when no size was set
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self.sh < self.sl || self.th < self.tl {
+            true => (0, 0),
+            false => (((self.sh - self.sl) >> 2) as usize + 1, ((self.th - self.tl) >> 2) as usize + 1),
+        }
+    }
+}
+
+// Where the load commands read texels from, RDRAM or the memory kept in an RDP capture
+pub trait TextureSource {
+    fn read8(&self, address: u32) -> u8;
+}
+
+impl TextureSource for RDRAM {
+    fn read8(&self, address: u32) -> u8 {
+        RDRAM::read8(self, address as i64)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+struct TextureImage {
+    size: u8,
+    width: u32,
+    address: u32,
+}
+
+impl TextureImage {
+    fn read(&self, memory: &impl TextureSource, offset: u32) -> u8 {
+        memory.read8((self.address + offset) % RDRAM_SIZE as u32)
+    }
+}
+
+// Bytes for texel counts, 4 bit texels pack two in a byte
+fn texel_bytes(size: u8, texels: u32) -> u32 {
+    (texels << size) >> 1
+}
+
+/*
+    DPC registers of the command interface. Only the commands that load textures into TMEM and the
+    ones that set the mode, combiner and blender state are executed, there is no rasterizer yet. The
+    rest of the queue stays in memory where the debugger can look at it.
+    https://n64brew.dev/wiki/Reality_Display_Processor/Interface
+*/
+pub struct RDP {
+    start: u32,
+    end: u32,
+    current: u32,
+    status: u32,
+    // The CPU writes the registers a byte at a time, writes are applied once the whole word arrived
+    pending_write: [u8; 4],
+    tmem: Vec<u8>,
+    tiles: [TileDescriptor; TILE_DESCRIPTORS],
+    texture_image: TextureImage,
+    // Set Other Modes without the command id
+    other_modes: u64,
+    combiner: Combiner,
+    blender: Blender,
+}
+
+impl RDP {
+    pub fn new() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            current: 0,
+            status: 0,
+            pending_write: [0; 4],
+            tmem: vec![0; TMEM_SIZE],
+            tiles: [TileDescriptor::default(); TILE_DESCRIPTORS],
+            texture_image: TextureImage::default(),
+            other_modes: 0,
+            combiner: Combiner::new(),
+            blender: Blender::new(),
+        }
+    }
+
+    pub fn tmem(&self) -> &[u8] {
+        &self.tmem
+    }
+
+    pub fn tiles(&self) -> &[TileDescriptor; TILE_DESCRIPTORS] {
+        &self.tiles
+    }
+
+    pub fn other_modes(&self) -> u64 {
+        self.other_modes
+    }
+
+    pub fn cycle_type(&self) -> u8 {
+        ((self.other_modes >> 52) & 0b11) as u8
+    }
+
+    pub fn combiner(&self) -> &Combiner {
+        &self.combiner
+    }
+
+    // Color of a pixel through the combiner with the current mode
+    pub fn combine(&self, inputs: &CombinerInputs) -> [u8; 4] {
+        self.combiner.combine(inputs, self.cycle_type() == CYCLE_TYPE_2CYCLE)
+    }
+
+    pub fn blender(&self) -> &Blender {
+        &self.blender
+    }
+
+    // What a pixel leaving the combiner writes over the one in memory, None when it's rejected
+    pub fn blend(&self, inputs: &BlenderInputs, memory: &FramebufferPixel) -> Option<FramebufferPixel> {
+        self.blender.blend(self.other_modes, self.cycle_type() == CYCLE_TYPE_2CYCLE, inputs, memory)
+    }
+
+    // Commands up to the new value have been run
+    pub fn set_current(&mut self, current: u32) {
+        self.current = current;
+    }
+
+    /*
+        Runs a command if it changes the texture, mode, combiner or blender state, everything else is ignored.
+        Odd lines are stored with their 32 bit words swapped like the hardware does. 32 bit textures
+        are split between the two halves of TMEM, red and green in the lower one and blue and alpha
+        in the upper one, so a line holds four texels per 64 bit word like 16 bit textures.
+        https://n64brew.dev/wiki/Reality_Display_Processor/Commands
+    */
+    pub fn execute(&mut self, words: &[u64], memory: &impl TextureSource) {
+        let word = words[0];
+        let tile = ((word >> 24) & 0b111) as usize;
+        let sl = ((word >> 44) & 0xFFF) as u16;
+        let tl = ((word >> 32) & 0xFFF) as u16;
+        let sh = ((word >> 12) & 0xFFF) as u16;
+        let th = (word & 0xFFF) as u16;
+        match (word >> 56) & 0x3F {
+            // Set Texture Image
+            0x3D => self.texture_image = TextureImage {
+                size: ((word >> 51) & 0b11) as u8,
+                width: ((word >> 32) & 0x3FF) as u32 + 1,
+                address: (word & 0xFFFFFF) as u32,
+            },
+            // Set Tile
+            0x35 => {
+                let descriptor = &mut self.tiles[tile];
+                descriptor.format = ((word >> 53) & 0b111) as u8;
+                descriptor.size = ((word >> 51) & 0b11) as u8;
+                descriptor.line = ((word >> 41) & 0x1FF) as u16;
+                descriptor.tmem_address = ((word >> 32) & 0x1FF) as u16;
+                descriptor.palette = ((word >> 20) & 0xF) as u8;
+                descriptor.clamp_t = (word >> 19) & 1 != 0;
+                descriptor.mirror_t = (word >> 18) & 1 != 0;
+                descriptor.mask_t = ((word >> 14) & 0xF) as u8;
+                descriptor.shift_t = ((word >> 10) & 0xF) as u8;
+                descriptor.clamp_s = (word >> 9) & 1 != 0;
+                descriptor.mirror_s = (word >> 8) & 1 != 0;
+                descriptor.mask_s = ((word >> 4) & 0xF) as u8;
+                descriptor.shift_s = (word & 0xF) as u8;
+            },
+            // Set Tile Size
+            0x32 => self.set_tile_size(tile, sl, tl, sh, th),
+            // Load Tile
+            0x34 => {
+                self.set_tile_size(tile, sl, tl, sh, th);
+                self.load_tile(tile, memory);
+            },
+            // Load Block, sh is the last texel and th the increment that swaps odd lines
+            0x33 => {
+                self.set_tile_size(tile, sl, tl, sh, th);
+                self.load_block(tile, sl as u32, tl as u32, sh as u32, th as u32, memory);
+            },
+            // Load TLUT
+            0x30 => {
+                self.set_tile_size(tile, sl, tl, sh, th);
+                self.load_tlut(tile, memory);
+            },
+            // Set Other Modes
+            0x2F => self.other_modes = word & 0x00FFFFFF_FFFFFFFF,
+            // Set Key GB, Set Key R, Set Convert, Set Prim Color, Set Env Color and Set Combine Mode
+            0x2A..=0x2C | 0x3A..=0x3C => self.combiner.execute(word),
+            // Set Fog Color and Set Blend Color
+            0x38 | 0x39 => self.blender.execute(word),
+            _ => {},
+        };
+    }
+
+    fn set_tile_size(&mut self, tile: usize, sl: u16, tl: u16, sh: u16, th: u16) {
+        let descriptor = &mut self.tiles[tile];
+        descriptor.sl = sl;
+        descriptor.tl = tl;
+        descriptor.sh = sh;
+        descriptor.th = th;
+    }
+
+    fn load_tile(&mut self, tile: usize, memory: &impl TextureSource) {
+        let descriptor = self.tiles[tile];
+        let image = self.texture_image;
+        let (width, height) = descriptor.dimensions();
+        for row in 0..height as u32 {
+            let source = texel_bytes(image.size, ((descriptor.tl as u32 >> 2) + row) * image.width + (descriptor.sl as u32 >> 2));
+            let destination = (descriptor.tmem_address as u32 + descriptor.line as u32 * row) * 8;
+            let swap = if row & 1 == 1 { 4 } else { 0 };
+            if image.size == 3 {
+                for texel in 0..width as u32 {
+                    self.store_split_texel((destination + texel * 2) ^ swap, image, memory, source + texel * 4);
+                }
+                continue;
+            }
+            for i in 0..texel_bytes(image.size, width as u32) {
+                let offset = ((destination + i) ^ swap) as usize % TMEM_SIZE;
+                self.tmem[offset] = image.read(memory, source + i);
+            }
+        }
+    }
+
+    fn load_block(&mut self, tile: usize, sl: u32, tl: u32, sh: u32, dxt: u32, memory: &impl TextureSource) {
+        let descriptor = self.tiles[tile];
+        let image = self.texture_image;
+        let source = texel_bytes(image.size, tl * image.width + sl);
+        let words = texel_bytes(image.size, sh.saturating_sub(sl) + 1).div_ceil(8);
+        let destination = descriptor.tmem_address as u32 * 8;
+        // dxt counts the 64 bit words read, each one has two 32 bit texels
+        if image.size == 3 {
+            for texel in 0..sh.saturating_sub(sl) + 1 {
+                let swap = if (((texel >> 1) * dxt) >> 11) & 1 == 1 { 4 } else { 0 };
+                self.store_split_texel((destination + texel * 2) ^ swap, image, memory, source + texel * 4);
+            }
+            return;
+        }
+        for word in 0..words {
+            let swap = if ((word * dxt) >> 11) & 1 == 1 { 4 } else { 0 };
+            for i in 0..8 {
+                let offset = ((destination + word * 8 + i) ^ swap) as usize % TMEM_SIZE;
+                self.tmem[offset] = image.read(memory, source + word * 8 + i);
+            }
+        }
+    }
+
+    // Red and green at offset in the lower half of TMEM, blue and alpha at the same place in the upper one
+    fn store_split_texel(&mut self, offset: u32, image: TextureImage, memory: &impl TextureSource, source: u32) {
+        let offset = offset as usize % (TMEM_SIZE / 2);
+        for i in 0..2 {
+            self.tmem[offset + i] = image.read(memory, source + i as u32);
+            self.tmem[offset + TMEM_SIZE / 2 + i] = image.read(memory, source + 2 + i as u32);
+        }
+    }
+
+    // Palette entries are 16 bit and stored four times each in the upper half of TMEM
+    fn load_tlut(&mut self, tile: usize, memory: &impl TextureSource) {
+        let descriptor = self.tiles[tile];
+        let image = self.texture_image;
+        let first = descriptor.sl as u32 >> 2;
+        let count = ((descriptor.sh as u32) >> 2).saturating_sub(first) + 1;
+        for entry in 0..count {
+            let source = (first + entry) * 2;
+            let color = [image.read(memory, source), image.read(memory, source + 1)];
+            for copy in 0..4 {
+                let offset = (descriptor.tmem_address as u32 * 8 + entry * 8 + copy * 2) as usize % TMEM_SIZE;
+                self.tmem[offset..offset + 2].copy_from_slice(&color);
+            }
+        }
+    }
+
+    pub fn get_start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn get_end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn get_current(&self) -> u32 {
+        self.current
+    }
+
+    pub fn get_status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn read_register(&self, address: i64) -> u32 {
+        match address & !0b11 {
+            DPC_START_ADDRESS => self.start,
+            DPC_END_ADDRESS => self.end,
+            DPC_CURRENT_ADDRESS => self.current,
+            DPC_STATUS_ADDRESS => self.status,
+            _ => 0,
+        }
+    }
+
+    pub fn get_register(&self, address: i64) -> u8 {
+        self.read_register(address).to_be_bytes()[(address & 0b11) as usize]
+    }
+
+    pub fn set_register(&mut self, address: i64, data: u8) {
+        let byte = (address & 0b11) as usize;
+        self.pending_write[byte] = data;
+        if byte != 3 {
+            return;
+        }
+        let value = u32::from_be_bytes(self.pending_write);
+        match address & !0b11 {
+            DPC_START_ADDRESS => {
+                self.start = value & 0xFFFFF8;
+                self.status |= DPC_STATUS_START_PENDING;
+            },
+            DPC_END_ADDRESS => {
+                self.end = value & 0xFFFFF8;
+                if self.status & DPC_STATUS_START_PENDING != 0 {
+                    self.current = self.start;
+                    self.status &= !DPC_STATUS_START_PENDING;
+                }
+            },
+            DPC_STATUS_ADDRESS => self.write_status(value),
+            _ => {},
+        };
+    }
+
+    fn write_status(&mut self, data: u32) {
+        let pairs = [
+            (0, 1, DPC_STATUS_XBUS),
+            (2, 3, DPC_STATUS_FREEZE),
+            (4, 5, DPC_STATUS_FLUSH),
+        ];
+        for (clear, set, bit) in pairs {
+            if data & (1 << clear) != 0 {
+                self.status &= !bit;
+            } else if data & (1 << set) != 0 {
+                self.status |= bit;
+            }
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u32(self.start);
+        writer.write_u32(self.end);
+        writer.write_u32(self.current);
+        writer.write_u32(self.status);
+        writer.write_block(&self.tmem);
+        for tile in self.tiles.iter() {
+            writer.write_u8(tile.format);
+            writer.write_u8(tile.size);
+            writer.write_u16(tile.line);
+            writer.write_u16(tile.tmem_address);
+            writer.write_u8(tile.palette);
+            for (clamp, mirror, mask, shift) in [(tile.clamp_s, tile.mirror_s, tile.mask_s, tile.shift_s), (tile.clamp_t, tile.mirror_t, tile.mask_t, tile.shift_t)] {
+                writer.write_bool(clamp);
+                writer.write_bool(mirror);
+                writer.write_u8(mask);
+                writer.write_u8(shift);
+            }
+            writer.write_u16(tile.sl);
+            writer.write_u16(tile.tl);
+            writer.write_u16(tile.sh);
+            writer.write_u16(tile.th);
+        }
+        writer.write_u8(self.texture_image.size);
+        writer.write_u32(self.texture_image.width);
+        writer.write_u32(self.texture_image.address);
+        writer.write_u64(self.other_modes);
+        self.combiner.save_state(writer);
+        self.blender.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.start = reader.read_u32()?;
+        self.end = reader.read_u32()?;
+        self.current = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        let tmem = reader.read_block()?;
+        let len = tmem.len().min(TMEM_SIZE);
+        self.tmem[..len].copy_from_slice(&tmem[..len]);
+        for tile in self.tiles.iter_mut() {
+            *tile = TileDescriptor {
+                format: reader.read_u8()?,
+                size: reader.read_u8()?,
+                line: reader.read_u16()?,
+                tmem_address: reader.read_u16()?,
+                palette: reader.read_u8()?,
+                clamp_s: reader.read_bool()?,
+                mirror_s: reader.read_bool()?,
+                mask_s: reader.read_u8()?,
+                shift_s: reader.read_u8()?,
+                clamp_t: reader.read_bool()?,
+                mirror_t: reader.read_bool()?,
+                mask_t: reader.read_u8()?,
+                shift_t: reader.read_u8()?,
+                sl: reader.read_u16()?,
+                tl: reader.read_u16()?,
+                sh: reader.read_u16()?,
+                th: reader.read_u16()?,
+            };
+        }
+        self.texture_image = TextureImage {
+            size: reader.read_u8()?,
+            width: reader.read_u32()?,
+            address: reader.read_u32()?,
+        };
+        self.other_modes = reader.read_u64()?;
+        self.combiner.load_state(reader)?;
+        self.blender.load_state(reader)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn rgba5551(color: u16) -> [u8; 4] {
+    let expand = |value: u16| ((value << 3) | (value >> 2)) as u8;
+    [expand((color >> 11) & 0x1F), expand((color >> 6) & 0x1F), expand((color >> 1) & 0x1F), if color & 1 != 0 { 0xFF } else { 0 }]
+}
+
+/*
+    Decodes what the tile descriptor points at in TMEM to RGBA, for the texture viewer.
+    Color indexed textures use the RGBA16 palette in the upper half, YUV shows as gray.
+*/
+pub fn decode_tile(tmem: &[u8], tile: &TileDescriptor, width: usize, height: usize) -> Vec<u8> {
+    let byte = |offset: usize| tmem[offset % TMEM_SIZE];
+    let half = |offset: usize| u16::from_be_bytes([byte(offset), byte(offset + 1)]);
+    let palette = |index: usize| rgba5551(half(0x800 + index * 8));
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = (tile.tmem_address as usize + tile.line as usize * y) * 8;
+        let swap = if y & 1 == 1 { 4 } else { 0 };
+        for x in 0..width {
+            let offset = (row + ((x << tile.size) >> 1)) ^ swap;
+            let nibble = match x & 1 {
+                0 => byte(offset) >> 4,
+                _ => byte(offset) & 0xF,
+            };
+            let pixel = match (tile.format, tile.size) {
+                (0, 2) => rgba5551(half(offset)),
+                (0, 3) => {
+                    let split = ((row + x * 2) ^ swap) % (TMEM_SIZE / 2);
+                    [byte(split), byte(split + 1), byte(split + TMEM_SIZE / 2), byte(split + TMEM_SIZE / 2 + 1)]
+                },
+                (2, 0) => palette(tile.palette as usize * 16 + nibble as usize),
+                (2, 1) => palette(byte(offset) as usize),
+                (3, 0) => {
+                    let intensity = (nibble >> 1) * 0x24 + (nibble >> 3) * 3;
+                    [intensity, intensity, intensity, if nibble & 1 != 0 { 0xFF } else { 0 }]
+                },
+                (3, 1) => [(byte(offset) >> 4) * 0x11, (byte(offset) >> 4) * 0x11, (byte(offset) >> 4) * 0x11, (byte(offset) & 0xF) * 0x11],
+                (3, 2) => [byte(offset), byte(offset), byte(offset), byte(offset + 1)],
+                (4, 0) => [nibble * 0x11, nibble * 0x11, nibble * 0x11, 0xFF],
+                (4, 1) => [byte(offset), byte(offset), byte(offset), 0xFF],
+                _ => [0x80, 0x80, 0x80, 0xFF],
+            };
+            pixels.extend_from_slice(&pixel);
+        }
+    }
+    pixels
+}
+
+#[derive(Clone, Debug)]
+pub struct RdpCommand {
+    pub address: u32,
+    pub words: Vec<u64>,
+    pub text: String,
+}
+
+// Size in 64 bit words: https://n64brew.dev/wiki/Reality_Display_Processor/Commands
+pub fn command_length(word: u64) -> usize {
+    match (word >> 56) & 0x3F {
+        id @ 0x08..=0x0F => 4 + (id & 0b100 != 0) as usize * 8 + (id & 0b010 != 0) as usize * 8 + (id & 0b001 != 0) as usize * 2,
+        0x24 | 0x25 => 2,
+        _ => 1,
+    }
+}
+
+pub fn command_name(id: u8) -> &'static str {
+    match id {
+        0x00 => "No Op",
+        0x08 => "Fill Triangle",
+        0x09 => "Fill ZBuffer Triangle",
+        0x0A => "Texture Triangle",
+        0x0B => "Texture ZBuffer Triangle",
+        0x0C => "Shade Triangle",
+        0x0D => "Shade ZBuffer Triangle",
+        0x0E => "Shade Texture Triangle",
+        0x0F => "Shade Texture ZBuffer Triangle",
+        0x24 => "Texture Rectangle",
+        0x25 => "Texture Rectangle Flip",
+        0x26 => "Sync Load",
+        0x27 => "Sync Pipe",
+        0x28 => "Sync Tile",
+        0x29 => "Sync Full",
+        0x2A => "Set Key GB",
+        0x2B => "Set Key R",
+        0x2C => "Set Convert",
+        0x2D => "Set Scissor",
+        0x2E => "Set Prim Depth",
+        0x2F => "Set Other Modes",
+        0x30 => "Load TLUT",
+        0x32 => "Set Tile Size",
+        0x33 => "Load Block",
+        0x34 => "Load Tile",
+        0x35 => "Set Tile",
+        0x36 => "Fill Rectangle",
+        0x37 => "Set Fill Color",
+        0x38 => "Set Fog Color",
+        0x39 => "Set Blend Color",
+        0x3A => "Set Prim Color",
+        0x3B => "Set Env Color",
+        0x3C => "Set Combine Mode",
+        0x3D => "Set Texture Image",
+        0x3E => "Set Mask Image",
+        0x3F => "Set Color Image",
+        _ => "Invalid",
+    }
+}
+
+// Coordinates are 10.2 fixed point
+fn coordinate(value: u64) -> f32 {
+    (value & 0xFFF) as f32 / 4.0
+}
+
+pub fn disassemble(words: &[u64]) -> String {
+    let word = words[0];
+    let id = ((word >> 56) & 0x3F) as u8;
+    let name = command_name(id);
+    match id {
+        0x2D | 0x36 => format!(
+            "{} ({}, {}) - ({}, {})", name,
+            coordinate(word >> 12), coordinate(word),
+            coordinate(word >> 44), coordinate(word >> 32),
+        ),
+        0x24 | 0x25 => format!(
+            "{} tile {} ({}, {}) - ({}, {})", name, (word >> 24) & 0b111,
+            coordinate(word >> 12), coordinate(word),
+            coordinate(word >> 44), coordinate(word >> 32),
+        ),
+        0x37..=0x3B => format!("{} {:08X}", name, word as u32),
+        0x3D | 0x3F => format!(
+            "{} format {} size {} width {} address {:06X}", name,
+            (word >> 53) & 0b111, (word >> 51) & 0b11, ((word >> 32) & 0x3FF) + 1, word & 0xFFFFFF,
+        ),
+        0x3E => format!("{} address {:06X}", name, word & 0xFFFFFF),
+        0x3C => format!("{} {}", name, CombineMode::from_command(word)),
+        0x30 | 0x32..=0x35 => format!("{} tile {}", name, (word >> 24) & 0b111),
+        _ => String::from(name),
+    }
+}
+
+// Splits the raw command buffer into commands, the address of the first byte is start
+pub fn decode_commands(data: &[u8], start: u32) -> Vec<RdpCommand> {
+    let words: Vec<u64> = data.chunks_exact(8)
+        .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+        .collect();
+    let mut commands = Vec::new();
+    let mut index = 0;
+    while index < words.len() && commands.len() < MAX_RDP_COMMANDS {
+        let length = command_length(words[index]).min(words.len() - index);
+        let command_words = words[index..index + length].to_vec();
+        commands.push(RdpCommand {
+            address: start + (index * 8) as u32,
+            text: disassemble(&command_words),
+            words: command_words,
+        });
+        index += length;
+    }
+    commands
+}
+
+#[cfg(test)]
+mod rdp_tests {
+    use super::*;
+
+    fn write_word(rdp: &mut RDP, address: i64, value: u32) {
+        for (i, byte) in value.to_be_bytes().iter().enumerate() {
+            rdp.set_register(address + i as i64, *byte);
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut rdp = RDP::new();
+        write_word(&mut rdp, DPC_START_ADDRESS, 0x00100000);
+        assert_eq!(rdp.get_status() & DPC_STATUS_START_PENDING, DPC_STATUS_START_PENDING);
+        write_word(&mut rdp, DPC_END_ADDRESS, 0x00100040);
+        assert_eq!(rdp.get_current(), 0x00100000);
+        assert_eq!(rdp.get_end(), 0x00100040);
+        assert_eq!(rdp.get_status(), 0);
+        write_word(&mut rdp, DPC_STATUS_ADDRESS, 0b10);
+        assert_eq!(rdp.get_register(DPC_STATUS_ADDRESS + 3), DPC_STATUS_XBUS as u8);
+    }
+
+    #[test]
+    fn test_load_tile() {
+        let mut rdram = RDRAM::new();
+        // 4x2 RGBA16 image: red, green, blue and white on both rows
+        let texels = [0xF801_u16, 0x07C1, 0x003F, 0xFFFF];
+        for row in 0..2 {
+            for (i, texel) in texels.iter().enumerate() {
+                let address = 0x1000 + (row * 4 + i as i64) * 2;
+                rdram.write8(address, (texel >> 8) as u8);
+                rdram.write8(address + 1, *texel as u8);
+            }
+        }
+        let mut rdp = RDP::new();
+        // Set Texture Image RGBA16, width 4, Set Tile line 1 at TMEM 0, Load Tile (0, 0) - (3, 1)
+        rdp.execute(&[0x3D100003_00001000], &rdram);
+        rdp.execute(&[0x35100200_00000000], &rdram);
+        rdp.execute(&[0x34000000_0000C004], &rdram);
+        let tile = rdp.tiles()[0];
+        assert_eq!(tile.dimensions(), (4, 2));
+        // The second row is stored with its 32 bit words swapped
+        assert_eq!(&rdp.tmem()[8..12], &[0x00, 0x3F, 0xFF, 0xFF]);
+        let pixels = decode_tile(rdp.tmem(), &tile, 4, 2);
+        assert_eq!(&pixels[..8], &[0xFF, 0, 0, 0xFF, 0, 0xFF, 0, 0xFF]);
+        assert_eq!(&pixels[16..20], &[0xFF, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_load_rgba32() {
+        let mut rdram = RDRAM::new();
+        // 4x2 RGBA32 image, texel n is [n, n + 0x10, n + 0x20, n + 0x30]
+        for texel in 0..8 {
+            for channel in 0..4 {
+                rdram.write8(0x1000 + texel * 4 + channel, (texel + channel * 0x10) as u8);
+            }
+        }
+        let mut rdp = RDP::new();
+        // Set Texture Image RGBA32, width 4, Set Tile line 1 clamping t and mirroring s, Load Tile (0, 0) - (3, 1)
+        rdp.execute(&[0x3D180003_00001000], &rdram);
+        rdp.execute(&[0x35180200_00095151], &rdram);
+        rdp.execute(&[0x34000000_0000C004], &rdram);
+        let tile = rdp.tiles()[0];
+        assert_eq!((tile.clamp_t, tile.mask_t, tile.shift_t), (true, 5, 4));
+        assert_eq!((tile.mirror_s, tile.mask_s, tile.shift_s), (true, 5, 1));
+        assert_eq!(&rdp.tmem()[0..4], &[0x00, 0x10, 0x01, 0x11]);
+        assert_eq!(&rdp.tmem()[0x800..0x804], &[0x20, 0x30, 0x21, 0x31]);
+        // Texels 4 and 5 start the second line, swapped with 6 and 7
+        assert_eq!(&rdp.tmem()[12..16], &[0x04, 0x14, 0x05, 0x15]);
+        let pixels = decode_tile(rdp.tmem(), &tile, 4, 2);
+        assert_eq!(&pixels[5 * 4..6 * 4], &[0x05, 0x15, 0x25, 0x35]);
+        let tile_tmem = rdp.tmem().to_vec();
+
+        // Load Block of the 8 texels, dxt of 1024 for two 64 bit words per line
+        let mut rdp = RDP::new();
+        rdp.execute(&[0x3D180003_00001000], &rdram);
+        rdp.execute(&[0x35180000_00000000], &rdram);
+        rdp.execute(&[0x33000000_00007400], &rdram);
+        assert_eq!(rdp.tmem(), &tile_tmem[..]);
+    }
+
+    #[test]
+    fn test_decode_commands() {
+        let mut data = Vec::new();
+        // Fill Rectangle (0, 0) - (319, 239)
+        data.extend_from_slice(&0x364FC3BC_00000000_u64.to_be_bytes());
+        // Shade Triangle, 12 words
+        data.extend_from_slice(&0x0C000000_00000000_u64.to_be_bytes());
+        data.extend_from_slice(&[0; 11 * 8]);
+        data.extend_from_slice(&0x37000000_FFFF0001_u64.to_be_bytes());
+        let commands = decode_commands(&data, 0x1000);
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0].text, "Fill Rectangle (0, 0) - (319, 239)");
+        assert_eq!(commands[1].words.len(), 12);
+        assert_eq!(commands[2].address, 0x1000 + 13 * 8);
+        assert_eq!(commands[2].text, "Set Fill Color FFFF0001");
+    }
+}
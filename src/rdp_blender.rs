@@ -0,0 +1,318 @@
+use std::io::Result;
+
+use crate::savestate::{StateReader, StateWriter};
+
+/*
+    Last stage of the RDP pipeline, after the combiner. It rejects pixels by alpha, coverage and
+    depth, blends the ones left with the framebuffer as (P * A + M * B) / (A + B) and works out
+    the coverage and depth written back. Edge antialiasing is the blender mixing a pixel with
+    what's in memory by their coverage, the video interface does the rest.
+    https://n64brew.dev/wiki/Reality_Display_Processor/Pipeline#Blender
+*/
+
+// Set Other Modes bits: https://n64brew.dev/wiki/Reality_Display_Processor/Commands#0x2F_-_Set_Other_Modes
+pub const OTHER_MODES_ALPHA_COMPARE: u64 = 1 << 0;
+pub const OTHER_MODES_DITHER_ALPHA: u64 = 1 << 1;
+pub const OTHER_MODES_ANTIALIAS: u64 = 1 << 3;
+pub const OTHER_MODES_Z_COMPARE: u64 = 1 << 4;
+pub const OTHER_MODES_Z_UPDATE: u64 = 1 << 5;
+pub const OTHER_MODES_IMAGE_READ: u64 = 1 << 6;
+pub const OTHER_MODES_COLOR_ON_CVG: u64 = 1 << 7;
+pub const OTHER_MODES_CVG_TIMES_ALPHA: u64 = 1 << 12;
+pub const OTHER_MODES_ALPHA_CVG_SELECT: u64 = 1 << 13;
+pub const OTHER_MODES_FORCE_BLEND: u64 = 1 << 14;
+
+// cvg_dest, what happens to the coverage in memory
+pub const CVG_DEST_CLAMP: u8 = 0;
+pub const CVG_DEST_WRAP: u8 = 1;
+pub const CVG_DEST_ZAP: u8 = 2;
+pub const CVG_DEST_SAVE: u8 = 3;
+
+pub const Z_MODE_OPAQUE: u8 = 0;
+pub const Z_MODE_INTERPENETRATING: u8 = 1;
+pub const Z_MODE_TRANSPARENT: u8 = 2;
+pub const Z_MODE_DECAL: u8 = 3;
+
+// Coverage is counted in eighths of a pixel, memory keeps 3 bits so a full pixel reads as 7
+pub const FULL_COVERAGE: u8 = 8;
+
+// 4x4 thresholds of rgb_dither_sel 0 and 1
+const MAGIC_SQUARE: [u8; 16] = [0, 6, 1, 7, 4, 2, 5, 3, 3, 5, 2, 4, 7, 1, 6, 0];
+const BAYER: [u8; 16] = [0, 4, 1, 5, 4, 0, 5, 1, 3, 7, 2, 6, 7, 3, 6, 2];
+
+// What the combiner and the rasterizer provide for a pixel
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct BlenderInputs {
+    // Combined color and alpha
+    pub pixel: [u8; 4],
+    pub shade_alpha: u8,
+    // 0 to 8
+    pub coverage: u8,
+    pub z: u32,
+    pub dz: u32,
+    // For the dither pattern
+    pub x: usize,
+    pub y: usize,
+    pub noise: u8,
+}
+
+// A pixel of the color and depth buffers
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct FramebufferPixel {
+    pub color: [u8; 3],
+    // 0 to 7
+    pub coverage: u8,
+    pub z: u32,
+}
+
+pub struct Blender {
+    blend_color: [u8; 4],
+    fog_color: [u8; 4],
+}
+
+impl Blender {
+    pub fn new() -> Self {
+        Self {
+            blend_color: [0; 4],
+            fog_color: [0; 4],
+        }
+    }
+
+    pub fn blend_color(&self) -> [u8; 4] {
+        self.blend_color
+    }
+
+    pub fn fog_color(&self) -> [u8; 4] {
+        self.fog_color
+    }
+
+    // Set Fog Color and Set Blend Color, the RDP passes them here
+    pub fn execute(&mut self, word: u64) {
+        match (word >> 56) & 0x3F {
+            0x38 => self.fog_color = (word as u32).to_be_bytes(),
+            0x39 => self.blend_color = (word as u32).to_be_bytes(),
+            _ => {},
+        };
+    }
+
+    // With dither_alpha_en the threshold is random instead of the blend color alpha
+    fn alpha_compare(&self, other_modes: u64, alpha: u8, noise: u8) -> bool {
+        if other_modes & OTHER_MODES_ALPHA_COMPARE == 0 {
+            return true;
+        }
+        let threshold = match other_modes & OTHER_MODES_DITHER_ALPHA != 0 {
+            true => noise,
+            false => self.blend_color[3],
+        };
+        alpha >= threshold
+    }
+
+    fn z_compare(other_modes: u64, inputs: &BlenderInputs, memory: &FramebufferPixel) -> bool {
+        if other_modes & OTHER_MODES_Z_COMPARE == 0 {
+            return true;
+        }
+        match ((other_modes >> 10) & 0b11) as u8 {
+            Z_MODE_INTERPENETRATING => inputs.z < memory.z.saturating_add(inputs.dz),
+            Z_MODE_DECAL => inputs.z.abs_diff(memory.z) <= inputs.dz,
+            _ => inputs.z < memory.z,
+        }
+    }
+
+    /*
+        One cycle of the blender, the selectors are P, A, M and B. The weights keep 5 bits, without
+        divide the sum is taken as 1.0 instead of dividing by A + B.
+    */
+    fn cycle(&self, other_modes: u64, cycle: usize, pixel: [u8; 3], inputs: &BlenderInputs, memory: &FramebufferPixel, divide: bool) -> [u8; 3] {
+        let selector = |shift: u64| ((other_modes >> (shift - cycle as u64 * 2)) & 0b11) as u8;
+        let color = |select: u8| match select {
+            0 => pixel,
+            1 => memory.color,
+            2 => [self.blend_color[0], self.blend_color[1], self.blend_color[2]],
+            _ => [self.fog_color[0], self.fog_color[1], self.fog_color[2]],
+        };
+        let a = match selector(26) {
+            0 => inputs.pixel[3],
+            1 => self.fog_color[3],
+            2 => inputs.shade_alpha,
+            _ => 0,
+        } as u32 >> 3;
+        let b = match selector(18) {
+            0 => 31 - a,
+            1 => (memory.coverage as u32) << 2,
+            2 => 31,
+            _ => 0,
+        };
+        let (p, m) = (color(selector(30)), color(selector(22)));
+        let mut result = [0; 3];
+        for channel in 0..3 {
+            let sum = p[channel] as u32 * a + m[channel] as u32 * (b + 1);
+            result[channel] = match divide {
+                true => sum / (a + b + 1),
+                false => sum >> 5,
+            }.min(0xFF) as u8;
+        }
+        result
+    }
+
+    // Rounds the color up to the next 5 bit step when its low bits are over the pattern's threshold
+    fn dither(other_modes: u64, color: [u8; 3], inputs: &BlenderInputs) -> [u8; 3] {
+        let position = (inputs.y & 3) * 4 + (inputs.x & 3);
+        let threshold = match (other_modes >> 38) & 0b11 {
+            0 => MAGIC_SQUARE[position],
+            1 => BAYER[position],
+            2 => inputs.noise & 7,
+            _ => return color,
+        };
+        color.map(|value| match value & 7 > threshold {
+            true => ((value & 0xF8) as u32 + 8).min(0xFF) as u8,
+            false => value,
+        })
+    }
+
+    /*
+        The pixel written to the framebuffer, None when it's rejected. The first of two cycles always
+        runs, usually for fog. The last one only blends with force_blend or on antialiased edges,
+        where the coverage of the pixel and the one in memory don't add up to a full pixel. With
+        color_on_cvg only those edges update the color.
+    */
+    pub fn blend(&self, other_modes: u64, two_cycle: bool, inputs: &BlenderInputs, memory: &FramebufferPixel) -> Option<FramebufferPixel> {
+        let mut inputs = *inputs;
+        if other_modes & OTHER_MODES_ALPHA_CVG_SELECT != 0 {
+            inputs.pixel[3] = (inputs.coverage as u32 * 32).min(0xFF) as u8;
+        }
+        if other_modes & OTHER_MODES_CVG_TIMES_ALPHA != 0 {
+            inputs.coverage = ((inputs.coverage as u32 * inputs.pixel[3] as u32 + 0x80) >> 8) as u8;
+        }
+        if !self.alpha_compare(other_modes, inputs.pixel[3], inputs.noise) || inputs.coverage == 0 {
+            return None;
+        }
+        if !Self::z_compare(other_modes, &inputs, memory) {
+            return None;
+        }
+
+        let memory_coverage = match other_modes & OTHER_MODES_IMAGE_READ != 0 {
+            true => memory.coverage,
+            false => FULL_COVERAGE - 1,
+        };
+        let total = inputs.coverage + memory_coverage + 1;
+        let overflow = total > FULL_COVERAGE;
+        let force_blend = other_modes & OTHER_MODES_FORCE_BLEND != 0;
+        let blend = force_blend || (other_modes & OTHER_MODES_ANTIALIAS != 0 && !overflow);
+        let pixel = [inputs.pixel[0], inputs.pixel[1], inputs.pixel[2]];
+        let color = match two_cycle {
+            true => self.cycle(other_modes, 0, pixel, &inputs, memory, false),
+            false => pixel,
+        };
+        let color = match blend {
+            true => self.cycle(other_modes, two_cycle as usize, color, &inputs, memory, !force_blend),
+            false => color,
+        };
+        let color = match other_modes & OTHER_MODES_COLOR_ON_CVG != 0 && overflow {
+            true => memory.color,
+            false => Self::dither(other_modes, color, &inputs),
+        };
+
+        let coverage = match ((other_modes >> 8) & 0b11) as u8 {
+            CVG_DEST_CLAMP => total.min(FULL_COVERAGE) - 1,
+            CVG_DEST_WRAP => (total - 1) & 7,
+            CVG_DEST_ZAP => FULL_COVERAGE - 1,
+            _ => memory.coverage,
+        };
+        let z = match other_modes & OTHER_MODES_Z_UPDATE != 0 {
+            true => inputs.z,
+            false => memory.z,
+        };
+        Some(FramebufferPixel {
+            color,
+            coverage,
+            z,
+        })
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.blend_color);
+        writer.write_bytes(&self.fog_color);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.blend_color.copy_from_slice(reader.read_bytes(4)?);
+        self.fog_color.copy_from_slice(reader.read_bytes(4)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rdp_blender_tests {
+    use super::*;
+
+    // Without dithering, rgb_dither_sel 3
+    const NO_DITHER: u64 = 3 << 38;
+
+    #[test]
+    fn test_blend() {
+        let mut blender = Blender::new();
+        let inputs = BlenderInputs {
+            pixel: [0xFF, 0, 0, 0x80],
+            shade_alpha: 0xFF,
+            coverage: FULL_COVERAGE,
+            ..Default::default()
+        };
+        let memory = FramebufferPixel {
+            color: [0, 0, 0xFF],
+            coverage: 7,
+            z: 0x100,
+        };
+        // PIXEL * COMBINED_ALPHA + MEMORY * (1 - A)
+        let translucent = OTHER_MODES_FORCE_BLEND | OTHER_MODES_IMAGE_READ | (1 << 22) | NO_DITHER;
+        let pixel = blender.blend(translucent, false, &inputs, &memory).unwrap();
+        assert_eq!(pixel.color, [0x7F, 0, 0x7F]);
+        assert_eq!(pixel.z, 0x100);
+
+        blender.execute(0x39000000_00000090);
+        assert_eq!(blender.blend(translucent | OTHER_MODES_ALPHA_COMPARE, false, &inputs, &memory), None);
+
+        // Fog in the first cycle, FOG * SHADE_ALPHA + PIXEL * (1 - A), the second one doesn't blend
+        blender.execute(0x38000000_0000FF00);
+        let fog = (3 << 30) | (2 << 26) | (3 << 24) | (2 << 16) | NO_DITHER;
+        let pixel = blender.blend(fog, true, &BlenderInputs { pixel: [0x80, 0x80, 0x80, 0xFF], ..inputs }, &memory).unwrap();
+        assert_eq!(pixel.color, [4, 4, 0xFB]);
+
+        // Depth
+        let depth = OTHER_MODES_Z_COMPARE | OTHER_MODES_Z_UPDATE | NO_DITHER;
+        assert_eq!(blender.blend(depth, false, &BlenderInputs { z: 0x200, ..inputs }, &memory), None);
+        assert_eq!(blender.blend(depth, false, &BlenderInputs { z: 0x80, ..inputs }, &memory).unwrap().z, 0x80);
+        let decal = depth | ((Z_MODE_DECAL as u64) << 10);
+        assert!(blender.blend(decal, false, &BlenderInputs { z: 0x108, dz: 0x10, ..inputs }, &memory).is_some());
+
+        // Magic square dithering rounds up where the low bits are over the threshold, 6 at (1, 0)
+        let dithered = blender.blend(0, false, &BlenderInputs { pixel: [0x87, 0x81, 0, 0xFF], x: 1, ..inputs }, &memory).unwrap();
+        assert_eq!(dithered.color, [0x88, 0x81, 0]);
+    }
+
+    #[test]
+    fn test_antialiasing() {
+        let blender = Blender::new();
+        // PIXEL * COVERAGE + MEMORY * MEMORY_CVG on the edges, the coverage is clamped
+        let antialias = OTHER_MODES_ANTIALIAS | OTHER_MODES_IMAGE_READ | OTHER_MODES_ALPHA_CVG_SELECT | (1 << 22) | (1 << 18) | NO_DITHER;
+        let inputs = BlenderInputs {
+            pixel: [0xFF, 0, 0, 0xFF],
+            coverage: 4,
+            ..Default::default()
+        };
+        let memory = FramebufferPixel {
+            color: [0, 0, 0xFF],
+            coverage: 3,
+            z: 0,
+        };
+        let pixel = blender.blend(antialias, false, &inputs, &memory).unwrap();
+        assert_eq!(pixel.color, [140, 0, 114]);
+        assert_eq!(pixel.coverage, 7);
+
+        // Together they cover more than a pixel, the color isn't blended
+        let covered = FramebufferPixel { coverage: 7, ..memory };
+        assert_eq!(blender.blend(antialias, false, &inputs, &covered).unwrap().color, [0xFF, 0, 0]);
+        assert_eq!(blender.blend(antialias | OTHER_MODES_COLOR_ON_CVG, false, &inputs, &covered).unwrap().color, [0, 0, 0xFF]);
+        let wrap = antialias | ((CVG_DEST_WRAP as u64) << 8);
+        assert_eq!(blender.blend(wrap, false, &inputs, &covered).unwrap().coverage, 3);
+    }
+}
@@ -0,0 +1,162 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Result;
+
+use flate2::Crc;
+
+use crate::rdp::{RDP, TextureSource};
+use crate::rdram::RDRAM;
+use crate::savestate::{StateReader, StateWriter};
+
+pub const RDP_CAPTURE_MAGIC: &[u8; 4] = b"R64R";
+pub const RDP_CAPTURE_VERSION: u32 = 4;
+
+/*
+    The RDP commands of a frame with the state they started from and the RDRAM bytes the load
+    commands read, like Dolphin's FIFO logs. Replaying one runs the commands on their own, so
+    a change in the RDP can be checked against a capture without the game or the CPU.
+*/
+pub struct RdpCapture {
+    // RDP state when the capture started, in the savestate format
+    start_state: Vec<u8>,
+    commands: Vec<Vec<u64>>,
+    memory: BTreeMap<u32, u8>,
+}
+
+// Reads RDRAM keeping every byte in the capture
+struct RecordingSource<'a> {
+    rdram: &'a RDRAM,
+    memory: RefCell<&'a mut BTreeMap<u32, u8>>,
+}
+
+impl TextureSource for RecordingSource<'_> {
+    fn read8(&self, address: u32) -> u8 {
+        let data = self.rdram.read8(address as i64);
+        self.memory.borrow_mut().insert(address, data);
+        data
+    }
+}
+
+// Bytes that weren't captured were never read while recording, they read as 0
+impl TextureSource for RdpCapture {
+    fn read8(&self, address: u32) -> u8 {
+        self.memory.get(&address).copied().unwrap_or(0)
+    }
+}
+
+impl RdpCapture {
+    pub fn new(rdp: &RDP) -> Self {
+        let mut writer = StateWriter::new();
+        rdp.save_state(&mut writer);
+        Self {
+            start_state: writer.finish(),
+            commands: Vec::new(),
+            memory: BTreeMap::new(),
+        }
+    }
+
+    pub fn commands(&self) -> &[Vec<u64>] {
+        &self.commands
+    }
+
+    // Runs a command on the RDP and records it
+    pub fn execute(&mut self, rdp: &mut RDP, words: &[u64], rdram: &RDRAM) {
+        self.commands.push(words.to_vec());
+        rdp.execute(words, &RecordingSource {
+            rdram,
+            memory: RefCell::new(&mut self.memory),
+        });
+    }
+
+    // The RDP after running every command from the captured state
+    pub fn replay(&self) -> Result<RDP> {
+        let mut rdp = RDP::new();
+        rdp.load_state(&mut StateReader::new(&self.start_state)?)?;
+        for words in &self.commands {
+            rdp.execute(words, self);
+        }
+        Ok(rdp)
+    }
+
+    // CRC32 of TMEM after the replay, what regression tests compare while there's no rasterizer
+    pub fn replay_hash(&self) -> Result<u32> {
+        let mut crc = Crc::new();
+        crc.update(self.replay()?.tmem());
+        Ok(crc.sum())
+    }
+
+    // Memory is stored as runs of consecutive addresses
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_header(RDP_CAPTURE_MAGIC, RDP_CAPTURE_VERSION);
+        writer.write_block(&self.start_state);
+        writer.write_u32(self.commands.len() as u32);
+        for words in &self.commands {
+            writer.write_u32(words.len() as u32);
+            for word in words {
+                writer.write_u64(*word);
+            }
+        }
+        let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (address, data) in &self.memory {
+            match runs.last_mut() {
+                Some((start, run)) if *start + run.len() as u32 == *address => run.push(*data),
+                _ => runs.push((*address, vec![*data])),
+            };
+        }
+        writer.write_u32(runs.len() as u32);
+        for (start, run) in runs {
+            writer.write_u32(start);
+            writer.write_block(&run);
+        }
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = StateReader::with_header(data, RDP_CAPTURE_MAGIC, RDP_CAPTURE_VERSION, "RDP capture")?;
+        let start_state = reader.read_block()?.to_vec();
+        let mut commands = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            let length = reader.read_u32()?;
+            commands.push((0..length).map(|_| reader.read_u64()).collect::<Result<Vec<u64>>>()?);
+        }
+        let mut memory = BTreeMap::new();
+        for _ in 0..reader.read_u32()? {
+            let start = reader.read_u32()?;
+            for (offset, data) in reader.read_block()?.iter().enumerate() {
+                memory.insert(start + offset as u32, *data);
+            }
+        }
+        Ok(Self {
+            start_state,
+            commands,
+            memory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod rdp_capture_tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_replay() {
+        let mut rdram = RDRAM::new();
+        for address in 0..0x40 {
+            rdram.write8(0x1000 + address, address as u8);
+        }
+        let mut rdp = RDP::new();
+        let mut capture = RdpCapture::new(&rdp);
+        // Set Texture Image, Set Tile and Load Tile of a 4x2 16 bit texture
+        capture.execute(&mut rdp, &[0x3D100003_00001000], &rdram);
+        capture.execute(&mut rdp, &[0x35100200_00000000], &rdram);
+        capture.execute(&mut rdp, &[0x34000000_0000C004], &rdram);
+
+        let restored = RdpCapture::from_bytes(&capture.to_bytes()).unwrap();
+        assert_eq!(restored.commands(), capture.commands());
+        assert_eq!(restored.memory, capture.memory);
+        let replayed = restored.replay().unwrap();
+        assert_eq!(replayed.tmem(), rdp.tmem());
+        assert_eq!(replayed.tiles(), rdp.tiles());
+        assert!(RdpCapture::from_bytes(b"R64S\0\0\0\x01").is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::io::Result;
+
+use crate::savestate::{StateReader, StateWriter};
+
+/*
+    Color combiner of the RDP, it evaluates (A - B) * C + D for the color and the alpha once per
+    cycle. In two cycle mode the second cycle can use what the first one combined, in one cycle
+    mode only the settings of the second cycle are used.
+    https://n64brew.dev/wiki/Reality_Display_Processor/Pipeline#Color_Combiner
+*/
+pub const COMBINER_CYCLES: usize = 2;
+
+// Inputs of the color, in the order of the A, B, C and D selectors
+const RGB_A_INPUTS: [&str; 8] = ["COMBINED", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "1", "NOISE"];
+const RGB_B_INPUTS: [&str; 8] = ["COMBINED", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "KEY_CENTER", "K4"];
+const RGB_C_INPUTS: [&str; 16] = [
+    "COMBINED", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "KEY_SCALE", "COMBINED_ALPHA",
+    "TEXEL0_ALPHA", "TEXEL1_ALPHA", "PRIMITIVE_ALPHA", "SHADE_ALPHA", "ENV_ALPHA", "LOD_FRACTION", "PRIM_LOD_FRAC", "K5",
+];
+const RGB_D_INPUTS: [&str; 8] = ["COMBINED", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "1", "0"];
+const ALPHA_INPUTS: [&str; 8] = ["COMBINED", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "1", "0"];
+const ALPHA_C_INPUTS: [&str; 8] = ["LOD_FRACTION", "TEXEL0", "TEXEL1", "PRIMITIVE", "SHADE", "ENVIRONMENT", "PRIM_LOD_FRAC", "0"];
+
+// Selectors past the end of a table are 0
+fn input_name(names: &[&'static str], index: u8) -> &'static str {
+    names.get(index as usize).copied().unwrap_or("0")
+}
+
+// The combiner works on 9 bit values, the ones with both top bits set are negative
+fn extend(value: i32) -> i32 {
+    match value & 0x180 {
+        0x180 => value | !0x1FF,
+        _ => value & 0x1FF,
+    }
+}
+
+fn clamp(value: i32) -> u8 {
+    match value & 0x180 {
+        0x180 => 0,
+        0x100 => 0xFF,
+        _ => value as u8,
+    }
+}
+
+// C is a signed 9 bit multiplier where 0x100 is 1.0, the result keeps 9 bits before being clamped
+fn equation(a: i32, b: i32, c: i32, d: i32) -> i32 {
+    let c = (c << 23) >> 23;
+    extend((((extend(a) - extend(b)) * c + (extend(d) << 8) + 0x80) >> 8) & 0x1FF)
+}
+
+// 9 bit signed constant of Set Convert
+fn convert_constant(word: u64, shift: u32) -> i32 {
+    ((((word >> shift) & 0x1FF) as i32) << 23) >> 23
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CombineMode {
+    // A, B, C and D selectors of each cycle
+    pub rgb: [[u8; 4]; COMBINER_CYCLES],
+    pub alpha: [[u8; 4]; COMBINER_CYCLES],
+}
+
+impl CombineMode {
+    // https://n64brew.dev/wiki/Reality_Display_Processor/Commands#0x3C_-_Set_Combine_Mode
+    pub fn from_command(word: u64) -> Self {
+        let field = |shift: u32, bits: u32| ((word >> shift) & ((1 << bits) - 1)) as u8;
+        Self {
+            rgb: [
+                [field(52, 4), field(28, 4), field(47, 5), field(15, 3)],
+                [field(37, 4), field(24, 4), field(32, 5), field(6, 3)],
+            ],
+            alpha: [
+                [field(44, 3), field(12, 3), field(41, 3), field(9, 3)],
+                [field(21, 3), field(3, 3), field(18, 3), field(0, 3)],
+            ],
+        }
+    }
+
+    pub fn rgb_equation(&self, cycle: usize) -> String {
+        let [a, b, c, d] = self.rgb[cycle];
+        format!(
+            "({} - {}) * {} + {}",
+            input_name(&RGB_A_INPUTS, a), input_name(&RGB_B_INPUTS, b),
+            input_name(&RGB_C_INPUTS, c), input_name(&RGB_D_INPUTS, d),
+        )
+    }
+
+    pub fn alpha_equation(&self, cycle: usize) -> String {
+        let [a, b, c, d] = self.alpha[cycle];
+        format!(
+            "({} - {}) * {} + {}",
+            input_name(&ALPHA_INPUTS, a), input_name(&ALPHA_INPUTS, b),
+            input_name(&ALPHA_C_INPUTS, c), input_name(&ALPHA_INPUTS, d),
+        )
+    }
+}
+
+impl fmt::Display for CombineMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{}, {} / {}, {}",
+            self.rgb_equation(0), self.alpha_equation(0), self.rgb_equation(1), self.alpha_equation(1),
+        )
+    }
+}
+
+// What the rasterizer and the texture units provide for a pixel
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CombinerInputs {
+    pub texel0: [u8; 4],
+    pub texel1: [u8; 4],
+    pub shade: [u8; 4],
+    pub lod_fraction: u8,
+    pub noise: u8,
+}
+
+pub struct Combiner {
+    mode: CombineMode,
+    primitive: [u8; 4],
+    environment: [u8; 4],
+    prim_lod_fraction: u8,
+    // Red, green and blue
+    key_center: [u8; 3],
+    key_scale: [u8; 3],
+    k4: i32,
+    k5: i32,
+}
+
+impl Combiner {
+    pub fn new() -> Self {
+        Self {
+            mode: CombineMode::default(),
+            primitive: [0; 4],
+            environment: [0; 4],
+            prim_lod_fraction: 0,
+            key_center: [0; 3],
+            key_scale: [0; 3],
+            k4: 0,
+            k5: 0,
+        }
+    }
+
+    pub fn mode(&self) -> &CombineMode {
+        &self.mode
+    }
+
+    pub fn primitive(&self) -> [u8; 4] {
+        self.primitive
+    }
+
+    pub fn environment(&self) -> [u8; 4] {
+        self.environment
+    }
+
+    // The commands that set the combiner state, the RDP passes them here
+    pub fn execute(&mut self, word: u64) {
+        match (word >> 56) & 0x3F {
+            // Set Key GB
+            0x2A => {
+                self.key_center[1] = (word >> 24) as u8;
+                self.key_scale[1] = (word >> 16) as u8;
+                self.key_center[2] = (word >> 8) as u8;
+                self.key_scale[2] = word as u8;
+            },
+            // Set Key R
+            0x2B => {
+                self.key_center[0] = (word >> 8) as u8;
+                self.key_scale[0] = word as u8;
+            },
+            // Set Convert, K4 and K5 are the only constants the combiner uses
+            0x2C => {
+                self.k4 = convert_constant(word, 9);
+                self.k5 = convert_constant(word, 0);
+            },
+            // Set Prim Color
+            0x3A => {
+                self.prim_lod_fraction = (word >> 32) as u8;
+                self.primitive = (word as u32).to_be_bytes();
+            },
+            // Set Env Color
+            0x3B => self.environment = (word as u32).to_be_bytes(),
+            // Set Combine Mode
+            0x3C => self.mode = CombineMode::from_command(word),
+            _ => {},
+        };
+    }
+
+    fn rgb_input(&self, selector: u8, channel: usize, combined: &[i32; 4], inputs: &CombinerInputs) -> i32 {
+        match selector {
+            0 => combined[channel],
+            1 => inputs.texel0[channel] as i32,
+            2 => inputs.texel1[channel] as i32,
+            3 => self.primitive[channel] as i32,
+            4 => inputs.shade[channel] as i32,
+            5 => self.environment[channel] as i32,
+            _ => 0,
+        }
+    }
+
+    fn alpha_input(&self, selector: u8, combined: &[i32; 4], inputs: &CombinerInputs) -> i32 {
+        match selector {
+            6 => 0x100,
+            7 => 0,
+            _ => self.rgb_input(selector, 3, combined, inputs),
+        }
+    }
+
+    fn cycle(&self, cycle: usize, combined: &[i32; 4], inputs: &CombinerInputs) -> [i32; 4] {
+        let [a, b, c, d] = self.mode.rgb[cycle];
+        let mut result = [0; 4];
+        for (channel, value) in result.iter_mut().take(3).enumerate() {
+            let a = match a {
+                6 => 0x100,
+                7 => inputs.noise as i32,
+                8.. => 0,
+                _ => self.rgb_input(a, channel, combined, inputs),
+            };
+            let b = match b {
+                6 => self.key_center[channel] as i32,
+                7 => self.k4,
+                8.. => 0,
+                _ => self.rgb_input(b, channel, combined, inputs),
+            };
+            let c = match c {
+                6 => self.key_scale[channel] as i32,
+                7 => combined[3],
+                8 => inputs.texel0[3] as i32,
+                9 => inputs.texel1[3] as i32,
+                10 => self.primitive[3] as i32,
+                11 => inputs.shade[3] as i32,
+                12 => self.environment[3] as i32,
+                13 => inputs.lod_fraction as i32,
+                14 => self.prim_lod_fraction as i32,
+                15 => self.k5,
+                16.. => 0,
+                _ => self.rgb_input(c, channel, combined, inputs),
+            };
+            let d = match d {
+                6 => 0x100,
+                7 => 0,
+                _ => self.rgb_input(d, channel, combined, inputs),
+            };
+            *value = equation(a, b, c, d);
+        }
+        let [a, b, c, d] = self.mode.alpha[cycle];
+        let c = match c {
+            0 => inputs.lod_fraction as i32,
+            6 => self.prim_lod_fraction as i32,
+            _ => self.alpha_input(c, combined, inputs),
+        };
+        result[3] = equation(self.alpha_input(a, combined, inputs), self.alpha_input(b, combined, inputs), c, self.alpha_input(d, combined, inputs));
+        result
+    }
+
+    /*
+        Color of a pixel. What the first cycle combined is kept with 9 bits for the second one,
+        only the final color is clamped. Without a previous cycle COMBINED reads as 0.
+    */
+    pub fn combine(&self, inputs: &CombinerInputs, two_cycle: bool) -> [u8; 4] {
+        let combined = match two_cycle {
+            true => self.cycle(0, &[0; 4], inputs),
+            false => [0; 4],
+        };
+        self.cycle(1, &combined, inputs).map(clamp)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for selectors in self.mode.rgb.iter().chain(self.mode.alpha.iter()) {
+            writer.write_bytes(selectors);
+        }
+        writer.write_bytes(&self.primitive);
+        writer.write_bytes(&self.environment);
+        writer.write_u8(self.prim_lod_fraction);
+        writer.write_bytes(&self.key_center);
+        writer.write_bytes(&self.key_scale);
+        writer.write_u32(self.k4 as u32);
+        writer.write_u32(self.k5 as u32);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let mut selectors = [[0; 4]; COMBINER_CYCLES * 2];
+        for cycle in selectors.iter_mut() {
+            cycle.copy_from_slice(reader.read_bytes(4)?);
+        }
+        self.mode = CombineMode {
+            rgb: [selectors[0], selectors[1]],
+            alpha: [selectors[2], selectors[3]],
+        };
+        self.primitive.copy_from_slice(reader.read_bytes(4)?);
+        self.environment.copy_from_slice(reader.read_bytes(4)?);
+        self.prim_lod_fraction = reader.read_u8()?;
+        self.key_center.copy_from_slice(reader.read_bytes(3)?);
+        self.key_scale.copy_from_slice(reader.read_bytes(3)?);
+        self.k4 = reader.read_u32()? as i32;
+        self.k5 = reader.read_u32()? as i32;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rdp_combiner_tests {
+    use super::*;
+    use crate::rdp::RDP;
+    use crate::rdp_capture::RdpCapture;
+    use crate::rdram::RDRAM;
+
+    fn combine_command(rgb: [[u8; 4]; 2], alpha: [[u8; 4]; 2]) -> u64 {
+        let fields = [
+            (rgb[0][0], 52), (rgb[0][1], 28), (rgb[0][2], 47), (rgb[0][3], 15),
+            (rgb[1][0], 37), (rgb[1][1], 24), (rgb[1][2], 32), (rgb[1][3], 6),
+            (alpha[0][0], 44), (alpha[0][1], 12), (alpha[0][2], 41), (alpha[0][3], 9),
+            (alpha[1][0], 21), (alpha[1][1], 3), (alpha[1][2], 18), (alpha[1][3], 0),
+        ];
+        fields.iter().fold(0x3C << 56, |word, (value, shift)| word | ((*value as u64) << shift))
+    }
+
+    #[test]
+    fn test_combine() {
+        let inputs = CombinerInputs {
+            texel0: [0x80, 0xFF, 0x40, 0xFF],
+            shade: [0xFF, 0x80, 0x80, 0x80],
+            ..Default::default()
+        };
+        // G_CC_MODULATERGBA in one cycle mode, (TEXEL0 - 0) * SHADE + 0
+        let modulate = combine_command([[1, 15, 4, 7], [1, 15, 4, 7]], [[1, 7, 4, 7], [1, 7, 4, 7]]);
+        let mut combiner = Combiner::new();
+        combiner.execute(modulate);
+        assert_eq!(combiner.mode().rgb_equation(1), "(TEXEL0 - 0) * SHADE + 0");
+        assert_eq!(combiner.combine(&inputs, false), [0x80, 0x80, 0x20, 0x80]);
+
+        // Both ends clamp, (1 - 0) * 0 + 1 and (0 - TEXEL0) * SHADE + 0
+        combiner.execute(combine_command([[0; 4], [6, 15, 31, 6]], [[0; 4], [7, 1, 4, 7]]));
+        assert_eq!(combiner.combine(&inputs, false), [0xFF, 0xFF, 0xFF, 0]);
+    }
+
+    #[test]
+    fn test_two_cycle_capture() {
+        let rdram = RDRAM::new();
+        let mut rdp = RDP::new();
+        let mut capture = RdpCapture::new(&rdp);
+        // Modulate in the first cycle, then (PRIMITIVE - COMBINED) * ENV_ALPHA + COMBINED
+        let commands = [
+            0x2F100000_00000000,
+            0x3A000000_FF0000FF,
+            0x3B000000_00000080,
+            combine_command([[1, 15, 4, 7], [3, 0, 12, 0]], [[1, 7, 4, 7], [7, 7, 7, 0]]),
+        ];
+        for word in commands {
+            capture.execute(&mut rdp, &[word], &rdram);
+        }
+        let inputs = CombinerInputs {
+            texel0: [0x80, 0xFF, 0x40, 0xFF],
+            shade: [0xFF, 0x80, 0x80, 0x80],
+            ..Default::default()
+        };
+        assert_eq!(rdp.combine(&inputs), [0xC0, 0x40, 0x10, 0x80]);
+
+        let replayed = RdpCapture::from_bytes(&capture.to_bytes()).unwrap().replay().unwrap();
+        assert_eq!(replayed.combine(&inputs), [0xC0, 0x40, 0x10, 0x80]);
+        assert_eq!(replayed.combiner().environment(), [0, 0, 0, 0x80]);
+    }
+}
@@ -1,58 +1,157 @@
-use crate::utils::box_array;
+use std::io::Result;
 
-#[derive(Copy, Clone)]
-pub struct Byte {
-    data: u16,
-}
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-impl Byte {
-    pub fn new() -> Self {
-        Self {
-            data: 0,
-        }
-    }
+use crate::savestate::{StateReader, StateWriter};
 
-    pub fn read(&self) -> u16 {
-        self.data & 0x1FF
-    }
+pub const RDRAM_SIZE: usize = 0x400000;
 
-    pub fn write(&mut self, data: u16) {
-        self.data = data & 0x1FF;
-    }
+// What RDRAM serializes as, the same planes it's kept in
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "RDRAM")]
+struct RDRAMState {
+    #[serde(with = "crate::savestate::bytes")]
+    data: Vec<u8>,
+    #[serde(with = "crate::savestate::bytes")]
+    ninth_bits: Vec<u8>,
+}
 
-    pub fn read8(&self) -> u8 {
-        self.data.to_be_bytes()[1]
+impl RDRAMState {
+    fn new(rdram: &RDRAM) -> Self {
+        Self {
+            data: rdram.data.to_vec(),
+            ninth_bits: rdram.ninth_bits.to_vec(),
+        }
     }
 
-    pub fn write8(&mut self, data: u8) {
-        self.data = (self.data & 0x100) | (data as u16);
+    fn restore(&self, rdram: &mut RDRAM) -> std::result::Result<(), String> {
+        if self.data.len() != RDRAM_SIZE || self.ninth_bits.len() != RDRAM_SIZE / 8 {
+            return Err(format!("Invalid RDRAM size {}", self.data.len()));
+        }
+        rdram.data.copy_from_slice(&self.data);
+        rdram.ninth_bits.copy_from_slice(&self.ninth_bits);
+        Ok(())
     }
 }
 
+/*
+    RDRAM bytes are 9 bits, the 9th only used by the RDP for coverage and Z. The 8 bits the CPU
+    sees are kept contiguous so DMAs and the VI can work on slices, the 9th bits are packed
+    8 to a byte apart: 4.5MB instead of the 8MB of a u16 per byte.
+*/
 pub struct RDRAM {
-    data: Box<[Byte; 0x400000]>,
+    data: Box<[u8]>,
+    ninth_bits: Box<[u8]>,
 }
 
 impl RDRAM {
     pub fn new() -> Self {
         Self {
-            data: box_array![Byte::new(); 0x400000],
+            data: vec![0; RDRAM_SIZE].into_boxed_slice(),
+            ninth_bits: vec![0; RDRAM_SIZE / 8].into_boxed_slice(),
         }
     }
 
+    fn ninth_bit(&self, address: usize) -> u16 {
+        ((self.ninth_bits[address / 8] >> (address % 8)) & 1) as u16
+    }
+
+    // The 9 bits of the byte
     pub fn read(&self, address: i64) -> u16 {
-        self.data[address as usize].read()
+        let address = address as usize;
+        (self.ninth_bit(address) << 8) | self.data[address] as u16
     }
 
     pub fn write(&mut self, address: i64, data: u16) {
-        self.data[address as usize].write(data);
+        let address = address as usize;
+        self.data[address] = data as u8;
+        let mask = 1 << (address % 8);
+        match data & 0x100 != 0 {
+            true => self.ninth_bits[address / 8] |= mask,
+            false => self.ninth_bits[address / 8] &= !mask,
+        };
     }
 
+    // 8 bit accesses leave the 9th bit as it was
     pub fn read8(&self, address: i64) -> u8 {
-        self.data[address as usize].read8()
+        self.data[address as usize]
     }
 
     pub fn write8(&mut self, address: i64, data: u8) {
-        self.data[address as usize].write8(data);
+        self.data[address as usize] = data;
+    }
+
+    pub fn read_range(&self, address: i64, len: usize) -> Vec<u8> {
+        let start = address as usize;
+        self.data[start..start + len].to_vec()
+    }
+
+    // The 8 bit view of the whole RDRAM, for DMAs and the VI
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    // Still a u16 per byte, so older savestates load
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for address in 0..RDRAM_SIZE {
+            writer.write_u16(self.read(address as i64));
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for address in 0..RDRAM_SIZE {
+            self.write(address as i64, reader.read_u16()?);
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RDRAM {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        RDRAMState::new(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RDRAM {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut rdram = RDRAM::new();
+        RDRAMState::deserialize(deserializer)?.restore(&mut rdram).map_err(D::Error::custom)?;
+        Ok(rdram)
+    }
+}
+
+#[cfg(test)]
+mod rdram_tests {
+    use super::*;
+
+    #[test]
+    fn test_serde_state() {
+        let mut rdram = RDRAM::new();
+        rdram.write(0, 0x1FF);
+        rdram.write(9, 0x134);
+        rdram.write8(RDRAM_SIZE as i64 - 1, 0x56);
+        let state = RDRAMState::new(&rdram);
+        assert_eq!(state.data.len(), RDRAM_SIZE);
+        assert_eq!(&state.ninth_bits[..2], &[0b1, 0b10]);
+
+        let mut restored = RDRAM::new();
+        state.restore(&mut restored).unwrap();
+        assert_eq!(restored.read(0), 0x1FF);
+        assert_eq!(restored.read(9), 0x134);
+        assert_eq!(restored.read(RDRAM_SIZE as i64 - 1), 0x56);
+        restored.write8(9, 0x12);
+        assert_eq!(restored.read(9), 0x112);
+        assert_eq!(&restored.as_slice()[8..10], &[0x00, 0x12]);
+
+        let truncated = RDRAMState {
+            data: vec![0; 16],
+            ninth_bits: vec![0; 2],
+        };
+        assert!(truncated.restore(&mut restored).is_err());
     }
 }
@@ -0,0 +1,163 @@
+use std::io::Result;
+
+use crate::rdram::RDRAM_SIZE;
+use crate::savestate::{StateReader, StateWriter};
+
+/*
+    Registers of the RDRAM chips themselves, at 0x03F00000. IPL3 broadcasts a Device ID to every
+    chip, then gives each one its own through the old ID: the chips are daisy chained, so only the
+    first one still answering to it takes the write. Reading Device Type back from an ID no chip
+    answers to returns 0, which is how IPL3 knows it ran out of memory.
+    https://n64brew.dev/wiki/RDRAM#RDRAM_registers
+*/
+pub const RDRAM_DEVICE_TYPE: usize = 0;
+pub const RDRAM_DEVICE_ID: usize = 1;
+pub const RDRAM_DELAY: usize = 2;
+pub const RDRAM_MODE: usize = 3;
+pub const RDRAM_REF_INTERVAL: usize = 4;
+pub const RDRAM_REF_ROW: usize = 5;
+pub const RDRAM_RAS_INTERVAL: usize = 6;
+pub const RDRAM_MIN_INTERVAL: usize = 7;
+pub const RDRAM_ADDRESS_SELECT: usize = 8;
+pub const RDRAM_DEVICE_MANUFACTURER: usize = 9;
+const REGISTER_COUNT: usize = 10;
+
+// Address bit 19 writes every chip at once, bits 10 to 18 otherwise pick the chip by its ID
+pub const RDRAM_BROADCAST: i64 = 0x80000;
+
+// 2MB chips
+pub const RDRAM_MODULE_SIZE: usize = 0x200000;
+pub const RDRAM_MODULES: usize = RDRAM_SIZE / RDRAM_MODULE_SIZE;
+
+// Power on values of the NEC chips in retail consoles
+const DEVICE_TYPE: u32 = 0xB5190010;
+const MODE: u32 = 0xC4C0C0C0;
+const MIN_INTERVAL: u32 = 0x0040C0E0;
+const DEVICE_MANUFACTURER: u32 = 0x00000500;
+
+// The ID is spread across the register: bits 0-5 at 26, bit 6 at 23, bits 7-14 at 8 and bit 15 at 7
+pub fn device_id(register: u32) -> u16 {
+    (((register >> 26) & 0x3F) | (((register >> 23) & 0x1) << 6) | (((register >> 8) & 0xFF) << 7) | (((register >> 7) & 0x1) << 15)) as u16
+}
+
+pub struct RdramRegisters {
+    modules: [[u32; REGISTER_COUNT]; RDRAM_MODULES],
+    // Bytes of the word being written, registers only change on the last one
+    pending_write: [u8; 4],
+}
+
+impl RdramRegisters {
+    pub fn new() -> Self {
+        let mut registers = [0; REGISTER_COUNT];
+        registers[RDRAM_DEVICE_TYPE] = DEVICE_TYPE;
+        registers[RDRAM_MODE] = MODE;
+        registers[RDRAM_MIN_INTERVAL] = MIN_INTERVAL;
+        registers[RDRAM_DEVICE_MANUFACTURER] = DEVICE_MANUFACTURER;
+        Self {
+            modules: [registers; RDRAM_MODULES],
+            pending_write: [0; 4],
+        }
+    }
+
+    pub fn module(&self, index: usize) -> &[u32] {
+        &self.modules[index]
+    }
+
+    // First chip in the chain with the ID in the address
+    fn find_module(&self, address: i64) -> Option<usize> {
+        let id = ((address >> 10) & 0x1FF) as u16;
+        self.modules.iter().position(|registers| device_id(registers[RDRAM_DEVICE_ID]) == id)
+    }
+
+    pub fn read_register(&self, address: i64) -> u32 {
+        let register = ((address >> 2) & 0xFF) as usize;
+        if address & RDRAM_BROADCAST != 0 || register >= REGISTER_COUNT {
+            return 0;
+        }
+        self.find_module(address).map_or(0, |module| self.modules[module][register])
+    }
+
+    pub fn get_register(&self, address: i64) -> u8 {
+        self.read_register(address).to_be_bytes()[(address & 0b11) as usize]
+    }
+
+    pub fn set_register(&mut self, address: i64, data: u8) {
+        let byte = (address & 0b11) as usize;
+        self.pending_write[byte] = data;
+        if byte != 3 {
+            return;
+        }
+        let value = u32::from_be_bytes(self.pending_write);
+        let register = ((address >> 2) & 0xFF) as usize;
+        // Device Type and Manufacturer are read only, the row register isn't kept
+        if register >= REGISTER_COUNT || register == RDRAM_DEVICE_TYPE || register == RDRAM_DEVICE_MANUFACTURER {
+            return;
+        }
+        match address & RDRAM_BROADCAST != 0 {
+            true => self.modules.iter_mut().for_each(|registers| registers[register] = value),
+            false => if let Some(module) = self.find_module(address) {
+                self.modules[module][register] = value;
+            },
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for registers in self.modules.iter() {
+            for register in registers.iter() {
+                writer.write_u32(*register);
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for registers in self.modules.iter_mut() {
+            for register in registers.iter_mut() {
+                *register = reader.read_u32()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rdram_registers_tests {
+    use super::*;
+
+    fn write_register(registers: &mut RdramRegisters, address: i64, value: u32) {
+        for (i, byte) in value.to_be_bytes().iter().enumerate() {
+            registers.set_register(address + i as i64, *byte);
+        }
+    }
+
+    // Inverse of device_id, how IPL3 writes it
+    fn device_id_register(id: u16) -> u32 {
+        let id = id as u32;
+        ((id & 0x3F) << 26) | (((id >> 6) & 0x1) << 23) | (((id >> 7) & 0xFF) << 8) | (((id >> 15) & 0x1) << 7)
+    }
+
+    #[test]
+    fn test_device_ids() {
+        let mut registers = RdramRegisters::new();
+        assert_eq!(device_id(device_id_register(0x1234)), 0x1234);
+        // Every chip moves out of the way, then each gets its own ID in turn
+        let high = 0x1F0;
+        write_register(&mut registers, 0x03F00000 | RDRAM_BROADCAST | (RDRAM_DEVICE_ID << 2) as i64, device_id_register(high));
+        for id in [0, 2] {
+            let address = 0x03F00000 | (high as i64) << 10 | (RDRAM_DEVICE_ID << 2) as i64;
+            assert_eq!(registers.read_register(0x03F00000 | (high as i64) << 10), DEVICE_TYPE);
+            write_register(&mut registers, address, device_id_register(id));
+        }
+        assert_eq!(registers.read_register(0x03F00000 | (high as i64) << 10), 0);
+        assert_eq!(device_id(registers.module(1)[RDRAM_DEVICE_ID]), 2);
+
+        // Only the chip with the ID in the address
+        write_register(&mut registers, 0x03F00000 | 2 << 10 | (RDRAM_DELAY << 2) as i64, 0x18082838);
+        assert_eq!(registers.module(0)[RDRAM_DELAY], 0);
+        assert_eq!(registers.read_register(0x03F00000 | 2 << 10 | (RDRAM_DELAY << 2) as i64), 0x18082838);
+        write_register(&mut registers, 0x03F00000 | RDRAM_BROADCAST | (RDRAM_MODE << 2) as i64, 0xC0C0C0C0);
+        assert!(registers.modules.iter().all(|module| module[RDRAM_MODE] == 0xC0C0C0C0));
+        // Read only
+        write_register(&mut registers, 0x03F00000 | RDRAM_BROADCAST, 0);
+        assert_eq!(registers.module(0)[RDRAM_DEVICE_TYPE], DEVICE_TYPE);
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+
+pub const MAX_RECENT_ROMS: usize = 10;
+
+// Key of the list in the GUI's persisted storage
+pub const RECENT_ROMS_KEY: &str = "recent_roms";
+
+// Most recently opened first
+pub struct RecentRoms {
+    paths: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn new() -> Self {
+        Self {
+            paths: Vec::new(),
+        }
+    }
+
+    // One path per line, the format the GUI stores
+    pub fn from_lines(data: &str) -> Self {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let path = PathBuf::from(line);
+            if !paths.contains(&path) && paths.len() < MAX_RECENT_ROMS {
+                paths.push(path);
+            }
+        }
+        Self {
+            paths,
+        }
+    }
+
+    pub fn to_lines(&self) -> String {
+        self.paths.iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect()
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn push(&mut self, path: &Path) {
+        self.paths.retain(|other| other != path);
+        self.paths.insert(0, path.to_path_buf());
+        self.paths.truncate(MAX_RECENT_ROMS);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.paths.retain(|other| other != path);
+    }
+
+    pub fn clear(&mut self) {
+        self.paths.clear();
+    }
+}
+
+#[cfg(test)]
+mod recent_roms_tests {
+    use super::*;
+
+    #[test]
+    fn test_push() {
+        let mut recent = RecentRoms::new();
+        for i in 0..MAX_RECENT_ROMS + 2 {
+            recent.push(Path::new(&format!("/roms/{}.z64", i)));
+        }
+        recent.push(Path::new("/roms/5.z64"));
+        assert_eq!(recent.paths().len(), MAX_RECENT_ROMS);
+        assert_eq!(recent.paths()[0], Path::new("/roms/5.z64"));
+        assert_eq!(recent.paths()[1], Path::new("/roms/11.z64"));
+
+        let restored = RecentRoms::from_lines(&recent.to_lines());
+        assert_eq!(restored.paths(), recent.paths());
+    }
+}
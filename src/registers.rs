@@ -1,3 +1,10 @@
+use std::io::Result;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::log::{log, Level, Subsystem};
+use crate::savestate::{StateReader, StateWriter};
+
 pub trait Register<T: PartialOrd + Copy> {
     fn get(&self) -> T;
     fn set(&mut self, val: T);
@@ -25,8 +32,20 @@ pub const CPU_REGISTER_NAMES: [&'static str; 32] = [
     "t8",   "t9", "k0", "k1", "gp", "sp", "s8", "ra"
 ];
 
+// What CPURegisters serializes as, the boxed registers can't be derived
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "CPURegisters")]
+struct CPURegistersState {
+    registers: [i64; 32],
+    program_counter: i64,
+    next_program_counter: i64,
+    hi: i64,
+    lo: i64,
+    load_link: bool,
+}
+
 pub struct CPURegisters {
-    registers: [Box<dyn Register<i64>>; 32],
+    registers: [Box<dyn Register<i64> + Send + Sync>; 32],
     program_counter: Generic<i64>,
     next_program_counter: Generic<i64>,
     hi: Generic<i64>,
@@ -171,6 +190,58 @@ impl CPURegisters {
     pub fn get_lo(&self) -> i64 {
         self.lo.get()
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for index in 0..32 {
+            writer.write_i64(self.get_by_number(index));
+        }
+        writer.write_i64(self.get_program_counter());
+        writer.write_i64(self.get_next_program_counter());
+        writer.write_i64(self.get_hi());
+        writer.write_i64(self.get_lo());
+        writer.write_bool(self.get_load_link());
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for index in 0..32 {
+            self.set_by_number(index, reader.read_i64()?);
+        }
+        self.set_program_counter(reader.read_i64()?);
+        self.set_next_program_counter(reader.read_i64()?);
+        self.set_hi(reader.read_i64()?);
+        self.set_lo(reader.read_i64()?);
+        self.set_load_link(reader.read_bool()?);
+        Ok(())
+    }
+}
+
+impl Serialize for CPURegisters {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        CPURegistersState {
+            registers: std::array::from_fn(|index| self.get_by_number(index)),
+            program_counter: self.get_program_counter(),
+            next_program_counter: self.get_next_program_counter(),
+            hi: self.get_hi(),
+            lo: self.get_lo(),
+            load_link: self.get_load_link(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CPURegisters {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let state = CPURegistersState::deserialize(deserializer)?;
+        let mut registers = CPURegisters::new();
+        for (index, val) in state.registers.into_iter().enumerate() {
+            registers.set_by_number(index, val);
+        }
+        registers.set_program_counter(state.program_counter);
+        registers.set_next_program_counter(state.next_program_counter);
+        registers.set_hi(state.hi);
+        registers.set_lo(state.lo);
+        registers.set_load_link(state.load_link);
+        Ok(registers)
+    }
 }
 
 pub const CP0_REGISTER_NAMES: [&'static str; 32] = [
@@ -180,6 +251,49 @@ pub const CP0_REGISTER_NAMES: [&'static str; 32] = [
     "24", "25", "ParityError", "CacheError", "TagLo", "TagHi", "ErrorEPC", "31"
 ];
 
+// IP7 of Cause, set when Count reaches Compare
+pub const CAUSE_TIMER_INTERRUPT: i32 = 1 << 15;
+
+// FR of Status, 32 64 bit FPRs when set, see CP1Registers
+pub const STATUS_FR: i32 = 1 << 26;
+
+/*
+    Config: https://n64brew.dev/wiki/COP0#Config
+    K0 (bits 0-2) is the cache algorithm of KSEG0, BE (bit 15) selects big endian, EP (bits 24-27) is the
+    writeback data pattern and EC (bits 28-30) the system clock ratio, hardwired to 1:1.5 on the N64.
+    Only K0, CU (bit 3), BE and EP can be written, the rest reads as the constants below. There's
+    no cache model, every access sees RDRAM whatever K0 says, so it's only decoded by the debugger.
+*/
+pub const CONFIG_BIG_ENDIAN: i32 = 1 << 15;
+const CONFIG_WRITABLE: i32 = 0x0F00800F;
+const CONFIG_HARDWIRED: i32 = 0x70066460;
+
+// Cache algorithm of K0 and the C field of TLB entries that bypasses the caches, any other value is cached
+pub const CACHE_ALGORITHM_UNCACHED: u8 = 2;
+
+// ExcCode field of the Cause register: https://n64brew.dev/wiki/COP0#Cause
+pub fn exception_code_name(code: u8) -> &'static str {
+    match code {
+        0 => "Int",
+        1 => "Mod",
+        2 => "TLBL",
+        3 => "TLBS",
+        4 => "AdEL",
+        5 => "AdES",
+        6 => "IBE",
+        7 => "DBE",
+        8 => "Sys",
+        9 => "Bp",
+        10 => "RI",
+        11 => "CpU",
+        12 => "Ov",
+        13 => "Tr",
+        15 => "FPE",
+        23 => "WATCH",
+        _ => "Reserved",
+    }
+}
+
 pub struct CP0Registers {
     index: Generic<i32>,
     random: Generic<i32>,
@@ -234,7 +348,7 @@ impl CP0Registers {
             cause: Generic(0),
             epc: Generic(0),
             prid: Generic(0),
-            config: Generic(0),
+            config: Generic(CONFIG_HARDWIRED | CONFIG_BIG_ENDIAN),
             lladdr: Generic(0),
             watch_lo: Generic(0),
             watch_hi: Generic(0),
@@ -258,7 +372,7 @@ impl CP0Registers {
         cp0.set_by_name_32("random", 0x0000001F);
         cp0.set_by_name_32("status", 0x70400004);
         cp0.set_by_name_32("PRId", 0x00000B00);
-        cp0.set_by_name_32("config", 0x0006E463);
+        cp0.set_config(0x0006E463);
 
         cp0
     }
@@ -270,7 +384,7 @@ impl CP0Registers {
     pub fn is_32bits(index: usize) -> bool {
         match index {
             0 | 1 | 5 | 6 | 9 | 11 | 12 | 13 | 15 | 16 | 17 | 18 | 19 | 26 | 27 | 28 | 29 => true,
-            2 | 3 | 4 | 8 | 10 | 14 | 20 | 21 | 22 | 23 | 24 | 25 | 30 => false,
+            2 | 3 | 4 | 7 | 8 | 10 | 14 | 20 | 21 | 22 | 23 | 24 | 25 | 30 | 31 => false,
             _ => unreachable!(),
         }
     }
@@ -398,6 +512,186 @@ impl CP0Registers {
         let index = CP0Registers::find_index(name);
         self.set_by_number_64(index, val);
     }
+
+    /*
+        Count reaching Compare sets IP7 in Cause, the timer interrupt, until Compare is written again.
+        https://n64brew.dev/wiki/COP0#Count
+    */
+    pub fn advance_count(&mut self, ticks: u32) {
+        let count = self.count.get() as u32;
+        let compare = self.compare.get() as u32;
+        if ticks > 0 && compare.wrapping_sub(count).wrapping_sub(1) < ticks {
+            self.cause.set(self.cause.get() | CAUSE_TIMER_INTERRUPT);
+        }
+        self.count.set(count.wrapping_add(ticks) as i32);
+    }
+
+    pub fn set_compare(&mut self, val: i32) {
+        self.compare.set(val);
+        self.cause.set(self.cause.get() & !CAUSE_TIMER_INTERRUPT);
+    }
+
+    // What MTC0 writes, the raw register is still set through set_by_number_32 by savestates and the debugger
+    pub fn set_config(&mut self, val: i32) {
+        if val & CONFIG_BIG_ENDIAN == 0 {
+            log!(Level::Warn, Subsystem::CPU, "Little endian mode selected in Config, it isn't emulated");
+        }
+        self.config.set((val & CONFIG_WRITABLE) | CONFIG_HARDWIRED);
+    }
+
+    pub fn fr(&self) -> bool {
+        self.status.get() & STATUS_FR != 0
+    }
+
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for index in 0..32 {
+            match CP0Registers::is_32bits(index) {
+                true => writer.write_i64(self.get_by_number_32(index) as i64),
+                false => writer.write_i64(self.get_by_number_64(index)),
+            };
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for index in 0..32 {
+            let val = reader.read_i64()?;
+            match CP0Registers::is_32bits(index) {
+                true => self.set_by_number_32(index, val as i32),
+                false => self.set_by_number_64(index, val),
+            };
+        }
+        Ok(())
+    }
+}
+
+// Every register as 64 bits, like the savestates do
+impl Serialize for CP0Registers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let registers: [i64; 32] = std::array::from_fn(|index| match CP0Registers::is_32bits(index) {
+            true => self.get_by_number_32(index) as i64,
+            false => self.get_by_number_64(index),
+        });
+        registers.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CP0Registers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut registers = CP0Registers::new();
+        for (index, val) in <[i64; 32]>::deserialize(deserializer)?.into_iter().enumerate() {
+            match CP0Registers::is_32bits(index) {
+                true => registers.set_by_number_32(index, val as i32),
+                false => registers.set_by_number_64(index, val),
+            };
+        }
+        Ok(registers)
+    }
+}
+
+/*
+    FPU control registers: https://n64brew.dev/wiki/COP1
+    FCR0 is the implementation/revision register, FCR31 holds the rounding mode, flags and compare result
+*/
+#[derive(Serialize, Deserialize)]
+pub struct CP1Registers {
+    fpr: [i64; 32],
+    fcr0: i32,
+    fcr31: i32,
+}
+
+impl CP1Registers {
+    pub fn new() -> Self {
+        Self {
+            fpr: [0; 32],
+            fcr0: 0x00000A00,
+            fcr31: 0,
+        }
+    }
+
+    pub fn get_fpr(&self, index: usize) -> i64 {
+        if index > 31 {
+            unreachable!("Register number {} not valid", index);
+        }
+        self.fpr[index]
+    }
+
+    pub fn set_fpr(&mut self, index: usize, val: i64) {
+        if index > 31 {
+            unreachable!("Register number {} not valid", index);
+        }
+        self.fpr[index] = val;
+    }
+
+    /*
+        The FPRs as instructions see them, depending on FR in Status. With FR set there are 32 registers
+        of 64 bits. With FR clear, the mode after reset, doubles only live in the even registers and the
+        odd ones are the upper halves of them for word accesses, 16 registers of 64 bits in total.
+        get_fpr and set_fpr are the registers as they're stored, which is what savestates keep.
+        https://n64brew.dev/wiki/COP1
+    */
+    pub fn get_fpr_32(&self, index: usize, fr: bool) -> i32 {
+        match fr || index & 1 == 0 {
+            true => self.get_fpr(index) as i32,
+            false => (self.get_fpr(index - 1) >> 32) as i32,
+        }
+    }
+
+    // The other half of the register is left as it was
+    pub fn set_fpr_32(&mut self, index: usize, val: i32, fr: bool) {
+        match fr || index & 1 == 0 {
+            true => {
+                let upper = self.get_fpr(index) as u64 & 0xFFFFFFFF00000000;
+                self.set_fpr(index, (upper | val as u32 as u64) as i64);
+            },
+            false => {
+                let lower = self.get_fpr(index - 1) as u64 & 0xFFFFFFFF;
+                self.set_fpr(index - 1, (((val as u32 as u64) << 32) | lower) as i64);
+            },
+        };
+    }
+
+    // The lowest bit of the index is ignored with FR clear
+    pub fn get_fpr_64(&self, index: usize, fr: bool) -> i64 {
+        match fr {
+            true => self.get_fpr(index),
+            false => self.get_fpr(index & !1),
+        }
+    }
+
+    pub fn set_fpr_64(&mut self, index: usize, val: i64, fr: bool) {
+        match fr {
+            true => self.set_fpr(index, val),
+            false => self.set_fpr(index & !1, val),
+        };
+    }
+
+    pub fn get_fcr0(&self) -> i32 {
+        self.fcr0
+    }
+
+    pub fn get_fcr31(&self) -> i32 {
+        self.fcr31
+    }
+
+    pub fn set_fcr31(&mut self, val: i32) {
+        self.fcr31 = val;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for val in self.fpr {
+            writer.write_i64(val);
+        }
+        writer.write_u32(self.fcr31 as u32);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for val in self.fpr.iter_mut() {
+            *val = reader.read_i64()?;
+        }
+        self.fcr31 = reader.read_u32()? as i32;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -446,4 +740,79 @@ mod cp0_registers_tests {
         assert_eq!(registers.get_by_name_64("context"), 20);
         assert_eq!(registers.get_by_number_64(4), 20);
     }
+
+    #[test]
+    fn test_advance_count() {
+        let mut registers = CP0Registers::new();
+        registers.set_compare(10);
+        registers.advance_count(9);
+        assert_eq!(registers.get_by_name_32("cause"), 0);
+        registers.advance_count(1);
+        assert_eq!(registers.get_by_name_32("count"), 10);
+        assert_eq!(registers.get_by_name_32("cause"), CAUSE_TIMER_INTERRUPT);
+        registers.set_compare(5);
+        assert_eq!(registers.get_by_name_32("cause"), 0);
+        // Wrapping around to reach it
+        registers.set_by_name_32("count", -2);
+        registers.advance_count(8);
+        assert_eq!(registers.get_by_name_32("count"), 6);
+        assert_eq!(registers.get_by_name_32("cause"), CAUSE_TIMER_INTERRUPT);
+    }
+
+    #[test]
+    fn test_config() {
+        let mut registers = CP0Registers::new_hle();
+        assert_eq!(registers.get_by_name_32("config"), 0x7006E463);
+        // K0 uncached and EP DxxDxx, EC and the constant bits can't be changed
+        registers.set_config(0x0600800A);
+        assert_eq!(registers.get_by_name_32("config"), 0x7606E46A);
+    }
+
+    #[test]
+    fn test_exception_code_name() {
+        assert_eq!(exception_code_name(0), "Int");
+        assert_eq!(exception_code_name(8), "Sys");
+        assert_eq!(exception_code_name(14), "Reserved");
+    }
+}
+
+#[cfg(test)]
+mod cp1_registers_tests {
+    use super::*;
+
+    #[test]
+    fn test_save_state() {
+        let mut registers = CP1Registers::new();
+        registers.set_fpr(31, 1.5_f64.to_bits() as i64);
+        registers.set_fcr31(0x01000003);
+        let mut writer = StateWriter::new();
+        registers.save_state(&mut writer);
+        let data = writer.finish();
+        let mut loaded = CP1Registers::new();
+        loaded.load_state(&mut StateReader::new(&data).unwrap()).unwrap();
+        assert_eq!(f64::from_bits(loaded.get_fpr(31) as u64), 1.5);
+        assert_eq!(loaded.get_fcr31(), 0x01000003);
+    }
+
+    #[test]
+    fn test_fr_modes() {
+        let mut registers = CP1Registers::new();
+        registers.set_fpr_64(2, 1.5_f64.to_bits() as i64, false);
+        // The odd register is the upper word of the double
+        assert_eq!(registers.get_fpr_32(3, false), (1.5_f64.to_bits() >> 32) as i32);
+        assert_eq!(registers.get_fpr_32(2, false), 0);
+        assert_eq!(registers.get_fpr_64(3, false), 1.5_f64.to_bits() as i64);
+        registers.set_fpr_32(3, 0x40000000, false);
+        assert_eq!(f64::from_bits(registers.get_fpr(2) as u64), 2.0);
+        assert_eq!(registers.get_fpr(3), 0);
+
+        // 32 registers on their own
+        registers.set_fpr_32(3, 0x3FC00000, true);
+        assert_eq!(f32::from_bits(registers.get_fpr_32(3, true) as u32), 1.5);
+        assert_eq!(registers.get_fpr(3), 0x3FC00000);
+        assert_eq!(f64::from_bits(registers.get_fpr_64(2, true) as u64), 2.0);
+        registers.set_fpr_64(3, -1, true);
+        assert_eq!(registers.get_fpr(2), 2.0_f64.to_bits() as i64);
+        assert_eq!(registers.get_fpr_64(3, true), -1);
+    }
 }
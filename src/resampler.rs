@@ -0,0 +1,155 @@
+use std::f64::consts::PI;
+
+/*
+    Converts the stereo samples of the AI, at the rate AI_DACRATE divides the VI clock into,
+    to the rate of the host output. Linear is cheap, sinc filters out what the host rate can't
+    represent instead of folding it back as noise.
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ResamplerKind {
+    Linear,
+    Sinc,
+}
+
+impl ResamplerKind {
+    pub const ALL: [ResamplerKind; 2] = [ResamplerKind::Linear, ResamplerKind::Sinc];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResamplerKind::Linear => "Linear",
+            ResamplerKind::Sinc => "Sinc",
+        }
+    }
+
+    // Input samples used on each side of an output sample
+    fn half_taps(&self) -> usize {
+        match self {
+            ResamplerKind::Linear => 1,
+            ResamplerKind::Sinc => SINC_HALF_TAPS,
+        }
+    }
+}
+
+const SINC_HALF_TAPS: usize = 8;
+
+fn sinc(x: f64) -> f64 {
+    match x == 0.0 {
+        true => 1.0,
+        false => (PI * x).sin() / (PI * x),
+    }
+}
+
+// Samples go in as they come from the AI and out whenever enough input is there, the rest waits for the next call
+pub struct Resampler {
+    kind: ResamplerKind,
+    input_rate: u64,
+    output_rate: u64,
+    // Input not fully used yet, the first samples are the history the filter still needs
+    input: Vec<[f64; 2]>,
+    // Where the next output sample falls, in input samples from the start of input
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(kind: ResamplerKind, output_rate: u64) -> Self {
+        let half_taps = kind.half_taps();
+        Self {
+            kind,
+            input_rate: output_rate,
+            output_rate,
+            // Silence before the first samples, so the filter can start right away
+            input: vec![[0.0; 2]; half_taps],
+            position: (half_taps - 1) as f64,
+        }
+    }
+
+    pub fn kind(&self) -> ResamplerKind {
+        self.kind
+    }
+
+    pub fn output_rate(&self) -> u64 {
+        self.output_rate
+    }
+
+    // Games can change AI_DACRATE at any time, the position carries over
+    pub fn set_input_rate(&mut self, rate: u64) {
+        if rate > 0 {
+            self.input_rate = rate;
+        }
+    }
+
+    pub fn process(&mut self, samples: &[[i16; 2]]) -> Vec<[i16; 2]> {
+        self.input.extend(samples.iter().map(|sample| [sample[0] as f64, sample[1] as f64]));
+        let half_taps = self.kind.half_taps();
+        let step = self.input_rate as f64 / self.output_rate as f64;
+        let mut output = Vec::with_capacity((samples.len() as f64 / step) as usize + 1);
+        while (self.position as usize) + half_taps < self.input.len() {
+            let [left, right] = match self.kind {
+                ResamplerKind::Linear => self.linear(),
+                ResamplerKind::Sinc => self.sinc(step),
+            };
+            output.push([left.round().clamp(-32768.0, 32767.0) as i16, right.round().clamp(-32768.0, 32767.0) as i16]);
+            self.position += step;
+        }
+        // Keep only the history the next output sample needs
+        let used = (self.position as usize + 1).saturating_sub(half_taps).min(self.input.len());
+        self.input.drain(..used);
+        self.position -= used as f64;
+        output
+    }
+
+    fn linear(&self) -> [f64; 2] {
+        let index = self.position as usize;
+        let fraction = self.position - index as f64;
+        let (current, next) = (self.input[index], self.input[index + 1]);
+        [0, 1].map(|channel| current[channel] + (next[channel] - current[channel]) * fraction)
+    }
+
+    // Windowed sinc, with the cutoff lowered to the output's Nyquist frequency when downsampling
+    fn sinc(&self, step: f64) -> [f64; 2] {
+        let cutoff = (1.0 / step).min(1.0);
+        let index = self.position as usize;
+        let mut sum = [0.0; 2];
+        let mut weights = 0.0;
+        for tap in (index + 1 - SINC_HALF_TAPS)..=(index + SINC_HALF_TAPS) {
+            let distance = self.position - tap as f64;
+            // Hann window over the taps
+            let window = 0.5 * (1.0 + (PI * distance / SINC_HALF_TAPS as f64).cos());
+            let weight = cutoff * sinc(cutoff * distance) * window;
+            sum[0] += self.input[tap][0] * weight;
+            sum[1] += self.input[tap][1] * weight;
+            weights += weight;
+        }
+        // Normalized so a constant input comes out the same
+        match weights == 0.0 {
+            true => sum,
+            false => sum.map(|value| value / weights),
+        }
+    }
+}
+
+#[cfg(test)]
+mod resampler_tests {
+    use super::*;
+
+    #[test]
+    fn test_resample() {
+        for kind in ResamplerKind::ALL {
+            let mut resampler = Resampler::new(kind, 48000);
+            resampler.set_input_rate(32000);
+            // A constant signal keeps its level, in chunks of any size
+            let mut output = Vec::new();
+            for _ in 0..10 {
+                output.extend(resampler.process(&[[1000, -1000]; 320]));
+            }
+            assert!((output.len() as i64 - 4800).abs() <= SINC_HALF_TAPS as i64 * 2, "{} samples", output.len());
+            assert!(output[100..].iter().all(|sample| *sample == [1000, -1000]), "{}", kind.name());
+        }
+
+        // Same rates only delay the input
+        let mut resampler = Resampler::new(ResamplerKind::Linear, 44100);
+        resampler.set_input_rate(44100);
+        assert_eq!(resampler.process(&[[1, 2], [3, 4], [5, 6]]), vec![[0, 0], [1, 2], [3, 4]]);
+        assert_eq!(resampler.process(&[[7, 8]]), vec![[5, 6]]);
+    }
+}
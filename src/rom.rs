@@ -1,12 +1,116 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 
+use crate::achievements;
+use crate::archive::{decompress_gzip, zip_entries};
+use crate::patch::{self, CIC, PATCH_EXTENSIONS};
+use crate::pif::EEPROM_BLOCK_SIZE;
+use crate::save::{SaveType, detect_save_type, save_file_name};
+use crate::savestate::{StateReader, StateWriter};
+use crate::storage::{self, DataKind};
 use crate::mmu::CARTRIDGE_DOMAIN_2_ADDRESS_2;
 use crate::mmu::CARTRIDGE_DOMAIN_1_ADDRESS_2;
 
+// https://n64brew.dev/wiki/ROM_Header
+pub const HEADER_COUNTRY_CODE: usize = 0x3E;
+
+// Extensions of the dumps looked for inside archives
+pub const ROM_EXTENSIONS: [&str; 4] = ["z64", "v64", "n64", "rom"];
+
+// What fits in the cartridge domain from 0x10000000 to 0x1FBFFFFF
+pub const MAX_ROM_SIZE: usize = 0x0FC00000;
+
+// Big enough for the largest cartridge save, the 128KB FlashRAM
+pub const CART_RAM_SIZE: usize = 0x20000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Region {
+    NTSC,
+    PAL,
+    MPAL,
+}
+
+impl Region {
+    pub fn from_country_code(code: u8) -> Self {
+        match code {
+            // Germany, France, Italy, Europe, Spain, Australia and the generic PAL codes
+            b'D' | b'F' | b'I' | b'P' | b'S' | b'U' | b'X' | b'Y' | b'H' | b'L' | b'W' => Self::PAL,
+            // Brazil
+            b'B' => Self::MPAL,
+            _ => Self::NTSC,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NTSC => "NTSC",
+            Self::PAL => "PAL",
+            Self::MPAL => "MPAL",
+        }
+    }
+
+    // Value stored by IPL3 in osTvType (0x80000300)
+    pub fn tv_type(&self) -> u32 {
+        match self {
+            Self::PAL => 0,
+            Self::NTSC => 1,
+            Self::MPAL => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExpansionPak {
+    Unused,
+    Optional,
+    Required,
+}
+
+impl ExpansionPak {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unused => "Not used",
+            Self::Optional => "Optional",
+            Self::Required => "Required",
+        }
+    }
+}
+
+// Games known to use the Expansion Pak, by the same two characters of the game code as the save types
+const EXPANSION_PAK_DATABASE: [(&str, ExpansionPak); 6] = [
+    ("DO", ExpansionPak::Required), // Donkey Kong 64
+    ("ZS", ExpansionPak::Required), // The Legend of Zelda: Majora's Mask
+    ("NP", ExpansionPak::Required), // Perfect Dark
+    ("RS", ExpansionPak::Optional), // Star Wars: Rogue Squadron
+    ("SQ", ExpansionPak::Optional), // StarCraft 64
+    ("T2", ExpansionPak::Optional), // Turok 2: Seeds of Evil
+];
+
+// What the ROM information window shows, parsed from the header: https://n64brew.dev/wiki/ROM_Header
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RomInfo {
+    pub name: String,
+    // Category, two character ID and destination, like "NSME"
+    pub game_code: String,
+    pub version: u8,
+    pub region: Region,
+    pub size: usize,
+    pub crc: (u32, u32),
+    // None when the CIC is unknown or the ROM is too small
+    pub calculated_crc: Option<(u32, u32)>,
+    pub cic: Option<CIC>,
+    pub save_type: SaveType,
+    pub expansion_pak: ExpansionPak,
+    // Identifies the game on RetroAchievements
+    pub ra_hash: String,
+}
+
 pub struct ROM {
     data: Vec<u8>,
     ram: Vec<u8>,
+    ram_dirty: bool,
+    path: Option<PathBuf>,
 }
 
 impl ROM {
@@ -14,6 +118,8 @@ impl ROM {
         Self {
             data: Vec::new(),
             ram: Vec::new(),
+            ram_dirty: false,
+            path: None,
         }
     }
 
@@ -21,12 +127,180 @@ impl ROM {
         let mut file = File::open(filename)?; 
         let mut data = vec![];
         file.read_to_end(&mut data)?;
-        Ok(Self {
-            data,
-            ram: vec![0; 0xFC00000],
+        let mut rom = Self {
+            data: ROM::to_big_endian(ROM::extract(data)?),
+            ram: vec![0; CART_RAM_SIZE],
+            ram_dirty: false,
+            path: Some(PathBuf::from(filename)),
+        };
+        // Soft patch with the same name as the ROM, like "Game.z64" and "Game.bps"
+        for extension in PATCH_EXTENSIONS {
+            let patch_path = Path::new(filename).with_extension(extension);
+            if patch_path.is_file() {
+                rom.apply_patch(&std::fs::read(patch_path)?)?;
+                break;
+            }
+        }
+        Ok(rom)
+    }
+
+    // Takes the ROM out of gzip and zip archives, other files are returned untouched
+    fn extract(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match data.as_slice() {
+            [0x1F, 0x8B, ..] => decompress_gzip(&data),
+            [b'P', b'K', 0x03, 0x04, ..] => {
+                let entries = zip_entries(&data)?;
+                let is_rom = |name: &str| ROM_EXTENSIONS.iter().any(|extension| name.to_lowercase().ends_with(&format!(".{}", extension)));
+                match entries.iter().find(|entry| is_rom(&entry.name)) {
+                    Some(entry) => entry.decompress(),
+                    None => Err(Error::new(ErrorKind::InvalidData, "No ROM found in the zip file")),
+                }
+            },
+            _ => Ok(data),
+        }
+    }
+
+    /*
+        Dumps come in three byte orders, told apart by the first word of the header:
+        .z64 is big endian, .v64 has its 16 bit halves swapped and .n64 is little endian.
+    */
+    pub fn to_big_endian(mut data: Vec<u8>) -> Vec<u8> {
+        match data.get(0..4) {
+            Some([0x37, 0x80, 0x40, 0x12]) => data.chunks_exact_mut(2).for_each(|half| half.swap(0, 1)),
+            Some([0x40, 0x12, 0x37, 0x80]) => data.chunks_exact_mut(4).for_each(|word| word.reverse()),
+            _ => {},
+        };
+        data
+    }
+
+    // Applies an IPS, BPS or xdelta patch and fixes the header CRC so IPL3 accepts the result
+    pub fn apply_patch(&mut self, patch: &[u8]) -> std::io::Result<()> {
+        self.data = patch::apply(&self.data, patch)?;
+        patch::fix_crc(&mut self.data);
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn save_type(&self) -> SaveType {
+        detect_save_type(self.header())
+    }
+
+    // Next to the ROM, named after the game like other emulators do. See storage::game_file for the data directory
+    pub fn save_path_with_extension(&self, extension: &str) -> Option<PathBuf> {
+        let file_name = save_file_name(self.header(), extension);
+        self.path.as_ref().map(|path| path.with_file_name(file_name))
+    }
+
+    // Path of the .eep, .sra or .fla file, None when the game doesn't save
+    pub fn save_path(&self) -> Option<PathBuf> {
+        match self.save_type() {
+            SaveType::None => None,
+            save_type => storage::game_file(self, DataKind::Saves, save_type.extension()),
+        }
+    }
+
+    pub fn load_save(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    // Only the part of the cartridge RAM used by the save type, so the file matches the standard sizes
+    pub fn save_data(&self) -> &[u8] {
+        let len = self.save_type().size().min(self.ram.len());
+        &self.ram[..len]
+    }
+
+    pub fn write_ram(&mut self, offset: usize, data: &[u8]) {
+        if let Some(bytes) = self.ram.get_mut(offset..offset + data.len()) {
+            self.ram_dirty |= bytes != data;
+            bytes.copy_from_slice(data);
+        }
+    }
+
+    // EEPROM saves are kept at the start of the cartridge RAM too, see PIF::eeprom
+    pub fn read_eeprom(&self, block: usize) -> Vec<u8> {
+        let start = block * EEPROM_BLOCK_SIZE;
+        self.ram.get(start..start + EEPROM_BLOCK_SIZE).map_or_else(|| vec![0; EEPROM_BLOCK_SIZE], <[u8]>::to_vec)
+    }
+
+    pub fn write_eeprom(&mut self, block: usize, data: &[u8]) {
+        self.write_ram(block * EEPROM_BLOCK_SIZE, &data[..EEPROM_BLOCK_SIZE]);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_block(&self.ram);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.ram = reader.read_block()?.to_vec();
+        self.ram_dirty = true;
+        Ok(())
+    }
+
+    // Returns whether the cartridge RAM changed since the last call
+    pub fn take_ram_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.ram_dirty, false)
+    }
+
+    // The cartridge contents as seen from 0x10000000
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.data[..self.data.len().min(0x40)]
+    }
+
+    // The two header checksums, which is how cheat lists and other databases identify a game
+    pub fn game_id(&self) -> Option<String> {
+        let checksums = self.data.get(0x10..0x18)?;
+        Some(format!(
+            "{:08X}-{:08X}",
+            u32::from_be_bytes(checksums[..4].try_into().unwrap()),
+            u32::from_be_bytes(checksums[4..].try_into().unwrap()),
+        ))
+    }
+
+    // None until a ROM with a full header is loaded. Calculating the CRC goes over a megabyte, so this isn't meant for every frame
+    pub fn info(&self) -> Option<RomInfo> {
+        let header = self.data.get(..0x40)?;
+        let text = |bytes: &[u8]| bytes.iter()
+            .map(|byte| match byte {
+                0x20..=0x7E => *byte as char,
+                _ => ' ',
+            })
+            .collect::<String>()
+            .trim()
+            .to_string();
+        let cic = CIC::detect(&self.data);
+        Some(RomInfo {
+            name: text(&header[0x20..0x34]),
+            game_code: text(&header[0x3B..0x3F]),
+            version: header[0x3F],
+            region: self.region(),
+            size: self.data.len(),
+            crc: (u32::from_be_bytes(header[0x10..0x14].try_into().unwrap()), u32::from_be_bytes(header[0x14..0x18].try_into().unwrap())),
+            calculated_crc: cic.and_then(|cic| patch::calculate_crc(&self.data, cic)),
+            cic,
+            save_type: self.save_type(),
+            expansion_pak: EXPANSION_PAK_DATABASE.iter()
+                .find(|(id, _)| id.as_bytes() == &header[0x3C..0x3E])
+                .map(|(_, expansion_pak)| *expansion_pak)
+                .unwrap_or(ExpansionPak::Unused),
+            ra_hash: achievements::rom_hash(&self.data),
         })
     }
 
+    pub fn region(&self) -> Region {
+        match self.data.get(HEADER_COUNTRY_CODE) {
+            Some(code) => Region::from_country_code(*code),
+            None => Region::NTSC,
+        }
+    }
+
     pub fn read(&self, address: i64) -> u8 {
         if CARTRIDGE_DOMAIN_2_ADDRESS_2.contains(&address) {
             return match self.ram.get((address - CARTRIDGE_DOMAIN_2_ADDRESS_2.min().unwrap()) as usize) {
@@ -44,7 +318,162 @@ impl ROM {
 
     pub fn write(&mut self, address: i64, data: u8) {
         if let Some(elem) = self.ram.get_mut((address - CARTRIDGE_DOMAIN_2_ADDRESS_2.min().unwrap()) as usize) {
-            *elem = data;
+            if *elem != data {
+                *elem = data;
+                self.ram_dirty = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod rom_tests {
+    use super::*;
+    use crate::flashram::*;
+
+    #[test]
+    fn test_region() {
+        assert_eq!(Region::from_country_code(b'E'), Region::NTSC);
+        assert_eq!(Region::from_country_code(b'J'), Region::NTSC);
+        assert_eq!(Region::from_country_code(b'P'), Region::PAL);
+        assert_eq!(Region::from_country_code(b'D'), Region::PAL);
+        assert_eq!(Region::from_country_code(b'B'), Region::MPAL);
+
+        let mut rom = ROM::new();
+        assert_eq!(rom.region(), Region::NTSC);
+        rom.data = vec![0; 0x40];
+        rom.data[HEADER_COUNTRY_CODE] = b'P';
+        assert_eq!(rom.region(), Region::PAL);
+    }
+
+    #[test]
+    fn test_to_big_endian() {
+        let z64 = vec![0x80, 0x37, 0x12, 0x40, 0x00, 0x00, 0x00, 0x0F];
+        assert_eq!(ROM::to_big_endian(z64.clone()), z64);
+        assert_eq!(ROM::to_big_endian(vec![0x37, 0x80, 0x40, 0x12, 0x00, 0x00, 0x0F, 0x00]), z64);
+        assert_eq!(ROM::to_big_endian(vec![0x40, 0x12, 0x37, 0x80, 0x0F, 0x00, 0x00, 0x00]), z64);
+    }
+
+    #[test]
+    fn test_save_path() {
+        let mut rom = ROM::new();
+        rom.data = vec![0; 0x40];
+        rom.data[0x20..0x2E].copy_from_slice(b"SUPER MARIO 64");
+        rom.data[0x3B..0x3F].copy_from_slice(b"NSME");
+        rom.ram = vec![0; CART_RAM_SIZE];
+        rom.path = Some(PathBuf::from("roms").join("mario.z64"));
+        assert_eq!(rom.save_path(), Some(PathBuf::from("roms").join("SUPER MARIO 64.eep")));
+        assert_eq!(rom.save_path_with_extension("eep"), Some(PathBuf::from("roms").join("SUPER MARIO 64.eep")));
+
+        rom.data[0x20..0x34].copy_from_slice(b"THE LEGEND OF ZELDA ");
+        rom.data[0x3B..0x3F].copy_from_slice(b"CZLE");
+        assert_eq!(rom.save_path(), Some(PathBuf::from("roms").join("THE LEGEND OF ZELDA.sra")));
+        assert_eq!(rom.save_data().len(), 0x8000);
+    }
+
+    #[test]
+    fn test_info() {
+        let mut rom = ROM::new();
+        assert_eq!(rom.info(), None);
+        rom.data = vec![0; 0x40];
+        rom.data[0x10..0x18].copy_from_slice(&[0x63, 0x5A, 0x2B, 0xFF, 0x8B, 0x02, 0x23, 0x26]);
+        rom.data[0x20..0x34].copy_from_slice(b"SUPER MARIO 64      ");
+        rom.data[0x3B..0x3F].copy_from_slice(b"NSME");
+        let info = rom.info().unwrap();
+        assert_eq!(info.name, "SUPER MARIO 64");
+        assert_eq!(info.game_code, "NSME");
+        assert_eq!(info.region, Region::NTSC);
+        assert_eq!(info.crc, (0x635A2BFF, 0x8B022326));
+        assert_eq!(info.cic, None);
+        assert_eq!(info.calculated_crc, None);
+        assert_eq!(info.save_type, SaveType::Eeprom4K);
+        assert_eq!(info.expansion_pak, ExpansionPak::Unused);
+
+        rom.data[0x3B..0x3F].copy_from_slice(b"NZSP");
+        let info = rom.info().unwrap();
+        assert_eq!(info.region, Region::PAL);
+        assert_eq!(info.save_type, SaveType::FlashRam);
+        assert_eq!(info.expansion_pak, ExpansionPak::Required);
+    }
+
+    #[test]
+    fn test_eeprom() {
+        use crate::pif::{PIF, PIF_RAM_SIZE, EEPROM_BUSY, EEPROM_WRITE_CYCLES};
+        let mut rom = ROM::new();
+        rom.data = vec![0; 0x40];
+        rom.data[0x3B..0x3F].copy_from_slice(b"NSME");
+        rom.ram = vec![0; CART_RAM_SIZE];
+        let mut pif = PIF::new();
+        // Skips the four controllers to reach the cartridge channel
+        let run = |pif: &mut PIF, rom: &mut ROM, command: &[u8]| {
+            for (offset, byte) in [0, 0, 0, 0].iter().chain(command).chain([0xFE].iter()).enumerate() {
+                pif.write(offset, *byte);
+            }
+            pif.write(PIF_RAM_SIZE - 1, 1);
+            pif.run_commands(rom);
+            pif.ram()[4 + 2 + command[0] as usize..4 + 2 + command[0] as usize + command[1] as usize].to_vec()
+        };
+        let write = [0x0A, 0x01, 0x05, 0x02, 1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(run(&mut pif, &mut rom, &write), vec![0]);
+        assert_eq!(rom.read_eeprom(2), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(rom.take_ram_dirty());
+        // Still writing the first block, the second write is dropped
+        let second = [0x0A, 0x01, 0x05, 0x02, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(run(&mut pif, &mut rom, &second), vec![EEPROM_BUSY]);
+        assert_eq!(run(&mut pif, &mut rom, &[0x01, 0x03, 0x00]), vec![0x00, 0x80, EEPROM_BUSY]);
+        assert_eq!(run(&mut pif, &mut rom, &[0x02, 0x08, 0x04, 0x02]), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        pif.tick(EEPROM_WRITE_CYCLES);
+        assert_eq!(run(&mut pif, &mut rom, &[0x01, 0x03, 0x00]), vec![0x00, 0x80, 0x00]);
+        assert_eq!(run(&mut pif, &mut rom, &second), vec![0]);
+        assert_eq!(rom.read_eeprom(2), vec![0; 8]);
+    }
+
+    fn flash_command(flash: &mut FlashRam, rom: &mut ROM, command: u32) {
+        for (i, byte) in command.to_be_bytes().iter().enumerate() {
+            flash.write(FLASHRAM_COMMAND_OFFSET + i as i64, *byte, rom);
+        }
+    }
+
+    fn read_word(flash: &FlashRam, rom: &ROM) -> u32 {
+        u32::from_be_bytes([0, 1, 2, 3].map(|i| flash.read(i, rom)))
+    }
+
+    #[test]
+    fn test_flashram() {
+        let mut rom = ROM::new();
+        rom.ram = vec![0; CART_RAM_SIZE];
+        let mut flash = FlashRam::new();
+
+        // How libultra erases the second sector
+        flash_command(&mut flash, &mut rom, 0x4B000000 | 0x80);
+        flash_command(&mut flash, &mut rom, 0x78000000);
+        assert_eq!(read_word(&flash, &rom), FLASHRAM_ERASE_BUSY as u32);
+        assert_eq!(rom.read(0x08000000 + FLASHRAM_SECTOR_SIZE as i64), 0xFF);
+        assert_eq!(rom.read(0x08000000), 0);
+        // Dropped while busy
+        flash_command(&mut flash, &mut rom, 0xB4000000);
+        assert_eq!(flash.mode(), FlashRamMode::Status);
+        flash.tick(FLASHRAM_SECTOR_ERASE_CYCLES);
+        assert_eq!(read_word(&flash, &rom), FLASHRAM_ERASE_OK as u32);
+        flash_command(&mut flash, &mut rom, 0xD2000000);
+        flash.write(0, 0, &mut rom);
+        assert_eq!(flash.status(), 0);
+
+        // Fill the page buffer, then program page 3
+        flash_command(&mut flash, &mut rom, 0xB4000000);
+        for i in 0..FLASHRAM_PAGE_SIZE {
+            flash.write(i as i64, i as u8, &mut rom);
         }
+        flash_command(&mut flash, &mut rom, 0xA5000003);
+        assert_eq!(read_word(&flash, &rom), FLASHRAM_PROGRAM_BUSY as u32);
+        flash.tick(FLASHRAM_PROGRAM_CYCLES);
+        assert_eq!(flash.status(), FLASHRAM_PROGRAM_OK);
+        flash_command(&mut flash, &mut rom, 0xF0000000);
+        assert_eq!(flash.read(3 * FLASHRAM_PAGE_SIZE as i64 + 5, &rom), 5);
+        assert_eq!(flash.dma_address(0x08000000 + 3 * 64), 0x08000000 + 3 * FLASHRAM_PAGE_SIZE as i64);
+
+        flash_command(&mut flash, &mut rom, 0xE1000000);
+        assert_eq!(read_word(&flash, &rom), 0x11118001);
+        assert_eq!(u32::from_be_bytes([4, 5, 6, 7].map(|i| flash.read(i, &rom))), 0x00C2001E);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,499 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::microcode::Microcode;
+use crate::rdp::{RDP, DPC_START_ADDRESS};
+use crate::savestate::{StateReader, StateWriter};
+use crate::utils::box_array;
+
+pub const SP_MEMORY_SIZE: usize = 0x1000;
+
+// https://n64brew.dev/wiki/Reality_Signal_Processor/Interface#0x0404_0010_-_SP_STATUS
+pub const SP_STATUS_HALT: u32 = 1 << 0;
+pub const SP_STATUS_BROKE: u32 = 1 << 1;
+pub const SP_STATUS_SSTEP: u32 = 1 << 5;
+pub const SP_STATUS_INTR_BREAK: u32 = 1 << 6;
+
+// https://n64brew.dev/wiki/Reality_Signal_Processor/Interface#DMA
+pub const SP_DMA_SPADDR_ADDRESS: i64 = 0x04040000;
+pub const SP_DMA_RAMADDR_ADDRESS: i64 = 0x04040004;
+pub const SP_DMA_RDLEN_ADDRESS: i64 = 0x04040008;
+pub const SP_DMA_WRLEN_ADDRESS: i64 = 0x0404000C;
+pub const SP_STATUS_ADDRESS: i64 = 0x04040010;
+pub const SP_DMA_FULL_ADDRESS: i64 = 0x04040014;
+pub const SP_DMA_BUSY_ADDRESS: i64 = 0x04040018;
+pub const SP_SEMAPHORE_ADDRESS: i64 = 0x0404001C;
+pub const SP_PC_ADDRESS: i64 = 0x04080000;
+
+/*
+    Scalar unit of the RSP and its memories. Vector instructions are not executed yet, but the
+    vector registers are kept so they can be inspected. COP0 registers 0 to 7 are the SP registers
+    and 8 to 15 the DP ones, microcodes use them to start DMAs, signal the CPU and kick the RDP.
+    https://n64brew.dev/wiki/Reality_Signal_Processor
+*/
+pub struct RSP {
+    dmem: Box<[u8; SP_MEMORY_SIZE]>,
+    imem: Box<[u8; SP_MEMORY_SIZE]>,
+    registers: [u32; 32],
+    vector_registers: [[u16; 8]; 32],
+    program_counter: u32,
+    next_program_counter: u32,
+    status: u32,
+    // SP_DMA_SPADDR, SP_DMA_RAMADDR, SP_DMA_RDLEN and SP_DMA_WRLEN, the MMU performs the transfers
+    dma_registers: [u32; 4],
+    // The CPU writes the registers a byte at a time, SP_STATUS is applied once the whole word arrived
+    status_write: [u8; 4],
+    // Of the last task started, and every different one started since reset, see microcode.rs
+    microcode: Option<Microcode>,
+    microcodes: Vec<Microcode>,
+    // Reading SP_SEMAPHORE sets it, including the reads the CPU makes through a shared reference
+    semaphore: AtomicBool,
+    // SP and DP register writes made through COP0 and the values written, the MMU applies them
+    cop0_writes: Vec<(i64, u32)>,
+}
+
+impl RSP {
+    pub fn new() -> Self {
+        Self {
+            dmem: box_array![0; SP_MEMORY_SIZE],
+            imem: box_array![0; SP_MEMORY_SIZE],
+            registers: [0; 32],
+            vector_registers: [[0; 8]; 32],
+            program_counter: 0,
+            next_program_counter: 4,
+            status: SP_STATUS_HALT,
+            dma_registers: [0; 4],
+            status_write: [0; 4],
+            microcode: None,
+            microcodes: Vec::new(),
+            semaphore: AtomicBool::new(false),
+            cop0_writes: Vec::new(),
+        }
+    }
+
+    pub fn dmem(&self) -> &[u8] {
+        &self.dmem[..]
+    }
+
+    pub fn mut_dmem(&mut self) -> &mut [u8] {
+        &mut self.dmem[..]
+    }
+
+    pub fn imem(&self) -> &[u8] {
+        &self.imem[..]
+    }
+
+    pub fn mut_imem(&mut self) -> &mut [u8] {
+        &mut self.imem[..]
+    }
+
+    pub fn registers(&self) -> &[u32; 32] {
+        &self.registers
+    }
+
+    pub fn vector_registers(&self) -> &[[u16; 8]; 32] {
+        &self.vector_registers
+    }
+
+    pub fn microcode(&self) -> Option<&Microcode> {
+        self.microcode.as_ref()
+    }
+
+    pub fn microcodes(&self) -> &[Microcode] {
+        &self.microcodes
+    }
+
+    // Returns true the first time this microcode runs
+    pub fn set_microcode(&mut self, microcode: Microcode) -> bool {
+        let new = !self.microcodes.iter().any(|seen| seen.hash == microcode.hash);
+        if new {
+            self.microcodes.push(microcode.clone());
+        }
+        self.microcode = Some(microcode);
+        new
+    }
+
+    pub fn take_cop0_writes(&mut self) -> Vec<(i64, u32)> {
+        std::mem::take(&mut self.cop0_writes)
+    }
+
+    pub fn semaphore(&self) -> bool {
+        self.semaphore.load(Ordering::Relaxed)
+    }
+
+    pub fn get_program_counter(&self) -> u32 {
+        self.program_counter
+    }
+
+    pub fn set_program_counter(&mut self, address: u32) {
+        self.program_counter = address & 0xFFC;
+        self.next_program_counter = (self.program_counter + 4) & 0xFFC;
+    }
+
+    pub fn get_status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn get_dma_register(&self, address: i64) -> u32 {
+        self.dma_registers[((address - SP_DMA_SPADDR_ADDRESS) >> 2) as usize]
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.status & SP_STATUS_HALT != 0
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        match halted {
+            true => self.status |= SP_STATUS_HALT,
+            false => self.status &= !(SP_STATUS_HALT | SP_STATUS_BROKE),
+        };
+    }
+
+    // https://n64brew.dev/wiki/Reality_Signal_Processor/Interface#0x0404_0010_-_SP_STATUS
+    fn write_status(&mut self, data: u32) {
+        let pairs = [
+            (0, 1, SP_STATUS_HALT),
+            (5, 6, SP_STATUS_SSTEP),
+            (7, 8, SP_STATUS_INTR_BREAK),
+        ];
+        for (clear, set, bit) in pairs {
+            if data & (1 << clear) != 0 {
+                self.status &= !bit;
+            } else if data & (1 << set) != 0 {
+                self.status |= bit;
+            }
+        }
+        if data & (1 << 2) != 0 {
+            self.status &= !SP_STATUS_BROKE;
+        }
+        // Signals 0 to 7
+        for signal in 0..8 {
+            let bit = 1 << (7 + signal);
+            if data & (1 << (9 + signal * 2)) != 0 {
+                self.status &= !bit;
+            } else if data & (1 << (10 + signal * 2)) != 0 {
+                self.status |= bit;
+            }
+        }
+    }
+
+    pub fn read_register(&self, address: i64) -> u32 {
+        match address & !0b11 {
+            SP_DMA_SPADDR_ADDRESS..=SP_DMA_WRLEN_ADDRESS => self.get_dma_register(address & !0b11),
+            SP_STATUS_ADDRESS => self.status,
+            // The MMU finishes the transfers as soon as they start
+            SP_DMA_FULL_ADDRESS | SP_DMA_BUSY_ADDRESS => 0,
+            SP_SEMAPHORE_ADDRESS => self.semaphore.swap(true, Ordering::Relaxed) as u32,
+            SP_PC_ADDRESS => self.program_counter,
+            _ => 0,
+        }
+    }
+
+    pub fn get_register(&self, address: i64) -> u8 {
+        // The semaphore is taken by the read of the byte that holds it
+        if address & !0b11 == SP_SEMAPHORE_ADDRESS && address & 0b11 != 3 {
+            return 0;
+        }
+        self.read_register(address).to_be_bytes()[(address & 0b11) as usize]
+    }
+
+    pub fn set_register(&mut self, address: i64, data: u8) {
+        let byte = (address & 0b11) as usize;
+        match address & !0b11 {
+            SP_DMA_SPADDR_ADDRESS..=SP_DMA_WRLEN_ADDRESS => {
+                let index = ((address - SP_DMA_SPADDR_ADDRESS) >> 2) as usize;
+                let mut value = self.dma_registers[index].to_be_bytes();
+                value[byte] = data;
+                self.dma_registers[index] = u32::from_be_bytes(value);
+            },
+            SP_STATUS_ADDRESS => {
+                self.status_write[byte] = data;
+                if byte == 3 {
+                    self.write_status(u32::from_be_bytes(self.status_write));
+                }
+            },
+            // Any write releases it
+            SP_SEMAPHORE_ADDRESS if byte == 3 => self.semaphore.store(false, Ordering::Relaxed),
+            SP_PC_ADDRESS => {
+                let mut value = self.program_counter.to_be_bytes();
+                value[byte] = data;
+                self.set_program_counter(u32::from_be_bytes(value));
+            },
+            _ => {},
+        };
+    }
+
+    // Executes an instruction unless the RSP is halted, with single step mode it halts again right after
+    pub fn tick(&mut self, rdp: &RDP) {
+        if self.is_halted() {
+            return;
+        }
+        self.step(rdp);
+        if self.status & SP_STATUS_SSTEP != 0 {
+            self.status |= SP_STATUS_HALT;
+        }
+    }
+
+    // The RDP is only read, by MFC0 on the DP registers
+    pub fn step(&mut self, rdp: &RDP) {
+        let pc = self.program_counter as usize;
+        let opcode = u32::from_be_bytes(self.imem[pc..pc + 4].try_into().unwrap());
+        self.program_counter = self.next_program_counter;
+        self.next_program_counter = (self.next_program_counter + 4) & 0xFFC;
+        self.exec_opcode(opcode, pc as u32, rdp);
+    }
+
+    fn get(&self, index: u32) -> u32 {
+        self.registers[index as usize]
+    }
+
+    fn set(&mut self, index: u32, val: u32) {
+        if index != 0 {
+            self.registers[index as usize] = val;
+        }
+    }
+
+    fn branch(&mut self, pc: u32, offset: u32, condition: bool) {
+        if condition {
+            let offset = ((offset as u16 as i16 as i32) << 2) as u32;
+            self.next_program_counter = pc.wrapping_add(4).wrapping_add(offset) & 0xFFC;
+        }
+    }
+
+    fn read_dmem(&self, address: u32, bytes: usize) -> u32 {
+        (0..bytes).fold(0, |value, i| (value << 8) | self.dmem[(address as usize + i) & 0xFFF] as u32)
+    }
+
+    // https://n64brew.dev/wiki/Reality_Signal_Processor/CPU_Core#COP0_registers
+    fn cop0_address(index: u32) -> i64 {
+        match index & 0xF {
+            0..=7 => SP_DMA_SPADDR_ADDRESS + ((index & 0x7) << 2) as i64,
+            _ => DPC_START_ADDRESS + ((index & 0x7) << 2) as i64,
+        }
+    }
+
+    fn write_dmem(&mut self, address: u32, bytes: usize, value: u32) {
+        for i in 0..bytes {
+            self.dmem[(address as usize + i) & 0xFFF] = (value >> ((bytes - 1 - i) * 8)) as u8;
+        }
+    }
+
+    // https://n64brew.dev/wiki/Reality_Signal_Processor/CPU_Core#Scalar_instructions
+    fn exec_opcode(&mut self, opcode: u32, pc: u32, rdp: &RDP) {
+        let rs = (opcode >> 21) & 0x1F;
+        let rt = (opcode >> 16) & 0x1F;
+        let rd = (opcode >> 11) & 0x1F;
+        let sa = (opcode >> 6) & 0x1F;
+        let immediate = opcode & 0xFFFF;
+        let signed_immediate = immediate as u16 as i16 as i32 as u32;
+        let target = (opcode << 2) & 0xFFC;
+        match opcode >> 26 {
+            // SPECIAL
+            0x00 => match opcode & 0x3F {
+                0x00 => self.set(rd, self.get(rt) << sa), // SLL
+                0x02 => self.set(rd, self.get(rt) >> sa), // SRL
+                0x03 => self.set(rd, ((self.get(rt) as i32) >> sa) as u32), // SRA
+                0x04 => self.set(rd, self.get(rt) << (self.get(rs) & 0x1F)), // SLLV
+                0x06 => self.set(rd, self.get(rt) >> (self.get(rs) & 0x1F)), // SRLV
+                0x07 => self.set(rd, ((self.get(rt) as i32) >> (self.get(rs) & 0x1F)) as u32), // SRAV
+                0x08 => self.next_program_counter = self.get(rs) & 0xFFC, // JR
+                // JALR
+                0x09 => {
+                    let target = self.get(rs) & 0xFFC;
+                    self.set(rd, (pc + 8) & 0xFFC);
+                    self.next_program_counter = target;
+                },
+                // BREAK
+                0x0D => self.status |= SP_STATUS_HALT | SP_STATUS_BROKE,
+                0x20 | 0x21 => self.set(rd, self.get(rs).wrapping_add(self.get(rt))), // ADD, ADDU
+                0x22 | 0x23 => self.set(rd, self.get(rs).wrapping_sub(self.get(rt))), // SUB, SUBU
+                0x24 => self.set(rd, self.get(rs) & self.get(rt)), // AND
+                0x25 => self.set(rd, self.get(rs) | self.get(rt)), // OR
+                0x26 => self.set(rd, self.get(rs) ^ self.get(rt)), // XOR
+                0x27 => self.set(rd, !(self.get(rs) | self.get(rt))), // NOR
+                0x2A => self.set(rd, ((self.get(rs) as i32) < (self.get(rt) as i32)) as u32), // SLT
+                0x2B => self.set(rd, (self.get(rs) < self.get(rt)) as u32), // SLTU
+                _ => {},
+            },
+            // REGIMM
+            0x01 => {
+                let negative = (self.get(rs) as i32) < 0;
+                let condition = match rt & 1 {
+                    0 => negative, // BLTZ, BLTZAL
+                    _ => !negative, // BGEZ, BGEZAL
+                };
+                if rt & 0x10 != 0 {
+                    self.set(31, (pc + 8) & 0xFFC);
+                }
+                self.branch(pc, immediate, condition);
+            },
+            0x02 => self.next_program_counter = target, // J
+            // JAL
+            0x03 => {
+                self.set(31, (pc + 8) & 0xFFC);
+                self.next_program_counter = target;
+            },
+            0x04 => self.branch(pc, immediate, self.get(rs) == self.get(rt)), // BEQ
+            0x05 => self.branch(pc, immediate, self.get(rs) != self.get(rt)), // BNE
+            0x06 => self.branch(pc, immediate, self.get(rs) as i32 <= 0), // BLEZ
+            0x07 => self.branch(pc, immediate, self.get(rs) as i32 > 0), // BGTZ
+            0x08 | 0x09 => self.set(rt, self.get(rs).wrapping_add(signed_immediate)), // ADDI, ADDIU
+            0x0A => self.set(rt, ((self.get(rs) as i32) < (signed_immediate as i32)) as u32), // SLTI
+            0x0B => self.set(rt, (self.get(rs) < signed_immediate) as u32), // SLTIU
+            0x0C => self.set(rt, self.get(rs) & immediate), // ANDI
+            0x0D => self.set(rt, self.get(rs) | immediate), // ORI
+            0x0E => self.set(rt, self.get(rs) ^ immediate), // XORI
+            0x0F => self.set(rt, immediate << 16), // LUI
+            // COP0
+            0x10 => match rs {
+                // MFC0
+                0x00 => {
+                    let address = Self::cop0_address(rd);
+                    let value = match rd & 0xF {
+                        0..=7 => self.read_register(address),
+                        _ => rdp.read_register(address),
+                    };
+                    self.set(rt, value);
+                },
+                // MTC0
+                0x04 => self.cop0_writes.push((Self::cop0_address(rd), self.get(rt))),
+                _ => {},
+            },
+            0x20 => self.set(rt, self.read_dmem(self.get(rs).wrapping_add(signed_immediate), 1) as u8 as i8 as i32 as u32), // LB
+            0x21 => self.set(rt, self.read_dmem(self.get(rs).wrapping_add(signed_immediate), 2) as u16 as i16 as i32 as u32), // LH
+            0x23 => self.set(rt, self.read_dmem(self.get(rs).wrapping_add(signed_immediate), 4)), // LW
+            0x24 => self.set(rt, self.read_dmem(self.get(rs).wrapping_add(signed_immediate), 1)), // LBU
+            0x25 => self.set(rt, self.read_dmem(self.get(rs).wrapping_add(signed_immediate), 2)), // LHU
+            0x28 => self.write_dmem(self.get(rs).wrapping_add(signed_immediate), 1, self.get(rt)), // SB
+            0x29 => self.write_dmem(self.get(rs).wrapping_add(signed_immediate), 2, self.get(rt)), // SH
+            0x2B => self.write_dmem(self.get(rs).wrapping_add(signed_immediate), 4, self.get(rt)), // SW
+            _ => {},
+        };
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_block(&self.dmem[..]);
+        writer.write_block(&self.imem[..]);
+        for register in self.registers {
+            writer.write_u32(register);
+        }
+        for register in self.vector_registers {
+            for element in register {
+                writer.write_u16(element);
+            }
+        }
+        writer.write_u32(self.program_counter);
+        writer.write_u32(self.next_program_counter);
+        writer.write_u32(self.status);
+        for register in self.dma_registers {
+            writer.write_u32(register);
+        }
+        writer.write_bool(self.semaphore.load(Ordering::Relaxed));
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for memory in [&mut self.dmem, &mut self.imem] {
+            let data = reader.read_block()?;
+            if data.len() != SP_MEMORY_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid RSP memory size"));
+            }
+            memory.copy_from_slice(data);
+        }
+        for register in self.registers.iter_mut() {
+            *register = reader.read_u32()?;
+        }
+        for register in self.vector_registers.iter_mut() {
+            for element in register.iter_mut() {
+                *element = reader.read_u16()?;
+            }
+        }
+        self.program_counter = reader.read_u32()?;
+        self.next_program_counter = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        for register in self.dma_registers.iter_mut() {
+            *register = reader.read_u32()?;
+        }
+        self.semaphore.store(reader.read_bool()?, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rsp_tests {
+    use super::*;
+    use crate::rdp::{DPC_STATUS_ADDRESS, DPC_STATUS_XBUS};
+
+    fn load_program(rsp: &mut RSP, program: &[u32]) {
+        for (index, opcode) in program.iter().enumerate() {
+            rsp.mut_imem()[index * 4..index * 4 + 4].copy_from_slice(&opcode.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let mut rsp = RSP::new();
+        let rdp = RDP::new();
+        load_program(&mut rsp, &[
+            0x24010010, // ADDIU at, zero, 0x10
+            0x3402BEEF, // ORI v0, zero, 0xBEEF
+            0xAC220000, // SW v0, 0(at)
+            0x1000FFFC, // B -4
+            0x00000000, // NOP
+        ]);
+        rsp.tick(&rdp);
+        assert_eq!(rsp.registers()[1], 0);
+
+        rsp.set_halted(false);
+        for _ in 0..5 {
+            rsp.tick(&rdp);
+        }
+        assert_eq!(rsp.read_dmem(0x10, 4), 0xBEEF);
+        assert_eq!(rsp.get_program_counter(), 0);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut rsp = RSP::new();
+        let rdp = RDP::new();
+        load_program(&mut rsp, &[0x0000000D]); // BREAK
+        // Clear halt and set single step through SP_STATUS
+        for (i, byte) in (0b1000001_u32).to_be_bytes().iter().enumerate() {
+            rsp.set_register(SP_STATUS_ADDRESS + i as i64, *byte);
+        }
+        assert_eq!(rsp.get_status(), SP_STATUS_SSTEP);
+        rsp.tick(&rdp);
+        assert_eq!(rsp.get_status(), SP_STATUS_SSTEP | SP_STATUS_HALT | SP_STATUS_BROKE);
+        assert_eq!(rsp.get_register(SP_PC_ADDRESS + 3), 4);
+    }
+
+    #[test]
+    fn test_cop0() {
+        let mut rsp = RSP::new();
+        let mut rdp = RDP::new();
+        for (i, byte) in 0b10_u32.to_be_bytes().iter().enumerate() {
+            rdp.set_register(DPC_STATUS_ADDRESS + i as i64, *byte);
+        }
+        load_program(&mut rsp, &[
+            0x40013800, // MFC0 at, c7 (SP_SEMAPHORE)
+            0x40023800, // MFC0 v0, c7
+            0x40803800, // MTC0 zero, c7
+            0x40035800, // MFC0 v1, c11 (DPC_STATUS)
+            0x34010007, // ORI at, zero, 7
+            0x40811000, // MTC0 at, c2 (SP_DMA_RDLEN)
+        ]);
+        rsp.set_halted(false);
+        for _ in 0..6 {
+            rsp.tick(&rdp);
+        }
+        assert_eq!(rsp.registers()[2], 1);
+        assert_eq!(rsp.registers()[3], DPC_STATUS_XBUS);
+        assert!(rsp.semaphore());
+        assert_eq!(rsp.take_cop0_writes(), vec![(SP_SEMAPHORE_ADDRESS, 0), (SP_DMA_RDLEN_ADDRESS, 7)]);
+
+        // Writes release the semaphore, the CPU takes it by reading the byte that holds it
+        rsp.set_register(SP_SEMAPHORE_ADDRESS + 3, 0);
+        assert_eq!(rsp.get_register(SP_SEMAPHORE_ADDRESS), 0);
+        assert_eq!(rsp.get_register(SP_SEMAPHORE_ADDRESS + 3), 0);
+        assert_eq!(rsp.get_register(SP_SEMAPHORE_ADDRESS + 3), 1);
+    }
+}
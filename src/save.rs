@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::log::{log, Level, Subsystem};
+
+// How long the save data has to stay unchanged before it gets written to disk
+pub const FLUSH_DEBOUNCE: Duration = Duration::from_millis(1000);
+
+// mupen64plus keeps the four Controller Paks in the same file
+pub const MEMPAK_EXTENSION: &str = "mpk";
+pub const MEMPAK_SIZE: usize = 0x8000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SaveType {
+    None,
+    Eeprom4K,
+    Eeprom16K,
+    Sram,
+    FlashRam,
+}
+
+impl SaveType {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Eeprom4K => 0x200,
+            Self::Eeprom16K => 0x800,
+            Self::Sram => 0x8000,
+            Self::FlashRam => 0x20000,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Eeprom4K => "EEPROM 4Kbit",
+            Self::Eeprom16K => "EEPROM 16Kbit",
+            Self::Sram => "SRAM",
+            Self::FlashRam => "FlashRAM",
+        }
+    }
+
+    // Extensions used by mupen64plus and Project64
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Eeprom4K | Self::Eeprom16K => "eep",
+            Self::Sram => "sra",
+            Self::FlashRam => "fla",
+        }
+    }
+
+    // SRAM and FlashRAM live in the cartridge domain 2, EEPROM is accessed through the PIF
+    pub fn is_cartridge_ram(&self) -> bool {
+        matches!(self, Self::Sram | Self::FlashRam)
+    }
+}
+
+// Save types of well known games, indexed by the two characters in the middle of the game code
+const SAVE_TYPE_DATABASE: [(&str, SaveType); 29] = [
+    ("SM", SaveType::Eeprom4K), // Super Mario 64
+    ("MK", SaveType::Eeprom4K), // Mario Kart 64
+    ("WR", SaveType::Eeprom4K), // Wave Race 64
+    ("PW", SaveType::Eeprom4K), // Pilotwings 64
+    ("GE", SaveType::Eeprom4K), // GoldenEye 007
+    ("FX", SaveType::Eeprom4K), // Star Fox 64
+    ("BC", SaveType::Eeprom4K), // Blast Corps
+    ("BK", SaveType::Eeprom4K), // Banjo-Kazooie
+    ("K4", SaveType::Eeprom4K), // Kirby 64
+    ("YS", SaveType::Eeprom16K), // Yoshi's Story
+    ("B7", SaveType::Eeprom16K), // Banjo-Tooie
+    ("MX", SaveType::Eeprom16K), // Excitebike 64
+    ("FU", SaveType::Eeprom16K), // Conker's Bad Fur Day
+    ("DY", SaveType::Eeprom16K), // Diddy Kong Racing
+    ("DO", SaveType::Eeprom16K), // Donkey Kong 64
+    ("JF", SaveType::Eeprom16K), // Jet Force Gemini
+    ("MV", SaveType::Eeprom16K), // Mario Party 3
+    ("M8", SaveType::Eeprom16K), // Mario Tennis
+    ("ZL", SaveType::Sram), // The Legend of Zelda: Ocarina of Time
+    ("AL", SaveType::Sram), // Super Smash Bros.
+    ("FZ", SaveType::Sram), // F-Zero X
+    ("MF", SaveType::Sram), // Mario Golf
+    ("TE", SaveType::Sram), // 1080 Snowboarding
+    ("ZS", SaveType::FlashRam), // The Legend of Zelda: Majora's Mask
+    ("MQ", SaveType::FlashRam), // Paper Mario
+    ("PO", SaveType::FlashRam), // Pokemon Stadium
+    ("P2", SaveType::FlashRam), // Pokemon Stadium 2
+    ("CP", SaveType::FlashRam), // Pokemon Puzzle League
+    ("DA", SaveType::FlashRam), // Mario no Photopie
+];
+
+/*
+    Homebrew can declare its save type in the header by using "ED" as the game code.
+    https://n64brew.dev/wiki/ROM_Header#Advanced_Homebrew_ROM_Header
+*/
+fn homebrew_save_type(flags: u8) -> SaveType {
+    match flags >> 4 {
+        1 => SaveType::Eeprom4K,
+        2 => SaveType::Eeprom16K,
+        3 | 4 | 6 => SaveType::Sram,
+        5 => SaveType::FlashRam,
+        _ => SaveType::None,
+    }
+}
+
+// Unknown games get SRAM since it is the most common save type using the cartridge RAM
+pub fn detect_save_type(header: &[u8]) -> SaveType {
+    if header.len() < 0x40 {
+        return SaveType::None;
+    }
+    let game_id = &header[0x3C..0x3E];
+    if game_id == b"ED" {
+        return homebrew_save_type(header[0x3F]);
+    }
+    SAVE_TYPE_DATABASE.iter()
+        .find(|(id, _)| id.as_bytes() == game_id)
+        .map(|(_, save_type)| *save_type)
+        .unwrap_or(SaveType::Sram)
+}
+
+/*
+    Saves are named after the internal name in the ROM header like Project64 does,
+    so they can be moved between emulators without renaming them.
+*/
+pub fn save_file_name(header: &[u8], extension: &str) -> String {
+    let internal_name: String = header.get(0x20..0x34).unwrap_or(&[])
+        .iter()
+        .map(|byte| match byte {
+            b'/' | b'\\' | b':' | b'*' | b'?' | b'"' | b'<' | b'>' | b'|' => '_',
+            0x20..=0x7E => *byte as char,
+            _ => ' ',
+        })
+        .collect();
+    let internal_name = internal_name.trim();
+    let name = match internal_name.is_empty() {
+        true => String::from_utf8_lossy(header.get(0x3B..0x3F).unwrap_or(b"save")).to_string(),
+        false => internal_name.to_string(),
+    };
+    format!("{}.{}", name, extension)
+}
+
+enum FlushCommand {
+    Write(PathBuf, Vec<u8>),
+    Flush(Sender<()>),
+    Shutdown,
+}
+
+/*
+    Writes battery saves (EEPROM, SRAM, FlashRAM, Controller Pak) from a background thread.
+    Games usually write their saves in many small chunks, so the file is only written once
+    the data stops changing for FLUSH_DEBOUNCE, keeping file I/O away from the emulation thread.
+*/
+pub struct SaveFlusher {
+    sender: Sender<FlushCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SaveFlusher {
+    pub fn new() -> Self {
+        Self::new_with_debounce(FLUSH_DEBOUNCE)
+    }
+
+    pub fn new_with_debounce(debounce: Duration) -> Self {
+        let (sender, receiver) = channel();
+        let thread = std::thread::spawn(move || {
+            let mut pending: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+            loop {
+                let command = match pending.is_empty() {
+                    true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    false => receiver.recv_timeout(debounce),
+                };
+                match command {
+                    Ok(FlushCommand::Write(path, data)) => {
+                        pending.insert(path, data);
+                    },
+                    Ok(FlushCommand::Flush(done)) => {
+                        write_pending(&mut pending);
+                        let _ = done.send(());
+                    },
+                    Err(RecvTimeoutError::Timeout) => write_pending(&mut pending),
+                    Ok(FlushCommand::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                        write_pending(&mut pending);
+                        break;
+                    },
+                };
+            }
+        });
+        Self {
+            sender,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn schedule(&self, path: PathBuf, data: Vec<u8>) {
+        let _ = self.sender.send(FlushCommand::Write(path, data));
+    }
+
+    // Blocks until every scheduled save has been written
+    pub fn flush(&self) {
+        let (done_sender, done_receiver) = channel();
+        if self.sender.send(FlushCommand::Flush(done_sender)).is_ok() {
+            let _ = done_receiver.recv();
+        }
+    }
+}
+
+impl Drop for SaveFlusher {
+    fn drop(&mut self) {
+        let _ = self.sender.send(FlushCommand::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_pending(pending: &mut HashMap<PathBuf, Vec<u8>>) {
+    for (path, data) in pending.drain() {
+        if let Err(err) = fs::write(&path, &data) {
+            log!(Level::Error, Subsystem::Save, "Could not write save file {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod save_tests {
+    use super::*;
+
+    fn header(name: &str, game_code: &[u8; 4]) -> Vec<u8> {
+        let mut header = vec![0; 0x40];
+        header[0x20..0x20 + name.len()].copy_from_slice(name.as_bytes());
+        header[0x3B..0x3F].copy_from_slice(game_code);
+        header
+    }
+
+    #[test]
+    fn test_detect_save_type() {
+        assert_eq!(detect_save_type(&header("SUPER MARIO 64", b"NSME")), SaveType::Eeprom4K);
+        assert_eq!(detect_save_type(&header("THE LEGEND OF ZELDA", b"CZLE")), SaveType::Sram);
+        assert_eq!(detect_save_type(&header("ZELDA MAJORA'S MASK", b"NZSE")), SaveType::FlashRam);
+        assert_eq!(detect_save_type(&header("DONKEY KONG 64", b"NDOE")), SaveType::Eeprom16K);
+        assert_eq!(detect_save_type(&header("MarioParty3", b"NMVE")), SaveType::Eeprom16K);
+        assert_eq!(detect_save_type(&header("JET FORCE GEMINI", b"NJFE")), SaveType::Eeprom16K);
+        assert_eq!(detect_save_type(&header("UNKNOWN", b"NXXE")), SaveType::Sram);
+        let mut homebrew = header("HOMEBREW", b"NEDA");
+        homebrew[0x3F] = 0x20;
+        assert_eq!(detect_save_type(&homebrew), SaveType::Eeprom16K);
+        assert_eq!(detect_save_type(&[]), SaveType::None);
+    }
+
+    #[test]
+    fn test_save_file_name() {
+        assert_eq!(save_file_name(&header("SUPER MARIO 64      ", b"NSME"), "eep"), "SUPER MARIO 64.eep");
+        assert_eq!(save_file_name(&header("A/B", b"NSME"), "sra"), "A_B.sra");
+        assert_eq!(save_file_name(&header("", b"NSME"), "fla"), "NSME.fla");
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rultra64_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_debounce() {
+        let path = temp_path("debounce.sav");
+        let _ = fs::remove_file(&path);
+        let flusher = SaveFlusher::new_with_debounce(Duration::from_secs(60));
+        flusher.schedule(path.clone(), vec![1, 2, 3]);
+        flusher.schedule(path.clone(), vec![4, 5, 6]);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!path.exists());
+        flusher.flush();
+        assert_eq!(fs::read(&path).unwrap(), vec![4, 5, 6]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let path = temp_path("drop.sav");
+        let _ = fs::remove_file(&path);
+        let flusher = SaveFlusher::new_with_debounce(Duration::from_secs(60));
+        flusher.schedule(path.clone(), vec![7, 8, 9]);
+        drop(flusher);
+        assert_eq!(fs::read(&path).unwrap(), vec![7, 8, 9]);
+        let _ = fs::remove_file(&path);
+    }
+}
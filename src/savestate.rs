@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Read, Result};
+
+pub const SAVESTATE_MAGIC: &[u8; 4] = b"R64S";
+pub const SAVESTATE_VERSION: u32 = 20;
+
+/*
+    Compressed savestates are this magic followed by a zstd frame of the raw state. RDRAM is most
+    of a state and mostly zeroes or repeated data, so they shrink by about an order of magnitude.
+    Raw states, starting with SAVESTATE_MAGIC, still load, they are easier to look at in a hex editor.
+*/
+pub const COMPRESSED_SAVESTATE_MAGIC: &[u8; 4] = b"R64Z";
+// Fast enough to compress a state on every frame for rewind
+const COMPRESSION_LEVEL: i32 = 1;
+
+pub fn compress_state(state: &[u8]) -> Vec<u8> {
+    let mut data = COMPRESSED_SAVESTATE_MAGIC.to_vec();
+    // Writing to a Vec can't fail
+    let compressed = zstd::stream::encode_all(state, COMPRESSION_LEVEL).unwrap();
+    data.extend_from_slice(&compressed);
+    data
+}
+
+// The raw state, whether the data was compressed or not
+pub fn decompress_state(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match data.strip_prefix(COMPRESSED_SAVESTATE_MAGIC.as_slice()) {
+        Some(compressed) => {
+            let mut state = Vec::new();
+            zstd::stream::read::Decoder::new(compressed)?.read_to_end(&mut state)?;
+            Ok(Cow::Owned(state))
+        },
+        None => Ok(Cow::Borrowed(data)),
+    }
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::with_header(SAVESTATE_MAGIC, SAVESTATE_VERSION)
+    }
+
+    // For other files in the same format, like RDP captures
+    pub fn with_header(magic: &[u8; 4], version: u32) -> Self {
+        let mut writer = Self {
+            data: Vec::new(),
+        };
+        writer.write_bytes(magic);
+        writer.write_u32(version);
+        writer
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_be_bytes());
+    }
+
+    pub fn write_i64(&mut self, val: i64) {
+        self.write_u64(val as u64);
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // Variable sized blocks are prefixed with their length
+    pub fn write_block(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.write_bytes(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/*
+    For memory in serde structs, #[serde(with = "crate::savestate::bytes")] writes a Vec<u8> as a
+    byte string instead of a sequence of numbers, which binary formats store in one piece.
+*/
+pub mod bytes {
+    use std::fmt;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(data)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a byte array")
+        }
+
+        fn visit_bytes<E: de::Error>(self, data: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(data.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, data: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(data)
+        }
+
+        // Formats without a bytes type, like JSON, give them back as a sequence
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                data.push(byte);
+            }
+            Ok(data)
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        StateReader::with_header(data, SAVESTATE_MAGIC, SAVESTATE_VERSION, "savestate")
+    }
+
+    // The kind of file goes in the error messages
+    pub fn with_header(data: &'a [u8], magic: &[u8; 4], version: u32, kind: &str) -> Result<Self> {
+        let mut reader = Self {
+            data,
+            position: 0,
+        };
+        if reader.read_bytes(4)? != magic {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Not a rultra64 {}", kind)));
+        }
+        let found = reader.read_u32()?;
+        if found != version {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported {} version {}", kind, found)));
+        }
+        Ok(reader)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.data.get(self.position..self.position + len) {
+            Some(bytes) => {
+                self.position += len;
+                Ok(bytes)
+            },
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "Savestate is truncated")),
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        Ok(self.read_u64()? as i64)
+    }
+
+    pub fn read_block(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+}
+
+#[cfg(test)]
+mod savestate_tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_i64(-1);
+        writer.write_block(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert_eq!(reader.read_bool().unwrap(), true);
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(reader.read_i64().unwrap(), -1);
+        assert_eq!(reader.read_block().unwrap(), &[1, 2, 3]);
+        assert!(reader.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_invalid_magic() {
+        assert!(StateReader::new(b"NOPE\0\0\0\x01").is_err());
+        assert!(StateReader::new(b"R64").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::archive::{decompress_gzip, zip_entries};
+use crate::emulator::Emulator;
+use crate::mmu::MMU;
+use crate::registers::CP0Registers;
+
+pub const MUPEN64PLUS_MAGIC: &[u8; 8] = b"M64+SAVE";
+pub const PROJECT64_MAGIC: u32 = 0x23D8A6C8;
+
+const RDRAM_SIZE: usize = 0x400000;
+
+// Registers and memory shared by the foreign formats, already converted to big endian
+struct ForeignState {
+    program_counter: u32,
+    gpr: [i64; 32],
+    cp0: [u32; 32],
+    hi: i64,
+    lo: i64,
+    vi: [u32; 14],
+    rdram: Vec<u8>,
+}
+
+struct LittleEndianReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> LittleEndianReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.data.get(self.position..self.position + len) {
+            Some(bytes) => {
+                self.position += len;
+                Ok(bytes)
+            },
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "Savestate is truncated")),
+        }
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u32_array<const N: usize>(&mut self) -> Result<[u32; N]> {
+        let mut values = [0; N];
+        for value in values.iter_mut() {
+            *value = self.u32()?;
+        }
+        Ok(values)
+    }
+
+    fn i64_array<const N: usize>(&mut self) -> Result<[i64; N]> {
+        let mut values = [0; N];
+        for value in values.iter_mut() {
+            *value = self.i64()?;
+        }
+        Ok(values)
+    }
+
+    // Both emulators keep the RDRAM as little endian 32 bit words
+    fn word_swapped(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut data = self.bytes(len)?.to_vec();
+        for word in data.chunks_exact_mut(4) {
+            word.reverse();
+        }
+        Ok(data)
+    }
+}
+
+// Project64 stores its savestates as a zip file containing a single entry
+fn decompress_zip(data: &[u8]) -> Result<Vec<u8>> {
+    match zip_entries(data)?.first() {
+        Some(entry) => entry.decompress(),
+        None => Err(Error::new(ErrorKind::InvalidData, "Empty zip file")),
+    }
+}
+
+/*
+    Layout written by savestates_save_m64p in mupen64plus-core.
+    Only the state rultra64 emulates is kept, the rest of the devices are skipped.
+*/
+fn parse_mupen64plus(data: &[u8]) -> Result<ForeignState> {
+    let mut reader = LittleEndianReader::new(data);
+    reader.skip(8)?; // magic
+    let version = u32::from_be_bytes(reader.bytes(4)?.try_into().unwrap());
+    if version >> 16 != 1 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported mupen64plus savestate version {:08X}", version)));
+    }
+    reader.skip(32)?; // ROM MD5
+    reader.skip(10 * 4)?; // RDRAM registers
+    reader.skip(32)?; // MI registers
+    reader.skip(13 * 4)?; // PI registers
+    reader.skip(64)?; // SP registers
+    reader.skip(4 * 4)?; // SI registers
+    let vi = reader.u32_array::<14>()?;
+    reader.skip(4)?; // VI delay
+    reader.skip(8 * 4)?; // RI registers
+    reader.skip(10 * 4)?; // AI registers and FIFO
+    reader.skip(48)?; // DPC registers
+    reader.skip(4 * 4)?; // DPS registers
+    let rdram = reader.word_swapped(0x800000)?;
+    reader.skip(0x2000)?; // SP DMEM and IMEM
+    reader.skip(0x40)?; // PIF RAM
+    reader.skip(24)?; // FlashRAM
+    reader.skip(0x400000 * 2)?; // TLB lookup tables
+    reader.skip(4)?; // LL bit
+    let gpr = reader.i64_array::<32>()?;
+    let cp0 = reader.u32_array::<32>()?;
+    let lo = reader.i64()?;
+    let hi = reader.i64()?;
+    reader.skip(32 * 8)?; // FPR
+    reader.skip(2 * 4)?; // FCR0 and FCR31
+    reader.skip(32 * 47)?; // TLB entries
+    let program_counter = reader.u32()?;
+    Ok(ForeignState {
+        program_counter,
+        gpr,
+        cp0,
+        hi,
+        lo,
+        vi,
+        rdram,
+    })
+}
+
+// Layout written by Machine_SaveState in Project64
+fn parse_project64(data: &[u8], emulator: &Emulator) -> Result<ForeignState> {
+    let mut reader = LittleEndianReader::new(data);
+    reader.skip(4)?; // magic
+    let rdram_size = reader.u32()? as usize;
+    let rom_header = reader.bytes(0x40)?;
+    let loaded_header = emulator.mmu().rom().header();
+    if loaded_header.len() >= 0x18 && rom_header[0x10..0x18] != loaded_header[0x10..0x18] {
+        return Err(Error::new(ErrorKind::InvalidData, "The savestate belongs to a different ROM"));
+    }
+    reader.skip(4)?; // Next VI timer
+    let program_counter = reader.u32()?;
+    let gpr = reader.i64_array::<32>()?;
+    reader.skip(32 * 8)?; // FPR
+    let cp0 = reader.u32_array::<32>()?;
+    reader.skip(32 * 4)?; // FPCR
+    let hi = reader.i64()?;
+    let lo = reader.i64()?;
+    reader.skip(10 * 4)?; // RDRAM registers
+    reader.skip(10 * 4)?; // SP registers
+    reader.skip(10 * 4)?; // DPC registers
+    reader.skip(4 * 4)?; // MI registers
+    let vi = reader.u32_array::<14>()?;
+    reader.skip(6 * 4)?; // AI registers
+    reader.skip(13 * 4)?; // PI registers
+    reader.skip(8 * 4)?; // RI registers
+    reader.skip(4 * 4)?; // SI registers
+    reader.skip(32 * 20)?; // TLB entries
+    reader.skip(0x40)?; // PIF RAM
+    let rdram = reader.word_swapped(rdram_size)?;
+    Ok(ForeignState {
+        program_counter,
+        gpr,
+        cp0,
+        hi,
+        lo,
+        vi,
+        rdram,
+    })
+}
+
+fn apply(emulator: &mut Emulator, state: ForeignState) -> Result<()> {
+    let pc = state.program_counter as i64;
+    if !(0x80000000..=0xBFFFFFFF).contains(&pc) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Invalid program counter {:08X} in savestate", pc)));
+    }
+
+    let registers = emulator.mut_cpu().mut_registers();
+    for (index, value) in state.gpr.iter().enumerate() {
+        registers.set_by_number(index, *value);
+    }
+    registers.set_hi(state.hi);
+    registers.set_lo(state.lo);
+    registers.set_program_counter(pc);
+    registers.set_next_program_counter(pc.wrapping_add(4));
+
+    let cp0 = emulator.mut_cpu().mut_cp0();
+    for (index, value) in state.cp0.iter().enumerate() {
+        match CP0Registers::is_32bits(index) {
+            true => cp0.set_by_number_32(index, *value as i32),
+            false => cp0.set_by_number_64(index, (*value as i32) as i64),
+        };
+    }
+
+    let mmu: &mut MMU = emulator.mut_mmu();
+    let len = state.rdram.len().min(RDRAM_SIZE);
+    mmu.write_physical(0, &state.rdram[..len]);
+    for (index, value) in state.vi.iter().enumerate() {
+        mmu.write_physical(0x04400000 + (index as i64) * 4, &value.to_be_bytes());
+    }
+    Ok(())
+}
+
+// Loads a mupen64plus (.st) or Project64 (.pj) savestate, compressed or not, into the emulator
+pub fn import(emulator: &mut Emulator, data: &[u8]) -> Result<()> {
+    let data = match data {
+        [0x1F, 0x8B, ..] => decompress_gzip(data)?,
+        [b'P', b'K', 0x03, 0x04, ..] => decompress_zip(data)?,
+        _ => data.to_vec(),
+    };
+    let state = if data.starts_with(MUPEN64PLUS_MAGIC) {
+        parse_mupen64plus(&data)?
+    } else if data.len() >= 4 && u32::from_le_bytes(data[0..4].try_into().unwrap()) == PROJECT64_MAGIC {
+        parse_project64(&data, emulator)?
+    } else {
+        return Err(Error::new(ErrorKind::InvalidData, "Unknown savestate format"));
+    };
+    apply(emulator, state)
+}
+
+#[cfg(test)]
+mod savestate_import_tests {
+    use super::*;
+
+    fn project64_state() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&PROJECT64_MAGIC.to_le_bytes());
+        data.extend_from_slice(&(RDRAM_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 0x40]);
+        data.extend_from_slice(&0_u32.to_le_bytes());
+        data.extend_from_slice(&0x80001234_u32.to_le_bytes());
+        for index in 0..32_i64 {
+            data.extend_from_slice(&(index * 2).to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 32 * 8]);
+        for index in 0..32_u32 {
+            data.extend_from_slice(&index.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 32 * 4]);
+        data.extend_from_slice(&7_i64.to_le_bytes());
+        data.extend_from_slice(&8_i64.to_le_bytes());
+        data.extend_from_slice(&[0; (10 + 10 + 10 + 4) * 4]);
+        for index in 0..14_u32 {
+            data.extend_from_slice(&(0x100 + index).to_le_bytes());
+        }
+        data.extend_from_slice(&[0; (6 + 13 + 8 + 4) * 4 + 32 * 20 + 0x40]);
+        let mut rdram = vec![0; RDRAM_SIZE];
+        rdram[0x400..0x404].copy_from_slice(&[0xEF, 0xBE, 0xAD, 0xDE]);
+        data.extend_from_slice(&rdram);
+        data
+    }
+
+    #[test]
+    fn test_import_project64() {
+        let mut emulator = Emulator::new_hle();
+        import(&mut emulator, &project64_state()).unwrap();
+        let registers = emulator.cpu().registers();
+        assert_eq!(registers.get_program_counter(), 0x80001234);
+        assert_eq!(registers.get_by_number(5), 10);
+        assert_eq!(registers.get_hi(), 7);
+        assert_eq!(registers.get_lo(), 8);
+        assert_eq!(emulator.cpu().cp0().get_by_number_32(12), 12);
+        assert_eq!(emulator.mmu().read_physical(0x400, 4), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(emulator.mmu().read_physical(0x04400004, 4), vec![0x00, 0x00, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let mut emulator = Emulator::new_hle();
+        assert!(import(&mut emulator, b"garbage").is_err());
+        let state = project64_state();
+        assert!(import(&mut emulator, &state[..state.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::io::Result;
+
+use crate::rom::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+// The VR4300 runs at 93.75 MHz: https://n64brew.dev/wiki/VR4300
+pub const CPU_CLOCK_RATE: u64 = 93_750_000;
+
+// CP0 Count goes up every other cycle: https://n64brew.dev/wiki/COP0#Count
+pub const COUNT_DIVISOR: u64 = 2;
+
+pub const MIN_CLOCK_MULTIPLIER: u8 = 1;
+pub const MAX_CLOCK_MULTIPLIER: u8 = 3;
+
+/*
+    PAL consoles drive the VI with a faster clock and draw 625 lines at 50Hz instead of 525 at 60Hz.
+    https://n64brew.dev/wiki/Video_Interface
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TimingProfile {
+    pub refresh_rate: u64,
+    pub vi_lines: u64,
+    pub vi_clock_rate: u64,
+}
+
+impl TimingProfile {
+    pub fn from_region(region: Region) -> Self {
+        match region {
+            Region::NTSC => Self {
+                refresh_rate: 60,
+                vi_lines: 525,
+                vi_clock_rate: 48_681_812,
+            },
+            Region::PAL => Self {
+                refresh_rate: 50,
+                vi_lines: 625,
+                vi_clock_rate: 49_656_530,
+            },
+            Region::MPAL => Self {
+                refresh_rate: 60,
+                vi_lines: 525,
+                vi_clock_rate: 48_628_316,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    VerticalInterrupt,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::VerticalInterrupt => "VI",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Event {
+    pub kind: EventKind,
+    // Value of the cycle counter the event fires at
+    pub cycle: u64,
+}
+
+/*
+    The single cycle counter everything timed derives from: the VI interrupt, the line the VI is
+    drawing and CP0 Count. Count follows the console's clock, so overclocking doesn't make
+    osGetTime and the timers of the game run faster.
+*/
+pub struct Scheduler {
+    cycles: u64,
+    next_vi: u64,
+    // Cycles not turned into Count ticks yet
+    count_cycles: u64,
+    clock_multiplier: u8,
+    timing: TimingProfile,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let mut scheduler = Self {
+            cycles: 0,
+            next_vi: 0,
+            count_cycles: 0,
+            clock_multiplier: MIN_CLOCK_MULTIPLIER,
+            timing: TimingProfile::from_region(Region::NTSC),
+        };
+        scheduler.next_vi = scheduler.cycles_per_vi();
+        scheduler
+    }
+
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+        self.next_vi = self.cycles_per_vi();
+        self.count_cycles = 0;
+    }
+
+    /*
+        Overclocking lets the CPU run more cycles between two vertical interrupts, which
+        removes the slowdown of games that are limited by the speed of the real console's CPU.
+    */
+    pub fn cycles_per_vi(&self) -> u64 {
+        (CPU_CLOCK_RATE / self.timing.refresh_rate) * (self.clock_multiplier as u64)
+    }
+
+    pub fn cycles_per_line(&self) -> u64 {
+        self.cycles_per_vi() / self.timing.vi_lines
+    }
+
+    // Half-line the VI is on, what VI_V_CURRENT reads. Progressive modes only show even ones
+    pub fn vi_half_line(&self) -> u32 {
+        let cycles_per_vi = self.cycles_per_vi();
+        let frame_start = self.next_vi.saturating_sub(cycles_per_vi);
+        let elapsed = self.cycles.saturating_sub(frame_start).min(cycles_per_vi - 1);
+        (elapsed * self.timing.vi_lines / cycles_per_vi) as u32 & !1
+    }
+
+    // Count ticks for the cycles run since the last call
+    pub fn take_count_ticks(&mut self) -> u32 {
+        let divisor = COUNT_DIVISOR * self.clock_multiplier as u64;
+        let ticks = self.count_cycles / divisor;
+        self.count_cycles %= divisor;
+        ticks as u32
+    }
+
+    pub fn get_timing(&self) -> TimingProfile {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: TimingProfile) {
+        self.timing = timing;
+        self.next_vi = self.cycles + self.cycles_per_vi();
+    }
+
+    pub fn get_clock_multiplier(&self) -> u8 {
+        self.clock_multiplier
+    }
+
+    pub fn set_clock_multiplier(&mut self, multiplier: u8) {
+        self.clock_multiplier = multiplier.clamp(MIN_CLOCK_MULTIPLIER, MAX_CLOCK_MULTIPLIER);
+        self.next_vi = self.cycles + self.cycles_per_vi();
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Sorted by the cycle they fire at
+    pub fn pending_events(&self) -> Vec<Event> {
+        let mut events = vec![
+            Event { kind: EventKind::VerticalInterrupt, cycle: self.next_vi },
+        ];
+        events.sort_by_key(|event| event.cycle);
+        events
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.cycles);
+        writer.write_u64(self.next_vi);
+        writer.write_u64(self.count_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.cycles = reader.read_u64()?;
+        self.next_vi = reader.read_u64()?;
+        self.count_cycles = reader.read_u64()?;
+        Ok(())
+    }
+
+    // Returns true when a vertical interrupt is due
+    pub fn tick(&mut self, cycles: u64) -> bool {
+        self.cycles += cycles;
+        self.count_cycles += cycles;
+        if self.cycles >= self.next_vi {
+            self.next_vi += self.cycles_per_vi();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+
+    #[test]
+    fn test_tick() {
+        let mut scheduler = Scheduler::new();
+        let cycles_per_vi = scheduler.cycles_per_vi();
+        assert!(!scheduler.tick(cycles_per_vi - 1));
+        assert!(scheduler.tick(1));
+        assert!(!scheduler.tick(1));
+    }
+
+    #[test]
+    fn test_clock_multiplier() {
+        let mut scheduler = Scheduler::new();
+        let base_cycles_per_vi = scheduler.cycles_per_vi();
+        scheduler.set_clock_multiplier(2);
+        assert_eq!(scheduler.cycles_per_vi(), base_cycles_per_vi * 2);
+        assert!(!scheduler.tick(base_cycles_per_vi));
+        assert!(scheduler.tick(base_cycles_per_vi));
+
+        scheduler.set_clock_multiplier(10);
+        assert_eq!(scheduler.get_clock_multiplier(), MAX_CLOCK_MULTIPLIER);
+        scheduler.set_clock_multiplier(0);
+        assert_eq!(scheduler.get_clock_multiplier(), MIN_CLOCK_MULTIPLIER);
+    }
+
+    #[test]
+    fn test_pending_events() {
+        let mut scheduler = Scheduler::new();
+        scheduler.tick(100);
+        let events = scheduler.pending_events();
+        assert_eq!(events, vec![Event { kind: EventKind::VerticalInterrupt, cycle: scheduler.cycles_per_vi() }]);
+        assert_eq!(events[0].cycle - scheduler.get_cycles(), scheduler.cycles_per_vi() - 100);
+    }
+
+    #[test]
+    fn test_count_and_vi_line() {
+        let mut scheduler = Scheduler::new();
+        scheduler.tick(5);
+        assert_eq!(scheduler.take_count_ticks(), 2);
+        scheduler.tick(1);
+        assert_eq!(scheduler.take_count_ticks(), 1);
+        assert_eq!(scheduler.take_count_ticks(), 0);
+        // Overclocked, Count keeps the speed of the real console
+        scheduler.set_clock_multiplier(2);
+        scheduler.tick(8);
+        assert_eq!(scheduler.take_count_ticks(), 2);
+
+        scheduler.set_clock_multiplier(1);
+        assert_eq!(scheduler.vi_half_line(), 0);
+        scheduler.tick(scheduler.cycles_per_line() * 11);
+        assert_eq!(scheduler.vi_half_line(), 10);
+        scheduler.tick(scheduler.cycles_per_vi() - scheduler.cycles_per_line() * 11 - 1);
+        assert_eq!(scheduler.vi_half_line(), 524);
+        scheduler.tick(1);
+        assert_eq!(scheduler.vi_half_line(), 0);
+    }
+
+    #[test]
+    fn test_timing() {
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.cycles_per_vi(), CPU_CLOCK_RATE / 60);
+        scheduler.set_timing(TimingProfile::from_region(Region::PAL));
+        assert_eq!(scheduler.cycles_per_vi(), CPU_CLOCK_RATE / 50);
+        assert_eq!(scheduler.cycles_per_line(), CPU_CLOCK_RATE / 50 / 625);
+        assert!(!scheduler.tick(CPU_CLOCK_RATE / 60));
+        assert!(scheduler.tick(CPU_CLOCK_RATE / 50 - CPU_CLOCK_RATE / 60));
+    }
+}
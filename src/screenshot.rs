@@ -0,0 +1,152 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::PathBuf;
+
+use flate2::Crc;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::rom::ROM;
+use crate::storage::{self, DataKind};
+
+// Screenshots are named after the game and the Unix time they were taken
+pub fn screenshot_path(rom: &ROM, timestamp: u64) -> Option<PathBuf> {
+    storage::game_file(rom, DataKind::Screenshots, &format!("{}.png", timestamp))
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/*
+    8 bit RGBA PNG without filtering, flate2 already does the compression so no image crate is needed.
+    https://www.w3.org/TR/png/#5Chunk-layout
+*/
+pub fn encode_png(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth, RGBA color type, compression, filter and interlace methods
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    png_chunk(&mut png, b"IHDR", &header);
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in rgba.chunks_exact(width * 4).take(height) {
+        // Every line starts with its filter type, 0 is none
+        encoder.write_all(&[0]).unwrap();
+        encoder.write_all(row).unwrap();
+    }
+    png_chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn invalid_png(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid PNG: {}", message))
+}
+
+// https://www.w3.org/TR/png/#9Filter-type-4-Paeth
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+/*
+    Reads 8 bit RGB and RGBA PNGs without interlacing, what image editors save texture packs as.
+    Returns the width, the height and RGBA pixels.
+*/
+pub fn decode_png(png: &[u8]) -> Result<(usize, usize, Vec<u8>)> {
+    if png.get(..8) != Some(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Err(invalid_png("missing signature"));
+    }
+    let mut header = None;
+    let mut compressed = Vec::new();
+    let mut position = 8;
+    while position + 8 <= png.len() {
+        let length = u32::from_be_bytes(png[position..position + 4].try_into().unwrap()) as usize;
+        let kind = &png[position + 4..position + 8];
+        let data = png.get(position + 8..position + 8 + length).ok_or_else(|| invalid_png("truncated chunk"))?;
+        match kind {
+            b"IHDR" => header = Some(data.to_vec()),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {},
+        };
+        position += 12 + length;
+    }
+    let header = header.filter(|header| header.len() == 13).ok_or_else(|| invalid_png("missing header"))?;
+    let width = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let channels = match (header[8], header[9], header[12]) {
+        (8, 2, 0) => 3,
+        (8, 6, 0) => 4,
+        _ => return Err(invalid_png("only 8 bit RGB and RGBA without interlacing are supported")),
+    };
+    let mut data = Vec::new();
+    ZlibDecoder::new(&compressed[..]).read_to_end(&mut data)?;
+    let stride = width * channels;
+    if data.len() < (stride + 1) * height {
+        return Err(invalid_png("not enough image data"));
+    }
+    let mut rows = vec![0_u8; stride * height];
+    for y in 0..height {
+        let line = &data[y * (stride + 1)..(y + 1) * (stride + 1)];
+        for x in 0..stride {
+            let left = if x >= channels { rows[y * stride + x - channels] } else { 0 };
+            let up = if y > 0 { rows[(y - 1) * stride + x] } else { 0 };
+            let up_left = if x >= channels && y > 0 { rows[(y - 1) * stride + x - channels] } else { 0 };
+            let predicted = match line[0] {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(invalid_png("unknown filter")),
+            };
+            rows[y * stride + x] = line[1 + x].wrapping_add(predicted);
+        }
+    }
+    let pixels = match channels {
+        4 => rows,
+        _ => rows.chunks_exact(3).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0xFF]).collect(),
+    };
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod screenshot_tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_png() {
+        let pixels = [0xFF, 0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF];
+        let png = encode_png(1, 2, &pixels);
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 1, 0, 0, 0, 2]);
+        // The IHDR CRC of a 1x2 RGBA image
+        assert_eq!(&png[29..33], &[0x99, 0x81, 0xB6, 0x27]);
+
+        let idat_length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut data = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_length]).read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![0, 0xFF, 0x00, 0x00, 0xFF, 0, 0x00, 0xFF, 0x00, 0xFF]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        assert_eq!(decode_png(&png).unwrap(), (1, 2, pixels.to_vec()));
+    }
+}
@@ -0,0 +1,143 @@
+// How many matches are sent back to the GUI after each scan
+pub const MAX_SEARCH_RESULTS: usize = 200;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl ValueType {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 | Self::I8 => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 | Self::F32 => 4,
+        }
+    }
+
+    // Every type fits in a f64 without losing precision, so comparisons can be done on it
+    pub fn decode(&self, bytes: &[u8]) -> f64 {
+        match self {
+            Self::U8 => bytes[0] as f64,
+            Self::I8 => bytes[0] as i8 as f64,
+            Self::U16 => u16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            Self::I16 => i16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            Self::U32 => u32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Self::I32 => i32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+            Self::F32 => f32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Comparison {
+    Equal(f64),
+    Greater(f64),
+    Less(f64),
+    Changed,
+    Unchanged,
+}
+
+impl Comparison {
+    fn matches(&self, current: f64, previous: f64) -> bool {
+        match self {
+            Self::Equal(value) => current == *value,
+            Self::Greater(value) => current > *value,
+            Self::Less(value) => current < *value,
+            Self::Changed => current != previous,
+            Self::Unchanged => current == previous,
+        }
+    }
+}
+
+/*
+    Cheat search over RDRAM: every scan narrows the candidates found by the previous one.
+    Values are aligned to their size, like games store them.
+*/
+pub struct MemorySearch {
+    value_type: ValueType,
+    previous: Vec<u8>,
+    candidates: Vec<u32>,
+}
+
+impl MemorySearch {
+    pub fn new(value_type: ValueType, memory: &[u8]) -> Self {
+        let candidates = (0..memory.len() / value_type.size())
+            .map(|index| (index * value_type.size()) as u32)
+            .collect();
+        Self {
+            value_type,
+            previous: memory.to_vec(),
+            candidates,
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    fn value(&self, memory: &[u8], offset: u32) -> f64 {
+        let offset = offset as usize;
+        self.value_type.decode(&memory[offset..offset + self.value_type.size()])
+    }
+
+    pub fn scan(&mut self, memory: &[u8], comparison: Comparison) {
+        let candidates = std::mem::take(&mut self.candidates);
+        self.candidates = candidates.into_iter()
+            .filter(|offset| comparison.matches(self.value(memory, *offset), self.value(&self.previous, *offset)))
+            .collect();
+        self.previous = memory.to_vec();
+    }
+
+    pub fn count(&self) -> usize {
+        self.candidates.len()
+    }
+
+    // Offsets and values of the first matches as of the last scan
+    pub fn results(&self, limit: usize) -> Vec<(u32, f64)> {
+        self.candidates.iter()
+            .take(limit)
+            .map(|offset| (*offset, self.value(&self.previous, *offset)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(ValueType::I8.decode(&[0xFF]), -1.0);
+        assert_eq!(ValueType::U16.decode(&[0x12, 0x34]), 0x1234 as f64);
+        assert_eq!(ValueType::I32.decode(&[0xFF, 0xFF, 0xFF, 0xFE]), -2.0);
+        assert_eq!(ValueType::F32.decode(&1.5_f32.to_be_bytes()), 1.5);
+    }
+
+    #[test]
+    fn test_successive_scans() {
+        let mut memory = vec![0; 16];
+        memory[3] = 5;
+        memory[7] = 5;
+        let mut search = MemorySearch::new(ValueType::U32, &memory);
+        assert_eq!(search.count(), 4);
+        search.scan(&memory, Comparison::Equal(5.0));
+        assert_eq!(search.results(10), vec![(0, 5.0), (4, 5.0)]);
+
+        memory[7] = 6;
+        search.scan(&memory, Comparison::Changed);
+        assert_eq!(search.results(10), vec![(4, 6.0)]);
+
+        search.scan(&memory, Comparison::Unchanged);
+        search.scan(&memory, Comparison::Greater(5.0));
+        assert_eq!(search.count(), 1);
+        search.scan(&memory, Comparison::Less(6.0));
+        assert_eq!(search.count(), 0);
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::emulator::Emulator;
+
+/*
+    Handle to an emulator driven from one thread and inspected from others. The thread running it
+    only gives the lock up between frames, at the VI interrupt that ends each one, so readers always
+    see the state of a complete frame: registers, memory and the frame buffer agree with each other.
+    Clones share the same emulator.
+*/
+#[derive(Clone)]
+pub struct SharedEmulator {
+    emulator: Arc<RwLock<Emulator>>,
+}
+
+impl SharedEmulator {
+    pub fn new(emulator: Emulator) -> Self {
+        Self {
+            emulator: Arc::new(RwLock::new(emulator)),
+        }
+    }
+
+    // A panic in the core leaves it as it was when it happened, which is what the debugger wants to see
+    fn read_lock(&self) -> RwLockReadGuard<'_, Emulator> {
+        self.emulator.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, Emulator> {
+        self.emulator.write().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Runs up to the next VI interrupt, readers wait until it's done
+    pub fn run_frame(&self) {
+        self.write_lock().run_frame();
+    }
+
+    // Lets readers in after every frame
+    pub fn run_frames(&self, frames: u64) {
+        for _ in 0..frames {
+            self.run_frame();
+        }
+    }
+
+    pub fn read<T, F: FnOnce(&Emulator) -> T>(&self, f: F) -> T {
+        f(&self.read_lock())
+    }
+
+    pub fn write<T, F: FnOnce(&mut Emulator) -> T>(&self, f: F) -> T {
+        f(&mut self.write_lock())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.read(|emulator| emulator.frames())
+    }
+
+    pub fn program_counter(&self) -> i64 {
+        self.read(|emulator| emulator.cpu().registers().get_program_counter())
+    }
+
+    // General purpose registers as of the end of the last frame
+    pub fn registers(&self) -> [i64; 32] {
+        self.read(|emulator| std::array::from_fn(|index| emulator.cpu().registers().get_by_number(index)))
+    }
+
+    pub fn read_memory(&self, address: i64, len: usize) -> Vec<u8> {
+        self.read(|emulator| emulator.mmu().read_virtual(address, len))
+    }
+}
+
+#[cfg(test)]
+mod shared_tests {
+    use super::*;
+    use crate::cheat::{Cheat, CheatCode};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_shared_emulator() {
+        assert_send_sync::<Emulator>();
+        let shared = SharedEmulator::new(Emulator::new_hle());
+        shared.write(|emulator| {
+            // A loop clearing 0x80000400, which a cheat sets back to DEADBEEF at the end of every frame
+            let program = [0x3C088000_u32, 0xAD000400, 0x08000400, 0x00000000];
+            for (index, opcode) in program.iter().enumerate() {
+                emulator.mut_mmu().write_virtual(0x80001000 + index as i64 * 4, &opcode.to_be_bytes());
+            }
+            emulator.mut_cpu().mut_registers().set_program_counter(0x80001000);
+            emulator.mut_cpu().mut_registers().set_next_program_counter(0x80001004);
+            emulator.mut_mmu().write_virtual(0x80000400, &[0xDE, 0xAD, 0xBE, 0xEF]);
+            let codes = ["81000400 DEAD", "81000402 BEEF"].iter().map(|code| CheatCode::parse(code).unwrap()).collect();
+            emulator.set_cheats(vec![Cheat { name: String::new(), enabled: true, codes }]);
+        });
+        let runner = shared.clone();
+        let thread = std::thread::spawn(move || runner.run_frames(2));
+        // Memory is only ever seen between frames, never cleared by the loop
+        while !thread.is_finished() {
+            assert_eq!(shared.read_memory(0x80000400, 4), vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        }
+        thread.join().unwrap();
+        assert_eq!(shared.frames(), 2);
+        assert_eq!(shared.registers()[0], 0);
+        // Which is what a reader would see in the middle of one
+        shared.write(|emulator| (0..4).for_each(|_| { emulator.tick(); }));
+        assert_eq!(shared.read_memory(0x80000400, 4), vec![0; 4]);
+    }
+}
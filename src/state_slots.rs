@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{Read, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rom::ROM;
+use crate::savestate::{StateReader, StateWriter};
+use crate::storage::{self, DataKind};
+
+pub const STATE_SLOTS: usize = 10;
+pub const THUMBNAIL_WIDTH: usize = 80;
+pub const THUMBNAIL_HEIGHT: usize = 60;
+
+// Header, timestamp, thumbnail size and the thumbnail itself, enough to list the slots without reading the states
+const SLOT_INFO_SIZE: u64 = 8 + 8 + 4 + 4 + (THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4) as u64;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    // RGBA
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    // Nearest neighbour downscale of an RGBA framebuffer
+    pub fn new(width: usize, height: usize, pixels: &[u8]) -> Self {
+        let mut thumbnail = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4);
+        for y in 0..THUMBNAIL_HEIGHT {
+            for x in 0..THUMBNAIL_WIDTH {
+                let offset = ((y * height / THUMBNAIL_HEIGHT) * width + x * width / THUMBNAIL_WIDTH) * 4;
+                thumbnail.extend_from_slice(pixels.get(offset..offset + 4).unwrap_or(&[0, 0, 0, 0xFF]));
+            }
+        }
+        Self {
+            width: THUMBNAIL_WIDTH,
+            height: THUMBNAIL_HEIGHT,
+            pixels: thumbnail,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SlotInfo {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+// Slots are named like the battery saves: <game>.st0 to <game>.st9
+pub fn slot_path(rom: &ROM, slot: usize) -> Option<PathBuf> {
+    storage::game_file(rom, DataKind::States, &format!("st{}", slot))
+}
+
+pub fn write_slot(thumbnail: Option<&Thumbnail>, state: &[u8]) -> Vec<u8> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let mut writer = StateWriter::new();
+    writer.write_u64(timestamp);
+    match thumbnail {
+        Some(thumbnail) => {
+            writer.write_u16(thumbnail.width as u16);
+            writer.write_u16(thumbnail.height as u16);
+            writer.write_block(&thumbnail.pixels);
+        },
+        None => {
+            writer.write_u16(0);
+            writer.write_u16(0);
+            writer.write_block(&[]);
+        },
+    };
+    writer.write_block(state);
+    writer.finish()
+}
+
+fn read_info(reader: &mut StateReader) -> Result<SlotInfo> {
+    let timestamp = reader.read_u64()?;
+    let width = reader.read_u16()? as usize;
+    let height = reader.read_u16()? as usize;
+    let pixels = reader.read_block()?;
+    Ok(SlotInfo {
+        timestamp,
+        thumbnail: match pixels.len() == width * height * 4 && !pixels.is_empty() {
+            true => Some(Thumbnail { width, height, pixels: pixels.to_vec() }),
+            false => None,
+        },
+    })
+}
+
+// The savestate stored in the slot
+pub fn read_slot(data: &[u8]) -> Result<&[u8]> {
+    let mut reader = StateReader::new(data)?;
+    read_info(&mut reader)?;
+    reader.read_block()
+}
+
+// None when the slot is empty or unreadable
+pub fn read_slot_info(path: &Path) -> Option<SlotInfo> {
+    let mut data = Vec::new();
+    File::open(path).ok()?.take(SLOT_INFO_SIZE).read_to_end(&mut data).ok()?;
+    read_info(&mut StateReader::new(&data).ok()?).ok()
+}
+
+// "2024-01-31 18:05 UTC", without pulling a date crate: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    let seconds = timestamp % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
+
+#[cfg(test)]
+mod state_slots_tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_roundtrip() {
+        let pixels: Vec<u8> = (0..320 * 240 * 4).map(|i| (i / 4 % 256) as u8).collect();
+        let thumbnail = Thumbnail::new(320, 240, &pixels);
+        assert_eq!(thumbnail.pixels.len(), THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 4);
+        assert_eq!(&thumbnail.pixels[4..8], &[4, 4, 4, 4]);
+
+        let data = write_slot(Some(&thumbnail), &[1, 2, 3]);
+        assert_eq!(read_slot(&data).unwrap(), &[1, 2, 3]);
+        let path = std::env::temp_dir().join(format!("rultra64_{}_slot.st0", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let info = read_slot_info(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(info.thumbnail, Some(thumbnail));
+        assert!(read_slot_info(&path).is_none());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(1706724300), "2024-01-31 18:05 UTC");
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::log::{log, Level, Subsystem};
+use crate::rom::ROM;
+use crate::save::save_file_name;
+use crate::state_slots::STATE_SLOTS;
+
+/*
+    Files made for a game go in a data directory, one subdirectory per kind and game:
+    <data>/saves/NSME-635A2BFF/SUPER MARIO 64.eep, keyed by the game code and the first header CRC
+    so two dumps with the same name don't share them. Without a data directory, like in the CLI and
+    the C API by default, they stay next to the ROM where older versions put them.
+*/
+static DATA_DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataKind {
+    // Battery saves and Controller Paks
+    Saves,
+    // Savestate slots and the autosave
+    States,
+    Screenshots,
+    // Settings that only apply to one game
+    Configs,
+}
+
+impl DataKind {
+    pub const ALL: [DataKind; 4] = [DataKind::Saves, DataKind::States, DataKind::Screenshots, DataKind::Configs];
+
+    pub fn directory_name(&self) -> &'static str {
+        match self {
+            DataKind::Saves => "saves",
+            DataKind::States => "states",
+            DataKind::Screenshots => "screenshots",
+            DataKind::Configs => "configs",
+        }
+    }
+
+    // Extensions of the files of this kind that older versions left next to the ROM
+    fn loose_extensions(&self) -> Vec<String> {
+        match self {
+            DataKind::Saves => ["eep", "sra", "fla", "mpk1", "mpk2", "mpk3", "mpk4"].map(String::from).to_vec(),
+            DataKind::States => (0..STATE_SLOTS).map(|slot| format!("st{}", slot)).chain([String::from("auto")]).collect(),
+            DataKind::Screenshots | DataKind::Configs => Vec::new(),
+        }
+    }
+}
+
+pub fn set_data_directory(directory: Option<PathBuf>) {
+    *DATA_DIRECTORY.lock().unwrap_or_else(|err| err.into_inner()) = directory;
+}
+
+pub fn data_directory() -> Option<PathBuf> {
+    DATA_DIRECTORY.lock().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+// RULTRA64_DATA_DIR, or where each platform keeps application data
+pub fn default_data_directory() -> Option<PathBuf> {
+    if let Some(directory) = std::env::var_os("RULTRA64_DATA_DIR") {
+        return Some(PathBuf::from(directory));
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home.map(|home| home.join("Library").join("Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME").map(PathBuf::from).or_else(|| home.map(|home| home.join(".local").join("share")))
+    };
+    base.map(|base| base.join("rultra64"))
+}
+
+// "NSME-635A2BFF", None without a full header
+pub fn game_key(rom: &ROM) -> Option<String> {
+    let header = rom.header();
+    let game_code: String = header.get(0x3B..0x3F)?
+        .iter()
+        .map(|byte| match byte.is_ascii_alphanumeric() {
+            true => *byte as char,
+            false => '_',
+        })
+        .collect();
+    let crc = u32::from_be_bytes(header.get(0x10..0x14)?.try_into().unwrap());
+    Some(format!("{}-{:08X}", game_code, crc))
+}
+
+pub fn game_directory(root: &Path, rom: &ROM, kind: DataKind) -> Option<PathBuf> {
+    game_key(rom).map(|key| root.join(kind.directory_name()).join(key))
+}
+
+fn game_file_in(root: Option<&Path>, rom: &ROM, kind: DataKind, extension: &str) -> Option<PathBuf> {
+    match root {
+        Some(root) => game_directory(root, rom, kind).map(|directory| directory.join(save_file_name(rom.header(), extension))),
+        None => rom.save_path_with_extension(extension),
+    }
+}
+
+// Where to keep the file of a game with this extension, named like the battery saves
+pub fn game_file(rom: &ROM, kind: DataKind, extension: &str) -> Option<PathBuf> {
+    game_file_in(data_directory().as_deref(), rom, kind, extension)
+}
+
+// Called when a ROM is loaded, creates its directories and moves the files left next to it
+pub fn prepare(rom: &ROM) -> Result<()> {
+    match data_directory() {
+        Some(root) => prepare_in(&root, rom),
+        None => Ok(()),
+    }
+}
+
+fn prepare_in(root: &Path, rom: &ROM) -> Result<()> {
+    for kind in DataKind::ALL {
+        if let Some(directory) = game_directory(root, rom, kind) {
+            std::fs::create_dir_all(directory)?;
+        }
+    }
+    let moved = migrate_loose_files(root, rom)?;
+    if moved > 0 {
+        log!(Level::Info, Subsystem::Frontend, "Moved {} files next to the ROM to {}", moved, root.display());
+    }
+    Ok(())
+}
+
+// Files already in the data directory are never replaced, the loose ones stay where they are then
+fn migrate_loose_files(root: &Path, rom: &ROM) -> Result<usize> {
+    let mut files = Vec::new();
+    for kind in DataKind::ALL {
+        for extension in kind.loose_extensions() {
+            files.push((kind, extension));
+        }
+    }
+    // Screenshots are named after the game and a timestamp, "<game>.<time>.png"
+    let prefix = save_file_name(rom.header(), "");
+    if let Some(entries) = rom.path().and_then(|path| std::fs::read_dir(path.parent()?).ok()) {
+        for name in entries.flatten().filter_map(|entry| entry.file_name().into_string().ok()) {
+            let timestamp = name.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(".png"));
+            if timestamp.is_some_and(|timestamp| !timestamp.is_empty() && timestamp.bytes().all(|byte| byte.is_ascii_digit())) {
+                files.push((DataKind::Screenshots, name[prefix.len()..].to_string()));
+            }
+        }
+    }
+
+    let mut moved = 0;
+    for (kind, extension) in files {
+        let source = rom.save_path_with_extension(&extension);
+        let destination = game_file_in(Some(root), rom, kind, &extension);
+        if let (Some(source), Some(destination)) = (source, destination) {
+            if source.is_file() && !destination.exists() {
+                // Renaming fails across file systems
+                if std::fs::rename(&source, &destination).is_err() {
+                    std::fs::copy(&source, &destination)?;
+                    std::fs::remove_file(&source)?;
+                }
+                moved += 1;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_loose_files() {
+        let directory = std::env::temp_dir().join(format!("rultra64-storage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let roms = directory.join("roms");
+        let root = directory.join("data");
+        std::fs::create_dir_all(&roms).unwrap();
+        let mut data = vec![0; 0x1000];
+        data[0x10..0x14].copy_from_slice(&[0x63, 0x5A, 0x2B, 0xFF]);
+        data[0x20..0x2E].copy_from_slice(b"SUPER MARIO 64");
+        data[0x3B..0x3F].copy_from_slice(b"NSME");
+        std::fs::write(roms.join("mario.z64"), &data).unwrap();
+        for name in ["SUPER MARIO 64.eep", "SUPER MARIO 64.st3", "SUPER MARIO 64.1700000000.png", "SUPER MARIO 64.notes.png"] {
+            std::fs::write(roms.join(name), name).unwrap();
+        }
+        let rom = ROM::new_from_filename(&roms.join("mario.z64").display().to_string()).unwrap();
+        assert_eq!(game_key(&rom), Some(String::from("NSME-635A2BFF")));
+
+        prepare_in(&root, &rom).unwrap();
+        let save = root.join("saves").join("NSME-635A2BFF").join("SUPER MARIO 64.eep");
+        assert_eq!(game_file_in(Some(&root), &rom, DataKind::Saves, "eep"), Some(save.clone()));
+        assert_eq!(std::fs::read_to_string(save).unwrap(), "SUPER MARIO 64.eep");
+        assert!(root.join("states").join("NSME-635A2BFF").join("SUPER MARIO 64.st3").is_file());
+        assert!(root.join("screenshots").join("NSME-635A2BFF").join("SUPER MARIO 64.1700000000.png").is_file());
+        assert!(root.join("configs").join("NSME-635A2BFF").is_dir());
+        assert!(!roms.join("SUPER MARIO 64.eep").exists());
+        // Not a screenshot of the emulator
+        assert!(roms.join("SUPER MARIO 64.notes.png").is_file());
+
+        // Without a data directory files stay next to the ROM
+        assert_eq!(game_file_in(None, &rom, DataKind::Saves, "eep"), Some(roms.join("SUPER MARIO 64.eep")));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+// Looked for next to the ROM, like "Game.z64" and "Game.sym"
+pub const SYMBOL_EXTENSIONS: [&str; 2] = ["sym", "map"];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Symbol {
+    // 32 bit virtual address, like 0x80001000
+    pub address: u32,
+    pub name: String,
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let value = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    // GNU ld maps print 64 bit addresses
+    u64::from_str_radix(value, 16).ok().map(|address| address as u32)
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_' || first == '.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "_.$@".contains(c))
+}
+
+/*
+    One symbol per line, in any of the formats homebrew toolchains and other debuggers write:
+        80001000=main                   plain text
+        80001000,code,main              Project64 .sym
+        80001000 T main                 nm
+        0x0000000080001000    main      GNU ld .map, where the other lines are skipped
+*/
+fn parse_line(line: &str) -> Option<Symbol> {
+    let line = line.trim();
+    let (address, name) = match line.split_once('=') {
+        Some((address, name)) => (address, name.trim()),
+        None if line.contains(',') => {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            (fields[0], *fields.get(2)?)
+        },
+        None => match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [address, name] => (address, name),
+            [address, kind, name] if kind.len() == 1 => (address, name),
+            _ => return None,
+        },
+    };
+    match is_identifier(name) {
+        true => Some(Symbol { address: parse_hex(address)?, name: name.to_string() }),
+        false => None,
+    }
+}
+
+// Sorted by address, for the debugger, breakpoints by name and trace logs
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn from_symbols(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self {
+            symbols,
+        }
+    }
+
+    // Lines that aren't symbols are skipped, map files are mostly sections and object files
+    pub fn parse(text: &str) -> Self {
+        Self::from_symbols(text.lines().filter_map(parse_line).collect())
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    // The symbol file with the ROM's name, if there's one
+    pub fn load_for_rom(rom_path: &Path) -> Option<Self> {
+        SYMBOL_EXTENSIONS.iter()
+            .map(|extension| rom_path.with_extension(extension))
+            .find(|path| path.is_file())
+            .and_then(|path| Self::load(&path).ok())
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address)
+    }
+
+    // The closest symbol at or before the address and the offset from it
+    pub fn lookup(&self, address: i64) -> Option<(&Symbol, u32)> {
+        let address = address as u32;
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        Some((symbol, address - symbol.address))
+    }
+
+    // "main" or "main+0x10"
+    pub fn describe(&self, address: i64) -> Option<String> {
+        self.lookup(address).map(|(symbol, offset)| match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+0x{:X}", symbol.name, offset),
+        })
+    }
+
+    // Addresses in hex, with or without 0x, or symbol names
+    pub fn resolve(&self, input: &str) -> Option<i64> {
+        let input = input.trim();
+        match self.address_of(input) {
+            Some(address) => Some(address as i64),
+            None => i64::from_str_radix(input.strip_prefix("0x").unwrap_or(input), 16).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let symbols = SymbolTable::parse("
+            80001100=draw_frame
+            80001000,code,main
+            80002000 T game_loop
+            .text           0x0000000080001000     0x2340 build/main.o
+                            0x0000000080003000                osCreateThread
+            not a symbol
+        ");
+        let names: Vec<&str> = symbols.symbols().iter().map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "draw_frame", "game_loop", "osCreateThread"]);
+        assert_eq!(symbols.address_of("game_loop"), Some(0x80002000));
+        assert_eq!(symbols.describe(0xFFFFFFFF80001000u64 as i64), Some("main".to_string()));
+        assert_eq!(symbols.describe(0x80001110), Some("draw_frame+0x10".to_string()));
+        assert_eq!(symbols.describe(0x80000000), None);
+        assert_eq!(symbols.resolve("main"), Some(0x80001000));
+        assert_eq!(symbols.resolve("0x80000400"), Some(0x80000400));
+        assert_eq!(symbols.resolve("missing"), None);
+    }
+}
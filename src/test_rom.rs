@@ -0,0 +1,162 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use flate2::Crc;
+
+use crate::emulator::Emulator;
+use crate::rom::ROM;
+
+// Directory with the test ROMs when RULTRA64_TEST_ROMS isn't set, they aren't part of the repository
+pub const TEST_ROMS_DIRECTORY: &str = "tests/roms";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TestCheck {
+    // dillonb's n64-tests leave 0 in r30 when they pass and -1 when they fail
+    Register { index: usize, value: i64 },
+    // Big endian word at a physical address
+    Memory { address: i64, value: u32 },
+    // krom's tests only draw the result, so the frame is compared against a stored CRC32
+    FramebufferHash(u32),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TestRom {
+    pub name: String,
+    // Relative to the test ROMs directory
+    pub path: PathBuf,
+    pub frames: u64,
+    pub check: TestCheck,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TestOutcome {
+    Pass,
+    Fail(String),
+}
+
+fn parse_hex(value: &str) -> Result<u64, String> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| format!("Invalid hex value {}", value))
+}
+
+impl TestCheck {
+    // "r30=0", "mem:00001000=12345678" or "fb:1A2B3C4D", values in hex
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(hash) = text.strip_prefix("fb:") {
+            return Ok(Self::FramebufferHash(parse_hex(hash)? as u32));
+        }
+        let (target, value) = text.split_once('=').ok_or(format!("Invalid check {}", text))?;
+        let value = match value.strip_prefix('-') {
+            Some(value) => -(parse_hex(value)? as i64),
+            None => parse_hex(value)? as i64,
+        };
+        if let Some(address) = target.strip_prefix("mem:") {
+            return Ok(Self::Memory { address: parse_hex(address)? as i64, value: value as u32 });
+        }
+        match target.strip_prefix('r').and_then(|index| index.parse().ok()) {
+            Some(index) if index < 32 => Ok(Self::Register { index, value }),
+            _ => Err(format!("Invalid check {}", text)),
+        }
+    }
+
+    pub fn run(&self, emulator: &Emulator) -> TestOutcome {
+        let (expected, actual) = match self {
+            Self::Register { index, value } => (*value, emulator.cpu().registers().get_by_number(*index)),
+            Self::Memory { address, value } => {
+                let data = emulator.mmu().read_physical(*address, 4);
+                (*value as i64, u32::from_be_bytes(data.try_into().unwrap()) as i64)
+            },
+            Self::FramebufferHash(hash) => match emulator.mmu().framebuffer_rgba() {
+                Some((_, _, pixels)) => (*hash as i64, framebuffer_hash(&pixels) as i64),
+                None => return TestOutcome::Fail("The video output is blank".to_string()),
+            },
+        };
+        match expected == actual {
+            true => TestOutcome::Pass,
+            false => TestOutcome::Fail(format!("Expected {:X}, got {:X}", expected, actual)),
+        }
+    }
+}
+
+// CRC32 of the RGBA pixels, what the goldens store
+pub fn framebuffer_hash(pixels: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(pixels);
+    crc.sum()
+}
+
+/*
+    One test per line: name, ROM path, frames to run and the check, separated by spaces.
+    Empty lines and the ones starting with # are skipped.
+*/
+pub fn parse_manifest(data: &str) -> Result<Vec<TestRom>, String> {
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [name, path, frames, check] => Ok(TestRom {
+                    name: name.to_string(),
+                    path: PathBuf::from(path),
+                    frames: frames.parse().map_err(|_| format!("Invalid number of frames in {}", line))?,
+                    check: TestCheck::parse(check)?,
+                }),
+                _ => Err(format!("Expected a name, a path, frames and a check in {}", line)),
+            }
+        })
+        .collect()
+}
+
+impl TestRom {
+    // None when the ROM isn't in the directory, so a partial checkout of the test ROMs still runs
+    pub fn run(&self, directory: &Path) -> Option<TestOutcome> {
+        let path = directory.join(&self.path);
+        if !path.is_file() {
+            return None;
+        }
+        let rom = match ROM::new_from_filename(&path.display().to_string()) {
+            Ok(rom) => rom,
+            Err(err) => return Some(TestOutcome::Fail(format!("Could not load {}: {}", path.display(), err))),
+        };
+        let mut emulator = Emulator::new_hle();
+        emulator.load_rom(rom);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            for _ in 0..self.frames {
+                emulator.run_frame();
+            }
+        }));
+        Some(match result {
+            Ok(_) => self.check.run(&emulator),
+            Err(_) => TestOutcome::Fail(format!("Emulation failed at PC {:016X}", emulator.cpu().registers().get_program_counter())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_rom_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let tests = parse_manifest("# dillonb\naddiu dillonb/addiu.z64 60 r30=0\n\nadd krom/CPUADD.N64 30 fb:1A2B3C4D\nfail x.z64 1 r30=-1\nmem x.z64 1 mem:1000=DEADBEEF\n").unwrap();
+        assert_eq!(tests.len(), 4);
+        assert_eq!(tests[0], TestRom {
+            name: "addiu".to_string(),
+            path: PathBuf::from("dillonb/addiu.z64"),
+            frames: 60,
+            check: TestCheck::Register { index: 30, value: 0 },
+        });
+        assert_eq!(tests[1].check, TestCheck::FramebufferHash(0x1A2B3C4D));
+        assert_eq!(tests[2].check, TestCheck::Register { index: 30, value: -1 });
+        assert_eq!(tests[3].check, TestCheck::Memory { address: 0x1000, value: 0xDEADBEEF });
+        assert!(parse_manifest("broken x.z64 60").is_err());
+        assert!(parse_manifest("broken x.z64 60 r32=0").is_err());
+
+        assert_eq!(tests[0].run(Path::new("/does/not/exist")), None);
+        let mut emulator = Emulator::new_hle();
+        emulator.mut_mmu().write_physical(0x1000, &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(tests[3].check.run(&emulator), TestOutcome::Pass);
+        assert_eq!(tests[1].check.run(&emulator), TestOutcome::Fail("The video output is blank".to_string()));
+        assert_eq!(framebuffer_hash(b"123456789"), 0xCBF43926);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use flate2::Crc;
+
+use crate::log::{log, Level, Subsystem};
+use crate::rdp::{decode_tile, TileDescriptor, TEXEL_SIZES, TEXTURE_FORMATS};
+use crate::rom::ROM;
+use crate::screenshot::{decode_png, encode_png};
+
+/*
+    Textures are named after the CRC32 of what they look like once decoded, so the same texture
+    gets the same name whatever TMEM address, palette slot or load command brought it in:
+    "<CRC32>#<format>#<size>.png". Dumps go in the dump directory of the pack, replacements
+    with the same name in the pack itself may be any size.
+*/
+pub const TEXTURE_DUMP_DIRECTORY: &str = "dump";
+
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+pub fn texture_hash(width: usize, height: usize, pixels: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(&(width as u32).to_be_bytes());
+    crc.update(&(height as u32).to_be_bytes());
+    crc.update(pixels);
+    crc.sum()
+}
+
+pub fn texture_file_name(hash: u32, tile: &TileDescriptor) -> String {
+    let format = TEXTURE_FORMATS.get(tile.format as usize).copied().unwrap_or("?");
+    format!("{:08X}#{}#{}.png", hash, format, TEXEL_SIZES[tile.size as usize & 0b11])
+}
+
+// The hash at the start of a texture file name
+fn parse_file_name(name: &str) -> Option<u32> {
+    let hash = name.strip_suffix(".png")?.split('#').next()?;
+    u32::from_str_radix(hash, 16).ok()
+}
+
+pub struct TexturePack {
+    directory: PathBuf,
+    dumping: bool,
+    dumped: HashSet<u32>,
+    // Files found in the pack, decoded the first time they're asked for
+    replacements: HashMap<u32, PathBuf>,
+    loaded: HashMap<u32, Option<Texture>>,
+}
+
+impl TexturePack {
+    pub fn new(directory: &Path) -> Self {
+        let mut replacements = HashMap::new();
+        if let Ok(entries) = std::fs::read_dir(directory) {
+            for entry in entries.flatten() {
+                if let Some(hash) = entry.file_name().to_str().and_then(parse_file_name) {
+                    replacements.insert(hash, entry.path());
+                }
+            }
+        }
+        let mut dumped = HashSet::new();
+        if let Ok(entries) = std::fs::read_dir(directory.join(TEXTURE_DUMP_DIRECTORY)) {
+            dumped.extend(entries.flatten().filter_map(|entry| entry.file_name().to_str().and_then(parse_file_name)));
+        }
+        Self {
+            directory: directory.to_path_buf(),
+            dumping: false,
+            dumped,
+            replacements,
+            loaded: HashMap::new(),
+        }
+    }
+
+    // The pack of a game is the "textures" directory named after it next to the ROM
+    pub fn for_rom(rom: &ROM) -> Option<Self> {
+        rom.save_path_with_extension("textures").map(|directory| TexturePack::new(&directory))
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn dumping(&self) -> bool {
+        self.dumping
+    }
+
+    pub fn set_dumping(&mut self, dumping: bool) {
+        self.dumping = dumping;
+    }
+
+    pub fn replacement_count(&self) -> usize {
+        self.replacements.len()
+    }
+
+    // Called when a tile gets texels and a size, returns the hash of the texture
+    pub fn texture_loaded(&mut self, tmem: &[u8], tile: &TileDescriptor) -> Option<u32> {
+        let (width, height) = tile.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let pixels = decode_tile(tmem, tile, width, height);
+        let hash = texture_hash(width, height, &pixels);
+        if self.dumping && self.dumped.insert(hash) {
+            if let Err(err) = self.dump(hash, tile, width, height, &pixels) {
+                log!(Level::Warn, Subsystem::RDP, "Could not dump texture {:08X}: {}", hash, err);
+            }
+        }
+        Some(hash)
+    }
+
+    fn dump(&self, hash: u32, tile: &TileDescriptor, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+        let directory = self.directory.join(TEXTURE_DUMP_DIRECTORY);
+        std::fs::create_dir_all(&directory)?;
+        std::fs::write(directory.join(texture_file_name(hash, tile)), encode_png(width, height, pixels))
+    }
+
+    // The texture to draw instead of the one with this hash, if the pack has one
+    pub fn replacement(&mut self, hash: u32) -> Option<&Texture> {
+        let path = self.replacements.get(&hash)?;
+        self.loaded.entry(hash).or_insert_with(|| {
+            match std::fs::read(path).and_then(|png| decode_png(&png)) {
+                Ok((width, height, pixels)) => Some(Texture { width, height, pixels }),
+                Err(err) => {
+                    log!(Level::Warn, Subsystem::RDP, "Could not load {}: {}", path.display(), err);
+                    None
+                },
+            }
+        }).as_ref()
+    }
+}
+
+#[cfg(test)]
+mod texture_pack_tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_and_replace() {
+        let directory = std::env::temp_dir().join(format!("rultra64-textures-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        // 2x2 I8 texture at the start of TMEM
+        let mut tmem = vec![0; 0x1000];
+        tmem[..2].copy_from_slice(&[0x10, 0x20]);
+        tmem[12..14].copy_from_slice(&[0x30, 0x40]);
+        let tile = TileDescriptor { format: 4, size: 1, line: 1, sh: 4, th: 4, ..TileDescriptor::default() };
+
+        let mut pack = TexturePack::new(&directory);
+        pack.set_dumping(true);
+        let hash = pack.texture_loaded(&tmem, &tile).unwrap();
+        let name = texture_file_name(hash, &tile);
+        assert!(name.ends_with("#I#8b.png"));
+        let dumped = std::fs::read(directory.join(TEXTURE_DUMP_DIRECTORY).join(&name)).unwrap();
+        let (width, height, pixels) = decode_png(&dumped).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(&pixels[..8], &[0x10, 0x10, 0x10, 0xFF, 0x20, 0x20, 0x20, 0xFF]);
+
+        // A 4x4 replacement for it
+        std::fs::write(directory.join(&name), encode_png(4, 4, &[0xAB; 64])).unwrap();
+        let mut pack = TexturePack::new(&directory);
+        assert_eq!(pack.replacement_count(), 1);
+        let replacement = pack.replacement(hash).unwrap();
+        assert_eq!((replacement.width, replacement.height), (4, 4));
+        assert!(pack.replacement(hash ^ 1).is_none());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::Result;
+
+use serde::{Deserialize, Serialize};
+
+use crate::savestate::{StateReader, StateWriter};
+
+pub const TLB_ENTRIES: usize = 32;
+
+/*
+    Raw copy of the PageMask, EntryHi, EntryLo0 and EntryLo1 registers written by TLBWI/TLBWR.
+    https://n64brew.dev/wiki/TLB
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TLBEntry {
+    pub page_mask: u32,
+    pub entry_hi: u64,
+    pub entry_lo_0: u64,
+    pub entry_lo_1: u64,
+}
+
+impl TLBEntry {
+    pub fn new() -> Self {
+        Self {
+            page_mask: 0,
+            entry_hi: 0,
+            entry_lo_0: 0,
+            entry_lo_1: 0,
+        }
+    }
+
+    // Bits covered by the offset inside an even/odd pair of pages
+    fn pair_mask(&self) -> u64 {
+        (self.page_mask as u64 & 0x01FFE000) | 0x1FFF
+    }
+
+    pub fn vpn2(&self) -> u64 {
+        (self.entry_hi & 0xFFFFFFFFFF) >> 13
+    }
+
+    pub fn asid(&self) -> u8 {
+        self.entry_hi as u8
+    }
+
+    pub fn global(&self) -> bool {
+        self.entry_lo_0 & self.entry_lo_1 & 1 != 0
+    }
+
+    pub fn pfn(entry_lo: u64) -> u32 {
+        ((entry_lo >> 6) & 0xFFFFF) as u32
+    }
+
+    pub fn dirty(entry_lo: u64) -> bool {
+        entry_lo & 0b100 != 0
+    }
+
+    pub fn valid(entry_lo: u64) -> bool {
+        entry_lo & 0b10 != 0
+    }
+
+    // C field, the cache algorithm of the page like K0 in Config
+    pub fn cache_algorithm(entry_lo: u64) -> u8 {
+        ((entry_lo >> 3) & 0b111) as u8
+    }
+
+    fn matches(&self, address: u64, asid: u8) -> bool {
+        let mask = !self.pair_mask() & 0xFFFFFFFFFF;
+        (address & mask) == (self.entry_hi & mask) && (self.global() || self.asid() == asid)
+    }
+
+    fn entry_lo(&self, address: u64) -> u64 {
+        let page_mask = self.pair_mask() >> 1;
+        match address & (page_mask + 1) != 0 {
+            true => self.entry_lo_1,
+            false => self.entry_lo_0,
+        }
+    }
+
+    fn translate(&self, address: u64) -> Option<i64> {
+        let page_mask = self.pair_mask() >> 1;
+        let entry_lo = self.entry_lo(address);
+        if !TLBEntry::valid(entry_lo) {
+            return None;
+        }
+        let base = (TLBEntry::pfn(entry_lo) as u64) << 12;
+        Some(((base & !page_mask) | (address & page_mask)) as i64)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TLB {
+    entries: [TLBEntry; TLB_ENTRIES],
+    // ASID of EntryHi, kept in sync by the CPU
+    asid: u8,
+    // Only used by the debugger, translations happen through shared references. The index plus one, 0 for none
+    #[serde(skip)]
+    last_used: AtomicUsize,
+}
+
+impl TLB {
+    pub fn new() -> Self {
+        Self {
+            entries: [TLBEntry::new(); TLB_ENTRIES],
+            asid: 0,
+            last_used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn entries(&self) -> &[TLBEntry; TLB_ENTRIES] {
+        &self.entries
+    }
+
+    pub fn read(&self, index: usize) -> TLBEntry {
+        self.entries[index % TLB_ENTRIES]
+    }
+
+    pub fn write(&mut self, index: usize, entry: TLBEntry) {
+        self.entries[index % TLB_ENTRIES] = entry;
+    }
+
+    pub fn set_asid(&mut self, asid: u8) {
+        self.asid = asid;
+    }
+
+    // Index of the entry that matches the VPN2 and ASID of EntryHi, used by TLBP
+    pub fn probe(&self, entry_hi: u64) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.matches(entry_hi, entry_hi as u8))
+    }
+
+    pub fn translate(&self, address: i64) -> Option<i64> {
+        let address = address as u64 & 0xFFFFFFFF;
+        let index = self.entries.iter().position(|entry| entry.matches(address, self.asid))?;
+        self.last_used.store(index + 1, Ordering::Relaxed);
+        self.entries[index].translate(address)
+    }
+
+    pub fn last_used(&self) -> Option<usize> {
+        self.last_used.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for entry in self.entries.iter() {
+            writer.write_u32(entry.page_mask);
+            writer.write_u64(entry.entry_hi);
+            writer.write_u64(entry.entry_lo_0);
+            writer.write_u64(entry.entry_lo_1);
+        }
+        writer.write_u8(self.asid);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for entry in self.entries.iter_mut() {
+            entry.page_mask = reader.read_u32()?;
+            entry.entry_hi = reader.read_u64()?;
+            entry.entry_lo_0 = reader.read_u64()?;
+            entry.entry_lo_1 = reader.read_u64()?;
+        }
+        self.asid = reader.read_u8()?;
+        self.last_used.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tlb_tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let mut tlb = TLB::new();
+        // 4KB pages at 0x00400000/0x00401000 mapped to 0x00100000/0x00200000, ASID 1
+        tlb.write(5, TLBEntry {
+            page_mask: 0,
+            entry_hi: 0x00400001,
+            entry_lo_0: (0x100 << 6) | 0b110,
+            entry_lo_1: (0x200 << 6) | 0b010,
+        });
+        assert_eq!(tlb.translate(0x00400010), None);
+        assert_eq!(tlb.last_used(), None);
+
+        tlb.set_asid(1);
+        assert_eq!(tlb.translate(0x00400010), Some(0x00100010));
+        assert_eq!(tlb.translate(0x00401FFC), Some(0x00200FFC));
+        assert_eq!(tlb.last_used(), Some(5));
+        assert_eq!(tlb.probe(0x00401001), Some(5));
+        assert_eq!(tlb.probe(0x00402001), None);
+    }
+}
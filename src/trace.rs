@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::emulator::Emulator;
+
+/*
+    One executed instruction: the PC, the opcode and the registers before running it.
+    Lines look like "FFFFFFFFA4000040: 3C0DBFC0 r1=0000000000000000 ...", the PC and the opcode
+    in front like the trace dumps of ares and cen64, so their dumps can be compared after
+    stripping them down to these fields. Anything after them that isn't a register is ignored.
+*/
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceEntry {
+    pub pc: i64,
+    pub opcode: u32,
+    // Only the registers present in the line, r0 is never written
+    pub registers: Vec<(usize, i64)>,
+}
+
+impl TraceEntry {
+    pub fn capture(emulator: &Emulator, with_registers: bool) -> Self {
+        let mmu = emulator.mmu();
+        let registers = emulator.cpu().registers();
+        let pc = registers.get_program_counter();
+        // Read through the physical address, so tracing doesn't show up in the memory heatmap
+        let opcode = mmu.read_physical(mmu.translate(pc), 4);
+        Self {
+            pc,
+            opcode: u32::from_be_bytes(opcode.try_into().unwrap()),
+            registers: match with_registers {
+                true => (1..32).map(|index| (index, registers.get_by_number(index))).collect(),
+                false => Vec::new(),
+            },
+        }
+    }
+
+    // 32 bit PCs are sign extended like the CPU does with KSEG addresses
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let pc = fields.next()?.trim_end_matches(':');
+        let pc = match pc.len() {
+            8 => u32::from_str_radix(pc, 16).ok()? as i32 as i64,
+            _ => u64::from_str_radix(pc, 16).ok()? as i64,
+        };
+        let opcode = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let registers = fields
+            .filter_map(|field| {
+                let (register, value) = field.strip_prefix('r')?.split_once('=')?;
+                let index: usize = register.parse().ok().filter(|index| *index < 32)?;
+                Some((index, u64::from_str_radix(value, 16).ok()? as i64))
+            })
+            .collect();
+        Some(Self {
+            pc,
+            opcode,
+            registers,
+        })
+    }
+
+    // Only what the expected entry has is compared, a trace without registers checks the control flow
+    pub fn difference(&self, actual: &TraceEntry) -> Option<String> {
+        if self.pc != actual.pc {
+            return Some(format!("PC expected {:016X}, got {:016X}", self.pc, actual.pc));
+        }
+        if self.opcode != actual.opcode {
+            return Some(format!("Opcode expected {:08X}, got {:08X}", self.opcode, actual.opcode));
+        }
+        let differences: Vec<String> = self.registers.iter()
+            .filter_map(|(index, expected)| {
+                let value = actual.registers.iter().find(|(other, _)| other == index).map(|(_, value)| *value)?;
+                (value != *expected).then(|| format!("r{} expected {:016X}, got {:016X}", index, expected, value))
+            })
+            .collect();
+        match differences.is_empty() {
+            true => None,
+            false => Some(differences.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016X}: {:08X}", self.pc, self.opcode)?;
+        for (index, value) in &self.registers {
+            write!(f, " r{}={:016X}", index, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Divergence {
+    // Instructions that matched before this one
+    pub index: usize,
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+    pub reason: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Divergence at instruction {}: {}", self.index, self.reason)?;
+        writeln!(f, "Expected {}", self.expected)?;
+        write!(f, "Got      {}", self.actual)
+    }
+}
+
+// Steps the emulator along the reference, returning how many instructions matched or the first one that didn't
+pub fn find_divergence(emulator: &mut Emulator, reference: impl Iterator<Item = TraceEntry>) -> Result<usize, Divergence> {
+    let mut index = 0;
+    for expected in reference {
+        let actual = TraceEntry::capture(emulator, !expected.registers.is_empty());
+        if let Some(reason) = expected.difference(&actual) {
+            return Err(Divergence {
+                index,
+                expected,
+                actual,
+                reason,
+            });
+        }
+        emulator.tick();
+        index += 1;
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+
+    #[test]
+    fn test_find_divergence() {
+        let entry = TraceEntry::parse("A4000040: 3C0DBFC0 lui t5, 0xBFC0 r13=0000000000000001").unwrap();
+        assert_eq!(entry.pc, 0xFFFFFFFFA4000040u64 as i64);
+        assert_eq!(entry.opcode, 0x3C0DBFC0);
+        assert_eq!(entry.registers, vec![(13, 1)]);
+        assert_eq!(TraceEntry::parse(&entry.to_string()), Some(entry));
+        assert_eq!(TraceEntry::parse("not a trace"), None);
+
+        let mut emulator = Emulator::new_hle();
+        let start = TraceEntry::capture(&emulator, true);
+        assert_eq!(start.registers.len(), 31);
+        let mut reference = start.clone();
+        reference.registers = vec![(29, start.registers[28].1)];
+        assert_eq!(find_divergence(&mut emulator, vec![reference.clone()].into_iter()), Ok(1));
+
+        let mut emulator = Emulator::new_hle();
+        reference.registers = vec![(29, 0x1234)];
+        let divergence = find_divergence(&mut emulator, vec![reference].into_iter()).unwrap_err();
+        assert_eq!(divergence.index, 0);
+        assert!(divergence.reason.starts_with("r29 expected 0000000000001234"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use crate::log::{log, Level, Subsystem};
+use crate::rdp::rgba5551;
+use crate::rom::ROM;
+use crate::screenshot::encode_png;
+
+/*
+    The USB ports of the 64drive and the EverDrive, as UNFLoader's debug library uses them.
+    Every transfer is a data type and a size: text goes to the log console, binaries and
+    screenshots are written next to the ROM. Nothing is ever sent back to the console.
+    https://github.com/buu342/N64-UNFLoader/tree/master/USB%20Implementation
+*/
+pub const D64_REGISTERS: RangeInclusive<i64> = 0x18000000..=0x180007FF;
+// Where UNFLoader puts the data before sending it, at the end of the 64MB of SDRAM. Bigger ROMs are hidden
+pub const D64_BUFFER: RangeInclusive<i64> = 0x13F00000..=0x13FEFFFF;
+pub const EVERDRIVE_REGISTERS: RangeInclusive<i64> = 0x1F800000..=0x1F80FFFF;
+
+const D64_REGISTER_STATUS: usize = 0x200;
+const D64_REGISTER_MAGIC: usize = 0x2EC;
+const D64_REGISTER_USBCOMSTAT: usize = 0x400;
+const D64_REGISTER_USBP0R0: usize = 0x404;
+const D64_REGISTER_USBP1R1: usize = 0x408;
+// "UDEV"
+const D64_MAGIC: u32 = 0x55444556;
+const D64_COMMAND_WRITE: u32 = 0x08;
+
+const EVERDRIVE_REGISTER_USBCFG: usize = 0x0004;
+const EVERDRIVE_REGISTER_VERSION: usize = 0x0014;
+const EVERDRIVE_REGISTER_USBDAT: usize = 0x0400;
+const EVERDRIVE_REGISTER_KEY: usize = 0x8004;
+const EVERDRIVE_USB_BUFFER_SIZE: usize = 512;
+const EVERDRIVE_KEY: u32 = 0xAA55;
+// EverDrive 64 X7
+const EVERDRIVE_VERSION: u32 = 0xED640013;
+const EVERDRIVE_USBMODE_MASK: u32 = 0xFE00;
+const EVERDRIVE_USBMODE_WRITE: u32 = 0xA000;
+// Powered with nothing to read, never busy since transfers complete right away
+const EVERDRIVE_USBSTAT: u32 = 0x1000 | 0x0400;
+
+// The EverDrive has no header registers, so UNFLoader wraps the data in these
+const PACKET_START: &[u8; 4] = b"DMA@";
+const PACKET_END: &[u8; 4] = b"CMPH";
+
+const DATATYPE_TEXT: u8 = 0x01;
+const DATATYPE_RAWBINARY: u8 = 0x02;
+const DATATYPE_HEADER: u8 = 0x03;
+const DATATYPE_SCREENSHOT: u8 = 0x04;
+
+// What the console sent that goes to a file
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UsbFile {
+    pub extension: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl UsbFile {
+    // Next to the ROM like screenshots, the index tells apart the files of the same second
+    pub fn write(&self, rom: &ROM, timestamp: u64, index: usize) -> std::io::Result<PathBuf> {
+        let path = rom.save_path_with_extension(&format!("usb-{}-{}.{}", timestamp, index, self.extension))
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No ROM loaded from a file"))?;
+        std::fs::write(&path, &self.data)?;
+        Ok(path)
+    }
+}
+
+fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub struct UsbDebug {
+    d64_registers: Vec<u8>,
+    d64_buffer: Vec<u8>,
+    everdrive_registers: Vec<u8>,
+    everdrive_unlocked: bool,
+    // Bytes sent through the EverDrive that don't make a whole packet yet
+    everdrive_stream: Vec<u8>,
+    // Text after the last new line
+    line: Vec<u8>,
+    // The last header, which describes the screenshot after it
+    header: Option<Vec<u8>>,
+    files: Vec<UsbFile>,
+}
+
+impl UsbDebug {
+    pub fn new() -> Self {
+        Self {
+            d64_registers: vec![0; D64_REGISTERS.clone().count()],
+            d64_buffer: vec![0; D64_BUFFER.clone().count()],
+            everdrive_registers: vec![0; EVERDRIVE_REGISTERS.clone().count()],
+            everdrive_unlocked: false,
+            everdrive_stream: Vec::new(),
+            line: Vec::new(),
+            header: None,
+            files: Vec::new(),
+        }
+    }
+
+    pub fn contains(address: i64) -> bool {
+        D64_REGISTERS.contains(&address) || D64_BUFFER.contains(&address) || EVERDRIVE_REGISTERS.contains(&address)
+    }
+
+    pub fn read(&self, address: i64) -> u8 {
+        if D64_BUFFER.contains(&address) {
+            return self.d64_buffer[(address - D64_BUFFER.start()) as usize];
+        } else if D64_REGISTERS.contains(&address) {
+            let offset = (address - D64_REGISTERS.start()) as usize;
+            let value = match offset & !0b11 {
+                D64_REGISTER_STATUS | D64_REGISTER_USBCOMSTAT => 0,
+                D64_REGISTER_MAGIC => D64_MAGIC,
+                _ => return self.d64_registers[offset],
+            };
+            return value.to_be_bytes()[offset & 0b11];
+        }
+        let offset = (address - EVERDRIVE_REGISTERS.start()) as usize;
+        let value = match offset & !0b11 {
+            EVERDRIVE_REGISTER_USBCFG => EVERDRIVE_USBSTAT,
+            EVERDRIVE_REGISTER_VERSION if self.everdrive_unlocked => EVERDRIVE_VERSION,
+            EVERDRIVE_REGISTER_VERSION => 0,
+            _ => return self.everdrive_registers[offset],
+        };
+        value.to_be_bytes()[offset & 0b11]
+    }
+
+    // Registers take effect once their last byte is written
+    pub fn write(&mut self, address: i64, data: u8) {
+        if D64_BUFFER.contains(&address) {
+            self.d64_buffer[(address - D64_BUFFER.start()) as usize] = data;
+        } else if D64_REGISTERS.contains(&address) {
+            let offset = (address - D64_REGISTERS.start()) as usize;
+            self.d64_registers[offset] = data;
+            if offset == D64_REGISTER_USBCOMSTAT + 3 && word(&self.d64_registers, D64_REGISTER_USBCOMSTAT) == D64_COMMAND_WRITE {
+                self.d64_send();
+            }
+        } else {
+            let offset = (address - EVERDRIVE_REGISTERS.start()) as usize;
+            self.everdrive_registers[offset] = data;
+            if offset == EVERDRIVE_REGISTER_KEY + 3 {
+                self.everdrive_unlocked = word(&self.everdrive_registers, EVERDRIVE_REGISTER_KEY) == EVERDRIVE_KEY;
+            } else if offset == EVERDRIVE_REGISTER_USBCFG + 3 {
+                let value = word(&self.everdrive_registers, EVERDRIVE_REGISTER_USBCFG);
+                if value & EVERDRIVE_USBMODE_MASK == EVERDRIVE_USBMODE_WRITE {
+                    self.everdrive_send((value & 0x1FF) as usize);
+                }
+            }
+        }
+    }
+
+    // The files sent since the last call
+    pub fn take_files(&mut self) -> Vec<UsbFile> {
+        std::mem::take(&mut self.files)
+    }
+
+    // The data is in SDRAM at the address in USBP0R0, in halfwords from the start of the cartridge
+    fn d64_send(&mut self) {
+        let address = 0x10000000 + ((word(&self.d64_registers, D64_REGISTER_USBP0R0) as i64) << 1);
+        let header = word(&self.d64_registers, D64_REGISTER_USBP1R1);
+        let size = (header & 0xFFFFFF) as i64;
+        let data = (address..address + size).map(|address| match D64_BUFFER.contains(&address) {
+            true => self.d64_buffer[(address - D64_BUFFER.start()) as usize],
+            false => 0,
+        }).collect();
+        self.receive((header >> 24) as u8, data);
+    }
+
+    // Sends the end of the USB buffer from the offset
+    fn everdrive_send(&mut self, offset: usize) {
+        let start = EVERDRIVE_REGISTER_USBDAT + offset;
+        let end = EVERDRIVE_REGISTER_USBDAT + EVERDRIVE_USB_BUFFER_SIZE;
+        self.everdrive_stream.extend_from_slice(&self.everdrive_registers[start..end]);
+        loop {
+            // Transfers are padded, anything before a packet starts is skipped
+            match self.everdrive_stream.windows(4).position(|window| window == PACKET_START) {
+                Some(start) => {
+                    self.everdrive_stream.drain(..start);
+                },
+                None => {
+                    let keep = self.everdrive_stream.len().saturating_sub(3);
+                    self.everdrive_stream.drain(..keep);
+                    return;
+                },
+            };
+            if self.everdrive_stream.len() < 8 {
+                return;
+            }
+            let header = word(&self.everdrive_stream, 4);
+            let size = (header & 0xFFFFFF) as usize;
+            if self.everdrive_stream.len() < 8 + size + 4 {
+                return;
+            }
+            let packet: Vec<u8> = self.everdrive_stream.drain(..8 + size + 4).collect();
+            if &packet[8 + size..] != PACKET_END {
+                log!(Level::Warn, Subsystem::USB, "Packet of {} bytes without its end marker", size);
+            }
+            self.receive((header >> 24) as u8, packet[8..8 + size].to_vec());
+        }
+    }
+
+    fn receive(&mut self, datatype: u8, data: Vec<u8>) {
+        match datatype {
+            DATATYPE_TEXT => {
+                for byte in data {
+                    match byte {
+                        b'\n' => {
+                            log!(Level::Info, Subsystem::USB, "{}", String::from_utf8_lossy(&self.line).trim_end_matches('\r'));
+                            self.line.clear();
+                        },
+                        // UNFLoader's strings end with their terminator
+                        0 => {},
+                        byte => self.line.push(byte),
+                    };
+                }
+            },
+            DATATYPE_RAWBINARY => self.files.push(UsbFile { extension: "bin", data }),
+            DATATYPE_HEADER => self.header = Some(data),
+            DATATYPE_SCREENSHOT => match self.header.take() {
+                Some(header) if header.len() >= 16 && word(&header, 0) == DATATYPE_SCREENSHOT as u32 => {
+                    let (depth, width, height) = (word(&header, 4) as usize, word(&header, 8) as usize, word(&header, 12) as usize);
+                    let pixels: Vec<u8> = match depth {
+                        2 => data.chunks_exact(2).flat_map(|pixel| {
+                            let [r, g, b, _] = rgba5551(u16::from_be_bytes([pixel[0], pixel[1]]));
+                            [r, g, b, 0xFF]
+                        }).collect(),
+                        _ => data.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0xFF]).collect(),
+                    };
+                    match pixels.len() == width * height * 4 {
+                        true => self.files.push(UsbFile { extension: "png", data: encode_png(width, height, &pixels) }),
+                        false => log!(Level::Warn, Subsystem::USB, "Screenshot of {}x{} with {} bytes", width, height, data.len()),
+                    };
+                },
+                _ => log!(Level::Warn, Subsystem::USB, "Screenshot without its header"),
+            },
+            _ => log!(Level::Warn, Subsystem::USB, "Unknown data type {:02X} of {} bytes", datatype, data.len()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod usb_debug_tests {
+    use super::*;
+
+    fn write_word(usb: &mut UsbDebug, address: i64, value: u32) {
+        for (offset, byte) in value.to_be_bytes().iter().enumerate() {
+            usb.write(address + offset as i64, *byte);
+        }
+    }
+
+    fn read_word(usb: &UsbDebug, address: i64) -> u32 {
+        u32::from_be_bytes([0, 1, 2, 3].map(|offset| usb.read(address + offset)))
+    }
+
+    #[test]
+    fn test_64drive() {
+        let mut usb = UsbDebug::new();
+        assert_eq!(read_word(&usb, 0x180002EC), D64_MAGIC);
+        for (offset, byte) in [1, 2, 3, 4, 5].iter().enumerate() {
+            usb.write(D64_BUFFER.start() + offset as i64, *byte);
+        }
+        write_word(&mut usb, 0x18000404, 0x03F00000 >> 1);
+        write_word(&mut usb, 0x18000408, (DATATYPE_RAWBINARY as u32) << 24 | 5);
+        write_word(&mut usb, 0x18000400, D64_COMMAND_WRITE);
+        assert_eq!(usb.take_files(), vec![UsbFile { extension: "bin", data: vec![1, 2, 3, 4, 5] }]);
+        assert!(usb.take_files().is_empty());
+    }
+
+    #[test]
+    fn test_everdrive() {
+        let mut usb = UsbDebug::new();
+        assert_eq!(read_word(&usb, 0x1F800014), 0);
+        write_word(&mut usb, 0x1F808004, EVERDRIVE_KEY);
+        assert_eq!(read_word(&usb, 0x1F800014), EVERDRIVE_VERSION);
+
+        // A 1x1 screenshot, split over two transfers
+        let mut header = Vec::new();
+        for value in [DATATYPE_SCREENSHOT as u32, 2, 1, 1] {
+            header.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut send = |datatype: u8, data: &[u8]| {
+            let mut packet = PACKET_START.to_vec();
+            packet.extend_from_slice(&((datatype as u32) << 24 | data.len() as u32).to_be_bytes());
+            packet.extend_from_slice(data);
+            packet.extend_from_slice(PACKET_END);
+            for chunk in packet.chunks(10) {
+                let offset = EVERDRIVE_USB_BUFFER_SIZE - chunk.len();
+                for (index, byte) in chunk.iter().enumerate() {
+                    usb.write(0x1F800400 + (offset + index) as i64, *byte);
+                }
+                write_word(&mut usb, 0x1F800004, EVERDRIVE_USBMODE_WRITE | offset as u32);
+            }
+        };
+        send(DATATYPE_HEADER, &header);
+        send(DATATYPE_SCREENSHOT, &[0xF8, 0x01]);
+        let files = usb.take_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension, "png");
+        assert_eq!(files[0].data, encode_png(1, 1, &[0xFF, 0, 0, 0xFF]));
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+use rultra64::test_rom::{parse_manifest, TestOutcome, TEST_ROMS_DIRECTORY};
+
+// Runs every test ROM found and reports all the failures at once, missing ROMs are skipped
+#[test]
+fn test_roms() {
+    let directory = std::env::var_os("RULTRA64_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(TEST_ROMS_DIRECTORY));
+    let manifest = include_str!("test_roms.txt");
+    let mut failures = Vec::new();
+    let mut skipped = 0;
+    for test in parse_manifest(manifest).unwrap() {
+        match test.run(&directory) {
+            Some(TestOutcome::Pass) => {},
+            Some(TestOutcome::Fail(reason)) => failures.push(format!("{}: {}", test.name, reason)),
+            None => skipped += 1,
+        };
+    }
+    if skipped > 0 {
+        eprintln!("Skipped {} test ROMs not found in {}", skipped, directory.display());
+    }
+    assert!(failures.is_empty(), "{} test ROMs failed:\n{}", failures.len(), failures.join("\n"));
+}